@@ -0,0 +1,64 @@
+//! `cargo libnss healthcheck`, which loads an installed module and runs its
+//! `_nss_<module>_healthcheck`, for monitoring to run on every host.
+
+use std::ffi::CStr;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use crate::module::Module;
+use crate::{flag_value, usage_error};
+
+type HealthcheckFn = unsafe extern "C" fn(*mut libc::c_char, libc::size_t) -> libc::c_int;
+
+/// Prints the module's report and exits with its status: 0 healthy, 1 degraded, 2 unhealthy, or
+/// 3 if the check couldn't be run at all.
+pub fn run(args: &[String]) -> io::Result<()> {
+    let mut module = None;
+    let mut library = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--library" => library = Some(PathBuf::from(flag_value(args, &mut i)?)),
+            arg if arg.starts_with('-') => {
+                return Err(usage_error(format!("unknown option `{}`", arg)))
+            }
+            arg if module.is_none() => module = Some(arg.to_string()),
+            arg => return Err(usage_error(format!("unexpected argument `{}`", arg))),
+        }
+        i += 1;
+    }
+    let module = module.ok_or_else(|| usage_error("healthcheck needs a module name".into()))?;
+
+    match check(&module, library) {
+        Ok((code, report)) => {
+            println!("{}", report);
+            process::exit(code);
+        }
+        Err(err) => {
+            println!("unknown: {}", err);
+            process::exit(3);
+        }
+    }
+}
+
+fn check(module: &str, library: Option<PathBuf>) -> io::Result<(i32, String)> {
+    let module = Module::open(module, library)?;
+    let function = module.symbol("healthcheck").ok_or_else(|| {
+        io::Error::other(format!(
+            "{} has no health check; add `libnss_healthcheck!({}, ...)` to the module",
+            module.library.display(),
+            module.name
+        ))
+    })?;
+
+    unsafe {
+        let function: HealthcheckFn = std::mem::transmute(function);
+        let mut report = [0 as libc::c_char; 1024];
+        let code = function(report.as_mut_ptr(), report.len());
+        let report = CStr::from_ptr(report.as_ptr())
+            .to_string_lossy()
+            .into_owned();
+        Ok((code, report))
+    }
+}
@@ -0,0 +1,166 @@
+//! `cargo libnss install`, which builds the module and copies it to where NSS looks for it, under
+//! the file name NSS loads.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{flag_value, usage_error};
+
+pub fn run(args: &[String]) -> io::Result<()> {
+    let mut manifest_path = PathBuf::from("Cargo.toml");
+    let mut libdir = None;
+    let mut release = true;
+    let mut dry_run = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--manifest-path" => manifest_path = PathBuf::from(flag_value(args, &mut i)?),
+            "--libdir" => libdir = Some(PathBuf::from(flag_value(args, &mut i)?)),
+            "--debug" => release = false,
+            "--dry-run" => dry_run = true,
+            arg => return Err(usage_error(format!("unknown option `{}`", arg))),
+        }
+        i += 1;
+    }
+
+    let module = module_name(&fs::read_to_string(&manifest_path)?)?;
+    let soname = libnss::build::soname_for(env::consts::OS, &module).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("NSS modules can't be installed on {}", env::consts::OS),
+        )
+    })?;
+    let libdir = libdir.unwrap_or_else(|| match env::consts::OS {
+        "linux" => PathBuf::from("/lib"),
+        _ => PathBuf::from("/usr/lib"),
+    });
+
+    let mut build = cargo();
+    build.args(["build", "--lib", "--manifest-path"]);
+    build.arg(&manifest_path);
+    if release {
+        build.arg("--release");
+    }
+    run_command(build, dry_run)?;
+
+    let built = target_dir(&manifest_path)?
+        .join(if release { "release" } else { "debug" })
+        .join(format!("libnss_{}.so", module));
+    let dest = libdir.join(&soname);
+    println!("Installing {} as {}", built.display(), dest.display());
+    if !dry_run {
+        install(&built, &dest)?;
+    }
+
+    if env::consts::OS == "linux" {
+        let mut ldconfig = Command::new("ldconfig");
+        ldconfig.arg(&libdir);
+        run_command(ldconfig, dry_run)?;
+    }
+    println!(
+        "Add `{}` to the databases it serves in /etc/nsswitch.conf to start using it",
+        module
+    );
+    Ok(())
+}
+
+/// The module a manifest builds, from its `[lib]` name of `nss_<module>`.
+fn module_name(manifest: &str) -> io::Result<String> {
+    let mut in_lib = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_lib = line == "[lib]";
+        } else if in_lib {
+            if let Some((key, value)) = line.split_once('=') {
+                if key.trim() == "name" {
+                    let name = value.trim().trim_matches('"');
+                    if let Some(module) = name.strip_prefix("nss_") {
+                        return Ok(module.to_string());
+                    }
+                }
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the crate's [lib] name isn't nss_<module>, so it doesn't build an NSS module; \
+         see `cargo libnss new` for the layout",
+    ))
+}
+
+fn cargo() -> Command {
+    Command::new(env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
+}
+
+fn run_command(mut command: Command, dry_run: bool) -> io::Result<()> {
+    println!("Running {:?}", command);
+    if dry_run {
+        return Ok(());
+    }
+    let status = command.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command, status
+        )))
+    }
+}
+
+/// Where cargo puts the crate's build output, which may be shared with a whole workspace.
+fn target_dir(manifest_path: &Path) -> io::Result<PathBuf> {
+    let output = cargo()
+        .args([
+            "metadata",
+            "--format-version",
+            "1",
+            "--no-deps",
+            "--manifest-path",
+        ])
+        .arg(manifest_path)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // The one string we need doesn't warrant a JSON parser
+    let metadata = String::from_utf8_lossy(&output.stdout);
+    let key = "\"target_directory\":\"";
+    let start = metadata
+        .find(key)
+        .map(|start| start + key.len())
+        .ok_or_else(|| io::Error::other("cargo metadata didn't report a target directory"))?;
+    let mut dir = String::new();
+    let mut chars = metadata[start..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok(PathBuf::from(dir)),
+            '\\' => dir.extend(chars.next()),
+            c => dir.push(c),
+        }
+    }
+    Err(io::Error::other("cargo metadata's output was cut short"))
+}
+
+/// Copies `built` to `dest` through a temporary file renamed into place, as overwriting the
+/// library in place would crash every running program that has it mapped.
+fn install(built: &Path, dest: &Path) -> io::Result<()> {
+    let mut temp = dest.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    fs::copy(built, &temp)?;
+    fs::set_permissions(&temp, fs::Permissions::from_mode(0o644))?;
+    fs::rename(&temp, dest).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
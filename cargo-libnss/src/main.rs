@@ -0,0 +1,87 @@
+//! `cargo libnss`: scaffolds a new NSS module, installs a built one where NSS will load it, runs
+//! an installed one's health check and warms its cache.
+
+use std::env;
+use std::io;
+use std::process;
+
+mod healthcheck;
+mod install;
+mod module;
+mod new;
+mod warm;
+
+const USAGE: &str = "\
+Usage: cargo libnss <command> [options]
+
+Commands:
+    new <name>    Create an NSS module crate named nss-<name> in a new directory
+    install       Build the module in the current crate and install it under the name NSS loads
+    healthcheck <module>
+                  Run an installed module's health check, exiting 0 if healthy, 1 if degraded,
+                  2 if unhealthy and 3 if the check can't be run
+    warm <module> Look up a list of users, groups and hosts through an installed module, filling
+                  its cache
+
+Options for new:
+    --path <dir>           Create the crate in <dir> rather than ./nss-<name>
+    --libnss-path <dir>    Depend on a local checkout of libnss rather than crates.io
+
+Options for install:
+    --manifest-path <path>    The module's Cargo.toml [default: ./Cargo.toml]
+    --libdir <dir>            Where to install [default: /lib on Linux, /usr/lib elsewhere]
+    --debug                   Install a debug build rather than a release one
+    --dry-run                 Print what would be done without doing it
+
+Options for healthcheck:
+    --library <path>    The module's library [default: the one NSS loads]
+
+Options for warm:
+    --passwd <names>    Users to look up, comma separated
+    --group <names>     Groups to look up, comma separated
+    --hosts <names>     Hosts to look up, comma separated
+    --file <path>       Entries to look up, a `<passwd|group|hosts> <name>` per line
+    --splay <secs>      Wait a random time up to <secs> first, so a fleet doesn't warm at once
+    --library <path>    The module's library [default: the one NSS loads]
+";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Cargo runs subcommands as `cargo-libnss libnss <args>`
+    if args.first().map(String::as_str) == Some("libnss") {
+        args.remove(0);
+    }
+
+    let result = match args.first().map(String::as_str) {
+        Some("new") => new::run(&args[1..]),
+        Some("install") => install::run(&args[1..]),
+        Some("healthcheck") => healthcheck::run(&args[1..]),
+        Some("warm") => warm::run(&args[1..]),
+        Some("-h") | Some("--help") | Some("help") | None => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        Some(command) => Err(usage_error(format!("unknown command `{}`", command))),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn usage_error(message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}\n\n{}", message, USAGE),
+    )
+}
+
+/// The value following the flag at `args[*i]`, advancing past it.
+fn flag_value(args: &[String], i: &mut usize) -> io::Result<String> {
+    let flag = &args[*i];
+    *i += 1;
+    args.get(*i)
+        .cloned()
+        .ok_or_else(|| usage_error(format!("`{}` needs a value", flag)))
+}
@@ -0,0 +1,60 @@
+//! Loading an installed module's library, for the commands that call into it.
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::PathBuf;
+
+pub struct Module {
+    pub name: String,
+    pub library: PathBuf,
+    handle: *mut libc::c_void,
+}
+
+impl Module {
+    /// Loads `library`, or by default the library NSS itself would load for the module `name`.
+    pub fn open(name: &str, library: Option<PathBuf>) -> io::Result<Self> {
+        let library = match library {
+            Some(library) => library,
+            None => PathBuf::from(libnss::build::soname_for(env::consts::OS, name).ok_or_else(
+                || {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("NSS modules can't be loaded on {}", env::consts::OS),
+                    )
+                },
+            )?),
+        };
+        let path = CString::new(library.to_string_lossy().into_owned())?;
+
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(io::Error::other(unsafe { dlerror() }));
+        }
+        Ok(Module {
+            name: name.to_string(),
+            library,
+            handle,
+        })
+    }
+
+    /// The module's `_nss_<module>_<function>`, if it exports one.
+    pub fn symbol(&self, function: &str) -> Option<*mut libc::c_void> {
+        let symbol = CString::new(format!("_nss_{}_{}", self.name, function)).ok()?;
+        let address = unsafe { libc::dlsym(self.handle, symbol.as_ptr()) };
+        if address.is_null() {
+            None
+        } else {
+            Some(address)
+        }
+    }
+}
+
+unsafe fn dlerror() -> String {
+    let message = libc::dlerror();
+    if message.is_null() {
+        "dlopen failed".to_string()
+    } else {
+        CStr::from_ptr(message).to_string_lossy().into_owned()
+    }
+}
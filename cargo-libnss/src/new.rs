@@ -0,0 +1,101 @@
+//! `cargo libnss new`, which writes a module crate ready to build and install.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{flag_value, usage_error};
+
+const CARGO_TOML: &str = include_str!("../templates/Cargo.toml.in");
+const BUILD_RS: &str = include_str!("../templates/build.rs.in");
+const LIB_RS: &str = include_str!("../templates/lib.rs.in");
+const CONFIG_RS: &str = include_str!("../templates/config.rs.in");
+const GITIGNORE: &str = include_str!("../templates/gitignore.in");
+
+pub fn run(args: &[String]) -> io::Result<()> {
+    let mut name = None;
+    let mut path = None;
+    let mut libnss_path = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--path" => path = Some(PathBuf::from(flag_value(args, &mut i)?)),
+            "--libnss-path" => libnss_path = Some(flag_value(args, &mut i)?),
+            flag if flag.starts_with('-') => {
+                return Err(usage_error(format!("unknown option `{}`", flag)))
+            }
+            arg if name.is_none() => name = Some(arg.to_string()),
+            arg => return Err(usage_error(format!("unexpected argument `{}`", arg))),
+        }
+        i += 1;
+    }
+
+    let name = name.ok_or_else(|| usage_error("`new` needs a module name".to_string()))?;
+    check_name(&name)?;
+    let dir = path.unwrap_or_else(|| PathBuf::from(format!("nss-{}", name)));
+    if dir.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dir.display()),
+        ));
+    }
+
+    let libnss = match libnss_path {
+        Some(path) => format!("{{ path = {:?} }}", path),
+        None => format!("\"{}\"", env!("CARGO_PKG_VERSION")),
+    };
+    let fill = |template: &str| {
+        template
+            .replace("{name}", &name)
+            .replace("{camel}", &camel_case(&name))
+            .replace("{libnss}", &libnss)
+    };
+
+    fs::create_dir_all(dir.join("src"))?;
+    write(&dir, "Cargo.toml", fill(CARGO_TOML))?;
+    write(&dir, "build.rs", fill(BUILD_RS))?;
+    write(&dir, "src/lib.rs", fill(LIB_RS))?;
+    write(&dir, "src/config.rs", fill(CONFIG_RS))?;
+    write(&dir, ".gitignore", fill(GITIGNORE))?;
+
+    println!(
+        "Created module `{}` in {}; build and install it with `cargo libnss install`",
+        name,
+        dir.display()
+    );
+    Ok(())
+}
+
+/// The name is both part of the library's file name and the prefix of every symbol NSS looks up,
+/// so it has to be a valid identifier, and NSS implementations expect lower case.
+fn check_name(name: &str) -> io::Result<()> {
+    let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(usage_error(format!(
+            "`{}` can't name a module: use lower case letters, digits and underscores, starting \
+             with a letter",
+            name
+        )))
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn write(dir: &Path, file: &str, contents: String) -> io::Result<()> {
+    fs::write(dir.join(file), contents)
+}
@@ -0,0 +1,253 @@
+//! `cargo libnss warm`, which looks up a list of users, groups and hosts through an installed
+//! module, so that its cache holds them before the programs needing them start.
+//!
+//! The lookups go through the module's own `_nss_*` functions, exactly as glibc would make them,
+//! so whatever the module caches (e.g. a backend's `cache_dir`, with a `ttl`) is filled as it
+//! would be by those programs, once rather than by each of them.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use libnss::interop::NssStatus;
+
+use crate::module::Module;
+use crate::{flag_value, usage_error};
+
+type GetpwnamFn = unsafe extern "C" fn(
+    *const libc::c_char,
+    *mut libc::passwd,
+    *mut libc::c_char,
+    libc::size_t,
+    *mut libc::c_int,
+) -> libc::c_int;
+type GetgrnamFn = unsafe extern "C" fn(
+    *const libc::c_char,
+    *mut libc::group,
+    *mut libc::c_char,
+    libc::size_t,
+    *mut libc::c_int,
+) -> libc::c_int;
+type Gethostbyname2Fn = unsafe extern "C" fn(
+    *const libc::c_char,
+    libc::c_int,
+    *mut libc::hostent,
+    *mut libc::c_char,
+    libc::size_t,
+    *mut libc::c_int,
+    *mut libc::c_int,
+) -> libc::c_int;
+
+const DATABASES: [&str; 3] = ["passwd", "group", "hosts"];
+
+/// The largest buffer offered to the module, as glibc stops growing its own around here too.
+const MAX_BUFFER: usize = 1 << 20;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+    Found,
+    NotFound,
+    Failed,
+}
+
+/// Looks up every entry listed and prints how many were found in each database, exiting 1 if
+/// any lookup failed rather than finding nothing.
+pub fn run(args: &[String]) -> io::Result<()> {
+    let mut module = None;
+    let mut library = None;
+    let mut splay = Duration::from_secs(0);
+    let mut entries: Vec<(&str, String)> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--library" => library = Some(PathBuf::from(flag_value(args, &mut i)?)),
+            "--splay" => {
+                let secs = flag_value(args, &mut i)?;
+                let secs = secs
+                    .parse()
+                    .map_err(|_| usage_error(format!("`--splay {}` isn't a number", secs)))?;
+                splay = Duration::from_secs(secs);
+            }
+            "--file" => {
+                let path = flag_value(args, &mut i)?;
+                entries.extend(read_list(&fs::read_to_string(&path)?)?);
+            }
+            flag if flag.starts_with("--") && DATABASES.contains(&&flag[2..]) => {
+                let database = DATABASES.iter().find(|d| **d == &flag[2..]).unwrap();
+                let names = flag_value(args, &mut i)?;
+                entries.extend(
+                    names
+                        .split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(|name| (*database, name.to_string())),
+                );
+            }
+            arg if arg.starts_with('-') => {
+                return Err(usage_error(format!("unknown option `{}`", arg)))
+            }
+            arg if module.is_none() => module = Some(arg.to_string()),
+            arg => return Err(usage_error(format!("unexpected argument `{}`", arg))),
+        }
+        i += 1;
+    }
+    let module = module.ok_or_else(|| usage_error("warm needs a module name".into()))?;
+    if entries.is_empty() {
+        return Err(usage_error("warm needs entries to look up".into()));
+    }
+
+    let module = Module::open(&module, library)?;
+    for database in DATABASES {
+        if entries.iter().any(|(d, _)| *d == database) && lookup_fn(&module, database).is_none() {
+            return Err(io::Error::other(format!(
+                "{} has no {} database",
+                module.library.display(),
+                database
+            )));
+        }
+    }
+
+    // So that a fleet restarting at once doesn't warm at once
+    if splay > Duration::from_secs(0) {
+        thread::sleep(jitter(splay));
+    }
+
+    let mut failed = false;
+    for database in DATABASES {
+        let mut counts = [0; 3];
+        for (_, name) in entries.iter().filter(|(d, _)| *d == database) {
+            let outcome = lookup(&module, database, name)?;
+            counts[outcome as usize] += 1;
+        }
+        if counts.iter().sum::<usize>() > 0 {
+            println!(
+                "{}: {} found, {} not found, {} failed",
+                database, counts[0], counts[1], counts[2]
+            );
+        }
+        failed |= counts[Outcome::Failed as usize] > 0;
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Parses a list of entries, one `<database> <name>` per line as `getent` takes them, skipping
+/// blank lines and `#` comments.
+fn read_list(list: &str) -> io::Result<Vec<(&'static str, String)>> {
+    let mut entries = Vec::new();
+    for (number, line) in list.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let database = fields
+            .next()
+            .and_then(|d| DATABASES.iter().find(|db| **db == d));
+        match (database, fields.next(), fields.next()) {
+            (Some(database), Some(name), None) => entries.push((*database, name.to_string())),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "line {}: expected `<passwd|group|hosts> <name>`",
+                        number + 1
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn lookup_fn(module: &Module, database: &str) -> Option<*mut libc::c_void> {
+    match database {
+        "passwd" => module.symbol("getpwnam_r"),
+        "group" => module.symbol("getgrnam_r"),
+        _ => module.symbol("gethostbyname2_r"),
+    }
+}
+
+fn lookup(module: &Module, database: &str, name: &str) -> io::Result<Outcome> {
+    let function = lookup_fn(module, database).unwrap();
+    let name = CString::new(name)?;
+    unsafe {
+        Ok(match database {
+            "passwd" => {
+                let function: GetpwnamFn = std::mem::transmute(function);
+                let mut result: libc::passwd = std::mem::zeroed();
+                call(|buffer, len, errnop| {
+                    function(name.as_ptr(), &mut result, buffer, len, errnop)
+                })
+            }
+            "group" => {
+                let function: GetgrnamFn = std::mem::transmute(function);
+                let mut result: libc::group = std::mem::zeroed();
+                call(|buffer, len, errnop| {
+                    function(name.as_ptr(), &mut result, buffer, len, errnop)
+                })
+            }
+            // Both families, as programs ask for either
+            _ => {
+                let function: Gethostbyname2Fn = std::mem::transmute(function);
+                let outcomes = [libc::AF_INET, libc::AF_INET6].iter().map(|&family| {
+                    let mut result: libc::hostent = std::mem::zeroed();
+                    let mut herrno = 0;
+                    call(|buffer, len, errnop| {
+                        function(
+                            name.as_ptr(),
+                            family,
+                            &mut result,
+                            buffer,
+                            len,
+                            errnop,
+                            &mut herrno,
+                        )
+                    })
+                });
+                let outcomes: Vec<Outcome> = outcomes.collect();
+                if outcomes.contains(&Outcome::Found) {
+                    Outcome::Found
+                } else if outcomes.contains(&Outcome::Failed) {
+                    Outcome::Failed
+                } else {
+                    Outcome::NotFound
+                }
+            }
+        })
+    }
+}
+
+/// Makes a `*_r` call, offering a bigger buffer each time the entry doesn't fit.
+fn call<F>(mut function: F) -> Outcome
+where
+    F: FnMut(*mut libc::c_char, libc::size_t, *mut libc::c_int) -> libc::c_int,
+{
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut errno = 0;
+        match NssStatus::try_from(function(buffer.as_mut_ptr(), buffer.len(), &mut errno)) {
+            Ok(NssStatus::Success) => return Outcome::Found,
+            Ok(NssStatus::NotFound | NssStatus::Return) => return Outcome::NotFound,
+            Ok(NssStatus::TryAgain) if errno == libc::ERANGE && buffer.len() < MAX_BUFFER => {
+                buffer.resize(buffer.len() * 2, 0)
+            }
+            _ => return Outcome::Failed,
+        }
+    }
+}
+
+/// Anywhere up to `splay`.
+fn jitter(splay: Duration) -> Duration {
+    // A freshly keyed hasher is a random number without a dependency for one
+    let random = RandomState::new().build_hasher().finish();
+    splay.mul_f64((random >> 11) as f64 / (1u64 << 53) as f64)
+}
@@ -0,0 +1,4 @@
+fn main() {
+    libnss::build::set_soname("etcfiles").unwrap();
+    libnss::build::export_nss_symbols("etcfiles").unwrap();
+}
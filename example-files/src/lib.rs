@@ -0,0 +1,164 @@
+//! Serves the classic files in `/etc` through `libnss::files`, as glibc's own `files` module
+//! does, so the two can be compared: see `libnss-e2e/tests/files.rs`. Every lookup reads the file
+//! afresh, and a missing or unreadable file holds no entries.
+
+#[macro_use]
+extern crate libnss;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use libnss::files::{self, FilesEntry};
+use libnss::group::{Group, GroupHooks};
+use libnss::health::{Health, HealthCheck};
+use libnss::host::{AddressFamily, Addresses, Host, HostHooks};
+use libnss::id::{Gid, Uid};
+use libnss::passwd::{Passwd, PasswdHooks};
+use libnss::shadow::{Shadow, ShadowHooks};
+
+libnss_module!(etcfiles, passwd, group, shadow, host);
+
+fn read<T: FilesEntry>(path: &str) -> Vec<T> {
+    files::read(path).unwrap_or_default()
+}
+
+struct EtcPasswd;
+libnss_passwd_hooks!(etcfiles, EtcPasswd);
+
+impl PasswdHooks for EtcPasswd {
+    fn get_all_entries() -> Vec<Passwd> {
+        read("/etc/passwd")
+    }
+
+    fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+        Self::get_all_entries()
+            .into_iter()
+            .find(|entry| entry.uid == uid)
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Passwd> {
+        Self::get_all_entries()
+            .into_iter()
+            .find(|entry| entry.name == name)
+    }
+}
+
+struct EtcHealth;
+libnss_healthcheck!(etcfiles, EtcHealth);
+
+/// Healthy if `/etc/passwd` can be read and has root in it.
+impl HealthCheck for EtcHealth {
+    fn check() -> Health {
+        Health::probe(|| EtcPasswd::get_entry_by_name("root".to_string()).is_some())
+    }
+}
+
+struct EtcGroup;
+libnss_group_hooks!(etcfiles, EtcGroup);
+
+impl GroupHooks for EtcGroup {
+    fn get_all_entries() -> Vec<Group> {
+        read("/etc/group")
+    }
+
+    fn get_entry_by_gid(gid: Gid) -> Option<Group> {
+        Self::get_all_entries()
+            .into_iter()
+            .find(|entry| entry.gid == gid)
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Group> {
+        Self::get_all_entries()
+            .into_iter()
+            .find(|entry| entry.name == name)
+    }
+}
+
+struct EtcShadow;
+libnss_shadow_hooks!(etcfiles, EtcShadow);
+
+impl ShadowHooks for EtcShadow {
+    fn get_all_entries() -> Vec<Shadow> {
+        read("/etc/shadow")
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Shadow> {
+        Self::get_all_entries()
+            .into_iter()
+            .find(|entry| entry.name == name)
+    }
+}
+
+struct EtcHosts;
+libnss_host_hooks!(etcfiles, EtcHosts);
+
+/// The line as an IPv4 host, which glibc takes `::1` lines to be as well, with `127.0.0.1`.
+fn ipv4(host: Host) -> Option<Host> {
+    match host.addresses {
+        Addresses::V4(_) => Some(host),
+        Addresses::V6(addrs) if addrs == [Ipv6Addr::LOCALHOST] => Some(Host {
+            addresses: Ipv4Addr::LOCALHOST.into(),
+            ..host
+        }),
+        Addresses::V6(_) => None,
+        Addresses::Mixed(_) => host.of_family(AddressFamily::IPv4),
+    }
+}
+
+impl HostHooks for EtcHosts {
+    /// One host per line, as each line holds a single address. Like glibc, only IPv4 hosts are
+    /// enumerated.
+    fn get_all_entries() -> Vec<Host> {
+        read("/etc/hosts").into_iter().filter_map(ipv4).collect()
+    }
+
+    /// Like glibc, every line naming the host contributes its addresses and aliases to the first.
+    fn get_host_by_name(name: &str, family: AddressFamily) -> Option<Host> {
+        let mut found: Option<Host> = None;
+        for line in read::<Host>("/etc/hosts") {
+            let names = Some(&line.name).into_iter().chain(&line.aliases);
+            if !names.into_iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            let line = match (&family, line.addresses) {
+                (&AddressFamily::IPv6, Addresses::V6(addrs)) => Host {
+                    addresses: Addresses::V6(addrs),
+                    ..line
+                },
+                (&AddressFamily::IPv6, Addresses::V4(_)) => continue,
+                (_, addresses) => match ipv4(Host { addresses, ..line }) {
+                    Some(line) => line,
+                    None => continue,
+                },
+            };
+
+            let host = match &mut found {
+                Some(host) => host,
+                None => {
+                    found = Some(line);
+                    continue;
+                }
+            };
+            match (&mut host.addresses, line.addresses) {
+                (Addresses::V4(all), Addresses::V4(addrs)) => all.extend(addrs),
+                (Addresses::V6(all), Addresses::V6(addrs)) => all.extend(addrs),
+                _ => {}
+            }
+            for alias in line.aliases.into_iter().chain(Some(line.name)) {
+                if alias != host.name && !host.aliases.contains(&alias) {
+                    host.aliases.push(alias);
+                }
+            }
+        }
+        found
+    }
+
+    fn get_host_by_addr(addr: IpAddr) -> Option<Host> {
+        read::<Host>("/etc/hosts")
+            .into_iter()
+            .find(|host| match (&host.addresses, addr) {
+                (Addresses::V4(addrs), IpAddr::V4(addr)) => addrs.contains(&addr),
+                (Addresses::V6(addrs), IpAddr::V6(addr)) => addrs.contains(&addr),
+                _ => false,
+            })
+    }
+}
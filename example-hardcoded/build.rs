@@ -0,0 +1,4 @@
+fn main() {
+    libnss::build::set_soname("hardcoded").unwrap();
+    libnss::build::export_nss_symbols("hardcoded").unwrap();
+}
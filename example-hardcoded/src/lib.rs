@@ -1,13 +1,13 @@
-extern crate libc;
-#[macro_use]
-extern crate lazy_static;
 #[macro_use]
 extern crate libnss;
 
+use libnss::id::{Gid, Uid};
 use libnss::passwd::{PasswdHooks, Passwd};
 use libnss::group::{GroupHooks, Group};
 use libnss::shadow::{ShadowHooks, Shadow};
-use libnss::host::{AddressFamily, Addresses, Host, HostHooks};
+use libnss::host::{AddressFamily, Addresses, Host, HostAnswer, HostHooks, NameForm};
+
+libnss_module!(hardcoded, passwd, group, shadow, host);
 
 struct HardcodedPasswd;
 libnss_passwd_hooks!(hardcoded, HardcodedPasswd);
@@ -19,9 +19,10 @@ impl PasswdHooks for HardcodedPasswd {
         vec![
             Passwd {
                 name: "test".to_string(),
+                name_bytes: None,
                 passwd: "x".to_string(),
-                uid: 1005,
-                gid: 1005,
+                uid: Uid::from_raw(1005),
+                gid: Gid::from_raw(1005),
                 gecos: "Test Account".to_string(),
                 dir: "/home/test".to_string(),
                 shell: "/bin/bash".to_string(),
@@ -29,13 +30,14 @@ impl PasswdHooks for HardcodedPasswd {
         ]
     }
 
-    fn get_entry_by_uid(uid: libc::uid_t) -> Option<Passwd> {
+    fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
         if uid == 1005 {
             return Some(Passwd {
                 name: "test".to_string(),
+                name_bytes: None,
                 passwd: "x".to_string(),
-                uid: 1005,
-                gid: 1005,
+                uid: Uid::from_raw(1005),
+                gid: Gid::from_raw(1005),
                 gecos: "Test Account".to_string(),
                 dir: "/home/test".to_string(),
                 shell: "/bin/bash".to_string(),
@@ -49,9 +51,10 @@ impl PasswdHooks for HardcodedPasswd {
         if name == "test" {
             return Some(Passwd {
                 name: "test".to_string(),
+                name_bytes: None,
                 passwd: "x".to_string(),
-                uid: 1005,
-                gid: 1005,
+                uid: Uid::from_raw(1005),
+                gid: Gid::from_raw(1005),
                 gecos: "Test Account".to_string(),
                 dir: "/home/test".to_string(),
                 shell: "/bin/bash".to_string(),
@@ -70,19 +73,21 @@ impl GroupHooks for HardcodedGroup {
         vec![
             Group {
                 name: "test".to_string(),
+                name_bytes: None,
                 passwd: "".to_string(),
-                gid: 1005,
+                gid: Gid::from_raw(1005),
                 members: vec!["someone".to_string()],
             }
         ]
     }
 
-    fn get_entry_by_gid(gid: libc::gid_t) -> Option<Group> {
+    fn get_entry_by_gid(gid: Gid) -> Option<Group> {
         if gid == 1005 {
             return Some(Group {
                 name: "test".to_string(),
+                name_bytes: None,
                 passwd: "".to_string(),
-                gid: 1005,
+                gid: Gid::from_raw(1005),
                 members: vec!["someone".to_string()],
             });
         }
@@ -94,8 +99,9 @@ impl GroupHooks for HardcodedGroup {
         if name == "test" {
             return Some(Group {
                 name: "test".to_string(),
+                name_bytes: None,
                 passwd: "".to_string(),
-                gid: 1005,
+                gid: Gid::from_raw(1005),
                 members: vec!["someone".to_string()],
             });
         }
@@ -151,11 +157,14 @@ struct HardcodedHost;
 libnss_host_hooks!(hardcoded, HardcodedHost);
 
 impl HostHooks for HardcodedHost {
+    const NAME_FORM: NameForm = NameForm::Folded;
+
     fn get_all_entries() -> Vec<Host> {
         vec![Host {
             name: "test.example".to_string(),
             addresses: Addresses::V4(vec![Ipv4Addr::new(177, 42, 42, 42)]),
             aliases: vec!["other.example".to_string()],
+            canonical_name: None,
         }]
     }
 
@@ -167,6 +176,7 @@ impl HostHooks for HardcodedHost {
                         name: "test.example".to_string(),
                         addresses: Addresses::V4(vec![Ipv4Addr::new(177, 42, 42, 42)]),
                         aliases: vec!["other.example".to_string()],
+                        canonical_name: None,
                     })
                 } else {
                     None
@@ -182,9 +192,19 @@ impl HostHooks for HardcodedHost {
                 name: name.to_string(),
                 addresses: Addresses::V4(vec![Ipv4Addr::new(177, 42, 42, 42)]),
                 aliases: vec!["test.example".to_string(), "other.example".to_string()],
+                canonical_name: None,
             })
         } else {
             None
         }
     }
+
+    // The .example names have IPv4 addresses alone, so an IPv6 lookup finds no data
+    fn resolve_host_by_name(name: &str, family: AddressFamily) -> HostAnswer {
+        match Self::get_host_by_name(name, family) {
+            Some(host) => HostAnswer::Found(host),
+            None if name.ends_with(".example") => HostAnswer::NoData,
+            None => HostAnswer::NotFound,
+        }
+    }
 }
@@ -0,0 +1,344 @@
+//! End-to-end tests of a module through glibc's NSS, which unit tests can't reach: symbol names,
+//! the `nsswitch.conf` plumbing and what `getent` or `id` actually print.
+//!
+//! A [`Sandbox`] runs programs in a mount namespace of their own (inside a user namespace too when
+//! not run as root), where `/etc/nsswitch.conf` is bind-mounted over with one naming the module and
+//! nscd's socket directory is hidden so that every lookup loads the module. The module itself is
+//! found through `LD_LIBRARY_PATH`, so nothing on the host changes and nothing needs installing.
+//!
+//! ```ignore
+//! let module = libnss_e2e::Module::build("example-hardcoded", "hardcoded")?;
+//! let sandbox = libnss_e2e::Sandbox::new(&module, &["passwd", "group"])?;
+//! assert_eq!(sandbox.getent("passwd", "test")?.as_deref(), Some("test:x:1005:..."));
+//! ```
+//!
+//! Linux only, as it needs glibc and namespaces.
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A module built from a workspace package, copied into a directory of its own under the file
+/// name glibc loads it by.
+pub struct Module {
+    name: String,
+    dir: TempDir,
+}
+
+impl Module {
+    /// Builds `package`, whose library is `libnss_<name>.so`.
+    pub fn build(package: &str, name: &str) -> io::Result<Module> {
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let status = Command::new(cargo)
+            .args(["build", "--lib", "-p", package])
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("building {} failed", package)));
+        }
+
+        let built = target_dir()
+            .join("debug")
+            .join(format!("libnss_{}.so", name));
+        let dir = TempDir::new()?;
+        fs::copy(&built, dir.0.join(format!("libnss_{}.so.2", name)))?;
+        Ok(Module {
+            name: name.to_string(),
+            dir,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+fn target_dir() -> PathBuf {
+    match env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("../target"),
+    }
+}
+
+pub struct Sandbox {
+    library_path: Option<PathBuf>,
+    nsswitch: CString,
+    nscd_dir: CString,
+    /// Files bind-mounted over others, as source and target
+    files: Vec<(CString, CString)>,
+    // Owns the files the namespace mounts
+    dir: TempDir,
+}
+
+impl Sandbox {
+    /// A sandbox whose `nsswitch.conf` has the module as the only source of each of `databases`,
+    /// and leaves every other database to the usual files. Fails with `Unsupported` if this host
+    /// can't create the namespaces.
+    pub fn new(module: &Module, databases: &[&str]) -> io::Result<Sandbox> {
+        let mut conf = String::new();
+        for database in databases {
+            conf.push_str(&format!("{}: {}\n", database, module.name));
+        }
+        for database in &["passwd", "group", "shadow", "hosts"] {
+            if !databases.contains(database) {
+                conf.push_str(&format!("{}: files\n", database));
+            }
+        }
+        Sandbox::with_nsswitch(Some(module.dir.0.clone()), &conf)
+    }
+
+    /// A sandbox where every database comes from glibc's own `files` module, to compare a module
+    /// with.
+    pub fn files() -> io::Result<Sandbox> {
+        let mut conf = String::new();
+        for database in &["passwd", "group", "shadow", "hosts"] {
+            conf.push_str(&format!("{}: files\n", database));
+        }
+        Sandbox::with_nsswitch(None, &conf)
+    }
+
+    fn with_nsswitch(library_path: Option<PathBuf>, conf: &str) -> io::Result<Sandbox> {
+        if !supported() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this host can't create mount namespaces",
+            ));
+        }
+
+        let dir = TempDir::new()?;
+        let nsswitch = dir.0.join("nsswitch.conf");
+        fs::write(&nsswitch, conf)?;
+        let nscd_dir = dir.0.join("nscd");
+        fs::create_dir(&nscd_dir)?;
+
+        Ok(Sandbox {
+            library_path,
+            nsswitch: path_cstring(&nsswitch)?,
+            nscd_dir: path_cstring(&nscd_dir)?,
+            files: Vec::new(),
+            dir,
+        })
+    }
+
+    /// Replaces `path`, an existing file such as `/etc/passwd`, with one holding `contents` for
+    /// everything run in the sandbox.
+    pub fn replace_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        let source = self.dir.0.join(format!("file-{}", self.files.len()));
+        fs::write(&source, contents)?;
+        self.files
+            .push((path_cstring(&source)?, path_cstring(Path::new(path))?));
+        Ok(())
+    }
+
+    /// Runs `program` in the sandbox.
+    pub fn run<S: AsRef<OsStr>>(&self, program: &str, args: &[S]) -> io::Result<Output> {
+        let mut command = Command::new(program);
+        command.args(args);
+        if let Some(library_path) = &self.library_path {
+            command.env("LD_LIBRARY_PATH", library_path);
+        }
+
+        let setup = NamespaceSetup::new(&self.nsswitch, &self.nscd_dir, &self.files)?;
+        unsafe {
+            command.pre_exec(move || setup.enter());
+        }
+        command.output()
+    }
+
+    /// What `getent <database> <key>` prints, without its trailing newline, or `None` if it found
+    /// nothing.
+    pub fn getent(&self, database: &str, key: &str) -> io::Result<Option<String>> {
+        self.getent_args(&[database, key])
+    }
+
+    /// Every entry `getent <database>` enumerates.
+    pub fn getent_all(&self, database: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .getent_args(&[database])?
+            .map(|out| out.lines().map(str::to_string).collect())
+            .unwrap_or_default())
+    }
+
+    fn getent_args(&self, args: &[&str]) -> io::Result<Option<String>> {
+        let output = self.run("getent", args)?;
+        match output.status.code() {
+            Some(0) => Ok(Some(
+                String::from_utf8_lossy(&output.stdout)
+                    .trim_end()
+                    .to_string(),
+            )),
+            // Key not found, or enumeration not supported
+            Some(2) | Some(3) => Ok(None),
+            _ => Err(failed("getent", &output)),
+        }
+    }
+
+    /// What `id <user>` prints.
+    pub fn id(&self, user: &str) -> io::Result<String> {
+        let output = self.run("id", &[user])?;
+        if !output.status.success() {
+            return Err(failed("id", &output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .to_string())
+    }
+}
+
+/// Whether namespaces are available here, found by trying.
+pub fn supported() -> bool {
+    let setup = match NamespaceSetup::new(cstr(b"/etc/nsswitch.conf\0"), cstr(b"/\0"), &[]) {
+        Ok(setup) => setup,
+        Err(_) => return false,
+    };
+    let mut command = Command::new("true");
+    unsafe {
+        command.pre_exec(move || setup.unshare());
+    }
+    matches!(command.status(), Ok(status) if status.success())
+}
+
+fn failed(program: &str, output: &Output) -> io::Error {
+    io::Error::other(format!(
+        "{} failed with {}: {}",
+        program,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    ))
+}
+
+fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+/// Everything the child needs to enter the namespace, prepared before forking as allocating
+/// between `fork` and `exec` can deadlock.
+struct NamespaceSetup {
+    nsswitch: CString,
+    nscd_dir: CString,
+    files: Vec<(CString, CString)>,
+    root: bool,
+    uid_map: CString,
+    gid_map: CString,
+}
+
+impl NamespaceSetup {
+    fn new(
+        nsswitch: &CStr,
+        nscd_dir: &CStr,
+        files: &[(CString, CString)],
+    ) -> io::Result<NamespaceSetup> {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Ok(NamespaceSetup {
+            nsswitch: nsswitch.to_owned(),
+            nscd_dir: nscd_dir.to_owned(),
+            files: files.to_vec(),
+            root: uid == 0,
+            uid_map: CString::new(format!("0 {} 1", uid)).map_err(io::Error::other)?,
+            gid_map: CString::new(format!("0 {} 1", gid)).map_err(io::Error::other)?,
+        })
+    }
+
+    fn unshare(&self) -> io::Result<()> {
+        let mut flags = libc::CLONE_NEWNS;
+        if !self.root {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        check(unsafe { libc::unshare(flags) })?;
+        if !self.root {
+            write_file(cstr(b"/proc/self/setgroups\0"), cstr(b"deny\0"))?;
+            write_file(cstr(b"/proc/self/uid_map\0"), &self.uid_map)?;
+            write_file(cstr(b"/proc/self/gid_map\0"), &self.gid_map)?;
+        }
+        Ok(())
+    }
+
+    fn enter(&self) -> io::Result<()> {
+        self.unshare()?;
+        unsafe {
+            // Keep the mounts below from propagating back to the host
+            check(libc::mount(
+                std::ptr::null(),
+                cstr(b"/\0").as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            bind(&self.nsswitch, cstr(b"/etc/nsswitch.conf\0"))?;
+            for (source, target) in &self.files {
+                bind(source, target)?;
+            }
+            // glibc asks a running nscd before loading any module
+            for nscd in [cstr(b"/run/nscd\0"), cstr(b"/var/run/nscd\0")] {
+                if libc::access(nscd.as_ptr(), libc::F_OK) == 0 {
+                    bind(&self.nscd_dir, nscd)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+unsafe fn bind(source: &CStr, target: &CStr) -> io::Result<()> {
+    check(libc::mount(
+        source.as_ptr(),
+        target.as_ptr(),
+        std::ptr::null(),
+        libc::MS_BIND,
+        std::ptr::null(),
+    ))
+}
+
+fn write_file(path: &CStr, contents: &CStr) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+        check(fd)?;
+        let bytes = contents.to_bytes();
+        let written = libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn cstr(bytes: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// A directory under the system's temporary one, removed on drop.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<TempDir> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "libnss-e2e-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
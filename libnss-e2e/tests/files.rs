@@ -0,0 +1,190 @@
+//! The `example-files` module against glibc's own `files` module, both reading the same files, so
+//! that any difference in what `getent` and `id` print shows where libnss parses, converts or
+//! lays out entries differently from glibc.
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::sync::OnceLock;
+
+use libnss_e2e::{Module, Sandbox};
+
+const PASSWD: &str = "\
+# comment
+root:x:0:0:root:/root:/bin/bash
+
+test:x:1005:1005:Test Account,Room 1,,:/home/test:/bin/bash
+empty::1006:1006:::
+dup:x:1007:1007:First:/home/dup:/bin/sh
+dup:x:1008:1008:Second:/home/dup2:/bin/sh
+unicode:x:1009:1009:Zoë Ünïcödé:/home/unicode:/bin/zsh
+  indented:x:1010:1010::/home/indented:/bin/sh
+broken:x:notanumber:1:::
+short:x:1012
+";
+
+const GROUP: &str = "\
+root:x:0:
+test::1005:someone,test
+many:x:1006:a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p
+gaps:x:1007:a,,b
+";
+
+const SHADOW: &str = "\
+root:*:19000:0:99999:7:::
+test:$6$salt$hash:19000:0:99999:7:30:20000:
+empty::::::::
+";
+
+const HOSTS: &str = "\
+127.0.0.1\tlocalhost
+::1\tlocalhost ip6-localhost ip6-loopback
+192.0.2.1   test.example test   # trailing comment
+192.0.2.2   test.example other
+2001:db8::1 test.example
+198.51.100.7 UPPER.example upper
+";
+
+/// A sandbox for glibc's files module and one for `example-files`, both seeing the fixtures in
+/// place of the real files, or `None` to skip where namespaces aren't available, unless
+/// `LIBNSS_E2E_REQUIRED` is set to make that a failure.
+fn sandboxes() -> Option<(Sandbox, Sandbox)> {
+    static MODULE: OnceLock<Module> = OnceLock::new();
+    let module = MODULE.get_or_init(|| Module::build("example-files", "etcfiles").unwrap());
+    let sandboxes = Sandbox::files().and_then(|files| {
+        let databases = ["passwd", "group", "shadow", "hosts"];
+        Ok((files, Sandbox::new(module, &databases)?))
+    });
+    let (mut files, mut etcfiles) = match sandboxes {
+        Ok(sandboxes) => sandboxes,
+        Err(err) if env::var_os("LIBNSS_E2E_REQUIRED").is_none() => {
+            eprintln!("skipping: {}", err);
+            return None;
+        }
+        Err(err) => panic!("{}", err),
+    };
+
+    // A group too big for the buffer getent starts with, which NSS has to retry with bigger ones
+    let members: Vec<String> = (0..400).map(|i| format!("member{}", i)).collect();
+    let group = format!("{}huge:x:1008:{}\n", GROUP, members.join(","));
+
+    for sandbox in [&mut files, &mut etcfiles] {
+        sandbox.replace_file("/etc/passwd", PASSWD).unwrap();
+        sandbox.replace_file("/etc/group", &group).unwrap();
+        sandbox.replace_file("/etc/shadow", SHADOW).unwrap();
+        sandbox.replace_file("/etc/hosts", HOSTS).unwrap();
+    }
+    Some((files, etcfiles))
+}
+
+/// Looks each of `keys` up in `database` through both modules, then enumerates it through both.
+fn compare(database: &str, keys: &[&str]) {
+    let (files, etcfiles) = match sandboxes() {
+        Some(sandboxes) => sandboxes,
+        None => return,
+    };
+    for key in keys {
+        assert_eq!(
+            etcfiles.getent(database, key).unwrap(),
+            files.getent(database, key).unwrap(),
+            "getent {} {}",
+            database,
+            key
+        );
+    }
+    assert_eq!(
+        etcfiles.getent_all(database).unwrap(),
+        files.getent_all(database).unwrap(),
+        "getent {}",
+        database
+    );
+}
+
+#[test]
+fn passwd() {
+    compare(
+        "passwd",
+        &[
+            "root",
+            "0",
+            "test",
+            "1005",
+            "empty",
+            "dup",
+            "1008",
+            "unicode",
+            "indented",
+            "broken",
+            "short",
+            "1012",
+            "nobody-here",
+            "4242",
+        ],
+    );
+}
+
+#[test]
+fn group() {
+    compare(
+        "group",
+        &[
+            "root",
+            "test",
+            "1005",
+            "many",
+            "gaps",
+            "1007",
+            "huge",
+            "1008",
+            "nobody-here",
+        ],
+    );
+}
+
+#[test]
+fn shadow() {
+    compare("shadow", &["root", "test", "empty", "nobody-here"]);
+}
+
+#[test]
+fn hosts() {
+    compare(
+        "hosts",
+        &[
+            "localhost",
+            "ip6-localhost",
+            "test.example",
+            "TEST.example",
+            "test",
+            "other",
+            "upper.example",
+            "192.0.2.1",
+            "192.0.2.2",
+            "2001:db8::1",
+            "::1",
+            "203.0.113.1",
+            "nowhere.example",
+        ],
+    );
+    compare("ahostsv4", &["localhost", "test.example", "upper.example"]);
+    compare("ahostsv6", &["localhost", "test.example", "upper.example"]);
+    // Not localhost: glibc answers `AF_UNSPEC` from files with `gethostbyname4_r`, which libnss
+    // doesn't provide, so getaddrinfo asks for each family instead and the IPv4 answer counts
+    // the `::1` line as `127.0.0.1` a second time
+    compare("ahosts", &["test.example", "upper.example"]);
+}
+
+#[test]
+fn id() {
+    let (files, etcfiles) = match sandboxes() {
+        Some(sandboxes) => sandboxes,
+        None => return,
+    };
+    for user in ["root", "test", "dup"] {
+        assert_eq!(
+            etcfiles.id(user).unwrap(),
+            files.id(user).unwrap(),
+            "id {}",
+            user
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! The `example-hardcoded` module, looked up through glibc.
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::sync::OnceLock;
+
+use libnss_e2e::{Module, Sandbox};
+
+/// The sandbox, or `None` to skip where namespaces aren't available, unless
+/// `LIBNSS_E2E_REQUIRED` is set to make that a failure.
+fn sandbox(databases: &[&str]) -> Option<Sandbox> {
+    static MODULE: OnceLock<Module> = OnceLock::new();
+    let module = MODULE.get_or_init(|| Module::build("example-hardcoded", "hardcoded").unwrap());
+    match Sandbox::new(module, databases) {
+        Ok(sandbox) => Some(sandbox),
+        Err(err) if env::var_os("LIBNSS_E2E_REQUIRED").is_none() => {
+            eprintln!("skipping: {}", err);
+            None
+        }
+        Err(err) => panic!("{}", err),
+    }
+}
+
+const TEST_PASSWD: &str = "test:x:1005:1005:Test Account:/home/test:/bin/bash";
+
+#[test]
+fn passwd() {
+    let sandbox = match sandbox(&["passwd"]) {
+        Some(sandbox) => sandbox,
+        None => return,
+    };
+    assert_eq!(
+        sandbox.getent("passwd", "test").unwrap().as_deref(),
+        Some(TEST_PASSWD)
+    );
+    assert_eq!(
+        sandbox.getent("passwd", "1005").unwrap().as_deref(),
+        Some(TEST_PASSWD)
+    );
+    assert_eq!(sandbox.getent("passwd", "nobody-here").unwrap(), None);
+    assert_eq!(sandbox.getent_all("passwd").unwrap(), vec![TEST_PASSWD]);
+}
+
+#[test]
+fn group() {
+    let sandbox = match sandbox(&["group"]) {
+        Some(sandbox) => sandbox,
+        None => return,
+    };
+    assert_eq!(
+        sandbox.getent("group", "test").unwrap().as_deref(),
+        Some("test::1005:someone")
+    );
+    assert_eq!(
+        sandbox.getent("group", "1005").unwrap().as_deref(),
+        Some("test::1005:someone")
+    );
+    assert_eq!(sandbox.getent("group", "1006").unwrap(), None);
+}
+
+#[test]
+fn shadow() {
+    let sandbox = match sandbox(&["shadow"]) {
+        Some(sandbox) => sandbox,
+        None => return,
+    };
+    let entry = sandbox.getent("shadow", "test").unwrap().unwrap();
+    assert!(entry.starts_with("test:$6$KEnq4G3CxkA2iU$"), "{}", entry);
+}
+
+#[test]
+fn id() {
+    let sandbox = match sandbox(&["passwd", "group"]) {
+        Some(sandbox) => sandbox,
+        None => return,
+    };
+    assert_eq!(
+        sandbox.id("test").unwrap(),
+        "uid=1005(test) gid=1005(test) groups=1005(test)"
+    );
+}
+
+#[test]
+fn hosts() {
+    let sandbox = match sandbox(&["hosts"]) {
+        Some(sandbox) => sandbox,
+        None => return,
+    };
+    let host = sandbox.getent("hosts", "test.example").unwrap().unwrap();
+    assert!(host.starts_with("177.42.42.42 "), "{}", host);
+    let host = sandbox.getent("hosts", "177.42.42.42").unwrap().unwrap();
+    assert!(host.contains("test.example"), "{}", host);
+    assert_eq!(sandbox.getent("hosts", "test.invalid").unwrap(), None);
+
+    // The hooks ask for names folded as DNS compares them
+    let host = sandbox.getent("hosts", "TEST.Example.").unwrap().unwrap();
+    assert!(host.ends_with(" test.example other.example"), "{}", host);
+
+    // getaddrinfo, as most programs resolve names
+    let addrinfo = sandbox.getent("ahostsv4", "test.example").unwrap().unwrap();
+    assert!(addrinfo.starts_with("177.42.42.42 "), "{}", addrinfo);
+}
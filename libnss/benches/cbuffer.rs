@@ -0,0 +1,98 @@
+//! What zeroing the caller's buffer before every entry costs, against writing the entry alone.
+//!
+//! `cargo bench --bench cbuffer` prints the time per entry for the buffer sizes glibc passes: 1 KiB
+//! to start with, then larger ones each time the module answers `ERANGE`.
+
+use std::hint::black_box;
+use std::mem::MaybeUninit;
+use std::time::{Duration, Instant};
+
+use libnss::group::{CGroup, Group};
+use libnss::id::{Gid, Uid};
+use libnss::interop::CBuffer;
+use libnss::libc::c_void;
+use libnss::passwd::{CPasswd, Passwd};
+
+const ITERATIONS: u32 = 100_000;
+
+/// Word-backed, so the buffer starts aligned as glibc's does.
+fn buffer(len: usize) -> Vec<usize> {
+    vec![0; len.div_ceil(std::mem::size_of::<usize>())]
+}
+
+fn time<F: FnMut()>(mut write: F) -> Duration {
+    for _ in 0..ITERATIONS / 10 {
+        write();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        write();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+#[allow(deprecated)]
+fn passwd(len: usize, clear: bool) -> Duration {
+    let entry = Passwd {
+        name: "alice".to_string(),
+        name_bytes: None,
+        passwd: "x".to_string(),
+        uid: Uid::from_raw(1000),
+        gid: Gid::from_raw(1000),
+        gecos: "Alice Example,,,".to_string(),
+        dir: "/home/alice".to_string(),
+        shell: "/bin/bash".to_string(),
+    };
+    let mut data = buffer(len);
+    let mut result = MaybeUninit::<CPasswd>::zeroed();
+    time(|| unsafe {
+        let mut buffer = CBuffer::new(data.as_mut_ptr() as *mut c_void, len);
+        if clear {
+            buffer.clear();
+        }
+        entry.clone().to_c_passwd(result.as_mut_ptr(), &mut buffer);
+        black_box(&result);
+    })
+}
+
+#[allow(deprecated)]
+fn group(len: usize, clear: bool) -> Duration {
+    let entry = Group {
+        name: "developers".to_string(),
+        name_bytes: None,
+        passwd: "x".to_string(),
+        gid: Gid::from_raw(2000),
+        members: (0..16).map(|i| format!("user{}", i)).collect(),
+    };
+    let mut data = buffer(len);
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    time(|| unsafe {
+        let mut buffer = CBuffer::new(data.as_mut_ptr() as *mut c_void, len);
+        if clear {
+            buffer.clear();
+        }
+        entry.clone().to_c_group(result.as_mut_ptr(), &mut buffer);
+        black_box(&result);
+    })
+}
+
+fn main() {
+    println!(
+        "{:<8} {:>8} {:>14} {:>14}",
+        "entry", "buffer", "clear + write", "write"
+    );
+    for len in [1024, 4096, 16384, 65536] {
+        for (name, bench) in [
+            ("passwd", passwd as fn(usize, bool) -> Duration),
+            ("group", group),
+        ] {
+            println!(
+                "{:<8} {:>8} {:>14?} {:>14?}",
+                name,
+                len,
+                bench(len, true),
+                bench(len, false)
+            );
+        }
+    }
+}
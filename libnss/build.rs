@@ -0,0 +1,14 @@
+fn main() {
+    println!("cargo:rerun-if-changed=src/nsdispatch");
+
+    // Cargo runs build scripts on the host, so check the target rather than using cfg!
+    match std::env::var("CARGO_CFG_TARGET_OS").as_deref() {
+        Ok("freebsd") => compile_nsdispatch("src/nsdispatch/freebsd.c"),
+        Ok("netbsd") => compile_nsdispatch("src/nsdispatch/netbsd.c"),
+        _ => {}
+    }
+}
+
+fn compile_nsdispatch(file: &str) {
+    cc::Build::new().file(file).compile("libnss_rs_nsdispatch");
+}
@@ -0,0 +1,445 @@
+//! Destination address ordering from RFC 6724, section 6, for backends returning several
+//! addresses for a host.
+//!
+//! Callers try addresses in the order they are given, so putting the ones reachable from a
+//! matching source address first saves them timing out on, say, a global IPv6 address from a host
+//! with only IPv4 connectivity. The source address for each destination is the one the kernel
+//! would pick, found the same way glibc's `getaddrinfo` does by connecting a UDP socket (which
+//! sends nothing).
+//!
+//! Rules 3, 4 and 7 need interface details the kernel doesn't report through sockets, so like
+//! most implementations they are skipped. The default policy table is used.
+
+use std::cmp::Ordering;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+/// Sorts `addrs` in place, most preferred first. Addresses that compare equal keep their order.
+pub fn sort(addrs: &mut [IpAddr]) {
+    sort_with_sources(addrs, source_address)
+}
+
+/// Sorts `addrs` as [`sort`] does, with `source` giving the source address each destination would
+/// be reached from, or `None` if it is unreachable.
+pub fn sort_with_sources<F>(addrs: &mut [IpAddr], mut source: F)
+where
+    F: FnMut(IpAddr) -> Option<IpAddr>,
+{
+    let mut keyed: Vec<(IpAddr, Option<Ipv6Addr>)> = addrs
+        .iter()
+        .map(|addr| (*addr, source(*addr).map(|source| to_ipv6(&source))))
+        .collect();
+    keyed.sort_by(|a, b| compare(&to_ipv6(&a.0), a.1.as_ref(), &to_ipv6(&b.0), b.1.as_ref()));
+    for (slot, (addr, _)) in addrs.iter_mut().zip(keyed) {
+        *slot = addr;
+    }
+}
+
+/// The source address the kernel would use to reach `dest`.
+pub fn source_address(dest: IpAddr) -> Option<IpAddr> {
+    let unspecified: IpAddr = match dest {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).ok()?;
+    // Any port will do, as connecting a UDP socket only picks a route
+    socket.connect(SocketAddr::new(dest, 9)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// `Less` when `a` is preferred over `b`.
+fn compare(a: &Ipv6Addr, sa: Option<&Ipv6Addr>, b: &Ipv6Addr, sb: Option<&Ipv6Addr>) -> Ordering {
+    let (sa, sb) = match (sa, sb) {
+        // Rule 1: avoid unusable destinations
+        (Some(_), None) => return Ordering::Less,
+        (None, Some(_)) => return Ordering::Greater,
+        (None, None) => return Ordering::Equal,
+        (Some(sa), Some(sb)) => (sa, sb),
+    };
+    let (policy_a, policy_b) = (policy(a), policy(b));
+
+    // Rule 2: prefer matching scope
+    let prefer = |a_matches: bool, b_matches: bool| b_matches.cmp(&a_matches);
+    prefer(scope(a) == scope(sa), scope(b) == scope(sb))
+        // Rule 5: prefer matching label
+        .then_with(|| {
+            prefer(
+                policy_a.label == policy(sa).label,
+                policy_b.label == policy(sb).label,
+            )
+        })
+        // Rule 6: prefer higher precedence
+        .then_with(|| policy_b.precedence.cmp(&policy_a.precedence))
+        // Rule 8: prefer smaller scope
+        .then_with(|| scope(a).cmp(&scope(b)))
+        // Rule 9: use longest matching prefix, between IPv6 addresses only
+        .then_with(|| {
+            if a.to_ipv4_mapped().is_some() || b.to_ipv4_mapped().is_some() {
+                return Ordering::Equal;
+            }
+            common_prefix(b, sb).cmp(&common_prefix(a, sa))
+        })
+    // Rule 10: otherwise leave the order unchanged
+}
+
+pub(crate) fn to_ipv6(addr: &IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => *addr,
+    }
+}
+
+const SCOPE_LINK_LOCAL: u8 = 0x2;
+const SCOPE_SITE_LOCAL: u8 = 0x5;
+const SCOPE_GLOBAL: u8 = 0xe;
+
+/// The scope of RFC 6724 section 3.1, with IPv4 addresses given the scopes of section 3.2.
+fn scope(addr: &Ipv6Addr) -> u8 {
+    if let Some(v4) = addr.to_ipv4_mapped() {
+        return if v4.is_loopback() || v4.is_link_local() {
+            SCOPE_LINK_LOCAL
+        } else {
+            SCOPE_GLOBAL
+        };
+    }
+
+    let segments = addr.segments();
+    if segments[0] >> 8 == 0xff {
+        // Multicast carries its scope
+        (segments[0] & 0xf) as u8
+    } else if addr.is_loopback() || segments[0] & 0xffc0 == 0xfe80 {
+        SCOPE_LINK_LOCAL
+    } else if segments[0] & 0xffc0 == 0xfec0 {
+        SCOPE_SITE_LOCAL
+    } else {
+        SCOPE_GLOBAL
+    }
+}
+
+struct Policy {
+    precedence: u8,
+    label: u8,
+}
+
+/// The default policy table of RFC 6724 section 2.1, longest prefix first.
+const POLICY_TABLE: &[(Ipv6Addr, u8, Policy)] = &[
+    (
+        Ipv6Addr::LOCALHOST,
+        128,
+        Policy {
+            precedence: 50,
+            label: 0,
+        },
+    ),
+    (
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0),
+        96,
+        Policy {
+            precedence: 35,
+            label: 4,
+        },
+    ),
+    (
+        Ipv6Addr::UNSPECIFIED,
+        96,
+        Policy {
+            precedence: 1,
+            label: 3,
+        },
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0),
+        32,
+        Policy {
+            precedence: 5,
+            label: 5,
+        },
+    ),
+    (
+        Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0),
+        16,
+        Policy {
+            precedence: 30,
+            label: 2,
+        },
+    ),
+    (
+        Ipv6Addr::new(0x3ffe, 0, 0, 0, 0, 0, 0, 0),
+        16,
+        Policy {
+            precedence: 1,
+            label: 12,
+        },
+    ),
+    (
+        Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0),
+        10,
+        Policy {
+            precedence: 1,
+            label: 11,
+        },
+    ),
+    (
+        Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0),
+        7,
+        Policy {
+            precedence: 3,
+            label: 13,
+        },
+    ),
+    (
+        Ipv6Addr::UNSPECIFIED,
+        0,
+        Policy {
+            precedence: 40,
+            label: 1,
+        },
+    ),
+];
+
+fn policy(addr: &Ipv6Addr) -> &'static Policy {
+    POLICY_TABLE
+        .iter()
+        .find(|(prefix, len, _)| common_prefix(addr, prefix) >= *len)
+        .map(|(_, _, policy)| policy)
+        .expect("::/0 matches every address")
+}
+
+/// How many leading bits `a` and `b` share.
+fn common_prefix(a: &Ipv6Addr, b: &Ipv6Addr) -> u8 {
+    (u128::from(*a) ^ u128::from(*b)).leading_zeros() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A destination, with the source address it would be reached from, if any.
+    type Dest = (&'static str, Option<&'static str>);
+
+    /// Sorts `dests`.
+    fn sorted(dests: &[Dest]) -> Vec<IpAddr> {
+        let sources: Vec<(IpAddr, Option<IpAddr>)> = dests
+            .iter()
+            .map(|(dest, source)| {
+                (
+                    dest.parse().unwrap(),
+                    source.map(|source| source.parse().unwrap()),
+                )
+            })
+            .collect();
+        let mut addrs: Vec<IpAddr> = sources.iter().map(|(dest, _)| *dest).collect();
+        sort_with_sources(&mut addrs, |dest| {
+            sources.iter().find(|(d, _)| *d == dest).unwrap().1
+        });
+        addrs
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn rules() {
+        let cases: &[(&str, &[Dest], &[&str])] = &[
+            // The examples of RFC 6724 section 10.2
+            (
+                "matching scope, over IPv4 from a link-local source",
+                &[
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                    ("198.51.100.121", Some("169.254.13.78")),
+                ],
+                &["2001:db8:1::1", "198.51.100.121"],
+            ),
+            (
+                "matching scope, over IPv6 from a link-local source",
+                &[
+                    ("2001:db8:1::1", Some("fe80::1")),
+                    ("198.51.100.121", Some("198.51.100.117")),
+                ],
+                &["198.51.100.121", "2001:db8:1::1"],
+            ),
+            (
+                "higher precedence, IPv6 over private IPv4",
+                &[
+                    ("10.1.2.3", Some("10.1.2.4")),
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                ],
+                &["2001:db8:1::1", "10.1.2.3"],
+            ),
+            (
+                "smaller scope",
+                &[
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                    ("fe80::1", Some("fe80::2")),
+                ],
+                &["fe80::1", "2001:db8:1::1"],
+            ),
+            (
+                "longest matching prefix",
+                &[
+                    ("2001:db8:3ffe::1", Some("2001:db8:3f44::2")),
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                ],
+                &["2001:db8:1::1", "2001:db8:3ffe::1"],
+            ),
+            (
+                "matching label, 6to4 from a 6to4 source",
+                &[
+                    ("2001:db8:1::1", Some("2002:c633:6401::2")),
+                    ("2002:c633:6401::1", Some("2002:c633:6401::2")),
+                ],
+                &["2002:c633:6401::1", "2001:db8:1::1"],
+            ),
+            (
+                "higher precedence, native IPv6 over 6to4",
+                &[
+                    ("2002:c633:6401::1", Some("2002:c633:6401::2")),
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                ],
+                &["2001:db8:1::1", "2002:c633:6401::1"],
+            ),
+            // Loopback
+            (
+                "IPv6 loopback over IPv4 loopback",
+                &[("127.0.0.1", Some("127.0.0.1")), ("::1", Some("::1"))],
+                &["::1", "127.0.0.1"],
+            ),
+            (
+                "loopback over global, by scope",
+                &[
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                    ("::1", Some("::1")),
+                ],
+                &["::1", "2001:db8:1::1"],
+            ),
+            // Unique local addresses are global in scope but below IPv4 in precedence
+            (
+                "global IPv6 over ULA",
+                &[
+                    ("fd00:1::1", Some("fd00:1::2")),
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                ],
+                &["2001:db8:1::1", "fd00:1::1"],
+            ),
+            (
+                "IPv4 over ULA",
+                &[
+                    ("fd00:1::1", Some("fd00:1::2")),
+                    ("198.51.100.1", Some("198.51.100.2")),
+                ],
+                &["198.51.100.1", "fd00:1::1"],
+            ),
+            (
+                "ULA from a global source, by label",
+                &[
+                    ("fd00:1::1", Some("2001:db8:1::2")),
+                    ("fd00:2::1", Some("fd00:2::2")),
+                ],
+                &["fd00:2::1", "fd00:1::1"],
+            ),
+            // Link-local
+            (
+                "IPv4 link-local over global, by scope",
+                &[
+                    ("198.51.100.1", Some("198.51.100.2")),
+                    ("169.254.1.1", Some("169.254.1.2")),
+                ],
+                &["169.254.1.1", "198.51.100.1"],
+            ),
+            (
+                "IPv6 link-local from a global source",
+                &[
+                    ("fe80::1", Some("2001:db8:1::2")),
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                ],
+                &["2001:db8:1::1", "fe80::1"],
+            ),
+            // IPv4-mapped addresses sort as the IPv4 addresses they are
+            (
+                "IPv4-mapped like IPv4",
+                &[
+                    ("::ffff:198.51.100.1", Some("198.51.100.2")),
+                    ("2001:db8:1::1", Some("2001:db8:1::2")),
+                ],
+                &["2001:db8:1::1", "::ffff:198.51.100.1"],
+            ),
+            (
+                "no prefix matching between IPv4 addresses",
+                &[
+                    ("203.0.113.1", Some("198.51.100.2")),
+                    ("198.51.100.1", Some("198.51.100.2")),
+                ],
+                &["203.0.113.1", "198.51.100.1"],
+            ),
+            // Unreachable destinations
+            (
+                "unreachable last",
+                &[
+                    ("2001:db8:1::1", None),
+                    ("198.51.100.1", Some("198.51.100.2")),
+                    ("2001:db8:2::1", None),
+                ],
+                &["198.51.100.1", "2001:db8:1::1", "2001:db8:2::1"],
+            ),
+            (
+                "nothing reachable, order unchanged",
+                &[("2001:db8:1::1", None), ("198.51.100.1", None)],
+                &["2001:db8:1::1", "198.51.100.1"],
+            ),
+        ];
+
+        for (rule, dests, expected) in cases {
+            assert_eq!(sorted(dests), addrs(expected), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn scopes() {
+        let cases = [
+            ("::1", SCOPE_LINK_LOCAL),
+            ("fe80::1", SCOPE_LINK_LOCAL),
+            ("febf::1", SCOPE_LINK_LOCAL),
+            ("fec0::1", SCOPE_SITE_LOCAL),
+            ("fd00::1", SCOPE_GLOBAL),
+            ("2001:db8::1", SCOPE_GLOBAL),
+            ("ff01::1", 0x1),
+            ("ff02::1", SCOPE_LINK_LOCAL),
+            ("ff05::1", SCOPE_SITE_LOCAL),
+            ("ff0e::1", SCOPE_GLOBAL),
+            ("127.0.0.1", SCOPE_LINK_LOCAL),
+            ("169.254.1.1", SCOPE_LINK_LOCAL),
+            ("10.0.0.1", SCOPE_GLOBAL),
+            ("198.51.100.1", SCOPE_GLOBAL),
+        ];
+        for (addr, expected) in cases {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(scope(&to_ipv6(&addr)), expected, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let cases = [
+            ("::1", 50, 0),
+            ("127.0.0.1", 35, 4),
+            ("::192.0.2.1", 1, 3),
+            ("2001::1", 5, 5),
+            ("2002:c633:6401::1", 30, 2),
+            ("3ffe::1", 1, 12),
+            ("fec0::1", 1, 11),
+            ("fd00::1", 3, 13),
+            ("fc00::1", 3, 13),
+            ("2001:db8::1", 40, 1),
+        ];
+        for (addr, precedence, label) in cases {
+            let addr: IpAddr = addr.parse().unwrap();
+            let policy = policy(&to_ipv6(&addr));
+            assert_eq!(
+                (policy.precedence, policy.label),
+                (precedence, label),
+                "{}",
+                addr
+            );
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! [proptest] generators for the entry types, behind the `proptest` feature, so that conversions
+//! and round trips can be checked against many entries rather than a couple of fixtures.
+//!
+//! The [`Arbitrary`] impls produce entries that pass [`Validate`](crate::validate::Validate), as a
+//! well behaved backend's would. The `adversarial_*` strategies produce what a careless one might
+//! return instead: separators, NULs and newlines in any field, empty names, very long strings and
+//! empty or huge lists.
+//!
+//! ```
+//! use libnss::files::FilesEntry;
+//! use libnss::passwd::Passwd;
+//! use proptest::prelude::*;
+//!
+//! proptest!(|(entry in any::<Passwd>())| {
+//!     let line = entry.to_line().unwrap();
+//!     prop_assert_eq!(Passwd::from_line(&line).unwrap(), entry);
+//! });
+//! ```
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use proptest::arbitrary::{any, Arbitrary};
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prop_oneof;
+use proptest::strategy::{BoxedStrategy, Just, Strategy};
+use proptest::string::string_regex;
+
+use crate::group::Group;
+use crate::host::{Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+
+impl Arbitrary for Uid {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Uid>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<libc::uid_t>().prop_map(Uid::from_raw).boxed()
+    }
+}
+
+impl Arbitrary for Gid {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Gid>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<libc::gid_t>().prop_map(Gid::from_raw).boxed()
+    }
+}
+
+/// A field none of the formats would misread: no NUL or newline, and none of `separators`, which
+/// is the inside of a regex character class.
+fn field(separators: &str, len: &str) -> BoxedStrategy<String> {
+    string_regex(&format!("[^\\x00\\n{}]{{{}}}", separators, len))
+        .unwrap()
+        .boxed()
+}
+
+/// A name, which also doesn't start with whitespace, a comment or a NIS compat `+` or `-`.
+fn name(separators: &str) -> BoxedStrategy<String> {
+    let first = string_regex(&format!("[^\\x00\\n\\s#+\\-{}]", separators)).unwrap();
+    (first, field(separators, "0,31"))
+        .prop_map(|(first, rest)| first + &rest)
+        .boxed()
+}
+
+/// A field with anything in it, weighted towards the characters some format gives a meaning.
+fn adversarial_field() -> BoxedStrategy<String> {
+    prop_oneof![
+        4 => "[a-z0-9:,# \\t\\n\\x00]{0,32}",
+        2 => "(?s).{0,32}",
+        1 => Just(String::new()),
+        // Longer than any caller's first buffer
+        1 => "[a-z]{1000,5000}",
+    ]
+    .boxed()
+}
+
+impl Arbitrary for Passwd {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Passwd>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        let text = || field(":", "0,32");
+        (
+            name(":"),
+            text(),
+            any::<Uid>(),
+            any::<Gid>(),
+            text(),
+            text(),
+            text(),
+        )
+            .prop_map(|(name, passwd, uid, gid, gecos, dir, shell)| Passwd {
+                name,
+                name_bytes: None,
+                passwd,
+                uid,
+                gid,
+                gecos,
+                dir,
+                shell,
+            })
+            .boxed()
+    }
+}
+
+pub fn adversarial_passwd() -> BoxedStrategy<Passwd> {
+    (
+        adversarial_field(),
+        adversarial_field(),
+        any::<Uid>(),
+        any::<Gid>(),
+        adversarial_field(),
+        adversarial_field(),
+        adversarial_field(),
+    )
+        .prop_map(|(name, passwd, uid, gid, gecos, dir, shell)| Passwd {
+            name,
+            name_bytes: None,
+            passwd,
+            uid,
+            gid,
+            gecos,
+            dir,
+            shell,
+        })
+        .boxed()
+}
+
+impl Arbitrary for Group {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Group>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            name(":"),
+            field(":", "0,32"),
+            any::<Gid>(),
+            vec(name(":,"), 0..8),
+        )
+            .prop_map(|(name, passwd, gid, members)| Group {
+                name,
+                name_bytes: None,
+                passwd,
+                gid,
+                members,
+            })
+            .boxed()
+    }
+}
+
+pub fn adversarial_group() -> BoxedStrategy<Group> {
+    (
+        adversarial_field(),
+        adversarial_field(),
+        any::<Gid>(),
+        prop_oneof![
+            4 => vec(adversarial_field(), 0..8),
+            1 => vec("[a-z]{1,32}", 1000..5000),
+        ],
+    )
+        .prop_map(|(name, passwd, gid, members)| Group {
+            name,
+            name_bytes: None,
+            passwd,
+            gid,
+            members,
+        })
+        .boxed()
+}
+
+/// The numeric fields, where -1 (or `u64::MAX` for the reserved one) stands for an empty field.
+fn shadow_numbers() -> impl Strategy<Value = ([i64; 6], u64)> {
+    let day = || prop_oneof![Just(-1i64), any::<i64>()];
+    (
+        [day(), day(), day(), day(), day(), day()],
+        prop_oneof![Just(u64::MAX), any::<u64>()],
+    )
+}
+
+fn shadow(name: String, passwd: String, numbers: [i64; 6], reserved: u64) -> Shadow {
+    Shadow {
+        name,
+        passwd,
+        last_change: numbers[0],
+        change_min_days: numbers[1],
+        change_max_days: numbers[2],
+        change_warn_days: numbers[3],
+        change_inactive_days: numbers[4],
+        expire_date: numbers[5],
+        reserved,
+    }
+}
+
+impl Arbitrary for Shadow {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Shadow>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (name(":"), field(":", "0,32"), shadow_numbers())
+            .prop_map(|(name, passwd, (numbers, reserved))| shadow(name, passwd, numbers, reserved))
+            .boxed()
+    }
+}
+
+pub fn adversarial_shadow() -> BoxedStrategy<Shadow> {
+    (adversarial_field(), adversarial_field(), shadow_numbers())
+        .prop_map(|(name, passwd, (numbers, reserved))| shadow(name, passwd, numbers, reserved))
+        .boxed()
+}
+
+impl Arbitrary for Addresses {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Addresses>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            vec(any::<Ipv4Addr>(), 1..8).prop_map(Addresses::V4),
+            vec(any::<Ipv6Addr>(), 1..8).prop_map(Addresses::V6),
+        ]
+        .boxed()
+    }
+}
+
+pub fn adversarial_addresses() -> BoxedStrategy<Addresses> {
+    prop_oneof![
+        Just(Addresses::V4(Vec::new())),
+        Just(Addresses::V6(Vec::new())),
+        vec(any::<Ipv4Addr>(), 1000..5000).prop_map(Addresses::V4),
+        vec(any::<Ipv6Addr>(), 1000..5000).prop_map(Addresses::V6),
+    ]
+    .boxed()
+}
+
+/// The hosts format splits on any whitespace, not just the spaces and tabs `Validate` rejects.
+const HOST_SEPARATORS: &str = "\\s#";
+
+impl Arbitrary for Host {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Host>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            name(HOST_SEPARATORS),
+            vec(name(HOST_SEPARATORS), 0..4),
+            any::<Addresses>(),
+            option::of(name(HOST_SEPARATORS)),
+        )
+            .prop_map(|(name, aliases, addresses, canonical_name)| Host {
+                name,
+                aliases,
+                addresses,
+                canonical_name,
+            })
+            .boxed()
+    }
+}
+
+pub fn adversarial_host() -> BoxedStrategy<Host> {
+    (
+        adversarial_field(),
+        prop_oneof![
+            4 => vec(adversarial_field(), 0..4),
+            1 => vec("[a-z]{1,32}", 1000..5000),
+        ],
+        prop_oneof![any::<Addresses>(), adversarial_addresses()],
+        option::of(adversarial_field()),
+    )
+        .prop_map(|(name, aliases, addresses, canonical_name)| Host {
+            name,
+            aliases,
+            addresses,
+            canonical_name,
+        })
+        .boxed()
+}
@@ -0,0 +1,188 @@
+//! An opt-in record of who looked up what, for modules replacing sssd where security teams expect
+//! one.
+//!
+//! [`Audited`] wraps any [`NssDatabase`] and reports each lookup to an [`AuditSink`]: the calling
+//! process, the key and whether anything was found. Entries themselves are never recorded, so
+//! neither are the password hashes in them. The default sink writes to syslog's `authpriv`
+//! facility under the calling program's own name, where auditd's syslog plugin and most log
+//! shippers already look.
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! use libnss::audit::Audited;
+//! use libnss::database::PasswdDatabase;
+//!
+//! // Usable anywhere passwd hooks are, e.g. `libnss_passwd_hooks!(example, ExampleHooks)`
+//! type ExampleHooks = Audited<PasswdDatabase<ExamplePasswd>>;
+//! ```
+
+use std::ffi::CString;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::context::CallContext;
+use crate::database::{Answer, NssDatabase};
+use crate::group::Group;
+use crate::host::{Host, NameForm};
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+
+/// An entry type that can be audited, named as in `nsswitch.conf`.
+pub trait AuditedEntry {
+    const DATABASE: &'static str;
+}
+
+impl AuditedEntry for Passwd {
+    const DATABASE: &'static str = "passwd";
+}
+
+impl AuditedEntry for Group {
+    const DATABASE: &'static str = "group";
+}
+
+impl AuditedEntry for Shadow {
+    const DATABASE: &'static str = "shadow";
+}
+
+impl AuditedEntry for Host {
+    const DATABASE: &'static str = "hosts";
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Found,
+    /// The key exists, without an entry of the kind asked for
+    NoData,
+    NotFound,
+    /// An enumeration, and how many entries it returned
+    Entries(usize),
+}
+
+impl<E> From<&Answer<E>> for Outcome {
+    fn from(answer: &Answer<E>) -> Self {
+        match answer {
+            Answer::Found(_) => Outcome::Found,
+            Answer::NoData => Outcome::NoData,
+            Answer::NotFound => Outcome::NotFound,
+        }
+    }
+}
+
+pub struct AuditRecord<'a> {
+    pub caller: &'a CallContext,
+    pub database: &'static str,
+    /// What was looked up, or `None` for an enumeration
+    pub key: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// The record as one log line, e.g.
+/// `nss audit: pid=812 euid=0 comm=sshd database=passwd key=name=alice outcome=found`.
+impl fmt::Display for AuditRecord<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nss audit:")?;
+        match self.caller.pid() {
+            Some(pid) => write!(f, " pid={}", pid)?,
+            None => write!(f, " pid=?")?,
+        }
+        write!(f, " euid={}", self.caller.euid())?;
+        if let Some(name) = self.caller.process_name() {
+            write!(f, " comm={}", name.escape_debug())?;
+        }
+        write!(f, " database={}", self.database)?;
+        match &self.key {
+            Some(key) => write!(f, " key={}", key.escape_debug())?,
+            None => write!(f, " key=*")?,
+        }
+        match self.outcome {
+            Outcome::Found => write!(f, " outcome=found"),
+            Outcome::NoData => write!(f, " outcome=nodata"),
+            Outcome::NotFound => write!(f, " outcome=notfound"),
+            Outcome::Entries(count) => write!(f, " outcome=entries:{}", count),
+        }
+    }
+}
+
+// Solaris and illumos have no separate private auth facility
+#[cfg(not(any(target_os = "illumos", target_os = "solaris")))]
+const AUTH_FACILITY: libc::c_int = libc::LOG_AUTHPRIV;
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+const AUTH_FACILITY: libc::c_int = libc::LOG_AUTH;
+
+pub trait AuditSink {
+    fn record(record: &AuditRecord);
+}
+
+/// Logs each record to syslog at `LOG_AUTHPRIV | LOG_INFO` (`LOG_AUTH` on illumos). It doesn't
+/// call `openlog`, which would change the identity the host program logs under.
+pub struct Syslog;
+
+impl AuditSink for Syslog {
+    fn record(record: &AuditRecord) {
+        syslog(AUTH_FACILITY | libc::LOG_INFO, &record.to_string());
+    }
+}
+
+/// Logs `message` at `priority`, under the host program's identity and, unless `priority` names
+/// one, its facility.
+pub(crate) fn syslog(priority: libc::c_int, message: &str) {
+    if let Ok(message) = CString::new(message) {
+        unsafe {
+            libc::syslog(
+                priority,
+                b"%s\0".as_ptr() as *const libc::c_char,
+                message.as_ptr(),
+            );
+        }
+    }
+}
+
+/// A database that reports every lookup to `S`.
+pub struct Audited<D, S = Syslog>(PhantomData<(D, S)>);
+
+impl<D, S> NssDatabase for Audited<D, S>
+where
+    D: NssDatabase,
+    D::Entry: AuditedEntry,
+    D::Key: fmt::Display,
+    S: AuditSink,
+{
+    type Entry = D::Entry;
+    type Key = D::Key;
+
+    const ENUMERATION_CACHE_TTL: Duration = D::ENUMERATION_CACHE_TTL;
+    const TRUNCATE_MEMBERS_AT: Option<usize> = D::TRUNCATE_MEMBERS_AT;
+    const RESOLVES_UNSPECIFIED: bool = D::RESOLVES_UNSPECIFIED;
+    const NAME_FORM: NameForm = D::NAME_FORM;
+
+    fn all_entries() -> Vec<D::Entry> {
+        let entries = D::all_entries();
+        S::record(&AuditRecord {
+            caller: &CallContext::current(),
+            database: D::Entry::DATABASE,
+            key: None,
+            outcome: Outcome::Entries(entries.len()),
+        });
+        entries
+    }
+
+    fn lookup(key: D::Key) -> Answer<D::Entry> {
+        let described = key.to_string();
+        let answer = D::lookup(key);
+        S::record(&AuditRecord {
+            caller: &CallContext::current(),
+            database: D::Entry::DATABASE,
+            key: Some(described),
+            outcome: Outcome::from(&answer),
+        });
+        answer
+    }
+}
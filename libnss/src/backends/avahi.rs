@@ -0,0 +1,750 @@
+//! Resolves `.local` names through a running avahi-daemon's D-Bus API, for systems that already
+//! run Avahi and want lookups to share its cache and conflict resolution rather than sending
+//! queries of their own as [`mdns`](crate::backends::mdns) does.
+//!
+//! Each lookup connects to the system bus and calls `org.freedesktop.Avahi.Server`'s
+//! `ResolveHostName` or `ResolveAddress`, waiting up to `timeout` for the answer; Avahi answers
+//! from its cache when it can. A connection is made per lookup, so nothing is shared with a
+//! forked child, and a process doesn't hold one open between lookups. Avahi answers with a single
+//! address, as with nss-mdns.
+//!
+//! Names outside `domains` are refused immediately, and reverse lookups are only made for
+//! link-local addresses unless `reverse_all` is set, as with the `mdns` backend. Nothing is cached
+//! here, so answers are exactly as fresh as Avahi's. There is no enumeration: `get_all_hosts` is
+//! always empty.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::host::{AddressFamily, Addresses, Host};
+
+const AVAHI: &str = "org.freedesktop.Avahi";
+const AVAHI_SERVER: &str = "org.freedesktop.Avahi.Server";
+
+const AVAHI_IF_UNSPEC: i32 = -1;
+const AVAHI_PROTO_UNSPEC: i32 = -1;
+const AVAHI_PROTO_INET: i32 = 0;
+const AVAHI_PROTO_INET6: i32 = 1;
+
+pub struct AvahiConfig {
+    /// The system bus' socket
+    pub bus: PathBuf,
+    /// How long to wait for Avahi to answer
+    pub timeout: Duration,
+    /// Domains answered, without leading or trailing dots
+    pub domains: Vec<String>,
+    /// Whether reverse lookups are made for every address, rather than only link-local ones
+    pub reverse_all: bool,
+}
+
+impl Default for AvahiConfig {
+    fn default() -> Self {
+        AvahiConfig {
+            bus: PathBuf::from("/run/dbus/system_bus_socket"),
+            timeout: Duration::from_secs(2),
+            domains: vec!["local".to_string()],
+            reverse_all: false,
+        }
+    }
+}
+
+pub struct AvahiBackend {
+    config: AvahiConfig,
+}
+
+impl AvahiBackend {
+    pub fn new(config: AvahiConfig) -> Self {
+        AvahiBackend { config }
+    }
+
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        vec![]
+    }
+
+    /// An unspecified family takes whichever address Avahi has.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if !self.in_domains(&name) {
+            return None;
+        }
+        let protocol = match family {
+            AddressFamily::IPv4 => AVAHI_PROTO_INET,
+            AddressFamily::IPv6 => AVAHI_PROTO_INET6,
+            AddressFamily::Unspecified => AVAHI_PROTO_UNSPEC,
+            AddressFamily::Other(_) => return None,
+        };
+
+        let mut body = Writer::default();
+        body.i32(AVAHI_IF_UNSPEC);
+        body.i32(AVAHI_PROTO_UNSPEC);
+        body.string(&name);
+        body.i32(protocol);
+        body.u32(0);
+        let mut reply = self.call("ResolveHostName", "iisiu", body)?;
+
+        // interface, protocol, name, address protocol, address, flags
+        reply.i32()?;
+        reply.i32()?;
+        reply.string()?;
+        reply.i32()?;
+        let addr: IpAddr = reply.string()?.parse().ok()?;
+        Some(Host {
+            name,
+            aliases: vec![],
+            addresses: Addresses::from(addr),
+            canonical_name: None,
+        })
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        if !self.config.reverse_all && !is_link_local(addr) {
+            return None;
+        }
+
+        let mut body = Writer::default();
+        body.i32(AVAHI_IF_UNSPEC);
+        body.i32(AVAHI_PROTO_UNSPEC);
+        body.string(&addr.to_string());
+        body.u32(0);
+        let mut reply = self.call("ResolveAddress", "iisu", body)?;
+
+        // interface, protocol, address protocol, address, name, flags
+        reply.i32()?;
+        reply.i32()?;
+        reply.i32()?;
+        reply.string()?;
+        let name = reply.string()?.trim_end_matches('.').to_lowercase();
+        Some(Host {
+            name,
+            aliases: vec![],
+            addresses: Addresses::from(addr),
+            canonical_name: None,
+        })
+    }
+
+    fn in_domains(&self, name: &str) -> bool {
+        self.config.domains.iter().any(|domain| {
+            let domain = domain.trim_matches('.').to_lowercase();
+            name.strip_suffix(&domain)
+                .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.'))
+        })
+    }
+
+    /// Calls a method of Avahi's server object, returning the reply's body. `None` if Avahi isn't
+    /// running, can't resolve the name or address, or doesn't answer within `timeout`.
+    fn call(&self, method: &str, signature: &str, body: Writer) -> Option<Reader> {
+        let mut bus = Bus::connect(&self.config.bus, self.config.timeout).ok()?;
+        bus.call(AVAHI, "/", AVAHI_SERVER, method, signature, body)
+            .ok()
+    }
+}
+
+fn is_link_local(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_link_local(),
+        IpAddr::V6(addr) => addr.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+/// Don't have the bus start a service that isn't running just to answer a lookup
+const NO_AUTO_START: u8 = 0x2;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// A connection to a D-Bus message bus, speaking just enough of the protocol to make method
+/// calls and read their replies.
+struct Bus {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Bus {
+    /// Connects, authenticates as this process' user and says hello, as every client must before
+    /// its first call.
+    fn connect(path: &Path, timeout: Duration) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut bus = Bus { stream, serial: 0 };
+
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        bus.stream
+            .write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())?;
+        if !bus.read_line()?.starts_with("OK ") {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the bus rejected authentication",
+            ));
+        }
+        bus.stream.write_all(b"BEGIN\r\n")?;
+
+        bus.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            "",
+            Writer::default(),
+        )?;
+        Ok(bus)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while !line.ends_with(b"\r\n") {
+            self.stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+            if line.len() > 1024 {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    /// Makes a method call and returns its reply's body, skipping any signals that arrive first.
+    fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: Writer,
+    ) -> io::Result<Reader> {
+        self.serial += 1;
+        let serial = self.serial;
+
+        let mut message = Writer::default();
+        message
+            .0
+            .extend_from_slice(&[b'l', METHOD_CALL, NO_AUTO_START, 1]);
+        message.u32(body.0.len() as u32);
+        message.u32(serial);
+        let fields_len = message.0.len();
+        message.u32(0);
+        message.align(8);
+        let fields_start = message.0.len();
+        message.field(FIELD_PATH, "o", path);
+        message.field(FIELD_DESTINATION, "s", destination);
+        message.field(FIELD_INTERFACE, "s", interface);
+        message.field(FIELD_MEMBER, "s", member);
+        if !signature.is_empty() {
+            message.field(FIELD_SIGNATURE, "g", signature);
+        }
+        let len = (message.0.len() - fields_start) as u32;
+        message.0[fields_len..fields_len + 4].copy_from_slice(&len.to_le_bytes());
+        message.align(8);
+        message.0.extend_from_slice(&body.0);
+        self.stream.write_all(&message.0)?;
+
+        loop {
+            let (kind, reply_serial, body) = self.read_message()?;
+            if reply_serial != Some(serial) {
+                continue;
+            }
+            return match kind {
+                METHOD_RETURN => Ok(body),
+                ERROR => Err(io::Error::other(format!("{} failed", member))),
+                _ => continue,
+            };
+        }
+    }
+
+    /// Reads the next message, returning its type, the serial of the call it replies to, if any,
+    /// and its body.
+    fn read_message(&mut self) -> io::Result<(u8, Option<u32>, Reader)> {
+        let mut fixed = [0; 16];
+        self.stream.read_exact(&mut fixed)?;
+        let big_endian = match fixed[0] {
+            b'l' => false,
+            b'B' => true,
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        };
+        let word = |at: usize| {
+            let bytes: [u8; 4] = fixed[at..at + 4].try_into().unwrap();
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        let (body_len, fields_len) = (word(4) as usize, word(12) as usize);
+        if body_len + fields_len > 1 << 27 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let header_end = 16 + fields_len;
+        let body_start = (header_end + 7) & !7;
+
+        let mut message = fixed.to_vec();
+        message.resize(body_start + body_len, 0);
+        self.stream.read_exact(&mut message[16..])?;
+
+        let body = Reader {
+            buffer: message.split_off(body_start),
+            offset: 0,
+            big_endian,
+        };
+        let mut fields = Reader {
+            buffer: message,
+            offset: 16,
+            big_endian,
+        };
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let mut reply_serial = None;
+        while fields.offset < header_end {
+            fields.align(8);
+            let code = fields.byte().ok_or_else(invalid)?;
+            match fields.signature().ok_or_else(invalid)?.as_str() {
+                "u" => {
+                    let value = fields.u32().ok_or_else(invalid)?;
+                    if code == FIELD_REPLY_SERIAL {
+                        reply_serial = Some(value);
+                    }
+                }
+                "s" | "o" => {
+                    fields.string().ok_or_else(invalid)?;
+                }
+                "g" => {
+                    fields.signature().ok_or_else(invalid)?;
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok((fixed[1], reply_serial, body))
+    }
+}
+
+/// Marshals little endian D-Bus values, aligned from the start of the buffer.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn align(&mut self, to: usize) {
+        let len = self.0.len().div_ceil(to) * to;
+        self.0.resize(len, 0);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.u32(value as u32);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.0.push(value.len() as u8);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    /// A header field: its code and a variant holding `value` of type `signature`.
+    fn field(&mut self, code: u8, signature: &str, value: &str) {
+        self.align(8);
+        self.0.push(code);
+        self.signature(signature);
+        match signature {
+            "g" => self.signature(value),
+            _ => self.string(value),
+        }
+    }
+}
+
+/// Unmarshals D-Bus values, aligned from the start of the buffer.
+struct Reader {
+    buffer: Vec<u8>,
+    offset: usize,
+    big_endian: bool,
+}
+
+impl Reader {
+    fn align(&mut self, to: usize) {
+        self.offset = self.offset.div_ceil(to) * to;
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.buffer.get(self.offset)?;
+        self.offset += 1;
+        Some(byte)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let bytes: [u8; 4] = self
+            .buffer
+            .get(self.offset..self.offset + 4)?
+            .try_into()
+            .ok()?;
+        self.offset += 4;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        self.u32().map(|value| value as i32)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        self.text(len)
+    }
+
+    fn signature(&mut self) -> Option<String> {
+        let len = self.byte()? as usize;
+        self.text(len)
+    }
+
+    /// `len` bytes of text and the NUL after them.
+    fn text(&mut self, len: usize) -> Option<String> {
+        let bytes = self.buffer.get(self.offset..self.offset + len)?;
+        let text = String::from_utf8_lossy(bytes).into_owned();
+        self.offset += len + 1;
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::Ipv6Addr;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    const SIGNAL: u8 = 4;
+
+    struct Call {
+        serial: u32,
+        fields: HashMap<u8, String>,
+        body: Reader,
+    }
+
+    /// Reads a method call as the bus would, or `None` once the client hangs up.
+    fn read_call(stream: &mut UnixStream) -> Option<Call> {
+        let mut fixed = [0; 16];
+        stream.read_exact(&mut fixed).ok()?;
+        assert_eq!(fixed[..4], [b'l', METHOD_CALL, NO_AUTO_START, 1]);
+        let word = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+        let header_end = 16 + word(12) as usize;
+        let body_start = (header_end + 7) & !7;
+
+        let mut message = fixed.to_vec();
+        message.resize(body_start + word(4) as usize, 0);
+        stream.read_exact(&mut message[16..]).unwrap();
+        let body = Reader {
+            buffer: message.split_off(body_start),
+            offset: 0,
+            big_endian: false,
+        };
+        let mut header = Reader {
+            buffer: message,
+            offset: 16,
+            big_endian: false,
+        };
+        let mut fields = HashMap::new();
+        while header.offset < header_end {
+            header.align(8);
+            let code = header.byte().unwrap();
+            let value = match header.signature().unwrap().as_str() {
+                "g" => header.signature(),
+                _ => header.string(),
+            };
+            fields.insert(code, value.unwrap());
+        }
+        Some(Call {
+            serial: word(8),
+            fields,
+            body,
+        })
+    }
+
+    fn send(
+        stream: &mut UnixStream,
+        kind: u8,
+        reply_serial: Option<u32>,
+        signature: &str,
+        body: Writer,
+    ) {
+        let mut message = Writer::default();
+        message.0.extend_from_slice(&[b'l', kind, 0, 1]);
+        message.u32(body.0.len() as u32);
+        message.u32(1000);
+        let fields_len = message.0.len();
+        message.u32(0);
+        message.align(8);
+        let fields_start = message.0.len();
+        if let Some(serial) = reply_serial {
+            message.align(8);
+            message.0.push(FIELD_REPLY_SERIAL);
+            message.signature("u");
+            message.u32(serial);
+        }
+        if kind == SIGNAL {
+            message.field(FIELD_MEMBER, "s", "StateChanged");
+        }
+        if !signature.is_empty() {
+            message.field(FIELD_SIGNATURE, "g", signature);
+        }
+        let len = (message.0.len() - fields_start) as u32;
+        message.0[fields_len..fields_len + 4].copy_from_slice(&len.to_le_bytes());
+        message.align(8);
+        message.0.extend_from_slice(&body.0);
+        stream.write_all(&message.0).unwrap();
+    }
+
+    /// Answers as the bus and an avahi-daemon knowing only `printer.local`, at 192.168.1.20 and
+    /// fe80::20, and 169.254.1.20.
+    fn serve(mut stream: UnixStream) {
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        let mut auth = vec![0; format!("\0AUTH EXTERNAL {}\r\n", hex).len()];
+        stream.read_exact(&mut auth).unwrap();
+        assert_eq!(auth, format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes());
+        stream.write_all(b"OK 0123456789abcdef\r\n").unwrap();
+        let mut begin = [0; 7];
+        stream.read_exact(&mut begin).unwrap();
+        assert_eq!(&begin, b"BEGIN\r\n");
+
+        while let Some(mut call) = read_call(&mut stream) {
+            let member = call.fields[&FIELD_MEMBER].clone();
+            let signature = call
+                .fields
+                .get(&FIELD_SIGNATURE)
+                .cloned()
+                .unwrap_or_default();
+            let mut reply = Writer::default();
+            let found = match member.as_str() {
+                "Hello" => {
+                    assert_eq!(call.fields[&FIELD_DESTINATION], "org.freedesktop.DBus");
+                    reply.string(":1.42");
+                    Some("s")
+                }
+                "ResolveHostName" => {
+                    assert_eq!(call.fields[&FIELD_DESTINATION], AVAHI);
+                    assert_eq!(call.fields[&FIELD_INTERFACE], AVAHI_SERVER);
+                    assert_eq!(signature, "iisiu");
+                    let body = &mut call.body;
+                    let (_, _, name, protocol) = (
+                        body.i32(),
+                        body.i32(),
+                        body.string().unwrap(),
+                        body.i32().unwrap(),
+                    );
+                    let addr = match protocol {
+                        AVAHI_PROTO_INET6 => "fe80::20",
+                        _ => "192.168.1.20",
+                    };
+                    // Signals can arrive before the reply
+                    send(&mut stream, SIGNAL, None, "", Writer::default());
+                    (name == "printer.local").then(|| {
+                        reply.i32(2);
+                        reply.i32(AVAHI_PROTO_INET);
+                        reply.string(&name);
+                        reply.i32(protocol.max(AVAHI_PROTO_INET));
+                        reply.string(addr);
+                        reply.u32(0);
+                        "iisisu"
+                    })
+                }
+                "ResolveAddress" => {
+                    assert_eq!(signature, "iisu");
+                    let body = &mut call.body;
+                    let (_, _, addr) = (body.i32(), body.i32(), body.string().unwrap());
+                    (addr == "169.254.1.20").then(|| {
+                        reply.i32(2);
+                        reply.i32(AVAHI_PROTO_INET);
+                        reply.i32(AVAHI_PROTO_INET);
+                        reply.string(&addr);
+                        reply.string("Printer.local.");
+                        reply.u32(0);
+                        "iiissu"
+                    })
+                }
+                member => panic!("unexpected call to {}", member),
+            };
+            match found {
+                Some(signature) => send(
+                    &mut stream,
+                    METHOD_RETURN,
+                    Some(call.serial),
+                    signature,
+                    reply,
+                ),
+                None => {
+                    let mut error = Writer::default();
+                    error.string("not found");
+                    send(&mut stream, ERROR, Some(call.serial), "s", error);
+                }
+            }
+        }
+    }
+
+    fn fake_bus(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("libnss-avahi-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                serve(stream.unwrap());
+            }
+        });
+        path
+    }
+
+    #[test]
+    fn marshalling() {
+        let mut writer = Writer::default();
+        writer.0.push(7);
+        writer.i32(-1);
+        writer.string("ab");
+        writer.signature("iisu");
+        writer.field(FIELD_MEMBER, "s", "Hello");
+        assert_eq!(
+            writer.0,
+            [
+                &[7, 0, 0, 0][..],
+                &[0xff; 4],
+                &[2, 0, 0, 0],
+                b"ab\0",
+                b"\x04iisu\0",
+                &[0; 3],
+                &[FIELD_MEMBER, 1, b's', 0],
+                &[5, 0, 0, 0],
+                b"Hello\0",
+            ]
+            .concat()
+        );
+
+        let mut reader = Reader {
+            buffer: writer.0.clone(),
+            offset: 1,
+            big_endian: false,
+        };
+        assert_eq!(reader.i32(), Some(-1));
+        assert_eq!(reader.string().as_deref(), Some("ab"));
+        assert_eq!(reader.signature().as_deref(), Some("iisu"));
+        reader.align(8);
+        assert_eq!(reader.byte(), Some(FIELD_MEMBER));
+        assert_eq!(reader.signature().as_deref(), Some("s"));
+        assert_eq!(reader.string().as_deref(), Some("Hello"));
+        assert_eq!(reader.byte(), None);
+
+        let mut big = Reader {
+            buffer: vec![0, 0, 0, 2, b'a', b'b', 0],
+            offset: 0,
+            big_endian: true,
+        };
+        assert_eq!(big.string().as_deref(), Some("ab"));
+        let mut short = Reader {
+            buffer: vec![9, 0, 0, 0, b'a', b'b', 0],
+            offset: 0,
+            big_endian: false,
+        };
+        assert_eq!(short.string(), None);
+    }
+
+    #[test]
+    fn refused_without_asking() {
+        let avahi = AvahiBackend::new(AvahiConfig {
+            bus: PathBuf::from("/nonexistent"),
+            domains: vec!["local".to_string(), ".Home.Arpa.".to_string()],
+            ..AvahiConfig::default()
+        });
+        for name in ["printer.local", "a.b.local", "nas.home.arpa"] {
+            assert!(avahi.in_domains(name), "{}", name);
+        }
+        for name in ["local", ".local", "printerlocal", "printer.local.com"] {
+            assert!(!avahi.in_domains(name), "{}", name);
+        }
+        for (addr, link_local) in [
+            ("169.254.1.2", true),
+            ("192.168.1.2", false),
+            ("fe80::1", true),
+            ("fd00::1", false),
+        ] {
+            assert_eq!(is_link_local(addr.parse().unwrap()), link_local, "{}", addr);
+        }
+        // With no bus, nothing resolves
+        assert_eq!(
+            avahi.get_host_by_name("printer.local", AddressFamily::IPv4),
+            None
+        );
+    }
+
+    #[test]
+    fn resolves_through_the_bus() {
+        let avahi = AvahiBackend::new(AvahiConfig {
+            bus: fake_bus("resolve"),
+            ..AvahiConfig::default()
+        });
+
+        assert_eq!(
+            avahi.get_host_by_name("Printer.local.", AddressFamily::Unspecified),
+            Some(Host {
+                name: "printer.local".to_string(),
+                aliases: vec![],
+                addresses: Addresses::V4(vec![[192, 168, 1, 20].into()]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            avahi
+                .get_host_by_name("printer.local", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fe80::20".parse::<Ipv6Addr>().unwrap()])
+        );
+        assert_eq!(
+            avahi.get_host_by_name("scanner.local", AddressFamily::IPv4),
+            None
+        );
+        assert_eq!(
+            avahi.get_host_by_name("printer.local", AddressFamily::Other(17)),
+            None
+        );
+        assert_eq!(
+            avahi.get_host_by_name("example.com", AddressFamily::IPv4),
+            None
+        );
+
+        let host = avahi
+            .get_host_by_addr("169.254.1.20".parse().unwrap())
+            .unwrap();
+        assert_eq!(host.name, "printer.local");
+        assert_eq!(
+            avahi.get_host_by_addr("169.254.1.21".parse().unwrap()),
+            None
+        );
+        // Not link-local
+        assert_eq!(
+            avahi.get_host_by_addr("192.168.1.20".parse().unwrap()),
+            None
+        );
+        std::fs::remove_file(&avahi.config.bus).unwrap();
+    }
+}
@@ -0,0 +1,861 @@
+//! Resolves the names of a cloud fleet's instances to their private addresses through the
+//! provider's API, so they resolve without a private DNS zone.
+//!
+//! - On EC2, running instances in the region are listed with `DescribeInstances` and named by a
+//!   tag (`Name` by default). Credentials come from the config, then the usual `AWS_*`
+//!   environment variables, then the instance's role via IMDSv2.
+//! - On GCE, running instances in the project are listed with an OAuth token from the metadata
+//!   server, so the instance's service account needs `compute.instances.list`.
+//!
+//! Instances are also answered by their ID, and under `domain` if one is set. The list is cached
+//! for `cache_ttl` and the stale list keeps being served while it can't be refreshed.
+//!
+//! The provider APIs are reached by name, which may well be resolved through this module again.
+//! Lookups made while the list is being refreshed are answered from the old list rather than
+//! waiting on the refresh, so they fall through to the next source in `nsswitch.conf`.
+
+use std::convert::TryFrom;
+use std::env;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use ureq::Agent;
+
+use crate::host::{AddressFamily, Addresses, Host};
+
+/// Reached by address, so that looking it up can't recurse into this module.
+const METADATA: &str = "http://169.254.169.254";
+
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for AwsCredentials {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.secret_access_key);
+        zeroize::Zeroize::zeroize(&mut self.session_token);
+    }
+}
+
+pub enum Provider {
+    Ec2 {
+        /// `None` uses `AWS_REGION`, then the instance's own region
+        region: Option<String>,
+        /// The tag holding each instance's name
+        name_tag: String,
+        /// `None` uses the environment, then the instance's role
+        credentials: Option<AwsCredentials>,
+    },
+    Gce {
+        /// `None` uses the instance's own project
+        project: Option<String>,
+    },
+}
+
+pub struct CloudConfig {
+    pub provider: Provider,
+    /// Also answer `<name>.<domain>`, and use that as the canonical name.
+    pub domain: Option<String>,
+    pub cache_ttl: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for CloudConfig {
+    fn default() -> Self {
+        CloudConfig {
+            provider: Provider::Ec2 {
+                region: None,
+                name_tag: "Name".to_string(),
+                credentials: None,
+            },
+            domain: None,
+            cache_ttl: Duration::from_secs(300),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Instance {
+    name: String,
+    id: String,
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<Ipv6Addr>,
+}
+
+struct Cache {
+    instances: Arc<Vec<Instance>>,
+    next_refresh: Instant,
+    refreshing: bool,
+}
+
+pub struct CloudBackend {
+    provider: Provider,
+    domain: Option<String>,
+    cache_ttl: Duration,
+    agent: Agent,
+    cache: RwLock<Cache>,
+}
+
+impl CloudBackend {
+    /// Creates the backend; instances are first listed by the first lookup.
+    pub fn new(config: CloudConfig) -> Self {
+        CloudBackend {
+            provider: config.provider,
+            domain: config
+                .domain
+                .map(|domain| domain.trim_matches('.').to_lowercase()),
+            cache_ttl: config.cache_ttl,
+            agent: Agent::config_builder()
+                .timeout_global(Some(config.timeout))
+                .build()
+                .new_agent(),
+            cache: RwLock::new(Cache {
+                instances: Arc::default(),
+                next_refresh: Instant::now(),
+                refreshing: false,
+            }),
+        }
+    }
+
+    /// One entry per instance and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let instances = self.instances();
+        let mut hosts = Vec::new();
+        for instance in instances.iter() {
+            hosts.extend(self.to_host(instance, AddressFamily::IPv4));
+            hosts.extend(self.to_host(instance, AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches names, `domain` names and IDs, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let name = match &self.domain {
+            Some(domain) => name
+                .strip_suffix(domain.as_str())
+                .and_then(|name| name.strip_suffix('.'))
+                .map(str::to_string)
+                .unwrap_or(name),
+            None => name,
+        };
+
+        let instances = self.instances();
+        let instance = instances.iter().find(|instance| {
+            instance.name.eq_ignore_ascii_case(&name) || instance.id.eq_ignore_ascii_case(&name)
+        })?;
+
+        match family {
+            AddressFamily::Unspecified => self
+                .to_host(instance, AddressFamily::IPv4)
+                .or_else(|| self.to_host(instance, AddressFamily::IPv6)),
+            family => self.to_host(instance, family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let instances = self.instances();
+        match addr {
+            IpAddr::V4(addr) => {
+                let instance = instances.iter().find(|i| i.v4.contains(&addr))?;
+                self.to_host(instance, AddressFamily::IPv4)
+            }
+            IpAddr::V6(addr) => {
+                let instance = instances.iter().find(|i| i.v6.contains(&addr))?;
+                self.to_host(instance, AddressFamily::IPv6)
+            }
+        }
+    }
+
+    fn to_host(&self, instance: &Instance, family: AddressFamily) -> Option<Host> {
+        let addresses = match family {
+            AddressFamily::IPv4 if !instance.v4.is_empty() => Addresses::V4(instance.v4.clone()),
+            AddressFamily::IPv6 if !instance.v6.is_empty() => Addresses::V6(instance.v6.clone()),
+            _ => return None,
+        };
+
+        let mut aliases = vec![];
+        let name = match &self.domain {
+            Some(domain) => {
+                aliases.push(instance.name.clone());
+                format!("{}.{}", instance.name, domain)
+            }
+            None => instance.name.clone(),
+        };
+        if instance.id != instance.name {
+            aliases.push(instance.id.clone());
+        }
+
+        Some(Host {
+            name,
+            aliases,
+            addresses,
+            canonical_name: None,
+        })
+    }
+
+    /// The cached instances, listing them again first if the cache has expired. Nested lookups
+    /// made by the refresh itself get the old list.
+    fn instances(&self) -> Arc<Vec<Instance>> {
+        let current = |cache: &Cache| cache.refreshing || Instant::now() < cache.next_refresh;
+        {
+            let cache = self.cache.read().unwrap();
+            if current(&cache) {
+                return cache.instances.clone();
+            }
+        }
+        {
+            let mut cache = self.cache.write().unwrap();
+            if current(&cache) {
+                return cache.instances.clone();
+            }
+            cache.refreshing = true;
+        }
+
+        let listed = match &self.provider {
+            Provider::Ec2 {
+                region,
+                name_tag,
+                credentials,
+            } => self.list_ec2(region.as_deref(), name_tag, credentials.as_ref()),
+            Provider::Gce { project } => self.list_gce(project.as_deref()),
+        };
+
+        let mut cache = self.cache.write().unwrap();
+        cache.refreshing = false;
+        match listed {
+            Ok(instances) => {
+                cache.instances = Arc::new(instances);
+                cache.next_refresh = Instant::now() + self.cache_ttl;
+            }
+            // Retry sooner than a full TTL, but don't make every lookup wait on a broken API
+            Err(_) => {
+                cache.next_refresh = Instant::now() + self.cache_ttl.min(Duration::from_secs(10))
+            }
+        }
+        cache.instances.clone()
+    }
+
+    fn list_ec2(
+        &self,
+        region: Option<&str>,
+        name_tag: &str,
+        credentials: Option<&AwsCredentials>,
+    ) -> io::Result<Vec<Instance>> {
+        // Only ask IMDS for a token if something actually needs it
+        let mut imds_token = None;
+        let mut imds = |path: &str| -> io::Result<String> {
+            if imds_token.is_none() {
+                imds_token = Some(read(
+                    self.agent
+                        .put(format!("{}/latest/api/token", METADATA))
+                        .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+                        .send_empty(),
+                )?);
+            }
+            read(
+                self.agent
+                    .get(format!("{}{}", METADATA, path))
+                    .header("X-aws-ec2-metadata-token", imds_token.as_deref().unwrap())
+                    .call(),
+            )
+        };
+
+        let region = match region {
+            Some(region) => region.to_string(),
+            None => match env::var("AWS_REGION").or_else(|_| env::var("AWS_DEFAULT_REGION")) {
+                Ok(region) => region,
+                Err(_) => imds("/latest/meta-data/placement/region")?,
+            },
+        };
+        let credentials = match credentials {
+            Some(credentials) => AwsCredentials {
+                access_key_id: credentials.access_key_id.clone(),
+                secret_access_key: credentials.secret_access_key.clone(),
+                session_token: credentials.session_token.clone(),
+            },
+            None => match (
+                env::var("AWS_ACCESS_KEY_ID"),
+                env::var("AWS_SECRET_ACCESS_KEY"),
+            ) {
+                (Ok(access_key_id), Ok(secret_access_key)) => AwsCredentials {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: env::var("AWS_SESSION_TOKEN").ok(),
+                },
+                _ => {
+                    let path = "/latest/meta-data/iam/security-credentials/";
+                    let role = imds(path)?;
+                    let role = role.lines().next().unwrap_or_default();
+                    let reply: Value = serde_json::from_str(&imds(&format!("{}{}", path, role))?)?;
+                    let field = |name: &str| {
+                        reply[name].as_str().map(str::to_string).ok_or_else(|| {
+                            io::Error::other(format!("IMDS credentials have no {}", name))
+                        })
+                    };
+                    AwsCredentials {
+                        access_key_id: field("AccessKeyId")?,
+                        secret_access_key: field("SecretAccessKey")?,
+                        session_token: Some(field("Token")?),
+                    }
+                }
+            },
+        };
+
+        let host = format!("ec2.{}.amazonaws.com", region);
+        let mut instances = Vec::new();
+        let mut next_token = None;
+        loop {
+            let mut query = vec![
+                ("Action", "DescribeInstances".to_string()),
+                ("Version", "2016-11-15".to_string()),
+                ("Filter.1.Name", "instance-state-name".to_string()),
+                ("Filter.1.Value.1", "running".to_string()),
+                ("MaxResults", "1000".to_string()),
+            ];
+            if let Some(token) = next_token.take() {
+                query.push(("NextToken", token));
+            }
+            let query = canonical_query(&query);
+
+            let mut request = self.agent.get(format!("https://{}/?{}", host, query));
+            let headers = sign_v4(
+                &credentials,
+                &region,
+                "ec2",
+                &host,
+                &query,
+                SystemTime::now(),
+            );
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+            let body = read(request.call())?;
+
+            let document = roxmltree::Document::parse(&body)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let root = document.root_element();
+            for reservation in children(child(root, "reservationSet"), "item") {
+                for item in children(child(reservation, "instancesSet"), "item") {
+                    instances.push(ec2_instance(item, name_tag));
+                }
+            }
+            match text(Some(root), "nextToken") {
+                Some(token) if !token.is_empty() => next_token = Some(token.to_string()),
+                _ => return Ok(instances),
+            }
+        }
+    }
+
+    fn list_gce(&self, project: Option<&str>) -> io::Result<Vec<Instance>> {
+        let metadata = |path: &str| {
+            read(
+                self.agent
+                    .get(format!("{}/computeMetadata/v1/{}", METADATA, path))
+                    .header("Metadata-Flavor", "Google")
+                    .call(),
+            )
+        };
+
+        let project = match project {
+            Some(project) => project.to_string(),
+            None => metadata("project/project-id")?,
+        };
+        let token: Value =
+            serde_json::from_str(&metadata("instance/service-accounts/default/token")?)?;
+        let token = token["access_token"]
+            .as_str()
+            .ok_or_else(|| io::Error::other("the metadata server returned no access token"))?;
+
+        let url = format!(
+            "https://compute.googleapis.com/compute/v1/projects/{}/aggregated/instances",
+            project
+        );
+        let mut instances = Vec::new();
+        let mut page_token = String::new();
+        loop {
+            let mut request = self
+                .agent
+                .get(&url)
+                .header("Authorization", format!("Bearer {}", token))
+                .query("filter", "status = RUNNING");
+            if !page_token.is_empty() {
+                request = request.query("pageToken", &page_token);
+            }
+            let page: Value = serde_json::from_str(&read(request.call())?)?;
+
+            for zone in page["items"]
+                .as_object()
+                .into_iter()
+                .flat_map(|z| z.values())
+            {
+                for item in zone["instances"].as_array().into_iter().flatten() {
+                    instances.push(gce_instance(item));
+                }
+            }
+            match page["nextPageToken"].as_str() {
+                Some(token) if !token.is_empty() => page_token = token.to_string(),
+                _ => return Ok(instances),
+            }
+        }
+    }
+}
+
+fn read(response: Result<ureq::http::Response<ureq::Body>, ureq::Error>) -> io::Result<String> {
+    let mut response = response.map_err(io::Error::other)?;
+    response
+        .body_mut()
+        .read_to_string()
+        .map_err(io::Error::other)
+        .map(|body| body.trim().to_string())
+}
+
+fn ec2_instance(item: roxmltree::Node, name_tag: &str) -> Instance {
+    let mut instance = Instance {
+        id: text(Some(item), "instanceId")
+            .unwrap_or_default()
+            .to_string(),
+        ..Instance::default()
+    };
+    instance.name = children(child(item, "tagSet"), "item")
+        .find(|tag| text(Some(*tag), "key") == Some(name_tag))
+        .and_then(|tag| text(Some(tag), "value"))
+        .unwrap_or(&instance.id)
+        .to_string();
+
+    for interface in children(child(item, "networkInterfaceSet"), "item") {
+        for address in children(child(interface, "privateIpAddressesSet"), "item") {
+            if let Some(Ok(addr)) = text(Some(address), "privateIpAddress").map(str::parse) {
+                instance.v4.push(addr);
+            }
+        }
+        for address in children(child(interface, "ipv6AddressesSet"), "item") {
+            if let Some(Ok(addr)) = text(Some(address), "ipv6Address").map(str::parse) {
+                instance.v6.push(addr);
+            }
+        }
+    }
+    // Instances without interface details still report their primary address
+    if instance.v4.is_empty() {
+        if let Some(Ok(addr)) = text(Some(item), "privateIpAddress").map(str::parse) {
+            instance.v4.push(addr);
+        }
+    }
+    instance
+}
+
+fn gce_instance(item: &Value) -> Instance {
+    let mut instance = Instance {
+        name: item["name"].as_str().unwrap_or_default().to_string(),
+        id: item["id"].as_str().unwrap_or_default().to_string(),
+        ..Instance::default()
+    };
+    for interface in item["networkInterfaces"].as_array().into_iter().flatten() {
+        if let Some(Ok(addr)) = interface["networkIP"].as_str().map(str::parse) {
+            instance.v4.push(addr);
+        }
+        if let Some(Ok(addr)) = interface["ipv6Address"].as_str().map(str::parse) {
+            instance.v6.push(addr);
+        }
+    }
+    instance
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn children<'a, 'input: 'a>(
+    node: Option<roxmltree::Node<'a, 'input>>,
+    name: &'a str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> + 'a {
+    node.into_iter()
+        .flat_map(|node| node.children())
+        .filter(move |n| n.has_tag_name(name))
+}
+
+fn text<'a>(node: Option<roxmltree::Node<'a, '_>>, name: &str) -> Option<&'a str> {
+    child(node?, name)?.text()
+}
+
+/// Percent-encodes everything but RFC 3986's unreserved characters, as SigV4 requires.
+fn uri_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn canonical_query(params: &[(&str, String)]) -> String {
+    let mut params: Vec<String> = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", uri_encode(key), uri_encode(value)))
+        .collect();
+    params.sort();
+    params.join("&")
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The headers signing a GET of `/?<query>` made at `now` with AWS Signature Version 4.
+fn sign_v4(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    host: &str,
+    query: &str,
+    now: SystemTime,
+) -> Vec<(&'static str, String)> {
+    let (date, time) = utc(now);
+    let timestamp = format!("{}T{}Z", date, time);
+
+    let mut headers = vec![
+        ("host", host.to_string()),
+        ("x-amz-date", timestamp.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "GET\n/\n{}\n{}\n{}\n{}",
+        query,
+        canonical_headers,
+        signed_headers,
+        hex(&Sha256::digest(b""))
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let secret = format!("AWS4{}", credentials.secret_access_key);
+    let key = hmac(secret.as_bytes(), &date);
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(&mut { secret });
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    let key = hmac(&key, "aws4_request");
+    let signature = hex(&hmac(&key, &string_to_sign));
+
+    headers.remove(0); // ureq sets Host itself
+    headers.push((
+        "Authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+    headers
+}
+
+/// `now` in UTC as `YYYYMMDD` and `HHMMSS`.
+fn utc(now: SystemTime) -> (String, String) {
+    let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = i64::try_from(seconds / 86400).unwrap_or_default();
+    let time = seconds % 86400;
+
+    // Howard Hinnant's days_from_civil, inverted
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        format!("{:04}{:02}{:02}", year, month, day),
+        format!("{:02}{:02}{:02}", time / 3600, time % 3600 / 60, time % 60),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(name: &str, id: &str, v4: &[&str], v6: &[&str]) -> Instance {
+        Instance {
+            name: name.to_string(),
+            id: id.to_string(),
+            v4: v4.iter().map(|addr| addr.parse().unwrap()).collect(),
+            v6: v6.iter().map(|addr| addr.parse().unwrap()).collect(),
+        }
+    }
+
+    fn fields(instance: &Instance) -> (&str, &str, &[Ipv4Addr], &[Ipv6Addr]) {
+        (&instance.name, &instance.id, &instance.v4, &instance.v6)
+    }
+
+    /// A backend that has already listed `instances`, so never calls the provider's API.
+    fn backend(domain: Option<&str>, instances: Vec<Instance>) -> CloudBackend {
+        let backend = CloudBackend::new(CloudConfig {
+            domain: domain.map(str::to_string),
+            ..CloudConfig::default()
+        });
+        let mut cache = backend.cache.write().unwrap();
+        cache.instances = Arc::new(instances);
+        cache.next_refresh = Instant::now() + Duration::from_secs(3600);
+        drop(cache);
+        backend
+    }
+
+    #[test]
+    fn ec2_instances() {
+        let body = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <reservationSet><item><instancesSet>
+                <item>
+                    <instanceId>i-0123</instanceId>
+                    <privateIpAddress>10.0.0.1</privateIpAddress>
+                    <tagSet>
+                        <item><key>Role</key><value>db</value></item>
+                        <item><key>Name</key><value>web-1</value></item>
+                    </tagSet>
+                    <networkInterfaceSet><item>
+                        <privateIpAddressesSet>
+                            <item><privateIpAddress>10.0.0.1</privateIpAddress></item>
+                            <item><privateIpAddress>10.0.0.2</privateIpAddress></item>
+                        </privateIpAddressesSet>
+                        <ipv6AddressesSet>
+                            <item><ipv6Address>2600:1f18::1</ipv6Address></item>
+                        </ipv6AddressesSet>
+                    </item></networkInterfaceSet>
+                </item>
+                <item>
+                    <instanceId>i-4567</instanceId>
+                    <privateIpAddress>10.0.0.3</privateIpAddress>
+                </item>
+            </instancesSet></item></reservationSet>
+        </DescribeInstancesResponse>"#;
+        let document = roxmltree::Document::parse(body).unwrap();
+        let instances: Vec<_> = children(child(document.root_element(), "reservationSet"), "item")
+            .flat_map(|reservation| children(child(reservation, "instancesSet"), "item"))
+            .map(|item| ec2_instance(item, "Name"))
+            .collect();
+
+        assert_eq!(
+            fields(&instances[0]),
+            fields(&instance(
+                "web-1",
+                "i-0123",
+                &["10.0.0.1", "10.0.0.2"],
+                &["2600:1f18::1"]
+            ))
+        );
+        // Untagged instances are named by their ID
+        assert_eq!(
+            fields(&instances[1]),
+            fields(&instance("i-4567", "i-4567", &["10.0.0.3"], &[]))
+        );
+    }
+
+    #[test]
+    fn gce_instances() {
+        let item = serde_json::json!({
+            "name": "web-1",
+            "id": "123456789",
+            "networkInterfaces": [
+                {"networkIP": "10.128.0.2", "ipv6Address": "fd20::2"},
+                {"networkIP": "10.129.0.2"},
+                {"networkIP": "not an address"},
+            ],
+        });
+        assert_eq!(
+            fields(&gce_instance(&item)),
+            fields(&instance(
+                "web-1",
+                "123456789",
+                &["10.128.0.2", "10.129.0.2"],
+                &["fd20::2"]
+            ))
+        );
+    }
+
+    #[test]
+    fn lookups() {
+        let cloud = backend(
+            Some(".Internal.Example."),
+            vec![
+                instance("web-1", "i-0123", &["10.0.0.1"], &["2600:1f18::1"]),
+                instance("i-4567", "i-4567", &[], &["2600:1f18::2"]),
+            ],
+        );
+
+        let web = cloud
+            .get_host_by_name("WEB-1.internal.example.", AddressFamily::Unspecified)
+            .unwrap();
+        assert_eq!(
+            web,
+            Host {
+                name: "web-1.internal.example".to_string(),
+                aliases: vec!["web-1".to_string(), "i-0123".to_string()],
+                addresses: Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+                canonical_name: None,
+            }
+        );
+        assert_eq!(
+            cloud.get_host_by_name("web-1", AddressFamily::IPv4),
+            Some(web.clone())
+        );
+        assert_eq!(
+            cloud.get_host_by_name("i-0123", AddressFamily::IPv4),
+            Some(web.clone())
+        );
+        assert_eq!(
+            cloud.get_host_by_addr("10.0.0.1".parse().unwrap()),
+            Some(web)
+        );
+
+        let untagged = cloud
+            .get_host_by_name("i-4567", AddressFamily::Unspecified)
+            .unwrap();
+        assert_eq!(untagged.aliases, ["i-4567"]);
+        assert_eq!(
+            untagged.addresses,
+            Addresses::V6(vec!["2600:1f18::2".parse().unwrap()])
+        );
+        assert_eq!(cloud.get_host_by_name("i-4567", AddressFamily::IPv4), None);
+        assert_eq!(cloud.get_host_by_name("web-2", AddressFamily::IPv4), None);
+        assert_eq!(cloud.get_host_by_addr("10.0.0.2".parse().unwrap()), None);
+        assert_eq!(cloud.get_all_hosts().len(), 3);
+
+        let bare = backend(None, vec![instance("web-1", "i-0123", &["10.0.0.1"], &[])]);
+        let host = bare.get_host_by_name("web-1", AddressFamily::IPv4).unwrap();
+        assert_eq!(
+            (host.name.as_str(), host.aliases),
+            ("web-1", vec!["i-0123".to_string()])
+        );
+        assert_eq!(
+            bare.get_host_by_name("web-1.internal.example", AddressFamily::IPv4),
+            None
+        );
+    }
+
+    #[test]
+    fn query_encoding() {
+        assert_eq!(uri_encode("AZaz09-_.~"), "AZaz09-_.~");
+        assert_eq!(uri_encode("a b/+=é"), "a%20b%2F%2B%3D%C3%A9");
+        assert_eq!(
+            canonical_query(&[
+                ("Filter.1.Value.1", "running".to_string()),
+                ("Action", "DescribeInstances".to_string()),
+                ("NextToken", "a/b=".to_string()),
+            ]),
+            "Action=DescribeInstances&Filter.1.Value.1=running&NextToken=a%2Fb%3D"
+        );
+    }
+
+    #[test]
+    fn utc_dates() {
+        for (seconds, date, time) in [
+            (0, "19700101", "000000"),
+            (951_782_400, "20000229", "000000"),
+            (1_440_938_160, "20150830", "123600"),
+            (4_102_444_799, "20991231", "235959"),
+        ] {
+            let now = UNIX_EPOCH + Duration::from_secs(seconds);
+            assert_eq!(utc(now), (date.to_string(), time.to_string()));
+        }
+    }
+
+    /// The `get-vanilla` cases from AWS's Signature Version 4 test suite.
+    #[test]
+    fn signatures() {
+        let mut credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+        let sign = |credentials: &AwsCredentials, query: &str| {
+            sign_v4(
+                credentials,
+                "us-east-1",
+                "service",
+                "example.amazonaws.com",
+                query,
+                now,
+            )
+        };
+        let authorization = |signature: &str, signed: &str| {
+            format!(
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                 SignedHeaders={}, Signature={}",
+                signed, signature
+            )
+        };
+
+        assert_eq!(
+            sign(&credentials, ""),
+            [
+                ("x-amz-date", "20150830T123600Z".to_string()),
+                (
+                    "Authorization",
+                    authorization(
+                        "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+                        "host;x-amz-date"
+                    )
+                ),
+            ]
+        );
+        let query = canonical_query(&[
+            ("Param2", "value2".to_string()),
+            ("Param1", "value1".to_string()),
+        ]);
+        assert_eq!(
+            sign(&credentials, &query)[1].1,
+            authorization(
+                "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500",
+                "host;x-amz-date"
+            )
+        );
+
+        credentials.session_token = Some("token".to_string());
+        let headers = sign(&credentials, "");
+        assert_eq!(headers[1], ("x-amz-security-token", "token".to_string()));
+        assert!(headers[2]
+            .1
+            .contains("SignedHeaders=host;x-amz-date;x-amz-security-token,"));
+    }
+}
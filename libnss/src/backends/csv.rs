@@ -0,0 +1,635 @@
+//! Serves users, groups and hosts from CSV files, such as a spreadsheet periodically exported
+//! from an HR or IPAM system.
+//!
+//! Each database reads its own file, whose first row names the columns. Which column holds which
+//! field is configurable, so exports can be used as they are. A column mapped to `None` isn't read
+//! and the field takes its default instead.
+//!
+//! Hosts take one row per address; rows sharing a name are answered together. Group members and
+//! host aliases are lists within one cell, split on `list_separator`.
+//!
+//! Rows that don't parse are skipped. A file that can't be read or lacks a mapped column is
+//! ignored in favour of what was last loaded from it, and every file is reloaded when it changes.
+
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backends::watch::WatchedFile;
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+pub struct PasswdColumns {
+    pub name: String,
+    pub uid: String,
+    pub gid: String,
+    /// Defaults to `x`
+    pub passwd: Option<String>,
+    /// Defaults to empty
+    pub gecos: Option<String>,
+    /// Defaults to `/`
+    pub dir: Option<String>,
+    /// Defaults to `/usr/sbin/nologin`
+    pub shell: Option<String>,
+}
+
+impl Default for PasswdColumns {
+    fn default() -> Self {
+        PasswdColumns {
+            name: "name".to_string(),
+            uid: "uid".to_string(),
+            gid: "gid".to_string(),
+            passwd: None,
+            gecos: Some("gecos".to_string()),
+            dir: Some("dir".to_string()),
+            shell: Some("shell".to_string()),
+        }
+    }
+}
+
+pub struct GroupColumns {
+    pub name: String,
+    pub gid: String,
+    /// Defaults to `x`
+    pub passwd: Option<String>,
+    /// Defaults to no members
+    pub members: Option<String>,
+}
+
+impl Default for GroupColumns {
+    fn default() -> Self {
+        GroupColumns {
+            name: "name".to_string(),
+            gid: "gid".to_string(),
+            passwd: None,
+            members: Some("members".to_string()),
+        }
+    }
+}
+
+pub struct HostColumns {
+    pub name: String,
+    pub address: String,
+    /// Defaults to no aliases
+    pub aliases: Option<String>,
+}
+
+impl Default for HostColumns {
+    fn default() -> Self {
+        HostColumns {
+            name: "name".to_string(),
+            address: "address".to_string(),
+            aliases: Some("aliases".to_string()),
+        }
+    }
+}
+
+pub struct CsvTable<C> {
+    pub path: PathBuf,
+    pub columns: C,
+}
+
+pub struct CsvConfig {
+    pub passwd: Option<CsvTable<PasswdColumns>>,
+    pub group: Option<CsvTable<GroupColumns>>,
+    pub hosts: Option<CsvTable<HostColumns>>,
+    pub delimiter: u8,
+    pub list_separator: char,
+    pub check_interval: Duration,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        CsvConfig {
+            passwd: None,
+            group: None,
+            hosts: None,
+            delimiter: b',',
+            list_separator: ' ',
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+struct HostRow {
+    name: String,
+    aliases: Vec<String>,
+    address: IpAddr,
+}
+
+struct Table<C, T> {
+    columns: C,
+    file: WatchedFile<Vec<T>>,
+}
+
+impl<C, T> Table<C, T> {
+    fn new(table: CsvTable<C>, check_interval: Duration) -> Self {
+        Table {
+            columns: table.columns,
+            file: WatchedFile::new(table.path, check_interval),
+        }
+    }
+}
+
+pub struct CsvBackend {
+    passwd: Option<Table<PasswdColumns, Passwd>>,
+    group: Option<Table<GroupColumns, Group>>,
+    hosts: Option<Table<HostColumns, HostRow>>,
+    delimiter: u8,
+    list_separator: char,
+}
+
+impl CsvBackend {
+    /// Creates the backend; each file is first read by the first lookup that needs it.
+    pub fn new(config: CsvConfig) -> Self {
+        let interval = config.check_interval;
+        CsvBackend {
+            passwd: config.passwd.map(|table| Table::new(table, interval)),
+            group: config.group.map(|table| Table::new(table, interval)),
+            hosts: config.hosts.map(|table| Table::new(table, interval)),
+            delimiter: config.delimiter,
+            list_separator: config.list_separator,
+        }
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.passwd().to_vec()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        self.passwd().iter().find(|u| u.uid == uid).cloned()
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        self.passwd().iter().find(|u| u.name == name).cloned()
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.groups().to_vec()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        self.groups().iter().find(|g| g.gid == gid).cloned()
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        self.groups().iter().find(|g| g.name == name).cloned()
+    }
+
+    /// One entry per host name and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let rows = self.hosts();
+        let mut names: Vec<&str> = Vec::new();
+        for row in rows.iter() {
+            if !names.contains(&row.name.as_str()) {
+                names.push(&row.name);
+            }
+        }
+
+        let mut hosts = Vec::new();
+        for name in names {
+            let matching: Vec<&HostRow> = rows.iter().filter(|row| row.name == name).collect();
+            hosts.extend(to_host(&matching, AddressFamily::IPv4));
+            hosts.extend(to_host(&matching, AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches the name or any alias, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let rows = self.hosts();
+        let name = &rows
+            .iter()
+            .find(|row| {
+                std::iter::once(&row.name)
+                    .chain(&row.aliases)
+                    .any(|n| n.eq_ignore_ascii_case(name))
+            })?
+            .name;
+        let matching: Vec<&HostRow> = rows.iter().filter(|row| &row.name == name).collect();
+
+        match family {
+            AddressFamily::Unspecified => to_host(&matching, AddressFamily::IPv4)
+                .or_else(|| to_host(&matching, AddressFamily::IPv6)),
+            family => to_host(&matching, family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let rows = self.hosts();
+        let name = &rows.iter().find(|row| row.address == addr)?.name;
+        let matching: Vec<&HostRow> = rows.iter().filter(|row| &row.name == name).collect();
+
+        let family = match addr {
+            IpAddr::V4(_) => AddressFamily::IPv4,
+            IpAddr::V6(_) => AddressFamily::IPv6,
+        };
+        to_host(&matching, family)
+    }
+
+    fn passwd(&self) -> Arc<Vec<Passwd>> {
+        let table = match &self.passwd {
+            Some(table) => table,
+            None => return Arc::default(),
+        };
+        let c = &table.columns;
+        let columns = [
+            Some(&c.name),
+            Some(&c.uid),
+            Some(&c.gid),
+            c.passwd.as_ref(),
+            c.gecos.as_ref(),
+            c.dir.as_ref(),
+            c.shell.as_ref(),
+        ];
+
+        table.file.get(|path| {
+            read(path, self.delimiter, &columns, |f| {
+                Some(Passwd {
+                    name: f[0]?.to_string(),
+                    name_bytes: None,
+                    uid: f[1]?.parse().ok()?,
+                    gid: f[2]?.parse().ok()?,
+                    passwd: f[3].unwrap_or("x").to_string(),
+                    gecos: f[4].unwrap_or("").to_string(),
+                    dir: f[5].unwrap_or("/").to_string(),
+                    shell: f[6].unwrap_or("/usr/sbin/nologin").to_string(),
+                })
+            })
+        })
+    }
+
+    fn groups(&self) -> Arc<Vec<Group>> {
+        let table = match &self.group {
+            Some(table) => table,
+            None => return Arc::default(),
+        };
+        let c = &table.columns;
+        let columns = [
+            Some(&c.name),
+            Some(&c.gid),
+            c.passwd.as_ref(),
+            c.members.as_ref(),
+        ];
+
+        table.file.get(|path| {
+            read(path, self.delimiter, &columns, |f| {
+                Some(Group {
+                    name: f[0]?.to_string(),
+                    name_bytes: None,
+                    gid: f[1]?.parse().ok()?,
+                    passwd: f[2].unwrap_or("x").to_string(),
+                    members: self.list(f[3]),
+                })
+            })
+        })
+    }
+
+    fn hosts(&self) -> Arc<Vec<HostRow>> {
+        let table = match &self.hosts {
+            Some(table) => table,
+            None => return Arc::default(),
+        };
+        let c = &table.columns;
+        let columns = [Some(&c.name), Some(&c.address), c.aliases.as_ref()];
+
+        table.file.get(|path| {
+            read(path, self.delimiter, &columns, |f| {
+                Some(HostRow {
+                    name: f[0]?.to_string(),
+                    address: f[1]?.parse().ok()?,
+                    aliases: self.list(f[2]),
+                })
+            })
+        })
+    }
+
+    fn list(&self, cell: Option<&str>) -> Vec<String> {
+        cell.unwrap_or("")
+            .split(self.list_separator)
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// Reads every row of the file at `path`, passing `convert` the cells of the named `columns` in
+/// order. Rows that fail to convert are skipped.
+fn read<T, F>(
+    path: &Path,
+    delimiter: u8,
+    columns: &[Option<&String>],
+    convert: F,
+) -> io::Result<Vec<T>>
+where
+    F: Fn(&[Option<&str>]) -> Option<T>,
+{
+    let mut reader = ::csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .trim(::csv::Trim::All)
+        .flexible(true)
+        .from_path(path)?;
+
+    let headers = reader.headers()?.clone();
+    let indexes = columns
+        .iter()
+        .map(|column| match column {
+            None => Ok(None),
+            Some(column) => match headers.iter().position(|h| h == column.as_str()) {
+                Some(index) => Ok(Some(index)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has no `{}` column", path.display(), column),
+                )),
+            },
+        })
+        .collect::<io::Result<Vec<Option<usize>>>>()?;
+
+    let mut entries = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        // An empty cell counts as missing, so optional fields take their defaults
+        let cells: Vec<Option<&str>> = indexes
+            .iter()
+            .map(|index| {
+                index
+                    .and_then(|i| record.get(i))
+                    .filter(|cell| !cell.is_empty())
+            })
+            .collect();
+        entries.extend(convert(&cells));
+    }
+    Ok(entries)
+}
+
+/// Combines the rows for one host into an entry with their addresses of `family`.
+fn to_host(rows: &[&HostRow], family: AddressFamily) -> Option<Host> {
+    let addresses = match family {
+        AddressFamily::IPv4 => Addresses::V4(
+            rows.iter()
+                .filter_map(|row| match row.address {
+                    IpAddr::V4(addr) => Some(addr),
+                    IpAddr::V6(_) => None,
+                })
+                .collect(),
+        ),
+        AddressFamily::IPv6 => Addresses::V6(
+            rows.iter()
+                .filter_map(|row| match row.address {
+                    IpAddr::V6(addr) => Some(addr),
+                    IpAddr::V4(_) => None,
+                })
+                .collect(),
+        ),
+        AddressFamily::Unspecified | AddressFamily::Other(_) => return None,
+    };
+    if addresses.is_empty() {
+        return None;
+    }
+
+    let mut aliases: Vec<String> = Vec::new();
+    for alias in rows.iter().flat_map(|row| &row.aliases) {
+        if !aliases.contains(alias) {
+            aliases.push(alias.clone());
+        }
+    }
+
+    Some(Host {
+        name: rows[0].name.clone(),
+        aliases,
+        addresses,
+        canonical_name: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::Ipv4Addr;
+
+    fn file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("libnss-csv-{}-{}.csv", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn table<C: Default>(path: &Path) -> Option<CsvTable<C>> {
+        Some(CsvTable {
+            path: path.to_path_buf(),
+            columns: C::default(),
+        })
+    }
+
+    #[test]
+    fn passwd() {
+        let path = file(
+            "passwd",
+            "uid,name,gid,gecos,dir,shell,extra\n\
+             1000,alice,100,\"Alice, Admin\",/home/alice,/bin/sh,ignored\n\
+             1001,bob,100,,,\n\
+             not a number,carol,100,,,\n\
+             1002,dave\n",
+        );
+        let csv = CsvBackend::new(CsvConfig {
+            passwd: table(&path),
+            ..CsvConfig::default()
+        });
+
+        assert_eq!(
+            csv.get_passwd_by_name("alice"),
+            Some(Passwd {
+                name: "alice".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                uid: Uid::from_raw(1000),
+                gid: Gid::from_raw(100),
+                gecos: "Alice, Admin".to_string(),
+                dir: "/home/alice".to_string(),
+                shell: "/bin/sh".to_string(),
+            })
+        );
+        // Empty cells take the defaults
+        let bob = csv.get_passwd_by_uid(Uid::from_raw(1001)).unwrap();
+        assert_eq!(
+            (bob.gecos.as_str(), bob.dir.as_str(), bob.shell.as_str()),
+            ("", "/", "/usr/sbin/nologin")
+        );
+        // Rows that don't parse are skipped
+        assert_eq!(csv.get_passwd_by_name("carol"), None);
+        assert_eq!(csv.get_passwd_by_name("dave"), None);
+        assert_eq!(csv.get_all_passwd().len(), 2);
+        // Databases that aren't configured are empty
+        assert!(csv.get_all_groups().is_empty());
+        assert!(csv.get_all_hosts().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn mapped_columns() {
+        let path = file(
+            "mapped",
+            "Login;Employee ID;Team;Full Name;Home\n alice ; 1000 ; 100 ; Alice ; /home/alice \n",
+        );
+        let csv = CsvBackend::new(CsvConfig {
+            passwd: Some(CsvTable {
+                path: path.clone(),
+                columns: PasswdColumns {
+                    name: "Login".to_string(),
+                    uid: "Employee ID".to_string(),
+                    gid: "Team".to_string(),
+                    passwd: None,
+                    gecos: Some("Full Name".to_string()),
+                    // Not read, even though the file has it
+                    dir: None,
+                    shell: None,
+                },
+            }),
+            delimiter: b';',
+            ..CsvConfig::default()
+        });
+
+        let alice = csv.get_passwd_by_name("alice").unwrap();
+        assert_eq!(
+            (alice.uid, alice.gecos.as_str(), alice.dir.as_str()),
+            (Uid::from_raw(1000), "Alice", "/")
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn groups() {
+        let path = file(
+            "group",
+            "name,gid,members\nstaff,50,alice  bob\nwheel,10,\nbad,,alice\n",
+        );
+        let csv = CsvBackend::new(CsvConfig {
+            group: table(&path),
+            ..CsvConfig::default()
+        });
+
+        assert_eq!(
+            csv.get_group_by_name("staff"),
+            Some(Group {
+                name: "staff".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                gid: Gid::from_raw(50),
+                members: vec!["alice".to_string(), "bob".to_string()],
+            })
+        );
+        assert!(csv
+            .get_group_by_gid(Gid::from_raw(10))
+            .unwrap()
+            .members
+            .is_empty());
+        assert_eq!(csv.get_all_groups().len(), 2);
+
+        let csv = CsvBackend::new(CsvConfig {
+            group: table(&path),
+            list_separator: ',',
+            ..CsvConfig::default()
+        });
+        fs::write(&path, "name,gid,members\nstaff,50,\"alice, bob,\"\n").unwrap();
+        assert_eq!(
+            csv.get_group_by_name("staff").unwrap().members,
+            ["alice", "bob"]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hosts() {
+        let path = file(
+            "hosts",
+            "name,address,aliases\n\
+             db,10.0.0.1,db.local\n\
+             db,10.0.0.2,database db.local\n\
+             db,fd00::1,\n\
+             web,fd00::2,www\n\
+             bad,not an address,\n",
+        );
+        let csv = CsvBackend::new(CsvConfig {
+            hosts: table(&path),
+            ..CsvConfig::default()
+        });
+
+        let db = Host {
+            name: "db".to_string(),
+            aliases: vec!["db.local".to_string(), "database".to_string()],
+            addresses: Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]),
+            canonical_name: None,
+        };
+        assert_eq!(
+            csv.get_host_by_name("DATABASE", AddressFamily::Unspecified),
+            Some(db.clone())
+        );
+        assert_eq!(
+            csv.get_host_by_name("db", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::1".parse().unwrap()])
+        );
+        assert_eq!(
+            csv.get_host_by_name("www", AddressFamily::Unspecified)
+                .unwrap()
+                .name,
+            "web"
+        );
+        assert_eq!(csv.get_host_by_name("web", AddressFamily::IPv4), None);
+        assert_eq!(csv.get_host_by_name("db", AddressFamily::Other(17)), None);
+        assert_eq!(
+            csv.get_host_by_name("bad", AddressFamily::Unspecified),
+            None
+        );
+
+        assert_eq!(csv.get_host_by_addr("10.0.0.2".parse().unwrap()), Some(db));
+        assert_eq!(csv.get_host_by_addr("10.0.0.3".parse().unwrap()), None);
+        // db over both families, and web
+        assert_eq!(csv.get_all_hosts().len(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reloads() {
+        let path = file(
+            "reload",
+            "name,uid,gid,gecos,dir,shell\nalice,1000,100,,,\n",
+        );
+        let csv = CsvBackend::new(CsvConfig {
+            passwd: table(&path),
+            check_interval: Duration::from_secs(0),
+            ..CsvConfig::default()
+        });
+        assert!(csv.get_passwd_by_name("alice").is_some());
+
+        fs::write(
+            &path,
+            "name,uid,gid,gecos,dir,shell\nalice,1000,100,,,\nbob,1001,100,,,\n",
+        )
+        .unwrap();
+        assert!(csv.get_passwd_by_name("bob").is_some());
+
+        // Missing a mapped column, so what was loaded before is kept
+        fs::write(&path, "name,uid,gid\ncarol,1002,100\n").unwrap();
+        assert!(csv.get_passwd_by_name("bob").is_some());
+        assert!(csv.get_passwd_by_name("carol").is_none());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(csv.get_all_passwd().len(), 2);
+
+        let missing = CsvBackend::new(CsvConfig {
+            passwd: table(&path),
+            ..CsvConfig::default()
+        });
+        assert!(missing.get_all_passwd().is_empty());
+    }
+}
@@ -0,0 +1,564 @@
+//! Resolves the hostnames DHCP clients sent to the addresses they were leased, and back, from
+//! dnsmasq's or ISC dhcpd's lease file, for routers that hand out addresses but don't run a DNS
+//! server that knows about them.
+//!
+//! - [`LeaseFormat::Dnsmasq`] reads dnsmasq's `dhcp-leasefile`, one lease per line, including
+//!   DHCPv6 leases after its `duid` line.
+//! - [`LeaseFormat::IscDhcpd`] reads dhcpd's `dhcpd.leases`, where the last `lease` block for an
+//!   address replaces the earlier ones and only those in `binding state active` count.
+//!
+//! Leases that have expired are ignored, as are those whose client sent no hostname or one that
+//! isn't a valid host name, since clients choose their own. A name with several leases resolves to
+//! the address of the one expiring last first. The file is checked for changes at most every
+//! `check_interval`.
+//!
+//! ```
+//! use libnss::backends::dhcp_leases::{DhcpLeasesBackend, DhcpLeasesConfig, LeaseFormat};
+//! use libnss::host::AddressFamily;
+//! # let path = std::env::temp_dir().join(format!("libnss-dhcpd-doc-{}.leases", std::process::id()));
+//! # std::fs::write(&path, "lease 192.168.1.20 {\n  starts 3 2024/01/03 10:00:00;\n  ends never;\n  binding state active;\n  client-hostname \"printer\";\n}\n").unwrap();
+//!
+//! let backend = DhcpLeasesBackend::new(DhcpLeasesConfig {
+//!     path: path.clone(),
+//!     format: LeaseFormat::IscDhcpd,
+//!     domain: Some("lan".to_string()),
+//!     ..DhcpLeasesConfig::default()
+//! });
+//!
+//! let printer = backend.get_host_by_name("printer.lan", AddressFamily::IPv4).unwrap();
+//! assert_eq!(printer.addresses, "192.168.1.20".parse::<std::net::Ipv4Addr>().unwrap().into());
+//! let addr = "192.168.1.20".parse().unwrap();
+//! assert_eq!(backend.get_host_by_addr(addr).unwrap().name, "printer.lan");
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backends::watch::WatchedFile;
+use crate::host::{AddressFamily, Addresses, Host};
+
+pub enum LeaseFormat {
+    Dnsmasq,
+    IscDhcpd,
+}
+
+pub struct DhcpLeasesConfig {
+    pub path: PathBuf,
+    pub format: LeaseFormat,
+    /// Also answer `<name>.<domain>`, and use that as the canonical name
+    pub domain: Option<String>,
+    pub check_interval: Duration,
+}
+
+impl Default for DhcpLeasesConfig {
+    fn default() -> Self {
+        DhcpLeasesConfig {
+            path: PathBuf::from("/var/lib/misc/dnsmasq.leases"),
+            format: LeaseFormat::Dnsmasq,
+            domain: None,
+            check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Lease {
+    /// Lowercased
+    name: String,
+    addr: IpAddr,
+    /// Seconds since the epoch, or `None` for a lease that never expires
+    expires: Option<u64>,
+}
+
+impl Lease {
+    fn current(&self, now: u64) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+pub struct DhcpLeasesBackend {
+    format: LeaseFormat,
+    domain: Option<String>,
+    /// Latest expiry first
+    file: WatchedFile<Vec<Lease>>,
+}
+
+impl DhcpLeasesBackend {
+    pub fn new(config: DhcpLeasesConfig) -> Self {
+        DhcpLeasesBackend {
+            format: config.format,
+            domain: config
+                .domain
+                .map(|domain| domain.trim_matches('.').to_lowercase()),
+            file: WatchedFile::new(config.path, config.check_interval),
+        }
+    }
+
+    /// One entry per name and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let leases = self.leases();
+        let now = now();
+        let mut names: Vec<&str> = Vec::new();
+        for lease in leases.iter().filter(|lease| lease.current(now)) {
+            if !names.contains(&lease.name.as_str()) {
+                names.push(&lease.name);
+            }
+        }
+        names
+            .into_iter()
+            .flat_map(|name| {
+                let v4 = self.to_host(&leases, name, AddressFamily::IPv4, now);
+                v4.into_iter()
+                    .chain(self.to_host(&leases, name, AddressFamily::IPv6, now))
+            })
+            .collect()
+    }
+
+    /// Matches names and `domain` names, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let name = match &self.domain {
+            Some(domain) => name
+                .strip_suffix(domain.as_str())
+                .and_then(|name| name.strip_suffix('.'))
+                .map(str::to_string)
+                .unwrap_or(name),
+            None => name,
+        };
+
+        let leases = self.leases();
+        let now = now();
+        match family {
+            AddressFamily::Unspecified => self
+                .to_host(&leases, &name, AddressFamily::IPv4, now)
+                .or_else(|| self.to_host(&leases, &name, AddressFamily::IPv6, now)),
+            family => self.to_host(&leases, &name, family, now),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let leases = self.leases();
+        let now = now();
+        let lease = leases
+            .iter()
+            .find(|lease| lease.addr == addr && lease.current(now))?;
+        let family = match addr {
+            IpAddr::V4(_) => AddressFamily::IPv4,
+            IpAddr::V6(_) => AddressFamily::IPv6,
+        };
+        let mut host = self.to_host(&leases, &lease.name, family, now)?;
+        host.addresses = Addresses::from(addr);
+        Some(host)
+    }
+
+    /// The entry for `name`'s current leases in `family`, if it has any.
+    fn to_host(
+        &self,
+        leases: &[Lease],
+        name: &str,
+        family: AddressFamily,
+        now: u64,
+    ) -> Option<Host> {
+        let addrs = leases
+            .iter()
+            .filter(|lease| lease.name == name && lease.current(now))
+            .map(|lease| lease.addr);
+        let addresses = match family {
+            AddressFamily::IPv4 => {
+                let v4: Vec<Ipv4Addr> = addrs
+                    .filter_map(|addr| match addr {
+                        IpAddr::V4(addr) => Some(addr),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect();
+                Some(Addresses::V4(v4)).filter(|a| !a.is_empty())?
+            }
+            AddressFamily::IPv6 => {
+                let v6: Vec<Ipv6Addr> = addrs
+                    .filter_map(|addr| match addr {
+                        IpAddr::V6(addr) => Some(addr),
+                        IpAddr::V4(_) => None,
+                    })
+                    .collect();
+                Some(Addresses::V6(v6)).filter(|a| !a.is_empty())?
+            }
+            _ => return None,
+        };
+
+        let (name, aliases) = match &self.domain {
+            Some(domain) => (format!("{}.{}", name, domain), vec![name.to_string()]),
+            None => (name.to_string(), vec![]),
+        };
+        Some(Host {
+            name,
+            aliases,
+            addresses,
+            canonical_name: None,
+        })
+    }
+
+    fn leases(&self) -> Arc<Vec<Lease>> {
+        self.file.get(|path| {
+            let mut leases = match self.format {
+                LeaseFormat::Dnsmasq => read_dnsmasq(path)?,
+                LeaseFormat::IscDhcpd => read_dhcpd(path)?,
+            };
+            leases.sort_by_key(|lease| std::cmp::Reverse(lease.expires.unwrap_or(u64::MAX)));
+            Ok(leases)
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// The client's hostname lowercased, if it sent one that can be a host name.
+fn hostname(name: &str) -> Option<String> {
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    Some(name.to_lowercase()).filter(|_| valid)
+}
+
+/// Reads dnsmasq's lease file: `<expiry> <MAC or IAID> <address> <hostname> <client ID>` per
+/// lease, with `*` for a missing hostname and an expiry of 0 for one that never expires.
+fn read_dnsmasq(path: &Path) -> io::Result<Vec<Lease>> {
+    let contents = fs::read_to_string(path)?;
+    let mut leases = Vec::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[0] == "duid" {
+            continue;
+        }
+        let (expires, addr, name) = match (
+            fields[0].parse::<u64>(),
+            fields[2].parse(),
+            hostname(fields[3]),
+        ) {
+            (Ok(expires), Ok(addr), Some(name)) => (expires, addr, name),
+            _ => continue,
+        };
+        leases.push(Lease {
+            name,
+            addr,
+            expires: Some(expires).filter(|&expires| expires != 0),
+        });
+    }
+    Ok(leases)
+}
+
+/// Reads dhcpd's lease database, which is appended to as leases change, so the last block for
+/// each address is the current one.
+fn read_dhcpd(path: &Path) -> io::Result<Vec<Lease>> {
+    let contents = fs::read_to_string(path)?;
+    let mut by_addr: HashMap<IpAddr, Option<Lease>> = HashMap::new();
+
+    let mut lease: Option<(IpAddr, Option<String>, Option<u64>, bool)> = None;
+    for statement in statements(&contents) {
+        let words: Vec<&str> = statement.split_whitespace().collect();
+        match (words.as_slice(), &mut lease) {
+            (["lease", addr, "{"], None) => {
+                lease = addr.parse().ok().map(|addr| (addr, None, None, false));
+            }
+            (["}"], Some(_)) => {
+                let (addr, name, expires, active) = lease.take().unwrap();
+                let current = match name {
+                    Some(name) if active => Some(Lease {
+                        name,
+                        addr,
+                        expires,
+                    }),
+                    _ => None,
+                };
+                by_addr.insert(addr, current);
+            }
+            (["binding", "state", state], Some((_, _, _, active))) => *active = *state == "active",
+            (["client-hostname", sent], Some((_, name, _, _))) => {
+                *name = hostname(sent.trim_matches('"'))
+            }
+            (["ends", "never"], Some((_, _, expires, _))) => *expires = None,
+            (["ends", "epoch", secs, ..], Some((_, _, expires, _))) => *expires = secs.parse().ok(),
+            (["ends", _, date, time], Some((_, _, expires, _))) => {
+                *expires = utc_seconds(date, time)
+            }
+            _ => {}
+        }
+    }
+    Ok(by_addr.into_values().flatten().collect())
+}
+
+/// Splits dhcpd's format into statements, each ending in `;`, `{` or `}`, with comments dropped.
+/// Block openings keep their `{` and closings are a lone `}`.
+fn statements(contents: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for line in contents.lines() {
+        for c in line.chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    current.push(c);
+                }
+                '#' if !quoted => break,
+                ';' if !quoted => statements.push(std::mem::take(&mut current)),
+                '{' if !quoted => {
+                    current.push_str(" {");
+                    statements.push(std::mem::take(&mut current));
+                }
+                '}' if !quoted => {
+                    statements.push(std::mem::take(&mut current));
+                    statements.push("}".to_string());
+                }
+                c => current.push(c),
+            }
+        }
+        current.push(' ');
+    }
+    statements
+}
+
+/// Seconds since the epoch at `YYYY/MM/DD HH:MM:SS` UTC, as dhcpd writes times.
+fn utc_seconds(date: &str, time: &str) -> Option<u64> {
+    let date: Vec<i64> = date
+        .split('/')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let time: Vec<u64> = time
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+
+    // Days from the civil date, after Howard Hinnant's algorithm
+    let (year, month, day) = (date[0] - i64::from(date[1] <= 2), date[1], date[2]);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+    Some(days * 86_400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "libnss-dhcp-leases-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn fields(mut leases: Vec<Lease>) -> Vec<(String, IpAddr, Option<u64>)> {
+        leases.sort_by_key(|lease| lease.addr);
+        leases
+            .into_iter()
+            .map(|lease| (lease.name, lease.addr, lease.expires))
+            .collect()
+    }
+
+    fn lease(name: &str, addr: &str, expires: Option<u64>) -> (String, IpAddr, Option<u64>) {
+        (name.to_string(), addr.parse().unwrap(), expires)
+    }
+
+    #[test]
+    fn hostnames() {
+        assert_eq!(hostname("Printer").as_deref(), Some("printer"));
+        assert_eq!(hostname("nas-1.home").as_deref(), Some("nas-1.home"));
+        assert_eq!(hostname("android_1234").as_deref(), Some("android_1234"));
+        for name in [
+            "",
+            "*",
+            "-printer",
+            "a..b",
+            "my printer",
+            "caf\u{e9}",
+            &"a".repeat(64),
+        ] {
+            assert_eq!(hostname(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn dhcpd_times() {
+        for (date, time, seconds) in [
+            ("1970/01/01", "00:00:00", 0),
+            ("2000/02/29", "00:00:00", 951_782_400),
+            ("2015/08/30", "12:36:00", 1_440_938_160),
+            ("2099/12/31", "23:59:59", 4_102_444_799),
+        ] {
+            assert_eq!(utc_seconds(date, time), Some(seconds), "{} {}", date, time);
+        }
+        assert_eq!(utc_seconds("1969/12/31", "23:59:59"), None);
+        assert_eq!(utc_seconds("2024/01", "00:00:00"), None);
+        assert_eq!(utc_seconds("2024/01/01", "noon"), None);
+    }
+
+    #[test]
+    fn dhcpd_statements() {
+        let contents = "# written by dhcpd\n\
+                        lease 10.0.0.1 {\n  starts 3 2024/01/03 10:00:00; # renewed\n\
+                        \x20 client-hostname \"a;b#c\";\n}\nserver-duid \"x\";";
+        assert_eq!(
+            statements(contents)
+                .iter()
+                .map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>(),
+            [
+                "lease 10.0.0.1 {",
+                "starts 3 2024/01/03 10:00:00",
+                "client-hostname \"a;b#c\"",
+                "",
+                "}",
+                "server-duid \"x\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn dnsmasq_leases() {
+        let path = file(
+            "dnsmasq",
+            "1700000000 52:54:00:aa:bb:01 192.168.1.20 Printer 01:52:54:00:aa:bb:01\n\
+             0 52:54:00:aa:bb:02 192.168.1.21 nas *\n\
+             1700000000 52:54:00:aa:bb:03 192.168.1.22 * *\n\
+             1700000000 52:54:00:aa:bb:04 not-an-address laptop *\n\
+             short line\n\
+             duid 00:01:00:01:2c:00:00:00:52:54:00:aa:bb:00\n\
+             1700000000 1234 fd00::20 printer 00:01:00:01\n",
+        );
+        assert_eq!(
+            fields(read_dnsmasq(&path).unwrap()),
+            [
+                lease("printer", "192.168.1.20", Some(1_700_000_000)),
+                lease("nas", "192.168.1.21", None),
+                lease("printer", "fd00::20", Some(1_700_000_000)),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(read_dnsmasq(&path).is_err());
+    }
+
+    #[test]
+    fn dhcpd_leases() {
+        let path = file(
+            "dhcpd",
+            "lease 10.0.0.1 {\n  binding state active;\n  client-hostname \"old\";\n\
+             \x20 ends never;\n}\n\
+             lease 10.0.0.2 {\n  ends 3 2024/01/03 10:00:00;\n  binding state active;\n\
+             \x20 client-hostname \"Printer\";\n}\n\
+             lease 10.0.0.3 {\n  ends epoch 1700000000; # Tue Nov 14 22:13:20 2023\n\
+             \x20 binding state active;\n  client-hostname \"nas\";\n}\n\
+             lease 10.0.0.4 {\n  binding state free;\n  client-hostname \"gone\";\n}\n\
+             lease 10.0.0.5 {\n  binding state active;\n}\n\
+             lease 10.0.0.1 {\n  binding state active;\n  client-hostname \"new\";\n\
+             \x20 ends never;\n}\n\
+             lease 10.0.0.6 {\n  binding state active;\n  client-hostname \"laptop\";\n}\n\
+             lease 10.0.0.6 {\n  binding state expired;\n  client-hostname \"laptop\";\n}\n",
+        );
+        assert_eq!(
+            fields(read_dhcpd(&path).unwrap()),
+            [
+                lease("new", "10.0.0.1", None),
+                lease("printer", "10.0.0.2", Some(1_704_276_000)),
+                lease("nas", "10.0.0.3", Some(1_700_000_000)),
+            ]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn lookups() {
+        let now = now();
+        let path = file(
+            "lookups",
+            &format!(
+                "{soon} 52:54:00:aa:bb:01 192.168.1.20 printer *\n\
+                 {later} 52:54:00:aa:bb:02 192.168.1.21 printer *\n\
+                 {later} 1234 fd00::20 printer *\n\
+                 1 52:54:00:aa:bb:03 192.168.1.22 laptop *\n\
+                 0 52:54:00:aa:bb:04 192.168.1.23 nas *\n",
+                soon = now + 600,
+                later = now + 3600,
+            ),
+        );
+        let leases = DhcpLeasesBackend::new(DhcpLeasesConfig {
+            path: path.clone(),
+            domain: Some(".Lan.".to_string()),
+            ..DhcpLeasesConfig::default()
+        });
+
+        let v4 = |addrs: &[&str]| Addresses::V4(addrs.iter().map(|a| a.parse().unwrap()).collect());
+        // The lease expiring last comes first
+        assert_eq!(
+            leases.get_host_by_name("PRINTER.lan.", AddressFamily::Unspecified),
+            Some(Host {
+                name: "printer.lan".to_string(),
+                aliases: vec!["printer".to_string()],
+                addresses: v4(&["192.168.1.21", "192.168.1.20"]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            leases
+                .get_host_by_name("printer", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::20".parse().unwrap()])
+        );
+        assert_eq!(
+            leases
+                .get_host_by_name("nas", AddressFamily::IPv4)
+                .unwrap()
+                .addresses,
+            v4(&["192.168.1.23"])
+        );
+        assert_eq!(leases.get_host_by_name("laptop", AddressFamily::IPv4), None);
+        assert_eq!(leases.get_host_by_name("nas", AddressFamily::IPv6), None);
+        assert_eq!(
+            leases.get_host_by_name("printer.other", AddressFamily::IPv4),
+            None
+        );
+
+        // Only the address asked about
+        let printer = leases
+            .get_host_by_addr("192.168.1.20".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            (printer.name.as_str(), printer.addresses),
+            ("printer.lan", v4(&["192.168.1.20"]))
+        );
+        assert_eq!(
+            leases.get_host_by_addr("192.168.1.22".parse().unwrap()),
+            None
+        );
+        // printer over both families, and nas
+        assert_eq!(leases.get_all_hosts().len(), 3);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! Synthesizes users on demand, like systemd's `DynamicUser=`, so sandboxed services can each run
+//! under their own identity without anything being written to `/etc/passwd` first.
+//!
+//! Any name starting with `prefix` is a user, with a uid hashed from the name into `uids`, a group
+//! of the same name and id, and `gecos`, `home` and `shell` filled from templates in which
+//! `{name}` and `{uid}` are replaced. The same name gets the same uid on every machine and in
+//! every process.
+//!
+//! Without a `state_dir` that is all there is to it, but a uid can't be looked up and two names
+//! that hash alike share a uid. With one, e.g. `/run/<module>`, each user is recorded there the
+//! first time its name is looked up, as a symlink named for the uid pointing at the name, as
+//! systemd records its own in `/run/systemd/dynamic-uid`. A name whose uid is taken then moves on
+//! to the next free one, and uids can be looked up and users listed. The directory only needs to
+//! be writable by whatever looks each name up first, typically the service manager starting it;
+//! other processes find the users recorded so far. Putting it on a tmpfs frees every uid at boot.
+//!
+//! ```
+//! use libnss::backends::dynamic_user::{DynamicUserBackend, DynamicUserConfig};
+//! # let state = std::env::temp_dir().join(format!("libnss-dynamic-doc-{}", std::process::id()));
+//!
+//! let backend = DynamicUserBackend::new(DynamicUserConfig {
+//!     state_dir: Some(state.clone()),
+//!     ..DynamicUserConfig::default()
+//! });
+//!
+//! let user = backend.get_passwd_by_name("svc-web").unwrap();
+//! assert!((61184..=65519).contains(&user.uid.as_raw()));
+//! assert_eq!(user.dir, "/run/svc-web");
+//! assert_eq!(backend.get_passwd_by_uid(user.uid).unwrap().name, "svc-web");
+//! assert!(backend.get_passwd_by_name("alice").is_none());
+//! # std::fs::remove_dir_all(&state).unwrap();
+//! ```
+
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use crate::group::Group;
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+pub struct DynamicUserConfig {
+    /// What a name must start with to be a user; an empty prefix makes a user of any name looked
+    /// up, including every typo at a login prompt
+    pub prefix: String,
+    pub uids: RangeInclusive<u32>,
+    /// Where users are recorded, if anywhere
+    pub state_dir: Option<PathBuf>,
+    pub gecos: String,
+    pub home: String,
+    pub shell: String,
+}
+
+impl Default for DynamicUserConfig {
+    fn default() -> Self {
+        DynamicUserConfig {
+            prefix: "svc-".to_string(),
+            // The range systemd allocates its own dynamic users from
+            uids: 61184..=65519,
+            state_dir: None,
+            gecos: "Dynamic User".to_string(),
+            home: "/run/{name}".to_string(),
+            shell: "/usr/sbin/nologin".to_string(),
+        }
+    }
+}
+
+pub struct DynamicUserBackend {
+    config: DynamicUserConfig,
+}
+
+impl DynamicUserBackend {
+    pub fn new(config: DynamicUserConfig) -> Self {
+        DynamicUserBackend { config }
+    }
+
+    /// The recorded users, in uid order; none without a `state_dir`.
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        let dir = match &self.config.state_dir {
+            Some(dir) => dir,
+            None => return Vec::new(),
+        };
+        let mut uids: Vec<u32> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|uid| self.config.uids.contains(uid))
+            .collect();
+        uids.sort_unstable();
+        uids.into_iter()
+            .filter_map(|uid| self.get_passwd_by_uid(Uid::from_raw(uid)))
+            .collect()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        let dir = self.config.state_dir.as_ref()?;
+        if !self.config.uids.contains(&uid.as_raw()) {
+            return None;
+        }
+        let name = recorded(dir, uid.as_raw())?;
+        if !self.is_user(&name) {
+            return None;
+        }
+        Some(self.passwd(name, uid.as_raw()))
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        if !self.is_user(name) {
+            return None;
+        }
+        let uid = match &self.config.state_dir {
+            Some(dir) => self.allocate(dir, name)?,
+            None => self.hashed(name, 0),
+        };
+        Some(self.passwd(name.to_string(), uid))
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.get_all_passwd().into_iter().map(to_group).collect()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        self.get_passwd_by_uid(Uid::from_raw(gid.as_raw()))
+            .map(to_group)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        self.get_passwd_by_name(name).map(to_group)
+    }
+
+    /// Whether `name` is one of ours, and can be recorded as a symlink's target and passed around
+    /// as a user name by other tools.
+    fn is_user(&self, name: &str) -> bool {
+        name.len() > self.config.prefix.len()
+            && name.len() <= 32
+            && name.starts_with(&self.config.prefix)
+            && !name.starts_with('-')
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b))
+    }
+
+    /// The `attempt`th uid `name` may have, counting on from its hash.
+    fn hashed(&self, name: &str, attempt: u64) -> u32 {
+        let (start, end) = (*self.config.uids.start(), *self.config.uids.end());
+        let len = u64::from(end.saturating_sub(start)) + 1;
+        start + (fnv1a(name).wrapping_add(attempt) % len) as u32
+    }
+
+    /// Finds the uid recorded for `name`, recording the first free one if there is none. If the
+    /// directory can't be written, only a uid already recorded is found.
+    fn allocate(&self, dir: &Path, name: &str) -> Option<u32> {
+        let (start, end) = (*self.config.uids.start(), *self.config.uids.end());
+        let len = u64::from(end.saturating_sub(start)) + 1;
+        for attempt in 0..len {
+            let uid = self.hashed(name, attempt);
+            let path = dir.join(uid.to_string());
+            loop {
+                match fs::read_link(&path) {
+                    Ok(target) if target == Path::new(name) => return Some(uid),
+                    Ok(_) => break,
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return None,
+                    Err(_) => {}
+                }
+                // Another process may record the uid first, so look again if it does
+                match create_dir(dir).and_then(|()| symlink(name, &path)) {
+                    Ok(()) => return Some(uid),
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                    Err(_) => return None,
+                }
+            }
+        }
+        None
+    }
+
+    fn passwd(&self, name: String, uid: u32) -> Passwd {
+        let fill = |template: &str| {
+            template
+                .replace("{name}", &name)
+                .replace("{uid}", &uid.to_string())
+        };
+        Passwd {
+            gecos: fill(&self.config.gecos),
+            dir: fill(&self.config.home),
+            shell: fill(&self.config.shell),
+            name,
+            name_bytes: None,
+            passwd: "!".to_string(),
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(uid),
+        }
+    }
+}
+
+fn create_dir(dir: &Path) -> io::Result<()> {
+    match fs::create_dir_all(dir) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The name recorded for `uid`, if any.
+fn recorded(dir: &Path, uid: u32) -> Option<String> {
+    let target = fs::read_link(dir.join(uid.to_string())).ok()?;
+    target.into_os_string().into_string().ok()
+}
+
+fn to_group(passwd: Passwd) -> Group {
+    Group {
+        name: passwd.name,
+        name_bytes: passwd.name_bytes,
+        passwd: "!".to_string(),
+        gid: passwd.gid,
+        members: Vec::new(),
+    }
+}
+
+/// 64 bit FNV-1a, which unlike std's hashers is the same on every platform and release.
+fn fnv1a(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libnss-dynamic-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn backend(state_dir: Option<&Path>, uids: RangeInclusive<u32>) -> DynamicUserBackend {
+        DynamicUserBackend::new(DynamicUserConfig {
+            uids,
+            state_dir: state_dir.map(Path::to_path_buf),
+            ..DynamicUserConfig::default()
+        })
+    }
+
+    #[test]
+    fn fnv1a_is_fnv1a() {
+        assert_eq!(fnv1a(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a("foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn uids_stay_in_range() {
+        for uids in [
+            61184..=65519,
+            10..=10,
+            0..=u32::MAX,
+            u32::MAX - 1..=u32::MAX,
+        ] {
+            let backend = backend(None, uids.clone());
+            for attempt in [0, 1, u64::from(u32::MAX), u64::MAX] {
+                for name in ["svc-a", "svc-web", "svc-backup.daily"] {
+                    let uid = backend.hashed(name, attempt);
+                    assert!(uids.contains(&uid), "{} in {:?}", uid, uids);
+                }
+            }
+        }
+
+        // Stateless, the uid is the hash's and nothing can be looked up by it
+        let stateless = backend(None, 61184..=65519);
+        let user = stateless.get_passwd_by_name("svc-web").unwrap();
+        assert_eq!(user.uid, Uid::from_raw(stateless.hashed("svc-web", 0)));
+        assert_eq!(stateless.get_passwd_by_uid(user.uid), None);
+        assert!(stateless.get_all_passwd().is_empty());
+    }
+
+    #[test]
+    fn names() {
+        let backend = backend(None, 61184..=65519);
+        for name in ["svc-web", "svc-a.b_c-d", "svc-0123456789012345678901234567"] {
+            assert!(backend.is_user(name), "{}", name);
+        }
+        for name in [
+            "svc-",
+            "alice",
+            "svc-web/..",
+            "svc-we b",
+            "svc-01234567890123456789012345678",
+        ] {
+            assert!(!backend.is_user(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn taken_uids_move_on_to_the_next() {
+        let dir = state_dir("collisions");
+        let backend = backend(Some(&dir), 100..=102);
+
+        let first = backend.hashed("svc-web", 0);
+        fs::create_dir_all(&dir).unwrap();
+        symlink("svc-other", dir.join(first.to_string())).unwrap();
+        let web = backend.get_passwd_by_name("svc-web").unwrap();
+        assert_eq!(web.uid.as_raw(), backend.hashed("svc-web", 1));
+        // Wrapping around the end of the range
+        assert_eq!(web.uid.as_raw(), 100 + (first - 100 + 1) % 3);
+        // The same uid again, now it is recorded
+        assert_eq!(backend.get_passwd_by_name("svc-web").unwrap().uid, web.uid);
+
+        // Until every uid is taken
+        let third = backend.get_passwd_by_name("svc-third").unwrap().uid;
+        assert!(third != web.uid && third.as_raw() != first);
+        assert_eq!(backend.get_passwd_by_name("svc-fourth"), None);
+
+        let names: Vec<_> = backend
+            .get_all_passwd()
+            .into_iter()
+            .map(|user| user.name)
+            .collect();
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&"svc-other".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recorded_users_are_read_back() {
+        let dir = state_dir("recorded");
+        let backend = backend(Some(&dir), 61184..=65519);
+        let uid = backend.hashed("svc-db", 0);
+        let other = 61184 + (uid - 61184 + 1) % 4336;
+        fs::create_dir_all(&dir).unwrap();
+        symlink("svc-db", dir.join(uid.to_string())).unwrap();
+        // Not ours, out of range, or not a uid
+        symlink("alice", dir.join(other.to_string())).unwrap();
+        symlink("svc-old", dir.join("60000")).unwrap();
+        symlink("svc-note", dir.join("README")).unwrap();
+
+        let db = backend.get_passwd_by_uid(Uid::from_raw(uid)).unwrap();
+        assert_eq!(
+            db,
+            Passwd {
+                name: "svc-db".to_string(),
+                name_bytes: None,
+                passwd: "!".to_string(),
+                uid: Uid::from_raw(uid),
+                gid: Gid::from_raw(uid),
+                gecos: "Dynamic User".to_string(),
+                dir: "/run/svc-db".to_string(),
+                shell: "/usr/sbin/nologin".to_string(),
+            }
+        );
+        assert_eq!(backend.get_passwd_by_name("svc-db").unwrap(), db);
+        assert_eq!(
+            backend.get_group_by_gid(Gid::from_raw(uid)).unwrap().name,
+            "svc-db"
+        );
+
+        assert_eq!(backend.get_passwd_by_uid(Uid::from_raw(other)), None);
+        assert_eq!(backend.get_passwd_by_uid(Uid::from_raw(60000)), None);
+        assert_eq!(backend.get_all_passwd(), vec![db]);
+        // Looking the users up by name doesn't record them again
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+//! Serves users, groups and hosts from environment variables, for injecting a few entries into a
+//! container or integration test without editing any files:
+//!
+//! ```sh
+//! NSS_EXTRA_HOSTS="db=10.0.0.5;cache=10.0.0.6,fd00::6"
+//! NSS_EXTRA_USERS="app:x:1000:1000::/srv/app:/bin/sh"
+//! NSS_EXTRA_GROUPS="app:x:1000:app;ops:x:1001:app,alice"
+//! ```
+//!
+//! Entries are separated by `;`. Hosts are `name=address[,address...]`, and users and groups use
+//! their `/etc/passwd` and `/etc/group` line formats. Malformed entries are skipped.
+//!
+//! The variables are read from the process doing the lookup (or nscd's, if it is caching for the
+//! module) each time they are needed. They are ignored in setuid and setgid programs, where they
+//! are under the control of a less privileged user.
+
+use std::env;
+use std::net::IpAddr;
+
+use crate::files::FilesEntry;
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+pub struct EnvConfig {
+    pub hosts_var: String,
+    pub passwd_var: String,
+    pub group_var: String,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        EnvConfig {
+            hosts_var: "NSS_EXTRA_HOSTS".to_string(),
+            passwd_var: "NSS_EXTRA_USERS".to_string(),
+            group_var: "NSS_EXTRA_GROUPS".to_string(),
+        }
+    }
+}
+
+struct EnvHost {
+    name: String,
+    addresses: Vec<IpAddr>,
+}
+
+pub struct EnvBackend {
+    config: EnvConfig,
+}
+
+impl EnvBackend {
+    pub fn new(config: EnvConfig) -> Self {
+        EnvBackend { config }
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        entries(&self.config.passwd_var)
+            .iter()
+            .filter_map(|entry| Passwd::from_line(entry.trim()).ok())
+            .collect()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        self.get_all_passwd().into_iter().find(|u| u.uid == uid)
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        self.get_all_passwd().into_iter().find(|u| u.name == name)
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        entries(&self.config.group_var)
+            .iter()
+            .filter_map(|entry| Group::from_line(entry.trim()).ok())
+            .collect()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        self.get_all_groups().into_iter().find(|g| g.gid == gid)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        self.get_all_groups().into_iter().find(|g| g.name == name)
+    }
+
+    /// One entry per host and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let mut hosts = Vec::new();
+        for host in self.hosts() {
+            hosts.extend(host.to_host(AddressFamily::IPv4));
+            hosts.extend(host.to_host(AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches the name ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let host = self
+            .hosts()
+            .into_iter()
+            .find(|host| host.name.eq_ignore_ascii_case(name))?;
+
+        match family {
+            AddressFamily::Unspecified => host
+                .to_host(AddressFamily::IPv4)
+                .or_else(|| host.to_host(AddressFamily::IPv6)),
+            family => host.to_host(family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let family = match addr {
+            IpAddr::V4(_) => AddressFamily::IPv4,
+            IpAddr::V6(_) => AddressFamily::IPv6,
+        };
+        self.hosts()
+            .into_iter()
+            .find(|host| host.addresses.contains(&addr))?
+            .to_host(family)
+    }
+
+    fn hosts(&self) -> Vec<EnvHost> {
+        entries(&self.config.hosts_var)
+            .iter()
+            .filter_map(|entry| {
+                let (name, addresses) = entry.split_once('=')?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return None;
+                }
+                let addresses = addresses
+                    .split(',')
+                    .map(|addr| addr.trim().parse())
+                    .collect::<Result<Vec<IpAddr>, _>>()
+                    .ok()?;
+
+                Some(EnvHost {
+                    name: name.to_string(),
+                    addresses,
+                })
+            })
+            .collect()
+    }
+}
+
+impl EnvHost {
+    fn to_host(&self, family: AddressFamily) -> Option<Host> {
+        let (v4, v6) = Addresses::split(self.addresses.iter().copied());
+        let addresses = match family {
+            AddressFamily::IPv4 => Addresses::V4(v4),
+            AddressFamily::IPv6 => Addresses::V6(v6),
+            AddressFamily::Unspecified | AddressFamily::Other(_) => return None,
+        };
+        if addresses.is_empty() {
+            return None;
+        }
+
+        Some(Host {
+            name: self.name.clone(),
+            aliases: vec![],
+            addresses,
+            canonical_name: None,
+        })
+    }
+}
+
+/// The non-empty `;` separated entries of the variable `name`, or none at all in a program running
+/// with privileges its environment's owner lacks.
+fn entries(name: &str) -> Vec<String> {
+    entries_unless(secure_execution(), name)
+}
+
+fn entries_unless(secure: bool, name: &str) -> Vec<String> {
+    let value = if secure { None } else { env::var(name).ok() };
+
+    value
+        .unwrap_or_default()
+        .split(';')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn secure_execution() -> bool {
+    unsafe { libc::getauxval(libc::AT_SECURE) != 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn secure_execution() -> bool {
+    unsafe { libc::getuid() != libc::geteuid() || libc::getgid() != libc::getegid() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A backend reading variables of its own, as tests run in parallel in one environment.
+    fn backend(test: &str, hosts: &str, passwd: &str, group: &str) -> EnvBackend {
+        let var = |kind: &str, value: &str| {
+            let name = format!("LIBNSS_TEST_{}_{}", test, kind);
+            env::set_var(&name, value);
+            name
+        };
+        EnvBackend::new(EnvConfig {
+            hosts_var: var("HOSTS", hosts),
+            passwd_var: var("USERS", passwd),
+            group_var: var("GROUPS", group),
+        })
+    }
+
+    #[test]
+    fn hosts_are_parsed() {
+        let env = backend(
+            "HOSTS",
+            " db = 10.0.0.5 ;;cache=10.0.0.6, fd00::6;v6=fd00::7;=10.0.0.8;bad=10.0.0.9,nope;none",
+            "",
+            "",
+        );
+
+        let db = env
+            .get_host_by_name("DB", AddressFamily::Unspecified)
+            .unwrap();
+        assert_eq!(db.name, "db");
+        assert_eq!(
+            db.addresses,
+            Addresses::V4(vec!["10.0.0.5".parse().unwrap()])
+        );
+        assert_eq!(env.get_host_by_name("db", AddressFamily::IPv6), None);
+
+        assert_eq!(
+            env.get_host_by_name("cache", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::6".parse().unwrap()])
+        );
+        assert_eq!(
+            env.get_host_by_name("v6", AddressFamily::Unspecified)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::7".parse().unwrap()])
+        );
+        assert_eq!(env.get_host_by_name("v6", AddressFamily::Other(99)), None);
+        assert_eq!(
+            env.get_host_by_addr("fd00::6".parse().unwrap())
+                .unwrap()
+                .name,
+            "cache"
+        );
+        // An entry with any address that doesn't parse is skipped whole
+        assert_eq!(env.get_host_by_name("bad", AddressFamily::IPv4), None);
+        assert_eq!(env.get_host_by_addr("10.0.0.8".parse().unwrap()), None);
+
+        let all: Vec<_> = env
+            .get_all_hosts()
+            .into_iter()
+            .map(|host| (host.name, host.addresses.len()))
+            .collect();
+        assert_eq!(
+            all,
+            [("db", 1), ("cache", 1), ("cache", 1), ("v6", 1)]
+                .iter()
+                .map(|&(name, len)| (name.to_string(), len))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn users_and_groups_are_parsed() {
+        let env = backend(
+            "IDS",
+            "",
+            "app:x:1000:1000::/srv/app:/bin/sh; bad:x:nope:1000::/:/bin/sh ;",
+            "app:x:1000:app;ops:x:1001:app,alice;bad:x",
+        );
+
+        let app = env.get_passwd_by_name("app").unwrap();
+        assert_eq!(
+            (app.uid, app.dir.as_str()),
+            (Uid::from_raw(1000), "/srv/app")
+        );
+        assert_eq!(
+            env.get_passwd_by_uid(Uid::from_raw(1000)).unwrap().name,
+            "app"
+        );
+        assert_eq!(env.get_all_passwd().len(), 1);
+
+        let ops = env.get_group_by_gid(Gid::from_raw(1001)).unwrap();
+        assert_eq!(ops.members, vec!["app", "alice"]);
+        assert_eq!(
+            env.get_group_by_name("app").unwrap().gid,
+            Gid::from_raw(1000)
+        );
+        assert_eq!(env.get_all_groups().len(), 2);
+    }
+
+    #[test]
+    fn unset_variables_have_no_entries() {
+        let env = EnvBackend::new(EnvConfig {
+            hosts_var: "LIBNSS_TEST_UNSET_HOSTS".to_string(),
+            passwd_var: "LIBNSS_TEST_UNSET_USERS".to_string(),
+            group_var: "LIBNSS_TEST_UNSET_GROUPS".to_string(),
+        });
+        assert!(env.get_all_hosts().is_empty());
+        assert!(env.get_all_passwd().is_empty());
+        assert!(env.get_all_groups().is_empty());
+    }
+
+    #[test]
+    fn variables_are_ignored_in_secure_execution() {
+        let env = backend("SECURE", "db=10.0.0.5", "", "");
+        // The tests aren't setuid
+        assert!(!secure_execution());
+        assert_eq!(entries(&env.config.hosts_var), vec!["db=10.0.0.5"]);
+        assert!(entries_unless(true, &env.config.hosts_var).is_empty());
+    }
+}
@@ -0,0 +1,628 @@
+//! Serves users, groups and hosts stored in etcd, for clusters that already keep machine metadata
+//! there.
+//!
+//! Entries live under `prefix`, one key each, with values in the `/etc` file formats:
+//!
+//! ```text
+//! /nss/passwd/app  app:x:1000:1000::/srv/app:/bin/sh
+//! /nss/group/app   app:x:1000:app
+//! /nss/hosts/db    10.0.0.5 db database
+//!                  fd00::5 db database
+//! ```
+//!
+//! What follows the database in a key is only there to keep keys distinct. A hosts value may hold
+//! several lines, and lines for the same name are answered together. Entries that don't parse are
+//! skipped.
+//!
+//! Everything under the prefix is read with one request and cached. A background thread watches
+//! the prefix and invalidates the cache when anything under it changes, so the next lookup reads
+//! it again. If etcd can't be reached, the entries last read keep being served. This talks to
+//! etcd's JSON gateway (`/v3/...`), which every etcd 3.4 and later serves on its client port.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use ureq::tls::{Certificate, ClientCert, PrivateKey, RootCerts, TlsConfig};
+use ureq::Agent;
+
+use crate::files::FilesEntry;
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+pub struct EtcdTlsConfig {
+    /// CA bundle used to verify etcd; the system roots are not consulted.
+    pub ca_certificate: PathBuf,
+    /// Client certificate and key (PEM), for etcd's `--client-cert-auth`.
+    pub identity: Option<(PathBuf, PathBuf)>,
+}
+
+pub struct EtcdConfig {
+    /// Tried in order. Use IP addresses: resolving a name here may end up back in this module.
+    pub endpoints: Vec<String>,
+    pub prefix: String,
+    pub tls: Option<EtcdTlsConfig>,
+    /// User name and password, for clusters with authentication enabled.
+    pub credentials: Option<(String, String)>,
+    pub timeout: Duration,
+}
+
+impl Default for EtcdConfig {
+    fn default() -> Self {
+        EtcdConfig {
+            endpoints: vec!["http://127.0.0.1:2379".to_string()],
+            prefix: "/nss/".to_string(),
+            tls: None,
+            credentials: None,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    users: Vec<Passwd>,
+    groups: Vec<Group>,
+    /// One per hosts line, so each has a single address
+    hosts: Vec<Host>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: Arc<Entries>,
+    /// Whether `entries` is current; cleared by the watcher
+    fresh: bool,
+}
+
+struct State {
+    /// The process the watcher was started in
+    pid: u32,
+    cache: Arc<RwLock<Cache>>,
+    watching: bool,
+}
+
+pub struct EtcdBackend {
+    client: Arc<Client>,
+    state: RwLock<State>,
+}
+
+impl EtcdBackend {
+    /// Creates the backend without connecting; etcd is first read by the first lookup.
+    pub fn new(config: EtcdConfig) -> io::Result<Self> {
+        Ok(EtcdBackend {
+            client: Arc::new(Client::new(config)?),
+            state: RwLock::new(State {
+                pid: std::process::id(),
+                cache: Arc::default(),
+                watching: false,
+            }),
+        })
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.entries().users.to_vec()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        self.entries().users.iter().find(|u| u.uid == uid).cloned()
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        self.entries()
+            .users
+            .iter()
+            .find(|u| u.name == name)
+            .cloned()
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.entries().groups.to_vec()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        self.entries().groups.iter().find(|g| g.gid == gid).cloned()
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        self.entries()
+            .groups
+            .iter()
+            .find(|g| g.name == name)
+            .cloned()
+    }
+
+    /// One entry per host name and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let entries = self.entries();
+        let mut names: Vec<&str> = Vec::new();
+        for host in &entries.hosts {
+            if !names.contains(&host.name.as_str()) {
+                names.push(&host.name);
+            }
+        }
+
+        let mut hosts = Vec::new();
+        for name in names {
+            hosts.extend(merge(&entries.hosts, name, AddressFamily::IPv4));
+            hosts.extend(merge(&entries.hosts, name, AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches the name or any alias, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let entries = self.entries();
+        let name = &entries
+            .hosts
+            .iter()
+            .find(|host| {
+                std::iter::once(&host.name)
+                    .chain(&host.aliases)
+                    .any(|n| n.eq_ignore_ascii_case(name))
+            })?
+            .name;
+
+        match family {
+            AddressFamily::Unspecified => merge(&entries.hosts, name, AddressFamily::IPv4)
+                .or_else(|| merge(&entries.hosts, name, AddressFamily::IPv6)),
+            family => merge(&entries.hosts, name, family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let entries = self.entries();
+        let (name, family) =
+            entries
+                .hosts
+                .iter()
+                .find_map(|host| match (&host.addresses, addr) {
+                    (Addresses::V4(addrs), IpAddr::V4(addr)) if addrs.contains(&addr) => {
+                        Some((&host.name, AddressFamily::IPv4))
+                    }
+                    (Addresses::V6(addrs), IpAddr::V6(addr)) if addrs.contains(&addr) => {
+                        Some((&host.name, AddressFamily::IPv6))
+                    }
+                    _ => None,
+                })?;
+        merge(&entries.hosts, name, family)
+    }
+
+    /// The cached entries, reading them again first if the watcher has seen a change.
+    fn entries(&self) -> Arc<Entries> {
+        let current = match &*self.state.read().unwrap() {
+            state if state.pid == std::process::id() => Some(state.cache.clone()),
+            _ => None,
+        };
+        let cache = current.unwrap_or_else(|| {
+            let mut state = self.state.write().unwrap();
+            // A forked child has the cache but not the thread invalidating it
+            if state.pid != std::process::id() {
+                state.pid = std::process::id();
+                state.cache = Arc::default();
+                state.watching = false;
+            }
+            state.cache.clone()
+        });
+
+        // Concurrent lookups only share a read lock until the watcher invalidates the entries
+        {
+            let locked = cache.read().unwrap();
+            if locked.fresh {
+                return locked.entries.clone();
+            }
+        }
+        let mut locked = cache.write().unwrap();
+        if locked.fresh {
+            return locked.entries.clone();
+        }
+        let revision = match self.client.range() {
+            Ok((entries, revision)) => {
+                locked.entries = Arc::new(entries);
+                locked.fresh = true;
+                revision
+            }
+            Err(_) => return locked.entries.clone(),
+        };
+        let entries = locked.entries.clone();
+        drop(locked);
+
+        // Watch from just after what was read, so no change can slip in between
+        let mut state = self.state.write().unwrap();
+        if !state.watching && Arc::ptr_eq(&state.cache, &cache) {
+            state.watching = true;
+            let client = self.client.clone();
+            let weak = Arc::downgrade(&cache);
+            thread::spawn(move || watch(&client, &weak, revision + 1));
+        }
+        entries
+    }
+}
+
+/// Invalidates `cache` whenever anything under the prefix changes, until the cache is dropped.
+fn watch(client: &Client, cache: &Weak<RwLock<Cache>>, mut revision: i64) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match client.watch(revision, cache) {
+            Ok(Some(next)) => {
+                revision = next;
+                backoff = Duration::from_secs(1);
+            }
+            Ok(None) => return,
+            Err(_) => {
+                // Changes may have been missed, and lookups read etcd until the watch is back.
+                // It then starts from the current revision, as the old one may be compacted.
+                match cache.upgrade() {
+                    Some(cache) => cache.write().unwrap().fresh = false,
+                    None => return,
+                }
+                revision = 0;
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+            }
+        }
+    }
+}
+
+/// Combines the hosts lines for `name` into one entry with their addresses of `family`.
+fn merge(hosts: &[Host], name: &str, family: AddressFamily) -> Option<Host> {
+    let mut merged = Host {
+        name: name.to_string(),
+        aliases: vec![],
+        addresses: match family {
+            AddressFamily::IPv4 => Addresses::V4(vec![]),
+            AddressFamily::IPv6 => Addresses::V6(vec![]),
+            AddressFamily::Unspecified | AddressFamily::Other(_) => return None,
+        },
+        canonical_name: None,
+    };
+    for host in hosts.iter().filter(|host| host.name == name) {
+        match (&mut merged.addresses, &host.addresses) {
+            (Addresses::V4(all), Addresses::V4(addrs)) => all.extend(addrs),
+            (Addresses::V6(all), Addresses::V6(addrs)) => all.extend(addrs),
+            _ => continue,
+        }
+        for alias in &host.aliases {
+            if !merged.aliases.contains(alias) {
+                merged.aliases.push(alias.clone());
+            }
+        }
+    }
+
+    if merged.addresses.is_empty() {
+        None
+    } else {
+        Some(merged)
+    }
+}
+
+struct Client {
+    agent: Agent,
+    /// An agent without a response timeout, for watches
+    watch_agent: Agent,
+    endpoints: Vec<String>,
+    prefix: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Client {
+    fn new(config: EtcdConfig) -> io::Result<Self> {
+        let mut tls = TlsConfig::builder();
+        if let Some(config) = config.tls {
+            let ca = Certificate::from_pem(&fs::read(config.ca_certificate)?).map_err(invalid)?;
+            tls = tls.root_certs(RootCerts::new_with_certs(&[ca]));
+            if let Some((cert, key)) = config.identity {
+                let cert = Certificate::from_pem(&fs::read(cert)?).map_err(invalid)?;
+                let key = PrivateKey::from_pem(&fs::read(key)?).map_err(invalid)?;
+                tls = tls.client_cert(Some(ClientCert::new_with_certs(&[cert], key)));
+            }
+        }
+        let tls = tls.build();
+
+        Ok(Client {
+            agent: Agent::config_builder()
+                .tls_config(tls.clone())
+                .timeout_global(Some(config.timeout))
+                .build()
+                .new_agent(),
+            watch_agent: Agent::config_builder()
+                .tls_config(tls)
+                .timeout_connect(Some(config.timeout))
+                .build()
+                .new_agent(),
+            endpoints: config
+                .endpoints
+                .iter()
+                .map(|e| e.trim_end_matches('/').to_string())
+                .collect(),
+            prefix: config.prefix,
+            credentials: config.credentials,
+        })
+    }
+
+    /// POSTs `body` to the first endpoint that answers.
+    fn post(&self, agent: &Agent, path: &str, body: &Value) -> io::Result<ureq::Body> {
+        let mut last_error = io::Error::other("no etcd endpoints are configured");
+        for endpoint in &self.endpoints {
+            match self.post_to(agent, endpoint, path, body) {
+                Ok(body) => return Ok(body),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn post_to(
+        &self,
+        agent: &Agent,
+        endpoint: &str,
+        path: &str,
+        body: &Value,
+    ) -> io::Result<ureq::Body> {
+        let mut request = agent
+            .post(format!("{}{}", endpoint, path))
+            .content_type("application/json");
+        if let Some((name, password)) = &self.credentials {
+            // Tokens expire, so get a new one for every request rather than tracking their TTL
+            let mut response = self
+                .agent
+                .post(format!("{}/v3/auth/authenticate", endpoint))
+                .content_type("application/json")
+                .send(json!({ "name": name, "password": password }).to_string())
+                .map_err(io::Error::other)?;
+            let reply: Value = serde_json::from_str(
+                &response
+                    .body_mut()
+                    .read_to_string()
+                    .map_err(io::Error::other)?,
+            )?;
+            let token = reply["token"]
+                .as_str()
+                .ok_or_else(|| io::Error::other("etcd returned no auth token"))?;
+            request = request.header("Authorization", token);
+        }
+
+        let response = request.send(body.to_string()).map_err(io::Error::other)?;
+        Ok(response.into_body())
+    }
+
+    /// The bounds of the prefix, as etcd's base64 `key` and `range_end`.
+    fn range_keys(&self) -> (String, String) {
+        let key = self.prefix.as_bytes().to_vec();
+        let mut end = key.clone();
+        // The end is the first key past every one starting with the prefix
+        while let Some(last) = end.pop() {
+            if last < 0xff {
+                end.push(last + 1);
+                break;
+            }
+        }
+        if end.is_empty() {
+            // "\0" means every key from `key` on
+            end.push(0);
+        }
+        (BASE64.encode(key), BASE64.encode(end))
+    }
+
+    /// Reads every entry under the prefix, returning them with the revision they were read at.
+    fn range(&self) -> io::Result<(Entries, i64)> {
+        let (key, range_end) = self.range_keys();
+        let body = json!({ "key": key, "range_end": range_end });
+        let reply: Value =
+            serde_json::from_reader(self.post(&self.agent, "/v3/kv/range", &body)?.into_reader())?;
+        self.entries_in(&reply)
+    }
+
+    /// The entries in a range `reply`, with the revision they were read at.
+    fn entries_in(&self, reply: &Value) -> io::Result<(Entries, i64)> {
+        let mut entries = Entries::default();
+        for kv in reply["kvs"].as_array().into_iter().flatten() {
+            let key = decode(&kv["key"]).unwrap_or_default();
+            let value = decode(&kv["value"]).unwrap_or_default();
+            let database = key
+                .strip_prefix(&self.prefix)
+                .and_then(|rest| rest.split('/').next());
+
+            match database {
+                Some("passwd") => entries.users.extend(parse(&value)),
+                Some("group") => entries.groups.extend(parse(&value)),
+                Some("hosts") => entries.hosts.extend(parse(&value)),
+                _ => {}
+            }
+        }
+        Ok((entries, revision(&reply["header"])?))
+    }
+
+    /// Watches the prefix from revision `start`, or from the current one if it is 0, invalidating
+    /// `cache` on every change. Returns the revision to resume from when etcd ends the watch, or
+    /// `None` once the cache is dropped.
+    fn watch(&self, start: i64, cache: &Weak<RwLock<Cache>>) -> io::Result<Option<i64>> {
+        let (key, range_end) = self.range_keys();
+        let body = json!({
+            "create_request": {
+                "key": key,
+                "range_end": range_end,
+                "start_revision": start.to_string(),
+            }
+        });
+        let stream = self.post(&self.watch_agent, "/v3/watch", &body)?;
+
+        let mut next = start;
+        for message in serde_json::Deserializer::from_reader(stream.into_reader()).into_iter() {
+            let message: Value = message?;
+            let result = &message["result"];
+            if result.is_null() {
+                return Err(io::Error::other(message["error"].to_string()));
+            }
+            if result["compact_revision"]
+                .as_str()
+                .is_some_and(|rev| rev != "0")
+            {
+                // The revision was compacted away, so changes since it may be missed
+                return Err(io::Error::other("watch revision was compacted"));
+            }
+            if start == 0 && result["created"].as_bool() == Some(true) {
+                // Lookups may have read etcd since the watch stopped, after a change it missed
+                next = revision(&result["header"])? + 1;
+                match cache.upgrade() {
+                    Some(cache) => cache.write().unwrap().fresh = false,
+                    None => return Ok(None),
+                }
+            }
+            if let Some(events) = result["events"].as_array() {
+                if events.is_empty() {
+                    continue;
+                }
+                for event in events {
+                    if let Ok(rev) = revision(&event["kv"]["mod_revision"]) {
+                        next = next.max(rev + 1);
+                    }
+                }
+                match cache.upgrade() {
+                    Some(cache) => cache.write().unwrap().fresh = false,
+                    None => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(next))
+    }
+}
+
+fn parse<T: FilesEntry>(value: &str) -> Vec<T> {
+    crate::files::parse(value).filter_map(Result::ok).collect()
+}
+
+fn decode(value: &Value) -> Option<String> {
+    let bytes = BASE64.decode(value.as_str()?).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// etcd's JSON gateway writes 64-bit integers as strings.
+fn revision(value: &Value) -> io::Result<i64> {
+    let revision = match value {
+        Value::Object(header) => header.get("revision").unwrap_or(&Value::Null),
+        value => value,
+    };
+    revision
+        .as_str()
+        .and_then(|rev| rev.parse().ok())
+        .or_else(|| revision.as_i64())
+        .ok_or_else(|| io::Error::other("etcd returned no revision"))
+}
+
+fn invalid<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(prefix: &str) -> Client {
+        Client::new(EtcdConfig {
+            prefix: prefix.to_string(),
+            ..EtcdConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn kv(key: &str, value: &str) -> Value {
+        json!({ "key": BASE64.encode(key), "value": BASE64.encode(value), "mod_revision": "3" })
+    }
+
+    #[test]
+    fn range_keys() {
+        let decoded = |(key, end): (String, String)| {
+            (BASE64.decode(key).unwrap(), BASE64.decode(end).unwrap())
+        };
+        assert_eq!(
+            decoded(client("/nss/").range_keys()),
+            (b"/nss/".to_vec(), b"/nss0".to_vec())
+        );
+        assert_eq!(
+            decoded(client("").range_keys()),
+            (b"".to_vec(), b"\0".to_vec())
+        );
+    }
+
+    #[test]
+    fn range_replies() {
+        let reply = json!({
+            "header": { "cluster_id": "1", "revision": "42" },
+            "kvs": [
+                kv("/nss/passwd/app", "app:x:1000:1000::/srv/app:/bin/sh"),
+                kv("/nss/passwd/bad", "bad:x:nope"),
+                kv("/nss/group/app", "app:x:1000:app,alice"),
+                kv("/nss/hosts/db", "10.0.0.5 db database\nfd00::5 db\nnot-an-address db"),
+                kv("/nss/hosts/db-2", "10.0.0.6 db replica # the replica"),
+                kv("/nss/hosts", "10.0.0.7 bare"),
+                kv("/nss/shadow/app", "app:!:1::::::"),
+                kv("/other/passwd/eve", "eve:x:0:0::/:/bin/sh"),
+                { "key": "not base64!", "value": BASE64.encode("eve:x:0:0::/:/bin/sh") },
+            ],
+        });
+        let (entries, revision) = client("/nss/").entries_in(&reply).unwrap();
+        assert_eq!(revision, 42);
+
+        let names = |users: &[Passwd]| users.iter().map(|u| u.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&entries.users), ["app"]);
+        assert_eq!(entries.users[0].uid, Uid::from_raw(1000));
+        assert_eq!(entries.groups.len(), 1);
+        assert_eq!(entries.groups[0].members, ["app", "alice"]);
+        assert_eq!(entries.hosts.len(), 4);
+
+        // Lines for the same name are answered together, whichever keys they are under
+        let db = merge(&entries.hosts, "db", AddressFamily::IPv4).unwrap();
+        assert_eq!(db.aliases, ["database", "replica"]);
+        assert_eq!(
+            db.addresses,
+            Addresses::V4(vec![
+                "10.0.0.5".parse().unwrap(),
+                "10.0.0.6".parse().unwrap()
+            ])
+        );
+        assert_eq!(
+            merge(&entries.hosts, "db", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::5".parse().unwrap()])
+        );
+        assert_eq!(merge(&entries.hosts, "bare", AddressFamily::IPv6), None);
+        assert_eq!(
+            merge(&entries.hosts, "db", AddressFamily::Unspecified),
+            None
+        );
+    }
+
+    #[test]
+    fn empty_and_malformed_replies() {
+        // etcd leaves `kvs` out when nothing is under the prefix
+        let (entries, revision) = client("/nss/")
+            .entries_in(&json!({ "header": { "revision": "7" } }))
+            .unwrap();
+        assert!(entries.users.is_empty() && entries.groups.is_empty() && entries.hosts.is_empty());
+        assert_eq!(revision, 7);
+
+        assert!(client("/nss/").entries_in(&json!({ "kvs": [] })).is_err());
+        assert!(client("/nss/")
+            .entries_in(&json!({ "header": { "revision": "many" } }))
+            .is_err());
+    }
+
+    #[test]
+    fn revisions() {
+        assert_eq!(revision(&json!({ "revision": "12" })).unwrap(), 12);
+        assert_eq!(revision(&json!({ "revision": 12 })).unwrap(), 12);
+        assert_eq!(revision(&json!("9223372036854775807")).unwrap(), i64::MAX);
+        assert!(revision(&json!({})).is_err());
+        assert!(revision(&Value::Null).is_err());
+    }
+}
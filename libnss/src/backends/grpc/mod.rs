@@ -0,0 +1,645 @@
+//! Resolves hosts, users, groups and shadow entries from a gRPC identity service implementing
+//! `proto/nss.proto`.
+//!
+//! Calls are made on a private single threaded tokio runtime and each one is bounded by the
+//! configured deadline, which is also sent to the server as `grpc-timeout`. This pulls an async
+//! runtime and a TLS stack into every process that loads the module, so consider running it
+//! behind the `daemon` feature's shim instead.
+//!
+//! While the service can't be reached, or answers with an error other than `NOT_FOUND`, a lookup
+//! is answered with the entry it last returned, for up to `max_staleness` after that, from a
+//! cache per database bounded by `eviction`, and kept in `cache_dir` if set. Enumerations aren't.
+//! Entries younger than `ttl` are answered from the cache without calling the service at all.
+
+pub mod proto;
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::runtime::Runtime;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Status, Streaming};
+use tonic_prost::ProstCodec;
+
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+use crate::stale::{CacheBudgets, StaleCache};
+use crate::stats::Database;
+
+pub struct TlsConfig {
+    /// CA bundle used to verify the server; the system roots are not consulted.
+    pub ca_certificate: PathBuf,
+    /// Client certificate and key (PEM), for mutual TLS.
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// Name to verify the server certificate against, if it differs from the endpoint host.
+    pub domain_name: Option<String>,
+}
+
+pub struct GrpcConfig {
+    pub endpoint: String,
+    pub deadline: Duration,
+    pub connect_timeout: Duration,
+    pub tls: Option<TlsConfig>,
+    /// How long an entry keeps being served while the service fails; zero disables this
+    pub max_staleness: Duration,
+    pub eviction: CacheBudgets,
+    /// Where to keep the cache across restarts, e.g. `/var/lib/<module>`, in a `grpc` directory
+    pub cache_dir: Option<PathBuf>,
+    /// Where to keep the cache in segments shared by every process, e.g. `/run/<module>`, in a
+    /// `grpc` directory. Takes the place of `eviction` and `cache_dir` once mapped
+    pub shared_dir: Option<PathBuf>,
+    /// How long an entry is answered from the cache without calling the service again; zero, the
+    /// default, always calls it
+    pub ttl: Duration,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            endpoint: "http://127.0.0.1:50051".to_string(),
+            deadline: Duration::from_secs(2),
+            connect_timeout: Duration::from_secs(1),
+            tls: None,
+            max_staleness: Duration::from_secs(24 * 60 * 60),
+            eviction: CacheBudgets::default(),
+            cache_dir: None,
+            shared_dir: None,
+            ttl: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Encoded replies, by method followed by the encoded request
+type ReplyCache = StaleCache<Vec<u8>, Vec<u8>>;
+
+pub struct GrpcBackend {
+    runtime: Runtime,
+    channel: Channel,
+    deadline: Duration,
+    /// By database
+    stale: Vec<ReplyCache>,
+}
+
+impl GrpcBackend {
+    /// Creates the backend without connecting; the channel connects on first use so a module can
+    /// be loaded while the service is down.
+    pub fn new(config: GrpcConfig) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        let mut endpoint = Endpoint::from_shared(config.endpoint)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .timeout(config.deadline)
+            .connect_timeout(config.connect_timeout);
+
+        if let Some(tls) = config.tls {
+            let mut tls_config = ClientTlsConfig::new()
+                .ca_certificate(Certificate::from_pem(fs::read(tls.ca_certificate)?));
+            if let Some((cert, key)) = tls.identity {
+                tls_config =
+                    tls_config.identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?));
+            }
+            if let Some(domain_name) = tls.domain_name {
+                tls_config = tls_config.domain_name(domain_name);
+            }
+            endpoint = endpoint
+                .tls_config(tls_config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        // The lazy channel spawns its worker onto the runtime it is created in
+        let channel = {
+            let _guard = runtime.enter();
+            endpoint.connect_lazy()
+        };
+        let ttl = config.ttl;
+
+        Ok(GrpcBackend {
+            runtime,
+            channel,
+            deadline: config.deadline,
+            stale: config
+                .eviction
+                .caches(
+                    config.max_staleness,
+                    config.cache_dir.map(|dir| dir.join("grpc")).as_deref(),
+                    config.shared_dir.map(|dir| dir.join("grpc")).as_deref(),
+                )
+                .into_iter()
+                .map(|cache| cache.with_ttl(ttl))
+                .collect(),
+        })
+    }
+
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        self.list::<proto::Host>("/libnss.v1.Hosts/ListHosts")
+            .into_iter()
+            .filter_map(to_host)
+            .collect()
+    }
+
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let family = match family {
+            AddressFamily::IPv4 => proto::AddressFamily::Inet,
+            AddressFamily::IPv6 => proto::AddressFamily::Inet6,
+            AddressFamily::Unspecified => proto::AddressFamily::Unspecified,
+            AddressFamily::Other(_) => return None,
+        };
+        let request = proto::LookupHostByNameRequest {
+            name: name.to_string(),
+            family: family as i32,
+        };
+
+        self.get::<_, proto::HostReply>(
+            Database::Hosts,
+            "/libnss.v1.Hosts/LookupHostByName",
+            request,
+        )?
+        .host
+        .and_then(to_host)
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let address = match addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        };
+        let request = proto::LookupHostByAddrRequest { address };
+
+        self.get::<_, proto::HostReply>(
+            Database::Hosts,
+            "/libnss.v1.Hosts/LookupHostByAddr",
+            request,
+        )?
+        .host
+        .and_then(to_host)
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.list::<proto::User>("/libnss.v1.Passwd/ListUsers")
+            .into_iter()
+            .map(to_passwd)
+            .collect()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        let request = proto::GetByIdRequest { id: uid.as_raw() };
+        self.get::<_, proto::UserReply>(
+            Database::Passwd,
+            "/libnss.v1.Passwd/GetUserByUid",
+            request,
+        )?
+        .user
+        .map(to_passwd)
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        let request = proto::GetByNameRequest {
+            name: name.to_string(),
+        };
+        self.get::<_, proto::UserReply>(
+            Database::Passwd,
+            "/libnss.v1.Passwd/GetUserByName",
+            request,
+        )?
+        .user
+        .map(to_passwd)
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.list::<proto::Group>("/libnss.v1.Groups/ListGroups")
+            .into_iter()
+            .map(to_group)
+            .collect()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        let request = proto::GetByIdRequest { id: gid.as_raw() };
+        self.get::<_, proto::GroupReply>(
+            Database::Group,
+            "/libnss.v1.Groups/GetGroupByGid",
+            request,
+        )?
+        .group
+        .map(to_group)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let request = proto::GetByNameRequest {
+            name: name.to_string(),
+        };
+        self.get::<_, proto::GroupReply>(
+            Database::Group,
+            "/libnss.v1.Groups/GetGroupByName",
+            request,
+        )?
+        .group
+        .map(to_group)
+    }
+
+    pub fn get_all_shadow(&self) -> Vec<Shadow> {
+        self.list::<proto::ShadowEntry>("/libnss.v1.Shadow/ListShadow")
+            .into_iter()
+            .map(to_shadow)
+            .collect()
+    }
+
+    pub fn get_shadow_by_name(&self, name: &str) -> Option<Shadow> {
+        let request = proto::GetByNameRequest {
+            name: name.to_string(),
+        };
+        self.get::<_, proto::ShadowReply>(
+            Database::Shadow,
+            "/libnss.v1.Shadow/GetShadowByName",
+            request,
+        )?
+        .shadow
+        .map(to_shadow)
+    }
+
+    /// Makes a unary call, unless the cache can answer it. `NOT_FOUND` and every failure alike
+    /// come back as `None`, unless a stale reply can be served for the failure.
+    fn get<Req, Resp>(&self, database: Database, path: &'static str, request: Req) -> Option<Resp>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Resp: prost::Message + Default + Send + Sync + 'static,
+    {
+        let mut key = path.as_bytes().to_vec();
+        request.encode(&mut key).ok()?;
+        let call = || {
+            let result: Result<Resp, Status> = self.runtime.block_on(async {
+                let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+                grpc.ready()
+                    .await
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+
+                let mut request = tonic::Request::new(request);
+                request.set_timeout(self.deadline);
+                let response = grpc
+                    .unary(
+                        request,
+                        PathAndQuery::from_static(path),
+                        ProstCodec::default(),
+                    )
+                    .await?;
+                Ok(response.into_inner())
+            });
+            match result {
+                Ok(reply) => Ok(Some(reply.encode_to_vec())),
+                Err(status) if status.code() == Code::NotFound => Ok(None),
+                Err(status) => Err(status),
+            }
+        };
+
+        let reply = self.stale[database as usize].lookup(key, call).ok()??;
+        Resp::decode(reply.as_slice()).ok()
+    }
+
+    /// Collects a server streaming enumeration. A failure part way through discards the partial
+    /// results, as a short listing would look like entries had been deleted.
+    fn list<Resp>(&self, path: &'static str) -> Vec<Resp>
+    where
+        Resp: prost::Message + Default + Send + Sync + 'static,
+    {
+        let result: Result<Vec<Resp>, Status> = self.runtime.block_on(async {
+            let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+            grpc.ready()
+                .await
+                .map_err(|e| Status::unavailable(e.to_string()))?;
+
+            let mut request = tonic::Request::new(proto::ListRequest {});
+            request.set_timeout(self.deadline);
+            let mut stream: Streaming<Resp> = grpc
+                .server_streaming(
+                    request,
+                    PathAndQuery::from_static(path),
+                    ProstCodec::default(),
+                )
+                .await?
+                .into_inner();
+
+            let mut entries = Vec::new();
+            while let Some(entry) = stream.message().await? {
+                entries.push(entry);
+            }
+            Ok(entries)
+        });
+
+        result.unwrap_or_default()
+    }
+}
+
+fn to_host(host: proto::Host) -> Option<Host> {
+    let addresses = match host.addresses.first().map(Vec::len) {
+        Some(4) => Addresses::V4(
+            host.addresses
+                .iter()
+                .map(|a| Some(Ipv4Addr::from(<[u8; 4]>::try_from(a.as_slice()).ok()?)))
+                .collect::<Option<_>>()?,
+        ),
+        Some(16) => Addresses::V6(
+            host.addresses
+                .iter()
+                .map(|a| Some(Ipv6Addr::from(<[u8; 16]>::try_from(a.as_slice()).ok()?)))
+                .collect::<Option<_>>()?,
+        ),
+        _ => return None,
+    };
+
+    Some(Host {
+        name: host.name,
+        aliases: host.aliases,
+        addresses,
+        canonical_name: None,
+    })
+}
+
+fn to_passwd(user: proto::User) -> Passwd {
+    Passwd {
+        name: user.name,
+        name_bytes: None,
+        passwd: user.passwd,
+        uid: Uid::from_raw(user.uid),
+        gid: Gid::from_raw(user.gid),
+        gecos: user.gecos,
+        dir: user.dir,
+        shell: user.shell,
+    }
+}
+
+fn to_group(group: proto::Group) -> Group {
+    Group {
+        name: group.name,
+        name_bytes: None,
+        passwd: group.passwd,
+        gid: Gid::from_raw(group.gid),
+        members: group.members,
+    }
+}
+
+fn to_shadow(shadow: proto::ShadowEntry) -> Shadow {
+    Shadow {
+        name: shadow.name,
+        passwd: shadow.passwd,
+        last_change: shadow.last_change,
+        change_min_days: shadow.change_min_days,
+        change_max_days: shadow.change_max_days,
+        change_warn_days: shadow.change_warn_days,
+        change_inactive_days: shadow.change_inactive_days,
+        expire_date: shadow.expire_date,
+        reserved: shadow.reserved,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    /// The tag and wire type of each field `proto/nss.proto` declares for `message`.
+    fn declared(message: &str) -> Vec<(u64, u64)> {
+        let proto = include_str!("../../../proto/nss.proto");
+        let start = format!("message {} {{", message);
+        let body = proto
+            .lines()
+            .skip_while(|line| *line != start)
+            .take_while(|line| *line != "}")
+            .skip(1);
+        let mut fields: Vec<_> = body
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("//"))
+            .map(|line| {
+                let (decl, tag) = line.trim_end_matches(';').split_once(" = ").unwrap();
+                let kind = decl
+                    .trim_start_matches("repeated ")
+                    .split(' ')
+                    .next()
+                    .unwrap();
+                let wire = match kind {
+                    "uint32" | "uint64" | "int64" | "AddressFamily" => 0,
+                    _ => 2,
+                };
+                (tag.parse().unwrap(), wire)
+            })
+            .collect();
+        fields.sort_unstable();
+        fields
+    }
+
+    /// The tag and wire type of each field in an encoded message.
+    fn encoded<M: Message>(message: &M) -> Vec<(u64, u64)> {
+        let bytes = message.encode_to_vec();
+        let mut buf = bytes.as_slice();
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = prost::encoding::decode_varint(&mut buf).unwrap();
+            let len = match key & 7 {
+                0 => {
+                    prost::encoding::decode_varint(&mut buf).unwrap();
+                    0
+                }
+                2 => prost::encoding::decode_varint(&mut buf).unwrap() as usize,
+                wire => panic!("unexpected wire type {}", wire),
+            };
+            buf = &buf[len..];
+            fields.push((key >> 3, key & 7));
+        }
+        fields.sort_unstable();
+        fields.dedup();
+        fields
+    }
+
+    fn host() -> proto::Host {
+        proto::Host {
+            name: "db".to_string(),
+            aliases: vec!["db.example".to_string()],
+            addresses: vec![vec![10, 0, 0, 1], vec![10, 0, 0, 2]],
+        }
+    }
+
+    fn user() -> proto::User {
+        proto::User {
+            name: "alice".to_string(),
+            passwd: "x".to_string(),
+            uid: 1000,
+            gid: 100,
+            gecos: "Alice".to_string(),
+            dir: "/home/alice".to_string(),
+            shell: "/bin/sh".to_string(),
+        }
+    }
+
+    fn group() -> proto::Group {
+        proto::Group {
+            name: "staff".to_string(),
+            passwd: "x".to_string(),
+            gid: 50,
+            members: vec!["alice".to_string()],
+        }
+    }
+
+    fn shadow() -> proto::ShadowEntry {
+        proto::ShadowEntry {
+            name: "alice".to_string(),
+            passwd: "!".to_string(),
+            last_change: 19000,
+            change_min_days: 1,
+            change_max_days: 99999,
+            change_warn_days: 7,
+            change_inactive_days: 30,
+            expire_date: 20000,
+            reserved: 1,
+        }
+    }
+
+    /// The hand-written messages encode every field with the tag and type the proto file gives it,
+    /// so they stay in sync.
+    #[test]
+    fn messages_match_the_proto_file() {
+        let proto = include_str!("../../../proto/nss.proto");
+        assert!(proto.contains("message ListRequest {}"));
+        assert_eq!(encoded(&proto::ListRequest {}), []);
+        assert_eq!(
+            encoded(&proto::GetByNameRequest {
+                name: "alice".to_string()
+            }),
+            declared("GetByNameRequest")
+        );
+        assert_eq!(
+            encoded(&proto::GetByIdRequest { id: 1000 }),
+            declared("GetByIdRequest")
+        );
+        assert_eq!(
+            encoded(&proto::LookupHostByNameRequest {
+                name: "db".to_string(),
+                family: proto::AddressFamily::Inet6 as i32,
+            }),
+            declared("LookupHostByNameRequest")
+        );
+        assert_eq!(
+            encoded(&proto::LookupHostByAddrRequest {
+                address: vec![10, 0, 0, 1]
+            }),
+            declared("LookupHostByAddrRequest")
+        );
+        assert_eq!(encoded(&host()), declared("Host"));
+        assert_eq!(
+            encoded(&proto::HostReply { host: Some(host()) }),
+            declared("HostReply")
+        );
+        assert_eq!(encoded(&user()), declared("User"));
+        assert_eq!(
+            encoded(&proto::UserReply { user: Some(user()) }),
+            declared("UserReply")
+        );
+        assert_eq!(encoded(&group()), declared("Group"));
+        assert_eq!(
+            encoded(&proto::GroupReply {
+                group: Some(group())
+            }),
+            declared("GroupReply")
+        );
+        assert_eq!(encoded(&shadow()), declared("ShadowEntry"));
+        assert_eq!(
+            encoded(&proto::ShadowReply {
+                shadow: Some(shadow())
+            }),
+            declared("ShadowReply")
+        );
+
+        assert!(proto.contains("ADDRESS_FAMILY_UNSPECIFIED = 0;"));
+        assert!(proto.contains("ADDRESS_FAMILY_INET = 1;"));
+        assert!(proto.contains("ADDRESS_FAMILY_INET6 = 2;"));
+        assert_eq!(proto::AddressFamily::Unspecified as i32, 0);
+        assert_eq!(proto::AddressFamily::Inet as i32, 1);
+        assert_eq!(proto::AddressFamily::Inet6 as i32, 2);
+    }
+
+    #[test]
+    fn hosts_keep_one_address_family() {
+        assert_eq!(
+            to_host(host()),
+            Some(Host {
+                name: "db".to_string(),
+                aliases: vec!["db.example".to_string()],
+                addresses: Addresses::V4(vec![
+                    Ipv4Addr::new(10, 0, 0, 1),
+                    Ipv4Addr::new(10, 0, 0, 2)
+                ]),
+                canonical_name: None,
+            })
+        );
+
+        let v6 = proto::Host {
+            addresses: vec![Ipv6Addr::LOCALHOST.octets().to_vec()],
+            ..host()
+        };
+        assert_eq!(
+            to_host(v6).unwrap().addresses,
+            Addresses::V6(vec![Ipv6Addr::LOCALHOST])
+        );
+
+        for addresses in [
+            Vec::new(),
+            vec![vec![10, 0, 0]],
+            vec![vec![10, 0, 0, 1], Ipv6Addr::LOCALHOST.octets().to_vec()],
+            vec![Ipv6Addr::LOCALHOST.octets().to_vec(), vec![10, 0, 0, 1]],
+        ] {
+            let entry = proto::Host {
+                addresses,
+                ..host()
+            };
+            assert_eq!(to_host(entry.clone()), None, "{:?}", entry.addresses);
+        }
+    }
+
+    #[test]
+    fn entries_map_field_for_field() {
+        assert_eq!(
+            to_passwd(user()),
+            Passwd {
+                name: "alice".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                uid: Uid::from_raw(1000),
+                gid: Gid::from_raw(100),
+                gecos: "Alice".to_string(),
+                dir: "/home/alice".to_string(),
+                shell: "/bin/sh".to_string(),
+            }
+        );
+        assert_eq!(
+            to_group(group()),
+            Group {
+                name: "staff".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                gid: Gid::from_raw(50),
+                members: vec!["alice".to_string()],
+            }
+        );
+        assert_eq!(
+            to_shadow(shadow()),
+            Shadow {
+                name: "alice".to_string(),
+                passwd: "!".to_string(),
+                last_change: 19000,
+                change_min_days: 1,
+                change_max_days: 99999,
+                change_warn_days: 7,
+                change_inactive_days: 30,
+                expire_date: 20000,
+                reserved: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Messages from `proto/nss.proto`, written out by hand so building doesn't need `protoc`. Keep
+//! the two in sync.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetByNameRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetByIdRequest {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum AddressFamily {
+    Unspecified = 0,
+    Inet = 1,
+    Inet6 = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LookupHostByNameRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(enumeration = "AddressFamily", tag = "2")]
+    pub family: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LookupHostByAddrRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub address: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Host {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, repeated, tag = "2")]
+    pub aliases: Vec<String>,
+    #[prost(bytes = "vec", repeated, tag = "3")]
+    pub addresses: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HostReply {
+    #[prost(message, optional, tag = "1")]
+    pub host: Option<Host>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct User {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub passwd: String,
+    #[prost(uint32, tag = "3")]
+    pub uid: u32,
+    #[prost(uint32, tag = "4")]
+    pub gid: u32,
+    #[prost(string, tag = "5")]
+    pub gecos: String,
+    #[prost(string, tag = "6")]
+    pub dir: String,
+    #[prost(string, tag = "7")]
+    pub shell: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UserReply {
+    #[prost(message, optional, tag = "1")]
+    pub user: Option<User>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Group {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub passwd: String,
+    #[prost(uint32, tag = "3")]
+    pub gid: u32,
+    #[prost(string, repeated, tag = "4")]
+    pub members: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GroupReply {
+    #[prost(message, optional, tag = "1")]
+    pub group: Option<Group>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ShadowEntry {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub passwd: String,
+    #[prost(int64, tag = "3")]
+    pub last_change: i64,
+    #[prost(int64, tag = "4")]
+    pub change_min_days: i64,
+    #[prost(int64, tag = "5")]
+    pub change_max_days: i64,
+    #[prost(int64, tag = "6")]
+    pub change_warn_days: i64,
+    #[prost(int64, tag = "7")]
+    pub change_inactive_days: i64,
+    #[prost(int64, tag = "8")]
+    pub expire_date: i64,
+    #[prost(uint64, tag = "9")]
+    pub reserved: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ShadowReply {
+    #[prost(message, optional, tag = "1")]
+    pub shadow: Option<ShadowEntry>,
+}
@@ -0,0 +1,762 @@
+//! Resolves users and groups from Hesiod TXT records in DNS, as a maintained replacement for
+//! nss_hesiod.
+//!
+//! Entries are looked up as nss_hesiod does, under `lhs` and `rhs` as they're set in
+//! `hesiod.conf`: a user `alice` at `alice.passwd.ns.example.com` and uid 1000 at
+//! `1000.uid.ns.example.com`, and groups likewise at `<name>.group` and `<gid>.gid`. A name of the
+//! form `alice@other.example.com` is looked up under `other.example.com` instead. Each record holds
+//! the entry as an `/etc/passwd` or `/etc/group` line, split across the record's strings if long.
+//!
+//! Queries go to `nameservers`, or those in `/etc/resolv.conf` if there are none, in turn, and are
+//! retried over TCP when an answer is truncated. Each class in `classes` is asked in turn, as with
+//! `classes=IN,HS` in `hesiod.conf`. Answers are cached for the shorter of their TTL and
+//! `cache_ttl`, and names that don't exist for the shorter of their zone's negative TTL and
+//! `negative_ttl`, so a login prompt being scanned for names doesn't hit DNS for every guess.
+//! Hesiod has no enumeration: `get_all_passwd` and `get_all_groups` are always empty.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::process;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::group::Group;
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+const DNS_PORT: u16 = 53;
+
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+
+const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum DnsClass {
+    In,
+    /// Hesiod's own class, which few servers other than BIND's serve
+    Hs,
+}
+
+impl DnsClass {
+    fn code(self) -> u16 {
+        match self {
+            DnsClass::In => 1,
+            DnsClass::Hs => 4,
+        }
+    }
+}
+
+pub struct HesiodConfig {
+    /// Put between the entry's type and `rhs`, with or without a leading dot, e.g. `.ns`
+    pub lhs: String,
+    /// The domain entries are looked up under, with or without a leading dot, e.g. `.example.com`
+    pub rhs: String,
+    /// Asked in order, after the one before doesn't answer an entry
+    pub classes: Vec<DnsClass>,
+    /// The servers to query, in order; empty, the default, uses those in `/etc/resolv.conf`
+    pub nameservers: Vec<SocketAddr>,
+    /// How long to wait for each server to answer
+    pub timeout: Duration,
+    /// Upper bound on how long an answer is cached, whatever its TTL
+    pub cache_ttl: Duration,
+    /// Upper bound on how long a name that doesn't exist is remembered, whatever its zone says
+    pub negative_ttl: Duration,
+}
+
+impl Default for HesiodConfig {
+    fn default() -> Self {
+        HesiodConfig {
+            lhs: ".ns".to_string(),
+            rhs: String::new(),
+            classes: vec![DnsClass::In, DnsClass::Hs],
+            nameservers: vec![],
+            timeout: Duration::from_secs(2),
+            cache_ttl: Duration::from_secs(600),
+            negative_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What a server said about a name.
+enum Answer {
+    /// The name's TXT records, each with its strings joined, and the shortest TTL among them
+    Found(Vec<String>, u32),
+    /// The name or its TXT records don't exist, for as long as the zone says
+    Missing(u32),
+}
+
+pub struct HesiodBackend {
+    config: HesiodConfig,
+    nameservers: Vec<SocketAddr>,
+    id: AtomicU16,
+    /// By DNS name, the records found there; none if it doesn't exist
+    cache: RwLock<HashMap<String, (Instant, Vec<String>)>>,
+}
+
+impl HesiodBackend {
+    pub fn new(config: HesiodConfig) -> Self {
+        let nameservers = if config.nameservers.is_empty() {
+            resolv_conf_nameservers()
+        } else {
+            config.nameservers.clone()
+        };
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.subsec_nanos());
+
+        HesiodBackend {
+            config,
+            nameservers,
+            id: AtomicU16::new((seed ^ process::id()) as u16),
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        vec![]
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        self.resolve(&uid.to_string(), "uid", |passwd: &Passwd| passwd.uid == uid)
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        let user = name.split('@').next().unwrap_or(name);
+        self.resolve(name, "passwd", |passwd: &Passwd| passwd.name == user)
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        vec![]
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        self.resolve(&gid.to_string(), "gid", |group: &Group| group.gid == gid)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let group = name.split('@').next().unwrap_or(name);
+        self.resolve(name, "group", |entry: &Group| entry.name == group)
+    }
+
+    /// The first record for `key` of Hesiod type `kind` that parses as an entry `matches` accepts.
+    fn resolve<T, F>(&self, key: &str, kind: &str, matches: F) -> Option<T>
+    where
+        T: std::str::FromStr,
+        F: Fn(&T) -> bool,
+    {
+        let name = self.dns_name(key, kind)?;
+        self.cached(&name)?
+            .iter()
+            .filter_map(|record| record.parse().ok())
+            .find(|entry| matches(entry))
+    }
+
+    /// Where Hesiod keeps `key`'s entry of type `kind`, or `None` if `key` can't be put in a DNS
+    /// name without changing its meaning.
+    fn dns_name(&self, key: &str, kind: &str) -> Option<String> {
+        let (key, domain) = match key.split_once('@') {
+            Some((key, domain)) => (key, domain.to_string()),
+            None => {
+                let lhs = self.config.lhs.trim_matches('.');
+                let rhs = self.config.rhs.trim_matches('.');
+                let domain = [lhs, rhs]
+                    .iter()
+                    .filter(|part| !part.is_empty())
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .join(".");
+                (key, domain)
+            }
+        };
+        let valid = !key.is_empty()
+            && key.len() <= 63
+            && key
+                .bytes()
+                .all(|b| b.is_ascii_graphic() && b != b'.' && b != b'\\');
+        if !valid || domain.trim_matches('.').is_empty() {
+            return None;
+        }
+        Some(format!("{}.{}.{}", key, kind, domain.trim_matches('.')).to_lowercase())
+    }
+
+    /// The records at `name`, from the cache or else from DNS, caching what DNS answers. `None` if
+    /// no nameserver answered, which is not cached.
+    fn cached(&self, name: &str) -> Option<Vec<String>> {
+        if let Some((expires, records)) = self.cache.read().unwrap().get(name) {
+            if Instant::now() < *expires {
+                return Some(records.clone());
+            }
+        }
+
+        let (records, ttl) = match self.lookup(name).ok()? {
+            Answer::Found(records, ttl) => (records, self.config.cache_ttl.min(secs(ttl))),
+            Answer::Missing(ttl) => (vec![], self.config.negative_ttl.min(secs(ttl))),
+        };
+        let expires = Instant::now() + ttl;
+
+        let mut cache = self.cache.write().unwrap();
+        cache.retain(|_, (expires, _)| Instant::now() < *expires);
+        cache.insert(name.to_string(), (expires, records.clone()));
+        Some(records)
+    }
+
+    /// Asks for `name` in each class in turn until one has records there.
+    fn lookup(&self, name: &str) -> io::Result<Answer> {
+        let mut missing = None;
+        for class in &self.config.classes {
+            match self.query(name, class.code())? {
+                Answer::Found(records, ttl) => return Ok(Answer::Found(records, ttl)),
+                Answer::Missing(ttl) => {
+                    missing = Some(missing.map_or(ttl, |shortest: u32| shortest.min(ttl)))
+                }
+            }
+        }
+        Ok(Answer::Missing(missing.unwrap_or(0)))
+    }
+
+    /// Asks each nameserver in turn for the TXT records at `name` until one answers.
+    fn query(&self, name: &str, class: u16) -> io::Result<Answer> {
+        let mut last = io::Error::new(io::ErrorKind::NotFound, "no nameservers configured");
+        for &server in &self.nameservers {
+            let id = self.id.fetch_add(1, Ordering::Relaxed);
+            let query = encode_query(id, name, class)?;
+            let answer =
+                self.query_udp(server, &query).and_then(|response| {
+                    match parse_response(&response, id, name, class)? {
+                        Some(answer) => Ok(answer),
+                        None => parse_response(&self.query_tcp(server, &query)?, id, name, class)?
+                            .ok_or_else(|| io::ErrorKind::InvalidData.into()),
+                    }
+                });
+            match answer {
+                Ok(answer) => return Ok(answer),
+                Err(err) => last = err,
+            }
+        }
+        Err(last)
+    }
+
+    fn query_udp(&self, server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        socket.send(query)?;
+
+        let deadline = Instant::now() + self.config.timeout;
+        let mut buffer = [0; 4096];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let len = match socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Err(io::ErrorKind::TimedOut.into())
+                }
+                Err(e) => return Err(e),
+            };
+            // Ignore stray datagrams, which can't be answering a query with a different ID
+            if buffer[..len].get(..2) == query.get(..2) {
+                return Ok(buffer[..len].to_vec());
+            }
+        }
+    }
+
+    fn query_tcp(&self, server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&server, self.config.timeout)?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream.write_all(&message)?;
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut response = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response)?;
+        Ok(response)
+    }
+}
+
+fn secs(ttl: u32) -> Duration {
+    Duration::from_secs(ttl.into())
+}
+
+/// The `nameserver`s in `/etc/resolv.conf`, or the local host's if there are none, as the C
+/// library's resolver falls back to.
+fn resolv_conf_nameservers() -> Vec<SocketAddr> {
+    let servers: Vec<SocketAddr> = fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next()? != "nameserver" {
+                return None;
+            }
+            // Drop any IPv6 zone, which IpAddr can't parse
+            let addr: IpAddr = words.next()?.split('%').next()?.parse().ok()?;
+            Some(SocketAddr::new(addr, DNS_PORT))
+        })
+        .collect();
+    if servers.is_empty() {
+        vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DNS_PORT)]
+    } else {
+        servers
+    }
+}
+
+fn encode_query(id: u16, name: &str, class: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0]); // flags: recursion desired
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a valid DNS name", name),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    Ok(packet)
+}
+
+/// What the response to query `id` for `name`'s TXT records in `class` says, or `None` if it
+/// didn't fit in a datagram and should be asked for again over TCP. An error if it isn't a response
+/// to the query or the server couldn't answer it.
+fn parse_response(packet: &[u8], id: u16, name: &str, class: u16) -> io::Result<Option<Answer>> {
+    let flags = match (read_u16(packet, 0), read_u16(packet, 2)) {
+        (Some(got), Some(flags)) if got == id && flags & 0x8000 != 0 => flags,
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    if flags & 0x0200 != 0 {
+        return Ok(None);
+    }
+    let rcode = flags & 0x000f;
+    if rcode != 0 && rcode != RCODE_NXDOMAIN {
+        return Err(io::Error::other(format!(
+            "nameserver answered with rcode {}",
+            rcode
+        )));
+    }
+    match parse_records(packet, name, class) {
+        Some((records, _)) if rcode == 0 && !records.is_empty() => {
+            let ttl = records.iter().map(|(_, ttl)| *ttl).min().unwrap_or(0);
+            let records = records.into_iter().map(|(text, _)| text).collect();
+            Ok(Some(Answer::Found(records, ttl)))
+        }
+        Some((_, negative_ttl)) => Ok(Some(Answer::Missing(negative_ttl))),
+        None => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
+/// The TXT records answering for `name`, following CNAMEs, each with its TTL, and the negative
+/// TTL (RFC 2308) from the authority section's SOA record, if any. `None` if the packet isn't
+/// well formed.
+fn parse_records(packet: &[u8], name: &str, class: u16) -> Option<(Vec<(String, u32)>, u32)> {
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+    let authorities = read_u16(packet, 8)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+
+    let mut owner = name.to_string();
+    let mut records = Vec::new();
+    let mut negative_ttl = 0;
+    for index in 0..answers + authorities {
+        let (record_owner, next) = read_name(packet, offset)?;
+        let rtype = read_u16(packet, next)?;
+        let record_class = read_u16(packet, next + 2)?;
+        let ttl = u32::from_be_bytes(packet.get(next + 4..next + 8)?.try_into().ok()?);
+        let length = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        let rdata = packet.get(data..data + length)?;
+        offset = data + length;
+
+        if index >= answers {
+            if rtype == TYPE_SOA {
+                // The SOA's minimum field is its last, after two names and four other counters
+                let (_, after_mname) = read_name(packet, data)?;
+                let (_, after_rname) = read_name(packet, after_mname)?;
+                let minimum = packet.get(after_rname + 16..after_rname + 20)?;
+                negative_ttl = ttl.min(u32::from_be_bytes(minimum.try_into().ok()?));
+            }
+            continue;
+        }
+        if record_class != class || record_owner != owner {
+            continue;
+        }
+        match rtype {
+            TYPE_CNAME => owner = read_name(packet, data)?.0,
+            TYPE_TXT => records.push((read_strings(rdata)?, ttl)),
+            _ => {}
+        }
+    }
+    Some((records, negative_ttl))
+}
+
+/// A TXT record's character strings, joined.
+fn read_strings(mut rdata: &[u8]) -> Option<String> {
+    let mut text = Vec::new();
+    while let Some((&len, rest)) = rdata.split_first() {
+        text.extend_from_slice(rest.get(..len as usize)?);
+        rdata = &rest[len as usize..];
+    }
+    Some(String::from_utf8_lossy(&text).into_owned())
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a possibly compressed name, returning it lowercased with the offset just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Each pointer has to go backwards, so this can't loop
+    let mut limit = offset;
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = (read_u16(packet, offset)? & 0x3fff) as usize;
+                if pointer >= limit {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                limit = pointer;
+                offset = pointer;
+            }
+            len if len < 64 => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                offset += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const IN: u16 = 1;
+
+    fn backend(nameservers: Vec<SocketAddr>) -> HesiodBackend {
+        HesiodBackend::new(HesiodConfig {
+            rhs: ".example.com".to_string(),
+            classes: vec![DnsClass::In],
+            nameservers,
+            timeout: Duration::from_millis(200),
+            ..HesiodConfig::default()
+        })
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.') {
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    fn txt(strings: &[&str]) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        for string in strings {
+            rdata.push(string.len() as u8);
+            rdata.extend_from_slice(string.as_bytes());
+        }
+        rdata
+    }
+
+    /// An SOA record's data with `minimum` as its negative TTL.
+    fn soa(minimum: u32) -> Vec<u8> {
+        let mut rdata = [name("ns.example.com"), name("hostmaster.example.com")].concat();
+        for field in [1, 3600, 600, 86400, minimum] {
+            rdata.extend_from_slice(&u32::to_be_bytes(field));
+        }
+        rdata
+    }
+
+    type Record<'a> = (&'a str, u16, u16, u32, Vec<u8>);
+
+    /// A response to query `id` for `question` with `flags` set on top of QR.
+    fn response(
+        id: u16,
+        flags: u16,
+        question: &str,
+        answers: &[Record<'_>],
+        authorities: &[Record<'_>],
+    ) -> Vec<u8> {
+        let mut packet = Vec::new();
+        for word in [
+            id,
+            0x8000 | flags,
+            1,
+            answers.len() as u16,
+            authorities.len() as u16,
+            0,
+        ] {
+            packet.extend_from_slice(&word.to_be_bytes());
+        }
+        packet.extend_from_slice(&name(question));
+        packet.extend_from_slice(&[0, 16, 0, 1]);
+        for (owner, rtype, class, ttl, rdata) in answers.iter().chain(authorities) {
+            packet.extend_from_slice(&name(owner));
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&class.to_be_bytes());
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            packet.extend_from_slice(rdata);
+        }
+        packet
+    }
+
+    fn found(answer: io::Result<Option<Answer>>) -> Option<(Vec<String>, u32)> {
+        match answer.unwrap()? {
+            Answer::Found(records, ttl) => Some((records, ttl)),
+            Answer::Missing(_) => None,
+        }
+    }
+
+    fn missing(answer: io::Result<Option<Answer>>) -> Option<u32> {
+        match answer.unwrap()? {
+            Answer::Found(..) => None,
+            Answer::Missing(ttl) => Some(ttl),
+        }
+    }
+
+    #[test]
+    fn dns_names() {
+        let hesiod = backend(vec![]);
+        assert_eq!(
+            hesiod.dns_name("alice", "passwd").as_deref(),
+            Some("alice.passwd.ns.example.com")
+        );
+        assert_eq!(
+            hesiod.dns_name("1000", "uid").as_deref(),
+            Some("1000.uid.ns.example.com")
+        );
+        assert_eq!(
+            hesiod
+                .dns_name("Alice@Other.Example.com.", "passwd")
+                .as_deref(),
+            Some("alice.passwd.other.example.com")
+        );
+        for key in ["", "a.b", "a\\b", "a b", "alice@", &"a".repeat(64)] {
+            assert_eq!(hesiod.dns_name(key, "passwd"), None, "{:?}", key);
+        }
+
+        let bare = HesiodBackend::new(HesiodConfig {
+            lhs: String::new(),
+            rhs: "example.com".to_string(),
+            ..HesiodConfig::default()
+        });
+        assert_eq!(
+            bare.dns_name("staff", "group").as_deref(),
+            Some("staff.group.example.com")
+        );
+    }
+
+    #[test]
+    fn queries() {
+        let query = encode_query(0x1234, "alice.passwd.ns.example.com", 4).unwrap();
+        let mut expected = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&name("alice.passwd.ns.example.com"));
+        expected.extend_from_slice(&[0, 16, 0, 4]);
+        assert_eq!(query, expected);
+
+        for bad in ["", "a..example.com", &format!("{}.com", "a".repeat(64))] {
+            assert_eq!(
+                encode_query(1, bad, IN).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn answers() {
+        let owner = "alice.passwd.ns.example.com";
+        let packet = response(
+            7,
+            0,
+            owner,
+            &[
+                (
+                    owner,
+                    TYPE_TXT,
+                    IN,
+                    300,
+                    txt(&["alice:x:1000:100:", "Alice:/home/alice:/bin/sh"]),
+                ),
+                (owner, TYPE_TXT, IN, 60, txt(&["second"])),
+                // Not the class asked for
+                (owner, TYPE_TXT, 4, 10, txt(&["hs"])),
+                ("other.example.com", TYPE_TXT, IN, 10, txt(&["other"])),
+            ],
+            &[],
+        );
+        assert_eq!(
+            found(parse_response(&packet, 7, owner, IN)),
+            Some((
+                vec![
+                    "alice:x:1000:100:Alice:/home/alice:/bin/sh".to_string(),
+                    "second".to_string()
+                ],
+                60
+            ))
+        );
+
+        // Followed through a CNAME, and names compare case-insensitively
+        let packet = response(
+            7,
+            0,
+            owner,
+            &[
+                (owner, TYPE_CNAME, IN, 300, name("Alice.Users.example.com")),
+                (
+                    "alice.users.example.com",
+                    TYPE_TXT,
+                    IN,
+                    300,
+                    txt(&["aliased"]),
+                ),
+            ],
+            &[],
+        );
+        assert_eq!(
+            found(parse_response(&packet, 7, owner, IN)),
+            Some((vec!["aliased".to_string()], 300))
+        );
+    }
+
+    #[test]
+    fn negative_answers() {
+        let owner = "bob.passwd.ns.example.com";
+        let authority = [("example.com", TYPE_SOA, IN, 900, soa(120))];
+        let nxdomain = response(7, RCODE_NXDOMAIN, owner, &[], &authority);
+        assert_eq!(missing(parse_response(&nxdomain, 7, owner, IN)), Some(120));
+        // No TXT records, bounded by the SOA's own TTL
+        let authority = [("example.com", TYPE_SOA, IN, 30, soa(120))];
+        let nodata = response(7, 0, owner, &[], &authority);
+        assert_eq!(missing(parse_response(&nodata, 7, owner, IN)), Some(30));
+        let bare = response(7, RCODE_NXDOMAIN, owner, &[], &[]);
+        assert_eq!(missing(parse_response(&bare, 7, owner, IN)), Some(0));
+    }
+
+    #[test]
+    fn bad_responses() {
+        let owner = "alice.passwd.ns.example.com";
+        let answer = [(owner, TYPE_TXT, IN, 300, txt(&["alice"]))];
+        let packet = response(7, 0, owner, &answer, &[]);
+
+        assert!(
+            parse_response(&response(7, 0x0200, owner, &[], &[]), 7, owner, IN)
+                .unwrap()
+                .is_none()
+        );
+        assert!(parse_response(&packet, 8, owner, IN).is_err());
+        let mut query = packet.clone();
+        query[2] &= 0x7f;
+        assert!(parse_response(&query, 7, owner, IN).is_err());
+        let servfail = response(7, 2, owner, &[], &[]);
+        assert!(parse_response(&servfail, 7, owner, IN).is_err());
+        for len in 12..packet.len() {
+            assert!(parse_response(&packet[..len], 7, owner, IN).is_err());
+        }
+        assert_eq!(read_strings(&[5, b'a']), None);
+    }
+
+    #[test]
+    fn compressed_names() {
+        let mut packet = vec![0; 12];
+        packet.extend_from_slice(&name("example.com"));
+        // `ns` followed by a pointer to `example.com`
+        packet.extend_from_slice(&[2, b'n', b's', 0xc0, 12]);
+        assert_eq!(
+            read_name(&packet, 25),
+            Some(("ns.example.com".to_string(), 30))
+        );
+        // Pointers have to point backwards
+        packet.extend_from_slice(&[0xc0, 30]);
+        assert_eq!(read_name(&packet, 30), None);
+        assert_eq!(read_name(&[0x40, 0], 0), None);
+    }
+
+    /// Answers each query it's sent with `answer` for as long as the test runs.
+    fn fake_nameserver(answer: &'static [&'static str]) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let id = read_u16(&buf, 0).unwrap();
+                let (question, _) = read_name(&buf[..len], 12).unwrap();
+                let packet = if question.starts_with("alice.") {
+                    let answer = [(question.as_str(), TYPE_TXT, IN, 300, txt(answer))];
+                    response(id, 0, &question, &answer, &[])
+                } else {
+                    response(id, RCODE_NXDOMAIN, &question, &[], &[])
+                };
+                socket.send_to(&packet, peer).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn lookups() {
+        let nameserver = fake_nameserver(&["alice:x:1000:100:Alice:/home/alice:/bin/sh"]);
+        // Servers that don't answer are skipped
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut hesiod = backend(vec![silent.local_addr().unwrap(), nameserver]);
+
+        let alice = hesiod.get_passwd_by_name("alice").unwrap();
+        assert_eq!(
+            (alice.uid, alice.gid),
+            (Uid::from_raw(1000), Gid::from_raw(100))
+        );
+        assert_eq!(hesiod.get_passwd_by_name("bob"), None);
+        // The record has to be for the entry asked for
+        assert_eq!(hesiod.get_group_by_name("alice"), None);
+        assert!(hesiod.get_all_passwd().is_empty());
+
+        // Served from the cache once answered
+        hesiod.nameservers.clear();
+        assert_eq!(hesiod.get_passwd_by_name("alice"), Some(alice));
+    }
+}
@@ -0,0 +1,565 @@
+//! Merges every `/etc/hosts` format file matching a glob, such as `/etc/hosts.d/*.hosts`, so
+//! configuration management can drop a file per service instead of rewriting one shared file.
+//!
+//! Only the pattern's last component may contain `*` and `?`, which match as the shell's do, so
+//! `*` doesn't match a leading dot and editors' hidden swap files are left alone.
+//!
+//! Each file's priority is the number its name starts with, as in `90-local.hosts`, or
+//! `default_priority` if it doesn't start with one. A name in several files resolves from the one
+//! with the highest priority, or of those the one whose name sorts last, as drop-ins override one
+//! another in `sysctl.d`. Within a file, lines for the same name are merged as glibc does with
+//! `multi on`. An address in several files likewise resolves from the highest priority file.
+//!
+//! The directory is watched with inotify, so changes are picked up by the next lookup after them.
+//! The files are also checked for changes every `check_interval`, which catches edits inotify
+//! misses, such as those to the targets of symlinks, or every change when inotify isn't available.
+//! Malformed lines are skipped and reported to syslog, naming the file and line.
+//!
+//! ```
+//! use libnss::backends::hosts_dir::{HostsDirBackend, HostsDirConfig};
+//! use libnss::host::AddressFamily;
+//! # let dir = std::env::temp_dir().join(format!("libnss-hosts-dir-doc-{}", std::process::id()));
+//! # std::fs::create_dir_all(&dir).unwrap();
+//! # std::fs::write(dir.join("50-db.hosts"), "10.0.0.5 db db.internal\n10.0.0.6 db\n").unwrap();
+//! # std::fs::write(dir.join("90-override.hosts"), "10.0.9.9 db.internal\n").unwrap();
+//!
+//! let backend = HostsDirBackend::new(HostsDirConfig {
+//!     glob: format!("{}/*.hosts", dir.display()),
+//!     ..HostsDirConfig::default()
+//! });
+//!
+//! let db = backend.get_host_by_name("db", AddressFamily::IPv4).unwrap();
+//! assert_eq!(db.addresses.len(), 2);
+//! let overridden = backend.get_host_by_name("db.internal", AddressFamily::IPv4).unwrap();
+//! assert_eq!(overridden.addresses, "10.0.9.9".parse::<std::net::Ipv4Addr>().unwrap().into());
+//! # std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::audit;
+use crate::files;
+use crate::host::{AddressFamily, Addresses, Host};
+
+pub struct HostsDirConfig {
+    /// Which files to merge
+    pub glob: String,
+    /// The priority of files whose names don't start with a number
+    pub default_priority: u32,
+    pub check_interval: Duration,
+}
+
+impl Default for HostsDirConfig {
+    fn default() -> Self {
+        HostsDirConfig {
+            glob: "/etc/hosts.d/*.hosts".to_string(),
+            default_priority: 50,
+            check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A file's entries, one per line.
+struct HostsFile {
+    path: PathBuf,
+    priority: u32,
+    /// Modification time and size of the file when read
+    stamp: Option<(SystemTime, u64)>,
+    hosts: Vec<Host>,
+}
+
+struct State {
+    /// Highest priority first
+    files: Arc<Vec<HostsFile>>,
+    /// `None` until the directory can be watched, and in a forked child until its first check
+    watch: Option<Inotify>,
+    checked: Option<Instant>,
+}
+
+pub struct HostsDirBackend {
+    dir: PathBuf,
+    pattern: String,
+    default_priority: u32,
+    check_interval: Duration,
+    state: RwLock<State>,
+}
+
+impl HostsDirBackend {
+    pub fn new(config: HostsDirConfig) -> Self {
+        let glob = Path::new(&config.glob);
+        let dir = glob.parent().unwrap_or_else(|| Path::new("."));
+        let pattern = glob.file_name().map(|name| name.to_string_lossy());
+
+        HostsDirBackend {
+            dir: dir.to_path_buf(),
+            pattern: pattern.unwrap_or_default().into_owned(),
+            default_priority: config.default_priority,
+            check_interval: config.check_interval,
+            state: RwLock::new(State {
+                files: Arc::default(),
+                watch: None,
+                checked: None,
+            }),
+        }
+    }
+
+    /// Every line of every file, highest priority first.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let files = self.files();
+        files
+            .iter()
+            .flat_map(|file| file.hosts.iter().cloned())
+            .collect()
+    }
+
+    /// Matches names and aliases, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let files = self.files();
+        let named = |host: &&Host| {
+            host.name.eq_ignore_ascii_case(name)
+                || host
+                    .aliases
+                    .iter()
+                    .any(|alias| alias.eq_ignore_ascii_case(name))
+        };
+        let file = files
+            .iter()
+            .find(|file| file.hosts.iter().any(|h| named(&h)))?;
+
+        let merge = |v6: bool| {
+            let mut lines = file
+                .hosts
+                .iter()
+                .filter(named)
+                .filter(|host| matches!(host.addresses, Addresses::V6(_)) == v6);
+            let mut merged = lines.next()?.clone();
+            for host in lines {
+                match (&mut merged.addresses, &host.addresses) {
+                    (Addresses::V4(all), Addresses::V4(addrs)) => all.extend(addrs),
+                    (Addresses::V6(all), Addresses::V6(addrs)) => all.extend(addrs),
+                    _ => {}
+                }
+                for alias in host.aliases.iter().chain(Some(&host.name)) {
+                    if *alias != merged.name && !merged.aliases.contains(alias) {
+                        merged.aliases.push(alias.clone());
+                    }
+                }
+            }
+            Some(merged)
+        };
+        match family {
+            AddressFamily::IPv4 => merge(false),
+            AddressFamily::IPv6 => merge(true),
+            AddressFamily::Unspecified => merge(false).or_else(|| merge(true)),
+            AddressFamily::Other(_) => None,
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let files = self.files();
+        let has = |host: &&Host| match (&host.addresses, addr) {
+            (Addresses::V4(addrs), IpAddr::V4(addr)) => addrs.contains(&addr),
+            (Addresses::V6(addrs), IpAddr::V6(addr)) => addrs.contains(&addr),
+            _ => false,
+        };
+        files
+            .iter()
+            .find_map(|file| file.hosts.iter().find(has))
+            .cloned()
+    }
+
+    /// The current files, reading them again first if inotify reported a change in the directory
+    /// or the periodic check found one.
+    fn files(&self) -> Arc<Vec<HostsFile>> {
+        let due = |state: &State| {
+            state
+                .checked
+                .is_none_or(|checked| checked.elapsed() >= self.check_interval)
+        };
+
+        // Reading events takes them off the queue, so a thread that sees one has to reload
+        let state = self.state.read().unwrap();
+        let notified = match &state.watch {
+            Some(watch) if watch.pid == process::id() => watch.changed(),
+            _ => false,
+        };
+        if !notified && !due(&state) {
+            return state.files.clone();
+        }
+        drop(state);
+
+        let mut state = self.state.write().unwrap();
+        if !notified && !due(&state) {
+            return state.files.clone();
+        }
+        state.checked = Some(Instant::now());
+        // A child shares its parent's inotify queue, so it starts one of its own
+        if state.watch.as_ref().is_none_or(|w| w.pid != process::id()) {
+            state.watch = Inotify::watch(&self.dir).ok();
+        }
+
+        let paths = self.matching();
+        let unchanged = paths.len() == state.files.len()
+            && state
+                .files
+                .iter()
+                .all(|file| paths.contains(&file.path) && stamp(&file.path) == file.stamp);
+        if notified || !unchanged {
+            state.files = Arc::new(self.read(paths));
+        }
+        state.files.clone()
+    }
+
+    /// The files matching the glob.
+    fn matching(&self) -> Vec<PathBuf> {
+        fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                let name = name.to_str()?;
+                if !matches_glob(self.pattern.as_bytes(), name.as_bytes()) {
+                    return None;
+                }
+                Some(self.dir.join(name))
+            })
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
+    fn read(&self, paths: Vec<PathBuf>) -> Vec<HostsFile> {
+        let mut files: Vec<HostsFile> = paths
+            .into_iter()
+            .filter_map(|path| {
+                let stamp = stamp(&path);
+                let contents = fs::read_to_string(&path).ok()?;
+                let mut hosts = Vec::new();
+                for entry in files::parse::<Host>(&contents) {
+                    match entry {
+                        Ok(host) => hosts.push(host),
+                        Err(err) => audit::syslog(
+                            libc::LOG_ERR,
+                            &format!("libnss: {}: {}", path.display(), err),
+                        ),
+                    }
+                }
+                let name = path.file_name()?.to_string_lossy();
+                let digits = name.bytes().take_while(u8::is_ascii_digit).count();
+                let priority = name[..digits].parse().unwrap_or(self.default_priority);
+                Some(HostsFile {
+                    path,
+                    priority,
+                    stamp,
+                    hosts,
+                })
+            })
+            .collect();
+        files.sort_by(|a, b| (b.priority, &b.path).cmp(&(a.priority, &a.path)));
+        files
+    }
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    fs::metadata(path)
+        .ok()
+        .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())))
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any run of characters and `?` any one,
+/// neither matching a leading dot.
+fn matches_glob(pattern: &[u8], name: &[u8]) -> bool {
+    if name.first() == Some(&b'.') && pattern.first() != Some(&b'.') {
+        return false;
+    }
+    // Where to resume after the last `*`, and the name position it last matched up to
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((resume, matched)) => {
+                    star = Some((resume, matched + 1));
+                    p = resume;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// An inotify instance watching one directory for files being written, created, renamed or
+/// removed.
+struct Inotify {
+    fd: OwnedFd,
+    /// The process that created it
+    pid: u32,
+}
+
+impl Inotify {
+    fn watch(dir: &Path) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_DELETE_SELF
+            | libc::IN_MOVE_SELF;
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            fd,
+            pid: process::id(),
+        })
+    }
+
+    /// Whether anything happened since the last call, taking every event off the queue. Any event
+    /// counts, as the glob is matched against the directory anew anyway.
+    fn changed(&self) -> bool {
+        let mut buffer = [0u8; 4096];
+        let mut changed = false;
+        loop {
+            let read = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if read <= 0 {
+                // EAGAIN once the queue is empty; any other error is treated as a change, so the
+                // files are checked rather than possibly going stale
+                let empty = io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock;
+                return changed || (read < 0 && !empty);
+            }
+            changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libnss-hosts-dir-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    fn backend(dir: &Path) -> HostsDirBackend {
+        HostsDirBackend::new(HostsDirConfig {
+            glob: format!("{}/*.hosts", dir.display()),
+            check_interval: Duration::from_secs(3600),
+            ..HostsDirConfig::default()
+        })
+    }
+
+    fn v4(addrs: &[[u8; 4]]) -> Addresses {
+        Addresses::V4(addrs.iter().map(|&octets| Ipv4Addr::from(octets)).collect())
+    }
+
+    #[test]
+    fn globs() {
+        for (pattern, name, matches) in [
+            ("*.hosts", "db.hosts", true),
+            ("*.hosts", ".hosts", false),
+            ("*.hosts", ".db.hosts", false),
+            ("*.hosts", "db.hosts.swp", false),
+            ("*.hosts", "db.host", false),
+            (".*.hosts", ".db.hosts", true),
+            ("??-*.hosts", "50-db.hosts", true),
+            ("??-*.hosts", "5-db.hosts", false),
+            ("*", "anything", true),
+            ("*", ".hidden", false),
+            ("*a*b", "xaxxab", true),
+            ("*a*b", "xaxxa", false),
+            ("hosts", "hosts", true),
+            ("hosts", "hosts2", false),
+            ("**", "", true),
+        ] {
+            assert_eq!(
+                matches_glob(pattern.as_bytes(), name.as_bytes()),
+                matches,
+                "{} {}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn priorities() {
+        let dir = dir(
+            "priorities",
+            &[
+                (
+                    "10-base.hosts",
+                    "10.0.0.1 db\n10.0.0.2 web\n10.0.0.3 cache\n",
+                ),
+                // The default priority of 50, beating 10 but not 90
+                ("local.hosts", "10.0.1.1 db\n10.0.1.2 web\n"),
+                ("90-override.hosts", "10.0.9.1 db\n"),
+                // Same priority as 10-base.hosts, but sorts after it
+                ("10-more.hosts", "10.0.0.9 cache\n"),
+                (".hidden.hosts", "10.0.7.1 db\n"),
+                ("20-ignored.conf", "10.0.7.2 db\n"),
+            ],
+        );
+        fs::create_dir(dir.join("30-dir.hosts")).unwrap();
+        let hosts = backend(&dir);
+
+        let addresses = |name| {
+            hosts
+                .get_host_by_name(name, AddressFamily::IPv4)
+                .unwrap()
+                .addresses
+        };
+        assert_eq!(addresses("db"), v4(&[[10, 0, 9, 1]]));
+        assert_eq!(addresses("web"), v4(&[[10, 0, 1, 2]]));
+        assert_eq!(addresses("cache"), v4(&[[10, 0, 0, 9]]));
+
+        assert_eq!(
+            hosts
+                .get_host_by_addr("10.0.0.1".parse().unwrap())
+                .unwrap()
+                .name,
+            "db"
+        );
+        assert_eq!(
+            hosts
+                .get_all_hosts()
+                .iter()
+                .map(|host| host.addresses.clone())
+                .collect::<Vec<_>>(),
+            [
+                v4(&[[10, 0, 9, 1]]),
+                v4(&[[10, 0, 1, 1]]),
+                v4(&[[10, 0, 1, 2]]),
+                v4(&[[10, 0, 0, 9]]),
+                v4(&[[10, 0, 0, 1]]),
+                v4(&[[10, 0, 0, 2]]),
+                v4(&[[10, 0, 0, 3]]),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merges_lines_within_a_file() {
+        let dir = dir(
+            "merge",
+            &[(
+                "50-db.hosts",
+                "10.0.0.5 db db.internal\n\
+                 not an address db\n\
+                 10.0.0.6 DB primary\n\
+                 fd00::5 db\n\
+                 10.0.0.7 other\n",
+            )],
+        );
+        let hosts = backend(&dir);
+
+        assert_eq!(
+            hosts.get_host_by_name("db.INTERNAL", AddressFamily::Unspecified),
+            Some(Host {
+                name: "db".to_string(),
+                aliases: vec!["db.internal".to_string()],
+                addresses: v4(&[[10, 0, 0, 5]]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            hosts.get_host_by_name("db", AddressFamily::IPv4),
+            Some(Host {
+                name: "db".to_string(),
+                aliases: vec![
+                    "db.internal".to_string(),
+                    "primary".to_string(),
+                    "DB".to_string()
+                ],
+                addresses: v4(&[[10, 0, 0, 5], [10, 0, 0, 6]]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            hosts
+                .get_host_by_name("db", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec![Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 5)])
+        );
+        assert_eq!(hosts.get_host_by_name("other", AddressFamily::IPv6), None);
+        assert_eq!(hosts.get_host_by_name("db", AddressFamily::Other(17)), None);
+        // The malformed line is skipped
+        assert_eq!(hosts.get_all_hosts().len(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn picks_up_changes() {
+        let dir = dir("changes", &[("50-db.hosts", "10.0.0.5 db\n")]);
+        let hosts = backend(&dir);
+        assert!(hosts.get_host_by_name("db", AddressFamily::IPv4).is_some());
+
+        // Long before `check_interval`, through inotify
+        fs::write(dir.join("60-web.hosts"), "10.0.0.6 web\n").unwrap();
+        assert!(hosts.get_host_by_name("web", AddressFamily::IPv4).is_some());
+        fs::rename(dir.join("50-db.hosts"), dir.join("50-db.hosts.bak")).unwrap();
+        assert!(hosts.get_host_by_name("db", AddressFamily::IPv4).is_none());
+
+        // Without inotify, once `check_interval` has passed
+        let polled = HostsDirBackend::new(HostsDirConfig {
+            glob: format!("{}/*.hosts", dir.display()),
+            check_interval: Duration::from_secs(0),
+            ..HostsDirConfig::default()
+        });
+        assert!(polled
+            .get_host_by_name("web", AddressFamily::IPv4)
+            .is_some());
+        polled.state.write().unwrap().watch = None;
+        fs::write(dir.join("60-web.hosts"), "10.0.0.7 web\n").unwrap();
+        assert_eq!(
+            polled
+                .get_host_by_name("web", AddressFamily::IPv4)
+                .unwrap()
+                .addresses,
+            v4(&[[10, 0, 0, 7]])
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
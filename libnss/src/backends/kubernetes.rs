@@ -0,0 +1,802 @@
+//! Resolves Kubernetes service names to their cluster IPs, for tooling on nodes and in pods that
+//! doesn't go through the cluster DNS.
+//!
+//! With [`Source::Api`] the services are listed from the API server and then kept current by
+//! watching it from a background thread, as client-go's informers do. The first lookup waits up to
+//! `sync_timeout` for the initial list; lookups after that are answered from memory. Every process
+//! that loads the module lists every service it can see, so in anything but long running processes
+//! prefer running this behind nscd or the `daemon` feature's shim.
+//!
+//! With [`Source::Environment`] the `<SERVICE>_SERVICE_HOST` variables kubelet sets in each
+//! container are used instead. They only cover services in the pod's own namespace that existed
+//! when it started, but need no API access.
+//!
+//! `service.namespace`, `service.namespace.svc` and `service.namespace.svc.<cluster domain>` are
+//! all answered, and bare service names too if `namespace` is set. Headless and `ExternalName`
+//! services have no cluster IP and are never answered.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use ureq::tls::{Certificate, RootCerts, TlsConfig};
+use ureq::Agent;
+
+use crate::host::{AddressFamily, Addresses, Host};
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+pub struct ApiConfig {
+    /// `None` uses the in-cluster address from `KUBERNETES_SERVICE_HOST` and
+    /// `KUBERNETES_SERVICE_PORT`. Use an IP address: resolving a name here may end up back in this
+    /// module.
+    pub server: Option<String>,
+    /// Bearer token, re-read for every request so rotated tokens are picked up
+    pub token_file: PathBuf,
+    /// CA bundle used to verify the API server
+    pub ca_certificate: PathBuf,
+    pub timeout: Duration,
+    /// How long the first lookup waits for the initial list of services
+    pub sync_timeout: Duration,
+    /// How long each watch request runs before it is renewed
+    pub watch_interval: Duration,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            server: None,
+            token_file: PathBuf::from(SERVICE_ACCOUNT).join("token"),
+            ca_certificate: PathBuf::from(SERVICE_ACCOUNT).join("ca.crt"),
+            timeout: Duration::from_secs(2),
+            sync_timeout: Duration::from_secs(2),
+            watch_interval: Duration::from_secs(300),
+        }
+    }
+}
+
+pub enum Source {
+    Api(ApiConfig),
+    Environment,
+}
+
+pub struct KubernetesConfig {
+    pub source: Source,
+    /// Only serve services in this namespace, which also makes bare service names resolve. With
+    /// the API source this only needs permission to list services in that namespace.
+    /// [`Source::Environment`] always uses a namespace, defaulting to the pod's own.
+    pub namespace: Option<String>,
+    pub cluster_domain: String,
+}
+
+impl Default for KubernetesConfig {
+    fn default() -> Self {
+        KubernetesConfig {
+            source: Source::Api(ApiConfig::default()),
+            namespace: None,
+            cluster_domain: "cluster.local".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Service {
+    name: String,
+    namespace: String,
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<Ipv6Addr>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Keyed by `namespace/name`. Shared with lookups, so the watcher copies it on write rather
+    /// than lookups copying it under the lock.
+    services: Arc<HashMap<String, Service>>,
+    synced: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    cache: Mutex<Cache>,
+    synced: Condvar,
+}
+
+struct Informer {
+    client: Arc<Client>,
+    sync_timeout: Duration,
+    /// The process the watcher was started in, and the cache it keeps current
+    state: RwLock<(u32, Arc<Shared>)>,
+}
+
+enum Services {
+    Api(Informer),
+    Environment,
+}
+
+pub struct KubernetesBackend {
+    services: Services,
+    namespace: Option<String>,
+    cluster_domain: String,
+}
+
+impl KubernetesBackend {
+    /// With the API source, starts listing and watching services in the background.
+    pub fn new(config: KubernetesConfig) -> io::Result<Self> {
+        let (services, namespace) = match config.source {
+            Source::Api(api) => {
+                let sync_timeout = api.sync_timeout;
+                let client = Arc::new(Client::new(api, config.namespace.as_deref())?);
+                let informer = Informer {
+                    state: RwLock::new(spawn(client.clone())),
+                    client,
+                    sync_timeout,
+                };
+                (Services::Api(informer), config.namespace)
+            }
+            Source::Environment => {
+                let namespace = match config.namespace {
+                    Some(namespace) => namespace,
+                    None => fs::read_to_string(PathBuf::from(SERVICE_ACCOUNT).join("namespace"))?
+                        .trim()
+                        .to_string(),
+                };
+                (Services::Environment, Some(namespace))
+            }
+        };
+
+        Ok(KubernetesBackend {
+            services,
+            namespace,
+            cluster_domain: config.cluster_domain.trim_matches('.').to_lowercase(),
+        })
+    }
+
+    /// One entry per service and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let mut hosts = Vec::new();
+        for service in self.services() {
+            hosts.extend(self.to_host(&service, AddressFamily::IPv4));
+            hosts.extend(self.to_host(&service, AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let (service, namespace) = self.parse_name(name)?;
+        let service = self
+            .services()
+            .into_iter()
+            .find(|s| s.name == service && s.namespace == namespace)?;
+
+        match family {
+            AddressFamily::Unspecified => self
+                .to_host(&service, AddressFamily::IPv4)
+                .or_else(|| self.to_host(&service, AddressFamily::IPv6)),
+            family => self.to_host(&service, family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let services = self.services();
+        match addr {
+            IpAddr::V4(addr) => {
+                let service = services.iter().find(|s| s.v4.contains(&addr))?;
+                self.to_host(service, AddressFamily::IPv4)
+            }
+            IpAddr::V6(addr) => {
+                let service = services.iter().find(|s| s.v6.contains(&addr))?;
+                self.to_host(service, AddressFamily::IPv6)
+            }
+        }
+    }
+
+    /// Splits a name this backend answers into the service and its namespace.
+    fn parse_name(&self, name: &str) -> Option<(String, String)> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let name = name
+            .strip_suffix(&format!(".svc.{}", self.cluster_domain))
+            .or_else(|| name.strip_suffix(".svc"))
+            .unwrap_or(&name);
+
+        match name.split_once('.') {
+            Some((service, namespace)) if !namespace.contains('.') => {
+                if self.namespace.as_deref().is_some_and(|n| n != namespace) {
+                    return None;
+                }
+                Some((service.to_string(), namespace.to_string()))
+            }
+            Some(_) => None,
+            None => Some((name.to_string(), self.namespace.clone()?)),
+        }
+    }
+
+    fn services(&self) -> Vec<Service> {
+        match &self.services {
+            Services::Api(informer) => informer.services(),
+            Services::Environment => self.environment_services(),
+        }
+    }
+
+    fn environment_services(&self) -> Vec<Service> {
+        let namespace = self.namespace.clone().unwrap_or_default();
+        env::vars()
+            .filter_map(|(key, value)| {
+                let name = key.strip_suffix("_SERVICE_HOST")?;
+                let mut service = Service {
+                    name: name.to_lowercase().replace('_', "-"),
+                    namespace: namespace.clone(),
+                    v4: vec![],
+                    v6: vec![],
+                };
+                match value.parse().ok()? {
+                    IpAddr::V4(addr) => service.v4.push(addr),
+                    IpAddr::V6(addr) => service.v6.push(addr),
+                }
+                Some(service)
+            })
+            .collect()
+    }
+
+    fn to_host(&self, service: &Service, family: AddressFamily) -> Option<Host> {
+        let addresses = match family {
+            AddressFamily::IPv4 if !service.v4.is_empty() => Addresses::V4(service.v4.clone()),
+            AddressFamily::IPv6 if !service.v6.is_empty() => Addresses::V6(service.v6.clone()),
+            _ => return None,
+        };
+
+        let short = format!("{}.{}", service.name, service.namespace);
+        Some(Host {
+            name: format!("{}.svc.{}", short, self.cluster_domain),
+            aliases: vec![format!("{}.svc", short), short],
+            addresses,
+            canonical_name: None,
+        })
+    }
+}
+
+impl Informer {
+    fn services(&self) -> Vec<Service> {
+        let shared = match &*self.state.read().unwrap() {
+            (pid, shared) if *pid == std::process::id() => Some(shared.clone()),
+            _ => None,
+        };
+        let shared = shared.unwrap_or_else(|| {
+            let mut state = self.state.write().unwrap();
+            // A forked child has the cache but not the thread keeping it current
+            if state.0 != std::process::id() {
+                *state = spawn(self.client.clone());
+            }
+            state.1.clone()
+        });
+
+        let cache = shared.cache.lock().unwrap();
+        let (cache, _) = shared
+            .synced
+            .wait_timeout_while(cache, self.sync_timeout, |cache| !cache.synced)
+            .unwrap();
+        let services = cache.services.clone();
+        drop(cache);
+        services.values().cloned().collect()
+    }
+}
+
+/// Starts a thread that lists and then watches services into a new cache, until the cache is
+/// dropped.
+fn spawn(client: Arc<Client>) -> (u32, Arc<Shared>) {
+    let shared = Arc::new(Shared::default());
+    let weak = Arc::downgrade(&shared);
+    thread::spawn(move || run(&client, &weak));
+    (std::process::id(), shared)
+}
+
+fn run(client: &Client, shared: &Weak<Shared>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let (services, mut resource_version) = match client.list() {
+            Ok(listed) => listed,
+            Err(_) => {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+                continue;
+            }
+        };
+        backoff = Duration::from_secs(1);
+        match shared.upgrade() {
+            Some(shared) => {
+                let mut cache = shared.cache.lock().unwrap();
+                cache.services = Arc::new(services);
+                cache.synced = true;
+                shared.synced.notify_all();
+            }
+            None => return,
+        }
+
+        // Resume the watch from the last version seen until the server says it's too old
+        loop {
+            let started = Instant::now();
+            match client.watch(&mut resource_version, shared) {
+                Ok(Watch::Expired) => break,
+                Ok(Watch::Dropped) => return,
+                Ok(Watch::Ended) => {}
+                Err(_) => {
+                    // Don't spin against a server that accepts the watch and then fails it
+                    if started.elapsed() < Duration::from_secs(1) {
+                        thread::sleep(backoff);
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Watch {
+    /// The request timed out as asked, so the watch can be resumed
+    Ended,
+    /// The resource version is no longer available, so the services have to be listed again
+    Expired,
+    /// The cache was dropped
+    Dropped,
+}
+
+struct Client {
+    agent: Agent,
+    url: String,
+    token_file: PathBuf,
+    watch_interval: Duration,
+}
+
+impl Client {
+    fn new(config: ApiConfig, namespace: Option<&str>) -> io::Result<Self> {
+        let server = match config.server {
+            Some(server) => server,
+            None => {
+                let host = env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+                    io::Error::other("KUBERNETES_SERVICE_HOST is not set; configure the server")
+                })?;
+                let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+                match host.parse() {
+                    Ok(IpAddr::V6(_)) => format!("https://[{}]:{}", host, port),
+                    _ => format!("https://{}:{}", host, port),
+                }
+            }
+        };
+        let url = match namespace {
+            Some(namespace) => format!(
+                "{}/api/v1/namespaces/{}/services",
+                server.trim_end_matches('/'),
+                namespace
+            ),
+            None => format!("{}/api/v1/services", server.trim_end_matches('/')),
+        };
+
+        let ca = fs::read(&config.ca_certificate)?;
+        let ca = Certificate::from_pem(&ca)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let agent = Agent::config_builder()
+            .tls_config(
+                TlsConfig::builder()
+                    .root_certs(RootCerts::new_with_certs(&[ca]))
+                    .build(),
+            )
+            .timeout_connect(Some(config.timeout))
+            .timeout_recv_response(Some(config.timeout))
+            .build()
+            .new_agent();
+
+        Ok(Client {
+            agent,
+            url,
+            token_file: config.token_file,
+            watch_interval: config.watch_interval,
+        })
+    }
+
+    fn get(&self, query: &[(&str, &str)]) -> io::Result<ureq::Body> {
+        let token = fs::read_to_string(&self.token_file)?;
+        let mut request = self
+            .agent
+            .get(&self.url)
+            .header("Authorization", format!("Bearer {}", token.trim()));
+        for (key, value) in query {
+            request = request.query(*key, *value);
+        }
+        let response = request.call().map_err(io::Error::other)?;
+        Ok(response.into_body())
+    }
+
+    /// Lists every service, a page at a time, returning them with the version to watch from.
+    fn list(&self) -> io::Result<(HashMap<String, Service>, String)> {
+        let mut services = HashMap::new();
+        let mut next = String::new();
+        loop {
+            let mut query = vec![("limit", "500")];
+            if !next.is_empty() {
+                query.push(("continue", &next));
+            }
+            let page: Value = serde_json::from_reader(self.get(&query)?.into_reader())?;
+
+            for item in page["items"].as_array().into_iter().flatten() {
+                if let Some((key, service)) = to_service(item) {
+                    services.insert(key, service);
+                }
+            }
+            let metadata = &page["metadata"];
+            match metadata["continue"].as_str() {
+                Some(token) if !token.is_empty() => next = token.to_string(),
+                _ => {
+                    let version = metadata["resourceVersion"].as_str().unwrap_or_default();
+                    return Ok((services, version.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Applies changes to the cache until the watch ends, keeping `resource_version` current.
+    fn watch(&self, resource_version: &mut String, shared: &Weak<Shared>) -> io::Result<Watch> {
+        let timeout = self.watch_interval.as_secs().max(1).to_string();
+        let body = self.get(&[
+            ("watch", "1"),
+            ("allowWatchBookmarks", "true"),
+            ("resourceVersion", resource_version.as_str()),
+            ("timeoutSeconds", &timeout),
+        ])?;
+
+        for line in BufReader::new(body.into_reader()).lines() {
+            let event: Value = serde_json::from_str(&line?)?;
+            let object = &event["object"];
+            let kind = event["type"].as_str().unwrap_or_default();
+            if kind == "ERROR" {
+                return match object["code"].as_u64() {
+                    Some(410) => Ok(Watch::Expired),
+                    _ => Err(io::Error::other(object["message"].to_string())),
+                };
+            }
+
+            if let Some(version) = object["metadata"]["resourceVersion"].as_str() {
+                *resource_version = version.to_string();
+            }
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return Ok(Watch::Dropped),
+            };
+            let mut cache = shared.cache.lock().unwrap();
+            match kind {
+                "ADDED" | "MODIFIED" => {
+                    // A service can lose its cluster IP, so drop it before re-adding
+                    if let Some(key) = key(object) {
+                        Arc::make_mut(&mut cache.services).remove(&key);
+                    }
+                    if let Some((key, service)) = to_service(object) {
+                        Arc::make_mut(&mut cache.services).insert(key, service);
+                    }
+                }
+                "DELETED" => {
+                    if let Some(key) = key(object) {
+                        Arc::make_mut(&mut cache.services).remove(&key);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Watch::Ended)
+    }
+}
+
+fn key(object: &Value) -> Option<String> {
+    let metadata = &object["metadata"];
+    Some(format!(
+        "{}/{}",
+        metadata["namespace"].as_str()?,
+        metadata["name"].as_str()?
+    ))
+}
+
+fn to_service(object: &Value) -> Option<(String, Service)> {
+    let metadata = &object["metadata"];
+    let spec = &object["spec"];
+    let mut service = Service {
+        name: metadata["name"].as_str()?.to_string(),
+        namespace: metadata["namespace"].as_str()?.to_string(),
+        v4: vec![],
+        v6: vec![],
+    };
+
+    // `clusterIPs` lists every family of a dual-stack service; older servers only set `clusterIP`
+    let ips = match spec["clusterIPs"].as_array() {
+        Some(ips) => ips.iter().filter_map(Value::as_str).collect(),
+        None => spec["clusterIP"].as_str().into_iter().collect::<Vec<_>>(),
+    };
+    for ip in ips {
+        // Headless services have a cluster IP of "None"
+        match ip.parse() {
+            Ok(IpAddr::V4(addr)) => service.v4.push(addr),
+            Ok(IpAddr::V6(addr)) => service.v6.push(addr),
+            Err(_) => {}
+        }
+    }
+    if service.v4.is_empty() && service.v6.is_empty() {
+        return None;
+    }
+
+    Some((key(object)?, service))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn service(name: &str, namespace: &str, v4: &[&str], v6: &[&str]) -> Service {
+        Service {
+            name: name.to_string(),
+            namespace: namespace.to_string(),
+            v4: v4.iter().map(|addr| addr.parse().unwrap()).collect(),
+            v6: v6.iter().map(|addr| addr.parse().unwrap()).collect(),
+        }
+    }
+
+    fn object(name: &str, namespace: &str, spec: Value) -> Value {
+        json!({
+            "metadata": {"name": name, "namespace": namespace, "resourceVersion": "1"},
+            "spec": spec,
+        })
+    }
+
+    fn client(url: String, token_file: PathBuf) -> Client {
+        Client {
+            agent: Agent::new_with_defaults(),
+            url,
+            token_file,
+            watch_interval: Duration::from_secs(60),
+        }
+    }
+
+    /// A backend whose informer has already listed `services`, without starting its watcher.
+    fn backend(namespace: Option<&str>, services: Vec<Service>) -> KubernetesBackend {
+        let shared = Arc::new(Shared::default());
+        {
+            let mut cache = shared.cache.lock().unwrap();
+            cache.services = Arc::new(
+                services
+                    .into_iter()
+                    .map(|s| (format!("{}/{}", s.namespace, s.name), s))
+                    .collect(),
+            );
+            cache.synced = true;
+        }
+        let informer = Informer {
+            client: Arc::new(client("http://127.0.0.1:1".to_string(), PathBuf::new())),
+            sync_timeout: Duration::from_secs(0),
+            state: RwLock::new((std::process::id(), shared)),
+        };
+        KubernetesBackend {
+            services: Services::Api(informer),
+            namespace: namespace.map(str::to_string),
+            cluster_domain: "cluster.local".to_string(),
+        }
+    }
+
+    #[test]
+    fn services_from_objects() {
+        let dual = object(
+            "web",
+            "prod",
+            json!({"clusterIP": "10.0.0.1", "clusterIPs": ["10.0.0.1", "fd00::1"]}),
+        );
+        assert_eq!(
+            to_service(&dual),
+            Some((
+                "prod/web".to_string(),
+                service("web", "prod", &["10.0.0.1"], &["fd00::1"])
+            ))
+        );
+        let legacy = object("web", "prod", json!({"clusterIP": "10.0.0.1"}));
+        assert_eq!(
+            to_service(&legacy).unwrap().1,
+            service("web", "prod", &["10.0.0.1"], &[])
+        );
+
+        let headless = object(
+            "web",
+            "prod",
+            json!({"clusterIP": "None", "clusterIPs": ["None"]}),
+        );
+        assert_eq!(to_service(&headless), None);
+        let external = object("web", "prod", json!({"type": "ExternalName"}));
+        assert_eq!(to_service(&external), None);
+        let unnamespaced = json!({"metadata": {"name": "web"}, "spec": {"clusterIP": "10.0.0.1"}});
+        assert_eq!(to_service(&unnamespaced), None);
+        assert_eq!(key(&headless).as_deref(), Some("prod/web"));
+    }
+
+    #[test]
+    fn names() {
+        let all = backend(None, vec![]);
+        for name in [
+            "web.prod",
+            "web.prod.",
+            "Web.Prod.svc",
+            "web.prod.svc.cluster.local",
+            "web.prod.svc.cluster.local.",
+        ] {
+            assert_eq!(
+                all.parse_name(name),
+                Some(("web".to_string(), "prod".to_string())),
+                "{}",
+                name
+            );
+        }
+        for name in ["web", "web.prod.example.com", "web.prod.svc.other.domain"] {
+            assert_eq!(all.parse_name(name), None, "{}", name);
+        }
+
+        let prod = backend(Some("prod"), vec![]);
+        assert_eq!(
+            prod.parse_name("web"),
+            Some(("web".to_string(), "prod".to_string()))
+        );
+        assert_eq!(prod.parse_name("web.staging"), None);
+    }
+
+    #[test]
+    fn lookups() {
+        let k8s = backend(
+            None,
+            vec![
+                service("web", "prod", &["10.0.0.1"], &["fd00::1"]),
+                service("db", "prod", &[], &["fd00::2"]),
+            ],
+        );
+
+        let web = k8s
+            .get_host_by_name("web.prod", AddressFamily::Unspecified)
+            .unwrap();
+        assert_eq!(
+            web,
+            Host {
+                name: "web.prod.svc.cluster.local".to_string(),
+                aliases: vec!["web.prod.svc".to_string(), "web.prod".to_string()],
+                addresses: Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+                canonical_name: None,
+            }
+        );
+        assert_eq!(
+            k8s.get_host_by_name("web.prod", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::1".parse().unwrap()])
+        );
+        // Only has IPv6
+        assert_eq!(
+            k8s.get_host_by_name("db.prod", AddressFamily::Unspecified)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::2".parse().unwrap()])
+        );
+        assert_eq!(k8s.get_host_by_name("db.prod", AddressFamily::IPv4), None);
+        assert_eq!(
+            k8s.get_host_by_name("web.staging", AddressFamily::IPv4),
+            None
+        );
+        assert_eq!(
+            k8s.get_host_by_name("web.prod", AddressFamily::Other(17)),
+            None
+        );
+
+        assert_eq!(k8s.get_host_by_addr("10.0.0.1".parse().unwrap()), Some(web));
+        assert_eq!(
+            k8s.get_host_by_addr("fd00::2".parse().unwrap())
+                .unwrap()
+                .name,
+            "db.prod.svc.cluster.local"
+        );
+        assert_eq!(k8s.get_host_by_addr("10.0.0.2".parse().unwrap()), None);
+        assert_eq!(k8s.get_all_hosts().len(), 3);
+    }
+
+    /// Serves each request with the next of `bodies`, recording its request line and token.
+    fn fake_api_server(bodies: Vec<String>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}/api/v1/services", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut head = Vec::new();
+                let mut byte = [0];
+                while !head.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8(head).unwrap();
+                assert!(head
+                    .to_lowercase()
+                    .contains("\r\nauthorization: bearer secret\r\n"));
+                seen.lock()
+                    .unwrap()
+                    .push(head.lines().next().unwrap().to_string());
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn lists_and_watches() {
+        let web = object("web", "prod", json!({"clusterIP": "10.0.0.1"}));
+        let db = object("db", "prod", json!({"clusterIP": "10.0.0.2"}));
+        let cache = object("cache", "prod", json!({"clusterIP": "10.0.0.3"}));
+        let headless = object("web", "prod", json!({"clusterIP": "None"}));
+        let events = [
+            json!({"type": "ADDED", "object": cache}),
+            json!({"type": "DELETED", "object": db}),
+            json!({"type": "MODIFIED", "object": headless}),
+            json!({"type": "BOOKMARK", "object": {"metadata": {"resourceVersion": "9"}}}),
+        ];
+        let (url, requests) = fake_api_server(vec![
+            json!({"items": [web, headless], "metadata": {"continue": "page2"}}).to_string(),
+            json!({"items": [db], "metadata": {"resourceVersion": "5"}}).to_string(),
+            events.iter().map(|event| format!("{}\n", event)).collect(),
+            format!("{}\n", json!({"type": "ERROR", "object": {"code": 410}})),
+        ]);
+        let token_file = env::temp_dir().join(format!("libnss-kubernetes-{}", std::process::id()));
+        fs::write(&token_file, "secret\n").unwrap();
+        let client = client(url, token_file);
+
+        let (services, mut version) = client.list().unwrap();
+        assert_eq!(version, "5");
+        let mut keys: Vec<_> = services.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["prod/db", "prod/web"]);
+
+        let shared = Arc::new(Shared::default());
+        shared.cache.lock().unwrap().services = Arc::new(services);
+        let weak = Arc::downgrade(&shared);
+        assert!(matches!(
+            client.watch(&mut version, &weak),
+            Ok(Watch::Ended)
+        ));
+        assert_eq!(version, "9");
+        let mut keys: Vec<_> = shared
+            .cache
+            .lock()
+            .unwrap()
+            .services
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        // The web service lost its cluster IP
+        assert_eq!(keys, ["prod/cache"]);
+        assert!(matches!(
+            client.watch(&mut version, &weak),
+            Ok(Watch::Expired)
+        ));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].starts_with("GET /api/v1/services?limit=500 "));
+        assert!(requests[1].contains("continue=page2"));
+        assert!(requests[2].contains("watch=1") && requests[2].contains("resourceVersion=5"));
+        assert!(requests[3].contains("resourceVersion=9"));
+        fs::remove_file(&client.token_file).unwrap();
+    }
+}
@@ -0,0 +1,485 @@
+//! Resolves libvirt guests by domain name to the addresses their networks' DHCP servers leased
+//! them, so `ssh myvm` works on a virtualization host without maintaining host entries.
+//!
+//! Like libvirt's own `libvirt_guest` module, this reads the files libvirt keeps for each network's
+//! dnsmasq in `lease_dir`, rather than connecting to libvirtd: `<network>.status` holds the
+//! leases and `<network>.macs` which domain has which MAC address. A domain with interfaces on
+//! several networks resolves to the addresses leased on all of them. Expired leases are ignored,
+//! as are guests with static addresses, which never ask for a lease.
+//!
+//! With `dhcp_hostnames`, guests are also answered by the hostname they sent with their DHCP
+//! request, like libvirt's `libvirt` module. Guests choose those themselves, so one can claim
+//! another's name; domain names take precedence.
+//!
+//! The files are read again at most every `check_interval`.
+//!
+//! ```
+//! use libnss::backends::libvirt::{LibvirtBackend, LibvirtConfig};
+//! use libnss::host::AddressFamily;
+//! # let dir = std::env::temp_dir().join(format!("libnss-libvirt-doc-{}", std::process::id()));
+//! # std::fs::create_dir_all(&dir).unwrap();
+//! # std::fs::write(dir.join("default.macs"), r#"[{"domain": "web1", "macs": ["52:54:00:aa:bb:01"]}]"#).unwrap();
+//! # std::fs::write(dir.join("default.status"), r#"[{"ip-address": "192.168.122.45", "mac-address": "52:54:00:aa:bb:01", "hostname": "localhost", "expiry-time": 0}]"#).unwrap();
+//!
+//! let backend = LibvirtBackend::new(LibvirtConfig {
+//!     lease_dir: dir.clone(),
+//!     ..LibvirtConfig::default()
+//! });
+//!
+//! let guest = backend.get_host_by_name("web1", AddressFamily::Unspecified).unwrap();
+//! assert_eq!(guest.addresses, "192.168.122.45".parse::<std::net::Ipv4Addr>().unwrap().into());
+//! assert!(backend.get_host_by_name("localhost", AddressFamily::Unspecified).is_none());
+//! # std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::host::{AddressFamily, Addresses, Host};
+
+pub struct LibvirtConfig {
+    /// Where libvirt keeps its networks' `.status` and `.macs` files; for session networks this is
+    /// `~/.cache/libvirt/dnsmasq`
+    pub lease_dir: PathBuf,
+    /// Also answer the hostnames guests sent with their DHCP requests
+    pub dhcp_hostnames: bool,
+    /// Also answer `<name>.<domain>`, and use that as the canonical name
+    pub domain: Option<String>,
+    pub check_interval: Duration,
+}
+
+impl Default for LibvirtConfig {
+    fn default() -> Self {
+        LibvirtConfig {
+            lease_dir: PathBuf::from("/var/lib/libvirt/dnsmasq"),
+            dhcp_hostnames: false,
+            domain: None,
+            check_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Guest {
+    name: String,
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<Ipv6Addr>,
+}
+
+struct Cache {
+    guests: Arc<Vec<Guest>>,
+    next_check: Instant,
+}
+
+pub struct LibvirtBackend {
+    lease_dir: PathBuf,
+    dhcp_hostnames: bool,
+    domain: Option<String>,
+    check_interval: Duration,
+    cache: RwLock<Cache>,
+}
+
+impl LibvirtBackend {
+    pub fn new(config: LibvirtConfig) -> Self {
+        LibvirtBackend {
+            lease_dir: config.lease_dir,
+            dhcp_hostnames: config.dhcp_hostnames,
+            domain: config
+                .domain
+                .map(|domain| domain.trim_matches('.').to_lowercase()),
+            check_interval: config.check_interval,
+            cache: RwLock::new(Cache {
+                guests: Arc::default(),
+                next_check: Instant::now(),
+            }),
+        }
+    }
+
+    /// One entry per guest and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let guests = self.guests();
+        let mut hosts = Vec::new();
+        for guest in guests.iter() {
+            hosts.extend(self.to_host(guest, AddressFamily::IPv4));
+            hosts.extend(self.to_host(guest, AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches names and `domain` names, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let name = match &self.domain {
+            Some(domain) => name
+                .strip_suffix(domain.as_str())
+                .and_then(|name| name.strip_suffix('.'))
+                .map(str::to_string)
+                .unwrap_or(name),
+            None => name,
+        };
+
+        let guests = self.guests();
+        let guest = guests.iter().find(|guest| guest.name == name)?;
+        match family {
+            AddressFamily::Unspecified => self
+                .to_host(guest, AddressFamily::IPv4)
+                .or_else(|| self.to_host(guest, AddressFamily::IPv6)),
+            family => self.to_host(guest, family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let guests = self.guests();
+        match addr {
+            IpAddr::V4(addr) => {
+                let guest = guests.iter().find(|g| g.v4.contains(&addr))?;
+                self.to_host(guest, AddressFamily::IPv4)
+            }
+            IpAddr::V6(addr) => {
+                let guest = guests.iter().find(|g| g.v6.contains(&addr))?;
+                self.to_host(guest, AddressFamily::IPv6)
+            }
+        }
+    }
+
+    fn to_host(&self, guest: &Guest, family: AddressFamily) -> Option<Host> {
+        let addresses = match family {
+            AddressFamily::IPv4 if !guest.v4.is_empty() => Addresses::V4(guest.v4.clone()),
+            AddressFamily::IPv6 if !guest.v6.is_empty() => Addresses::V6(guest.v6.clone()),
+            _ => return None,
+        };
+
+        let (name, aliases) = match &self.domain {
+            Some(domain) => (
+                format!("{}.{}", guest.name, domain),
+                vec![guest.name.clone()],
+            ),
+            None => (guest.name.clone(), vec![]),
+        };
+        Some(Host {
+            name,
+            aliases,
+            addresses,
+            canonical_name: None,
+        })
+    }
+
+    /// The guests, reading the lease files again first if `check_interval` has passed.
+    fn guests(&self) -> Arc<Vec<Guest>> {
+        {
+            let cache = self.cache.read().unwrap();
+            if Instant::now() < cache.next_check {
+                return cache.guests.clone();
+            }
+        }
+
+        let guests = Arc::new(read_guests(&self.lease_dir, self.dhcp_hostnames));
+        let mut cache = self.cache.write().unwrap();
+        cache.guests = guests.clone();
+        cache.next_check = Instant::now() + self.check_interval;
+        guests
+    }
+}
+
+/// Every guest with an unexpired lease on any network, by domain name and then, if wanted, by
+/// DHCP hostname.
+fn read_guests(dir: &Path, dhcp_hostnames: bool) -> Vec<Guest> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "status"))
+        .collect();
+    files.sort();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    let mut by_domain: Vec<Guest> = Vec::new();
+    let mut by_hostname: Vec<Guest> = Vec::new();
+    for status in files {
+        let domains = domains_by_mac(&status.with_extension("macs"));
+        for lease in read_json(&status).as_array().into_iter().flatten() {
+            let expiry = lease["expiry-time"].as_u64().unwrap_or(0);
+            if expiry != 0 && expiry <= now {
+                continue;
+            }
+            let addr: IpAddr = match lease["ip-address"].as_str().and_then(|a| a.parse().ok()) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let mac = lease["mac-address"].as_str().unwrap_or("").to_lowercase();
+
+            if let Some(domain) = domains.get(&mac) {
+                add(&mut by_domain, domain, addr);
+            }
+            match lease["hostname"].as_str() {
+                Some(hostname) if dhcp_hostnames && !hostname.is_empty() => {
+                    add(&mut by_hostname, &hostname.to_lowercase(), addr)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    by_hostname.retain(|guest| !by_domain.iter().any(|domain| domain.name == guest.name));
+    by_domain.extend(by_hostname);
+    by_domain
+}
+
+/// The domain each MAC address in a network's `.macs` file belongs to, with both lowercased.
+fn domains_by_mac(path: &Path) -> HashMap<String, String> {
+    let mut domains = HashMap::new();
+    for domain in read_json(path).as_array().into_iter().flatten() {
+        let name = match domain["domain"].as_str() {
+            Some(name) if !name.is_empty() => name.to_lowercase(),
+            _ => continue,
+        };
+        for mac in domain["macs"].as_array().into_iter().flatten() {
+            if let Some(mac) = mac.as_str() {
+                domains.insert(mac.to_lowercase(), name.clone());
+            }
+        }
+    }
+    domains
+}
+
+/// The file's JSON, or null if it can't be read. dnsmasq leaves `.status` files empty until a
+/// network has leased anything.
+fn read_json(path: &Path) -> Value {
+    fs::read(path)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or(Value::Null)
+}
+
+fn add(guests: &mut Vec<Guest>, name: &str, addr: IpAddr) {
+    let index = match guests.iter().position(|guest| guest.name == name) {
+        Some(index) => index,
+        None => {
+            guests.push(Guest {
+                name: name.to_string(),
+                ..Guest::default()
+            });
+            guests.len() - 1
+        }
+    };
+    let guest = &mut guests[index];
+    match addr {
+        IpAddr::V4(addr) if !guest.v4.contains(&addr) => guest.v4.push(addr),
+        IpAddr::V6(addr) if !guest.v6.contains(&addr) => guest.v6.push(addr),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lease directory holding a `default` network with two guests and an `isolated` one that
+    /// web1 is also on.
+    fn lease_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libnss-libvirt-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let future = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+
+        fs::write(
+            dir.join("default.macs"),
+            r#"[
+                {"domain": "Web1", "macs": ["52:54:00:AA:BB:01"]},
+                {"domain": "db1", "macs": ["52:54:00:aa:bb:02", "52:54:00:aa:bb:03"]},
+                {"domain": "", "macs": ["52:54:00:aa:bb:09"]}
+            ]"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("default.status"),
+            format!(
+                r#"[
+                    {{"ip-address": "192.168.122.45", "mac-address": "52:54:00:aa:bb:01",
+                      "hostname": "web", "expiry-time": {future}}},
+                    {{"ip-address": "192.168.122.46", "mac-address": "52:54:00:aa:bb:02",
+                      "hostname": "web1", "expiry-time": 0}},
+                    {{"ip-address": "fd00::46", "mac-address": "52:54:00:aa:bb:03",
+                      "expiry-time": {future}}},
+                    {{"ip-address": "192.168.122.47", "mac-address": "52:54:00:aa:bb:04",
+                      "hostname": "Stray", "expiry-time": 1}},
+                    {{"ip-address": "192.168.122.48", "mac-address": "52:54:00:aa:bb:05",
+                      "hostname": "laptop"}},
+                    {{"ip-address": "not an address", "mac-address": "52:54:00:aa:bb:01"}}
+                ]"#,
+                future = future
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("isolated.macs"),
+            r#"[{"domain": "web1", "macs": ["52:54:00:aa:cc:01"]}]"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("isolated.status"),
+            r#"[{"ip-address": "10.0.0.45", "mac-address": "52:54:00:aa:cc:01"}]"#,
+        )
+        .unwrap();
+        // A network that hasn't leased anything yet
+        fs::write(dir.join("empty.status"), "").unwrap();
+        dir
+    }
+
+    fn backend(dir: &Path, dhcp_hostnames: bool, domain: Option<&str>) -> LibvirtBackend {
+        LibvirtBackend::new(LibvirtConfig {
+            lease_dir: dir.to_path_buf(),
+            dhcp_hostnames,
+            domain: domain.map(str::to_string),
+            check_interval: Duration::from_secs(3600),
+        })
+    }
+
+    fn v4(addrs: &[&str]) -> Addresses {
+        Addresses::V4(addrs.iter().map(|addr| addr.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn guests_by_domain() {
+        let dir = lease_dir("domain");
+        let libvirt = backend(&dir, false, None);
+
+        assert_eq!(
+            libvirt.get_host_by_name("WEB1.", AddressFamily::Unspecified),
+            Some(Host {
+                name: "web1".to_string(),
+                aliases: vec![],
+                addresses: v4(&["192.168.122.45", "10.0.0.45"]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            libvirt
+                .get_host_by_name("db1", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::46".parse().unwrap()])
+        );
+        assert_eq!(libvirt.get_host_by_name("web1", AddressFamily::IPv6), None);
+        // Not a domain's, or expired
+        assert_eq!(
+            libvirt.get_host_by_name("laptop", AddressFamily::IPv4),
+            None
+        );
+        assert_eq!(
+            libvirt.get_host_by_addr("192.168.122.47".parse().unwrap()),
+            None
+        );
+
+        assert_eq!(
+            libvirt
+                .get_host_by_addr("10.0.0.45".parse().unwrap())
+                .unwrap()
+                .name,
+            "web1"
+        );
+        assert_eq!(
+            libvirt
+                .get_host_by_addr("fd00::46".parse().unwrap())
+                .unwrap()
+                .name,
+            "db1"
+        );
+        // web1 over IPv4, db1 over both
+        assert_eq!(libvirt.get_all_hosts().len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dhcp_hostnames() {
+        let dir = lease_dir("hostnames");
+        let libvirt = backend(&dir, true, None);
+
+        assert_eq!(
+            libvirt
+                .get_host_by_name("laptop", AddressFamily::IPv4)
+                .unwrap()
+                .addresses,
+            v4(&["192.168.122.48"])
+        );
+        assert_eq!(
+            libvirt
+                .get_host_by_name("web", AddressFamily::IPv4)
+                .unwrap()
+                .addresses,
+            v4(&["192.168.122.45"])
+        );
+        // db1 claims web1's name, but the domain wins
+        assert_eq!(
+            libvirt
+                .get_host_by_name("web1", AddressFamily::IPv4)
+                .unwrap()
+                .addresses,
+            v4(&["192.168.122.45", "10.0.0.45"])
+        );
+        assert_eq!(libvirt.get_host_by_name("stray", AddressFamily::IPv4), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn domain_names() {
+        let dir = lease_dir("suffix");
+        let libvirt = backend(&dir, false, Some(".VMs.Example."));
+
+        let web1 = libvirt
+            .get_host_by_name("web1.vms.example", AddressFamily::IPv4)
+            .unwrap();
+        assert_eq!(
+            (web1.name.as_str(), web1.aliases.as_slice()),
+            ("web1.vms.example", &["web1".to_string()][..])
+        );
+        assert_eq!(
+            libvirt.get_host_by_name("web1", AddressFamily::IPv4),
+            Some(web1)
+        );
+        assert_eq!(
+            libvirt.get_host_by_name("web1.other.example", AddressFamily::IPv4),
+            None
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rereads_after_check_interval() {
+        let dir = lease_dir("reread");
+        let cached = backend(&dir, false, None);
+        let fresh = LibvirtBackend::new(LibvirtConfig {
+            lease_dir: dir.clone(),
+            check_interval: Duration::from_secs(0),
+            ..LibvirtConfig::default()
+        });
+        assert!(cached
+            .get_host_by_name("web1", AddressFamily::IPv4)
+            .is_some());
+        assert!(fresh
+            .get_host_by_name("web1", AddressFamily::IPv4)
+            .is_some());
+
+        fs::write(dir.join("isolated.status"), "[]").unwrap();
+        fs::remove_file(dir.join("default.status")).unwrap();
+        assert!(cached
+            .get_host_by_name("web1", AddressFamily::IPv4)
+            .is_some());
+        assert!(fresh
+            .get_host_by_name("web1", AddressFamily::IPv4)
+            .is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,614 @@
+//! Resolves `.local` names with multicast DNS, as a dependency free alternative to nss-mdns and
+//! avahi on minimal systems.
+//!
+//! Each lookup sends a one-shot query (RFC 6762 section 5.1) from an ephemeral port to
+//! 224.0.0.251 and takes the first response that answers it; responders reply to such queries
+//! directly. Queries go out over IPv4 on the default multicast interface, but ask for IPv6
+//! addresses too when they're wanted.
+//!
+//! Names outside `domains` are refused immediately, so the module doesn't delay every other lookup
+//! by `timeout`. Reverse lookups are only made for link-local addresses by default, like nss-mdns'
+//! `mdns_minimal`. Answers, including the lack of one, are cached for the shorter of their TTL and
+//! `cache_ttl`. There is no enumeration: `get_all_hosts` is always empty.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::host::{AddressFamily, Addresses, Host};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+pub struct MdnsConfig {
+    /// How long to wait for a response
+    pub timeout: Duration,
+    /// Upper bound on how long an answer is cached, whatever its TTL
+    pub cache_ttl: Duration,
+    /// Domains answered, without leading or trailing dots
+    pub domains: Vec<String>,
+    /// Whether reverse lookups are made for every address, rather than only link-local ones
+    pub reverse_all: bool,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            timeout: Duration::from_secs(1),
+            cache_ttl: Duration::from_secs(10),
+            domains: vec!["local".to_string()],
+            reverse_all: false,
+        }
+    }
+}
+
+/// A resource record from a response, reduced to what lookups use.
+#[derive(Debug, PartialEq)]
+enum Record {
+    V4(String, Ipv4Addr, u32),
+    V6(String, Ipv6Addr, u32),
+    Ptr(String, String, u32),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Query {
+    Name(String, u8),
+    Addr(IpAddr),
+}
+
+pub struct MdnsBackend {
+    config: MdnsConfig,
+    cache: RwLock<HashMap<Query, (Instant, Option<Host>)>>,
+}
+
+impl MdnsBackend {
+    pub fn new(config: MdnsConfig) -> Self {
+        MdnsBackend {
+            config,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        vec![]
+    }
+
+    /// An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if !self.in_domains(&name) {
+            return None;
+        }
+
+        let (tag, types): (u8, &[u16]) = match family {
+            AddressFamily::IPv4 => (4, &[TYPE_A]),
+            AddressFamily::IPv6 => (6, &[TYPE_AAAA]),
+            AddressFamily::Unspecified => (0, &[TYPE_A, TYPE_AAAA]),
+            AddressFamily::Other(_) => return None,
+        };
+        let key = Query::Name(name.clone(), tag);
+        self.cached(key, || {
+            let records = self.query(&name, types).ok()?;
+            to_host(&name, &records, family)
+        })
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        if !self.config.reverse_all && !is_link_local(addr) {
+            return None;
+        }
+
+        self.cached(Query::Addr(addr), || {
+            let reverse = reverse_name(addr);
+            let records = self.query(&reverse, &[TYPE_PTR]).ok()?;
+            let (name, ttl) = records.iter().find_map(|record| match record {
+                Record::Ptr(owner, target, ttl) if owner.eq_ignore_ascii_case(&reverse) => {
+                    Some((target.trim_end_matches('.').to_lowercase(), *ttl))
+                }
+                _ => None,
+            })?;
+
+            let addresses = Addresses::from(addr);
+            Some((
+                Host {
+                    name,
+                    aliases: vec![],
+                    addresses,
+                    canonical_name: None,
+                },
+                ttl,
+            ))
+        })
+    }
+
+    fn in_domains(&self, name: &str) -> bool {
+        self.config.domains.iter().any(|domain| {
+            let domain = domain.trim_matches('.').to_lowercase();
+            name.strip_suffix(&domain)
+                .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.'))
+        })
+    }
+
+    /// Answers from the cache, or from `lookup` (which also gives the answer's TTL), caching what
+    /// it returns.
+    fn cached<F>(&self, key: Query, lookup: F) -> Option<Host>
+    where
+        F: FnOnce() -> Option<(Host, u32)>,
+    {
+        if let Some((expires, host)) = self.cache.read().unwrap().get(&key) {
+            if Instant::now() < *expires {
+                return host.clone();
+            }
+        }
+
+        let (host, ttl) = match lookup() {
+            Some((host, ttl)) => (Some(host), Duration::from_secs(ttl.into())),
+            None => (None, self.config.cache_ttl),
+        };
+        let expires = Instant::now() + ttl.min(self.config.cache_ttl);
+
+        let mut cache = self.cache.write().unwrap();
+        cache.retain(|_, (expires, _)| Instant::now() < *expires);
+        cache.insert(key, (expires, host.clone()));
+        host
+    }
+
+    /// Sends a one-shot query for `name` and returns the records of the first response that
+    /// answers it.
+    fn query(&self, name: &str, types: &[u16]) -> io::Result<Vec<Record>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.send_to(
+            &encode_query(name, types)?,
+            SocketAddr::from((MDNS_GROUP, MDNS_PORT)),
+        )?;
+
+        let deadline = Instant::now() + self.config.timeout;
+        let mut buffer = [0; 9000];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            socket.set_read_timeout(Some(remaining))?;
+
+            let (len, _) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Err(io::ErrorKind::TimedOut.into())
+                }
+                Err(e) => return Err(e),
+            };
+            let records = match parse_response(&buffer[..len]) {
+                Some(records) => records,
+                None => continue,
+            };
+            let answers = records.iter().any(|record| match record {
+                Record::V4(owner, _, _) => types.contains(&TYPE_A) && owner == name,
+                Record::V6(owner, _, _) => types.contains(&TYPE_AAAA) && owner == name,
+                Record::Ptr(owner, _, _) => types.contains(&TYPE_PTR) && owner == name,
+            });
+            if answers {
+                return Ok(records);
+            }
+        }
+    }
+}
+
+/// The entry for `name` from `records`, and the shortest TTL among the records used. An
+/// unspecified family prefers IPv4.
+fn to_host(name: &str, records: &[Record], family: AddressFamily) -> Option<(Host, u32)> {
+    let mut ttl = u32::MAX;
+    let v4: Vec<Ipv4Addr> = records
+        .iter()
+        .filter_map(|record| match record {
+            Record::V4(owner, addr, record_ttl) if owner == name => {
+                ttl = ttl.min(*record_ttl);
+                Some(*addr)
+            }
+            _ => None,
+        })
+        .collect();
+    let mut v6_ttl = u32::MAX;
+    let v6: Vec<Ipv6Addr> = records
+        .iter()
+        .filter_map(|record| match record {
+            Record::V6(owner, addr, record_ttl) if owner == name => {
+                v6_ttl = v6_ttl.min(*record_ttl);
+                Some(*addr)
+            }
+            _ => None,
+        })
+        .collect();
+
+    let (addresses, ttl) = match family {
+        AddressFamily::IPv4 | AddressFamily::Unspecified if !v4.is_empty() => {
+            (Addresses::V4(v4), ttl)
+        }
+        AddressFamily::IPv6 | AddressFamily::Unspecified if !v6.is_empty() => {
+            (Addresses::V6(v6), v6_ttl)
+        }
+        _ => return None,
+    };
+    Some((
+        Host {
+            name: name.to_string(),
+            aliases: vec![],
+            addresses,
+            canonical_name: None,
+        },
+        ttl,
+    ))
+}
+
+fn is_link_local(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_link_local(),
+        IpAddr::V6(addr) => addr.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// The `in-addr.arpa` or `ip6.arpa` name for `addr`.
+fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for byte in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+fn encode_query(name: &str, types: &[u16]) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(512);
+    // RFC 6762 asks for a zero ID, and responses are matched by name rather than ID anyway
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&[0, 0]); // flags: a standard query
+    packet.extend_from_slice(&(types.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    for qtype in types {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{}` is not a valid DNS name", name),
+                ));
+            }
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    Ok(packet)
+}
+
+/// The A, AAAA and PTR records in every section of a response, with lowercased names. `None` if
+/// the packet isn't a well formed response.
+fn parse_response(packet: &[u8]) -> Option<Vec<Record>> {
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    let questions = read_u16(packet, 4)?;
+    let records = read_u16(packet, 6)? as usize
+        + read_u16(packet, 8)? as usize
+        + read_u16(packet, 10)? as usize;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+
+    let mut parsed = Vec::new();
+    for _ in 0..records {
+        let (owner, next) = read_name(packet, offset)?;
+        let rtype = read_u16(packet, next)?;
+        // The top bit of the class is mDNS' cache-flush flag
+        let class = read_u16(packet, next + 2)? & 0x7fff;
+        let ttl = u32::from_be_bytes(packet.get(next + 4..next + 8)?.try_into().ok()?);
+        let length = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        let rdata = packet.get(data..data + length)?;
+        offset = data + length;
+
+        if class != CLASS_IN {
+            continue;
+        }
+        match rtype {
+            TYPE_A => {
+                let octets: [u8; 4] = rdata.try_into().ok()?;
+                parsed.push(Record::V4(owner, Ipv4Addr::from(octets), ttl));
+            }
+            TYPE_AAAA => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                parsed.push(Record::V6(owner, Ipv6Addr::from(octets), ttl));
+            }
+            TYPE_PTR => parsed.push(Record::Ptr(owner, read_name(packet, data)?.0, ttl)),
+            _ => {}
+        }
+    }
+    Some(parsed)
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a possibly compressed name, returning it lowercased with the offset just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Each pointer has to go backwards, so this can't loop
+    let mut limit = offset;
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = (read_u16(packet, offset)? & 0x3fff) as usize;
+                if pointer >= limit {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                limit = pointer;
+                offset = pointer;
+            }
+            len if len < 64 => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                offset += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.') {
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    /// A response with no questions and `records` as its answers.
+    fn response(records: &[(&str, u16, u16, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0];
+        packet.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        for (owner, rtype, class, ttl, rdata) in records {
+            packet.extend_from_slice(&name(owner));
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&class.to_be_bytes());
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            packet.extend_from_slice(rdata);
+        }
+        packet
+    }
+
+    #[test]
+    fn domains() {
+        let mdns = MdnsBackend::new(MdnsConfig {
+            domains: vec!["local".to_string(), ".Home.Arpa.".to_string()],
+            ..MdnsConfig::default()
+        });
+        for name in ["printer.local", "a.b.local", "nas.home.arpa"] {
+            assert!(mdns.in_domains(name), "{}", name);
+        }
+        for name in [
+            "local",
+            ".local",
+            "printerlocal",
+            "printer.local.com",
+            "home.arpa",
+        ] {
+            assert!(!mdns.in_domains(name), "{}", name);
+        }
+        // Refused without asking the network
+        assert_eq!(
+            mdns.get_host_by_name("example.com", AddressFamily::IPv4),
+            None
+        );
+        assert_eq!(mdns.get_host_by_addr("192.0.2.1".parse().unwrap()), None);
+        assert!(mdns.cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name("169.254.1.2".parse().unwrap()),
+            "2.1.254.169.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name("fe80::1:abcd".parse().unwrap()),
+            "d.c.b.a.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.e.f.ip6.arpa"
+        );
+
+        for (addr, link_local) in [
+            ("169.254.1.2", true),
+            ("192.168.1.2", false),
+            ("fe80::1", true),
+            ("febf::1", true),
+            ("fec0::1", false),
+            ("fd00::1", false),
+        ] {
+            assert_eq!(is_link_local(addr.parse().unwrap()), link_local, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let query = encode_query("printer.local", &[TYPE_A, TYPE_AAAA]).unwrap();
+        let mut expected = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            expected.extend_from_slice(&name("printer.local"));
+            expected.extend_from_slice(&qtype.to_be_bytes());
+            expected.extend_from_slice(&[0, 1]);
+        }
+        assert_eq!(query, expected);
+
+        for bad in ["", "printer..local", &format!("{}.local", "a".repeat(64))] {
+            assert_eq!(
+                encode_query(bad, &[TYPE_A]).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn responses() {
+        let packet = response(&[
+            // With the cache-flush bit set, as responders send unique records
+            ("Printer.local", TYPE_A, 0x8001, 120, vec![192, 168, 1, 20]),
+            ("printer.local", TYPE_AAAA, CLASS_IN, 60, {
+                "fe80::20".parse::<Ipv6Addr>().unwrap().octets().to_vec()
+            }),
+            (
+                "20.1.168.192.in-addr.arpa",
+                TYPE_PTR,
+                CLASS_IN,
+                30,
+                name("printer.local"),
+            ),
+            // Not IN, or not a type lookups use
+            ("printer.local", TYPE_A, 3, 120, vec![10, 0, 0, 1]),
+            ("printer.local", 16, CLASS_IN, 120, vec![0]),
+        ]);
+        assert_eq!(
+            parse_response(&packet),
+            Some(vec![
+                Record::V4(
+                    "printer.local".to_string(),
+                    Ipv4Addr::new(192, 168, 1, 20),
+                    120
+                ),
+                Record::V6("printer.local".to_string(), "fe80::20".parse().unwrap(), 60),
+                Record::Ptr(
+                    "20.1.168.192.in-addr.arpa".to_string(),
+                    "printer.local".to_string(),
+                    30
+                ),
+            ])
+        );
+
+        for len in 0..packet.len() {
+            assert_eq!(parse_response(&packet[..len]), None, "{}", len);
+        }
+        // Queries, including our own looped back, aren't responses
+        let query = encode_query("printer.local", &[TYPE_A]).unwrap();
+        assert_eq!(parse_response(&query), None);
+        let short_a = response(&[("printer.local", TYPE_A, CLASS_IN, 120, vec![192, 168])]);
+        assert_eq!(parse_response(&short_a), None);
+    }
+
+    #[test]
+    fn hosts() {
+        let v4 = Ipv4Addr::new(192, 168, 1, 20);
+        let v6: Ipv6Addr = "fe80::20".parse().unwrap();
+        let records = [
+            Record::V4("printer.local".to_string(), v4, 120),
+            Record::V4(
+                "printer.local".to_string(),
+                Ipv4Addr::new(192, 168, 1, 21),
+                90,
+            ),
+            Record::V6("printer.local".to_string(), v6, 60),
+            Record::V4(
+                "other.local".to_string(),
+                Ipv4Addr::new(192, 168, 1, 30),
+                10,
+            ),
+        ];
+
+        let (host, ttl) = to_host("printer.local", &records, AddressFamily::Unspecified).unwrap();
+        assert_eq!(
+            (host.name.as_str(), host.addresses, ttl),
+            (
+                "printer.local",
+                Addresses::V4(vec![v4, Ipv4Addr::new(192, 168, 1, 21)]),
+                90
+            )
+        );
+        let (host, ttl) = to_host("printer.local", &records, AddressFamily::IPv6).unwrap();
+        assert_eq!((host.addresses, ttl), (Addresses::V6(vec![v6]), 60));
+        let (host, _) =
+            to_host("printer.local", &records[2..3], AddressFamily::Unspecified).unwrap();
+        assert_eq!(host.addresses, Addresses::V6(vec![v6]));
+
+        assert!(to_host("printer.local", &records[2..], AddressFamily::IPv4).is_none());
+        assert!(to_host("printer.local", &records, AddressFamily::Other(17)).is_none());
+        assert!(to_host("scanner.local", &records, AddressFamily::Unspecified).is_none());
+    }
+
+    #[test]
+    fn caching() {
+        let mdns = MdnsBackend::new(MdnsConfig::default());
+        let lookups = Cell::new(0);
+        let host = Host {
+            name: "printer.local".to_string(),
+            aliases: vec![],
+            addresses: Addresses::V4(vec![Ipv4Addr::new(192, 168, 1, 20)]),
+            canonical_name: None,
+        };
+        let found = || {
+            lookups.set(lookups.get() + 1);
+            Some((host.clone(), 120))
+        };
+        let key = Query::Name("printer.local".to_string(), 4);
+        assert_eq!(mdns.cached(key.clone(), found), Some(host.clone()));
+        assert_eq!(mdns.cached(key.clone(), found), Some(host.clone()));
+        assert_eq!(lookups.get(), 1);
+
+        // The lack of an answer is remembered too
+        let missing = || {
+            lookups.set(lookups.get() + 1);
+            None
+        };
+        let key = Query::Name("scanner.local".to_string(), 4);
+        assert_eq!(mdns.cached(key.clone(), missing), None);
+        assert_eq!(mdns.cached(key, found), None);
+        assert_eq!(lookups.get(), 2);
+
+        // A zero TTL isn't cached at all
+        let expired = || {
+            lookups.set(lookups.get() + 1);
+            Some((host.clone(), 0))
+        };
+        let key = Query::Addr("169.254.1.20".parse().unwrap());
+        assert_eq!(mdns.cached(key.clone(), expired), Some(host.clone()));
+        assert_eq!(mdns.cached(key, expired), Some(host));
+        assert_eq!(lookups.get(), 4);
+    }
+}
@@ -0,0 +1,547 @@
+//! Resolves the short names of mesh VPN peers to their tunnel addresses, so peers resolve the same
+//! way on every machine without MagicDNS or entries in `/etc/hosts`.
+//!
+//! - With [`Source::Tailscale`] the peers, and this machine, are read from tailscaled's LocalAPI
+//!   socket, as `tailscale status` does. Each is named by the first label of its MagicDNS name and
+//!   also answered under the tailnet's MagicDNS suffix, unless `domain` is set.
+//! - With [`Source::WireGuard`] they're read from a `wg-quick` config. WireGuard doesn't name
+//!   peers, so each `[Peer]` section (or the `[Interface]`, for this machine) is named by a
+//!   `# Name = <name>` comment in it, as most config generators write, and resolves to the single
+//!   addresses (`/32` and `/128`) in its `AllowedIPs` (or `Address`). Sections without one are
+//!   skipped.
+//!
+//! The peers are cached for `cache_ttl`, and the last list keeps being served while the source
+//! can't be read, e.g. while tailscaled restarts.
+//!
+//! ```
+//! use libnss::backends::mesh::{MeshBackend, MeshConfig, Source};
+//! use libnss::host::AddressFamily;
+//! # let config = std::env::temp_dir().join(format!("libnss-mesh-doc-{}.conf", std::process::id()));
+//! # std::fs::write(&config, "[Interface]\n# Name = laptop\nAddress = 10.8.0.1/24\n\n[Peer]\n# Name = Build-Box\nPublicKey = x\nAllowedIPs = 10.8.0.2/32, fd00::2/128, 192.168.5.0/24\n").unwrap();
+//!
+//! let backend = MeshBackend::new(MeshConfig {
+//!     source: Source::WireGuard { config: config.clone() },
+//!     domain: Some("wg".to_string()),
+//!     ..MeshConfig::default()
+//! });
+//!
+//! let peer = backend.get_host_by_name("build-box", AddressFamily::IPv4).unwrap();
+//! assert_eq!(peer.name, "build-box.wg");
+//! assert_eq!(peer.addresses, "10.8.0.2".parse::<std::net::Ipv4Addr>().unwrap().into());
+//! assert!(backend.get_host_by_name("laptop.wg", AddressFamily::IPv6).is_none());
+//! assert_eq!(backend.get_all_hosts().len(), 3);
+//! # std::fs::remove_file(&config).unwrap();
+//! ```
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::host::{AddressFamily, Addresses, Host};
+
+pub enum Source {
+    Tailscale {
+        /// tailscaled's LocalAPI socket
+        socket: PathBuf,
+    },
+    WireGuard {
+        /// A `wg-quick` config, e.g. `/etc/wireguard/wg0.conf`
+        config: PathBuf,
+    },
+}
+
+pub struct MeshConfig {
+    pub source: Source,
+    /// Also answer `<name>.<domain>`, and use that as the canonical name. With
+    /// [`Source::Tailscale`], `None` uses the tailnet's MagicDNS suffix
+    pub domain: Option<String>,
+    pub cache_ttl: Duration,
+    /// How long to wait for tailscaled to answer
+    pub timeout: Duration,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        MeshConfig {
+            source: Source::Tailscale {
+                socket: PathBuf::from("/var/run/tailscale/tailscaled.sock"),
+            },
+            domain: None,
+            cache_ttl: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Peer {
+    name: String,
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<Ipv6Addr>,
+}
+
+#[derive(Default)]
+struct Peers {
+    peers: Vec<Peer>,
+    /// The domain peers are answered under
+    domain: Option<String>,
+}
+
+struct Cache {
+    peers: Arc<Peers>,
+    next_refresh: Instant,
+}
+
+pub struct MeshBackend {
+    source: Source,
+    domain: Option<String>,
+    cache_ttl: Duration,
+    timeout: Duration,
+    cache: RwLock<Cache>,
+}
+
+impl MeshBackend {
+    /// Creates the backend; peers are first read by the first lookup.
+    pub fn new(config: MeshConfig) -> Self {
+        MeshBackend {
+            source: config.source,
+            domain: config.domain.as_deref().map(normalize_domain),
+            cache_ttl: config.cache_ttl,
+            timeout: config.timeout,
+            cache: RwLock::new(Cache {
+                peers: Arc::default(),
+                next_refresh: Instant::now(),
+            }),
+        }
+    }
+
+    /// One entry per peer and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let peers = self.peers();
+        let mut hosts = Vec::new();
+        for peer in &peers.peers {
+            hosts.extend(to_host(&peers, peer, AddressFamily::IPv4));
+            hosts.extend(to_host(&peers, peer, AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches short and `domain` names, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let peers = self.peers();
+        let name = name.trim_end_matches('.').to_lowercase();
+        let name = match &peers.domain {
+            Some(domain) => name
+                .strip_suffix(domain.as_str())
+                .and_then(|name| name.strip_suffix('.'))
+                .map(str::to_string)
+                .unwrap_or(name),
+            None => name,
+        };
+
+        let peer = peers.peers.iter().find(|peer| peer.name == name)?;
+        match family {
+            AddressFamily::Unspecified => to_host(&peers, peer, AddressFamily::IPv4)
+                .or_else(|| to_host(&peers, peer, AddressFamily::IPv6)),
+            family => to_host(&peers, peer, family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let peers = self.peers();
+        match addr {
+            IpAddr::V4(addr) => {
+                let peer = peers.peers.iter().find(|p| p.v4.contains(&addr))?;
+                to_host(&peers, peer, AddressFamily::IPv4)
+            }
+            IpAddr::V6(addr) => {
+                let peer = peers.peers.iter().find(|p| p.v6.contains(&addr))?;
+                to_host(&peers, peer, AddressFamily::IPv6)
+            }
+        }
+    }
+
+    /// The cached peers, reading them again first if the cache has expired.
+    fn peers(&self) -> Arc<Peers> {
+        {
+            let cache = self.cache.read().unwrap();
+            if Instant::now() < cache.next_refresh {
+                return cache.peers.clone();
+            }
+        }
+
+        let read = match &self.source {
+            Source::Tailscale { socket } => self.read_tailscale(socket),
+            Source::WireGuard { config } => fs::read_to_string(config).map(|config| Peers {
+                peers: parse_wg_quick(&config),
+                domain: self.domain.clone(),
+            }),
+        };
+
+        let mut cache = self.cache.write().unwrap();
+        if let Ok(peers) = read {
+            cache.peers = Arc::new(peers);
+        }
+        cache.next_refresh = Instant::now() + self.cache_ttl;
+        cache.peers.clone()
+    }
+
+    fn read_tailscale(&self, socket: &Path) -> io::Result<Peers> {
+        let mut stream = UnixStream::connect(socket)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        // HTTP/1.0, so the body isn't chunked and ends with the connection
+        stream.write_all(
+            b"GET /localapi/v0/status?peers=true HTTP/1.0\r\nHost: local-tailscaled.sock\r\n\r\n",
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(|| invalid("malformed LocalAPI response"))?;
+        let status = response.split(|&b| b == b'\n').next().unwrap_or_default();
+        if status.split(|&b| b == b' ').nth(1) != Some(b"200") {
+            return Err(invalid("LocalAPI status request failed"));
+        }
+        let status: Value = serde_json::from_slice(&response[split + 4..])?;
+
+        let peers = status["Self"]
+            .as_object()
+            .into_iter()
+            .chain(
+                status["Peer"]
+                    .as_object()
+                    .into_iter()
+                    .flat_map(|peers| peers.values().filter_map(Value::as_object)),
+            )
+            .filter_map(tailscale_peer)
+            .collect();
+        let domain = self.domain.clone().or_else(|| {
+            status["MagicDNSSuffix"]
+                .as_str()
+                .map(normalize_domain)
+                .filter(|suffix| !suffix.is_empty())
+        });
+        Ok(Peers { peers, domain })
+    }
+}
+
+/// A peer from tailscaled's status, named by the first label of its MagicDNS name, or else its
+/// host name.
+fn tailscale_peer(peer: &serde_json::Map<String, Value>) -> Option<Peer> {
+    let name = peer
+        .get("DNSName")
+        .and_then(Value::as_str)
+        .and_then(|name| name.split('.').next())
+        .filter(|name| !name.is_empty())
+        .or_else(|| peer.get("HostName").and_then(Value::as_str))?
+        .to_lowercase();
+
+    let mut entry = Peer {
+        name,
+        ..Peer::default()
+    };
+    let addrs = peer.get("TailscaleIPs").and_then(Value::as_array)?;
+    for addr in addrs.iter().filter_map(Value::as_str) {
+        match addr.parse() {
+            Ok(IpAddr::V4(addr)) => entry.v4.push(addr),
+            Ok(IpAddr::V6(addr)) => entry.v6.push(addr),
+            Err(_) => {}
+        }
+    }
+    Some(entry)
+}
+
+/// The named sections of a `wg-quick` config.
+fn parse_wg_quick(config: &str) -> Vec<Peer> {
+    let mut peers = Vec::new();
+    let mut section: Option<Peer> = None;
+    let mut key = "";
+    for line in config.lines().map(str::trim) {
+        if line.starts_with('[') {
+            peers.extend(section.take().filter(|peer| !peer.name.is_empty()));
+            key = if line.eq_ignore_ascii_case("[Interface]") {
+                "address"
+            } else if line.eq_ignore_ascii_case("[Peer]") {
+                "allowedips"
+            } else {
+                ""
+            };
+            section = Some(Peer::default()).filter(|_| !key.is_empty());
+            continue;
+        }
+        let peer = match &mut section {
+            Some(peer) => peer,
+            None => continue,
+        };
+
+        let (comment, line) = match line.strip_prefix('#') {
+            Some(comment) => (true, comment),
+            None => (false, line),
+        };
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+        if comment && name == "name" {
+            peer.name = value.to_lowercase();
+        } else if !comment && name == key {
+            for addr in value.split(',').map(str::trim) {
+                let (addr, prefix) = addr.split_once('/').unwrap_or((addr, ""));
+                match addr.parse() {
+                    Ok(IpAddr::V4(addr)) if key == "address" || matches!(prefix, "" | "32") => {
+                        peer.v4.push(addr)
+                    }
+                    Ok(IpAddr::V6(addr)) if key == "address" || matches!(prefix, "" | "128") => {
+                        peer.v6.push(addr)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    peers.extend(section.filter(|peer| !peer.name.is_empty()));
+    peers
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_matches('.').to_lowercase()
+}
+
+fn to_host(peers: &Peers, peer: &Peer, family: AddressFamily) -> Option<Host> {
+    let addresses = match family {
+        AddressFamily::IPv4 if !peer.v4.is_empty() => Addresses::V4(peer.v4.clone()),
+        AddressFamily::IPv6 if !peer.v6.is_empty() => Addresses::V6(peer.v6.clone()),
+        _ => return None,
+    };
+
+    let (name, aliases) = match &peers.domain {
+        Some(domain) => (format!("{}.{}", peer.name, domain), vec![peer.name.clone()]),
+        None => (peer.name.clone(), vec![]),
+    };
+    Some(Host {
+        name,
+        aliases,
+        addresses,
+        canonical_name: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    fn addresses(peer: &Peer) -> (Vec<String>, Vec<String>) {
+        (
+            peer.v4.iter().map(ToString::to_string).collect(),
+            peer.v6.iter().map(ToString::to_string).collect(),
+        )
+    }
+
+    #[test]
+    fn wg_quick_configs() {
+        let peers = parse_wg_quick(
+            "[Interface]\n\
+             # Name = Laptop\n\
+             Address = 10.8.0.1/24, fd00::1/64\n\
+             PrivateKey = x\n\
+             \n\
+             [Peer]\n\
+             #Name=build-box\n\
+             PublicKey = y\n\
+             AllowedIPs = 10.8.0.2/32, fd00::2/128, 10.8.0.3, 192.168.5.0/24, ::/0, nonsense\n\
+             \n\
+             [Peer]\n\
+             # A peer without a name\n\
+             AllowedIPs = 10.8.0.4/32\n\
+             \n\
+             [peer]\n\
+             # name = router\n\
+             allowedips = 10.8.0.5/32\n\
+             Address = 10.8.0.99/32\n\
+             \n\
+             [Other]\n\
+             # Name = stray\n\
+             AllowedIPs = 10.8.0.6/32\n",
+        );
+
+        let named: Vec<_> = peers
+            .iter()
+            .map(|peer| (peer.name.as_str(), addresses(peer)))
+            .collect();
+        let strings = |addrs: &[&str]| addrs.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            named,
+            [
+                ("laptop", (strings(&["10.8.0.1"]), strings(&["fd00::1"]))),
+                (
+                    "build-box",
+                    (strings(&["10.8.0.2", "10.8.0.3"]), strings(&["fd00::2"]))
+                ),
+                ("router", (strings(&["10.8.0.5"]), strings(&[]))),
+            ]
+        );
+    }
+
+    #[test]
+    fn tailscale_peers() {
+        let peer = |value: Value| tailscale_peer(value.as_object().unwrap());
+
+        let named = peer(json!({
+            "DNSName": "Build-Box.tail1234.ts.net.",
+            "HostName": "buildbox",
+            "TailscaleIPs": ["100.64.0.2", "fd7a:115c:a1e0::2", "bogus"],
+        }))
+        .unwrap();
+        assert_eq!(named.name, "build-box");
+        assert_eq!(
+            addresses(&named),
+            (
+                vec!["100.64.0.2".to_string()],
+                vec!["fd7a:115c:a1e0::2".to_string()]
+            )
+        );
+
+        // Without MagicDNS
+        let unnamed = json!({ "DNSName": "", "HostName": "Phone", "TailscaleIPs": [] });
+        assert_eq!(peer(unnamed).unwrap().name, "phone");
+        assert!(peer(json!({ "HostName": "phone" })).is_none());
+        assert!(peer(json!({ "TailscaleIPs": ["100.64.0.3"] })).is_none());
+    }
+
+    /// A LocalAPI socket answering each connection with the next of `responses`.
+    fn local_api(name: &str, responses: Vec<String>) -> PathBuf {
+        let socket =
+            std::env::temp_dir().join(format!("libnss-mesh-{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).unwrap();
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        socket
+    }
+
+    fn backend(socket: &Path, domain: Option<&str>) -> MeshBackend {
+        MeshBackend::new(MeshConfig {
+            source: Source::Tailscale {
+                socket: socket.to_path_buf(),
+            },
+            domain: domain.map(str::to_string),
+            cache_ttl: Duration::ZERO,
+            ..MeshConfig::default()
+        })
+    }
+
+    #[test]
+    fn tailscale_status() {
+        let status = json!({
+            "MagicDNSSuffix": "Tail1234.ts.net.",
+            "Self": { "DNSName": "laptop.tail1234.ts.net.", "TailscaleIPs": ["100.64.0.1"] },
+            "Peer": {
+                "nodekey:1": {
+                    "DNSName": "build-box.tail1234.ts.net.",
+                    "TailscaleIPs": ["100.64.0.2", "fd7a:115c:a1e0::2"],
+                },
+            },
+        });
+        let ok = format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
+            status
+        );
+        let socket = local_api(
+            "status",
+            vec![
+                ok.clone(),
+                "HTTP/1.0 403 Forbidden\r\n\r\naccess denied".to_string(),
+                ok,
+            ],
+        );
+        let mesh = backend(&socket, None);
+
+        let host = mesh
+            .get_host_by_name("BUILD-BOX.tail1234.ts.net.", AddressFamily::Unspecified)
+            .unwrap();
+        assert_eq!(
+            host,
+            Host {
+                name: "build-box.tail1234.ts.net".to_string(),
+                aliases: vec!["build-box".to_string()],
+                addresses: Addresses::V4(vec![Ipv4Addr::new(100, 64, 0, 2)]),
+                canonical_name: None,
+            }
+        );
+        // A failed request keeps the peers last read
+        assert_eq!(
+            mesh.get_host_by_addr("100.64.0.1".parse().unwrap())
+                .unwrap()
+                .name,
+            "laptop.tail1234.ts.net"
+        );
+        assert_eq!(mesh.get_all_hosts().len(), 3);
+
+        // And the socket is gone
+        fs::remove_file(&socket).unwrap();
+        assert_eq!(
+            mesh.get_host_by_name("build-box", AddressFamily::IPv6)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd7a:115c:a1e0::2".parse().unwrap()])
+        );
+    }
+
+    #[test]
+    fn configured_domains() {
+        let socket = local_api(
+            "domain",
+            vec![format!(
+                "HTTP/1.0 200 OK\r\n\r\n{}",
+                json!({
+                    "MagicDNSSuffix": "tail1234.ts.net",
+                    "Peer": { "nodekey:1": { "HostName": "nas", "TailscaleIPs": ["100.64.0.9"] } },
+                })
+            )],
+        );
+        let mesh = backend(&socket, Some(".Mesh."));
+
+        assert_eq!(
+            mesh.get_host_by_name("nas.mesh", AddressFamily::IPv4)
+                .unwrap()
+                .name,
+            "nas.mesh"
+        );
+        assert!(mesh
+            .get_host_by_name("nas.tail1234.ts.net", AddressFamily::IPv4)
+            .is_none());
+        fs::remove_file(&socket).unwrap();
+
+        let peers = Peers {
+            peers: vec![],
+            domain: None,
+        };
+        let peer = Peer {
+            name: "nas".to_string(),
+            v4: vec![],
+            v6: vec!["fd00::9".parse().unwrap()],
+        };
+        let host = to_host(&peers, &peer, AddressFamily::IPv6).unwrap();
+        assert_eq!((host.name.as_str(), host.aliases.len()), ("nas", 0));
+        assert!(to_host(&peers, &peer, AddressFamily::IPv4).is_none());
+    }
+}
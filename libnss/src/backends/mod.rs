@@ -0,0 +1,54 @@
+#[cfg(feature = "avahi")]
+pub mod avahi;
+#[cfg(feature = "cloud")]
+pub mod cloud;
+#[cfg(feature = "csv")]
+pub mod csv;
+#[cfg(feature = "dhcp_leases")]
+pub mod dhcp_leases;
+#[cfg(feature = "dynamic_user")]
+pub mod dynamic_user;
+#[cfg(feature = "env")]
+pub mod env;
+#[cfg(feature = "etcd")]
+pub mod etcd;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "hesiod")]
+pub mod hesiod;
+#[cfg(feature = "hosts_dir")]
+pub mod hosts_dir;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+#[cfg(feature = "libvirt")]
+pub mod libvirt;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "mesh")]
+pub mod mesh;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+#[cfg(feature = "nis")]
+pub mod nis;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+mod sql;
+#[cfg(feature = "sssd")]
+pub mod sssd;
+#[cfg(feature = "static_file")]
+pub mod static_file;
+#[cfg(feature = "toml")]
+pub mod toml;
+#[cfg(feature = "userdb")]
+pub mod userdb;
+#[cfg(any(
+    feature = "static_file",
+    feature = "csv",
+    feature = "sssd",
+    feature = "toml",
+    feature = "dhcp_leases"
+))]
+mod watch;
@@ -0,0 +1,690 @@
+//! Resolves users, groups and hosts from a NIS (YP) domain, for fronting one with a module of
+//! your own while migrating off it.
+//!
+//! Lookups are made over UDP straight to the domain's `servers`, as `ypmatch` and `ypcat` would,
+//! without needing `ypbind`. Each server's `ypserv` is found through its portmapper unless `port`
+//! is set, and when one doesn't answer within `timeout` after `retries` retransmissions the next
+//! is asked. These maps are read, whose values are lines in the `/etc` formats:
+//!
+//! | Database | By name          | By id or address               |
+//! |----------|------------------|--------------------------------|
+//! | passwd   | `passwd.byname`  | `passwd.byuid`                 |
+//! | group    | `group.byname`   | `group.bygid`                  |
+//! | hosts    | `hosts.byname`   | `hosts.byaddr`, also listed    |
+//!
+//! Every successful read is remembered, so when no server can be reached the last known value is
+//! served instead of failing the lookup, for up to `max_staleness` after it was read. How many
+//! values are remembered for each database is up to `eviction`, and they are kept in `cache_dir`
+//! across restarts if it is set. Values younger than `ttl` are served without asking the servers
+//! at all.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::stale::{CacheBudgets, StaleCache};
+use crate::stats::Database;
+
+pub struct NisConfig {
+    pub domain: String,
+    /// Asked in order, moving on to the next when one doesn't answer
+    pub servers: Vec<IpAddr>,
+    /// Where `ypserv` listens; `None`, the default, asks each server's portmapper
+    pub port: Option<u16>,
+    /// How long to wait for each answer before sending the request again
+    pub timeout: Duration,
+    pub retries: u32,
+    /// How long a value keeps being served while no server can be reached; zero disables this
+    pub max_staleness: Duration,
+    pub eviction: CacheBudgets,
+    /// Where to keep the cache across restarts, e.g. `/var/lib/<module>`, in a `nis` directory
+    pub cache_dir: Option<PathBuf>,
+    /// Where to keep the cache in segments shared by every process, e.g. `/run/<module>`, in a
+    /// `nis` directory. Takes the place of `eviction` and `cache_dir` once mapped
+    pub shared_dir: Option<PathBuf>,
+    /// How long a value is served from the cache without asking the servers again; zero, the
+    /// default, always asks them
+    pub ttl: Duration,
+}
+
+impl Default for NisConfig {
+    fn default() -> Self {
+        NisConfig {
+            domain: String::new(),
+            servers: Vec::new(),
+            port: None,
+            timeout: Duration::from_millis(500),
+            retries: 2,
+            max_staleness: Duration::from_secs(24 * 60 * 60),
+            eviction: CacheBudgets::default(),
+            cache_dir: None,
+            shared_dir: None,
+            ttl: Duration::from_secs(0),
+        }
+    }
+}
+
+const PMAP_PROGRAM: u32 = 100_000;
+const PMAP_VERSION: u32 = 2;
+const PMAP_PORT: u16 = 111;
+const PMAPPROC_GETPORT: u32 = 3;
+const IPPROTO_UDP: u32 = 17;
+
+const YP_PROGRAM: u32 = 100_004;
+const YP_VERSION: u32 = 2;
+const YPPROC_MATCH: u32 = 3;
+const YPPROC_FIRST: u32 = 4;
+const YPPROC_NEXT: u32 = 5;
+
+const YP_TRUE: u32 = 1;
+const YP_NOMORE: u32 = 2;
+const YP_NOMAP: u32 = -1i32 as u32;
+const YP_NOKEY: u32 = -3i32 as u32;
+
+#[derive(Debug)]
+enum NisError {
+    Io(io::Error),
+    /// No server answered
+    Unreachable,
+    /// The server answered with something other than a successful ONC RPC reply
+    Rpc(&'static str),
+    /// `ypserv` answered with a status other than success, "no such key" or "no such map"
+    Status(i32),
+}
+
+impl fmt::Display for NisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NisError::Io(err) => err.fmt(f),
+            NisError::Unreachable => f.write_str("no NIS server answered"),
+            NisError::Rpc(reason) => write!(f, "bad RPC reply: {}", reason),
+            NisError::Status(status) => write!(f, "ypserv answered with status {}", status),
+        }
+    }
+}
+
+impl Error for NisError {}
+
+impl From<io::Error> for NisError {
+    fn from(err: io::Error) -> Self {
+        NisError::Io(err)
+    }
+}
+
+pub struct NisBackend {
+    domain: String,
+    servers: Vec<IpAddr>,
+    port: Option<u16>,
+    timeout: Duration,
+    retries: u32,
+    /// `ypserv`'s port on each server, as last told by its portmapper
+    ports: Mutex<HashMap<IpAddr, u16>>,
+    xid: AtomicU32,
+    /// By database
+    caches: Vec<StaleCache<String, String>>,
+}
+
+impl NisBackend {
+    /// Creates the backend without contacting any server.
+    pub fn new(config: NisConfig) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.subsec_nanos());
+        let ttl = config.ttl;
+
+        NisBackend {
+            domain: config.domain,
+            servers: config.servers,
+            port: config.port,
+            timeout: config.timeout,
+            retries: config.retries,
+            ports: Mutex::new(HashMap::new()),
+            xid: AtomicU32::new(seed ^ process::id().rotate_left(16)),
+            caches: config
+                .eviction
+                .caches(
+                    config.max_staleness,
+                    config.cache_dir.map(|dir| dir.join("nis")).as_deref(),
+                    config.shared_dir.map(|dir| dir.join("nis")).as_deref(),
+                )
+                .into_iter()
+                .map(|cache| cache.with_ttl(ttl))
+                .collect(),
+        }
+    }
+
+    /// One entry per address.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        self.all(Database::Hosts, "hosts.byaddr")
+            .iter()
+            .filter_map(|line| line.parse().ok())
+            .collect()
+    }
+
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let host: Host = self
+            .get(Database::Hosts, "hosts.byname", name)?
+            .parse()
+            .ok()?;
+        match (family, &host.addresses) {
+            (AddressFamily::IPv4, Addresses::V4(_))
+            | (AddressFamily::IPv6, Addresses::V6(_))
+            | (AddressFamily::Unspecified, _) => Some(host),
+            _ => None,
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let line = self.get(Database::Hosts, "hosts.byaddr", &addr.to_string())?;
+        let host: Host = line.parse().ok()?;
+        let matches = match (&host.addresses, addr) {
+            (Addresses::V4(addrs), IpAddr::V4(addr)) => addrs.contains(&addr),
+            (Addresses::V6(addrs), IpAddr::V6(addr)) => addrs.contains(&addr),
+            _ => false,
+        };
+        Some(host).filter(|_| matches)
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.all(Database::Passwd, "passwd.byname")
+            .iter()
+            .filter_map(|line| line.parse().ok())
+            .collect()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        let line = self.get(Database::Passwd, "passwd.byuid", &uid.to_string())?;
+        line.parse::<Passwd>().ok().filter(|p| p.uid == uid)
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        let line = self.get(Database::Passwd, "passwd.byname", name)?;
+        line.parse::<Passwd>().ok().filter(|p| p.name == name)
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.all(Database::Group, "group.byname")
+            .iter()
+            .filter_map(|line| line.parse().ok())
+            .collect()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        let line = self.get(Database::Group, "group.bygid", &gid.to_string())?;
+        line.parse::<Group>().ok().filter(|g| g.gid == gid)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let line = self.get(Database::Group, "group.byname", name)?;
+        line.parse::<Group>().ok().filter(|g| g.name == name)
+    }
+
+    /// Looks `key` up in `map`, falling back to the last value seen for it if no server answers.
+    fn get(&self, database: Database, map: &str, key: &str) -> Option<String> {
+        self.caches[database as usize]
+            .lookup(format!("{}:{}", map, key), || self.ypmatch(map, key))
+            .ok()
+            .flatten()
+    }
+
+    /// Every value in `map`, falling back to those last listed if no server answers.
+    fn all(&self, database: Database, map: &str) -> Vec<String> {
+        let values = self.caches[database as usize]
+            .lookup(map.to_string(), || {
+                let values = self.ypall(map)?;
+                Ok::<_, NisError>(Some(values.join("\n")).filter(|v| !v.is_empty()))
+            })
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        values.lines().map(str::to_string).collect()
+    }
+
+    fn ypmatch(&self, map: &str, key: &str) -> Result<Option<String>, NisError> {
+        let mut args = Vec::new();
+        put_opaque(&mut args, self.domain.as_bytes());
+        put_opaque(&mut args, map.as_bytes());
+        put_opaque(&mut args, key.as_bytes());
+
+        let reply = self.yp_call(YPPROC_MATCH, &args)?;
+        let mut reply = Reader(&reply);
+        match reply.u32()? {
+            YP_TRUE => Ok(Some(value(reply.opaque()?))),
+            YP_NOKEY | YP_NOMAP => Ok(None),
+            status => Err(NisError::Status(status as i32)),
+        }
+    }
+
+    /// Walks `map` with `YPPROC_FIRST` and `YPPROC_NEXT`, which unlike `YPPROC_ALL` work over UDP.
+    fn ypall(&self, map: &str) -> Result<Vec<String>, NisError> {
+        let mut args = Vec::new();
+        put_opaque(&mut args, self.domain.as_bytes());
+        put_opaque(&mut args, map.as_bytes());
+        let mut reply = self.yp_call(YPPROC_FIRST, &args)?;
+
+        let mut values = Vec::new();
+        loop {
+            let mut fields = Reader(&reply);
+            match fields.u32()? {
+                YP_TRUE => {}
+                YP_NOMORE | YP_NOMAP => return Ok(values),
+                status => return Err(NisError::Status(status as i32)),
+            }
+            values.push(value(fields.opaque()?));
+            let key = fields.opaque()?.to_vec();
+
+            let mut args = Vec::new();
+            put_opaque(&mut args, self.domain.as_bytes());
+            put_opaque(&mut args, map.as_bytes());
+            put_opaque(&mut args, &key);
+            reply = self.yp_call(YPPROC_NEXT, &args)?;
+        }
+    }
+
+    /// Calls `ypserv` on each server in turn until one answers.
+    fn yp_call(&self, procedure: u32, args: &[u8]) -> Result<Vec<u8>, NisError> {
+        let mut last = NisError::Unreachable;
+        for &server in &self.servers {
+            let port = match self.ypserv_port(server) {
+                Ok(port) => port,
+                Err(err) => {
+                    last = err;
+                    continue;
+                }
+            };
+            let target = SocketAddr::new(server, port);
+            match self.call(target, YP_PROGRAM, YP_VERSION, procedure, args) {
+                Ok(reply) => return Ok(reply),
+                Err(err) => {
+                    // ypserv may have restarted on another port
+                    if self.port.is_none() {
+                        self.ports
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .remove(&server);
+                    }
+                    last = err;
+                }
+            }
+        }
+        Err(last)
+    }
+
+    fn ypserv_port(&self, server: IpAddr) -> Result<u16, NisError> {
+        if let Some(port) = self.port {
+            return Ok(port);
+        }
+        let ports = self.ports.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&port) = ports.get(&server) {
+            return Ok(port);
+        }
+        drop(ports);
+
+        let mut args = Vec::new();
+        for word in [YP_PROGRAM, YP_VERSION, IPPROTO_UDP, 0] {
+            put_u32(&mut args, word);
+        }
+        let target = SocketAddr::new(server, PMAP_PORT);
+        let reply = self.call(target, PMAP_PROGRAM, PMAP_VERSION, PMAPPROC_GETPORT, &args)?;
+        // Zero means ypserv isn't registered
+        let port = match Reader(&reply).u32()? {
+            port @ 1..=0xffff => port as u16,
+            _ => return Err(NisError::Unreachable),
+        };
+        self.ports
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(server, port);
+        Ok(port)
+    }
+
+    /// Makes an ONC RPC call over UDP with no authentication, returning the procedure's results.
+    fn call(
+        &self,
+        target: SocketAddr,
+        program: u32,
+        version: u32,
+        procedure: u32,
+        args: &[u8],
+    ) -> Result<Vec<u8>, NisError> {
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+
+        let xid = self.xid.fetch_add(1, Ordering::Relaxed);
+        let mut request = Vec::with_capacity(40 + args.len());
+        // Call, RPC version 2, then the credential and verifier, both AUTH_NONE
+        for word in [xid, 0, 2, program, version, procedure, 0, 0, 0, 0] {
+            put_u32(&mut request, word);
+        }
+        request.extend_from_slice(args);
+
+        let mut buf = vec![0; 65536];
+        for _ in 0..=self.retries {
+            socket.send(&request)?;
+            loop {
+                let len = match socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(err)
+                        if err.kind() == io::ErrorKind::WouldBlock
+                            || err.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let mut reply = Reader(&buf[..len]);
+                // Ignore answers to earlier transmissions or calls
+                if reply.u32()? != xid {
+                    continue;
+                }
+                return accepted(reply).map(<[u8]>::to_vec);
+            }
+        }
+        Err(NisError::Unreachable)
+    }
+}
+
+/// Checks an RPC reply whose xid has been read was accepted and succeeded, returning its results.
+fn accepted(mut reply: Reader<'_>) -> Result<&[u8], NisError> {
+    if reply.u32()? != 1 {
+        return Err(NisError::Rpc("not a reply"));
+    }
+    if reply.u32()? != 0 {
+        return Err(NisError::Rpc("call denied"));
+    }
+    // The verifier's flavor and body
+    reply.u32()?;
+    reply.opaque()?;
+    match reply.u32()? {
+        0 => Ok(reply.0),
+        1 => Err(NisError::Rpc("program unavailable")),
+        2 => Err(NisError::Rpc("program version mismatch")),
+        3 => Err(NisError::Rpc("procedure unavailable")),
+        _ => Err(NisError::Rpc("call failed")),
+    }
+}
+
+/// A map's value as text. Some servers count a trailing NUL in the value's length.
+fn value(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_suffix(b"\0").unwrap_or(bytes);
+    String::from_utf8_lossy(bytes).trim_end().to_string()
+}
+
+fn put_u32(buf: &mut Vec<u8>, word: u32) {
+    buf.extend_from_slice(&word.to_be_bytes());
+}
+
+/// An XDR variable length opaque or string: its length, then its bytes padded to four.
+fn put_opaque(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+    buf.resize(buf.len() + (4 - bytes.len() % 4) % 4, 0);
+}
+
+/// Reads XDR from the front of a reply.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Result<u32, NisError> {
+        let word = self.take(4)?;
+        Ok(u32::from_be_bytes(word.try_into().unwrap()))
+    }
+
+    fn opaque(&mut self) -> Result<&'a [u8], NisError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        self.take((4 - len % 4) % 4)?;
+        Ok(bytes)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NisError> {
+        if self.0.len() < len {
+            return Err(NisError::Rpc("truncated"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    type Entry = (&'static str, &'static str, &'static str);
+
+    /// Answers `requests` calls as `ypserv` would for the `(map, key, value)` entries in
+    /// `domain`, then stops listening.
+    fn fake_ypserv(entries: &'static [Entry], requests: usize) -> u16 {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut buf = vec![0; 65536];
+            for _ in 0..requests {
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let mut call = Reader(&buf[..len]);
+                let xid = call.u32().unwrap();
+                let header: Vec<u32> = (0..5).map(|_| call.u32().unwrap()).collect();
+                assert_eq!(header[..4], [0, 2, YP_PROGRAM, YP_VERSION]);
+                // The credential and verifier
+                for _ in 0..2 {
+                    call.u32().unwrap();
+                    call.opaque().unwrap();
+                }
+                assert_eq!(call.opaque().unwrap(), b"example");
+                let map = std::str::from_utf8(call.opaque().unwrap()).unwrap();
+                let mut entries = entries.iter().filter(|entry| entry.0 == map).peekable();
+
+                let mut reply = Vec::new();
+                for word in [xid, 1, 0, 0, 0, 0] {
+                    put_u32(&mut reply, word);
+                }
+                let found = match header[4] {
+                    YPPROC_MATCH => {
+                        let key = call.opaque().unwrap();
+                        entries.find(|entry| entry.1.as_bytes() == key)
+                    }
+                    YPPROC_FIRST => entries.next(),
+                    YPPROC_NEXT => {
+                        let key = call.opaque().unwrap();
+                        entries.find(|entry| entry.1.as_bytes() == key);
+                        entries.next()
+                    }
+                    procedure => panic!("unexpected procedure {}", procedure),
+                };
+                match (found, header[4]) {
+                    (Some(&(_, key, value)), procedure) => {
+                        put_u32(&mut reply, YP_TRUE);
+                        put_opaque(&mut reply, value.as_bytes());
+                        if procedure != YPPROC_MATCH {
+                            put_opaque(&mut reply, key.as_bytes());
+                        }
+                    }
+                    (None, YPPROC_MATCH) => put_u32(&mut reply, YP_NOKEY),
+                    (None, _) => put_u32(&mut reply, YP_NOMORE),
+                }
+                socket.send_to(&reply, peer).unwrap();
+            }
+        });
+        port
+    }
+
+    fn backend(port: u16) -> NisBackend {
+        NisBackend::new(NisConfig {
+            domain: "example".to_string(),
+            servers: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: Some(port),
+            timeout: Duration::from_millis(200),
+            retries: 0,
+            ..NisConfig::default()
+        })
+    }
+
+    #[test]
+    fn xdr() {
+        let mut buf = Vec::new();
+        put_u32(&mut buf, 0x0102_0304);
+        put_opaque(&mut buf, b"");
+        put_opaque(&mut buf, b"abcd");
+        put_opaque(&mut buf, b"abcde");
+        assert_eq!(
+            buf,
+            [
+                &[1, 2, 3, 4][..],
+                &[0, 0, 0, 0],
+                &[0, 0, 0, 4],
+                b"abcd",
+                &[0, 0, 0, 5],
+                b"abcde\0\0\0",
+            ]
+            .concat()
+        );
+
+        let mut reader = Reader(&buf);
+        assert_eq!(reader.u32().unwrap(), 0x0102_0304);
+        assert_eq!(reader.opaque().unwrap(), b"");
+        assert_eq!(reader.opaque().unwrap(), b"abcd");
+        assert_eq!(reader.opaque().unwrap(), b"abcde");
+        assert!(reader.0.is_empty());
+        for len in 1..buf.len() {
+            let mut reader = Reader(&buf[..len]);
+            let read = (|| {
+                reader.u32()?;
+                reader.opaque()?;
+                reader.opaque()?;
+                reader.opaque()
+            })();
+            assert!(matches!(read, Err(NisError::Rpc("truncated"))));
+        }
+    }
+
+    #[test]
+    fn replies() {
+        let reply = |words: &[u32]| -> Vec<u8> {
+            let mut buf = Vec::new();
+            for &word in words {
+                put_u32(&mut buf, word);
+            }
+            buf
+        };
+
+        let ok = [reply(&[1, 0, 0, 0, 0]), reply(&[7])].concat();
+        assert_eq!(accepted(Reader(&ok)).unwrap(), [0, 0, 0, 7]);
+        for (words, reason) in [
+            (&[0][..], "not a reply"),
+            (&[1, 1], "call denied"),
+            (&[1, 0, 0, 0, 1], "program unavailable"),
+            (&[1, 0, 0, 0, 2], "program version mismatch"),
+            (&[1, 0, 0, 0, 3], "procedure unavailable"),
+            (&[1, 0, 0, 0, 4], "call failed"),
+            (&[1, 0, 0, 8], "truncated"),
+        ] {
+            match accepted(Reader(&reply(words))) {
+                Err(NisError::Rpc(got)) => assert_eq!(got, reason),
+                other => panic!("{:?} for {:?}", other, words),
+            }
+        }
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(
+            value(b"alice:x:1000:100::/home/alice:/bin/sh"),
+            "alice:x:1000:100::/home/alice:/bin/sh"
+        );
+        assert_eq!(value(b"staff:x:50:alice\0"), "staff:x:50:alice");
+        assert_eq!(value(b"staff:x:50:alice \n"), "staff:x:50:alice");
+        assert_eq!(value(b"caf\xe9"), "caf\u{fffd}");
+    }
+
+    const ENTRIES: &[Entry] = &[
+        (
+            "passwd.byname",
+            "alice",
+            "alice:x:1000:100:Alice:/home/alice:/bin/sh\0",
+        ),
+        (
+            "passwd.byname",
+            "bob",
+            "bob:x:1001:100:Bob:/home/bob:/bin/sh",
+        ),
+        (
+            "passwd.byuid",
+            "1000",
+            "alice:x:1000:100:Alice:/home/alice:/bin/sh",
+        ),
+        (
+            "passwd.byuid",
+            "1002",
+            "bob:x:1001:100:Bob:/home/bob:/bin/sh",
+        ),
+        ("group.byname", "staff", "staff:x:50:alice,bob"),
+        ("hosts.byname", "db", "10.0.0.1 db db.local"),
+        ("hosts.byaddr", "10.0.0.1", "10.0.0.1 db db.local"),
+        ("hosts.byaddr", "fd00::1", "fd00::1 db6"),
+    ];
+
+    #[test]
+    fn lookups() {
+        let nis = backend(fake_ypserv(ENTRIES, 100));
+
+        let alice = nis.get_passwd_by_name("alice").unwrap();
+        assert_eq!(
+            (alice.uid, alice.gecos.as_str()),
+            (Uid::from_raw(1000), "Alice")
+        );
+        assert_eq!(nis.get_passwd_by_uid(Uid::from_raw(1000)), Some(alice));
+        assert_eq!(nis.get_passwd_by_name("carol"), None);
+        // The value has to be the entry asked for
+        assert_eq!(nis.get_passwd_by_uid(Uid::from_raw(1002)), None);
+        assert_eq!(
+            nis.get_all_passwd()
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>(),
+            ["alice", "bob"]
+        );
+
+        let staff = nis.get_group_by_name("staff").unwrap();
+        assert_eq!(staff.members, ["alice", "bob"]);
+        assert_eq!(nis.get_all_groups(), [staff]);
+        // No such map
+        assert_eq!(nis.get_group_by_gid(Gid::from_raw(50)), None);
+
+        let db = nis.get_host_by_name("db", AddressFamily::IPv4).unwrap();
+        assert_eq!(db.aliases, ["db.local"]);
+        assert_eq!(nis.get_host_by_name("db", AddressFamily::IPv6), None);
+        assert_eq!(nis.get_host_by_addr("10.0.0.1".parse().unwrap()), Some(db));
+        assert_eq!(nis.get_all_hosts().len(), 2);
+    }
+
+    #[test]
+    fn serves_the_last_value_when_no_server_answers() {
+        let nis = backend(fake_ypserv(ENTRIES, 1));
+        let alice = nis.get_passwd_by_name("alice");
+        assert!(alice.is_some());
+        assert_eq!(nis.get_passwd_by_name("alice"), alice);
+        assert_eq!(nis.get_passwd_by_name("bob"), None);
+
+        let nis = backend(fake_ypserv(ENTRIES, 0));
+        assert_eq!(nis.get_passwd_by_name("alice"), None);
+    }
+}
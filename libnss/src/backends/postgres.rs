@@ -0,0 +1,432 @@
+//! Resolves hosts, users and groups from PostgreSQL.
+//!
+//! Every lookup runs one of the configured [`Queries`], binding the name, id or address looked up
+//! as `$1` in whatever type the query expects for it. The defaults read tables shaped like
+//! `/etc`'s files, but any query will do as long as it returns these columns, in order:
+//!
+//! | Database | Columns                                                          |
+//! |----------|------------------------------------------------------------------|
+//! | hosts    | `name`, `aliases` (`text[]`), `addresses` (`inet[]`)             |
+//! | passwd   | `name`, `passwd`, `uid`, `gid`, `gecos`, `dir`, `shell`          |
+//! | group    | `name`, `passwd`, `gid`, `members` (`text[]`)                    |
+//!
+//! Ids may be any integer type, and lists may also be whitespace or comma separated text. `NULL`s
+//! read as empty, or as `x` for `passwd`.
+//!
+//! The backend runs inside every process that looks anything up, so its pool holds a single
+//! connection by default and closes it once idle for a while. Each connection sets
+//! `statement_timeout`, so a slow query fails the lookup instead of hanging the caller.
+//!
+//! Every successful read is remembered, so when PostgreSQL cannot be reached the last known value
+//! is served instead of failing the lookup, for up to `max_staleness` after it was read. How many
+//! values are remembered for each database is up to `eviction`, and they are kept in `cache_dir`
+//! across restarts if it is set. Values younger than `ttl` are served without querying PostgreSQL
+//! at all.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ::postgres::types::{Kind, ToSql, Type};
+use ::postgres::{Client, NoTls, Row};
+
+use crate::backends::sql::{self, Param, Rows};
+use crate::group::Group;
+use crate::host::{AddressFamily, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::stale::{CacheBudgets, StaleCache};
+use crate::stats::Database;
+
+pub struct Queries {
+    pub host_by_name: String,
+    pub host_by_addr: String,
+    pub host_all: String,
+    pub passwd_by_name: String,
+    pub passwd_by_uid: String,
+    pub passwd_all: String,
+    pub group_by_name: String,
+    pub group_by_gid: String,
+    pub group_all: String,
+}
+
+impl Default for Queries {
+    fn default() -> Self {
+        const HOST: &str = "SELECT name, aliases, addresses FROM hosts";
+        const PASSWD: &str = "SELECT name, passwd, uid, gid, gecos, dir, shell FROM passwd";
+        const GROUP: &str = "SELECT name, passwd, gid, members FROM groups";
+
+        Queries {
+            host_by_name: format!("{} WHERE name = $1 OR $1 = ANY(aliases)", HOST),
+            host_by_addr: format!("{} WHERE $1 = ANY(addresses)", HOST),
+            host_all: format!("{} ORDER BY name", HOST),
+            passwd_by_name: format!("{} WHERE name = $1", PASSWD),
+            passwd_by_uid: format!("{} WHERE uid = $1", PASSWD),
+            passwd_all: format!("{} ORDER BY uid", PASSWD),
+            group_by_name: format!("{} WHERE name = $1", GROUP),
+            group_by_gid: format!("{} WHERE gid = $1", GROUP),
+            group_all: format!("{} ORDER BY gid", GROUP),
+        }
+    }
+}
+
+pub struct PostgresConfig {
+    /// A libpq connection string, e.g. `host=/run/postgresql dbname=nss user=nss`, or URL. TLS
+    /// isn't supported, so connect over a local socket or a trusted network
+    pub connection: String,
+    pub queries: Queries,
+    pub pool_size: u32,
+    pub connection_timeout: Duration,
+    /// How long a query may run before PostgreSQL cancels it
+    pub statement_timeout: Duration,
+    /// How long an unused connection is kept open
+    pub idle_timeout: Duration,
+    /// How long a value keeps being served while PostgreSQL can't be reached; zero disables this
+    pub max_staleness: Duration,
+    pub eviction: CacheBudgets,
+    /// Where to keep the cache across restarts, e.g. `/var/lib/<module>`, in a `postgres`
+    /// directory
+    pub cache_dir: Option<PathBuf>,
+    /// Where to keep the cache in segments shared by every process, e.g. `/run/<module>`, in a
+    /// `postgres` directory. Takes the place of `eviction` and `cache_dir` once mapped
+    pub shared_dir: Option<PathBuf>,
+    /// How long a value is served from the cache without querying PostgreSQL again; zero, the
+    /// default, always queries it
+    pub ttl: Duration,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> Self {
+        PostgresConfig {
+            connection: "host=/run/postgresql dbname=nss".to_string(),
+            queries: Queries::default(),
+            pool_size: 1,
+            connection_timeout: Duration::from_millis(500),
+            statement_timeout: Duration::from_secs(2),
+            idle_timeout: Duration::from_secs(30),
+            max_staleness: Duration::from_secs(24 * 60 * 60),
+            eviction: CacheBudgets::default(),
+            cache_dir: None,
+            shared_dir: None,
+            ttl: Duration::from_secs(0),
+        }
+    }
+}
+
+struct ConnectionManager {
+    config: ::postgres::Config,
+}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = Client;
+    type Error = ::postgres::Error;
+
+    fn connect(&self) -> Result<Client, ::postgres::Error> {
+        self.config.connect(NoTls)
+    }
+
+    fn is_valid(&self, conn: &mut Client) -> Result<(), ::postgres::Error> {
+        conn.simple_query("").map(drop)
+    }
+
+    fn has_broken(&self, conn: &mut Client) -> bool {
+        conn.is_closed()
+    }
+}
+
+/// Converts to the type the statement expects, or `None` if the value can't be one, in which case
+/// nothing can match it.
+fn bind(param: &Param, ty: &Type) -> Option<Vec<Box<dyn ToSql + Sync>>> {
+    let value: Box<dyn ToSql + Sync> = match *param {
+        Param::None => return Some(Vec::new()),
+        Param::Id(id) if *ty == Type::INT2 => Box::new(i16::try_from(id).ok()?),
+        Param::Id(id) if *ty == Type::INT4 => Box::new(i32::try_from(id).ok()?),
+        Param::Id(id) if *ty == Type::INT8 => Box::new(i64::from(id)),
+        Param::Id(id) if *ty == Type::OID => Box::new(id),
+        Param::Addr(addr) if *ty == Type::INET => Box::new(addr),
+        _ => Box::new(param.to_string()),
+    };
+    Some(vec![value])
+}
+
+pub struct PostgresBackend {
+    pool: r2d2::Pool<ConnectionManager>,
+    queries: Queries,
+    /// By database
+    caches: Vec<StaleCache<String, Rows>>,
+}
+
+impl PostgresBackend {
+    /// Creates the backend without connecting; connections are made lazily by the pool so a
+    /// module can be loaded while PostgreSQL is down.
+    pub fn new(config: PostgresConfig) -> Result<Self, ::postgres::Error> {
+        let mut pg_config: ::postgres::Config = config.connection.parse()?;
+        let options = format!(
+            "-c statement_timeout={}",
+            config.statement_timeout.as_millis()
+        );
+        let options = match pg_config.get_options() {
+            Some(existing) => format!("{} {}", existing, options),
+            None => options,
+        };
+        pg_config
+            .options(&options)
+            .connect_timeout(config.connection_timeout);
+
+        let pool = r2d2::Pool::builder()
+            .max_size(config.pool_size)
+            .min_idle(Some(0))
+            .idle_timeout(Some(config.idle_timeout))
+            .connection_timeout(config.connection_timeout)
+            .build_unchecked(ConnectionManager { config: pg_config });
+        let ttl = config.ttl;
+
+        Ok(PostgresBackend {
+            pool,
+            queries: config.queries,
+            caches: config
+                .eviction
+                .caches(
+                    config.max_staleness,
+                    config.cache_dir.map(|dir| dir.join("postgres")).as_deref(),
+                    config.shared_dir.map(|dir| dir.join("postgres")).as_deref(),
+                )
+                .into_iter()
+                .map(|cache| cache.with_ttl(ttl))
+                .collect(),
+        })
+    }
+
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        self.fetch(Database::Hosts, "all", &self.queries.host_all, Param::None)
+            .iter()
+            .filter_map(|row| sql::host(row, AddressFamily::Unspecified))
+            .collect()
+    }
+
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let param = Param::Name(name.to_string());
+        self.fetch(Database::Hosts, "name", &self.queries.host_by_name, param)
+            .iter()
+            .find_map(|row| sql::host(row, family))
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let family = match addr {
+            IpAddr::V4(_) => AddressFamily::IPv4,
+            IpAddr::V6(_) => AddressFamily::IPv6,
+        };
+        self.fetch(
+            Database::Hosts,
+            "addr",
+            &self.queries.host_by_addr,
+            Param::Addr(addr),
+        )
+        .iter()
+        .find_map(|row| sql::host(row, family))
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.fetch(
+            Database::Passwd,
+            "all",
+            &self.queries.passwd_all,
+            Param::None,
+        )
+        .iter()
+        .filter_map(|row| sql::passwd(row))
+        .collect()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        let param = Param::Id(uid.as_raw());
+        self.fetch(Database::Passwd, "uid", &self.queries.passwd_by_uid, param)
+            .iter()
+            .filter_map(|row| sql::passwd(row))
+            .find(|p| p.uid == uid)
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        let param = Param::Name(name.to_string());
+        self.fetch(
+            Database::Passwd,
+            "name",
+            &self.queries.passwd_by_name,
+            param,
+        )
+        .iter()
+        .filter_map(|row| sql::passwd(row))
+        .find(|p| p.name == name)
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.fetch(Database::Group, "all", &self.queries.group_all, Param::None)
+            .iter()
+            .filter_map(|row| sql::group(row))
+            .collect()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        let param = Param::Id(gid.as_raw());
+        self.fetch(Database::Group, "gid", &self.queries.group_by_gid, param)
+            .iter()
+            .filter_map(|row| sql::group(row))
+            .find(|g| g.gid == gid)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let param = Param::Name(name.to_string());
+        self.fetch(Database::Group, "name", &self.queries.group_by_name, param)
+            .iter()
+            .filter_map(|row| sql::group(row))
+            .find(|g| g.name == name)
+    }
+
+    /// Runs a query, falling back to the last rows it returned if PostgreSQL is unreachable.
+    fn fetch(&self, database: Database, kind: &str, query: &str, param: Param) -> Vec<Vec<String>> {
+        let key = format!("{}:{}", kind, param);
+        self.caches[database as usize]
+            .lookup(key, || self.query(query, &param))
+            .ok()
+            .flatten()
+            .map(|rows| rows.0)
+            .unwrap_or_default()
+    }
+
+    fn query(&self, query: &str, param: &Param) -> Result<Option<Rows>, Box<dyn Error>> {
+        let mut conn = self.pool.get()?;
+        let statement = conn.prepare(query)?;
+        let values = match statement.params().first() {
+            Some(ty) => bind(param, ty),
+            None => Some(Vec::new()),
+        };
+        let values = match values {
+            Some(values) => values,
+            None => return Ok(None),
+        };
+        let values: Vec<&(dyn ToSql + Sync)> = values.iter().map(|value| &**value).collect();
+
+        let rows = conn
+            .query(&statement, &values)?
+            .iter()
+            .map(fields)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(if rows.is_empty() {
+            None
+        } else {
+            Some(Rows(rows))
+        })
+    }
+}
+
+/// Reads every column of a row as text, joining arrays with spaces.
+fn fields(row: &Row) -> Result<Vec<String>, ::postgres::Error> {
+    fn text<T: ToString>(value: Option<T>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_default()
+    }
+    fn join<T: ToString>(values: Option<Vec<Option<T>>>) -> String {
+        values
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    (0..row.len())
+        .map(|idx| {
+            let ty = row.columns()[idx].type_();
+            Ok(if *ty == Type::INT2 {
+                text(row.try_get::<_, Option<i16>>(idx)?)
+            } else if *ty == Type::INT4 {
+                text(row.try_get::<_, Option<i32>>(idx)?)
+            } else if *ty == Type::INT8 {
+                text(row.try_get::<_, Option<i64>>(idx)?)
+            } else if *ty == Type::OID {
+                text(row.try_get::<_, Option<u32>>(idx)?)
+            } else if *ty == Type::INET {
+                text(row.try_get::<_, Option<IpAddr>>(idx)?)
+            } else if *ty == Type::INET_ARRAY {
+                join(row.try_get::<_, Option<Vec<Option<IpAddr>>>>(idx)?)
+            } else if let Kind::Array(_) = ty.kind() {
+                join(row.try_get::<_, Option<Vec<Option<String>>>>(idx)?)
+            } else {
+                text(row.try_get::<_, Option<String>>(idx)?)
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// What `param` binds to for a statement expecting `ty`, as its debug form.
+    fn bound(param: Param, ty: Type) -> Option<String> {
+        let mut values = bind(&param, &ty)?;
+        assert_eq!(values.len(), 1);
+        Some(format!("{:?}", values.remove(0)))
+    }
+
+    #[test]
+    fn ids_bind_as_the_integer_the_query_expects() {
+        assert_eq!(bound(Param::Id(1000), Type::INT2).as_deref(), Some("1000"));
+        assert_eq!(bound(Param::Id(1000), Type::INT4).as_deref(), Some("1000"));
+        assert_eq!(
+            bound(Param::Id(u32::MAX), Type::INT8).as_deref(),
+            Some("4294967295")
+        );
+        assert_eq!(
+            bound(Param::Id(u32::MAX), Type::OID).as_deref(),
+            Some("4294967295")
+        );
+        // Ids the column can't hold can't match anything
+        assert_eq!(bound(Param::Id(70000), Type::INT2), None);
+        assert_eq!(bound(Param::Id(u32::MAX), Type::INT4), None);
+        // Anything else gets the text
+        assert_eq!(
+            bound(Param::Id(1000), Type::TEXT).as_deref(),
+            Some("\"1000\"")
+        );
+    }
+
+    #[test]
+    fn names_and_addresses_bind() {
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(
+            bound(Param::Addr(addr), Type::INET).as_deref(),
+            Some("10.0.0.1")
+        );
+        assert_eq!(
+            bound(Param::Addr(addr), Type::TEXT).as_deref(),
+            Some("\"10.0.0.1\"")
+        );
+        assert_eq!(
+            bound(Param::Name("alice".to_string()), Type::VARCHAR).as_deref(),
+            Some("\"alice\"")
+        );
+        assert!(bind(&Param::None, &Type::TEXT).unwrap().is_empty());
+    }
+
+    #[test]
+    fn default_queries_bind_the_key_they_look_up() {
+        let queries = Queries::default();
+        for query in [
+            &queries.host_by_name,
+            &queries.host_by_addr,
+            &queries.passwd_by_name,
+            &queries.passwd_by_uid,
+            &queries.group_by_name,
+            &queries.group_by_gid,
+        ] {
+            assert!(query.contains("$1") && !query.contains("$2"), "{}", query);
+        }
+        for query in [&queries.host_all, &queries.passwd_all, &queries.group_all] {
+            assert!(!query.contains('$'), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,638 @@
+//! Resolves hosts, users and groups from Redis.
+//!
+//! Every entry lives under a key built from a [`KeyScheme`] template, where `{}` is replaced by
+//! the entry name (or address/id for the reverse index keys). Entries are stored either as hashes
+//! or as plain strings, depending on the configured [`Layout`]:
+//!
+//! | Database | `Layout::Hash` fields                                | `Layout::String` value          |
+//! |----------|------------------------------------------------------|---------------------------------|
+//! | hosts    | `addresses`, `aliases` (whitespace separated)        | whitespace separated addresses  |
+//! | passwd   | `passwd`, `uid`, `gid`, `gecos`, `dir`, `shell`      | an `/etc/passwd` line           |
+//! | group    | `passwd`, `gid`, `members` (comma separated)         | an `/etc/group` line            |
+//!
+//! The reverse index keys (`host_addr`, `passwd_uid` and `group_gid`) always hold the plain name
+//! of the entry they point at.
+//!
+//! Every successful read is remembered, so when Redis cannot be reached the last known value is
+//! served instead of failing the lookup, for up to `max_staleness` after it was read. How many
+//! values are remembered for each database is up to `eviction`, and they are kept in `cache_dir`
+//! across restarts if it is set. Values younger than `ttl` are served without reading Redis at
+//! all.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ::redis::Commands;
+
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::stale::{CacheBudgets, Persist, StaleCache};
+use crate::stats::Database;
+
+pub struct KeyScheme {
+    pub host: String,
+    pub host_addr: String,
+    pub passwd: String,
+    pub passwd_uid: String,
+    pub group: String,
+    pub group_gid: String,
+}
+
+impl KeyScheme {
+    fn key(template: &str, value: &str) -> String {
+        template.replacen("{}", value, 1)
+    }
+
+    fn name_from_key<'a>(template: &str, key: &'a str) -> Option<&'a str> {
+        let (prefix, suffix) = template.split_at(template.find("{}")?);
+        key.strip_prefix(prefix)?.strip_suffix(&suffix[2..])
+    }
+}
+
+impl Default for KeyScheme {
+    fn default() -> Self {
+        KeyScheme {
+            host: "nss:host:{}".to_string(),
+            host_addr: "nss:host_addr:{}".to_string(),
+            passwd: "nss:passwd:{}".to_string(),
+            passwd_uid: "nss:passwd_uid:{}".to_string(),
+            group: "nss:group:{}".to_string(),
+            group_gid: "nss:group_gid:{}".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layout {
+    String,
+    Hash,
+}
+
+pub struct RedisConfig {
+    pub url: String,
+    pub key_scheme: KeyScheme,
+    pub layout: Layout,
+    pub pool_size: u32,
+    pub connection_timeout: Duration,
+    /// How long a value keeps being served while Redis can't be reached; zero disables this
+    pub max_staleness: Duration,
+    pub eviction: CacheBudgets,
+    /// Where to keep the cache across restarts, e.g. `/var/lib/<module>`, in a `redis` directory
+    pub cache_dir: Option<PathBuf>,
+    /// Where to keep the cache in segments shared by every process, e.g. `/run/<module>`, in a
+    /// `redis` directory. Takes the place of `eviction` and `cache_dir` once mapped
+    pub shared_dir: Option<PathBuf>,
+    /// How long a value is served from the cache without reading Redis again; zero, the default,
+    /// always reads it
+    pub ttl: Duration,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            url: "redis://127.0.0.1/".to_string(),
+            key_scheme: KeyScheme::default(),
+            layout: Layout::Hash,
+            pool_size: 4,
+            connection_timeout: Duration::from_millis(500),
+            max_staleness: Duration::from_secs(24 * 60 * 60),
+            eviction: CacheBudgets::default(),
+            cache_dir: None,
+            shared_dir: None,
+            ttl: Duration::from_secs(0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Record {
+    String(String),
+    Hash(HashMap<String, String>),
+}
+
+/// A tag, then a string's bytes, or each field's name and value prefixed by its length.
+impl Persist for Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Record::String(value) => {
+                buf.push(b's');
+                buf.extend_from_slice(value.as_bytes());
+            }
+            Record::Hash(fields) => {
+                buf.push(b'h');
+                for part in fields.iter().flat_map(|(name, value)| [name, value]) {
+                    buf.extend_from_slice(&(part.len() as u32).to_le_bytes());
+                    buf.extend_from_slice(part.as_bytes());
+                }
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, mut rest) = bytes.split_first()?;
+        match tag {
+            b's' => String::decode(rest).map(Record::String),
+            b'h' => {
+                let mut parts = Vec::new();
+                while !rest.is_empty() {
+                    let (len, tail) = rest.split_at(4.min(rest.len()));
+                    let len = u32::from_le_bytes(len.try_into().ok()?) as usize;
+                    if tail.len() < len {
+                        return None;
+                    }
+                    let (part, tail) = tail.split_at(len);
+                    parts.push(String::decode(part)?);
+                    rest = tail;
+                }
+                if parts.len() % 2 != 0 {
+                    return None;
+                }
+                let mut parts = parts.into_iter();
+                let mut fields = HashMap::new();
+                while let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                    fields.insert(name, value);
+                }
+                Some(Record::Hash(fields))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct RedisBackend {
+    pool: r2d2::Pool<::redis::Client>,
+    key_scheme: KeyScheme,
+    layout: Layout,
+    /// By database
+    caches: Vec<StaleCache<String, Record>>,
+}
+
+impl RedisBackend {
+    /// Creates the backend without connecting; connections are made lazily by the pool so a
+    /// module can be loaded while Redis is down.
+    pub fn new(config: RedisConfig) -> ::redis::RedisResult<Self> {
+        let client = ::redis::Client::open(config.url.as_str())?;
+        let pool = r2d2::Pool::builder()
+            .max_size(config.pool_size)
+            .min_idle(Some(0))
+            .connection_timeout(config.connection_timeout)
+            .build_unchecked(client);
+        let ttl = config.ttl;
+
+        Ok(RedisBackend {
+            pool,
+            key_scheme: config.key_scheme,
+            layout: config.layout,
+            caches: config
+                .eviction
+                .caches(
+                    config.max_staleness,
+                    config.cache_dir.map(|dir| dir.join("redis")).as_deref(),
+                    config.shared_dir.map(|dir| dir.join("redis")).as_deref(),
+                )
+                .into_iter()
+                .map(|cache| cache.with_ttl(ttl))
+                .collect(),
+        })
+    }
+
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        self.scan(Database::Hosts, &self.key_scheme.host)
+            .into_iter()
+            .filter_map(|name| self.get_host_by_name(&name, AddressFamily::Unspecified))
+            .collect()
+    }
+
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let key = KeyScheme::key(&self.key_scheme.host, name);
+        let record = self.fetch(Database::Hosts, &key, self.layout)?;
+        host(name, family, record)
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let key = KeyScheme::key(&self.key_scheme.host_addr, &addr.to_string());
+        let name = match self.fetch(Database::Hosts, &key, Layout::String)? {
+            Record::String(name) => name,
+            Record::Hash(_) => return None,
+        };
+
+        let family = match addr {
+            IpAddr::V4(_) => AddressFamily::IPv4,
+            IpAddr::V6(_) => AddressFamily::IPv6,
+        };
+        self.get_host_by_name(&name, family)
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.scan(Database::Passwd, &self.key_scheme.passwd)
+            .into_iter()
+            .filter_map(|name| self.get_passwd_by_name(&name))
+            .collect()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        let name = self.lookup_index(
+            Database::Passwd,
+            &self.key_scheme.passwd_uid,
+            &uid.to_string(),
+        )?;
+        self.get_passwd_by_name(&name).filter(|p| p.uid == uid)
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        let key = KeyScheme::key(&self.key_scheme.passwd, name);
+        passwd(name, self.fetch(Database::Passwd, &key, self.layout)?)
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.scan(Database::Group, &self.key_scheme.group)
+            .into_iter()
+            .filter_map(|name| self.get_group_by_name(&name))
+            .collect()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        let name = self.lookup_index(
+            Database::Group,
+            &self.key_scheme.group_gid,
+            &gid.to_string(),
+        )?;
+        self.get_group_by_name(&name).filter(|g| g.gid == gid)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let key = KeyScheme::key(&self.key_scheme.group, name);
+        group(name, self.fetch(Database::Group, &key, self.layout)?)
+    }
+
+    fn lookup_index(&self, database: Database, template: &str, value: &str) -> Option<String> {
+        match self.fetch(database, &KeyScheme::key(template, value), Layout::String)? {
+            Record::String(name) => Some(name),
+            Record::Hash(_) => None,
+        }
+    }
+
+    /// Reads a key, falling back to the last value seen for it if Redis is unreachable.
+    fn fetch(&self, database: Database, key: &str, layout: Layout) -> Option<Record> {
+        self.caches[database as usize]
+            .lookup(key.to_string(), || self.query(key, layout))
+            .ok()
+            .flatten()
+    }
+
+    fn query(&self, key: &str, layout: Layout) -> Result<Option<Record>, Box<dyn Error>> {
+        let mut conn = self.pool.get()?;
+
+        Ok(match layout {
+            Layout::String => conn.get::<_, Option<String>>(key)?.map(Record::String),
+            Layout::Hash => {
+                let fields: HashMap<String, String> = conn.hgetall(key)?;
+                if fields.is_empty() {
+                    None
+                } else {
+                    Some(Record::Hash(fields))
+                }
+            }
+        })
+    }
+
+    /// Lists the names of every entry stored under `template`, falling back to the cached keys if
+    /// Redis is unreachable.
+    fn scan(&self, database: Database, template: &str) -> Vec<String> {
+        let keys = self.pool.get().map_err(Box::<dyn Error>::from).and_then(|mut conn| {
+            let pattern = template.replacen("{}", "*", 1);
+            let keys = conn.scan_match(pattern)?.collect::<Result<Vec<String>, _>>()?;
+            Ok(keys)
+        });
+
+        let keys = match keys {
+            Ok(keys) => keys,
+            Err(_) => self.caches[database as usize].keys(),
+        };
+
+        keys.iter()
+            .filter_map(|key| KeyScheme::name_from_key(template, key))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// A host from its record, with the addresses of `family`.
+fn host(name: &str, family: AddressFamily, record: Record) -> Option<Host> {
+    let (addresses, aliases) = match record {
+        Record::String(value) => (value, String::new()),
+        Record::Hash(mut fields) => (
+            fields.remove("addresses")?,
+            fields.remove("aliases").unwrap_or_default(),
+        ),
+    };
+
+    let addresses: Vec<IpAddr> = addresses
+        .split_whitespace()
+        .filter_map(|a| a.parse().ok())
+        .collect();
+    let v4: Vec<_> = addresses
+        .iter()
+        .filter_map(|a| match a {
+            IpAddr::V4(a) => Some(*a),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+    let v6: Vec<_> = addresses
+        .iter()
+        .filter_map(|a| match a {
+            IpAddr::V6(a) => Some(*a),
+            IpAddr::V4(_) => None,
+        })
+        .collect();
+
+    let addresses = match family {
+        AddressFamily::IPv4 if !v4.is_empty() => Addresses::V4(v4),
+        AddressFamily::IPv6 if !v6.is_empty() => Addresses::V6(v6),
+        AddressFamily::Unspecified if !v4.is_empty() => Addresses::V4(v4),
+        AddressFamily::Unspecified if !v6.is_empty() => Addresses::V6(v6),
+        _ => return None,
+    };
+
+    Some(Host {
+        name: name.to_string(),
+        aliases: aliases.split_whitespace().map(str::to_string).collect(),
+        addresses,
+        canonical_name: None,
+    })
+}
+
+/// A passwd entry from its record, a line or a hash.
+fn passwd(name: &str, record: Record) -> Option<Passwd> {
+    match record {
+        Record::String(line) => {
+            let fields: Vec<&str> = line.trim_end().split(':').collect();
+            if fields.len() != 7 || fields[0] != name {
+                return None;
+            }
+
+            Some(Passwd {
+                name: name.to_string(),
+                name_bytes: None,
+                passwd: fields[1].to_string(),
+                uid: fields[2].parse().ok()?,
+                gid: fields[3].parse().ok()?,
+                gecos: fields[4].to_string(),
+                dir: fields[5].to_string(),
+                shell: fields[6].to_string(),
+            })
+        }
+        Record::Hash(mut fields) => Some(Passwd {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: fields.remove("passwd").unwrap_or_else(|| "x".to_string()),
+            uid: fields.get("uid")?.parse().ok()?,
+            gid: fields.get("gid")?.parse().ok()?,
+            gecos: fields.remove("gecos").unwrap_or_default(),
+            dir: fields.remove("dir").unwrap_or_default(),
+            shell: fields.remove("shell").unwrap_or_default(),
+        }),
+    }
+}
+
+/// A group from its record, a line or a hash.
+fn group(name: &str, record: Record) -> Option<Group> {
+    let (passwd, gid, members) = match record {
+        Record::String(line) => {
+            let fields: Vec<&str> = line.trim_end().split(':').collect();
+            if fields.len() != 4 || fields[0] != name {
+                return None;
+            }
+
+            (
+                fields[1].to_string(),
+                fields[2].parse().ok()?,
+                fields[3].to_string(),
+            )
+        }
+        Record::Hash(mut fields) => (
+            fields.remove("passwd").unwrap_or_else(|| "x".to_string()),
+            fields.get("gid")?.parse().ok()?,
+            fields.remove("members").unwrap_or_default(),
+        ),
+    };
+
+    Some(Group {
+        name: name.to_string(),
+        name_bytes: None,
+        passwd,
+        gid,
+        members: members
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn hash(fields: &[(&str, &str)]) -> Record {
+        Record::Hash(
+            fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn line(line: &str) -> Record {
+        Record::String(line.to_string())
+    }
+
+    #[test]
+    fn keys_round_trip_through_their_templates() {
+        let scheme = KeyScheme::default();
+        let key = KeyScheme::key(&scheme.passwd, "alice");
+        assert_eq!(key, "nss:passwd:alice");
+        assert_eq!(
+            KeyScheme::name_from_key(&scheme.passwd, &key),
+            Some("alice")
+        );
+        assert_eq!(KeyScheme::name_from_key(&scheme.group, &key), None);
+
+        assert_eq!(KeyScheme::key("{}.hosts", "db"), "db.hosts");
+        assert_eq!(KeyScheme::name_from_key("{}.hosts", "db.hosts"), Some("db"));
+        assert_eq!(
+            KeyScheme::name_from_key("hosts/{}/v1", "hosts/db/v1"),
+            Some("db")
+        );
+        assert_eq!(KeyScheme::name_from_key("hosts/{}/v1", "hosts/db"), None);
+        assert_eq!(KeyScheme::name_from_key("hosts", "hosts"), None);
+    }
+
+    #[test]
+    fn records_persist() {
+        for record in [
+            line("alice:x:1000:1000::/home/alice:/bin/sh"),
+            line(""),
+            hash(&[]),
+            hash(&[("uid", "1000"), ("gecos", "Alice, Room 1"), ("", "")]),
+        ] {
+            let mut buf = Vec::new();
+            record.encode(&mut buf);
+            assert_eq!(Record::decode(&buf), Some(record));
+        }
+
+        let mut buf = Vec::new();
+        hash(&[("uid", "1000")]).encode(&mut buf);
+        for len in 2..buf.len() {
+            assert_eq!(Record::decode(&buf[..len]), None);
+        }
+        assert_eq!(Record::decode(b""), None);
+        assert_eq!(Record::decode(b"x"), None);
+        // A name without its value
+        assert_eq!(Record::decode(b"h\x01\0\0\0a"), None);
+    }
+
+    #[test]
+    fn hosts() {
+        let record = line("10.0.0.1 fd00::1 not-an-address 10.0.0.2");
+        assert_eq!(
+            host("db", AddressFamily::IPv4, record.clone()).unwrap(),
+            Host {
+                name: "db".to_string(),
+                aliases: Vec::new(),
+                addresses: Addresses::V4(vec![
+                    Ipv4Addr::new(10, 0, 0, 1),
+                    Ipv4Addr::new(10, 0, 0, 2)
+                ]),
+                canonical_name: None,
+            }
+        );
+        assert_eq!(
+            host("db", AddressFamily::IPv6, record.clone())
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::1".parse::<Ipv6Addr>().unwrap()])
+        );
+        assert_eq!(
+            host("db", AddressFamily::Unspecified, record)
+                .unwrap()
+                .addresses,
+            Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)])
+        );
+
+        let record = hash(&[("addresses", "::1"), ("aliases", " db.local  database ")]);
+        let entry = host("db", AddressFamily::Unspecified, record.clone()).unwrap();
+        assert_eq!(entry.aliases, ["db.local", "database"]);
+        assert_eq!(entry.addresses, Addresses::V6(vec![Ipv6Addr::LOCALHOST]));
+        assert_eq!(host("db", AddressFamily::IPv4, record), None);
+
+        assert_eq!(
+            host("db", AddressFamily::IPv4, hash(&[("aliases", "db")])),
+            None
+        );
+        assert_eq!(host("db", AddressFamily::IPv4, line("")), None);
+        assert_eq!(host("db", AddressFamily::Other(17), line("10.0.0.1")), None);
+    }
+
+    #[test]
+    fn passwd_entries() {
+        let alice = Passwd {
+            name: "alice".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(1000),
+            gid: Gid::from_raw(100),
+            gecos: "Alice".to_string(),
+            dir: "/home/alice".to_string(),
+            shell: "/bin/sh".to_string(),
+        };
+        assert_eq!(
+            passwd(
+                "alice",
+                line("alice:x:1000:100:Alice:/home/alice:/bin/sh\n")
+            ),
+            Some(alice.clone())
+        );
+        assert_eq!(
+            passwd(
+                "alice",
+                hash(&[
+                    ("uid", "1000"),
+                    ("gid", "100"),
+                    ("gecos", "Alice"),
+                    ("dir", "/home/alice"),
+                    ("shell", "/bin/sh"),
+                ])
+            ),
+            Some(alice)
+        );
+
+        // The line must be the entry asked for, whole
+        assert_eq!(
+            passwd("bob", line("alice:x:1000:100:Alice:/home/alice:/bin/sh")),
+            None
+        );
+        assert_eq!(
+            passwd("alice", line("alice:x:1000:100:Alice:/home/alice")),
+            None
+        );
+        assert_eq!(
+            passwd("alice", line("alice:x:1000:100:Alice:/home/alice:/bin/sh:")),
+            None
+        );
+        assert_eq!(
+            passwd("alice", line("alice:x:-1:100:Alice:/home/alice:/bin/sh")),
+            None
+        );
+        assert_eq!(passwd("alice", hash(&[("gid", "100")])), None);
+        assert_eq!(
+            passwd("alice", hash(&[("uid", "1000"), ("gid", "staff")])),
+            None
+        );
+
+        let bare = passwd("alice", hash(&[("uid", "1000"), ("gid", "100")])).unwrap();
+        assert_eq!(
+            (bare.passwd.as_str(), bare.dir.as_str(), bare.shell.as_str()),
+            ("x", "", "")
+        );
+    }
+
+    #[test]
+    fn groups() {
+        let staff = Group {
+            name: "staff".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            gid: Gid::from_raw(50),
+            members: vec!["alice".to_string(), "bob".to_string()],
+        };
+        assert_eq!(
+            group("staff", line("staff:x:50:alice,bob\n")),
+            Some(staff.clone())
+        );
+        assert_eq!(
+            group("staff", hash(&[("gid", "50"), ("members", "alice,,bob")])),
+            Some(staff)
+        );
+
+        assert_eq!(
+            group("staff", line("staff:x:50:")).unwrap().members,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            group("staff", hash(&[("gid", "50")])).unwrap().members,
+            Vec::<String>::new()
+        );
+        assert_eq!(group("wheel", line("staff:x:50:alice")), None);
+        assert_eq!(group("staff", line("staff:x:50")), None);
+        assert_eq!(group("staff", line("staff:x:fifty:alice")), None);
+        assert_eq!(group("staff", hash(&[("members", "alice")])), None);
+    }
+}
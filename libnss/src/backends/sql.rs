@@ -0,0 +1,271 @@
+//! What the SQL backends share: the rows their queries return, and reading entries from them.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::passwd::Passwd;
+use crate::stale::Persist;
+
+/// What a query's parameters are bound to.
+pub(super) enum Param {
+    None,
+    Name(String),
+    Id(u32),
+    Addr(IpAddr),
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Param::None => Ok(()),
+            Param::Name(name) => name.fmt(f),
+            Param::Id(id) => id.fmt(f),
+            Param::Addr(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// The rows a query returned, with every column read as text.
+#[derive(Clone, PartialEq)]
+pub(super) struct Rows(pub(super) Vec<Vec<String>>);
+
+/// Each row's field count, then each field prefixed by its length.
+impl Persist for Rows {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for row in &self.0 {
+            buf.extend_from_slice(&(row.len() as u32).to_le_bytes());
+            for field in row {
+                buf.extend_from_slice(&(field.len() as u32).to_le_bytes());
+                buf.extend_from_slice(field.as_bytes());
+            }
+        }
+    }
+
+    fn decode(mut bytes: &[u8]) -> Option<Self> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if bytes.len() < len {
+                return None;
+            }
+            let (head, tail) = bytes.split_at(len);
+            *bytes = tail;
+            Some(head)
+        }
+        fn take_len(bytes: &mut &[u8]) -> Option<usize> {
+            Some(u32::from_le_bytes(take(bytes, 4)?.try_into().ok()?) as usize)
+        }
+
+        let mut rows = Vec::new();
+        while !bytes.is_empty() {
+            let fields = take_len(&mut bytes)?;
+            let mut row = Vec::with_capacity(fields.min(16));
+            for _ in 0..fields {
+                let len = take_len(&mut bytes)?;
+                row.push(String::decode(take(&mut bytes, len)?)?);
+            }
+            rows.push(row);
+        }
+        Some(Rows(rows))
+    }
+}
+fn list(field: &str) -> impl Iterator<Item = &str> {
+    field
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+}
+
+pub(super) fn host(row: &[String], family: AddressFamily) -> Option<Host> {
+    let (name, aliases, addresses) = match row {
+        [name, aliases, addresses] => (name, aliases, addresses),
+        _ => return None,
+    };
+
+    let addresses: Vec<IpAddr> = list(addresses).filter_map(|a| a.parse().ok()).collect();
+    let v4: Vec<_> = addresses
+        .iter()
+        .filter_map(|a| match a {
+            IpAddr::V4(a) => Some(*a),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+    let v6: Vec<_> = addresses
+        .iter()
+        .filter_map(|a| match a {
+            IpAddr::V6(a) => Some(*a),
+            IpAddr::V4(_) => None,
+        })
+        .collect();
+
+    let addresses = match family {
+        AddressFamily::IPv4 if !v4.is_empty() => Addresses::V4(v4),
+        AddressFamily::IPv6 if !v6.is_empty() => Addresses::V6(v6),
+        AddressFamily::Unspecified if !v4.is_empty() => Addresses::V4(v4),
+        AddressFamily::Unspecified if !v6.is_empty() => Addresses::V6(v6),
+        _ => return None,
+    };
+
+    Some(Host {
+        name: name.to_string(),
+        aliases: list(aliases).map(str::to_string).collect(),
+        addresses,
+        canonical_name: None,
+    })
+}
+
+pub(super) fn passwd(row: &[String]) -> Option<Passwd> {
+    match row {
+        [name, passwd, uid, gid, gecos, dir, shell] => Some(Passwd {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: or_x(passwd),
+            uid: uid.parse().ok()?,
+            gid: gid.parse().ok()?,
+            gecos: gecos.to_string(),
+            dir: dir.to_string(),
+            shell: shell.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+pub(super) fn group(row: &[String]) -> Option<Group> {
+    match row {
+        [name, passwd, gid, members] => Some(Group {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: or_x(passwd),
+            gid: gid.parse().ok()?,
+            members: list(members).map(str::to_string).collect(),
+        }),
+        _ => None,
+    }
+}
+
+fn or_x(passwd: &str) -> String {
+    if passwd.is_empty() {
+        "x".to_string()
+    } else {
+        passwd.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::{Gid, Uid};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn row(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|field| field.to_string()).collect()
+    }
+
+    #[test]
+    fn rows_persist() {
+        for rows in [
+            Rows(Vec::new()),
+            Rows(vec![Vec::new()]),
+            Rows(vec![row(&["db", "", "10.0.0.1"]), row(&["café", "x"])]),
+        ] {
+            let mut buf = Vec::new();
+            rows.encode(&mut buf);
+            assert!(Rows::decode(&buf) == Some(rows));
+        }
+
+        let mut buf = Vec::new();
+        Rows(vec![row(&["alice", "x"])]).encode(&mut buf);
+        for len in 1..buf.len() {
+            assert!(Rows::decode(&buf[..len]).is_none());
+        }
+    }
+
+    #[test]
+    fn hosts() {
+        let db = row(&["db", "db.local, database", "10.0.0.1,fd00::1 10.0.0.2 junk"]);
+        assert_eq!(
+            host(&db, AddressFamily::IPv4),
+            Some(Host {
+                name: "db".to_string(),
+                aliases: vec!["db.local".to_string(), "database".to_string()],
+                addresses: Addresses::V4(vec![
+                    Ipv4Addr::new(10, 0, 0, 1),
+                    Ipv4Addr::new(10, 0, 0, 2)
+                ]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            host(&db, AddressFamily::IPv6).unwrap().addresses,
+            Addresses::V6(vec!["fd00::1".parse().unwrap()])
+        );
+
+        let v6 = row(&["db", "", "::1"]);
+        assert_eq!(
+            host(&v6, AddressFamily::Unspecified).unwrap().addresses,
+            Addresses::V6(vec![Ipv6Addr::LOCALHOST])
+        );
+        assert_eq!(host(&v6, AddressFamily::IPv4), None);
+        assert_eq!(host(&v6, AddressFamily::Other(17)), None);
+        assert_eq!(
+            host(&row(&["db", "", ""]), AddressFamily::Unspecified),
+            None
+        );
+        assert_eq!(host(&row(&["db", "10.0.0.1"]), AddressFamily::IPv4), None);
+    }
+
+    #[test]
+    fn passwd_rows() {
+        assert_eq!(
+            passwd(&row(&[
+                "alice",
+                "",
+                "1000",
+                "100",
+                "Alice",
+                "/home/alice",
+                "/bin/sh"
+            ])),
+            Some(Passwd {
+                name: "alice".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                uid: Uid::from_raw(1000),
+                gid: Gid::from_raw(100),
+                gecos: "Alice".to_string(),
+                dir: "/home/alice".to_string(),
+                shell: "/bin/sh".to_string(),
+            })
+        );
+        let hashed = passwd(&row(&["alice", "$6$salt$hash", "1000", "100", "", "", ""]));
+        assert_eq!(hashed.unwrap().passwd, "$6$salt$hash");
+
+        assert_eq!(passwd(&row(&["alice", "x", "1000", "100", "", ""])), None);
+        assert_eq!(passwd(&row(&["alice", "x", "", "100", "", "", ""])), None);
+        assert_eq!(passwd(&row(&["alice", "x", "-1", "100", "", "", ""])), None);
+        assert_eq!(
+            passwd(&row(&["alice", "x", "1000", "4294967296", "", "", ""])),
+            None
+        );
+    }
+
+    #[test]
+    fn group_rows() {
+        assert_eq!(
+            group(&row(&["staff", "", "50", "alice, bob,,carol"])),
+            Some(Group {
+                name: "staff".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                gid: Gid::from_raw(50),
+                members: vec!["alice".to_string(), "bob".to_string(), "carol".to_string()],
+            })
+        );
+        assert_eq!(
+            group(&row(&["staff", "x", "50", ""])).unwrap().members,
+            Vec::<String>::new()
+        );
+        assert_eq!(group(&row(&["staff", "x", "50"])), None);
+        assert_eq!(group(&row(&["staff", "x", "staff", ""])), None);
+    }
+}
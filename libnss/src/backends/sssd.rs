@@ -0,0 +1,603 @@
+//! Serves users and groups from sssd's on-disk cache, as a last resort for when sssd itself is
+//! down.
+//!
+//! sssd keeps every user and group it has resolved for a domain in
+//! `/var/lib/sss/db/cache_<domain>.ldb`, a TDB file of LDB records. This backend reads those
+//! files without sssd's help and never writes them, so listing it after `sss` keeps logins working
+//! through an outage of sssd or its identity provider:
+//!
+//! ```text
+//! passwd: files sss example
+//! group:  files sss example
+//! ```
+//!
+//! Entries are served however long ago sssd fetched them, since its own expiry is what stops it
+//! answering in the first place. Group members include those of nested groups, as sssd lists
+//! them. sssd's names are qualified with their domain, e.g. `alice@example.com`; unless
+//! `fully_qualified_names` is set they are answered unqualified, and found either way.
+//!
+//! The cache directory is only readable by root, so other processes need to reach this backend
+//! through daemon mode. Each file is checked at most once per `check_interval` and reloaded
+//! whenever it changes; one that can't be read or parsed keeps serving what was last loaded from
+//! it.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backends::watch::WatchedFile;
+use crate::group::Group;
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+pub struct SssdConfig {
+    pub db_dir: PathBuf,
+    /// The domains to serve, in the order they are searched. Empty, the default, serves every
+    /// `cache_<domain>.ldb` in `db_dir` when the backend is created
+    pub domains: Vec<String>,
+    /// Answers names as `name@domain`, like sssd's `use_fully_qualified_names`
+    pub fully_qualified_names: bool,
+    pub check_interval: Duration,
+}
+
+impl Default for SssdConfig {
+    fn default() -> Self {
+        SssdConfig {
+            db_dir: PathBuf::from("/var/lib/sss/db"),
+            domains: Vec::new(),
+            fully_qualified_names: false,
+            check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    passwd: Vec<Passwd>,
+    groups: Vec<Group>,
+    /// The names sssd knows each of `passwd` and `groups` by, in the same order
+    passwd_names: Vec<String>,
+    group_names: Vec<String>,
+}
+
+struct Domain {
+    name: String,
+    file: WatchedFile<Entries>,
+}
+
+pub struct SssdBackend {
+    domains: Vec<Domain>,
+    fully_qualified_names: bool,
+}
+
+impl SssdBackend {
+    /// Creates the backend; each domain's cache is first read by the first lookup that needs it.
+    pub fn new(config: SssdConfig) -> Self {
+        let db_dir = config.db_dir;
+        let interval = config.check_interval;
+        let mut names = config.domains;
+        if names.is_empty() {
+            names = fs::read_dir(&db_dir)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| {
+                    let file = entry.ok()?.file_name().into_string().ok()?;
+                    let domain = file.strip_prefix("cache_")?.strip_suffix(".ldb")?;
+                    Some(domain.to_string())
+                })
+                .collect();
+            names.sort();
+        }
+
+        SssdBackend {
+            domains: names
+                .into_iter()
+                .map(|name| Domain {
+                    file: WatchedFile::new(db_dir.join(format!("cache_{}.ldb", name)), interval),
+                    name,
+                })
+                .collect(),
+            fully_qualified_names: config.fully_qualified_names,
+        }
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.entries()
+            .iter()
+            .flat_map(|entries| entries.passwd.iter().cloned())
+            .collect()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        self.entries()
+            .iter()
+            .find_map(|entries| entries.passwd.iter().find(|p| p.uid == uid).cloned())
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        self.entries().iter().find_map(|entries| {
+            let (p, _) = entries
+                .passwd
+                .iter()
+                .zip(&entries.passwd_names)
+                .find(|(p, sssd_name)| p.name == name || *sssd_name == name)?;
+            Some(p.clone())
+        })
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.entries()
+            .iter()
+            .flat_map(|entries| entries.groups.iter().cloned())
+            .collect()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        self.entries()
+            .iter()
+            .find_map(|entries| entries.groups.iter().find(|g| g.gid == gid).cloned())
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        self.entries().iter().find_map(|entries| {
+            let (g, _) = entries
+                .groups
+                .iter()
+                .zip(&entries.group_names)
+                .find(|(g, sssd_name)| g.name == name || *sssd_name == name)?;
+            Some(g.clone())
+        })
+    }
+
+    fn entries(&self) -> Vec<Arc<Entries>> {
+        self.domains
+            .iter()
+            .map(|domain| {
+                domain
+                    .file
+                    .get(|path| load(path, &domain.name, self.fully_qualified_names))
+            })
+            .collect()
+    }
+}
+
+fn load(path: &Path, domain: &str, fully_qualified_names: bool) -> io::Result<Entries> {
+    let bytes = fs::read(path)?;
+    let records: Vec<Record> = tdb_values(&bytes)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a TDB file"))?
+        .into_iter()
+        .filter_map(unpack)
+        .collect();
+
+    // Both the name to answer with and the one sssd knows the entry by
+    let names = |sssd_name: &str| -> (String, String) {
+        let name = match sssd_name.rsplit_once('@') {
+            Some((short, suffix))
+                if !fully_qualified_names && suffix.eq_ignore_ascii_case(domain) =>
+            {
+                short
+            }
+            _ => sssd_name,
+        };
+        (name.to_string(), sssd_name.to_string())
+    };
+
+    let mut entries = Entries::default();
+    let mut users_by_dn = HashMap::new();
+    let mut groups_by_dn = HashMap::new();
+    for record in &records {
+        if record.is("user") {
+            let (name, sssd_name) = match record.get("name") {
+                Some(sssd_name) => names(sssd_name),
+                None => continue,
+            };
+            let (uid, gid) = match (record.get("uidNumber"), record.get("gidNumber")) {
+                (Some(uid), Some(gid)) => match (uid.parse(), gid.parse()) {
+                    (Ok(uid), Ok(gid)) => (uid, gid),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            users_by_dn.insert(record.dn.to_ascii_lowercase(), name.clone());
+            entries.passwd.push(Passwd {
+                name,
+                name_bytes: None,
+                passwd: "*".to_string(),
+                uid,
+                gid,
+                gecos: record.get("gecos").unwrap_or_default().to_string(),
+                dir: record.get("homeDirectory").unwrap_or_default().to_string(),
+                shell: record.get("loginShell").unwrap_or_default().to_string(),
+            });
+            entries.passwd_names.push(sssd_name);
+        } else if record.is("group") {
+            groups_by_dn.insert(record.dn.to_ascii_lowercase(), record);
+        }
+    }
+
+    for record in records.iter().filter(|record| record.is("group")) {
+        let ((name, sssd_name), gid) = match (record.get("name"), record.get("gidNumber")) {
+            (Some(sssd_name), Some(gid)) => match gid.parse() {
+                Ok(gid) => (names(sssd_name), gid),
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+
+        let mut members = Vec::new();
+        let mut ghosts = Vec::new();
+        let mut seen = HashSet::new();
+        let by_dn = (&users_by_dn, &groups_by_dn);
+        collect_members(record, by_dn, &mut seen, &mut members, &mut ghosts);
+        for (ghost, _) in ghosts.into_iter().map(names) {
+            if !members.contains(&ghost) {
+                members.push(ghost);
+            }
+        }
+
+        entries.groups.push(Group {
+            name,
+            name_bytes: None,
+            passwd: "*".to_string(),
+            gid,
+            members,
+        });
+        entries.group_names.push(sssd_name);
+    }
+    Ok(entries)
+}
+
+/// Adds the names of a group's members, and those of the groups nested in it, to `members`, and
+/// those of members sssd hasn't looked up themselves yet, as it knows them, to `ghosts`.
+fn collect_members<'a>(
+    group: &'a Record,
+    (users_by_dn, groups_by_dn): (&HashMap<String, String>, &HashMap<String, &'a Record>),
+    seen: &mut HashSet<&'a str>,
+    members: &mut Vec<String>,
+    ghosts: &mut Vec<&'a str>,
+) {
+    if !seen.insert(group.dn.as_str()) {
+        return;
+    }
+    ghosts.extend(group.all("ghost"));
+    for dn in group.all("member") {
+        let dn = dn.to_ascii_lowercase();
+        if let Some(user) = users_by_dn.get(&dn) {
+            if !members.contains(user) {
+                members.push(user.clone());
+            }
+        } else if let Some(nested) = groups_by_dn.get(&dn) {
+            collect_members(nested, (users_by_dn, groups_by_dn), seen, members, ghosts);
+        }
+    }
+}
+
+/// An LDB record's DN and attributes, whose names are matched case insensitively as in LDAP.
+struct Record {
+    dn: String,
+    attributes: Vec<(String, Vec<String>)>,
+}
+
+impl Record {
+    fn all<'a>(&'a self, attribute: &'a str) -> impl Iterator<Item = &'a str> {
+        self.attributes
+            .iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(attribute))
+            .flat_map(|(_, values)| values.iter().map(String::as_str))
+    }
+
+    fn get<'a>(&'a self, attribute: &'a str) -> Option<&'a str> {
+        self.all(attribute).next()
+    }
+
+    /// Whether sssd stored this as a `user` or `group`, which it records as the `objectCategory`
+    /// or, before 1.16, the `objectClass`.
+    fn is(&self, category: &str) -> bool {
+        self.all("objectCategory")
+            .chain(self.all("objectClass"))
+            .any(|value| value.eq_ignore_ascii_case(category))
+    }
+}
+
+const TDB_MAGIC_FOOD: &[u8] = b"TDB file\n";
+const TDB_VERSION: u32 = 0x2601_1967 + 6;
+const TDB_MAGIC: u32 = 0x2601_1999;
+const TDB_HEADER_LEN: usize = 168;
+const TDB_RECORD_HEADER_LEN: usize = 24;
+
+/// The values of every live record in a TDB file, found by walking the records from the end of
+/// the hash table rather than through it. TDB files are in their writer's byte order.
+fn tdb_values(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    if !bytes.starts_with(TDB_MAGIC_FOOD) {
+        return None;
+    }
+    let word =
+        |offset: usize| -> Option<[u8; 4]> { bytes.get(offset..offset + 4)?.try_into().ok() };
+    let read: fn([u8; 4]) -> u32 = match word(32)? {
+        version if u32::from_le_bytes(version) == TDB_VERSION => u32::from_le_bytes,
+        version if u32::from_be_bytes(version) == TDB_VERSION => u32::from_be_bytes,
+        _ => return None,
+    };
+    let u32_at = |offset: usize| Some(read(word(offset)?) as usize);
+
+    let mut values = Vec::new();
+    let mut offset = TDB_HEADER_LEN.checked_add(u32_at(36)?.checked_add(1)?.checked_mul(4)?)?;
+    while offset + TDB_RECORD_HEADER_LEN <= bytes.len() {
+        let (rec_len, key_len, data_len) = (
+            u32_at(offset + 4)?,
+            u32_at(offset + 8)?,
+            u32_at(offset + 12)?,
+        );
+        let start = offset + TDB_RECORD_HEADER_LEN;
+        let live = u32_at(offset + 20)? == TDB_MAGIC as usize;
+        if live && key_len + data_len <= rec_len {
+            values.extend(bytes.get(start + key_len..start + key_len + data_len));
+        }
+        offset = match start.checked_add(rec_len) {
+            Some(next) if next > offset => next,
+            _ => break,
+        };
+    }
+    Some(values)
+}
+
+const LDB_PACKING_FORMAT: u32 = 0x2601_1967;
+
+/// Parses an LDB record in the packing format sssd's caches are written in: the format and
+/// element count, the DN, then each element's name, value count and values, with lengths and
+/// counts in little endian and strings `NUL` terminated.
+fn unpack(mut data: &[u8]) -> Option<Record> {
+    fn u32_le(data: &mut &[u8]) -> Option<usize> {
+        let (word, rest) = (data.get(..4)?, data.get(4..)?);
+        *data = rest;
+        Some(u32::from_le_bytes(word.try_into().ok()?) as usize)
+    }
+    fn string(data: &mut &[u8], len: Option<usize>) -> Option<String> {
+        let len = match len {
+            Some(len) => len,
+            None => data.iter().position(|&b| b == 0)?,
+        };
+        let value = String::from_utf8_lossy(data.get(..len)?).into_owned();
+        *data = data.get(len + 1..)?;
+        Some(value)
+    }
+
+    if u32_le(&mut data)? != LDB_PACKING_FORMAT as usize {
+        return None;
+    }
+    let elements = u32_le(&mut data)?;
+    let dn = string(&mut data, None)?;
+    // LDB's own records, such as indexes and `@BASEINFO`
+    if dn.starts_with('@') {
+        return None;
+    }
+
+    let mut attributes = Vec::with_capacity(elements.min(64));
+    for _ in 0..elements {
+        let name = string(&mut data, None)?;
+        let count = u32_le(&mut data)?;
+        let mut values = Vec::with_capacity(count.min(64));
+        for _ in 0..count {
+            let len = u32_le(&mut data)?;
+            values.push(string(&mut data, Some(len))?);
+        }
+        attributes.push((name, values));
+    }
+    Some(Record { dn, attributes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An LDB record packed as sssd writes them.
+    fn pack(dn: &str, attributes: &[(&str, &[&str])]) -> Vec<u8> {
+        let mut data = LDB_PACKING_FORMAT.to_le_bytes().to_vec();
+        data.extend(&(attributes.len() as u32).to_le_bytes());
+        data.extend(dn.as_bytes());
+        data.push(0);
+        for (name, values) in attributes {
+            data.extend(name.as_bytes());
+            data.push(0);
+            data.extend(&(values.len() as u32).to_le_bytes());
+            for value in *values {
+                data.extend(&(value.len() as u32).to_le_bytes());
+                data.extend(value.as_bytes());
+                data.push(0);
+            }
+        }
+        data
+    }
+
+    /// A TDB file in `to_bytes`' byte order holding each value under a key of its own, with a
+    /// few bytes of slack after each as TDB leaves.
+    fn tdb(values: &[Vec<u8>], to_bytes: fn(u32) -> [u8; 4]) -> Vec<u8> {
+        let hash_size = 3;
+        let mut file = TDB_MAGIC_FOOD.to_vec();
+        file.resize(32, 0);
+        file.extend(&to_bytes(TDB_VERSION));
+        file.extend(&to_bytes(hash_size));
+        file.resize(TDB_HEADER_LEN + (hash_size as usize + 1) * 4, 0);
+        for (i, value) in values.iter().enumerate() {
+            let key = format!("DN=record{}", i).into_bytes();
+            let slack = 8;
+            file.extend(&to_bytes(0));
+            file.extend(&to_bytes((key.len() + value.len() + slack) as u32));
+            file.extend(&to_bytes(key.len() as u32));
+            file.extend(&to_bytes(value.len() as u32));
+            file.extend(&to_bytes(0));
+            file.extend(&to_bytes(TDB_MAGIC));
+            file.extend(&key);
+            file.extend(value);
+            file.extend(&[0; 8][..slack]);
+        }
+        file
+    }
+
+    fn alice() -> Vec<u8> {
+        pack(
+            "name=alice@example.com,cn=users,cn=example.com,cn=sysdb",
+            &[
+                ("objectCategory", &["user"]),
+                ("name", &["alice@example.com"]),
+                ("uidNumber", &["1000"]),
+                ("gidNumber", &["1000"]),
+                ("homeDirectory", &["/home/alice"]),
+            ],
+        )
+    }
+
+    #[test]
+    fn one_record() {
+        for to_bytes in [u32::to_le_bytes, u32::to_be_bytes] {
+            let file = tdb(&[alice()], to_bytes);
+            assert_eq!(tdb_values(&file), Some(vec![&alice()[..]]));
+        }
+
+        let record = unpack(&alice()).unwrap();
+        assert_eq!(
+            record.dn,
+            "name=alice@example.com,cn=users,cn=example.com,cn=sysdb"
+        );
+        assert!(record.is("USER"));
+        assert_eq!(record.get("UIDNUMBER"), Some("1000"));
+        assert_eq!(record.get("loginShell"), None);
+    }
+
+    #[test]
+    fn truncated_records_are_skipped() {
+        let whole = tdb(&[alice(), alice()], u32::to_le_bytes);
+        // Into the second record's value
+        let file = &whole[..whole.len() - 20];
+        assert_eq!(tdb_values(file), Some(vec![&alice()[..]]));
+        // Into the second record's header
+        let header = whole.len() - 8 - alice().len() - "DN=record1".len() - 10;
+        assert_eq!(tdb_values(&whole[..header]), Some(vec![&alice()[..]]));
+        // Into the header, or the hash table
+        assert_eq!(tdb_values(&whole[..34]), None);
+        assert_eq!(tdb_values(&whole[..TDB_HEADER_LEN]), Some(vec![]));
+
+        let packed = alice();
+        for len in 0..packed.len() {
+            assert!(unpack(&packed[..len]).is_none(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn dead_and_foreign_records_are_skipped() {
+        let mut file = tdb(&[alice(), alice()], u32::to_le_bytes);
+        let magic = TDB_HEADER_LEN + 4 * 4 + 20;
+        file[magic..magic + 4].copy_from_slice(&0xfeed_face_u32.to_le_bytes());
+        assert_eq!(tdb_values(&file), Some(vec![&alice()[..]]));
+
+        let mut not_tdb = tdb(&[alice()], u32::to_le_bytes);
+        not_tdb[0] = b'X';
+        assert_eq!(tdb_values(&not_tdb), None);
+        let mut other_version = tdb(&[alice()], u32::to_le_bytes);
+        other_version[32] ^= 1;
+        assert_eq!(tdb_values(&other_version), None);
+    }
+
+    #[test]
+    fn other_packing_formats_are_skipped() {
+        let mut packed = alice();
+        packed[..4].copy_from_slice(&(LDB_PACKING_FORMAT + 1).to_le_bytes());
+        assert!(unpack(&packed).is_none());
+        packed[..4].copy_from_slice(&LDB_PACKING_FORMAT.to_be_bytes());
+        assert!(unpack(&packed).is_none());
+
+        // LDB's own records
+        assert!(unpack(&pack("@BASEINFO", &[("sequenceNumber", &["4"])])).is_none());
+    }
+
+    #[test]
+    fn lengths_past_the_end_are_refused() {
+        let mut packed = pack("name=x,cn=sysdb", &[("name", &["x"])]);
+        let value_len = packed.len() - 2 - 4;
+        for len in [2, 1000, u32::MAX] {
+            packed[value_len..value_len + 4].copy_from_slice(&len.to_le_bytes());
+            assert!(unpack(&packed).is_none(), "length {}", len);
+        }
+
+        // As are element and value counts that there aren't as many of
+        let mut packed = pack("name=x,cn=sysdb", &[("name", &["x"])]);
+        packed[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(unpack(&packed).is_none());
+        let mut packed = pack("name=x,cn=sysdb", &[("name", &["x"])]);
+        let count = 8 + "name=x,cn=sysdb".len() + 1 + "name".len() + 1;
+        packed[count..count + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(unpack(&packed).is_none());
+
+        // And TDB keys and values longer than the file. A record that is itself longer, with its
+        // value in the file, ends the walk after that value
+        let file = tdb(&[alice()], u32::to_le_bytes);
+        let lengths = TDB_HEADER_LEN + 4 * 4 + 4;
+        for (offset, len) in [(4, u32::MAX), (8, u32::MAX), (8, 1 << 20)] {
+            let mut file = file.clone();
+            file[lengths + offset..lengths + offset + 4].copy_from_slice(&len.to_le_bytes());
+            assert_eq!(
+                tdb_values(&file),
+                Some(vec![]),
+                "length {} at {}",
+                len,
+                offset
+            );
+        }
+        for len in [u32::MAX, 1 << 20] {
+            let mut file = file.clone();
+            file[lengths..lengths + 4].copy_from_slice(&len.to_le_bytes());
+            assert_eq!(tdb_values(&file), Some(vec![&alice()[..]]));
+        }
+        let mut file = file;
+        let hash_size = 36;
+        file[hash_size..hash_size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(tdb_values(&file), Some(vec![]));
+    }
+
+    #[test]
+    fn caches_are_served() {
+        let dir = std::env::temp_dir().join(format!("libnss-sssd-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let admins = pack(
+            "name=admins@example.com,cn=groups,cn=example.com,cn=sysdb",
+            &[
+                ("objectCategory", &["group"]),
+                ("name", &["admins@example.com"]),
+                ("gidNumber", &["2000"]),
+                (
+                    "member",
+                    &["name=alice@example.com,cn=users,cn=example.com,cn=sysdb"],
+                ),
+                ("ghost", &["bob@example.com"]),
+            ],
+        );
+        fs::write(
+            dir.join("cache_example.com.ldb"),
+            tdb(&[alice(), admins], u32::to_le_bytes),
+        )
+        .unwrap();
+
+        let sssd = SssdBackend::new(SssdConfig {
+            db_dir: dir.clone(),
+            ..SssdConfig::default()
+        });
+        let alice = sssd.get_passwd_by_name("alice@example.com").unwrap();
+        assert_eq!(
+            (alice.name.as_str(), alice.uid),
+            ("alice", Uid::from_raw(1000))
+        );
+        assert_eq!(alice.dir, "/home/alice");
+        assert_eq!(
+            sssd.get_passwd_by_uid(Uid::from_raw(1000)).unwrap().name,
+            "alice"
+        );
+        let admins = sssd.get_group_by_name("admins").unwrap();
+        assert_eq!(admins.gid, Gid::from_raw(2000));
+        assert_eq!(admins.members, vec!["alice", "bob"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
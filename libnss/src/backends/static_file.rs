@@ -0,0 +1,502 @@
+//! Serves users, groups and hosts from a JSON or YAML file, for containers and images whose
+//! entries are fixed when they are built.
+//!
+//! The file holds one object with optional `users`, `groups` and `hosts` lists:
+//!
+//! ```yaml
+//! users:
+//!   - name: app
+//!     uid: 1000
+//!     gid: 1000
+//!     gecos: Application  # optional, defaults to ""
+//!     dir: /srv/app       # optional, defaults to "/"
+//!     shell: /bin/sh      # optional, defaults to "/usr/sbin/nologin"
+//!     passwd: x           # optional, defaults to "x"
+//! groups:
+//!   - name: app
+//!     gid: 1000
+//!     members: [app]      # optional
+//!     passwd: x           # optional, defaults to "x"
+//! hosts:
+//!   - name: db
+//!     aliases: [database] # optional
+//!     addresses: [10.0.0.5, "fd00::5"]
+//! ```
+//!
+//! The file is checked at most once per `check_interval` and reloaded whenever it changes. If it
+//! can't be read or any entry in it is invalid, the entries last loaded keep being served.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::backends::watch::WatchedFile;
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+pub struct StaticFileConfig {
+    pub path: PathBuf,
+    /// `None` picks JSON for a `.json` file and YAML for anything else.
+    pub format: Option<Format>,
+    pub check_interval: Duration,
+}
+
+impl Default for StaticFileConfig {
+    fn default() -> Self {
+        StaticFileConfig {
+            path: PathBuf::from("/etc/nss-static.yaml"),
+            format: None,
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    users: Vec<Passwd>,
+    groups: Vec<Group>,
+    hosts: Vec<StaticHost>,
+}
+
+/// A host as written in the file, which may mix address families.
+struct StaticHost {
+    name: String,
+    aliases: Vec<String>,
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<Ipv6Addr>,
+}
+
+pub struct StaticFileBackend {
+    format: Option<Format>,
+    file: WatchedFile<Entries>,
+}
+
+impl StaticFileBackend {
+    /// Creates the backend; the file is first read by the first lookup.
+    pub fn new(config: StaticFileConfig) -> Self {
+        StaticFileBackend {
+            format: config.format,
+            file: WatchedFile::new(config.path, config.check_interval),
+        }
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.entries().users.to_vec()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        let entries = self.entries();
+        entries.users.iter().find(|u| u.uid == uid).cloned()
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        let entries = self.entries();
+        entries.users.iter().find(|u| u.name == name).cloned()
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.entries().groups.to_vec()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        let entries = self.entries();
+        entries.groups.iter().find(|g| g.gid == gid).cloned()
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let entries = self.entries();
+        entries.groups.iter().find(|g| g.name == name).cloned()
+    }
+
+    /// One entry per host and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let entries = self.entries();
+        let mut hosts = Vec::new();
+        for host in &entries.hosts {
+            hosts.extend(host.to_host(AddressFamily::IPv4));
+            hosts.extend(host.to_host(AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches the name or any alias, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let entries = self.entries();
+        let host = entries.hosts.iter().find(|host| {
+            std::iter::once(&host.name)
+                .chain(&host.aliases)
+                .any(|n| n.eq_ignore_ascii_case(name))
+        })?;
+
+        match family {
+            AddressFamily::Unspecified => host
+                .to_host(AddressFamily::IPv4)
+                .or_else(|| host.to_host(AddressFamily::IPv6)),
+            family => host.to_host(family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let entries = self.entries();
+        match addr {
+            IpAddr::V4(addr) => entries
+                .hosts
+                .iter()
+                .find(|host| host.v4.contains(&addr))?
+                .to_host(AddressFamily::IPv4),
+            IpAddr::V6(addr) => entries
+                .hosts
+                .iter()
+                .find(|host| host.v6.contains(&addr))?
+                .to_host(AddressFamily::IPv6),
+        }
+    }
+
+    fn entries(&self) -> Arc<Entries> {
+        self.file.get(|path| load(path, self.format))
+    }
+}
+
+fn load(path: &Path, format: Option<Format>) -> io::Result<Entries> {
+    let contents = fs::read_to_string(path)?;
+    let format = format.unwrap_or_else(|| match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => Format::Json,
+        _ => Format::Yaml,
+    });
+    let document: Value = match format {
+        Format::Json => serde_json::from_str(&contents).map_err(invalid)?,
+        Format::Yaml => serde_yaml::from_str(&contents).map_err(invalid)?,
+    };
+
+    Ok(Entries {
+        users: list(&document, "users", to_passwd)?,
+        groups: list(&document, "groups", to_group)?,
+        hosts: list(&document, "hosts", to_host)?,
+    })
+}
+
+impl StaticHost {
+    fn to_host(&self, family: AddressFamily) -> Option<Host> {
+        let addresses = match family {
+            AddressFamily::IPv4 if !self.v4.is_empty() => Addresses::V4(self.v4.clone()),
+            AddressFamily::IPv6 if !self.v6.is_empty() => Addresses::V6(self.v6.clone()),
+            _ => return None,
+        };
+
+        Some(Host {
+            name: self.name.clone(),
+            aliases: self.aliases.clone(),
+            addresses,
+            canonical_name: None,
+        })
+    }
+}
+
+fn invalid<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn list<T>(document: &Value, key: &str, convert: fn(&Value) -> Option<T>) -> io::Result<Vec<T>> {
+    let records = match document.get(key) {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(Value::Array(records)) => records,
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("`{}` is not a list", key),
+            ))
+        }
+    };
+
+    records
+        .iter()
+        .enumerate()
+        .map(|(index, record)| {
+            convert(record).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("entry {} of `{}` is invalid", index, key),
+                )
+            })
+        })
+        .collect()
+}
+
+fn string(record: &Value, field: &str, default: Option<&str>) -> Option<String> {
+    match record.get(field) {
+        Some(value) => value.as_str().map(str::to_string),
+        None => default.map(str::to_string),
+    }
+}
+
+fn strings(record: &Value, field: &str) -> Option<Vec<String>> {
+    match record.get(field) {
+        Some(value) => value
+            .as_array()?
+            .iter()
+            .map(|v| v.as_str().map(str::to_string))
+            .collect(),
+        None => Some(Vec::new()),
+    }
+}
+
+fn id<T: From<u32>>(record: &Value, field: &str) -> Option<T> {
+    u32::try_from(record.get(field)?.as_u64()?)
+        .ok()
+        .map(T::from)
+}
+
+fn to_passwd(record: &Value) -> Option<Passwd> {
+    Some(Passwd {
+        name: string(record, "name", None)?,
+        name_bytes: None,
+        passwd: string(record, "passwd", Some("x"))?,
+        uid: id(record, "uid")?,
+        gid: id(record, "gid")?,
+        gecos: string(record, "gecos", Some(""))?,
+        dir: string(record, "dir", Some("/"))?,
+        shell: string(record, "shell", Some("/usr/sbin/nologin"))?,
+    })
+}
+
+fn to_group(record: &Value) -> Option<Group> {
+    Some(Group {
+        name: string(record, "name", None)?,
+        name_bytes: None,
+        passwd: string(record, "passwd", Some("x"))?,
+        gid: id(record, "gid")?,
+        members: strings(record, "members")?,
+    })
+}
+
+fn to_host(record: &Value) -> Option<StaticHost> {
+    let mut host = StaticHost {
+        name: string(record, "name", None)?,
+        aliases: strings(record, "aliases")?,
+        v4: Vec::new(),
+        v6: Vec::new(),
+    };
+    for address in strings(record, "addresses")? {
+        match address.parse().ok()? {
+            IpAddr::V4(addr) => host.v4.push(addr),
+            IpAddr::V6(addr) => host.v6.push(addr),
+        }
+    }
+    Some(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("libnss-static-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn backend(path: &Path) -> StaticFileBackend {
+        StaticFileBackend::new(StaticFileConfig {
+            path: path.to_path_buf(),
+            format: None,
+            check_interval: Duration::ZERO,
+        })
+    }
+
+    #[test]
+    fn yaml() {
+        let path = file(
+            "entries.yaml",
+            r#"
+users:
+  - name: app
+    uid: 1000
+    gid: 1000
+  - name: alice
+    uid: 1001
+    gid: 100
+    gecos: Alice
+    dir: /home/alice
+    shell: /bin/sh
+    passwd: "*"
+groups:
+  - name: app
+    gid: 1000
+    members: [app, alice]
+hosts:
+  - name: db
+    aliases: [database]
+    addresses: [10.0.0.5, "fd00::5", 10.0.0.6]
+  - name: v6
+    addresses: ["fd00::7"]
+"#,
+        );
+        let backend = backend(&path);
+
+        assert_eq!(
+            backend.get_passwd_by_name("app"),
+            Some(Passwd {
+                name: "app".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                uid: Uid::from_raw(1000),
+                gid: Gid::from_raw(1000),
+                gecos: String::new(),
+                dir: "/".to_string(),
+                shell: "/usr/sbin/nologin".to_string(),
+            })
+        );
+        let alice = backend.get_passwd_by_uid(Uid::from_raw(1001)).unwrap();
+        assert_eq!(
+            (
+                alice.passwd.as_str(),
+                alice.gecos.as_str(),
+                alice.shell.as_str()
+            ),
+            ("*", "Alice", "/bin/sh")
+        );
+        assert_eq!(
+            backend.get_group_by_gid(Gid::from_raw(1000)),
+            Some(Group {
+                name: "app".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                gid: Gid::from_raw(1000),
+                members: vec!["app".to_string(), "alice".to_string()],
+            })
+        );
+
+        assert_eq!(
+            backend.get_host_by_name("DATABASE", AddressFamily::Unspecified),
+            Some(Host {
+                name: "db".to_string(),
+                aliases: vec!["database".to_string()],
+                addresses: Addresses::V4(vec![
+                    Ipv4Addr::new(10, 0, 0, 5),
+                    Ipv4Addr::new(10, 0, 0, 6)
+                ]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            backend
+                .get_host_by_name("v6", AddressFamily::Unspecified)
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::7".parse().unwrap()])
+        );
+        assert_eq!(backend.get_host_by_name("v6", AddressFamily::IPv4), None);
+        assert_eq!(
+            backend
+                .get_host_by_addr("fd00::5".parse().unwrap())
+                .unwrap()
+                .name,
+            "db"
+        );
+        // db once per family, and v6
+        assert_eq!(backend.get_all_hosts().len(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn json() {
+        let path = file(
+            "entries.json",
+            r#"{"users": [{"name": "app", "uid": 1000, "gid": 1000}], "groups": null}"#,
+        );
+        let backend = backend(&path);
+        assert_eq!(backend.get_all_passwd().len(), 1);
+        assert!(backend.get_all_groups().is_empty());
+        assert!(backend.get_all_hosts().is_empty());
+
+        // Not picked by the extension
+        let yaml = file(
+            "json-in-yaml",
+            r#"{"users": [{"name": "app", "uid": 1, "gid": 1}]}"#,
+        );
+        let json = StaticFileBackend::new(StaticFileConfig {
+            path: yaml.clone(),
+            format: Some(Format::Json),
+            check_interval: Duration::ZERO,
+        });
+        assert_eq!(json.get_all_passwd().len(), 1);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(yaml).unwrap();
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let invalid = |contents: &str| {
+            let path = file("invalid.yaml", contents);
+            let err = load(&path, None).err().map(|err| err.to_string());
+            fs::remove_file(path).unwrap();
+            err
+        };
+
+        assert_eq!(invalid("users: []\n"), None);
+        assert_eq!(
+            invalid("users: {name: app}\n").as_deref(),
+            Some("`users` is not a list")
+        );
+        // Missing, of the wrong type, or out of range
+        for user in [
+            "{uid: 1, gid: 1}",
+            "{name: app, gid: 1}",
+            "{name: app, uid: \"1\", gid: 1}",
+            "{name: app, uid: -1, gid: 1}",
+            "{name: app, uid: 4294967296, gid: 1}",
+            "{name: app, uid: 1, gid: 1, shell: 7}",
+        ] {
+            assert_eq!(
+                invalid(&format!("users: [{{name: ok, uid: 0, gid: 0}}, {}]", user)).as_deref(),
+                Some("entry 1 of `users` is invalid"),
+                "{}",
+                user
+            );
+        }
+        assert_eq!(
+            invalid("groups: [{name: app, gid: 1, members: app}]").as_deref(),
+            Some("entry 0 of `groups` is invalid")
+        );
+        assert_eq!(
+            invalid("hosts: [{name: db, addresses: [10.0.0.5, db.local]}]").as_deref(),
+            Some("entry 0 of `hosts` is invalid")
+        );
+        assert!(invalid("users: [").is_some());
+    }
+
+    #[test]
+    fn invalid_files_keep_the_last_entries() {
+        let path = file("reload.yaml", "users: [{name: app, uid: 1000, gid: 1000}]");
+        let backend = backend(&path);
+        assert!(backend.get_passwd_by_name("app").is_some());
+
+        fs::write(
+            &path,
+            "users: [{name: app, uid: 1000}, {name: bob, uid: 1, gid: 1}]",
+        )
+        .unwrap();
+        assert!(backend.get_passwd_by_name("app").is_some());
+        assert!(backend.get_passwd_by_name("bob").is_none());
+
+        fs::write(&path, "users: [{name: bob, uid: 1001, gid: 1000}]").unwrap();
+        assert!(backend.get_passwd_by_name("app").is_none());
+        assert!(backend.get_passwd_by_name("bob").is_some());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,787 @@
+//! Serves users, groups and hosts declared in TOML, for small fleets whose extra accounts are
+//! easier to review as one file per team or service than as lines in `/etc/passwd`.
+//!
+//! ```toml
+//! include = ["teams", "/etc/nss/web.toml"]  # optional, see below
+//!
+//! [[users]]
+//! name = "app"
+//! uid = 1000
+//! gid = 1000
+//! gecos = "Application"  # optional, defaults to ""
+//! dir = "/srv/app"       # optional, defaults to "/"
+//! shell = "/bin/sh"      # optional, defaults to "/usr/sbin/nologin"
+//! passwd = "x"           # optional, defaults to "x"
+//!
+//! [[groups]]
+//! name = "app"
+//! gid = 1000
+//! members = ["app"]      # optional
+//! passwd = "x"           # optional, defaults to "x"
+//!
+//! [[hosts]]
+//! name = "db"
+//! aliases = ["database"] # optional
+//! addresses = ["10.0.0.5", "fd00::5"]
+//! ```
+//!
+//! Each `include` is a file, or a directory whose `*.toml` files are read in name order, relative
+//! to the including file.
+//!
+//! Everything is checked as it is loaded: a misspelt or misplaced key, a field of the wrong type,
+//! a character the entry formats can't hold, an unparseable address, or a user, group or host
+//! defined twice rejects the whole file with an error naming the file and entry at fault, logged
+//! to syslog. [`check`] reports the same errors, to vet a change before it is deployed. Until the
+//! files load again, the entries last loaded keep being served.
+//!
+//! The files are checked at most once per `check_interval` and reloaded whenever any of them
+//! changes.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::toml::{Table, Value};
+
+use crate::audit;
+use crate::backends::watch::WatchedFile;
+use crate::group::Group;
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::validate::Validate;
+
+pub struct TomlConfig {
+    pub path: PathBuf,
+    pub check_interval: Duration,
+}
+
+impl Default for TomlConfig {
+    fn default() -> Self {
+        TomlConfig {
+            path: PathBuf::from("/etc/nss.toml"),
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Why the files couldn't be loaded.
+#[derive(Debug, PartialEq)]
+pub struct TomlError {
+    /// The file at fault
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for TomlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+impl Error for TomlError {}
+
+/// Loads `path` and everything it includes as the backend would, reporting the first problem.
+///
+/// ```
+/// # let dir = std::env::temp_dir().join(format!("libnss-toml-doc-{}", std::process::id()));
+/// # std::fs::create_dir_all(&dir).unwrap();
+/// let path = dir.join("nss.toml");
+/// std::fs::write(&path, "[[users]]\nname = 'app'\nuid = 1000\ngid = 1000\nshel = '/bin/sh'\n")?;
+///
+/// let err = libnss::backends::toml::check(&path).unwrap_err();
+/// assert_eq!(
+///     err.message,
+///     "user `app`: unknown key `shel`, did you mean `shell`?"
+/// );
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn check(path: impl AsRef<Path>) -> Result<(), TomlError> {
+    load(path.as_ref(), &mut Vec::new()).map(drop)
+}
+
+#[derive(Default)]
+struct Entries {
+    users: Vec<Passwd>,
+    groups: Vec<Group>,
+    hosts: Vec<TomlHost>,
+}
+
+/// A host as written in the file, which may mix address families.
+struct TomlHost {
+    name: String,
+    aliases: Vec<String>,
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<Ipv6Addr>,
+}
+
+pub struct TomlBackend {
+    file: WatchedFile<Entries>,
+}
+
+impl TomlBackend {
+    /// Creates the backend; the files are first read by the first lookup.
+    pub fn new(config: TomlConfig) -> Self {
+        TomlBackend {
+            file: WatchedFile::new(config.path, config.check_interval),
+        }
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        self.entries().users.to_vec()
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        let entries = self.entries();
+        entries.users.iter().find(|u| u.uid == uid).cloned()
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        let entries = self.entries();
+        entries.users.iter().find(|u| u.name == name).cloned()
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        self.entries().groups.to_vec()
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        let entries = self.entries();
+        entries.groups.iter().find(|g| g.gid == gid).cloned()
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let entries = self.entries();
+        entries.groups.iter().find(|g| g.name == name).cloned()
+    }
+
+    /// One entry per host and address family.
+    pub fn get_all_hosts(&self) -> Vec<Host> {
+        let entries = self.entries();
+        let mut hosts = Vec::new();
+        for host in &entries.hosts {
+            hosts.extend(host.to_host(AddressFamily::IPv4));
+            hosts.extend(host.to_host(AddressFamily::IPv6));
+        }
+        hosts
+    }
+
+    /// Matches the name or any alias, ignoring case. An unspecified family prefers IPv4.
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> Option<Host> {
+        let entries = self.entries();
+        let host = entries.hosts.iter().find(|host| {
+            std::iter::once(&host.name)
+                .chain(&host.aliases)
+                .any(|n| n.eq_ignore_ascii_case(name))
+        })?;
+
+        match family {
+            AddressFamily::Unspecified => host
+                .to_host(AddressFamily::IPv4)
+                .or_else(|| host.to_host(AddressFamily::IPv6)),
+            family => host.to_host(family),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> Option<Host> {
+        let entries = self.entries();
+        match addr {
+            IpAddr::V4(addr) => entries
+                .hosts
+                .iter()
+                .find(|host| host.v4.contains(&addr))?
+                .to_host(AddressFamily::IPv4),
+            IpAddr::V6(addr) => entries
+                .hosts
+                .iter()
+                .find(|host| host.v6.contains(&addr))?
+                .to_host(AddressFamily::IPv6),
+        }
+    }
+
+    fn entries(&self) -> Arc<Entries> {
+        self.file.get_with_includes(|path, includes| {
+            load(path, includes).map_err(|err| {
+                audit::syslog(libc::LOG_ERR, &format!("libnss: {}", err));
+                io::Error::new(io::ErrorKind::InvalidData, err)
+            })
+        })
+    }
+}
+
+impl TomlHost {
+    fn to_host(&self, family: AddressFamily) -> Option<Host> {
+        let addresses = match family {
+            AddressFamily::IPv4 if !self.v4.is_empty() => Addresses::V4(self.v4.clone()),
+            AddressFamily::IPv6 if !self.v6.is_empty() => Addresses::V6(self.v6.clone()),
+            _ => return None,
+        };
+
+        Some(Host {
+            name: self.name.clone(),
+            aliases: self.aliases.clone(),
+            addresses,
+            canonical_name: None,
+        })
+    }
+}
+
+fn load(path: &Path, includes: &mut Vec<PathBuf>) -> Result<Entries, TomlError> {
+    let mut loader = Loader {
+        entries: Entries::default(),
+        defined: HashMap::new(),
+        stack: Vec::new(),
+        includes,
+    };
+    loader.file(path)?;
+    Ok(loader.entries)
+}
+
+struct Loader<'a> {
+    entries: Entries,
+    /// The file each user, group and host name was first defined in
+    defined: HashMap<(&'static str, String), PathBuf>,
+    /// The files being read, each included by the one before
+    stack: Vec<PathBuf>,
+    /// Every file and directory read besides the first
+    includes: &'a mut Vec<PathBuf>,
+}
+
+impl Loader<'_> {
+    fn file(&mut self, path: &Path) -> Result<(), TomlError> {
+        let error = |message: String| TomlError {
+            path: path.to_path_buf(),
+            message,
+        };
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if self.stack.contains(&canonical) {
+            return Err(error("is included by a file it includes".to_string()));
+        }
+
+        let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+        let table: Table = text
+            .parse()
+            .map_err(|err: ::toml::de::Error| error(err.to_string().trim_end().to_string()))?;
+        let document = Fields {
+            path,
+            at: "top level".to_string(),
+            table: &table,
+        };
+        document.known(&["include", "users", "groups", "hosts"])?;
+
+        for user in document.tables("users")? {
+            let user = to_passwd(&user.named("user")?)?;
+            self.define(path, "user", &user.name)?;
+            self.entries.users.push(user);
+        }
+        for group in document.tables("groups")? {
+            let group = to_group(&group.named("group")?)?;
+            self.define(path, "group", &group.name)?;
+            self.entries.groups.push(group);
+        }
+        for host in document.tables("hosts")? {
+            let host = to_host(&host.named("host")?)?;
+            self.define(path, "host", &host.name)?;
+            self.entries.hosts.push(host);
+        }
+
+        self.stack.push(canonical);
+        let base = path.parent().unwrap_or_else(|| Path::new("/"));
+        for include in document.strings("include")? {
+            let include = base.join(include);
+            self.includes.push(include.clone());
+            if include.is_dir() {
+                let mut files: Vec<PathBuf> = fs::read_dir(&include)
+                    .map_err(|err| error(format!("`{}`: {}", include.display(), err)))?
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|file| file.extension().is_some_and(|ext| ext == "toml"))
+                    .collect();
+                files.sort();
+                for file in files {
+                    self.includes.push(file.clone());
+                    self.file(&file)?;
+                }
+            } else {
+                self.file(&include)?;
+            }
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn define(&mut self, path: &Path, kind: &'static str, name: &str) -> Result<(), TomlError> {
+        if let Some(first) = self.defined.get(&(kind, name.to_string())) {
+            return Err(TomlError {
+                path: path.to_path_buf(),
+                message: format!(
+                    "{} `{}` is already defined in {}",
+                    kind,
+                    name,
+                    first.display()
+                ),
+            });
+        }
+        self.defined
+            .insert((kind, name.to_string()), path.to_path_buf());
+        Ok(())
+    }
+}
+
+/// A table being read, with where it is in its file for error messages.
+struct Fields<'a> {
+    path: &'a Path,
+    at: String,
+    table: &'a Table,
+}
+
+impl<'a> Fields<'a> {
+    fn error(&self, message: impl fmt::Display) -> TomlError {
+        TomlError {
+            path: self.path.to_path_buf(),
+            message: format!("{}: {}", self.at, message),
+        }
+    }
+
+    /// Rejects any key not in `known`, suggesting the one it was likely meant to be.
+    fn known(&self, known: &[&str]) -> Result<(), TomlError> {
+        for key in self.table.keys() {
+            if known.contains(&key.as_str()) {
+                continue;
+            }
+            let suggestion = known
+                .iter()
+                .map(|k| (distance(k, key), k))
+                .filter(|(d, _)| *d <= 2)
+                .min();
+            return Err(match suggestion {
+                Some((_, k)) => self.error(format!("unknown key `{}`, did you mean `{}`?", key, k)),
+                None => self.error(format!(
+                    "unknown key `{}`, expected one of `{}`",
+                    key,
+                    known.join("`, `")
+                )),
+            });
+        }
+        Ok(())
+    }
+
+    /// The `[[key]]` tables, each placed at e.g. `users[2]`.
+    fn tables(&self, key: &str) -> Result<Vec<Fields<'a>>, TomlError> {
+        let values = match self.table.get(key) {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(values)) => values,
+            Some(_) => return Err(self.error(format!("`{}` must be a list of tables", key))),
+        };
+
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let at = format!("{}[{}]", key, index);
+                match value {
+                    Value::Table(table) => Ok(Fields {
+                        path: self.path,
+                        at,
+                        table,
+                    }),
+                    _ => Err(self.error(format!("`{}` must be a table", at))),
+                }
+            })
+            .collect()
+    }
+
+    /// Names the table after its `name` in later errors, e.g. "user `app`".
+    fn named(self, kind: &str) -> Result<Self, TomlError> {
+        let name = self.string("name", None)?;
+        Ok(Fields {
+            at: format!("{} `{}`", kind, name),
+            ..self
+        })
+    }
+
+    fn string(&self, key: &str, default: Option<&str>) -> Result<String, TomlError> {
+        match (self.table.get(key), default) {
+            (Some(Value::String(value)), _) => Ok(value.clone()),
+            (Some(value), _) => {
+                Err(self.error(format!("`{}` must be a string, not {}", key, value)))
+            }
+            (None, Some(default)) => Ok(default.to_string()),
+            (None, None) => Err(self.error(format!("missing `{}`", key))),
+        }
+    }
+
+    fn strings(&self, key: &str) -> Result<Vec<String>, TomlError> {
+        let wrong = || self.error(format!("`{}` must be a list of strings", key));
+        match self.table.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| value.as_str().map(str::to_string).ok_or_else(wrong))
+                .collect(),
+            Some(_) => Err(wrong()),
+        }
+    }
+
+    fn id<T: From<u32>>(&self, key: &str) -> Result<T, TomlError> {
+        match self.table.get(key) {
+            Some(Value::Integer(id)) => u32::try_from(*id).map(T::from).map_err(|_| {
+                self.error(format!(
+                    "`{}` must be from 0 to {}, not {}",
+                    key,
+                    u32::MAX,
+                    id
+                ))
+            }),
+            Some(value) => Err(self.error(format!("`{}` must be a number, not {}", key, value))),
+            None => Err(self.error(format!("missing `{}`", key))),
+        }
+    }
+}
+
+/// How many single character edits turn `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn to_passwd(fields: &Fields) -> Result<Passwd, TomlError> {
+    fields.known(&["name", "uid", "gid", "gecos", "dir", "shell", "passwd"])?;
+    let user = Passwd {
+        name: fields.string("name", None)?,
+        name_bytes: None,
+        passwd: fields.string("passwd", Some("x"))?,
+        uid: fields.id("uid")?,
+        gid: fields.id("gid")?,
+        gecos: fields.string("gecos", Some(""))?,
+        dir: fields.string("dir", Some("/"))?,
+        shell: fields.string("shell", Some("/usr/sbin/nologin"))?,
+    };
+    user.validate().map_err(|err| fields.error(err))?;
+    Ok(user)
+}
+
+fn to_group(fields: &Fields) -> Result<Group, TomlError> {
+    fields.known(&["name", "gid", "members", "passwd"])?;
+    let group = Group {
+        name: fields.string("name", None)?,
+        name_bytes: None,
+        passwd: fields.string("passwd", Some("x"))?,
+        gid: fields.id("gid")?,
+        members: fields.strings("members")?,
+    };
+    group.validate().map_err(|err| fields.error(err))?;
+    Ok(group)
+}
+
+fn to_host(fields: &Fields) -> Result<TomlHost, TomlError> {
+    fields.known(&["name", "aliases", "addresses"])?;
+    let mut host = TomlHost {
+        name: fields.string("name", None)?,
+        aliases: fields.strings("aliases")?,
+        v4: Vec::new(),
+        v6: Vec::new(),
+    };
+    let addresses = fields.strings("addresses")?;
+    if addresses.is_empty() {
+        return Err(fields.error("needs at least one of `addresses`"));
+    }
+    for address in addresses {
+        match address.parse() {
+            Ok(IpAddr::V4(addr)) => host.v4.push(addr),
+            Ok(IpAddr::V6(addr)) => host.v6.push(addr),
+            Err(_) => {
+                return Err(fields.error(format!("`{}` is not an IP address", address)));
+            }
+        }
+    }
+
+    let checked = Host {
+        name: host.name.clone(),
+        aliases: host.aliases.clone(),
+        addresses: Addresses::V4(Vec::new()),
+        canonical_name: None,
+    };
+    checked.validate().map_err(|err| fields.error(err))?;
+    Ok(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("libnss-toml-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn backend(path: &Path) -> TomlBackend {
+        TomlBackend::new(TomlConfig {
+            path: path.to_path_buf(),
+            check_interval: Duration::ZERO,
+        })
+    }
+
+    /// The error loading `contents` as the only file, without its path.
+    fn error(contents: &str) -> String {
+        let dir = dir("error");
+        let path = dir.join("nss.toml");
+        fs::write(&path, contents).unwrap();
+        let err = check(&path).unwrap_err();
+        assert_eq!(err.path, path);
+        fs::remove_dir_all(&dir).unwrap();
+        err.message
+    }
+
+    #[test]
+    fn entries() {
+        let dir = dir("entries");
+        let path = dir.join("nss.toml");
+        fs::write(
+            &path,
+            r#"
+[[users]]
+name = "app"
+uid = 1000
+gid = 1000
+
+[[users]]
+name = "alice"
+uid = 1001
+gid = 100
+gecos = "Alice"
+dir = "/home/alice"
+shell = "/bin/sh"
+passwd = "*"
+
+[[groups]]
+name = "app"
+gid = 1000
+members = ["app", "alice"]
+
+[[hosts]]
+name = "db"
+aliases = ["database"]
+addresses = ["10.0.0.5", "fd00::5", "10.0.0.6"]
+"#,
+        )
+        .unwrap();
+        let toml = backend(&path);
+
+        assert_eq!(
+            toml.get_passwd_by_name("app"),
+            Some(Passwd {
+                name: "app".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                uid: Uid::from_raw(1000),
+                gid: Gid::from_raw(1000),
+                gecos: String::new(),
+                dir: "/".to_string(),
+                shell: "/usr/sbin/nologin".to_string(),
+            })
+        );
+        let alice = toml.get_passwd_by_uid(Uid::from_raw(1001)).unwrap();
+        assert_eq!(
+            (
+                alice.passwd.as_str(),
+                alice.dir.as_str(),
+                alice.shell.as_str()
+            ),
+            ("*", "/home/alice", "/bin/sh")
+        );
+        assert_eq!(
+            toml.get_group_by_gid(Gid::from_raw(1000)).unwrap().members,
+            ["app", "alice"]
+        );
+        assert_eq!(toml.get_group_by_name("app").unwrap().passwd, "x");
+
+        assert_eq!(
+            toml.get_host_by_name("Database", AddressFamily::Unspecified),
+            Some(Host {
+                name: "db".to_string(),
+                aliases: vec!["database".to_string()],
+                addresses: Addresses::V4(vec![
+                    Ipv4Addr::new(10, 0, 0, 5),
+                    Ipv4Addr::new(10, 0, 0, 6)
+                ]),
+                canonical_name: None,
+            })
+        );
+        assert_eq!(
+            toml.get_host_by_addr("fd00::5".parse().unwrap())
+                .unwrap()
+                .addresses,
+            Addresses::V6(vec!["fd00::5".parse().unwrap()])
+        );
+        assert_eq!(toml.get_all_hosts().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn includes() {
+        let dir = dir("includes");
+        let path = dir.join("nss.toml");
+        fs::create_dir_all(dir.join("teams")).unwrap();
+        fs::write(
+            &path,
+            "include = ['teams', 'web.toml']\n[[users]]\nname = 'root-app'\nuid = 1\ngid = 1\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("web.toml"),
+            "[[hosts]]\nname = 'web'\naddresses = ['10.0.0.8']\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("teams/b.toml"),
+            "[[users]]\nname = 'bob'\nuid = 3\ngid = 3\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("teams/a.toml"),
+            "[[users]]\nname = 'alice'\nuid = 2\ngid = 2\n",
+        )
+        .unwrap();
+        fs::write(dir.join("teams/notes.txt"), "not toml").unwrap();
+
+        let mut includes = Vec::new();
+        let entries = load(&path, &mut includes).unwrap();
+        let names: Vec<_> = entries.users.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(names, ["root-app", "alice", "bob"]);
+        assert_eq!(entries.hosts[0].name, "web");
+        assert_eq!(
+            includes,
+            [
+                dir.join("teams"),
+                dir.join("teams/a.toml"),
+                dir.join("teams/b.toml"),
+                dir.join("web.toml")
+            ]
+        );
+
+        // Defined twice, across files
+        fs::write(
+            dir.join("web.toml"),
+            "[[users]]\nname = 'alice'\nuid = 4\ngid = 4\n",
+        )
+        .unwrap();
+        assert_eq!(
+            check(&path).unwrap_err(),
+            TomlError {
+                path: dir.join("web.toml"),
+                message: format!(
+                    "user `alice` is already defined in {}",
+                    dir.join("teams/a.toml").display()
+                ),
+            }
+        );
+
+        // Included by itself
+        fs::write(dir.join("web.toml"), "include = ['nss.toml']\n").unwrap();
+        assert_eq!(
+            check(&path).unwrap_err(),
+            TomlError {
+                path: dir.join("nss.toml"),
+                message: "is included by a file it includes".to_string(),
+            }
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("[[user]]\nname = 'app'\n"),
+            "top level: unknown key `user`, did you mean `users`?"
+        );
+        assert_eq!(
+            error("colour = 'blue'\n"),
+            "top level: unknown key `colour`, expected one of `include`, `users`, `groups`, `hosts`"
+        );
+        assert_eq!(
+            error("users = 'app'\n"),
+            "top level: `users` must be a list of tables"
+        );
+        assert_eq!(
+            error("[[users]]\nuid = 1\ngid = 1\n"),
+            "users[0]: missing `name`"
+        );
+        assert_eq!(
+            error("[[users]]\nname = 'app'\nuid = '1'\ngid = 1\n"),
+            "user `app`: `uid` must be a number, not \"1\""
+        );
+        assert_eq!(
+            error("[[users]]\nname = 'app'\nuid = -1\ngid = 1\n"),
+            "user `app`: `uid` must be from 0 to 4294967295, not -1"
+        );
+        assert_eq!(
+            error("[[users]]\nname = 'app'\nuid = 1\n"),
+            "user `app`: missing `gid`"
+        );
+        assert_eq!(
+            error("[[users]]\nname = 'app'\nuid = 1\ngid = 1\nshell = 7\n"),
+            "user `app`: `shell` must be a string, not 7"
+        );
+        assert!(error("[[users]]\nname = 'a:b'\nuid = 1\ngid = 1\n").starts_with("user `a:b`: "));
+        assert_eq!(
+            error("[[groups]]\nname = 'app'\ngid = 1\nmembers = 'app'\n"),
+            "group `app`: `members` must be a list of strings"
+        );
+        assert_eq!(
+            error("[[hosts]]\nname = 'db'\n"),
+            "host `db`: needs at least one of `addresses`"
+        );
+        assert_eq!(
+            error("[[hosts]]\nname = 'db'\naddresses = ['10.0.0.300']\n"),
+            "host `db`: `10.0.0.300` is not an IP address"
+        );
+        assert!(error("[[users]\n").contains("TOML parse error"));
+    }
+
+    #[test]
+    fn invalid_files_keep_the_last_entries() {
+        let dir = dir("reload");
+        let path = dir.join("nss.toml");
+        fs::write(&path, "[[users]]\nname = 'app'\nuid = 1\ngid = 1\n").unwrap();
+        let toml = backend(&path);
+        assert!(toml.get_passwd_by_name("app").is_some());
+
+        fs::write(
+            &path,
+            "[[users]]\nname = 'bob'\nuid = 2\ngid = 2\nshel = '/bin/sh'\n",
+        )
+        .unwrap();
+        assert!(toml.get_passwd_by_name("app").is_some());
+        assert!(toml.get_passwd_by_name("bob").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(distance("shell", "shell"), 0);
+        assert_eq!(distance("shell", "shel"), 1);
+        assert_eq!(distance("gid", "uid"), 1);
+        assert_eq!(distance("members", "memebrs"), 2);
+        assert_eq!(distance("", "dir"), 3);
+    }
+}
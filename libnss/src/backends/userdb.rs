@@ -0,0 +1,453 @@
+//! Resolves users and groups from systemd's `io.systemd.UserDatabase` varlink services.
+//!
+//! Each service listens on a socket in `/run/systemd/userdb`, named after the service. By default
+//! every socket found there is consulted in directory order, except the multiplexer and the
+//! `io.systemd.NameServiceSwitch` service: the latter answers from NSS itself, so asking it from
+//! inside an NSS module would recurse straight back into us.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufReader};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+
+use crate::group::Group;
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::varlink;
+
+const INTERFACE: &str = "io.systemd.UserDatabase";
+
+pub struct UserDbConfig {
+    pub socket_dir: PathBuf,
+    /// Services to query, in order. `None` queries every socket in `socket_dir` other than the
+    /// ones listed in `excluded_services`.
+    pub services: Option<Vec<String>>,
+    pub excluded_services: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl Default for UserDbConfig {
+    fn default() -> Self {
+        UserDbConfig {
+            socket_dir: PathBuf::from("/run/systemd/userdb"),
+            services: None,
+            excluded_services: vec![
+                "io.systemd.Multiplexer".to_string(),
+                "io.systemd.NameServiceSwitch".to_string(),
+            ],
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+pub struct UserDbBackend {
+    config: UserDbConfig,
+}
+
+impl UserDbBackend {
+    pub fn new(config: UserDbConfig) -> Self {
+        UserDbBackend { config }
+    }
+
+    pub fn get_all_passwd(&self) -> Vec<Passwd> {
+        let mut entries: Vec<Passwd> = Vec::new();
+        for service in self.services() {
+            for reply in self.call_or_empty(&service, "GetUserRecord", Map::new(), true) {
+                if let Some(entry) = to_passwd(&reply) {
+                    if !entries.iter().any(|e| e.name == entry.name) {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+        entries
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> Option<Passwd> {
+        self.lookup("GetUserRecord", "uid", json!(uid.as_raw()), to_passwd)
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> Option<Passwd> {
+        self.lookup("GetUserRecord", "userName", json!(name), to_passwd)
+    }
+
+    pub fn get_all_groups(&self) -> Vec<Group> {
+        let mut entries: Vec<Group> = Vec::new();
+        for service in self.services() {
+            for reply in self.call_or_empty(&service, "GetGroupRecord", Map::new(), true) {
+                if let Some(entry) = to_group(&reply) {
+                    if !entries.iter().any(|e| e.name == entry.name) {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+
+        for entry in entries.iter_mut() {
+            self.add_memberships(entry);
+        }
+        entries
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> Option<Group> {
+        let mut entry = self.lookup("GetGroupRecord", "gid", json!(gid.as_raw()), to_group)?;
+        self.add_memberships(&mut entry);
+        Some(entry)
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> Option<Group> {
+        let mut entry = self.lookup("GetGroupRecord", "groupName", json!(name), to_group)?;
+        self.add_memberships(&mut entry);
+        Some(entry)
+    }
+
+    /// Memberships may be recorded separately from the group record itself, so merge them in.
+    fn add_memberships(&self, group: &mut Group) {
+        let mut parameters = Map::new();
+        parameters.insert("groupName".to_string(), json!(group.name));
+
+        for service in self.services() {
+            for reply in self.call_or_empty(&service, "GetMemberships", parameters.clone(), true) {
+                if let Some(user) = reply.get("userName").and_then(Value::as_str) {
+                    if !group.members.iter().any(|m| m == user) {
+                        group.members.push(user.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    fn lookup<T>(
+        &self,
+        method: &str,
+        field: &str,
+        value: Value,
+        convert: fn(&Value) -> Option<T>,
+    ) -> Option<T> {
+        let mut parameters = Map::new();
+        parameters.insert(field.to_string(), value);
+
+        self.services().iter().find_map(|service| {
+            self.call_or_empty(service, method, parameters.clone(), false)
+                .first()
+                .and_then(convert)
+        })
+    }
+
+    fn services(&self) -> Vec<String> {
+        if let Some(services) = &self.config.services {
+            return services.clone();
+        }
+
+        let mut services: Vec<String> = match fs::read_dir(&self.config.socket_dir) {
+            Ok(dir) => dir
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| !self.config.excluded_services.contains(name))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        services.sort();
+        services
+    }
+
+    /// Calls a method, treating any failure (including "no record found") as no results.
+    fn call_or_empty(
+        &self,
+        service: &str,
+        method: &str,
+        parameters: Map<String, Value>,
+        more: bool,
+    ) -> Vec<Value> {
+        self.call(service, method, parameters, more)
+            .unwrap_or_default()
+    }
+
+    /// Performs a single varlink call and returns the parameters of every reply.
+    fn call(
+        &self,
+        service: &str,
+        method: &str,
+        mut parameters: Map<String, Value>,
+        more: bool,
+    ) -> io::Result<Vec<Value>> {
+        let stream = UnixStream::connect(self.config.socket_dir.join(service))?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+
+        parameters.insert("service".to_string(), json!(service));
+        let mut request = json!({
+            "method": format!("{}.{}", INTERFACE, method),
+            "parameters": parameters,
+        });
+        if more {
+            request["more"] = json!(true);
+        }
+
+        varlink::write_message(&mut &stream, &request)?;
+
+        let mut reader = BufReader::new(&stream);
+        let mut replies = Vec::new();
+        loop {
+            let mut reply = varlink::read_message(&mut reader)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "varlink connection closed")
+            })?;
+            if let Some(error) = reply.get("error").and_then(Value::as_str) {
+                return Err(io::Error::other(error.to_string()));
+            }
+
+            let continues = reply
+                .get("continues")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            replies.push(reply["parameters"].take());
+            if !continues {
+                return Ok(replies);
+            }
+        }
+    }
+}
+
+fn string(record: &Value, field: &str) -> Option<String> {
+    record
+        .get(field)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/// A uid or gid, which JSON can hold out of range.
+fn id(value: &Value) -> Option<u32> {
+    u32::try_from(value.as_u64()?).ok()
+}
+
+fn to_passwd(reply: &Value) -> Option<Passwd> {
+    let record = reply.get("record")?;
+    let uid = id(record.get("uid")?)?;
+    // A user without a gid has a group of its own, with the same id
+    let gid = match record.get("gid") {
+        Some(gid) => id(gid)?,
+        None => uid,
+    };
+
+    Some(Passwd {
+        name: string(record, "userName")?,
+        name_bytes: None,
+        passwd: "x".to_string(),
+        uid: Uid::from_raw(uid),
+        gid: Gid::from_raw(gid),
+        gecos: string(record, "realName").unwrap_or_default(),
+        dir: string(record, "homeDirectory").unwrap_or_else(|| "/".to_string()),
+        shell: string(record, "shell").unwrap_or_else(|| "/bin/sh".to_string()),
+    })
+}
+
+fn to_group(reply: &Value) -> Option<Group> {
+    let record = reply.get("record")?;
+
+    Some(Group {
+        name: string(record, "groupName")?,
+        name_bytes: None,
+        passwd: "x".to_string(),
+        gid: Gid::from_raw(id(record.get("gid")?)?),
+        members: record
+            .get("members")
+            .and_then(Value::as_array)
+            .map(|members| {
+                members
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread;
+
+    fn reply(record: Value) -> Value {
+        json!({ "record": record, "incomplete": false })
+    }
+
+    #[test]
+    fn user_records() {
+        let full = reply(json!({
+            "userName": "alice",
+            "uid": 1000,
+            "gid": 100,
+            "realName": "Alice",
+            "homeDirectory": "/home/alice",
+            "shell": "/bin/zsh",
+            "disposition": "regular",
+        }));
+        assert_eq!(
+            to_passwd(&full),
+            Some(Passwd {
+                name: "alice".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                uid: Uid::from_raw(1000),
+                gid: Gid::from_raw(100),
+                gecos: "Alice".to_string(),
+                dir: "/home/alice".to_string(),
+                shell: "/bin/zsh".to_string(),
+            })
+        );
+
+        let minimal = to_passwd(&reply(json!({ "userName": "svc", "uid": 61184 }))).unwrap();
+        assert_eq!(minimal.gid, Gid::from_raw(61184));
+        assert_eq!(
+            (
+                minimal.gecos.as_str(),
+                minimal.dir.as_str(),
+                minimal.shell.as_str()
+            ),
+            ("", "/", "/bin/sh")
+        );
+    }
+
+    #[test]
+    fn malformed_user_records() {
+        for record in [
+            json!({ "uid": 1000 }),
+            json!({ "userName": "alice" }),
+            json!({ "userName": 1000, "uid": 1000 }),
+            json!({ "userName": "alice", "uid": "1000" }),
+            json!({ "userName": "alice", "uid": -1 }),
+            json!({ "userName": "alice", "uid": 4_294_968_296u64 }),
+            json!({ "userName": "alice", "uid": 1000, "gid": "100" }),
+            json!({ "userName": "alice", "uid": 1000, "gid": 4_294_967_296u64 }),
+        ] {
+            assert_eq!(to_passwd(&reply(record.clone())), None, "{}", record);
+        }
+        assert_eq!(
+            to_passwd(&json!({ "userName": "alice", "uid": 1000 })),
+            None
+        );
+        assert_eq!(to_passwd(&json!(null)), None);
+
+        // Optional fields of the wrong type are left out
+        let alice = to_passwd(&reply(json!({
+            "userName": "alice",
+            "uid": 1000,
+            "realName": ["Alice"],
+            "shell": false,
+        })))
+        .unwrap();
+        assert_eq!(
+            (alice.gecos.as_str(), alice.shell.as_str()),
+            ("", "/bin/sh")
+        );
+    }
+
+    #[test]
+    fn group_records() {
+        assert_eq!(
+            to_group(&reply(json!({
+                "groupName": "wheel",
+                "gid": 10,
+                "members": ["alice", 7, "bob"],
+            }))),
+            Some(Group {
+                name: "wheel".to_string(),
+                name_bytes: None,
+                passwd: "x".to_string(),
+                gid: Gid::from_raw(10),
+                members: vec!["alice".to_string(), "bob".to_string()],
+            })
+        );
+        let empty = to_group(&reply(
+            json!({ "groupName": "empty", "gid": 11, "members": "alice" }),
+        ));
+        assert_eq!(empty.unwrap().members, Vec::<String>::new());
+
+        for record in [
+            json!({ "gid": 10 }),
+            json!({ "groupName": "wheel" }),
+            json!({ "groupName": "wheel", "gid": "10" }),
+            json!({ "groupName": "wheel", "gid": 4_294_967_296u64 }),
+        ] {
+            assert_eq!(to_group(&reply(record.clone())), None, "{}", record);
+        }
+    }
+
+    /// A service answering one call with `replies`, handing back the request it got.
+    fn service(dir: &Path, name: &str, replies: Vec<Value>) -> thread::JoinHandle<Value> {
+        fs::create_dir_all(dir).unwrap();
+        let _ = fs::remove_file(dir.join(name));
+        let listener = UnixListener::bind(dir.join(name)).unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let request = varlink::read_message(&mut BufReader::new(&stream))
+                .unwrap()
+                .unwrap();
+            for reply in replies {
+                varlink::write_message(&mut &stream, &reply).unwrap();
+            }
+            request
+        })
+    }
+
+    #[test]
+    fn calls() {
+        let dir = std::env::temp_dir().join(format!("libnss-userdb-{}", std::process::id()));
+        let backend = UserDbBackend::new(UserDbConfig {
+            socket_dir: dir.clone(),
+            ..UserDbConfig::default()
+        });
+        let user = |name: &str, uid: u32| json!({ "record": { "userName": name, "uid": uid } });
+
+        let answered = service(
+            &dir,
+            "io.example.Users",
+            vec![
+                json!({ "parameters": user("alice", 1000), "continues": true }),
+                json!({ "parameters": user("bob", 1001) }),
+            ],
+        );
+        let replies = backend
+            .call("io.example.Users", "GetUserRecord", Map::new(), true)
+            .unwrap();
+        assert_eq!(replies, [user("alice", 1000), user("bob", 1001)]);
+        assert_eq!(
+            answered.join().unwrap(),
+            json!({
+                "method": "io.systemd.UserDatabase.GetUserRecord",
+                "parameters": { "service": "io.example.Users" },
+                "more": true,
+            })
+        );
+
+        let failed = service(
+            &dir,
+            "io.example.Users",
+            vec![json!({ "error": "io.systemd.UserDatabase.NoRecordFound" })],
+        );
+        let err = backend
+            .call("io.example.Users", "GetUserRecord", Map::new(), false)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "io.systemd.UserDatabase.NoRecordFound");
+        failed.join().unwrap();
+
+        // A service that hangs up mid-stream
+        let cut = service(
+            &dir,
+            "io.example.Users",
+            vec![json!({ "parameters": user("alice", 1000), "continues": true })],
+        );
+        let err = backend
+            .call("io.example.Users", "GetUserRecord", Map::new(), true)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        cut.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
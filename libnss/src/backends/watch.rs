@@ -0,0 +1,109 @@
+//! Keeps the parsed contents of a file current for the file based backends.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+struct State<T> {
+    contents: Arc<T>,
+    /// Modification time and size of the file `contents` came from
+    loaded: Option<(SystemTime, u64)>,
+    /// The same for every other file `contents` were read from
+    includes: Vec<(PathBuf, Option<(SystemTime, u64)>)>,
+    checked: Option<Instant>,
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    fs::metadata(path)
+        .ok()
+        .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())))
+}
+
+/// A file that is checked at most once per `check_interval` and reloaded whenever its
+/// modification time or size has changed. Until the first successful load, and whenever a reload
+/// fails, the last contents loaded are kept.
+pub(crate) struct WatchedFile<T> {
+    path: PathBuf,
+    check_interval: Duration,
+    state: RwLock<State<T>>,
+}
+
+impl<T: Default> WatchedFile<T> {
+    pub(crate) fn new(path: PathBuf, check_interval: Duration) -> Self {
+        WatchedFile {
+            path,
+            check_interval,
+            state: RwLock::new(State {
+                contents: Arc::new(T::default()),
+                loaded: None,
+                includes: Vec::new(),
+                checked: None,
+            }),
+        }
+    }
+
+    /// The current contents, reloading them with `load` first if the file has changed.
+    #[cfg(any(
+        feature = "static_file",
+        feature = "csv",
+        feature = "sssd",
+        feature = "dhcp_leases"
+    ))]
+    pub(crate) fn get<F>(&self, load: F) -> Arc<T>
+    where
+        F: FnOnce(&Path) -> io::Result<T>,
+    {
+        self.get_with_includes(|path, _| load(path))
+    }
+
+    /// Like [`get`](Self::get) for a file that pulls in others: `load` adds every other file or
+    /// directory it read to its second argument, and a change to any of them reloads it too.
+    pub(crate) fn get_with_includes<F>(&self, load: F) -> Arc<T>
+    where
+        F: FnOnce(&Path, &mut Vec<PathBuf>) -> io::Result<T>,
+    {
+        let fresh = |state: &State<T>| {
+            state
+                .checked
+                .is_some_and(|checked| checked.elapsed() < self.check_interval)
+        };
+
+        // Concurrent lookups only share a read lock until a check is due
+        let state = self.state.read().unwrap();
+        if fresh(&state) {
+            return state.contents.clone();
+        }
+        drop(state);
+
+        let mut state = self.state.write().unwrap();
+        if fresh(&state) {
+            return state.contents.clone();
+        }
+        state.checked = Some(Instant::now());
+
+        let main = stamp(&self.path);
+        let changed = main != state.loaded
+            || state
+                .includes
+                .iter()
+                .any(|(path, loaded)| stamp(path) != *loaded);
+        if main.is_some() && changed {
+            // On failure the stamps are left alone, so the next check tries again
+            let mut includes = Vec::new();
+            if let Ok(contents) = load(&self.path, &mut includes) {
+                state.contents = Arc::new(contents);
+                state.loaded = main;
+                state.includes = includes
+                    .into_iter()
+                    .map(|path| {
+                        let stamp = stamp(&path);
+                        (path, stamp)
+                    })
+                    .collect();
+            }
+        }
+        state.contents.clone()
+    }
+}
@@ -0,0 +1,189 @@
+//! Helpers for a module's `build.rs`. Add `libnss` to `[build-dependencies]` too, then:
+//!
+//! ```ignore
+//! fn main() {
+//!     libnss::build::set_soname("example").unwrap();
+//!     libnss::build::export_nss_symbols("example").unwrap();
+//! }
+//! ```
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A linker version script exporting only the entry points NSS looks up for `module`: the
+/// `_nss_<module>_*` functions (including the Solaris `_nss_<module>_<db>_constr` ones) and the
+/// BSDs' `nss_module_register`.
+///
+/// The entries are globs so the script still links when a database or platform hook is absent.
+pub fn version_script(module: &str) -> String {
+    script(&[
+        format!("_nss_{}_*", module),
+        "nss_module_register*".to_string(),
+    ])
+}
+
+/// The libc functions [`libnss_preload!`](crate::libnss_preload) defines.
+const PRELOAD_SYMBOLS: &[&str] = &[
+    "getpwnam*",
+    "getpwuid*",
+    "getgrnam*",
+    "getgrgid*",
+    "getaddrinfo*",
+    "freeaddrinfo*",
+];
+
+/// [`version_script`], also exporting the libc functions [`libnss_preload!`](crate::libnss_preload)
+/// overrides, which a library built for musl has to for the overrides to take effect.
+pub fn preload_version_script(module: &str) -> String {
+    let mut globals = vec![format!("_nss_{}_*", module)];
+    globals.extend(PRELOAD_SYMBOLS.iter().map(|symbol| symbol.to_string()));
+    script(&globals)
+}
+
+fn script(globals: &[String]) -> String {
+    let mut script = "{\n  global:\n".to_string();
+    for global in globals {
+        script.push_str(&format!("    {};\n", global));
+    }
+    script.push_str("  local:\n    *;\n};\n");
+    script
+}
+
+/// Links the cdylib with [`version_script`], so only NSS entry points are exported. Building for
+/// musl it uses [`preload_version_script`] instead, keeping the overrides of
+/// [`libnss_preload!`](crate::libnss_preload).
+///
+/// rustc already keeps Rust internals out of a cdylib's dynamic symbol table, but it exports every
+/// `#[no_mangle]` function from every crate linked in. A module is loaded into arbitrary processes,
+/// where one of those could interpose on a symbol of the same name, so hide everything else.
+///
+/// Only GNU ld compatible linkers take version scripts; on other targets this does nothing.
+pub fn export_nss_symbols(module: &str) -> io::Result<()> {
+    match env::var("CARGO_CFG_TARGET_OS").as_deref() {
+        Ok("linux") | Ok("freebsd") | Ok("netbsd") => {}
+        _ => return Ok(()),
+    }
+
+    let out_dir = env::var_os("OUT_DIR")
+        .ok_or_else(|| io::Error::other("OUT_DIR is not set; call this from build.rs"))?;
+    let path = PathBuf::from(out_dir).join(format!("libnss_{}.map", module));
+    let script = match env::var("CARGO_CFG_TARGET_ENV").as_deref() {
+        Ok("musl") => preload_version_script(module),
+        _ => version_script(module),
+    };
+    fs::write(&path, script)?;
+
+    println!(
+        "cargo:rustc-cdylib-link-arg=-Wl,--version-script={}",
+        path.display()
+    );
+    Ok(())
+}
+
+/// The file name the target's NSS implementation loads `module` from, which is also what its
+/// SONAME has to be.
+pub fn soname(module: &str) -> Option<String> {
+    soname_for(&env::var("CARGO_CFG_TARGET_OS").ok()?, module)
+}
+
+/// As [`soname`], for a `target_os` given rather than read from the build script's environment.
+pub fn soname_for(target_os: &str, module: &str) -> Option<String> {
+    match target_os {
+        "linux" => Some(format!("libnss_{}.so.2", module)),
+        "freebsd" | "illumos" | "solaris" => Some(format!("nss_{}.so.1", module)),
+        "netbsd" => Some(format!("nss_{}.so.0", module)),
+        _ => None,
+    }
+}
+
+/// Checks the crate builds a cdylib named `nss_<module>` and links it with the SONAME from
+/// [`soname`], so the only step left is installing `target/release/libnss_<module>.so` under that
+/// name.
+pub fn set_soname(module: &str) -> io::Result<()> {
+    check_crate(module)?;
+
+    let soname = match soname(module) {
+        Some(soname) => soname,
+        None => return Ok(()),
+    };
+    let flag = match env::var("CARGO_CFG_TARGET_OS").as_deref() {
+        Ok("illumos") | Ok("solaris") => format!("-Wl,-h,{}", soname),
+        _ => format!("-Wl,-soname,{}", soname),
+    };
+    println!("cargo:rustc-cdylib-link-arg={}", flag);
+    Ok(())
+}
+
+/// Fails the build, explaining what to change, unless the `[lib]` section of the crate's manifest
+/// builds a shared library NSS will load for `module`: `crate-type = ["cdylib"]`, and a library
+/// `name` of `nss_<module>` so that cargo builds `libnss_<module>.so`. [`set_soname`] calls this
+/// itself; call it directly to keep the check without setting a SONAME.
+pub fn check_crate(module: &str) -> io::Result<()> {
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").ok_or_else(|| {
+        io::Error::other("CARGO_MANIFEST_DIR is not set; call this from build.rs")
+    })?;
+    let manifest = fs::read_to_string(Path::new(&manifest_dir).join("Cargo.toml"))?;
+
+    let mut name = None;
+    let mut crate_types = None;
+    let mut in_lib = false;
+    let mut lines = manifest.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if line.starts_with('[') {
+            in_lib = line == "[lib]";
+        } else if in_lib {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "name" => name = Some(value.trim_matches('"').to_string()),
+                // An array may span several lines
+                "crate-type" | "crate_type" => {
+                    let mut value = value.to_string();
+                    while !value.contains(']') {
+                        match lines.next() {
+                            Some(line) => value.push_str(line),
+                            None => break,
+                        }
+                    }
+                    crate_types = Some(value);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Without a [lib] name cargo names the library after the package
+    let name = name.unwrap_or_else(|| {
+        env::var("CARGO_PKG_NAME")
+            .unwrap_or_default()
+            .replace('-', "_")
+    });
+    let expected = format!("nss_{}", module);
+    if name != expected {
+        return Err(io::Error::other(format!(
+            "the library is named `{}`, which builds lib{}.so; NSS only loads module `{}` from a \
+             library named `{}`, so set `name = \"{}\"` under [lib] in Cargo.toml",
+            name,
+            name,
+            module,
+            soname(module).unwrap_or_else(|| format!("lib{}.so", expected)),
+            expected
+        )));
+    }
+    match crate_types {
+        Some(types) if types.contains("\"cdylib\"") => Ok(()),
+        Some(types) => Err(io::Error::other(format!(
+            "the library's crate-type is {}, which doesn't build a shared library; NSS modules are \
+             loaded with dlopen, so set `crate-type = [\"cdylib\"]` under [lib] in Cargo.toml",
+            types
+        ))),
+        None => Err(io::Error::other(
+            "the library has no crate-type, so cargo builds an rlib only Rust can link; NSS modules \
+             are loaded with dlopen, so set `crate-type = [\"cdylib\"]` under [lib] in Cargo.toml",
+        )),
+    }
+}
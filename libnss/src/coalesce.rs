@@ -0,0 +1,311 @@
+//! One backend request for a burst of lookups of the same key.
+//!
+//! When a deploy starts fifty workers at once, each looks up the same service user, and each
+//! would otherwise send the backend the same request. [`Coalesced`] wraps any [`NssDatabase`] so
+//! that while a lookup of a key is in flight, lookups of the same key in other threads wait for
+//! its answer rather than making their own. Enumerations are passed straight through.
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! use libnss::coalesce::Coalesced;
+//! use libnss::database::PasswdDatabase;
+//!
+//! // Usable anywhere passwd hooks are, e.g. `libnss_passwd_hooks!(example, ExampleHooks)`
+//! type ExampleHooks = Coalesced<PasswdDatabase<ExamplePasswd>>;
+//! ```
+//!
+//! Hooks can't answer `TryAgain`, so the waiting threads block until the first lookup returns,
+//! however long the backend takes to answer it. If that lookup panics they each make their own.
+//!
+//! Waiters get the first lookup's answer whoever they are, without their own lookup running, so
+//! `Coalesced` must sit inside middleware that looks at the caller or records each lookup, never
+//! outside it. `PrivilegedOnly<Coalesced<D>>` checks every caller before coalescing, whereas
+//! `Coalesced<PrivilegedOnly<D>>` would hand root's in-flight shadow entry to an unprivileged
+//! waiter, and `Coalesced<Audited<D>>` would leave the waiters' lookups out of the audit log:
+//!
+//! ```
+//! # use libnss::shadow::{Shadow, ShadowHooks};
+//! # struct ExampleShadow;
+//! # impl ShadowHooks for ExampleShadow {
+//! #     fn get_all_entries() -> Vec<Shadow> { vec![] }
+//! #     fn get_entry_by_name(_: String) -> Option<Shadow> { None }
+//! # }
+//! use libnss::audit::Audited;
+//! use libnss::coalesce::Coalesced;
+//! use libnss::database::ShadowDatabase;
+//! use libnss::privileged::PrivilegedOnly;
+//!
+//! type ExampleHooks = Audited<PrivilegedOnly<Coalesced<ShadowDatabase<ExampleShadow>>>>;
+//! ```
+
+use std::any::{Any, TypeId};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::database::{Answer, NssDatabase};
+use crate::host::NameForm;
+
+/// The lookups in flight, few enough at any moment to search through.
+static FLIGHTS: Mutex<Vec<Arc<Flight>>> = Mutex::new(Vec::new());
+
+fn flights() -> MutexGuard<'static, Vec<Arc<Flight>>> {
+    FLIGHTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct Flight {
+    /// The wrapped database, which also fixes the type of the answer
+    database: TypeId,
+    key: String,
+    state: Mutex<State>,
+    landed: Condvar,
+}
+
+enum State {
+    InFlight,
+    Landed(Box<dyn Any + Send>),
+    Abandoned,
+}
+
+impl Flight {
+    /// The answer, or `None` if the lookup was abandoned.
+    fn wait<T: Clone + 'static>(&self) -> Option<Answer<T>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match &*state {
+                State::InFlight => {
+                    state = self.landed.wait(state).unwrap_or_else(|e| e.into_inner())
+                }
+                State::Landed(answer) => return answer.downcast_ref::<Answer<T>>().cloned(),
+                State::Abandoned => return None,
+            }
+        }
+    }
+}
+
+/// Held by the thread making a lookup; dropping it unanswered, as a panic does, abandons the
+/// lookup so that no waiter is left waiting.
+struct Landing {
+    flight: Arc<Flight>,
+    answer: Option<Box<dyn Any + Send>>,
+}
+
+impl Drop for Landing {
+    fn drop(&mut self) {
+        // Lookups from here on are new ones
+        flights().retain(|flight| !Arc::ptr_eq(flight, &self.flight));
+        let mut state = self.flight.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = match self.answer.take() {
+            Some(answer) => State::Landed(answer),
+            None => State::Abandoned,
+        };
+        self.flight.landed.notify_all();
+    }
+}
+
+/// A database making at most one lookup of each key at a time. Wrap it in, rather than with,
+/// middleware that depends on who is asking, such as `PrivilegedOnly` and `Audited`.
+pub struct Coalesced<D>(PhantomData<D>);
+
+impl<D> NssDatabase for Coalesced<D>
+where
+    D: NssDatabase + 'static,
+    D::Entry: Clone + Send + 'static,
+    D::Key: fmt::Display,
+{
+    type Entry = D::Entry;
+    type Key = D::Key;
+
+    const ENUMERATION_CACHE_TTL: Duration = D::ENUMERATION_CACHE_TTL;
+    const TRUNCATE_MEMBERS_AT: Option<usize> = D::TRUNCATE_MEMBERS_AT;
+    const RESOLVES_UNSPECIFIED: bool = D::RESOLVES_UNSPECIFIED;
+    const NAME_FORM: NameForm = D::NAME_FORM;
+
+    fn all_entries() -> Vec<D::Entry> {
+        D::all_entries()
+    }
+
+    fn lookup(key: D::Key) -> Answer<D::Entry> {
+        let database = TypeId::of::<D>();
+        let described = key.to_string();
+        let mut landing = {
+            let mut flights = flights();
+            let flight = flights
+                .iter()
+                .find(|flight| flight.database == database && flight.key == described)
+                .cloned();
+            match flight {
+                Some(flight) => {
+                    drop(flights);
+                    if let Some(answer) = flight.wait() {
+                        return answer;
+                    }
+                    return D::lookup(key);
+                }
+                None => {
+                    let flight = Arc::new(Flight {
+                        database,
+                        key: described,
+                        state: Mutex::new(State::InFlight),
+                        landed: Condvar::new(),
+                    });
+                    flights.push(flight.clone());
+                    Landing {
+                        flight,
+                        answer: None,
+                    }
+                }
+            }
+        };
+
+        let answer = D::lookup(key);
+        landing.answer = Some(Box::new(answer.clone()));
+        answer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// A backend whose lookups block until released, one per test as the flights are global.
+    trait Gate: 'static {
+        fn calls() -> &'static AtomicUsize;
+        fn released() -> &'static (Mutex<bool>, Condvar);
+        /// Whether the first lookup panics rather than answering
+        const PANICS_FIRST: bool;
+    }
+
+    struct Gated<G>(PhantomData<G>);
+
+    impl<G: Gate> NssDatabase for Gated<G> {
+        type Entry = String;
+        type Key = String;
+
+        fn all_entries() -> Vec<String> {
+            Vec::new()
+        }
+
+        fn lookup(key: String) -> Answer<String> {
+            let call = G::calls().fetch_add(1, Ordering::SeqCst);
+            let (released, release) = G::released();
+            let mut released = released.lock().unwrap();
+            while !*released {
+                released = release.wait(released).unwrap();
+            }
+            drop(released);
+            if G::PANICS_FIRST && call == 0 {
+                panic!("backend failed");
+            }
+            Answer::Found(format!("{} #{}", key, call))
+        }
+    }
+
+    macro_rules! gate {
+        ($name:ident, $panics:expr) => {
+            struct $name;
+
+            impl Gate for $name {
+                fn calls() -> &'static AtomicUsize {
+                    static CALLS: AtomicUsize = AtomicUsize::new(0);
+                    &CALLS
+                }
+
+                fn released() -> &'static (Mutex<bool>, Condvar) {
+                    static RELEASED: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+                    &RELEASED
+                }
+
+                const PANICS_FIRST: bool = $panics;
+            }
+        };
+    }
+
+    gate!(Answering, false);
+    gate!(Panicking, true);
+    gate!(Separate, false);
+
+    fn release<G: Gate>() {
+        let (released, release) = G::released();
+        *released.lock().unwrap() = true;
+        release.notify_all();
+    }
+
+    /// Spins until a lookup of `D` is in flight with `waiters` threads waiting for it.
+    fn await_waiters<D: 'static>(waiters: usize) {
+        let database = TypeId::of::<D>();
+        loop {
+            let count = flights()
+                .iter()
+                .find(|flight| flight.database == database)
+                .map(Arc::strong_count);
+            // The list and the landing hold one each
+            if count == Some(waiters + 2) {
+                return;
+            }
+            thread::yield_now();
+        }
+    }
+
+    fn lookup<G: Gate>(key: &str) -> thread::JoinHandle<Answer<String>> {
+        let key = key.to_string();
+        thread::spawn(move || Coalesced::<Gated<G>>::lookup(key))
+    }
+
+    #[test]
+    fn waiters_get_the_first_lookups_answer() {
+        let first = lookup::<Answering>("alice");
+        await_waiters::<Gated<Answering>>(0);
+        let second = lookup::<Answering>("alice");
+        await_waiters::<Gated<Answering>>(1);
+
+        release::<Answering>();
+        let answer = Answer::Found("alice #0".to_string());
+        assert_eq!(first.join().unwrap(), answer);
+        assert_eq!(second.join().unwrap(), answer);
+        assert_eq!(Answering::calls().load(Ordering::SeqCst), 1);
+        assert!(flights()
+            .iter()
+            .all(|flight| flight.database != TypeId::of::<Gated<Answering>>()));
+    }
+
+    #[test]
+    fn waiters_look_up_themselves_when_the_first_lookup_panics() {
+        let first = lookup::<Panicking>("alice");
+        await_waiters::<Gated<Panicking>>(0);
+        let second = lookup::<Panicking>("alice");
+        await_waiters::<Gated<Panicking>>(1);
+
+        release::<Panicking>();
+        assert!(first.join().is_err());
+        assert_eq!(
+            second.join().unwrap(),
+            Answer::Found("alice #1".to_string())
+        );
+        assert_eq!(Panicking::calls().load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn different_keys_are_looked_up_apart() {
+        let first = lookup::<Separate>("alice");
+        await_waiters::<Gated<Separate>>(0);
+        let second = lookup::<Separate>("bob");
+        while Separate::calls().load(Ordering::SeqCst) < 2 {
+            thread::yield_now();
+        }
+
+        release::<Separate>();
+        assert!(matches!(first.join().unwrap(), Answer::Found(a) if a.starts_with("alice #")));
+        assert!(matches!(second.join().unwrap(), Answer::Found(b) if b.starts_with("bob #")));
+    }
+}
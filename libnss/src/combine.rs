@@ -0,0 +1,501 @@
+//! One module over several identity sources.
+//!
+//! [`Combined`] serves a database from two others, `A` taking precedence over `B`: a lookup asks
+//! `B` only if `A` finds nothing, and an enumeration lists `A`'s entries and then those of `B`'s
+//! that don't share a name or id with one of them. Lookups leave out the same entries, so that
+//! `B`'s `alice` with uid 1000 isn't found by uid when `A` has an `alice` of its own. Its [`MergeRule`] can combine the two sources'
+//! entries instead, as [`UnionMembers`] does for groups defined in both. More sources nest, and
+//! each database picks its own order:
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::group::{Group, GroupHooks};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # fn group(members: &[&str]) -> Group {
+//! #     Group {
+//! #         name: "admins".to_string(),
+//! #         name_bytes: None,
+//! #         passwd: "x".to_string(),
+//! #         gid: Gid::from_raw(70000),
+//! #         members: members.iter().map(|m| m.to_string()).collect(),
+//! #     }
+//! # }
+//! # struct RestPasswd; struct StaticPasswd; struct EtcPasswd;
+//! # impl PasswdHooks for RestPasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! # impl PasswdHooks for StaticPasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! # impl PasswdHooks for EtcPasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! # struct RestGroup; struct StaticGroup;
+//! # impl GroupHooks for RestGroup {
+//! #     fn get_all_entries() -> Vec<Group> { vec![group(&["alice", "bob"])] }
+//! #     fn get_entry_by_gid(_: Gid) -> Option<Group> { Some(group(&["alice", "bob"])) }
+//! #     fn get_entry_by_name(_: String) -> Option<Group> { Some(group(&["alice", "bob"])) }
+//! # }
+//! # impl GroupHooks for StaticGroup {
+//! #     fn get_all_entries() -> Vec<Group> { vec![group(&["bob", "deploy"])] }
+//! #     fn get_entry_by_gid(_: Gid) -> Option<Group> { Some(group(&["bob", "deploy"])) }
+//! #     fn get_entry_by_name(_: String) -> Option<Group> { Some(group(&["bob", "deploy"])) }
+//! # }
+//! use libnss::combine::{Combined, UnionMembers};
+//! use libnss::database::{GroupDatabase, PasswdDatabase};
+//!
+//! // Usable anywhere hooks are, e.g. `libnss_passwd_hooks!(example, ExamplePasswd)`
+//! type ExamplePasswd = Combined<
+//!     PasswdDatabase<StaticPasswd>,
+//!     Combined<PasswdDatabase<RestPasswd>, PasswdDatabase<EtcPasswd>>,
+//! >;
+//! type ExampleGroup = Combined<GroupDatabase<RestGroup>, GroupDatabase<StaticGroup>, UnionMembers>;
+//!
+//! let admins = ExampleGroup::get_entry_by_name("admins".to_string()).unwrap();
+//! assert_eq!(admins.members, ["alice", "bob", "deploy"]);
+//! assert_eq!(ExampleGroup::get_all_entries().len(), 1);
+//! ```
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::database::{Answer, GroupKey, HostKey, NssDatabase, PasswdKey, ShadowKey};
+use crate::group::Group;
+use crate::host::{Host, NameForm};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+
+/// What makes two sources' entries the same entry: the same name, or the same id.
+pub trait Identity {
+    fn name(&self) -> &str;
+
+    /// The uid or gid, for entries that have one
+    fn id(&self) -> Option<u32>;
+}
+
+impl Identity for Passwd {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Option<u32> {
+        Some(self.uid.as_raw())
+    }
+}
+
+impl Identity for Group {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Option<u32> {
+        Some(self.gid.as_raw())
+    }
+}
+
+impl Identity for Shadow {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl Identity for Host {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// The key that finds an entry by what it wasn't looked up by: its name when looked up by id, and
+/// its id when looked up by name.
+pub trait IdentityKey: Sized {
+    /// `None` when there is no other key, or the database doesn't leave out entries on it
+    fn other_key<E: Identity>(&self, entry: &E) -> Option<Self>;
+}
+
+impl IdentityKey for PasswdKey {
+    fn other_key<E: Identity>(&self, entry: &E) -> Option<Self> {
+        match self {
+            PasswdKey::Uid(_) => Some(PasswdKey::Name(entry.name().to_string())),
+            PasswdKey::Name(_) | PasswdKey::NameBytes(_) => {
+                entry.id().map(|id| PasswdKey::Uid(Uid::from_raw(id)))
+            }
+        }
+    }
+}
+
+impl IdentityKey for GroupKey {
+    fn other_key<E: Identity>(&self, entry: &E) -> Option<Self> {
+        match self {
+            GroupKey::Gid(_) => Some(GroupKey::Name(entry.name().to_string())),
+            GroupKey::Name(_) | GroupKey::NameBytes(_) => {
+                entry.id().map(|id| GroupKey::Gid(Gid::from_raw(id)))
+            }
+        }
+    }
+}
+
+/// Looked up by name alone.
+impl IdentityKey for ShadowKey {
+    fn other_key<E: Identity>(&self, _: &E) -> Option<Self> {
+        None
+    }
+}
+
+/// A host's name and addresses are each answered by whichever source knows them, as a name
+/// lookup for one family says nothing of the other.
+impl IdentityKey for HostKey {
+    fn other_key<E: Identity>(&self, _: &E) -> Option<Self> {
+        None
+    }
+}
+
+/// How an entry found in both sources is answered.
+pub trait MergeRule<E> {
+    /// Whether the lower source is asked even when the higher one found the entry
+    const MERGES: bool;
+
+    fn merge(higher: E, lower: E) -> E;
+}
+
+/// The higher source's entry, ignoring the lower's.
+pub struct Precedence;
+
+impl<E> MergeRule<E> for Precedence {
+    const MERGES: bool = false;
+
+    fn merge(higher: E, _: E) -> E {
+        higher
+    }
+}
+
+/// The higher source's group, with the members of both.
+pub struct UnionMembers;
+
+impl MergeRule<Group> for UnionMembers {
+    const MERGES: bool = true;
+
+    fn merge(mut higher: Group, lower: Group) -> Group {
+        for member in lower.members {
+            if !higher.members.contains(&member) {
+                higher.members.push(member);
+            }
+        }
+        higher
+    }
+}
+
+/// A database served from `A` and then `B`, combined by `R`.
+pub struct Combined<A, B, R = Precedence>(PhantomData<(A, B, R)>);
+
+impl<A, B, R> NssDatabase for Combined<A, B, R>
+where
+    A: NssDatabase,
+    A::Key: Clone + IdentityKey,
+    A::Entry: Identity,
+    B: NssDatabase<Entry = A::Entry, Key = A::Key>,
+    R: MergeRule<A::Entry>,
+{
+    type Entry = A::Entry;
+    type Key = A::Key;
+
+    /// The shorter of the two, so that neither source's entries are reused for longer than it
+    /// allows.
+    const ENUMERATION_CACHE_TTL: Duration =
+        if A::ENUMERATION_CACHE_TTL.as_nanos() <= B::ENUMERATION_CACHE_TTL.as_nanos() {
+            A::ENUMERATION_CACHE_TTL
+        } else {
+            B::ENUMERATION_CACHE_TTL
+        };
+    /// The smaller of the two, truncating wherever either would.
+    const TRUNCATE_MEMBERS_AT: Option<usize> =
+        match (A::TRUNCATE_MEMBERS_AT, B::TRUNCATE_MEMBERS_AT) {
+            (Some(a), Some(b)) if b < a => Some(b),
+            (Some(a), _) => Some(a),
+            (None, b) => b,
+        };
+    /// Only if both answer `Unspecified` themselves; otherwise each family is asked for in turn,
+    /// which those that do answer as well.
+    const RESOLVES_UNSPECIFIED: bool = A::RESOLVES_UNSPECIFIED && B::RESOLVES_UNSPECIFIED;
+    /// `A`'s, which `B` is given names in too.
+    const NAME_FORM: NameForm = A::NAME_FORM;
+
+    fn all_entries() -> Vec<A::Entry> {
+        let mut entries = A::all_entries();
+        let mut names: HashMap<String, usize> = HashMap::new();
+        let mut ids: HashMap<u32, usize> = HashMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            names.entry(entry.name().to_string()).or_insert(index);
+            if let Some(id) = entry.id() {
+                ids.entry(id).or_insert(index);
+            }
+        }
+
+        let mut merged = Vec::new();
+        for entry in B::all_entries() {
+            let existing = names
+                .get(entry.name())
+                .or_else(|| entry.id().and_then(|id| ids.get(&id)));
+            match existing {
+                Some(&index) => {
+                    if R::MERGES {
+                        merged.push((index, entry));
+                    }
+                }
+                None => {
+                    let index = entries.len();
+                    names.insert(entry.name().to_string(), index);
+                    if let Some(id) = entry.id() {
+                        ids.insert(id, index);
+                    }
+                    entries.push(entry);
+                }
+            }
+        }
+
+        // Merged in place, keeping the higher source's order
+        if !merged.is_empty() {
+            let mut slots: Vec<Option<A::Entry>> = entries.into_iter().map(Some).collect();
+            for (index, lower) in merged {
+                if let Some(higher) = slots[index].take() {
+                    slots[index] = Some(R::merge(higher, lower));
+                }
+            }
+            entries = slots.into_iter().flatten().collect();
+        }
+        entries
+    }
+
+    /// A key `A` knows without an entry of the kind asked for stays `NoData` unless `B` has one.
+    fn lookup(key: A::Key) -> Answer<A::Entry> {
+        match A::lookup(key.clone()) {
+            Answer::Found(higher) if R::MERGES => match B::lookup(key) {
+                Answer::Found(lower) => Answer::Found(R::merge(higher, lower)),
+                Answer::NoData | Answer::NotFound => Answer::Found(higher),
+            },
+            Answer::Found(higher) => Answer::Found(higher),
+            Answer::NoData => match Self::lower(key) {
+                Answer::NotFound => Answer::NoData,
+                lower => lower,
+            },
+            Answer::NotFound => Self::lower(key),
+        }
+    }
+}
+
+impl<A, B, R> Combined<A, B, R>
+where
+    A: NssDatabase,
+    A::Key: Clone + IdentityKey,
+    A::Entry: Identity,
+    B: NssDatabase<Entry = A::Entry, Key = A::Key>,
+{
+    /// `B`'s answer, leaving out an entry whose name or id `A` has, as enumerations do.
+    fn lower(key: A::Key) -> Answer<A::Entry> {
+        match B::lookup(key.clone()) {
+            Answer::Found(lower) => {
+                let shadowed = key
+                    .other_key(&lower)
+                    .is_some_and(|other| matches!(A::lookup(other), Answer::Found(_)));
+                if shadowed {
+                    Answer::NotFound
+                } else {
+                    Answer::Found(lower)
+                }
+            }
+            answer => answer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{GroupDatabase, PasswdDatabase};
+    use crate::group::GroupHooks;
+    use crate::passwd::PasswdHooks;
+
+    fn user(name: &str, uid: u32) -> Passwd {
+        Passwd {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(100),
+            gecos: String::new(),
+            dir: format!("/home/{}", name),
+            shell: "/bin/sh".to_string(),
+        }
+    }
+
+    fn group(name: &str, gid: u32, members: &[&str]) -> Group {
+        Group {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            gid: Gid::from_raw(gid),
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    /// Answers lookups from its enumeration, as a simple source does.
+    macro_rules! source {
+        ($name:ident, passwd, $entries:expr) => {
+            struct $name;
+
+            impl PasswdHooks for $name {
+                fn get_all_entries() -> Vec<Passwd> {
+                    $entries
+                }
+
+                fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+                    Self::get_all_entries().into_iter().find(|e| e.uid == uid)
+                }
+
+                fn get_entry_by_name(name: String) -> Option<Passwd> {
+                    Self::get_all_entries().into_iter().find(|e| e.name == name)
+                }
+            }
+        };
+        ($name:ident, group, $entries:expr) => {
+            struct $name;
+
+            impl GroupHooks for $name {
+                fn get_all_entries() -> Vec<Group> {
+                    $entries
+                }
+
+                fn get_entry_by_gid(gid: Gid) -> Option<Group> {
+                    Self::get_all_entries().into_iter().find(|e| e.gid == gid)
+                }
+
+                fn get_entry_by_name(name: String) -> Option<Group> {
+                    Self::get_all_entries().into_iter().find(|e| e.name == name)
+                }
+            }
+        };
+    }
+
+    source!(
+        HigherPasswd,
+        passwd,
+        vec![user("alice", 2000), user("dave", 3000)]
+    );
+    source!(
+        LowerPasswd,
+        passwd,
+        vec![user("alice", 1000), user("bob", 3000), user("erin", 4000)]
+    );
+
+    type Users = Combined<PasswdDatabase<HigherPasswd>, PasswdDatabase<LowerPasswd>>;
+
+    #[test]
+    fn enumeration_prefers_the_higher_source() {
+        let names: Vec<_> = Users::all_entries()
+            .into_iter()
+            .map(|e| (e.name, e.uid.as_raw()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("alice".to_string(), 2000),
+                ("dave".to_string(), 3000),
+                ("erin".to_string(), 4000)
+            ]
+        );
+    }
+
+    #[test]
+    fn lookups_find_only_what_enumeration_lists() {
+        let uid = |uid| Users::lookup(PasswdKey::Uid(Uid::from_raw(uid)));
+        let name = |name: &str| Users::lookup(PasswdKey::Name(name.to_string()));
+
+        assert_eq!(name("alice"), Answer::Found(user("alice", 2000)));
+        // The lower source's alice, whose name the higher one has
+        assert_eq!(uid(1000), Answer::NotFound);
+        // The lower source's bob, whose uid the higher one's dave has
+        assert_eq!(name("bob"), Answer::NotFound);
+        assert_eq!(uid(3000), Answer::Found(user("dave", 3000)));
+        assert_eq!(name("erin"), Answer::Found(user("erin", 4000)));
+        assert_eq!(uid(4000), Answer::Found(user("erin", 4000)));
+        assert_eq!(uid(5000), Answer::NotFound);
+
+        let listed = Users::all_entries();
+        for key in [1000, 2000, 3000, 4000].iter() {
+            if let Answer::Found(entry) = uid(*key) {
+                assert!(listed.contains(&entry));
+            }
+        }
+    }
+
+    source!(
+        HigherGroup,
+        group,
+        vec![
+            group("admins", 70000, &["alice", "bob"]),
+            group("wheel", 10, &["root"])
+        ]
+    );
+    source!(
+        LowerGroup,
+        group,
+        vec![
+            group("admins", 70000, &["bob", "deploy"]),
+            group("adm", 10, &["syslog"]),
+            group("ops", 70001, &["erin"])
+        ]
+    );
+
+    type Groups = Combined<GroupDatabase<HigherGroup>, GroupDatabase<LowerGroup>, UnionMembers>;
+
+    #[test]
+    fn union_members_merges_groups_in_both() {
+        let admins = group("admins", 70000, &["alice", "bob", "deploy"]);
+        assert_eq!(
+            Groups::lookup(GroupKey::Name("admins".to_string())),
+            Answer::Found(admins.clone())
+        );
+        assert_eq!(
+            Groups::lookup(GroupKey::Gid(Gid::from_raw(70000))),
+            Answer::Found(admins.clone())
+        );
+
+        let wheel = group("wheel", 10, &["root", "syslog"]);
+        assert_eq!(
+            Groups::all_entries(),
+            [admins, wheel.clone(), group("ops", 70001, &["erin"])]
+        );
+        assert_eq!(
+            Groups::lookup(GroupKey::Gid(Gid::from_raw(10))),
+            Answer::Found(wheel)
+        );
+    }
+
+    #[test]
+    fn union_members_leaves_out_what_enumeration_merges_away() {
+        // Merged into wheel, which has its gid
+        assert_eq!(
+            Groups::lookup(GroupKey::Name("adm".to_string())),
+            Answer::NotFound
+        );
+        assert_eq!(
+            Groups::lookup(GroupKey::Name("ops".to_string())),
+            Answer::Found(group("ops", 70001, &["erin"]))
+        );
+    }
+}
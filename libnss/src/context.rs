@@ -0,0 +1,248 @@
+//! Who a lookup is being answered for.
+//!
+//! NSS modules run inside the process doing the lookup, so hooks can tell who is asking and, for
+//! example, refuse shadow entries to unprivileged callers or answer some programs differently:
+//!
+//! ```
+//! use libnss::context::CallContext;
+//!
+//! let caller = CallContext::current();
+//! if caller.euid() != 0 || caller.process_name().as_deref() == Some("sshd") {
+//!     // ...
+//! }
+//! ```
+//!
+//! Servers answering lookups for other processes (`libnss::nscd::NscdServer` and
+//! `libnss::daemon::server::DaemonServer`) run their hooks in the context of the process on the
+//! other end of the socket, so the same hooks behave alike in-process and behind a server.
+
+use std::cell::RefCell;
+use std::os::unix::net::UnixStream;
+use std::sync::OnceLock;
+
+use crate::id::{Gid, Uid};
+
+#[derive(Clone, Debug)]
+pub struct CallContext {
+    euid: Uid,
+    pid: Option<libc::pid_t>,
+    /// Read on first use, as most hooks never ask
+    process_name: OnceLock<Option<String>>,
+    groups: OnceLock<Vec<Gid>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<CallContext>> = const { RefCell::new(None) };
+}
+
+impl CallContext {
+    /// A process identified by its effective uid and, where known, its pid.
+    pub fn new(euid: Uid, pid: Option<libc::pid_t>) -> Self {
+        CallContext {
+            euid,
+            pid,
+            process_name: OnceLock::new(),
+            groups: OnceLock::new(),
+        }
+    }
+
+    /// This context with `groups` as its effective gid and supplementary groups, for a caller whose
+    /// groups are known without asking procfs.
+    pub fn with_groups(mut self, groups: Vec<Gid>) -> Self {
+        self.groups = OnceLock::from(groups);
+        self
+    }
+
+    /// The caller of the lookup being answered on this thread: the process this module is loaded
+    /// into, unless a server is answering for another one with [`CallContext::scope`].
+    pub fn current() -> Self {
+        CURRENT
+            .with(|current| current.borrow().clone())
+            .unwrap_or_else(|| {
+                // Read afresh each time, as the process may change its euid between lookups
+                let (euid, pid) = unsafe { (libc::geteuid(), libc::getpid()) };
+                CallContext::new(Uid::from_raw(euid), Some(pid))
+            })
+    }
+
+    /// The process on the other end of a unix socket, as vouched for by the kernel.
+    pub fn of_peer(stream: &UnixStream) -> Option<Self> {
+        let (euid, pid) = peer_credentials(stream)?;
+        Some(CallContext::new(Uid::from_raw(euid), pid))
+    }
+
+    /// Runs `f` with this as the [current](CallContext::current) context on this thread.
+    pub fn scope<T, F: FnOnce() -> T>(self, f: F) -> T {
+        struct Restore(Option<CallContext>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                CURRENT.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let _restore = Restore(CURRENT.with(|current| current.replace(Some(self))));
+        f()
+    }
+
+    pub fn euid(&self) -> Uid {
+        self.euid
+    }
+
+    /// Unknown for peers on platforms whose sockets only report credentials.
+    pub fn pid(&self) -> Option<libc::pid_t> {
+        self.pid
+    }
+
+    /// The short name of the program (`/proc/<pid>/comm` on Linux), if it can be found.
+    pub fn process_name(&self) -> Option<String> {
+        self.process_name
+            .get_or_init(|| process_name(self.pid?))
+            .clone()
+    }
+
+    /// The effective gid and supplementary groups, where they can be found: always for this
+    /// process, and from procfs on Linux for others.
+    pub fn groups(&self) -> Vec<Gid> {
+        self.groups
+            .get_or_init(|| self.pid.map(groups).unwrap_or_default())
+            .clone()
+    }
+}
+
+fn groups(pid: libc::pid_t) -> Vec<Gid> {
+    if pid == unsafe { libc::getpid() } {
+        return own_groups();
+    }
+    peer_groups(pid)
+}
+
+fn own_groups() -> Vec<Gid> {
+    let mut gids = vec![unsafe { libc::getegid() }];
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count > 0 {
+        let mut supplementary = vec![0; count as usize];
+        let count = unsafe { libc::getgroups(count, supplementary.as_mut_ptr()) };
+        supplementary.truncate(count.max(0) as usize);
+        gids.extend(supplementary);
+    }
+    gids.into_iter().map(Gid::from_raw).collect()
+}
+
+#[cfg(target_os = "linux")]
+fn peer_groups(pid: libc::pid_t) -> Vec<Gid> {
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(_) => return Vec::new(),
+    };
+    let mut gids = Vec::new();
+    for line in status.lines() {
+        // `Gid:` lists the real, effective, saved and filesystem gids
+        if let Some(ids) = line.strip_prefix("Gid:") {
+            gids.extend(
+                ids.split_whitespace()
+                    .nth(1)
+                    .and_then(|id| id.parse::<Gid>().ok()),
+            );
+        } else if let Some(ids) = line.strip_prefix("Groups:") {
+            gids.extend(
+                ids.split_whitespace()
+                    .filter_map(|id| id.parse::<Gid>().ok()),
+            );
+        }
+    }
+    gids
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_groups(_pid: libc::pid_t) -> Vec<Gid> {
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn process_name(pid: libc::pid_t) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end_matches('\n').to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_name(pid: libc::pid_t) -> Option<String> {
+    // Only our own name is within reach without procfs
+    if pid != unsafe { libc::getpid() } {
+        return None;
+    }
+    let path = std::env::args_os().next()?;
+    let name = std::path::Path::new(&path).file_name()?;
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(stream: &UnixStream) -> Option<(libc::uid_t, Option<libc::pid_t>)> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret == 0 {
+        Some((cred.uid, Some(cred.pid)))
+    } else {
+        None
+    }
+}
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+fn peer_credentials(stream: &UnixStream) -> Option<(libc::uid_t, Option<libc::pid_t>)> {
+    use std::os::unix::io::AsRawFd;
+
+    // From <ucred.h>, which the libc crate doesn't cover
+    #[allow(non_camel_case_types)]
+    enum ucred_t {}
+    extern "C" {
+        fn getpeerucred(fd: libc::c_int, ucred: *mut *mut ucred_t) -> libc::c_int;
+        fn ucred_geteuid(ucred: *const ucred_t) -> libc::uid_t;
+        fn ucred_getpid(ucred: *const ucred_t) -> libc::pid_t;
+        fn ucred_free(ucred: *mut ucred_t);
+    }
+
+    let mut ucred = std::ptr::null_mut();
+    unsafe {
+        if getpeerucred(stream.as_raw_fd(), &mut ucred) != 0 {
+            return None;
+        }
+        let uid = ucred_geteuid(ucred);
+        let pid = ucred_getpid(ucred);
+        ucred_free(ucred);
+        // -1 means the credential didn't carry an effective uid
+        if uid == libc::uid_t::MAX {
+            None
+        } else {
+            Some((uid, Some(pid).filter(|pid| *pid != -1)))
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "illumos", target_os = "solaris")))]
+fn peer_credentials(stream: &UnixStream) -> Option<(libc::uid_t, Option<libc::pid_t>)> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } == 0 {
+        Some((uid, None))
+    } else {
+        None
+    }
+}
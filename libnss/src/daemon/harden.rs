@@ -0,0 +1,371 @@
+//! Locking down the daemon half. Unlike the shim, which lives in whatever process loads it, the
+//! daemon is a process of its own that only needs to answer lookups, so once its socket is bound
+//! it can give up nearly everything:
+//!
+//! ```no_run
+//! use std::os::unix::net::UnixListener;
+//! use libnss::daemon::harden;
+//! use libnss::daemon::server::DaemonServer;
+//!
+//! let listener = UnixListener::bind("/run/nss-example/socket").unwrap();
+//! harden::drop_privileges("nss-example").unwrap();
+//! harden::landlock(&["/etc/nss-example"], &[]).unwrap();
+//! harden::seccomp(&[]).unwrap();
+//! DaemonServer::new().serve(listener).unwrap();
+//! ```
+//!
+//! [`chroot`] and [`drop_privileges`] need root, so come first; [`landlock`] and [`seccomp`]
+//! work unprivileged. All of them apply to every thread, but spawn none before calling them.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::files;
+use crate::passwd::Passwd;
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Switches to `user`'s uid and primary gid for good, with no supplementary groups.
+///
+/// The user is read from `/etc/passwd` rather than looked up through NSS, which could end up
+/// asking this very daemon before it is serving.
+pub fn drop_privileges(user: &str) -> io::Result<()> {
+    let entry = files::read::<Passwd, _>("/etc/passwd")?
+        .into_iter()
+        .find(|entry| entry.name == user)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no user {} in /etc/passwd", user),
+            )
+        })?;
+    let (uid, gid) = (entry.uid.as_raw(), entry.gid.as_raw());
+
+    unsafe {
+        check(libc::setgroups(1, &gid))?;
+        check(libc::setgid(gid))?;
+        check(libc::setuid(uid))?;
+
+        // A saved set-user-ID left behind would let the process switch back
+        if uid != 0 && libc::setuid(0) != -1 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "root privileges could be regained after dropping them",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Makes `dir` the root directory. Everything the backend reads later must be inside it.
+pub fn chroot<P: AsRef<Path>>(dir: P) -> io::Result<()> {
+    let dir = c_path(dir.as_ref())?;
+    unsafe {
+        check(libc::chroot(dir.as_ptr()))?;
+        check(libc::chdir(b"/\0".as_ptr() as *const libc::c_char))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod landlock_sys {
+    pub const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    pub const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    pub const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    pub const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    pub const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    pub const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// The rights that apply to files rather than directories
+    pub const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+    /// Every filesystem right of the first ABI
+    pub const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+
+    #[repr(C)]
+    pub struct RulesetAttr {
+        pub handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    pub struct PathBeneathAttr {
+        pub allowed_access: u64,
+        pub parent_fd: libc::c_int,
+    }
+}
+
+/// Restricts filesystem access to reading `read_only` and reading and writing `read_write`, each
+/// with everything beneath them. Returns `Ok(false)` without restricting anything on kernels
+/// without Landlock (before 5.13) or with it disabled.
+#[cfg(target_os = "linux")]
+pub fn landlock<P: AsRef<Path>>(read_only: &[P], read_write: &[P]) -> io::Result<bool> {
+    use landlock_sys::*;
+    use std::fs::File;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    let read = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+    unsafe {
+        let abi = libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        );
+        if abi < 1 {
+            return Ok(false);
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_V1,
+        };
+        let ruleset = libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        );
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = OwnedFd::from_raw_fd(ruleset as libc::c_int);
+
+        let paths = read_only
+            .iter()
+            .map(|path| (path.as_ref(), read))
+            .chain(read_write.iter().map(|path| (path.as_ref(), ACCESS_FS_V1)));
+        for (path, access) in paths {
+            let dir = File::open(path)?;
+            // Rules for files may only grant rights that apply to files
+            let access = if dir.metadata()?.is_dir() {
+                access
+            } else {
+                access & ACCESS_FS_FILE
+            };
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: dir.as_raw_fd(),
+            };
+            if libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &rule,
+                0,
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+        if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// What a lookup daemon needs: threads, memory, time, files it already may open, and client
+/// sockets to reach its backend and answer on its own socket.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_membarrier,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_restart_syscall,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_uname,
+    libc::SYS_prlimit64,
+    libc::SYS_getrandom,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+];
+
+/// Installs a seccomp filter allowing only what a lookup daemon needs, plus `extra` (`SYS_*`
+/// numbers a backend turns out to need). Anything else fails with `EPERM` rather than killing
+/// the daemon, so a missing syscall shows up as an error in its logs.
+///
+/// Only x86_64 and aarch64 are supported; elsewhere this returns an `Unsupported` error.
+#[cfg(target_os = "linux")]
+pub fn seccomp(extra: &[libc::c_long]) -> io::Result<()> {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        use libc::{sock_filter, sock_fprog};
+
+        const LD: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+        const JEQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+        const JGE: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+        const RET: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+        // Offsets into struct seccomp_data
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+        // Syscalls of the x32 ABI, which share the x86_64 audit arch
+        const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+        let stmt = |code, k| sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        let jump = |code, k, jt, jf| sock_filter { code, jt, jf, k };
+
+        let allowed: Vec<u32> = ALLOWED_SYSCALLS
+            .iter()
+            .chain(extra)
+            .map(|nr| *nr as u32)
+            .collect();
+        if allowed.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many syscalls to allow",
+            ));
+        }
+
+        let mut filter = vec![
+            stmt(LD, ARCH),
+            jump(JEQ, AUDIT_ARCH, 1, 0),
+            stmt(RET, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(LD, NR),
+            jump(JGE, X32_SYSCALL_BIT, allowed.len() as u8, 0),
+        ];
+        // Each match jumps past the remaining comparisons and the refusal to the allow
+        for (i, nr) in allowed.iter().enumerate() {
+            filter.push(jump(JEQ, *nr, (allowed.len() - i) as u8, 0));
+        }
+        filter.push(stmt(
+            RET,
+            libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA),
+        ));
+        filter.push(stmt(RET, libc::SECCOMP_RET_ALLOW));
+
+        let program = sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        unsafe {
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            if libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program,
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = extra;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no seccomp filter for this architecture",
+        ))
+    }
+}
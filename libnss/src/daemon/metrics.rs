@@ -0,0 +1,337 @@
+//! Prometheus metrics for the daemon, so that failing or slow identity lookups can be alerted on
+//! before logins start failing.
+//!
+//! [`DaemonServer`](crate::daemon::server::DaemonServer) records every request it answers into
+//! [`Metrics::global`]: how many per database and result, and how long each took. The hooks
+//! report what only they can see through the same instance: cache hits and misses, backend errors
+//! and the state of any circuit breaker in front of the backend. [`serve`] answers scrapes of
+//! `/metrics`; bind its listener before [`harden`](crate::daemon::harden)ing the daemon.
+//!
+//! ```no_run
+//! use std::net::TcpListener;
+//! use std::thread;
+//! use libnss::daemon::metrics::{self, Database, Metrics};
+//!
+//! let listener = TcpListener::bind("127.0.0.1:9464").unwrap();
+//! thread::spawn(move || metrics::serve(listener, Metrics::global()));
+//!
+//! // In a hooks implementation with a cache of its own
+//! Metrics::global().record_cache(Database::Passwd, true);
+//! ```
+//!
+//! The cache hit ratio is then
+//! `rate(libnss_daemon_cache_lookups_total{result="hit"}[5m]) / rate(libnss_daemon_cache_lookups_total[5m])`.
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::Duration;
+
+pub use crate::stats::Database;
+use crate::stats::DATABASES;
+
+/// How the daemon answered a request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Found,
+    NotFound,
+    /// The database isn't served, or not to this caller
+    Unavailable,
+    /// The hooks panicked
+    Error,
+}
+
+const OUTCOMES: [Outcome; 4] = [
+    Outcome::Found,
+    Outcome::NotFound,
+    Outcome::Unavailable,
+    Outcome::Error,
+];
+
+impl Outcome {
+    fn name(self) -> &'static str {
+        match self {
+            Outcome::Found => "found",
+            Outcome::NotFound => "not_found",
+            Outcome::Unavailable => "unavailable",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+const BREAKER_STATES: [BreakerState; 3] = [
+    BreakerState::Closed,
+    BreakerState::HalfOpen,
+    BreakerState::Open,
+];
+
+impl BreakerState {
+    fn name(self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::HalfOpen => "half_open",
+            BreakerState::Open => "open",
+        }
+    }
+}
+
+/// Upper bounds of the latency histogram's buckets, in seconds: lookups range from answering out
+/// of memory to waiting out a backend's timeout.
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 2.5,
+];
+
+// Array repeat expressions need a constant to copy
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_BREAKER: AtomicU8 = AtomicU8::new(u8::MAX);
+
+struct Histogram {
+    /// Per bucket rather than cumulative, plus one for everything slower than the last bound
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [ZERO; BUCKETS.len() + 1],
+            sum_nanos: ZERO,
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_HISTOGRAM: Histogram = Histogram::new();
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LOOKUPS: [AtomicU64; OUTCOMES.len()] = [ZERO; OUTCOMES.len()];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CACHE_LOOKUPS: [AtomicU64; 2] = [ZERO; 2];
+
+/// Counters for everything the endpoint reports, updated without locking.
+pub struct Metrics {
+    lookups: [[AtomicU64; OUTCOMES.len()]; DATABASES.len()],
+    latency: [Histogram; DATABASES.len()],
+    /// Hits, then misses
+    cache: [[AtomicU64; 2]; DATABASES.len()],
+    backend_errors: [AtomicU64; DATABASES.len()],
+    /// Index into `BREAKER_STATES`, or `u8::MAX` where no breaker has reported
+    breakers: [AtomicU8; DATABASES.len()],
+}
+
+static GLOBAL: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            lookups: [NO_LOOKUPS; DATABASES.len()],
+            latency: [EMPTY_HISTOGRAM; DATABASES.len()],
+            cache: [NO_CACHE_LOOKUPS; DATABASES.len()],
+            backend_errors: [ZERO; DATABASES.len()],
+            breakers: [NO_BREAKER; DATABASES.len()],
+        }
+    }
+
+    /// The instance the daemon server records into.
+    pub fn global() -> &'static Metrics {
+        &GLOBAL
+    }
+
+    pub fn record_lookup(&self, database: Database, outcome: Outcome, elapsed: Duration) {
+        let db = database as usize;
+        self.lookups[db][outcome as usize].fetch_add(1, Ordering::Relaxed);
+
+        let histogram = &self.latency[db];
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        histogram.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Records a lookup in a cache in front of the backend.
+    pub fn record_cache(&self, database: Database, hit: bool) {
+        let index = if hit { 0 } else { 1 };
+        self.cache[database as usize][index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the backend failing to answer, which the hooks otherwise report as not found.
+    pub fn record_backend_error(&self, database: Database) {
+        self.backend_errors[database as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_breaker_state(&self, database: Database, state: BreakerState) {
+        self.breakers[database as usize].store(state as u8, Ordering::Relaxed);
+    }
+
+    /// Everything recorded, in Prometheus' text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP libnss_daemon_lookups_total Requests answered, by result.\n");
+        out.push_str("# TYPE libnss_daemon_lookups_total counter\n");
+        for database in DATABASES {
+            for outcome in OUTCOMES {
+                let count =
+                    self.lookups[database as usize][outcome as usize].load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "libnss_daemon_lookups_total{{database=\"{}\",result=\"{}\"}} {}",
+                    database.name(),
+                    outcome.name(),
+                    count
+                );
+            }
+        }
+
+        out.push_str(
+            "# HELP libnss_daemon_lookup_duration_seconds Time taken to answer requests.\n",
+        );
+        out.push_str("# TYPE libnss_daemon_lookup_duration_seconds histogram\n");
+        for database in DATABASES {
+            let histogram = &self.latency[database as usize];
+            let mut count = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                count += bucket.load(Ordering::Relaxed);
+                let bound = match BUCKETS.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "libnss_daemon_lookup_duration_seconds_bucket{{database=\"{}\",le=\"{}\"}} {}",
+                    database.name(),
+                    bound,
+                    count
+                );
+            }
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(
+                out,
+                "libnss_daemon_lookup_duration_seconds_sum{{database=\"{}\"}} {}",
+                database.name(),
+                sum
+            );
+            let _ = writeln!(
+                out,
+                "libnss_daemon_lookup_duration_seconds_count{{database=\"{}\"}} {}",
+                database.name(),
+                count
+            );
+        }
+
+        out.push_str("# HELP libnss_daemon_cache_lookups_total Lookups in the hooks' cache.\n");
+        out.push_str("# TYPE libnss_daemon_cache_lookups_total counter\n");
+        for database in DATABASES {
+            let cache = &self.cache[database as usize];
+            for (result, count) in ["hit", "miss"].iter().zip(cache) {
+                let _ = writeln!(
+                    out,
+                    "libnss_daemon_cache_lookups_total{{database=\"{}\",result=\"{}\"}} {}",
+                    database.name(),
+                    result,
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        out.push_str("# HELP libnss_daemon_backend_errors_total Backend failures the hooks saw.\n");
+        out.push_str("# TYPE libnss_daemon_backend_errors_total counter\n");
+        for database in DATABASES {
+            let _ = writeln!(
+                out,
+                "libnss_daemon_backend_errors_total{{database=\"{}\"}} {}",
+                database.name(),
+                self.backend_errors[database as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str(
+            "# HELP libnss_daemon_circuit_breaker_state Whether the breaker is in each state.\n",
+        );
+        out.push_str("# TYPE libnss_daemon_circuit_breaker_state gauge\n");
+        for database in DATABASES {
+            let current = self.breakers[database as usize].load(Ordering::Relaxed);
+            // Databases without a breaker are left out rather than shown as closed
+            if current == u8::MAX {
+                continue;
+            }
+            for state in BREAKER_STATES {
+                let _ = writeln!(
+                    out,
+                    "libnss_daemon_circuit_breaker_state{{database=\"{}\",state=\"{}\"}} {}",
+                    database.name(),
+                    state.name(),
+                    (current == state as u8) as u8
+                );
+            }
+        }
+
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Answers `GET /metrics` on `listener` with `metrics` forever, one connection at a time, as
+/// scrapes are infrequent.
+pub fn serve(listener: TcpListener, metrics: &Metrics) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            // A connection reset before it was accepted
+            Err(_) => continue,
+        };
+        let _ = answer(stream, metrics);
+    }
+    Ok(())
+}
+
+fn answer(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // Only the request line matters, which comes first
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let line = request.split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut parts = line.split(|b| *b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", metrics.render()),
+        (Some(b"GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,38 @@
+//! Split deployment: a thin NSS shim that forwards every lookup over a unix socket to a daemon
+//! which runs the real hooks.
+//!
+//! Loading TLS stacks, async runtimes or LDAP clients into every process that resolves a user is
+//! heavy and risky, so instead the module built into `libnss_<name>.so.2` only contains the
+//! [`shim`] hooks, and a separate long running process serves the backend with
+//! [`server::DaemonServer`]. Both halves share the framing and serialization in [`wire`].
+//!
+//! Module side:
+//!
+//! ```ignore
+//! use libnss::daemon::shim::{ShimPasswd, ShimSocket};
+//!
+//! struct ExampleSocket;
+//! impl ShimSocket for ExampleSocket {
+//!     const PATH: &'static str = "/run/nss-example/socket";
+//! }
+//!
+//! type ExamplePasswd = ShimPasswd<ExampleSocket>;
+//! libnss_passwd_hooks!(example, ExamplePasswd);
+//! ```
+//!
+//! Daemon side:
+//!
+//! ```ignore
+//! use libnss::daemon::server::DaemonServer;
+//!
+//! DaemonServer::new()
+//!     .with_passwd::<BackendPasswd>()
+//!     .listen("/run/nss-example/socket")
+//!     .unwrap();
+//! ```
+
+pub mod harden;
+pub mod metrics;
+pub mod server;
+pub mod shim;
+pub mod wire;
@@ -0,0 +1,326 @@
+//! The daemon half: answers shim requests from hooks implementations.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::context::CallContext;
+use crate::daemon::metrics::{Database, Metrics, Outcome};
+use crate::daemon::wire::{self, Request, Response};
+use crate::group::GroupHooks;
+use crate::health::{Health, HealthCheck};
+use crate::host::{HostAnswer, HostHooks};
+use crate::interop::NssStatus;
+use crate::passwd::PasswdHooks;
+use crate::resolver::Resolver;
+use crate::shadow::ShadowHooks;
+
+/// How many connections are served at once unless [`DaemonServer::with_max_connections`] says
+/// otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Serves the databases whose hooks have been registered, answering as a [`Resolver`] does; the
+/// rest answer [`Response::Unavailable`]. Shadow entries are only ever sent to root, as with
+/// `/etc/shadow`. Every lookup is recorded in [`Metrics::global`].
+pub struct DaemonServer {
+    resolver: Resolver,
+    healthcheck: Option<fn() -> Health>,
+    max_connections: usize,
+}
+
+impl Default for DaemonServer {
+    fn default() -> Self {
+        DaemonServer {
+            resolver: Resolver::default(),
+            healthcheck: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+impl DaemonServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_passwd<P: PasswdHooks>(mut self) -> Self {
+        self.resolver = self.resolver.with_passwd::<P>();
+        self
+    }
+
+    pub fn with_group<G: GroupHooks>(mut self) -> Self {
+        self.resolver = self.resolver.with_group::<G>();
+        self
+    }
+
+    pub fn with_shadow<S: ShadowHooks>(mut self) -> Self {
+        self.resolver = self.resolver.with_shadow::<S>();
+        self
+    }
+
+    pub fn with_host<H: HostHooks>(mut self) -> Self {
+        self.resolver = self.resolver.with_host::<H>();
+        self
+    }
+
+    /// Caches the hooks' answers as [`Resolver::with_cache_ttl`] does.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.resolver = self.resolver.with_cache_ttl(ttl);
+        self
+    }
+
+    /// Answers [`Request::Health`] with `H`'s check. Without one the daemon reports itself
+    /// healthy as long as it answers.
+    pub fn with_healthcheck<H: HealthCheck>(mut self) -> Self {
+        self.healthcheck = Some(H::check);
+        self
+    }
+
+    /// Serves at most `max` connections at once. Any user can connect to the socket, so further
+    /// connections wait in the listen backlog for one of those to finish rather than each getting
+    /// a thread of its own.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Binds a socket at `path`, replacing any stale one, and serves it forever.
+    pub fn listen<T: AsRef<Path>>(self, path: T) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        // Every process on the system resolves through the shim, so everyone must be able to connect
+        fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+        self.serve(listener)
+    }
+
+    /// Serves an already bound listener (for example one passed in by socket activation).
+    pub fn serve(self, listener: UnixListener) -> io::Result<()> {
+        let slots = Arc::new(Slots::new(self.max_connections));
+        let server = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let slot = slots.acquire();
+            let server = server.clone();
+            thread::spawn(move || {
+                let _ = server.handle_connection(stream);
+                drop(slot);
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut buf = Vec::new();
+        wire::read_frame(&mut stream, &mut buf, wire::MAX_REQUEST_LEN)?;
+        let response = match wire::decode_message::<Request>(&buf) {
+            Ok(request) => {
+                let database = database(&request);
+                let start = Instant::now();
+                // A panic in the hooks fails this request rather than dropping the connection
+                let response = panic::catch_unwind(AssertUnwindSafe(|| {
+                    // The hooks answer as if they were loaded into the peer
+                    match CallContext::of_peer(&stream) {
+                        Some(peer) => {
+                            let is_root = peer.euid() == 0;
+                            peer.scope(|| self.respond(request, is_root))
+                        }
+                        None => self.respond(request, false),
+                    }
+                }))
+                .unwrap_or_else(|_| Response::Error("the daemon's hooks panicked".to_string()));
+                if let Some(database) = database {
+                    Metrics::global().record_lookup(database, outcome(&response), start.elapsed());
+                }
+                response
+            }
+            Err(err) => Response::Error(err.to_string()),
+        };
+
+        wire::encode_message(&response, &mut buf);
+        wire::write_frame(&mut stream, &buf)
+    }
+
+    fn respond(&self, request: Request, peer_is_root: bool) -> Response {
+        let resolver = &self.resolver;
+        match request {
+            Request::PasswdAll => entries(resolver.all_passwd_entries(), Response::Passwd),
+            Request::PasswdByUid(uid) => {
+                entry(resolver.lookup_passwd_by_uid(uid), Response::Passwd)
+            }
+            Request::PasswdByName(name) => {
+                entry(resolver.lookup_passwd_by_name(&name), Response::Passwd)
+            }
+            Request::GroupAll => entries(resolver.all_group_entries(), Response::Group),
+            Request::GroupByGid(gid) => entry(resolver.lookup_group_by_gid(gid), Response::Group),
+            Request::GroupByName(name) => {
+                entry(resolver.lookup_group_by_name(&name), Response::Group)
+            }
+            Request::ShadowAll | Request::ShadowByName(_) if !peer_is_root => Response::Unavailable,
+            Request::ShadowAll => entries(resolver.all_shadow_entries(), Response::Shadow),
+            Request::ShadowByName(name) => {
+                entry(resolver.lookup_shadow_by_name(&name), Response::Shadow)
+            }
+            Request::HostAll => entries(resolver.all_host_entries(), Response::Host),
+            Request::HostByName(name, family) => match resolver.resolve_host_by_name(&name, family)
+            {
+                Ok(HostAnswer::NoData) => Response::HostNoData,
+                answer => {
+                    let host =
+                        answer.and_then(|answer| answer.into_host().ok_or(NssStatus::NotFound));
+                    entry(host, Response::Host)
+                }
+            },
+            Request::HostByAddr(addr) => entry(resolver.lookup_host_by_addr(addr), Response::Host),
+            Request::Health => Response::Health(match self.healthcheck {
+                Some(check) => check(),
+                None => Health::probe(|| true),
+            }),
+        }
+    }
+}
+
+/// A counting semaphore over the connections being served.
+struct Slots {
+    used: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+/// A connection being served, which frees its slot when dropped.
+struct Slot(Arc<Slots>);
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Slots {
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+            max,
+        }
+    }
+
+    /// Waits until fewer than `max` connections are being served.
+    fn acquire(self: &Arc<Self>) -> Slot {
+        let mut used = self.used.lock().unwrap();
+        while *used >= self.max {
+            used = self.freed.wait(used).unwrap();
+        }
+        *used += 1;
+        Slot(self.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.used.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// The response listing `found`, which is empty for an entry not found.
+fn entry<T>(found: Result<T, NssStatus>, response: fn(Vec<T>) -> Response) -> Response {
+    entries(found.map(|found| vec![found]), response)
+}
+
+/// The response listing `found`, or [`Response::Unavailable`] for a database without hooks.
+fn entries<T>(found: Result<Vec<T>, NssStatus>, response: fn(Vec<T>) -> Response) -> Response {
+    match found {
+        Ok(found) => response(found),
+        Err(NssStatus::NotFound) => response(Vec::new()),
+        Err(_) => Response::Unavailable,
+    }
+}
+
+/// The database a request looks up, or `None` for a health check.
+fn database(request: &Request) -> Option<Database> {
+    Some(match request {
+        Request::PasswdAll | Request::PasswdByUid(_) | Request::PasswdByName(_) => Database::Passwd,
+        Request::GroupAll | Request::GroupByGid(_) | Request::GroupByName(_) => Database::Group,
+        Request::ShadowAll | Request::ShadowByName(_) => Database::Shadow,
+        Request::HostAll | Request::HostByName(_, _) | Request::HostByAddr(_) => Database::Hosts,
+        Request::Health => return None,
+    })
+}
+
+fn outcome(response: &Response) -> Outcome {
+    let found = match response {
+        Response::Passwd(entries) => !entries.is_empty(),
+        Response::Group(entries) => !entries.is_empty(),
+        Response::Shadow(entries) => !entries.is_empty(),
+        Response::Host(entries) => !entries.is_empty(),
+        Response::HostNoData => false,
+        Response::Health(_) => true,
+        Response::Unavailable => return Outcome::Unavailable,
+        Response::Error(_) => return Outcome::Error,
+    };
+    if found {
+        Outcome::Found
+    } else {
+        Outcome::NotFound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::mpsc;
+
+    #[test]
+    fn oversize_requests_are_refused() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client
+            .write_all(&((wire::MAX_REQUEST_LEN + 1) as u32).to_be_bytes())
+            .unwrap();
+        let err = DaemonServer::new().handle_connection(server).unwrap_err();
+        assert_eq!(err.to_string(), "frame too large");
+
+        // Closed without an answer
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn requests_are_answered() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut buf = Vec::new();
+        wire::encode_message(&Request::PasswdAll, &mut buf);
+        wire::write_frame(&mut client, &buf).unwrap();
+        DaemonServer::new().handle_connection(server).unwrap();
+
+        wire::read_frame(&mut client, &mut buf, wire::MAX_FRAME_LEN).unwrap();
+        let response: Response = wire::decode_message(&buf).unwrap();
+        assert_eq!(response, Response::Unavailable);
+    }
+
+    #[test]
+    fn slots_wait_for_a_connection_to_finish() {
+        let slots = Arc::new(Slots::new(2));
+        let first = slots.acquire();
+        let _second = slots.acquire();
+
+        let (acquired, waited) = mpsc::channel();
+        let waiting = slots.clone();
+        let thread = thread::spawn(move || {
+            let _third = waiting.acquire();
+            acquired.send(()).unwrap();
+        });
+        assert!(waited.recv_timeout(Duration::from_millis(50)).is_err());
+
+        drop(first);
+        waited.recv_timeout(Duration::from_secs(5)).unwrap();
+        thread.join().unwrap();
+    }
+}
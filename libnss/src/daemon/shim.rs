@@ -0,0 +1,172 @@
+//! Hooks implementations that forward every lookup to a daemon.
+//!
+//! The shim keeps no state between lookups and pulls in nothing beyond `std`, so it is cheap to
+//! load into every process. If the daemon can't be reached, lookups find nothing and NSS moves on
+//! to the next source in `nsswitch.conf`.
+
+use std::cell::RefCell;
+use std::io;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::daemon::wire::{self, Request, Response};
+use crate::group::{Group, GroupHooks};
+use crate::health::{Health, HealthCheck};
+use crate::host::{AddressFamily, Host, HostAnswer, HostHooks};
+use crate::id::{Gid, Uid};
+use crate::passwd::{Passwd, PasswdHooks};
+use crate::shadow::{Shadow, ShadowHooks};
+
+/// Where the shim finds its daemon.
+pub trait ShimSocket {
+    const PATH: &'static str;
+
+    /// Bounds both connecting and waiting for the answer, so a wedged daemon can't hang callers.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+}
+
+thread_local! {
+    // Each lookup reuses the thread's buffer rather than allocating a fresh one
+    static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sends one request to the daemon and waits for its response.
+pub fn call<S: ShimSocket>(request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(S::PATH)?;
+    stream.set_read_timeout(Some(S::TIMEOUT))?;
+    stream.set_write_timeout(Some(S::TIMEOUT))?;
+
+    BUFFER.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        wire::encode_message(request, &mut buf);
+        wire::write_frame(&mut stream, &buf)?;
+        wire::read_frame(&mut stream, &mut buf, wire::MAX_FRAME_LEN)?;
+        wire::decode_message(&buf)
+    })
+}
+
+pub struct ShimPasswd<S>(PhantomData<S>);
+
+impl<S: ShimSocket> ShimPasswd<S> {
+    fn query(request: Request) -> Vec<Passwd> {
+        match call::<S>(&request) {
+            Ok(Response::Passwd(entries)) => entries,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<S: ShimSocket> PasswdHooks for ShimPasswd<S> {
+    fn get_all_entries() -> Vec<Passwd> {
+        Self::query(Request::PasswdAll)
+    }
+
+    fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+        Self::query(Request::PasswdByUid(uid)).into_iter().next()
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Passwd> {
+        Self::query(Request::PasswdByName(name)).into_iter().next()
+    }
+}
+
+pub struct ShimGroup<S>(PhantomData<S>);
+
+impl<S: ShimSocket> ShimGroup<S> {
+    fn query(request: Request) -> Vec<Group> {
+        match call::<S>(&request) {
+            Ok(Response::Group(entries)) => entries,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<S: ShimSocket> GroupHooks for ShimGroup<S> {
+    fn get_all_entries() -> Vec<Group> {
+        Self::query(Request::GroupAll)
+    }
+
+    fn get_entry_by_gid(gid: Gid) -> Option<Group> {
+        Self::query(Request::GroupByGid(gid)).into_iter().next()
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Group> {
+        Self::query(Request::GroupByName(name)).into_iter().next()
+    }
+}
+
+pub struct ShimShadow<S>(PhantomData<S>);
+
+impl<S: ShimSocket> ShimShadow<S> {
+    fn query(request: Request) -> Vec<Shadow> {
+        match call::<S>(&request) {
+            Ok(Response::Shadow(entries)) => entries,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<S: ShimSocket> ShadowHooks for ShimShadow<S> {
+    fn get_all_entries() -> Vec<Shadow> {
+        Self::query(Request::ShadowAll)
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Shadow> {
+        Self::query(Request::ShadowByName(name)).into_iter().next()
+    }
+}
+
+pub struct ShimHost<S>(PhantomData<S>);
+
+impl<S: ShimSocket> ShimHost<S> {
+    fn query(request: Request) -> Vec<Host> {
+        match call::<S>(&request) {
+            Ok(Response::Host(entries)) => entries,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<S: ShimSocket> HostHooks for ShimHost<S> {
+    /// The server makes the IPv4 then IPv6 calls itself if its hooks need them
+    const RESOLVES_UNSPECIFIED: bool = true;
+
+    fn get_all_entries() -> Vec<Host> {
+        Self::query(Request::HostAll)
+    }
+
+    fn get_host_by_name(name: &str, family: AddressFamily) -> Option<Host> {
+        Self::resolve_host_by_name(name, family).into_host()
+    }
+
+    fn resolve_host_by_name(name: &str, family: AddressFamily) -> HostAnswer {
+        match call::<S>(&Request::HostByName(name.to_string(), family)) {
+            Ok(Response::Host(entries)) => entries.into_iter().next().into(),
+            Ok(Response::HostNoData) => HostAnswer::NoData,
+            _ => HostAnswer::NotFound,
+        }
+    }
+
+    fn get_host_by_addr(addr: IpAddr) -> Option<Host> {
+        Self::query(Request::HostByAddr(addr)).into_iter().next()
+    }
+}
+
+/// Runs the daemon's health check, so the module's `_nss_<module>_healthcheck` reports on the
+/// daemon and its backend rather than on the shim. A daemon that can't be reached is unhealthy.
+pub struct ShimHealth<S>(PhantomData<S>);
+
+impl<S: ShimSocket> HealthCheck for ShimHealth<S> {
+    fn check() -> Health {
+        match call::<S>(&Request::Health) {
+            Ok(Response::Health(health)) => health,
+            Ok(Response::Error(message)) => {
+                Health::unhealthy(format!("the daemon failed the health check: {}", message))
+            }
+            Ok(_) => Health::unhealthy("the daemon answered the health check with something else"),
+            Err(err) => Health::unhealthy(format!("the daemon can't be reached: {}", err)),
+        }
+    }
+}
@@ -0,0 +1,837 @@
+//! The shim/daemon protocol.
+//!
+//! Every message is a frame: a big endian `u32` payload length followed by the payload. A payload
+//! starts with the protocol [`VERSION`] and a message tag, then the message body. Integers are big
+//! endian, strings are a `u32` byte length followed by UTF-8 bytes, lists are a `u32` count
+//! followed by the items, optional values are a `0` byte or a `1` byte followed by the value, and
+//! addresses are a family byte (`4` or `6`) followed by the octets.
+//!
+//! A connection carries one request and one response. A daemon that receives a version it does
+//! not speak answers with [`Response::Error`] rather than guessing, so shims and daemons can be
+//! upgraded independently as long as the version is bumped whenever the encoding changes.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use crate::group::Group;
+use crate::health::{Health, Status};
+use crate::host::{AddressFamily, Addresses, Host};
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+
+pub const VERSION: u8 = 2;
+
+/// Frames larger than this are rejected instead of being allocated.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// The largest request the daemon reads. Requests carry at most a name, so anything near this is
+/// a client trying to make the daemon buffer it.
+pub const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    PasswdAll,
+    PasswdByUid(Uid),
+    PasswdByName(String),
+    GroupAll,
+    GroupByGid(Gid),
+    GroupByName(String),
+    ShadowAll,
+    ShadowByName(String),
+    HostAll,
+    HostByName(String, AddressFamily),
+    HostByAddr(IpAddr),
+    /// Runs the daemon's health check
+    Health,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Passwd(Vec<Passwd>),
+    Group(Vec<Group>),
+    Shadow(Vec<Shadow>),
+    Host(Vec<Host>),
+    /// The host name exists, with no addresses of the family asked for. Shims from before this
+    /// response read it as an unknown one, and so find nothing as they always did.
+    HostNoData,
+    Health(Health),
+    /// The daemon does not serve this database, or refused to serve it to this caller.
+    Unavailable,
+    Error(String),
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(invalid("frame too large"));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Reads one frame of at most `max_len` bytes into `buf`, reusing its allocation. The buffer grows
+/// as the payload arrives rather than by the length the header claims, so that a peer can't make
+/// the reader commit memory it never sends.
+pub fn read_frame<R: Read>(reader: &mut R, buf: &mut Vec<u8>, max_len: usize) -> io::Result<()> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(invalid("frame too large"));
+    }
+
+    buf.clear();
+    if reader.take(len as u64).read_to_end(buf)? < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// A value with a wire encoding.
+pub trait Wire: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self>;
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(invalid("truncated message"));
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+impl Wire for u8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(take(buf, 1)?[0])
+    }
+}
+
+impl Wire for u32 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(u32::from_be_bytes(take(buf, 4)?.try_into().unwrap()))
+    }
+}
+
+impl Wire for Uid {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_raw().encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        u32::decode(buf).map(Uid::from_raw)
+    }
+}
+
+impl Wire for Gid {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_raw().encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        u32::decode(buf).map(Gid::from_raw)
+    }
+}
+
+impl Wire for i64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(i64::from_be_bytes(take(buf, 8)?.try_into().unwrap()))
+    }
+}
+
+impl Wire for u64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(u64::from_be_bytes(take(buf, 8)?.try_into().unwrap()))
+    }
+}
+
+impl Wire for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        let len = u32::decode(buf)? as usize;
+        let bytes = take(buf, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        for item in self {
+            item.encode(buf);
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        let count = u32::decode(buf)? as usize;
+        // Don't trust the count for the allocation; every item takes at least a byte
+        let mut items = Vec::with_capacity(count.min(buf.len()));
+        for _ in 0..count {
+            items.push(T::decode(buf)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Wire> Wire for Option<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => buf.push(0),
+            Some(value) => {
+                buf.push(1);
+                value.encode(buf);
+            }
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        match u8::decode(buf)? {
+            0 => Ok(None),
+            1 => T::decode(buf).map(Some),
+            _ => Err(invalid("invalid optional value")),
+        }
+    }
+}
+
+impl Wire for IpAddr {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            IpAddr::V4(addr) => {
+                buf.push(4);
+                buf.extend_from_slice(&addr.octets());
+            }
+            IpAddr::V6(addr) => {
+                buf.push(6);
+                buf.extend_from_slice(&addr.octets());
+            }
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        match u8::decode(buf)? {
+            4 => {
+                let octets: [u8; 4] = take(buf, 4)?.try_into().unwrap();
+                Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            6 => {
+                let octets: [u8; 16] = take(buf, 16)?.try_into().unwrap();
+                Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => Err(invalid("unknown address family")),
+        }
+    }
+}
+
+/// Any other family is tagged 255 and followed by its `AF_*` number.
+impl Wire for AddressFamily {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            AddressFamily::Unspecified => buf.push(0),
+            AddressFamily::IPv4 => buf.push(4),
+            AddressFamily::IPv6 => buf.push(6),
+            AddressFamily::Other(family) => {
+                buf.push(255);
+                (*family as u32).encode(buf);
+            }
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        match u8::decode(buf)? {
+            0 => Ok(AddressFamily::Unspecified),
+            4 => Ok(AddressFamily::IPv4),
+            6 => Ok(AddressFamily::IPv6),
+            255 => Ok(AddressFamily::Other(u32::decode(buf)? as libc::c_int)),
+            _ => Err(invalid("unknown address family")),
+        }
+    }
+}
+
+impl Wire for Passwd {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.passwd.encode(buf);
+        self.uid.encode(buf);
+        self.gid.encode(buf);
+        self.gecos.encode(buf);
+        self.dir.encode(buf);
+        self.shell.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(Passwd {
+            name: Wire::decode(buf)?,
+            name_bytes: None,
+            passwd: Wire::decode(buf)?,
+            uid: Wire::decode(buf)?,
+            gid: Wire::decode(buf)?,
+            gecos: Wire::decode(buf)?,
+            dir: Wire::decode(buf)?,
+            shell: Wire::decode(buf)?,
+        })
+    }
+}
+
+impl Wire for Group {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.passwd.encode(buf);
+        self.gid.encode(buf);
+        self.members.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(Group {
+            name: Wire::decode(buf)?,
+            name_bytes: None,
+            passwd: Wire::decode(buf)?,
+            gid: Wire::decode(buf)?,
+            members: Wire::decode(buf)?,
+        })
+    }
+}
+
+impl Wire for Shadow {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.passwd.encode(buf);
+        self.last_change.encode(buf);
+        self.change_min_days.encode(buf);
+        self.change_max_days.encode(buf);
+        self.change_warn_days.encode(buf);
+        self.change_inactive_days.encode(buf);
+        self.expire_date.encode(buf);
+        self.reserved.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(Shadow {
+            name: Wire::decode(buf)?,
+            passwd: Wire::decode(buf)?,
+            last_change: Wire::decode(buf)?,
+            change_min_days: Wire::decode(buf)?,
+            change_max_days: Wire::decode(buf)?,
+            change_warn_days: Wire::decode(buf)?,
+            change_inactive_days: Wire::decode(buf)?,
+            expire_date: Wire::decode(buf)?,
+            reserved: Wire::decode(buf)?,
+        })
+    }
+}
+
+impl Wire for Host {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.aliases.encode(buf);
+        match self.addresses {
+            Addresses::V4(_) => AddressFamily::IPv4.encode(buf),
+            Addresses::V6(_) => AddressFamily::IPv6.encode(buf),
+            Addresses::Mixed(_) => AddressFamily::Unspecified.encode(buf),
+        }
+        self.addresses.to_vec().encode(buf);
+        self.canonical_name.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        let name = Wire::decode(buf)?;
+        let aliases = Wire::decode(buf)?;
+        let family = AddressFamily::decode(buf)?;
+        let addresses: Vec<IpAddr> = Wire::decode(buf)?;
+        let canonical_name = Wire::decode(buf)?;
+
+        let addresses = match family {
+            AddressFamily::IPv4 => Addresses::V4(
+                addresses
+                    .into_iter()
+                    .map(|a| match a {
+                        IpAddr::V4(a) => Ok(a),
+                        IpAddr::V6(_) => Err(invalid("mixed address families")),
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+            AddressFamily::IPv6 => Addresses::V6(
+                addresses
+                    .into_iter()
+                    .map(|a| match a {
+                        IpAddr::V6(a) => Ok(a),
+                        IpAddr::V4(_) => Err(invalid("mixed address families")),
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+            AddressFamily::Unspecified => Addresses::Mixed(addresses),
+            AddressFamily::Other(_) => return Err(invalid("host without address family")),
+        };
+
+        Ok(Host {
+            name,
+            aliases,
+            addresses,
+            canonical_name,
+        })
+    }
+}
+
+impl Wire for Duration {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.as_nanos().min(u64::MAX as u128) as u64).encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        u64::decode(buf).map(Duration::from_nanos)
+    }
+}
+
+impl Wire for Health {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.status.code() as u8).encode(buf);
+        (self.reachable as u8).encode(buf);
+        self.latency.encode(buf);
+        self.cache_age.encode(buf);
+        self.message.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        let status = Status::from_code(u8::decode(buf)? as i32)
+            .ok_or_else(|| invalid("unknown health status"))?;
+        Ok(Health {
+            status,
+            reachable: u8::decode(buf)? != 0,
+            latency: Wire::decode(buf)?,
+            cache_age: Wire::decode(buf)?,
+            message: Wire::decode(buf)?,
+        })
+    }
+}
+
+impl Wire for Request {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Request::PasswdAll => buf.push(1),
+            Request::PasswdByUid(uid) => {
+                buf.push(2);
+                uid.encode(buf);
+            }
+            Request::PasswdByName(name) => {
+                buf.push(3);
+                name.encode(buf);
+            }
+            Request::GroupAll => buf.push(4),
+            Request::GroupByGid(gid) => {
+                buf.push(5);
+                gid.encode(buf);
+            }
+            Request::GroupByName(name) => {
+                buf.push(6);
+                name.encode(buf);
+            }
+            Request::ShadowAll => buf.push(7),
+            Request::ShadowByName(name) => {
+                buf.push(8);
+                name.encode(buf);
+            }
+            Request::HostAll => buf.push(9),
+            Request::HostByName(name, family) => {
+                buf.push(10);
+                name.encode(buf);
+                family.encode(buf);
+            }
+            Request::HostByAddr(addr) => {
+                buf.push(11);
+                addr.encode(buf);
+            }
+            Request::Health => buf.push(12),
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(match u8::decode(buf)? {
+            1 => Request::PasswdAll,
+            2 => Request::PasswdByUid(Wire::decode(buf)?),
+            3 => Request::PasswdByName(Wire::decode(buf)?),
+            4 => Request::GroupAll,
+            5 => Request::GroupByGid(Wire::decode(buf)?),
+            6 => Request::GroupByName(Wire::decode(buf)?),
+            7 => Request::ShadowAll,
+            8 => Request::ShadowByName(Wire::decode(buf)?),
+            9 => Request::HostAll,
+            10 => Request::HostByName(Wire::decode(buf)?, Wire::decode(buf)?),
+            11 => Request::HostByAddr(Wire::decode(buf)?),
+            12 => Request::Health,
+            _ => return Err(invalid("unknown request")),
+        })
+    }
+}
+
+impl Wire for Response {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Response::Passwd(entries) => {
+                buf.push(1);
+                entries.encode(buf);
+            }
+            Response::Group(entries) => {
+                buf.push(2);
+                entries.encode(buf);
+            }
+            Response::Shadow(entries) => {
+                buf.push(3);
+                entries.encode(buf);
+            }
+            Response::Host(entries) => {
+                buf.push(4);
+                entries.encode(buf);
+            }
+            Response::Health(health) => {
+                buf.push(5);
+                health.encode(buf);
+            }
+            Response::HostNoData => buf.push(6),
+            Response::Unavailable => buf.push(0xfe),
+            Response::Error(message) => {
+                buf.push(0xff);
+                message.encode(buf);
+            }
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(match u8::decode(buf)? {
+            1 => Response::Passwd(Wire::decode(buf)?),
+            2 => Response::Group(Wire::decode(buf)?),
+            3 => Response::Shadow(Wire::decode(buf)?),
+            4 => Response::Host(Wire::decode(buf)?),
+            5 => Response::Health(Wire::decode(buf)?),
+            6 => Response::HostNoData,
+            0xfe => Response::Unavailable,
+            0xff => Response::Error(Wire::decode(buf)?),
+            _ => return Err(invalid("unknown response")),
+        })
+    }
+}
+
+/// Encodes a message as a versioned payload, ready for [`write_frame`].
+pub fn encode_message<T: Wire>(message: &T, buf: &mut Vec<u8>) {
+    buf.clear();
+    buf.push(VERSION);
+    message.encode(buf);
+}
+
+/// Decodes a versioned payload produced by [`encode_message`].
+pub fn decode_message<T: Wire>(mut payload: &[u8]) -> io::Result<T> {
+    if u8::decode(&mut payload)? != VERSION {
+        return Err(invalid("unsupported protocol version"));
+    }
+    let message = T::decode(&mut payload)?;
+    if !payload.is_empty() {
+        return Err(invalid("trailing bytes after message"));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn passwd() -> Passwd {
+        Passwd {
+            name: "alice".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(1000),
+            gid: Gid::from_raw(1000),
+            gecos: "Alice".to_string(),
+            dir: "/home/alice".to_string(),
+            shell: "/bin/sh".to_string(),
+        }
+    }
+
+    fn group() -> Group {
+        Group {
+            name: "staff".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            gid: Gid::from_raw(50),
+            members: vec!["alice".to_string(), "bob".to_string()],
+        }
+    }
+
+    fn shadow() -> Shadow {
+        Shadow {
+            name: "alice".to_string(),
+            passwd: "!".to_string(),
+            last_change: 19000,
+            change_min_days: 0,
+            change_max_days: 99999,
+            change_warn_days: 7,
+            change_inactive_days: -1,
+            expire_date: -1,
+            reserved: u64::MAX,
+        }
+    }
+
+    fn hosts() -> Vec<Host> {
+        vec![
+            Host {
+                name: "app.example".to_string(),
+                aliases: vec!["app".to_string()],
+                addresses: Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+                canonical_name: None,
+            },
+            Host {
+                name: "db.example".to_string(),
+                aliases: Vec::new(),
+                addresses: Addresses::V6(vec![Ipv6Addr::LOCALHOST, Ipv6Addr::UNSPECIFIED]),
+                canonical_name: Some("db-1.example".to_string()),
+            },
+            Host {
+                name: "dual.example".to_string(),
+                aliases: Vec::new(),
+                addresses: Addresses::Mixed(vec![
+                    IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                ]),
+                canonical_name: None,
+            },
+        ]
+    }
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::PasswdAll,
+            Request::PasswdByUid(Uid::from_raw(1000)),
+            Request::PasswdByName("alice".to_string()),
+            Request::GroupAll,
+            Request::GroupByGid(Gid::from_raw(50)),
+            Request::GroupByName("staff".to_string()),
+            Request::ShadowAll,
+            Request::ShadowByName("alice".to_string()),
+            Request::HostAll,
+            Request::HostByName("app.example".to_string(), AddressFamily::Unspecified),
+            Request::HostByName("app.example".to_string(), AddressFamily::IPv4),
+            Request::HostByName("app.example".to_string(), AddressFamily::IPv6),
+            Request::HostByName("app.example".to_string(), AddressFamily::Other(17)),
+            Request::HostByAddr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            Request::HostByAddr(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            Request::Health,
+        ]
+    }
+
+    fn responses() -> Vec<Response> {
+        vec![
+            Response::Passwd(vec![passwd()]),
+            Response::Passwd(Vec::new()),
+            Response::Group(vec![group()]),
+            Response::Shadow(vec![shadow()]),
+            Response::Host(hosts()),
+            Response::HostNoData,
+            Response::Health(Health {
+                status: Status::Degraded,
+                reachable: true,
+                latency: Duration::from_millis(12),
+                cache_age: Some(Duration::from_secs(90)),
+                message: "serving cached entries".to_string(),
+            }),
+            Response::Health(Health {
+                status: Status::Unhealthy,
+                reachable: false,
+                latency: Duration::from_secs(5),
+                cache_age: None,
+                message: String::new(),
+            }),
+            Response::Unavailable,
+            Response::Error("no such database".to_string()),
+        ]
+    }
+
+    fn encoded<T: Wire>(message: &T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_message(message, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            let decoded: Request = decode_message(&encoded(&request)).unwrap();
+            assert_eq!(decoded, request);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in responses() {
+            let decoded: Response = decode_message(&encoded(&response)).unwrap();
+            assert_eq!(decoded, response);
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        for request in requests() {
+            let payload = encoded(&request);
+            for len in 0..payload.len() {
+                assert!(decode_message::<Request>(&payload[..len]).is_err());
+            }
+        }
+        for response in responses() {
+            let payload = encoded(&response);
+            for len in 0..payload.len() {
+                assert!(decode_message::<Response>(&payload[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_errors() {
+        let mut payload = encoded(&Request::PasswdAll);
+        payload.push(0);
+        let err = decode_message::<Request>(&payload).unwrap_err();
+        assert_eq!(err.to_string(), "trailing bytes after message");
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        for version in [0, VERSION - 1, VERSION + 1, 0xff] {
+            let mut payload = encoded(&Request::PasswdAll);
+            payload[0] = version;
+            let err = decode_message::<Request>(&payload).unwrap_err();
+            assert_eq!(err.to_string(), "unsupported protocol version");
+        }
+    }
+
+    #[test]
+    fn unknown_tags_are_errors() {
+        assert!(decode_message::<Request>(&[VERSION, 0]).is_err());
+        assert!(decode_message::<Request>(&[VERSION, 13]).is_err());
+        assert!(decode_message::<Response>(&[VERSION, 7]).is_err());
+
+        // A host's addresses are IPv4, IPv6 or mixed
+        let payload = [
+            VERSION, 4, 0, 0, 0, 1, 0, 0, 0, 1, b'a', 0, 0, 0, 0, 255, 0, 0, 0, 17, 0, 0, 0, 0, 0,
+        ];
+        let err = decode_message::<Response>(&payload).unwrap_err();
+        assert_eq!(err.to_string(), "host without address family");
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let payload = encoded(&Request::Health);
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &payload).unwrap();
+        assert_eq!(&stream[..4], &(payload.len() as u32).to_be_bytes());
+
+        let mut buf = vec![0xaa; 64];
+        read_frame(&mut Cursor::new(&stream), &mut buf, MAX_FRAME_LEN).unwrap();
+        assert_eq!(buf, payload);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &encoded(&Request::PasswdAll)).unwrap();
+        for len in 0..stream.len() {
+            let err = read_frame(
+                &mut Cursor::new(&stream[..len]),
+                &mut Vec::new(),
+                MAX_FRAME_LEN,
+            )
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn oversize_frames_are_refused_before_allocating() {
+        let header = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        let mut buf = Vec::new();
+        let err = read_frame(&mut Cursor::new(&header), &mut buf, MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.to_string(), "frame too large");
+        assert_eq!(buf.capacity(), 0);
+
+        let mut stream = Vec::new();
+        let err = write_frame(&mut stream, &vec![0; MAX_FRAME_LEN + 1]).unwrap_err();
+        assert_eq!(err.to_string(), "frame too large");
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn oversize_requests_are_refused_before_allocating() {
+        let header = ((MAX_REQUEST_LEN + 1) as u32).to_be_bytes();
+        let mut buf = Vec::new();
+        let err = read_frame(&mut Cursor::new(&header), &mut buf, MAX_REQUEST_LEN).unwrap_err();
+        assert_eq!(err.to_string(), "frame too large");
+        assert_eq!(buf.capacity(), 0);
+    }
+
+    #[test]
+    fn frame_lengths_are_not_trusted_for_allocation() {
+        // Claims the largest frame allowed, then sends a single byte
+        let mut stream = (MAX_FRAME_LEN as u32).to_be_bytes().to_vec();
+        stream.push(0);
+        let mut buf = Vec::new();
+        let err = read_frame(&mut Cursor::new(&stream), &mut buf, MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn list_counts_are_not_trusted_for_allocation() {
+        let mut payload = vec![VERSION, 1];
+        u32::MAX.encode(&mut payload);
+        assert!(decode_message::<Response>(&payload).is_err());
+    }
+
+    /// Pinned encodings of messages added after the first version, which older shims and daemons
+    /// must keep reading as they did.
+    #[test]
+    fn later_messages_keep_their_encoding() {
+        assert_eq!(encoded(&Request::Health), [VERSION, 12]);
+        assert_eq!(encoded(&Response::HostNoData), [VERSION, 6]);
+        assert_eq!(
+            encoded(&Request::HostByName(
+                "a".to_string(),
+                AddressFamily::Other(17)
+            )),
+            [VERSION, 10, 0, 0, 0, 1, b'a', 255, 0, 0, 0, 17]
+        );
+        assert_eq!(
+            encoded(&Response::Health(Health {
+                status: Status::Healthy,
+                reachable: true,
+                latency: Duration::from_nanos(1),
+                cache_age: None,
+                message: String::new(),
+            })),
+            [VERSION, 5, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]
+        );
+    }
+}
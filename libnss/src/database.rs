@@ -0,0 +1,371 @@
+//! A single trait over every database, for middleware written once rather than per database.
+//!
+//! Each hooks trait ([`PasswdHooks`], [`GroupHooks`], [`ShadowHooks`], [`HostHooks`]) is
+//! implemented for any [`NssDatabase`] with the matching entry and key types, and each has an
+//! adapter going the other way ([`PasswdDatabase`] and friends). A middleware wraps any database
+//! and is itself one, forwarding the wrapped database's associated consts, which would otherwise
+//! fall back to the hooks' defaults:
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! use std::fmt::Debug;
+//! use std::marker::PhantomData;
+//! use std::time::Duration;
+//! use libnss::database::{Answer, NssDatabase, PasswdDatabase};
+//! use libnss::host::NameForm;
+//!
+//! struct Logged<D>(PhantomData<D>);
+//!
+//! impl<D: NssDatabase> NssDatabase for Logged<D>
+//! where
+//!     D::Key: Debug,
+//! {
+//!     type Entry = D::Entry;
+//!     type Key = D::Key;
+//!
+//!     const ENUMERATION_CACHE_TTL: Duration = D::ENUMERATION_CACHE_TTL;
+//!     const TRUNCATE_MEMBERS_AT: Option<usize> = D::TRUNCATE_MEMBERS_AT;
+//!     const RESOLVES_UNSPECIFIED: bool = D::RESOLVES_UNSPECIFIED;
+//!     const NAME_FORM: NameForm = D::NAME_FORM;
+//!
+//!     fn all_entries() -> Vec<D::Entry> {
+//!         D::all_entries()
+//!     }
+//!
+//!     fn lookup(key: D::Key) -> Answer<D::Entry> {
+//!         eprintln!("lookup {:?}", key);
+//!         D::lookup(key)
+//!     }
+//! }
+//!
+//! // Usable anywhere passwd hooks are, e.g. `libnss_passwd_hooks!(example, ExampleHooks)`
+//! type ExampleHooks = Logged<PasswdDatabase<ExamplePasswd>>;
+//! assert!(ExampleHooks::get_entry_by_uid(Uid::from_raw(0)).is_none());
+//! ```
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::group::{Group, GroupHooks};
+use crate::host::{AddressFamily, Host, HostAnswer, HostHooks, NameForm};
+use crate::id::{Gid, Uid};
+use crate::passwd::{Passwd, PasswdHooks};
+use crate::shadow::{Shadow, ShadowHooks};
+
+pub trait NssDatabase {
+    type Entry;
+    /// Everything an entry can be looked up by
+    type Key;
+
+    /// The hooks' `ENUMERATION_CACHE_TTL`.
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(0);
+    /// [`GroupHooks::TRUNCATE_MEMBERS_AT`], for group databases.
+    const TRUNCATE_MEMBERS_AT: Option<usize> = None;
+    /// [`HostHooks::RESOLVES_UNSPECIFIED`], for host databases.
+    const RESOLVES_UNSPECIFIED: bool = false;
+    /// [`HostHooks::NAME_FORM`], for host databases.
+    const NAME_FORM: NameForm = NameForm::AsGiven;
+
+    fn all_entries() -> Vec<Self::Entry>;
+
+    fn lookup(key: Self::Key) -> Answer<Self::Entry>;
+}
+
+/// What a lookup found. Knowing a key but not an entry of the kind asked for, as a host name
+/// without addresses of the family asked for is, is `NoData` rather than `NotFound`, so that
+/// middleware passes it on for resolvers to try another kind instead of giving up on the key.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Answer<E> {
+    Found(E),
+    /// The key exists, without an entry of the kind asked for
+    NoData,
+    NotFound,
+}
+
+impl<E> Answer<E> {
+    pub fn into_entry(self) -> Option<E> {
+        match self {
+            Answer::Found(entry) => Some(entry),
+            Answer::NoData | Answer::NotFound => None,
+        }
+    }
+
+    /// `NotFound` in place of an entry `predicate` rejects, as [`Option::filter`].
+    pub fn filter<P: FnOnce(&E) -> bool>(self, predicate: P) -> Self {
+        match self {
+            Answer::Found(entry) if !predicate(&entry) => Answer::NotFound,
+            answer => answer,
+        }
+    }
+}
+
+impl<E> From<Option<E>> for Answer<E> {
+    fn from(entry: Option<E>) -> Self {
+        entry.map_or(Answer::NotFound, Answer::Found)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PasswdKey {
+    Uid(Uid),
+    Name(String),
+    /// A name as the C string an application passed, which isn't UTF-8
+    NameBytes(CString),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GroupKey {
+    Gid(Gid),
+    Name(String),
+    /// A name as the C string an application passed, which isn't UTF-8
+    NameBytes(CString),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ShadowKey {
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HostKey {
+    Name(String, AddressFamily),
+    /// A name as the C string an application passed, which isn't UTF-8
+    NameBytes(CString, AddressFamily),
+    Addr(IpAddr),
+}
+
+impl fmt::Display for PasswdKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PasswdKey::Uid(uid) => write!(f, "uid={}", uid),
+            PasswdKey::Name(name) => write!(f, "name={}", name),
+            // Escaped rather than lossy, as for host names
+            PasswdKey::NameBytes(name) => write!(f, "name={}", name.to_bytes().escape_ascii()),
+        }
+    }
+}
+
+impl fmt::Display for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupKey::Gid(gid) => write!(f, "gid={}", gid),
+            GroupKey::Name(name) => write!(f, "name={}", name),
+            GroupKey::NameBytes(name) => write!(f, "name={}", name.to_bytes().escape_ascii()),
+        }
+    }
+}
+
+impl fmt::Display for ShadowKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShadowKey::Name(name) => write!(f, "name={}", name),
+        }
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostKey::Name(name, AddressFamily::IPv4) => write!(f, "name={}/ipv4", name),
+            HostKey::Name(name, AddressFamily::IPv6) => write!(f, "name={}/ipv6", name),
+            HostKey::Name(name, AddressFamily::Unspecified) => write!(f, "name={}", name),
+            HostKey::Name(name, AddressFamily::Other(family)) => {
+                write!(f, "name={}/af{}", name, family)
+            }
+            // Escaped rather than lossy, so that different names stay different keys
+            HostKey::NameBytes(name, family) => {
+                let name = name.to_bytes().escape_ascii();
+                match family {
+                    AddressFamily::IPv4 => write!(f, "name={}/ipv4", name),
+                    AddressFamily::IPv6 => write!(f, "name={}/ipv6", name),
+                    AddressFamily::Unspecified => write!(f, "name={}", name),
+                    AddressFamily::Other(family) => write!(f, "name={}/af{}", name, family),
+                }
+            }
+            HostKey::Addr(addr) => write!(f, "addr={}", addr),
+        }
+    }
+}
+
+impl<D: NssDatabase<Entry = Passwd, Key = PasswdKey>> PasswdHooks for D {
+    const ENUMERATION_CACHE_TTL: Duration = <D as NssDatabase>::ENUMERATION_CACHE_TTL;
+
+    fn get_all_entries() -> Vec<Passwd> {
+        D::all_entries()
+    }
+
+    fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+        D::lookup(PasswdKey::Uid(uid)).into_entry()
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Passwd> {
+        D::lookup(PasswdKey::Name(name)).into_entry()
+    }
+
+    fn get_entry_by_name_bytes(name: &CStr) -> Option<Passwd> {
+        D::lookup(PasswdKey::NameBytes(name.to_owned())).into_entry()
+    }
+}
+
+impl<D: NssDatabase<Entry = Group, Key = GroupKey>> GroupHooks for D {
+    const ENUMERATION_CACHE_TTL: Duration = <D as NssDatabase>::ENUMERATION_CACHE_TTL;
+    const TRUNCATE_MEMBERS_AT: Option<usize> = <D as NssDatabase>::TRUNCATE_MEMBERS_AT;
+
+    fn get_all_entries() -> Vec<Group> {
+        D::all_entries()
+    }
+
+    fn get_entry_by_gid(gid: Gid) -> Option<Group> {
+        D::lookup(GroupKey::Gid(gid)).into_entry()
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Group> {
+        D::lookup(GroupKey::Name(name)).into_entry()
+    }
+
+    fn get_entry_by_name_bytes(name: &CStr) -> Option<Group> {
+        D::lookup(GroupKey::NameBytes(name.to_owned())).into_entry()
+    }
+}
+
+impl<D: NssDatabase<Entry = Shadow, Key = ShadowKey>> ShadowHooks for D {
+    const ENUMERATION_CACHE_TTL: Duration = <D as NssDatabase>::ENUMERATION_CACHE_TTL;
+
+    fn get_all_entries() -> Vec<Shadow> {
+        D::all_entries()
+    }
+
+    fn get_entry_by_name(name: String) -> Option<Shadow> {
+        D::lookup(ShadowKey::Name(name)).into_entry()
+    }
+}
+
+impl<D: NssDatabase<Entry = Host, Key = HostKey>> HostHooks for D {
+    const ENUMERATION_CACHE_TTL: Duration = <D as NssDatabase>::ENUMERATION_CACHE_TTL;
+    const RESOLVES_UNSPECIFIED: bool = <D as NssDatabase>::RESOLVES_UNSPECIFIED;
+    const NAME_FORM: NameForm = <D as NssDatabase>::NAME_FORM;
+
+    fn get_all_entries() -> Vec<Host> {
+        D::all_entries()
+    }
+
+    fn get_host_by_name(name: &str, family: AddressFamily) -> Option<Host> {
+        Self::resolve_host_by_name(name, family).into_host()
+    }
+
+    fn resolve_host_by_name(name: &str, family: AddressFamily) -> HostAnswer {
+        D::lookup(HostKey::Name(name.to_string(), family))
+    }
+
+    fn resolve_host_by_name_bytes(name: &CStr, family: AddressFamily) -> HostAnswer {
+        D::lookup(HostKey::NameBytes(name.to_owned(), family))
+    }
+
+    fn get_host_by_addr(addr: IpAddr) -> Option<Host> {
+        D::lookup(HostKey::Addr(addr)).into_host()
+    }
+}
+
+/// Passwd hooks as an [`NssDatabase`].
+pub struct PasswdDatabase<P>(PhantomData<P>);
+
+impl<P: PasswdHooks> NssDatabase for PasswdDatabase<P> {
+    type Entry = Passwd;
+    type Key = PasswdKey;
+
+    const ENUMERATION_CACHE_TTL: Duration = P::ENUMERATION_CACHE_TTL;
+
+    fn all_entries() -> Vec<Passwd> {
+        P::get_all_entries()
+    }
+
+    fn lookup(key: PasswdKey) -> Answer<Passwd> {
+        match key {
+            PasswdKey::Uid(uid) => P::get_entry_by_uid(uid),
+            PasswdKey::Name(name) => P::get_entry_by_name(name),
+            PasswdKey::NameBytes(name) => P::get_entry_by_name_bytes(&name),
+        }
+        .into()
+    }
+}
+
+/// Group hooks as an [`NssDatabase`].
+pub struct GroupDatabase<G>(PhantomData<G>);
+
+impl<G: GroupHooks> NssDatabase for GroupDatabase<G> {
+    type Entry = Group;
+    type Key = GroupKey;
+
+    const ENUMERATION_CACHE_TTL: Duration = G::ENUMERATION_CACHE_TTL;
+    const TRUNCATE_MEMBERS_AT: Option<usize> = G::TRUNCATE_MEMBERS_AT;
+
+    fn all_entries() -> Vec<Group> {
+        G::get_all_entries()
+    }
+
+    fn lookup(key: GroupKey) -> Answer<Group> {
+        match key {
+            GroupKey::Gid(gid) => G::get_entry_by_gid(gid),
+            GroupKey::Name(name) => G::get_entry_by_name(name),
+            GroupKey::NameBytes(name) => G::get_entry_by_name_bytes(&name),
+        }
+        .into()
+    }
+}
+
+/// Shadow hooks as an [`NssDatabase`].
+pub struct ShadowDatabase<S>(PhantomData<S>);
+
+impl<S: ShadowHooks> NssDatabase for ShadowDatabase<S> {
+    type Entry = Shadow;
+    type Key = ShadowKey;
+
+    const ENUMERATION_CACHE_TTL: Duration = S::ENUMERATION_CACHE_TTL;
+
+    fn all_entries() -> Vec<Shadow> {
+        S::get_all_entries()
+    }
+
+    fn lookup(key: ShadowKey) -> Answer<Shadow> {
+        match key {
+            ShadowKey::Name(name) => S::get_entry_by_name(name),
+        }
+        .into()
+    }
+}
+
+/// Host hooks as an [`NssDatabase`].
+pub struct HostDatabase<H>(PhantomData<H>);
+
+impl<H: HostHooks> NssDatabase for HostDatabase<H> {
+    type Entry = Host;
+    type Key = HostKey;
+
+    const ENUMERATION_CACHE_TTL: Duration = H::ENUMERATION_CACHE_TTL;
+    const RESOLVES_UNSPECIFIED: bool = H::RESOLVES_UNSPECIFIED;
+    const NAME_FORM: NameForm = H::NAME_FORM;
+
+    fn all_entries() -> Vec<Host> {
+        H::get_all_entries()
+    }
+
+    /// Names go through `resolve_host_by_name` and its bytes counterpart, as lookups do, so that
+    /// a name without addresses of the family asked for stays `NoData`.
+    fn lookup(key: HostKey) -> HostAnswer {
+        match key {
+            HostKey::Name(name, family) => H::resolve_host_by_name(&name, family),
+            HostKey::NameBytes(name, family) => H::resolve_host_by_name_bytes(&name, family),
+            HostKey::Addr(addr) => H::get_host_by_addr(addr).into(),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Entries of the ethers database, which maps hardware addresses to hostnames for
+//! `ether_ntohost` and `ether_hostton`.
+//!
+//! Addresses are [`MacAddress`]es rather than strings, so that a backend can't hand back one the
+//! C side would have to reject, and so that `00:1A:2b:3c:4d:5e`, `0:1a:2b:3c:4d:5e` and
+//! `00-1a-2b-3c-4d-5e` all look up the same entry.
+//!
+//! ```
+//! use libnss::ether::MacAddress;
+//!
+//! let addr: MacAddress = "00-1A-2b-3c-4d-5e".parse().unwrap();
+//! assert_eq!(addr, MacAddress([0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e]));
+//! assert_eq!(addr.to_string(), "00:1a:2b:3c:4d:5e");
+//! assert_eq!(addr.to_dashed_string(), "00-1a-2b-3c-4d-5e");
+//! assert_eq!("0:1a:2b:3c:4d:5e".parse(), Ok(addr));
+//! assert!("00:1a:2b-3c:4d:5e".parse::<MacAddress>().is_err());
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ether {
+    pub name: String,
+    pub addr: MacAddress,
+}
+
+/// A 48-bit IEEE 802 hardware address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    pub fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+
+    /// Whether the address names a group of interfaces rather than one, the broadcast address
+    /// among them.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddress::BROADCAST
+    }
+
+    /// Whether the address was assigned locally, as for virtual interfaces, rather than by the
+    /// vendor.
+    pub fn is_local(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    /// The address as `00-1a-2b-3c-4d-5e`, the form Windows tools use.
+    pub fn to_dashed_string(&self) -> String {
+        self.to_string().replace(':', "-")
+    }
+
+    pub fn to_c_ether_addr(self) -> CEtherAddr {
+        CEtherAddr {
+            ether_addr_octet: self.0,
+        }
+    }
+}
+
+impl From<[u8; 6]> for MacAddress {
+    fn from(octets: [u8; 6]) -> Self {
+        MacAddress(octets)
+    }
+}
+
+impl From<CEtherAddr> for MacAddress {
+    fn from(addr: CEtherAddr) -> Self {
+        MacAddress(addr.ether_addr_octet)
+    }
+}
+
+/// As `00:1a:2b:3c:4d:5e`, the form `/etc/ethers` uses.
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// Parses six hexadecimal octets of one or two digits, separated by all colons or all dashes.
+/// One digit octets are what `ether_ntoa` writes.
+impl FromStr for MacAddress {
+    type Err = MacAddressError;
+
+    fn from_str(s: &str) -> Result<Self, MacAddressError> {
+        let invalid = || MacAddressError(s.to_string());
+        let separator = if s.contains('-') { '-' } else { ':' };
+
+        let mut octets = [0u8; 6];
+        let mut parts = s.split(separator);
+        for octet in octets.iter_mut() {
+            let part = parts.next().ok_or_else(invalid)?;
+            if part.is_empty() || part.len() > 2 || !part.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            *octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(MacAddress(octets))
+    }
+}
+
+/// A string that isn't a hardware address, as given.
+#[derive(Debug, PartialEq)]
+pub struct MacAddressError(pub String);
+
+impl fmt::Display for MacAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` is not a valid hardware address", self.0)
+    }
+}
+
+impl Error for MacAddressError {}
+
+/// NSS C `struct ether_addr`, which libc doesn't export
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CEtherAddr {
+    pub ether_addr_octet: [u8; 6],
+}
@@ -0,0 +1,582 @@
+//! Parsing and writing the classic `/etc/passwd`, `/etc/group`, `/etc/shadow` and `/etc/hosts`
+//! formats, for modules that serve "files, but from somewhere else".
+//!
+//! Parsing follows glibc's files source: blank lines and `#` comments are skipped, and hosts
+//! lines may also end in a comment. A malformed line is reported with its line number rather than
+//! ending the parse, so callers can skip it like glibc does or reject the whole file.
+//!
+//! The formats have no escaping, so writing a field that contains its own separator (or a
+//! newline) is an error rather than silently producing a different entry. A backslash is an
+//! ordinary character, as glibc reads it, so a line escaping a separator the way other formats do
+//! is split at it: a passwd, group or shadow line then has too many fields, and is malformed.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::group::Group;
+use crate::host::{Addresses, Host};
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+use crate::validate::Validate;
+
+/// An entry type with a line based files format.
+pub trait FilesEntry: Sized {
+    /// Parses one line, which has already had comments and surrounding whitespace removed.
+    fn from_line(line: &str) -> Result<Self, String>;
+
+    /// Formats the entry as it would appear in the file, without a trailing newline. Hosts with
+    /// several addresses take one line per address.
+    fn to_line(&self) -> io::Result<String>;
+
+    /// Whether `#` starts a comment part way through a line, rather than only at its start.
+    const TRAILING_COMMENTS: bool = false;
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    /// 1-based
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parses every entry in `contents`, yielding an error in place of each malformed line.
+pub fn parse<T: FilesEntry>(contents: &str) -> impl Iterator<Item = Result<T, ParseError>> + '_ {
+    entries(contents).map(|(line, entry)| entry.map_err(|message| ParseError { line, message }))
+}
+
+/// Each entry in `contents` with its 1-based line number.
+fn entries<T: FilesEntry>(contents: &str) -> impl Iterator<Item = (usize, Result<T, String>)> + '_ {
+    contents.lines().enumerate().filter_map(|(index, line)| {
+        let line = if T::TRAILING_COMMENTS {
+            line.split('#').next().unwrap_or_default()
+        } else {
+            line
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        Some((index + 1, T::from_line(line)))
+    })
+}
+
+/// Parses `s` as exactly one entry, for `FromStr`.
+fn parse_one<T: FilesEntry>(s: &str) -> Result<T, ParseError> {
+    let mut entries = entries::<T>(s);
+    let entry = match entries.next() {
+        Some((line, entry)) => entry.map_err(|message| ParseError { line, message })?,
+        None => {
+            return Err(ParseError {
+                line: 1,
+                message: "expected an entry".to_string(),
+            })
+        }
+    };
+    match entries.next() {
+        Some((line, _)) => Err(ParseError {
+            line,
+            message: "expected a single entry".to_string(),
+        }),
+        None => Ok(entry),
+    }
+}
+
+/// Reads every well formed entry from `path`, skipping malformed lines as glibc does.
+pub fn read<T: FilesEntry, P: AsRef<Path>>(path: P) -> io::Result<Vec<T>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(parse(&contents).filter_map(Result::ok).collect())
+}
+
+/// Formats `entries` as a complete file.
+pub fn serialize<T: FilesEntry>(entries: &[T]) -> io::Result<String> {
+    let mut contents = String::new();
+    for entry in entries {
+        contents.push_str(&entry.to_line()?);
+        contents.push('\n');
+    }
+    Ok(contents)
+}
+
+fn fields(line: &str, count: usize) -> Result<Vec<&str>, String> {
+    let fields: Vec<&str> = line.split(':').collect();
+    if fields.len() != count {
+        let hint = if line.contains("\\:") {
+            " (`\\:` is not an escape)"
+        } else {
+            ""
+        };
+        return Err(format!(
+            "expected {} fields, found {}{}",
+            count,
+            fields.len(),
+            hint
+        ));
+    }
+    Ok(fields)
+}
+
+fn number<T: std::str::FromStr>(field: &str, name: &str) -> Result<T, String> {
+    field
+        .parse()
+        .map_err(|_| format!("{} `{}` is not a valid number", name, field))
+}
+
+/// A shadow field that may be left empty, which glibc reads as -1.
+fn optional_number(field: &str, name: &str) -> Result<i64, String> {
+    if field.is_empty() {
+        Ok(-1)
+    } else {
+        number(field, name)
+    }
+}
+
+fn check(entry: &impl Validate) -> io::Result<()> {
+    entry
+        .validate()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+impl FilesEntry for Passwd {
+    fn from_line(line: &str) -> Result<Self, String> {
+        if line.starts_with('+') || line.starts_with('-') {
+            return Err("NIS compat entries are not supported".to_string());
+        }
+        let fields = fields(line, 7)?;
+
+        Ok(Passwd {
+            name: fields[0].to_string(),
+            name_bytes: None,
+            passwd: fields[1].to_string(),
+            uid: number(fields[2], "uid")?,
+            gid: number(fields[3], "gid")?,
+            gecos: fields[4].to_string(),
+            dir: fields[5].to_string(),
+            shell: fields[6].to_string(),
+        })
+    }
+
+    fn to_line(&self) -> io::Result<String> {
+        check(self)?;
+        Ok(self.to_string())
+    }
+}
+
+impl FilesEntry for Group {
+    fn from_line(line: &str) -> Result<Self, String> {
+        if line.starts_with('+') || line.starts_with('-') {
+            return Err("NIS compat entries are not supported".to_string());
+        }
+        let fields = fields(line, 4)?;
+        let members = fields[3]
+            .split(',')
+            .filter(|member| !member.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        Ok(Group {
+            name: fields[0].to_string(),
+            name_bytes: None,
+            passwd: fields[1].to_string(),
+            gid: number(fields[2], "gid")?,
+            members,
+        })
+    }
+
+    fn to_line(&self) -> io::Result<String> {
+        check(self)?;
+        Ok(self.to_string())
+    }
+}
+
+impl FilesEntry for Shadow {
+    fn from_line(line: &str) -> Result<Self, String> {
+        if line.starts_with('+') || line.starts_with('-') {
+            return Err("NIS compat entries are not supported".to_string());
+        }
+        let fields = fields(line, 9)?;
+
+        Ok(Shadow {
+            name: fields[0].to_string(),
+            passwd: fields[1].to_string(),
+            last_change: optional_number(fields[2], "last change")?,
+            change_min_days: optional_number(fields[3], "minimum age")?,
+            change_max_days: optional_number(fields[4], "maximum age")?,
+            change_warn_days: optional_number(fields[5], "warning period")?,
+            change_inactive_days: optional_number(fields[6], "inactivity period")?,
+            expire_date: optional_number(fields[7], "expiration date")?,
+            reserved: if fields[8].is_empty() {
+                u64::MAX
+            } else {
+                number(fields[8], "reserved field")?
+            },
+        })
+    }
+
+    fn to_line(&self) -> io::Result<String> {
+        check(self)?;
+        Ok(self.to_string())
+    }
+}
+
+impl FilesEntry for Host {
+    const TRAILING_COMMENTS: bool = true;
+
+    /// Each hosts line holds a single address, so the result always has exactly one.
+    fn from_line(line: &str) -> Result<Self, String> {
+        let mut fields = line.split_whitespace();
+        let address = fields.next().unwrap_or_default();
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("`{}` is not a valid address", address))?;
+        let name = fields
+            .next()
+            .ok_or_else(|| "expected a host name after the address".to_string())?;
+
+        Ok(Host {
+            name: name.to_string(),
+            aliases: fields.map(str::to_string).collect(),
+            addresses: address.into(),
+            canonical_name: None,
+        })
+    }
+
+    fn to_line(&self) -> io::Result<String> {
+        check(self)?;
+
+        if self.addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("host `{}` has no addresses", self.name),
+            ));
+        }
+        Ok(self.to_string())
+    }
+}
+
+/// Parses a line of `/etc/passwd` or `getent passwd` output.
+impl FromStr for Passwd {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        parse_one(s)
+    }
+}
+
+/// Parses a line of `/etc/group` or `getent group` output.
+impl FromStr for Group {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        parse_one(s)
+    }
+}
+
+/// Parses a line of `/etc/shadow` or `getent shadow` output.
+impl FromStr for Shadow {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        parse_one(s)
+    }
+}
+
+/// Parses `getent hosts` output for one host, or `/etc/hosts` lines for it: one line per address,
+/// all with the same name and address family.
+impl FromStr for Host {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let mut host: Option<Host> = None;
+        for (line, entry) in entries::<Host>(s) {
+            let entry = entry.map_err(|message| ParseError { line, message })?;
+            let host = match &mut host {
+                Some(host) => host,
+                None => {
+                    host = Some(entry);
+                    continue;
+                }
+            };
+
+            if entry.name != host.name {
+                return Err(ParseError {
+                    line,
+                    message: format!("expected host `{}`, found `{}`", host.name, entry.name),
+                });
+            }
+            match (&mut host.addresses, entry.addresses) {
+                (Addresses::V4(all), Addresses::V4(addrs)) => all.extend(addrs),
+                (Addresses::V6(all), Addresses::V6(addrs)) => all.extend(addrs),
+                _ => {
+                    return Err(ParseError {
+                        line,
+                        message: "a host's addresses must all be the same family".to_string(),
+                    })
+                }
+            }
+            for alias in entry.aliases {
+                if !host.aliases.contains(&alias) {
+                    host.aliases.push(alias);
+                }
+            }
+        }
+
+        host.ok_or_else(|| ParseError {
+            line: 1,
+            message: "expected an entry".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::{Gid, Uid};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn passwd() -> Passwd {
+        Passwd {
+            name: "alice".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(1000),
+            gid: Gid::from_raw(1000),
+            gecos: "Alice Example,,,".to_string(),
+            dir: "/home/alice".to_string(),
+            shell: "/bin/bash".to_string(),
+        }
+    }
+
+    fn group() -> Group {
+        Group {
+            name: "staff".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            gid: Gid::from_raw(50),
+            members: vec!["alice".to_string(), "bob".to_string()],
+        }
+    }
+
+    fn shadow() -> Shadow {
+        Shadow {
+            name: "alice".to_string(),
+            passwd: "$6$salt$hash".to_string(),
+            last_change: 19000,
+            change_min_days: 0,
+            change_max_days: 99999,
+            change_warn_days: 7,
+            change_inactive_days: -1,
+            expire_date: -1,
+            reserved: u64::MAX,
+        }
+    }
+
+    fn host() -> Host {
+        Host {
+            name: "app.example".to_string(),
+            aliases: vec!["app".to_string()],
+            addresses: Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]),
+            canonical_name: None,
+        }
+    }
+
+    /// The message for each line that failed to parse, by line number.
+    fn errors<T: FilesEntry>(contents: &str) -> Vec<(usize, String)> {
+        parse::<T>(contents)
+            .filter_map(Result::err)
+            .map(|err| (err.line, err.message))
+            .collect()
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let contents = "# users\n\n   \nalice:x:1000:1000:Alice Example,,,:/home/alice:/bin/bash\n  # indented\n";
+        let entries: Vec<Passwd> = parse(contents).collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries, [passwd()]);
+    }
+
+    #[test]
+    fn only_hosts_lines_end_in_comments() {
+        let host: Host = "10.0.0.1 app.example app # the app server".parse().unwrap();
+        assert_eq!(host.aliases, ["app"]);
+
+        // `#` is an ordinary character part way through a passwd line
+        let passwd: Passwd = "alice:x:1000:1000:Room #4:/home/alice:/bin/bash"
+            .parse()
+            .unwrap();
+        assert_eq!(passwd.gecos, "Room #4");
+    }
+
+    #[test]
+    fn malformed_lines_are_reported_by_line_number() {
+        let contents = "alice:x:1000:1000::/home/alice\n\
+                        bob:x:abc:1000::/home/bob:/bin/sh\n\
+                        # comment\n\
+                        carol:x:1002:1002::/home/carol:/bin/sh\n\
+                        +dave::::::\n";
+        assert_eq!(
+            errors::<Passwd>(contents),
+            [
+                (1, "expected 7 fields, found 6".to_string()),
+                (2, "uid `abc` is not a valid number".to_string()),
+                (5, "NIS compat entries are not supported".to_string()),
+            ]
+        );
+        assert_eq!(parse::<Passwd>(contents).filter(Result::is_ok).count(), 1);
+    }
+
+    #[test]
+    fn malformed_hosts_lines() {
+        assert_eq!(
+            errors::<Host>("10.0.0.300 app.example\n10.0.0.1\n::1 localhost\n"),
+            [
+                (1, "`10.0.0.300` is not a valid address".to_string()),
+                (2, "expected a host name after the address".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_fields() {
+        let passwd: Passwd = "nobody::65534:65534:::".parse().unwrap();
+        assert_eq!((passwd.passwd.as_str(), passwd.gecos.as_str()), ("", ""));
+        assert_eq!((passwd.dir.as_str(), passwd.shell.as_str()), ("", ""));
+
+        let group: Group = "empty:x:100:".parse().unwrap();
+        assert!(group.members.is_empty());
+        let group: Group = "sparse:x:101:alice,,bob,".parse().unwrap();
+        assert_eq!(group.members, ["alice", "bob"]);
+
+        // Unset shadow fields read as -1, and an unset reserved field as glibc's all ones
+        let shadow: Shadow = "alice:!:::::::".parse().unwrap();
+        assert_eq!(shadow.last_change, -1);
+        assert_eq!(shadow.expire_date, -1);
+        assert_eq!(shadow.reserved, u64::MAX);
+    }
+
+    #[test]
+    fn backslashes_are_not_escapes() {
+        assert_eq!(
+            errors::<Passwd>("alice:x:1000:1000:Alice\\: Example:/home/alice:/bin/bash"),
+            [(
+                1,
+                "expected 7 fields, found 8 (`\\:` is not an escape)".to_string()
+            )]
+        );
+
+        // Split at the separator as glibc splits them, keeping the backslash
+        let group: Group = "staff:x:50:alice\\,bob".parse().unwrap();
+        assert_eq!(group.members, ["alice\\", "bob"]);
+        let host: Host = "10.0.0.1 app\\ server.example".parse().unwrap();
+        assert_eq!(host.name, "app\\");
+        assert_eq!(host.aliases, ["server.example"]);
+        let host: Host = "10.0.0.1 app\\#1.example".parse().unwrap();
+        assert_eq!(host.name, "app\\");
+
+        // So a field ending in one round trips
+        let passwd = Passwd {
+            gecos: "DOMAIN\\".to_string(),
+            ..passwd()
+        };
+        assert_eq!(passwd.to_line().unwrap().parse::<Passwd>().unwrap(), passwd);
+    }
+
+    #[test]
+    fn from_str_wants_exactly_one_entry() {
+        let err = "".parse::<Passwd>().unwrap_err();
+        assert_eq!(err.to_string(), "line 1: expected an entry");
+        let err = "# only a comment\n".parse::<Group>().unwrap_err();
+        assert_eq!(err.message, "expected an entry");
+
+        let two = "alice:x:1000:1000::/home/alice:/bin/sh\n\nbob:x:1001:1001::/home/bob:/bin/sh";
+        let err = two.parse::<Passwd>().unwrap_err();
+        assert_eq!(
+            (err.line, err.message.as_str()),
+            (3, "expected a single entry")
+        );
+    }
+
+    #[test]
+    fn host_lines_merge_into_one_host() {
+        let host: Host = "10.0.0.1 app.example app\n10.0.0.2 app.example app-2"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            host.addresses,
+            Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)])
+        );
+        assert_eq!(host.aliases, ["app", "app-2"]);
+
+        let err = "10.0.0.1 app.example\n10.0.0.2 db.example"
+            .parse::<Host>()
+            .unwrap_err();
+        assert_eq!(err.line, 2);
+        let err = "10.0.0.1 app.example\n::1 app.example"
+            .parse::<Host>()
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "a host's addresses must all be the same family"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(passwd().to_string().parse::<Passwd>().unwrap(), passwd());
+        assert_eq!(group().to_string().parse::<Group>().unwrap(), group());
+        assert_eq!(shadow().to_string().parse::<Shadow>().unwrap(), shadow());
+        assert_eq!(host().to_string().parse::<Host>().unwrap(), host());
+
+        let v6 = Host {
+            addresses: Addresses::V6(vec![Ipv6Addr::LOCALHOST]),
+            aliases: Vec::new(),
+            ..host()
+        };
+        assert_eq!(v6.to_string().parse::<Host>().unwrap(), v6);
+    }
+
+    #[test]
+    fn serialized_files_parse_back() {
+        let contents = serialize(&[passwd(), passwd()]).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        let entries: Vec<Passwd> = parse(&contents).collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries, [passwd(), passwd()]);
+
+        // A host takes a line per address
+        let contents = serialize(&[host()]).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(parse::<Host>(&contents).count(), 2);
+    }
+
+    #[test]
+    fn fields_the_format_cannot_hold_are_not_written() {
+        let passwd = Passwd {
+            gecos: "Alice: admin".to_string(),
+            ..passwd()
+        };
+        assert!(passwd.to_line().is_err());
+        let group = Group {
+            members: vec!["alice,bob".to_string()],
+            ..group()
+        };
+        assert!(serialize(&[group]).is_err());
+        let host = Host {
+            addresses: Addresses::V4(Vec::new()),
+            ..host()
+        };
+        assert!(host.to_line().is_err());
+    }
+}
@@ -1,27 +1,275 @@
-use crate::interop::CBuffer;
+use crate::audit;
+use crate::id::Gid;
+use crate::interop::{
+    align_padding, checked_size, rebase_ptr, wipe, write_entry, CBlob, CBuffer, NssStatus, Rebase,
+    SizeOverflow,
+};
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::mem;
+use std::time::Duration;
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Group {
     pub name: String,
+    /// The name's original bytes, for a name that isn't UTF-8, such as a latin-1 one from a legacy
+    /// directory. Every lookup copies these rather than `name`, the bytes decoded, so that the
+    /// name a `getgrgid` returns finds the entry again.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub name_bytes: Option<CString>,
     pub passwd: String,
-    pub gid: libc::gid_t,
+    pub gid: Gid,
     pub members: Vec<String>,
 }
 
 impl Group {
-    pub unsafe fn to_c_group(self, pwbuf: *mut CGroup, buffer: &mut CBuffer) {
-        (*pwbuf).name = buffer.write_str(self.name);
-        (*pwbuf).passwd = buffer.write_str(self.passwd);
-        (*pwbuf).gid = self.gid;
-        (*pwbuf).members = buffer.write_strs(&self.members);
+    pub unsafe fn to_c_group(mut self, pwbuf: *mut CGroup, buffer: &mut CBuffer) {
+        let written = self.write_c_group(None, &self.members, pwbuf, buffer);
+        wipe(&mut self.passwd);
+        written.expect("Not enough free space in buffer")
+    }
+
+    /// Copies the fields with `members` in place of the entry's own, and under `name` rather than
+    /// its own name if given, without taking them, or returns `SizeOverflow` once one doesn't
+    /// fit. The members array comes first, so that only the start of a misaligned buffer is
+    /// skipped to align it.
+    unsafe fn write_c_group(
+        &self,
+        name: Option<&CStr>,
+        members: &[String],
+        pwbuf: *mut CGroup,
+        buffer: &mut CBuffer,
+    ) -> Result<(), SizeOverflow> {
+        let array = buffer.reserve_ptr_array(members.len())?;
+        (*pwbuf).name = match name.or(self.name_bytes.as_deref()) {
+            Some(name) => buffer.write_cstr(name)?,
+            None => buffer.copy_str(&self.name)?,
+        };
+        (*pwbuf).passwd = buffer.copy_str(&self.passwd)?;
+        (*pwbuf).gid = self.gid.as_raw();
+        for (index, member) in members.iter().enumerate() {
+            let member = buffer.copy_str(member)?;
+            buffer.set_ptr(array.add(index), member);
+        }
+        (*pwbuf).members = array;
+        Ok(())
+    }
+
+    /// The space the entry needs in an aligned buffer, which `to_c_group` must be given, or
+    /// `usize::MAX` for one too big to lay out at all. A misaligned buffer needs its
+    /// [`align_padding`] more.
+    pub fn c_size(&self) -> usize {
+        self.checked_c_size(None, &self.members)
+            .unwrap_or(usize::MAX)
+    }
+
+    fn checked_c_size(
+        &self,
+        name: Option<&CStr>,
+        members: &[String],
+    ) -> Result<usize, SizeOverflow> {
+        let name = name
+            .or(self.name_bytes.as_deref())
+            .map_or(self.name.len() + 1, |name| name.to_bytes_with_nul().len());
+        let fields = [name, self.passwd.len() + 1, POINTER_SIZE];
+        checked_size(
+            fields
+                .iter()
+                .copied()
+                .chain(members.iter().map(|m| member_size(m))),
+        )
+    }
+
+    /// How many of the members fit in `buflen` bytes of an aligned buffer along with the rest of
+    /// the entry, under `name` if given, or `None` if it doesn't fit even without any.
+    fn fitting_members(&self, name: Option<&CStr>, buflen: usize) -> Option<usize> {
+        let mut size = self.checked_c_size(name, &[]).ok()?;
+        if size > buflen {
+            return None;
+        }
+        Some(
+            self.members
+                .iter()
+                .take_while(|member| {
+                    size = size.saturating_add(member_size(member));
+                    size <= buflen
+                })
+                .count(),
+        )
+    }
+
+    /// The entry with as many of its members as fit in `buflen` bytes of an aligned buffer, or
+    /// `None` if it doesn't fit even without any.
+    pub fn truncated_to(&self, buflen: usize) -> Option<Group> {
+        let fitting = self.fitting_members(None, buflen)?;
+        Some(Group {
+            name: self.name.clone(),
+            name_bytes: self.name_bytes.clone(),
+            passwd: self.passwd.clone(),
+            gid: self.gid,
+            members: self.members[..fitting].to_vec(),
+        })
+    }
+
+    /// The entry serialized once, to copy into each caller's buffer. Panics for an entry too big to
+    /// lay out, which [`try_to_c_blob`](Self::try_to_c_blob) returns as an error instead.
+    pub fn to_c_blob(&self) -> CBlob<CGroup> {
+        self.try_to_c_blob().expect("entry too big to lay out")
+    }
+
+    pub fn try_to_c_blob(&self) -> Result<CBlob<CGroup>, SizeOverflow> {
+        let capacity = self.checked_c_size(None, &self.members)?;
+        unsafe {
+            Ok(CBlob::new(capacity, |pwbuf, buffer| {
+                self.write_c_group(None, &self.members, pwbuf, buffer)
+                    .expect("entry sized to fit")
+            }))
+        }
+    }
+
+    /// The entry serialized as [`try_to_c_blob`](Self::try_to_c_blob) does, but under `name`, the
+    /// bytes it was looked up by, rather than its own name, which the hooks may have decoded from
+    /// them.
+    pub fn try_to_c_blob_as(&self, name: &CStr) -> Result<CBlob<CGroup>, SizeOverflow> {
+        let capacity = self.checked_c_size(Some(name), &self.members)?;
+        unsafe {
+            Ok(CBlob::new(capacity, |pwbuf, buffer| {
+                self.write_c_group(Some(name), &self.members, pwbuf, buffer)
+                    .expect("entry sized to fit")
+            }))
+        }
+    }
+}
+
+const POINTER_SIZE: usize = mem::size_of::<*mut libc::c_char>();
+
+/// A member's string and its pointer in the members array.
+fn member_size(member: &str) -> usize {
+    member.len() + 1 + POINTER_SIZE
+}
+
+/// Copies `group` as a `getgr*_r` function returns it, straight into the caller's buffer. A buffer
+/// too small for it gets `TryAgain` with `ERANGE` in `errnop`, for the caller to retry with a
+/// bigger one, unless it is at least `truncate_at` bytes: then the group is copied with as many of
+/// its members as fit, and a warning logged.
+pub unsafe fn write_group(
+    group: &Group,
+    truncate_at: Option<usize>,
+    result: *mut CGroup,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+) -> NssStatus {
+    write_group_named(group, None, truncate_at, result, buf, buflen, errnop)
+}
+
+/// [`write_group`] under `name`, the bytes the entry was looked up by, as
+/// [`Group::try_to_c_blob_as`] serializes it.
+pub unsafe fn write_group_as(
+    group: &Group,
+    name: &CStr,
+    truncate_at: Option<usize>,
+    result: *mut CGroup,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+) -> NssStatus {
+    write_group_named(group, Some(name), truncate_at, result, buf, buflen, errnop)
+}
+
+unsafe fn write_group_named(
+    group: &Group,
+    name: Option<&CStr>,
+    truncate_at: Option<usize>,
+    result: *mut CGroup,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+) -> NssStatus {
+    // The members array is aligned, skipping the start of a misaligned buffer
+    let padding = align_padding(buf);
+    let mut members = group.members.as_slice();
+    let size = group.checked_c_size(name, members).unwrap_or(usize::MAX);
+    if size.saturating_add(padding) > buflen && truncate_at.is_some_and(|at| buflen >= at) {
+        let fitting = buflen
+            .checked_sub(padding)
+            .and_then(|aligned| group.fitting_members(name, aligned));
+        if let Some(fitting) = fitting {
+            let shown = name.map_or(Cow::Borrowed(group.name.as_str()), CStr::to_string_lossy);
+            audit::syslog(
+                libc::LOG_WARNING,
+                &format!(
+                    "libnss: group `{}` needs a {} byte buffer but was given {}, so only {} of its \
+                     {} members were returned",
+                    shown.escape_debug(),
+                    size.saturating_add(padding),
+                    buflen,
+                    fitting,
+                    group.members.len()
+                ),
+            );
+            members = &members[..fitting];
+        }
+    }
+    let size = group
+        .checked_c_size(name, members)
+        .and_then(|size| checked_size(vec![size, padding]));
+    write_entry(size, result, buf, buflen, errnop, |pwbuf, buffer| {
+        group.write_c_group(name, members, pwbuf, buffer)
+    })
+}
+
+/// The entry as `getent group` prints it.
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.name,
+            self.passwd,
+            self.gid,
+            self.members.join(",")
+        )
     }
 }
 
 pub trait GroupHooks {
-    fn get_all_entries() -> Vec<Group>;
+    /// How long a `setgrent` may reuse the entries an earlier enumeration fetched, rather
+    /// than fetching them all again. Zero, the default, always fetches.
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(0);
+
+    /// The buffer size from which a group too big for the caller's buffer is returned with only
+    /// the members that fit, logging a warning, rather than `ERANGE`. `None`, the default, never
+    /// truncates, as glibc retries with ever bigger buffers; set it for callers that give up at a
+    /// fixed size instead.
+    const TRUNCATE_MEMBERS_AT: Option<usize> = None;
 
-    fn get_entry_by_gid(gid: libc::gid_t) -> Option<Group>;
+    /// The entries `getent group` lists. The default lists nothing, as
+    /// [`PasswdHooks::get_all_entries`](crate::passwd::PasswdHooks) does.
+    fn get_all_entries() -> Vec<Group> {
+        Vec::new()
+    }
+
+    /// The default finds nothing, so `getgrgid` answers `NotFound`.
+    fn get_entry_by_gid(_gid: Gid) -> Option<Group> {
+        None
+    }
+
+    /// The default finds nothing, so `getgrnam` answers `NotFound`.
+    fn get_entry_by_name(_name: String) -> Option<Group> {
+        None
+    }
 
-    fn get_entry_by_name(name: String) -> Option<Group>;
+    /// `get_entry_by_name` for a name as the C string an application passed, which needn't be
+    /// UTF-8, as [`PasswdHooks::get_entry_by_name_bytes`](crate::passwd::PasswdHooks) is for users.
+    fn get_entry_by_name_bytes(name: &CStr) -> Option<Group> {
+        Self::get_entry_by_name(name.to_str().ok()?.to_string())
+    }
 }
 
 #[repr(C)]
@@ -33,90 +281,195 @@ pub struct CGroup {
     pub members: *mut *mut libc::c_char,
 }
 
+impl Rebase for CGroup {
+    unsafe fn rebase(&mut self, from: usize, len: usize, to: usize) {
+        rebase_ptr(&mut self.name, from, len, to);
+        rebase_ptr(&mut self.passwd, from, len, to);
+        rebase_ptr(&mut self.members, from, len, to);
+    }
+}
+
+/// Generates the `_nss_<module>_*` group functions NSS calls and the `<module>_group` module, as
+/// [`libnss_passwd_hooks!`] does for passwd.
 #[macro_export]
 macro_rules! libnss_group_hooks {
-($mod_ident:ident, $hooks_ident:ident) => (
-    paste::item! {
-        pub use self::[<libnss_group_ $mod_ident _hooks_impl>]::*;
-        mod [<libnss_group_ $mod_ident _hooks_impl>] {
+($mod_ident:ident, $hooks:ty) => (
+    $crate::paste::item! {
+        $crate::libnss_group_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, no_enumeration) => (
+    $crate::paste::item! {
+        $crate::libnss_group_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, no_enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, prefix = $prefix:ident) => (
+    $crate::libnss_group_hooks!(@module $mod_ident, $prefix, $hooks, enumeration);
+);
+($mod_ident:ident, $hooks:ty, no_enumeration, prefix = $prefix:ident) => (
+    $crate::libnss_group_hooks!(@module $mod_ident, $prefix, $hooks, no_enumeration);
+);
+(@module $mod_ident:ident, $prefix:ident, $hooks:ty, $enumeration:ident) => (
+    $crate::paste::item! {
+        // Named here so that the hooks type resolves where the macro was invoked
+        #[allow(non_camel_case_types)]
+        type [<libnss_group_ $mod_ident _hooks>] = $hooks;
+
+        pub mod [<$mod_ident _group>] {
             #![allow(non_upper_case_globals)]
 
-            use std::ffi::CStr;
-            use std::str;
-            use std::sync::{Mutex, MutexGuard};
-            use $crate::interop::{CBuffer, Iterator, NssStatus};
+            use ::std::ffi::CStr;
+            use ::std::str;
+            use $crate::validate::Validate;
+            use $crate::interop::NssStatus;
             use $crate::group::{CGroup, GroupHooks, Group};
+            use $crate::stats::{Call, Database};
+            use super::[<libnss_group_ $mod_ident _hooks>] as Hooks;
 
-            lazy_static! {
-            static ref [<GROUP_ $mod_ident _ITERATOR>]: Mutex<Iterator<Group>> = Mutex::new(Iterator::<Group>::new());
+            /// The entries `setgrent` enumerates, as `getgrent_r` would return them.
+            pub fn all_group_entries() -> Vec<Group> {
+                Hooks::get_all_entries().into_iter().filter(Validate::is_valid).collect()
             }
 
-            #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _setgrent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Group>> = [<GROUP_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.open(super::$hooks_ident::get_all_entries());
-                NssStatus::Success.to_c()
+            /// The entry `getgrgid_r` returns, without going through its C interface.
+            pub fn lookup_group_by_gid(gid: $crate::id::Gid) -> Result<Group, NssStatus> {
+                Hooks::get_entry_by_gid(gid).filter(Validate::is_valid).ok_or(NssStatus::NotFound)
             }
 
-            #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _endgrent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Group>> = [<GROUP_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.close();
-
-                NssStatus::Success.to_c()
+            /// The entry `getgrnam_r` returns, without going through its C interface.
+            pub fn lookup_group_by_name(name: &str) -> Result<Group, NssStatus> {
+                Hooks::get_entry_by_name(name.to_string()).filter(Validate::is_valid).ok_or(NssStatus::NotFound)
             }
 
-            #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getgrent_r>](pwbuf: *mut CGroup, buf: *mut libc::c_char, buflen: libc::size_t,
-                                                                  _errnop: *mut libc::c_int) -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Group>> = [<GROUP_ $mod_ident _ITERATOR>].lock().unwrap();
-                match iter.next() {
-                    None => $crate::interop::NssStatus::NotFound.to_c(),
-                    Some(entry) => {
-                        let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                        buffer.clear();
-
-                        entry.to_c_group(pwbuf, &mut buffer);
-                        NssStatus::Success.to_c()
-                    }
-                }
+            /// The entry `getgrnam_r` returns for a name that isn't UTF-8, which it copies under
+            /// that name.
+            pub fn lookup_group_by_name_bytes(name: &CStr) -> Result<Group, NssStatus> {
+                $crate::validate::validate_name_bytes(name).map_err(|_| NssStatus::NotFound)?;
+                Hooks::get_entry_by_name_bytes(name).filter(Validate::is_valid).ok_or(NssStatus::NotFound)
             }
 
+            $crate::libnss_group_hooks!(@$enumeration $mod_ident, $prefix);
+
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getgrgid_r>](uid: libc::gid_t, pwbuf: *mut CGroup, buf: *mut libc::c_char,
-                                                                  buflen: libc::size_t, _errnop: *mut libc::c_int) -> libc::c_int {
-                match super::$hooks_ident::get_entry_by_gid(uid) {
-                    Some(val) => {
-                        let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                        buffer.clear();
-
-                        val.to_c_group(pwbuf, &mut buffer);
-                        NssStatus::Success.to_c()
+            unsafe extern "C" fn [<$prefix getgrgid_r>](uid: $crate::libc::gid_t, pwbuf: *mut CGroup, buf: *mut $crate::libc::c_char,
+                                                                  buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                let call = Call::start(Database::Group);
+                let status = match lookup_group_by_gid($crate::id::Gid::from_raw(uid)) {
+                    Ok(mut val) => {
+                        let status = $crate::group::write_group(&val, <Hooks as GroupHooks>::TRUNCATE_MEMBERS_AT, pwbuf, buf, buflen, errnop);
+                        $crate::interop::wipe(&mut val.passwd);
+                        status
                     },
-                    None => NssStatus::NotFound.to_c()
-                }
+                    Err(status) => status
+                };
+                call.finish(status, errnop)
             }
 
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getgrnam_r>](name_: *const libc::c_char, pwbuf: *mut CGroup, buf: *mut libc::c_char,
-                                                                  buflen: libc::size_t, _errnop: *mut libc::c_int) -> libc::c_int {
+            unsafe extern "C" fn [<$prefix getgrnam_r>](name_: *const $crate::libc::c_char, pwbuf: *mut CGroup, buf: *mut $crate::libc::c_char,
+                                                                  buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                let call = Call::start(Database::Group);
+                if name_.is_null() {
+                    return call.finish($crate::interop::invalid_argument(errnop), errnop);
+                }
                 let cstr = CStr::from_ptr(name_);
 
-                match str::from_utf8(cstr.to_bytes()) {
-                    Ok(name) => match super::$hooks_ident::get_entry_by_name(name.to_string()) {
-                        Some(val) => {
-                            let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                            buffer.clear();
-
-                            val.to_c_group(pwbuf, &mut buffer);
-                            NssStatus::Success.to_c()
+                let status = match str::from_utf8(cstr.to_bytes()) {
+                    Ok(name) => match lookup_group_by_name(name) {
+                        Ok(mut val) => {
+                            let status = $crate::group::write_group(&val, <Hooks as GroupHooks>::TRUNCATE_MEMBERS_AT, pwbuf, buf, buflen, errnop);
+                            $crate::interop::wipe(&mut val.passwd);
+                            status
                         },
-                        None => NssStatus::NotFound.to_c()
+                        Err(status) => status
                     },
-                    Err(_) => NssStatus::NotFound.to_c()
-                }
+                    Err(_) => match lookup_group_by_name_bytes(cstr) {
+                        Ok(mut val) => {
+                            let status = $crate::group::write_group_as(&val, cstr, <Hooks as GroupHooks>::TRUNCATE_MEMBERS_AT, pwbuf, buf, buflen, errnop);
+                            $crate::interop::wipe(&mut val.passwd);
+                            status
+                        },
+                        Err(status) => status
+                    }
+                };
+                call.finish(status, errnop)
             }
+
+            #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+            #[no_mangle]
+            extern "C" fn [<$prefix group_constr>](_db_name: *const $crate::libc::c_char, _src_name: *const $crate::libc::c_char,
+                                                          _cfg_args: *const $crate::libc::c_char) -> *mut $crate::solaris::NssBackend {
+                $crate::solaris::group_backend::<Hooks>()
+            }
+        }
+    }
+);
+(@enumeration $mod_ident:ident, $prefix:ident) => (
+    $crate::paste::item! {
+        use ::std::sync::{Mutex, MutexGuard};
+        use $crate::interop::EntryCursor;
+
+        static [<GROUP_ $mod_ident _ITERATOR>]: Mutex<EntryCursor<Group>> = Mutex::new(EntryCursor::new());
+
+        #[no_mangle]
+        extern "C" fn [<$prefix setgrent>](stayopen: $crate::libc::c_int) -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Group>> = [<GROUP_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.open_cached(<Hooks as GroupHooks>::ENUMERATION_CACHE_TTL, stayopen != 0, all_group_entries);
+            NssStatus::Success.to_c()
+        }
+
+        #[no_mangle]
+        extern "C" fn [<$prefix endgrent>]() -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Group>> = [<GROUP_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.close();
+
+            NssStatus::Success.to_c()
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn [<$prefix getgrent_r>](pwbuf: *mut CGroup, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t,
+                                                              errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+            let call = Call::start(Database::Group);
+            // Serializing the entry doesn't need the cursor, so release it first
+            let entry = [<GROUP_ $mod_ident _ITERATOR>].lock().unwrap().next();
+            let status = match entry {
+                None => NssStatus::NotFound,
+                Some(mut entry) => {
+                    let status = $crate::group::write_group(&entry, <Hooks as GroupHooks>::TRUNCATE_MEMBERS_AT, pwbuf, buf, buflen, errnop);
+                    // The caller will ask again with a bigger buffer
+                    if status == NssStatus::TryAgain {
+                        [<GROUP_ $mod_ident _ITERATOR>].lock().unwrap().put_back(entry);
+                    } else {
+                        $crate::interop::wipe(&mut entry.passwd);
+                    }
+                    status
+                }
+            };
+            call.finish(status, errnop)
+        }
+    }
+);
+(@no_enumeration $mod_ident:ident, $prefix:ident) => (
+    // The BSDs' method tables need every function, so there an enumeration finds nothing
+    $crate::paste::item! {
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix setgrent>](_stayopen: $crate::libc::c_int) -> $crate::libc::c_int {
+            NssStatus::Success.to_c()
+        }
+
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix endgrent>]() -> $crate::libc::c_int {
+            NssStatus::Success.to_c()
+        }
+
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix getgrent_r>](_result: *mut CGroup, _buf: *mut $crate::libc::c_char, _buflen: $crate::libc::size_t,
+                                                       _errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+            NssStatus::NotFound.to_c()
         }
     }
-)
+);
 }
\ No newline at end of file
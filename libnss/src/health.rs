@@ -0,0 +1,199 @@
+//! A health check entry point, so that fleet monitoring notices identity resolution breaking
+//! before users do.
+//!
+//! A module implements [`HealthCheck`], typically by looking up an entry the backend must always
+//! have with [`Health::probe`], and [`libnss_healthcheck!`](crate::libnss_healthcheck) exports it
+//! as `_nss_<module>_healthcheck`:
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! use libnss::health::{Health, HealthCheck, Status};
+//! use libnss::libnss_healthcheck;
+//!
+//! struct ExampleHealth;
+//!
+//! impl HealthCheck for ExampleHealth {
+//!     fn check() -> Health {
+//!         Health::probe(|| ExamplePasswd::get_entry_by_name("svc-probe".to_string()).is_some())
+//!     }
+//! }
+//!
+//! libnss_healthcheck!(example, ExampleHealth);
+//!
+//! assert_eq!(ExampleHealth::check().status, Status::Unhealthy);
+//! ```
+//!
+//! `cargo libnss healthcheck <module>` loads the installed module and calls it, printing the
+//! report and exiting with the status, as Nagios style checks do. Behind a daemon,
+//! [`ShimHealth`](crate::daemon::shim::ShimHealth) asks the daemon to run the check it was given
+//! with [`DaemonServer::with_healthcheck`](crate::daemon::server::DaemonServer::with_healthcheck).
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How well the module can answer, ordered from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Healthy,
+    /// Answering, but from entries older than they should be, or slowly
+    Degraded,
+    Unhealthy,
+}
+
+impl Status {
+    /// The status as the exported function returns it, and as a monitoring check exits: `0`,
+    /// `1` or `2`.
+    pub fn code(self) -> i32 {
+        match self {
+            Status::Healthy => 0,
+            Status::Degraded => 1,
+            Status::Unhealthy => 2,
+        }
+    }
+
+    pub fn from_code(code: i32) -> Option<Status> {
+        match code {
+            0 => Some(Status::Healthy),
+            1 => Some(Status::Degraded),
+            2 => Some(Status::Unhealthy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Health {
+    pub status: Status,
+    /// Whether the backend answered the probe
+    pub reachable: bool,
+    /// How long the probe took
+    pub latency: Duration,
+    /// How old the entries being served are, for backends that cache them
+    pub cache_age: Option<Duration>,
+    /// Why the status isn't `Healthy`, or empty
+    pub message: String,
+}
+
+impl Health {
+    /// Runs `lookup`, which should find an entry the backend always has: finding it is healthy,
+    /// finding nothing unhealthy.
+    pub fn probe<F: FnOnce() -> bool>(lookup: F) -> Health {
+        let start = Instant::now();
+        let found = lookup();
+        Health {
+            status: if found {
+                Status::Healthy
+            } else {
+                Status::Unhealthy
+            },
+            reachable: found,
+            latency: start.elapsed(),
+            cache_age: None,
+            message: if found {
+                String::new()
+            } else {
+                "the probe lookup found nothing".to_string()
+            },
+        }
+    }
+
+    pub fn unhealthy<M: Into<String>>(message: M) -> Health {
+        Health {
+            status: Status::Unhealthy,
+            reachable: false,
+            latency: Duration::from_secs(0),
+            cache_age: None,
+            message: message.into(),
+        }
+    }
+
+    /// Records the age of the entries being served, degrading a healthy result if it exceeds
+    /// `max_age`.
+    pub fn with_cache_age(mut self, age: Duration, max_age: Duration) -> Health {
+        self.cache_age = Some(age);
+        if age > max_age && self.status == Status::Healthy {
+            self.status = Status::Degraded;
+            self.message = format!("the cached entries are {:?} old", age);
+        }
+        self
+    }
+
+    /// Degrades a healthy result if the probe took longer than `max_latency`.
+    pub fn with_max_latency(mut self, max_latency: Duration) -> Health {
+        if self.latency > max_latency && self.status == Status::Healthy {
+            self.status = Status::Degraded;
+            self.message = format!("the probe lookup took {:?}", self.latency);
+        }
+        self
+    }
+}
+
+/// One line, e.g. `degraded reachable=yes latency=1.2ms cache_age=600s: the cached entries are
+/// 600s old`.
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Healthy => "healthy",
+            Status::Degraded => "degraded",
+            Status::Unhealthy => "unhealthy",
+        };
+        let reachable = if self.reachable { "yes" } else { "no" };
+        write!(
+            f,
+            "{} reachable={} latency={:?}",
+            status, reachable, self.latency
+        )?;
+        if let Some(age) = self.cache_age {
+            write!(f, " cache_age={:?}", age)?;
+        }
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait HealthCheck {
+    fn check() -> Health;
+}
+
+/// Generates `_nss_<module>_healthcheck`, answered by `$check`, any type implementing
+/// [`HealthCheck`](health/trait.HealthCheck.html):
+///
+/// ```c
+/// int _nss_example_healthcheck(char *report, size_t len);
+/// ```
+///
+/// It returns the [`Status`](health/enum.Status.html) code and writes the report into `report`
+/// as one NUL terminated line, truncated to fit. The linker script from
+/// `build::export_nss_symbols` exports it along with the NSS entry points.
+#[macro_export]
+macro_rules! libnss_healthcheck {
+($mod_ident:ident, $check:ty) => (
+    $crate::paste::item! {
+        #[allow(non_camel_case_types)]
+        type [<libnss_ $mod_ident _healthcheck>] = $check;
+
+        #[no_mangle]
+        unsafe extern "C" fn [<_nss_ $mod_ident _healthcheck>](report: *mut $crate::libc::c_char, len: $crate::libc::size_t) -> $crate::libc::c_int {
+            // Monitoring wants to hear about a panic, not be killed by it
+            let health = ::std::panic::catch_unwind(<[<libnss_ $mod_ident _healthcheck>] as $crate::health::HealthCheck>::check)
+                .unwrap_or_else(|_| $crate::health::Health::unhealthy("the health check panicked"));
+            if !report.is_null() && len > 0 {
+                let line = health.to_string();
+                let n = line.len().min(len - 1);
+                ::std::ptr::copy_nonoverlapping(line.as_ptr(), report as *mut u8, n);
+                *report.add(n) = 0;
+            }
+            health.status.code()
+        }
+    }
+);
+}
@@ -1,94 +1,971 @@
-use crate::interop::CBuffer;
-use std::mem;
+use crate::address_order;
+use crate::audit;
+use crate::database::Answer;
+use crate::interop::{
+    align_padding, checked_size, invalid_argument, ptr_array_size, rebase_ptr, write_entry, CBlob,
+    CBuffer, NssStatus, Rebase, SizeOverflow,
+};
+use crate::validate::Validate;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::iter::FromIterator;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::panic;
+use std::thread;
+use std::time::Duration;
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Host {
     pub name: String,
     pub aliases: Vec<String>,
     pub addresses: Addresses,
+    /// The name `name` resolves through (as with a DNS CNAME), if it isn't canonical itself. The
+    /// hostent then carries this as its name and `name` among the aliases.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub canonical_name: Option<String>,
 }
 
-#[derive(PartialEq)]
+/// The family a lookup asks for, as one of libc's `AF_*` constants.
+///
+/// ```
+/// use libnss::host::AddressFamily;
+///
+/// assert_eq!(AddressFamily::from(libc::AF_INET6), AddressFamily::IPv6);
+/// assert_eq!(libc::c_int::from(AddressFamily::Unspecified), libc::AF_UNSPEC);
+/// assert_eq!(AddressFamily::from(libc::AF_PACKET), AddressFamily::Other(libc::AF_PACKET));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AddressFamily {
+    /// `AF_INET`
     IPv4,
+    /// `AF_INET6`
     IPv6,
+    /// `AF_UNSPEC`, for addresses of either family
     Unspecified,
+    /// Any other family, which no [`Host`] has addresses of
+    Other(libc::c_int),
 }
 
+impl From<libc::c_int> for AddressFamily {
+    fn from(family: libc::c_int) -> Self {
+        match family {
+            libc::AF_INET => AddressFamily::IPv4,
+            libc::AF_INET6 => AddressFamily::IPv6,
+            libc::AF_UNSPEC => AddressFamily::Unspecified,
+            other => AddressFamily::Other(other),
+        }
+    }
+}
+
+impl From<AddressFamily> for libc::c_int {
+    fn from(family: AddressFamily) -> Self {
+        match family {
+            AddressFamily::IPv4 => libc::AF_INET,
+            AddressFamily::IPv6 => libc::AF_INET6,
+            AddressFamily::Unspecified => libc::AF_UNSPEC,
+            AddressFamily::Other(other) => other,
+        }
+    }
+}
+
+/// The spelling host hooks are given internationalized names in. Applications may pass either
+/// the Unicode form (`bücher.example`) or the ASCII one (`xn--bcher-kva.example`), so hooks
+/// storing names in one form ask for it to have both resolve.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NameForm {
+    /// The name exactly as the application passed it
+    AsGiven,
+    /// As [`fold_hostname`] leaves it, so that hooks can compare names exactly
+    Folded,
+    /// Folded, with any non-ASCII label in its `xn--` punycode form
+    #[cfg(feature = "idna")]
+    Ascii,
+    /// Folded, with any `xn--` label decoded to Unicode
+    #[cfg(feature = "idna")]
+    Unicode,
+}
+
+/// What a name lookup for one family found. Knowing a name but not its addresses of that family
+/// is `NoData` rather than `NotFound`, so that resolvers go on to try the other family instead of
+/// giving up on the name.
+pub type HostAnswer = Answer<Host>;
+
+impl HostAnswer {
+    pub fn into_host(self) -> Option<Host> {
+        self.into_entry()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Addresses {
     V4(Vec<Ipv4Addr>),
     V6(Vec<Ipv6Addr>),
+    /// Addresses of both families, as a dual-stack source lists them. Lookups answer with those
+    /// of the family asked for (see [`Addresses::of_family`]), and a hostent written with both
+    /// holds them as IPv6, the IPv4 ones mapped as glibc maps them for `RES_USE_INET6`.
+    Mixed(Vec<IpAddr>),
+}
+
+impl From<Ipv4Addr> for Addresses {
+    fn from(addr: Ipv4Addr) -> Self {
+        Addresses::V4(vec![addr])
+    }
+}
+
+impl From<Ipv6Addr> for Addresses {
+    fn from(addr: Ipv6Addr) -> Self {
+        Addresses::V6(vec![addr])
+    }
+}
+
+impl From<IpAddr> for Addresses {
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(addr) => addr.into(),
+            IpAddr::V6(addr) => addr.into(),
+        }
+    }
+}
+
+impl From<Vec<Ipv4Addr>> for Addresses {
+    fn from(addrs: Vec<Ipv4Addr>) -> Self {
+        Addresses::V4(addrs)
+    }
+}
+
+impl From<Vec<Ipv6Addr>> for Addresses {
+    fn from(addrs: Vec<Ipv6Addr>) -> Self {
+        Addresses::V6(addrs)
+    }
+}
+
+/// A list of one family converts to that family, and one mixing both to [`Addresses::Mixed`].
+impl From<Vec<IpAddr>> for Addresses {
+    fn from(addrs: Vec<IpAddr>) -> Self {
+        match Addresses::split(addrs.iter().copied()) {
+            (v4, v6) if v6.is_empty() => Addresses::V4(v4),
+            (v4, v6) if v4.is_empty() => Addresses::V6(v6),
+            _ => Addresses::Mixed(addrs),
+        }
+    }
+}
+
+impl FromIterator<IpAddr> for Addresses {
+    fn from_iter<I: IntoIterator<Item = IpAddr>>(addrs: I) -> Self {
+        addrs.into_iter().collect::<Vec<_>>().into()
+    }
+}
+
+impl Addresses {
+    /// Separates a list of addresses by family, keeping the order within each, for hooks that
+    /// answer each family with a host of its own.
+    ///
+    /// ```
+    /// use std::net::IpAddr;
+    /// use libnss::host::Addresses;
+    ///
+    /// let addrs: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+    /// assert_eq!(Addresses::from(addrs.clone()), Addresses::Mixed(addrs.clone()));
+    ///
+    /// let (v4, v6) = Addresses::split(addrs);
+    /// assert_eq!(Addresses::from(v4), "10.0.0.1".parse::<IpAddr>().unwrap().into());
+    /// assert_eq!(Addresses::from(v6).len(), 1);
+    /// ```
+    pub fn split<I: IntoIterator<Item = IpAddr>>(addrs: I) -> (Vec<Ipv4Addr>, Vec<Ipv6Addr>) {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for addr in addrs {
+            match addr {
+                IpAddr::V4(addr) => v4.push(addr),
+                IpAddr::V6(addr) => v6.push(addr),
+            }
+        }
+        (v4, v6)
+    }
+
+    /// The addresses of `family`, or for `Unspecified` all of them unless they are mixed, when
+    /// the IPv4 ones are preferred as the legacy ABI does, falling back to the IPv6 ones.
+    ///
+    /// ```
+    /// use std::net::IpAddr;
+    /// use libnss::host::{AddressFamily, Addresses};
+    ///
+    /// let v4: IpAddr = "10.0.0.1".parse().unwrap();
+    /// let v6: IpAddr = "fd00::1".parse().unwrap();
+    /// let mixed: Addresses = vec![v6, v4].into_iter().collect();
+    /// assert_eq!(mixed.of_family(AddressFamily::IPv6), v6.into());
+    /// assert_eq!(mixed.of_family(AddressFamily::Unspecified), v4.into());
+    /// assert!(Addresses::from(v4).of_family(AddressFamily::IPv6).is_empty());
+    /// ```
+    pub fn of_family(&self, family: AddressFamily) -> Addresses {
+        match (self, family) {
+            (Addresses::V4(_), AddressFamily::IPv4 | AddressFamily::Unspecified)
+            | (Addresses::V6(_), AddressFamily::IPv6 | AddressFamily::Unspecified) => self.clone(),
+            (Addresses::Mixed(addrs), AddressFamily::IPv4) => {
+                Addresses::V4(Addresses::split(addrs.iter().copied()).0)
+            }
+            (Addresses::Mixed(addrs), AddressFamily::IPv6) => {
+                Addresses::V6(Addresses::split(addrs.iter().copied()).1)
+            }
+            (Addresses::Mixed(addrs), AddressFamily::Unspecified) => {
+                match Addresses::split(addrs.iter().copied()) {
+                    (v4, _) if !v4.is_empty() => Addresses::V4(v4),
+                    (_, v6) => Addresses::V6(v6),
+                }
+            }
+            (_, AddressFamily::IPv6) => Addresses::V6(Vec::new()),
+            _ => Addresses::V4(Vec::new()),
+        }
+    }
+
+    /// The addresses in order, whatever their family.
+    pub fn to_vec(&self) -> Vec<IpAddr> {
+        match self {
+            Addresses::V4(addrs) => addrs.iter().copied().map(IpAddr::V4).collect(),
+            Addresses::V6(addrs) => addrs.iter().copied().map(IpAddr::V6).collect(),
+            Addresses::Mixed(addrs) => addrs.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Addresses::V4(addrs) => addrs.len(),
+            Addresses::V6(addrs) => addrs.len(),
+            Addresses::Mixed(addrs) => addrs.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Orders the addresses as RFC 6724 prefers, see [`crate::address_order`].
+    pub fn sort(&mut self) {
+        match self {
+            Addresses::V4(addrs) => {
+                let mut ips: Vec<IpAddr> = addrs.iter().map(|addr| IpAddr::V4(*addr)).collect();
+                address_order::sort(&mut ips);
+                *addrs = ips
+                    .into_iter()
+                    .filter_map(|ip| match ip {
+                        IpAddr::V4(addr) => Some(addr),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect();
+            }
+            Addresses::V6(addrs) => {
+                let mut ips: Vec<IpAddr> = addrs.iter().map(|addr| IpAddr::V6(*addr)).collect();
+                address_order::sort(&mut ips);
+                *addrs = ips
+                    .into_iter()
+                    .filter_map(|ip| match ip {
+                        IpAddr::V6(addr) => Some(addr),
+                        IpAddr::V4(_) => None,
+                    })
+                    .collect();
+            }
+            Addresses::Mixed(addrs) => address_order::sort(addrs),
+        }
+    }
+}
+
+/// Parses a comma or whitespace separated list of addresses, converted like
+/// `From<Vec<IpAddr>>`. A list without any is refused, as is one with an address that doesn't
+/// parse.
+impl TryFrom<&str> for Addresses {
+    type Error = HostError;
+
+    fn try_from(s: &str) -> Result<Self, HostError> {
+        s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| {
+                addr.parse::<IpAddr>()
+                    .map_err(|_| HostError::InvalidAddress(addr.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|addrs| match addrs.is_empty() {
+                true => Err(HostError::NoAddresses),
+                false => Ok(addrs.into()),
+            })
+    }
 }
 
 impl Host {
+    pub fn builder() -> HostBuilder {
+        HostBuilder::default()
+    }
+
+    /// The canonical name, which is `name` unless a `canonical_name` is set.
+    pub fn canonical(&self) -> &str {
+        self.canonical_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The host with only its addresses of `family`, as [`Addresses::of_family`] picks them, or
+    /// `None` if it has none.
+    pub fn of_family(&self, family: AddressFamily) -> Option<Host> {
+        let addresses = self.addresses.of_family(family);
+        if addresses.is_empty() {
+            return None;
+        }
+        Some(Host {
+            addresses,
+            ..self.clone()
+        })
+    }
+
+    /// The host as hostents give it, one for each family it has addresses of.
+    pub fn families(&self) -> Vec<Host> {
+        match &self.addresses {
+            Addresses::Mixed(_) => [AddressFamily::IPv4, AddressFamily::IPv6]
+                .iter()
+                .filter_map(|family| self.of_family(*family))
+                .collect(),
+            _ => vec![self.clone()],
+        }
+    }
+
+    /// The aliases as a hostent lists them: `name` first if it isn't the canonical name.
+    fn hostent_aliases(&self) -> Vec<String> {
+        let canonical = self.canonical();
+        let mut aliases = Vec::with_capacity(self.aliases.len() + 1);
+        if self.name != canonical {
+            aliases.push(self.name.clone());
+        }
+        for alias in &self.aliases {
+            if alias != canonical && !aliases.contains(alias) {
+                aliases.push(alias.clone());
+            }
+        }
+        aliases
+    }
+
+    /// Checks what [`HostBuilder`] would have for a host built some other way, before it is handed
+    /// to the resolver: that it has addresses, which a hostent without any leaves callers to
+    /// mishandle, that no name is longer than a hostname can be, and that no alias is listed twice.
+    pub fn validate_hostent(&self) -> Result<(), HostError> {
+        if self.addresses.is_empty() {
+            return Err(HostError::NoAddresses);
+        }
+        let names = Some(&self.name)
+            .into_iter()
+            .chain(&self.canonical_name)
+            .chain(&self.aliases);
+        for name in names {
+            if name.len() > MAX_HOSTNAME_LEN {
+                return Err(HostError::InvalidName(name.clone()));
+            }
+        }
+        for (index, alias) in self.aliases.iter().enumerate() {
+            if self.aliases[..index].contains(alias) {
+                return Err(HostError::DuplicateAlias(alias.clone()));
+            }
+        }
+        Ok(())
+    }
+
     pub unsafe fn to_c_hostent(self, hostent: *mut CHost, buffer: &mut CBuffer) {
-        (*hostent).name = buffer.write_str(self.name);
-        (*hostent).h_aliases = buffer.write_strs(&self.aliases);
+        self.write_c_hostent(hostent, buffer)
+            .expect("Not enough free space in buffer")
+    }
 
-        let (addr_len, count) = match &self.addresses {
+    /// Copies the fields without taking them, or returns `SizeOverflow` once one doesn't fit. The
+    /// arrays come first, so that only the start of a misaligned buffer is skipped to align them,
+    /// and the addresses after them, aligned as well.
+    unsafe fn write_c_hostent(
+        &self,
+        hostent: *mut CHost,
+        buffer: &mut CBuffer,
+    ) -> Result<(), SizeOverflow> {
+        let aliases = self.hostent_aliases();
+        let alias_array = buffer.reserve_ptr_array(aliases.len())?;
+
+        let addrs: Vec<Vec<u8>> = match &self.addresses {
             Addresses::V4(addrs) => {
                 (*hostent).h_addrtype = libc::AF_INET;
                 (*hostent).h_length = 4;
-
-                (4, addrs.len())
+                addrs.iter().map(|a| a.octets().to_vec()).collect()
             }
             Addresses::V6(addrs) => {
                 (*hostent).h_addrtype = libc::AF_INET6;
                 (*hostent).h_length = 16;
+                addrs.iter().map(|a| a.octets().to_vec()).collect()
+            }
+            Addresses::Mixed(addrs) => {
+                (*hostent).h_addrtype = libc::AF_INET6;
+                (*hostent).h_length = 16;
+                addrs
+                    .iter()
+                    .map(|a| address_order::to_ipv6(a).octets().to_vec())
+                    .collect()
+            }
+        };
+        let addr_array = buffer.reserve_ptr_array(addrs.len())?;
+        for (index, addr) in addrs.iter().enumerate() {
+            let addr = buffer.write_bytes(addr)?;
+            buffer.set_ptr(addr_array.add(index), addr);
+        }
+        (*hostent).h_addr_list = addr_array;
+
+        (*hostent).name = buffer.copy_str(self.canonical())?;
+        for (index, alias) in aliases.iter().enumerate() {
+            let alias = buffer.copy_str(alias)?;
+            buffer.set_ptr(alias_array.add(index), alias);
+        }
+        (*hostent).h_aliases = alias_array;
+        Ok(())
+    }
+
+    /// The entry serialized once, to copy into each caller's buffer. Panics for an entry too big to
+    /// lay out, which [`try_to_c_blob`](Self::try_to_c_blob) returns as an error instead.
+    pub fn to_c_blob(&self) -> CBlob<CHost> {
+        self.try_to_c_blob().expect("entry too big to lay out")
+    }
+
+    pub fn try_to_c_blob(&self) -> Result<CBlob<CHost>, SizeOverflow> {
+        let capacity = self.checked_c_size()?;
+        unsafe {
+            Ok(CBlob::new(capacity, |hostent, buffer| {
+                self.write_c_hostent(hostent, buffer)
+                    .expect("entry sized to fit")
+            }))
+        }
+    }
+
+    fn checked_c_size(&self) -> Result<usize, SizeOverflow> {
+        let (addr_len, count) = match &self.addresses {
+            Addresses::V4(addrs) => (4usize, addrs.len()),
+            Addresses::V6(addrs) => (16, addrs.len()),
+            Addresses::Mixed(addrs) => (16, addrs.len()),
+        };
+        let aliases = self.hostent_aliases();
+        // The two arrays, which keep each other aligned, then the addresses, the name and aliases
+        let fixed = [
+            ptr_array_size(aliases.len())?,
+            ptr_array_size(count)?,
+            addr_len.checked_mul(count).ok_or(SizeOverflow)?,
+            self.canonical().len() + 1,
+        ];
+        let strings = aliases.iter().map(|s| s.len() + 1);
+        checked_size(fixed.iter().copied().chain(strings))
+    }
+}
+
+/// Builds a [`Host`] that is safe to hand to the resolver: the name and aliases are valid
+/// hostnames, lowercased and without a trailing dot, the aliases are unique and don't repeat the
+/// name, and there is at least one address, all of one family.
+#[derive(Default)]
+pub struct HostBuilder {
+    name: Option<String>,
+    canonical_name: Option<String>,
+    aliases: Vec<String>,
+    addresses: Vec<IpAddr>,
+}
+
+impl HostBuilder {
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn canonical_name(mut self, name: impl Into<String>) -> Self {
+        self.canonical_name = Some(name.into());
+        self
+    }
+
+    pub fn alias(mut self, alias: impl Into<String>) -> Self {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    pub fn aliases<I: IntoIterator<Item = S>, S: Into<String>>(mut self, aliases: I) -> Self {
+        self.aliases.extend(aliases.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn address(mut self, addr: impl Into<IpAddr>) -> Self {
+        self.addresses.push(addr.into());
+        self
+    }
+
+    pub fn addresses<I: IntoIterator<Item = A>, A: Into<IpAddr>>(mut self, addrs: I) -> Self {
+        self.addresses.extend(addrs.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn build(self) -> Result<Host, HostError> {
+        let name = normalize_hostname(&self.name.ok_or(HostError::MissingName)?)?;
+        let canonical_name = match &self.canonical_name {
+            Some(canonical) => Some(normalize_hostname(canonical)?).filter(|c| *c != name),
+            None => None,
+        };
 
-                (16, addrs.len())
+        let mut aliases: Vec<String> = Vec::new();
+        for alias in &self.aliases {
+            let alias = normalize_hostname(alias)?;
+            if alias != name && !aliases.contains(&alias) {
+                aliases.push(alias);
             }
+        }
+
+        let addresses = match Addresses::from(self.addresses) {
+            Addresses::Mixed(_) => return Err(HostError::MixedFamilies),
+            addresses if addresses.is_empty() => return Err(HostError::NoAddresses),
+            addresses => addresses,
         };
 
-        let ptr_size = mem::size_of::<*mut libc::c_char>() as isize;
-        let mut array_pos =
-            buffer.reserve(ptr_size * (count as isize + 1)) as *mut *mut libc::c_char;
-        (*hostent).h_addr_list = array_pos;
+        Ok(Host {
+            name,
+            aliases,
+            addresses,
+            canonical_name,
+        })
+    }
+}
 
-        match &self.addresses {
-            Addresses::V4(addrs) => {
-                for a in addrs {
-                    let ptr = buffer.reserve(addr_len);
-
-                    let o = a.octets();
-                    libc::memcpy(
-                        ptr as *mut libc::c_void,
-                        o.as_ptr() as *mut libc::c_void,
-                        addr_len as usize,
-                    );
-
-                    *array_pos = ptr;
-                    array_pos = array_pos.offset(1);
-                }
+/// The longest a name can be in DNS, without its trailing dot
+const MAX_HOSTNAME_LEN: usize = 253;
+
+/// Checks `name` against RFC 1123 hostname syntax, returning it lowercased and without a
+/// trailing dot.
+pub(crate) fn normalize_hostname(name: &str) -> Result<String, HostError> {
+    let normalized = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    if normalized.is_empty()
+        || normalized.len() > MAX_HOSTNAME_LEN
+        || !normalized.split('.').all(valid_label)
+    {
+        return Err(HostError::InvalidName(name.to_string()));
+    }
+
+    Ok(normalized)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HostError {
+    MissingName,
+    /// The name or an alias, as given
+    InvalidName(String),
+    NoAddresses,
+    /// An address that doesn't parse, as given
+    InvalidAddress(String),
+    /// A built host holds addresses of a single family, see [`Addresses::Mixed`]
+    MixedFamilies,
+    /// An alias listed more than once, as given
+    DuplicateAlias(String),
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostError::MissingName => write!(f, "host has no name"),
+            HostError::InvalidName(name) => write!(f, "`{}` is not a valid hostname", name),
+            HostError::NoAddresses => write!(f, "host has no addresses"),
+            HostError::InvalidAddress(addr) => write!(f, "`{}` is not a valid address", addr),
+            HostError::MixedFamilies => write!(f, "host has both IPv4 and IPv6 addresses"),
+            HostError::DuplicateAlias(alias) => write!(f, "alias `{}` is listed twice", alias),
+        }
+    }
+}
+
+impl Error for HostError {}
+
+/// The entry as `getent hosts` prints it: one line per address, followed by the canonical name
+/// and aliases.
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addresses = self.addresses.to_vec();
+
+        let aliases = self.hostent_aliases();
+        for (index, addr) in addresses.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
             }
-            Addresses::V6(addrs) => {
-                for a in addrs {
-                    let ptr = buffer.reserve(addr_len);
-
-                    let o = a.octets();
-                    libc::memcpy(
-                        ptr as *mut libc::c_void,
-                        o.as_ptr() as *mut libc::c_void,
-                        addr_len as usize,
-                    );
-
-                    *array_pos = ptr;
-                    array_pos = array_pos.offset(1);
-                }
+            write!(f, "{:<15} {}", addr, self.canonical())?;
+            for alias in &aliases {
+                write!(f, " {}", alias)?;
             }
         }
-
-        // Write null termination
-        libc::memset(array_pos as *mut libc::c_void, 0, 1);
+        Ok(())
     }
 }
 
 pub trait HostHooks {
-    fn get_all_entries() -> Vec<Host>;
+    /// How long a `sethostent` may reuse the entries an earlier enumeration fetched, rather
+    /// than fetching them all again. Zero, the default, always fetches.
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(0);
+
+    /// Set when `get_host_by_name` answers [`AddressFamily::Unspecified`] itself with whichever
+    /// family it has, so an `AF_UNSPEC` lookup costs one call instead of an IPv4 lookup followed
+    /// by an IPv6 one. A host holds a single family, so prefer IPv4 as the legacy ABI does.
+    const RESOLVES_UNSPECIFIED: bool = false;
+
+    /// The form `get_host_by_name` is given names in. A name that can't be converted (an empty
+    /// one, or one that isn't a valid internationalized name) is not found without calling it.
+    const NAME_FORM: NameForm = NameForm::AsGiven;
+
+    /// The entries `getent hosts` lists. The default lists nothing, as
+    /// [`PasswdHooks::get_all_entries`](crate::passwd::PasswdHooks) does.
+    fn get_all_entries() -> Vec<Host> {
+        Vec::new()
+    }
+
+    /// The default finds nothing, so names answer `NotFound`.
+    fn get_host_by_name(_name: &str, _family: AddressFamily) -> Option<Host> {
+        None
+    }
+
+    /// `get_host_by_name`, for hooks that can tell a name without addresses of `family` from an
+    /// unknown one. Lookups go through this; the default answers `NotFound` whenever
+    /// `get_host_by_name` finds nothing.
+    fn resolve_host_by_name(name: &str, family: AddressFamily) -> HostAnswer {
+        Self::get_host_by_name(name, family).into()
+    }
+
+    /// Answers a name as the C string an application passed, which needn't be UTF-8: a name in
+    /// latin-1, say, that the methods above would never be given. Hooks whose data can answer
+    /// such names override this to decode them; the default hands UTF-8 names to
+    /// `resolve_host_by_name` and finds nothing for the rest. Lookups only call it for names that
+    /// aren't UTF-8, and with `family` possibly `Unspecified`, for either family.
+    ///
+    /// ```
+    /// use std::ffi::CStr;
+    /// use std::net::Ipv4Addr;
+    /// use libnss::host::{AddressFamily, Host, HostAnswer, HostHooks};
+    ///
+    /// /// Knows `café.example`, which older applications ask for in latin-1.
+    /// struct ExampleHost;
+    ///
+    /// impl HostHooks for ExampleHost {
+    ///     fn resolve_host_by_name_bytes(name: &CStr, family: AddressFamily) -> HostAnswer {
+    ///         // Every byte is a latin-1 character, and they are Unicode's first 256
+    ///         let name: String = name.to_bytes().iter().map(|&b| char::from(b)).collect();
+    ///         Self::get_host_by_name(&name, family).into()
+    ///     }
+    ///
+    ///     fn get_host_by_name(name: &str, _: AddressFamily) -> Option<Host> {
+    ///         match name {
+    ///             "café.example" => Host::builder()
+    ///                 .name("xn--caf-dma.example")
+    ///                 .address(Ipv4Addr::LOCALHOST)
+    ///                 .build()
+    ///                 .ok(),
+    ///             _ => None,
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let latin1 = CStr::from_bytes_with_nul(b"caf\xe9.example\0").unwrap();
+    /// let host = ExampleHost::resolve_host_by_name_bytes(latin1, AddressFamily::IPv4);
+    /// assert!(matches!(host, HostAnswer::Found(_)));
+    /// ```
+    fn resolve_host_by_name_bytes(name: &CStr, family: AddressFamily) -> HostAnswer {
+        match name.to_str() {
+            Ok(name) => Self::resolve_host_by_name(name, family),
+            Err(_) => HostAnswer::NotFound,
+        }
+    }
+
+    /// The default finds nothing, so addresses answer `NotFound`: hooks for a forward-only
+    /// source needn't write reverse lookups.
+    fn get_host_by_addr(_addr: IpAddr) -> Option<Host> {
+        None
+    }
+}
+
+// <netdb.h> values, which libc doesn't export
+const NETDB_INTERNAL: libc::c_int = -1;
+const HOST_NOT_FOUND: libc::c_int = 1;
+const NO_RECOVERY: libc::c_int = 3;
+const NO_DATA: libc::c_int = 4;
+
+/// Copies `host` as a `gethostbyXXX_r` function returns it. A buffer too small for it gets
+/// `TryAgain`, with `ERANGE` in `errnop` and `NETDB_INTERNAL` in `herrnop` as glibc expects, and
+/// one too big for any buffer `Unavail` with `NO_RECOVERY`, as is one that fails
+/// [`Host::validate_hostent`], with a warning logged.
+pub unsafe fn write_hostent(
+    host: &Host,
+    result: *mut CHost,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+    herrnop: *mut libc::c_int,
+) -> NssStatus {
+    if result.is_null() || buf.is_null() {
+        return invalid_host_argument(errnop, herrnop);
+    }
+    let status = match host.validate_hostent() {
+        Err(err) => {
+            audit::syslog(
+                libc::LOG_WARNING,
+                &format!(
+                    "libnss: host `{}` was not returned: {}",
+                    host.name.escape_debug(),
+                    err
+                ),
+            );
+            NssStatus::Unavail
+        }
+        Ok(()) => {
+            // The arrays are aligned, skipping the start of a misaligned buffer
+            let size = host
+                .checked_c_size()
+                .and_then(|size| checked_size(vec![size, align_padding(buf)]));
+            write_entry(size, result, buf, buflen, errnop, |hostent, buffer| {
+                host.write_c_hostent(hostent, buffer)
+            })
+        }
+    };
+    if !herrnop.is_null() {
+        match status {
+            NssStatus::TryAgain => *herrnop = NETDB_INTERNAL,
+            NssStatus::Unavail => *herrnop = NO_RECOVERY,
+            _ => {}
+        }
+    }
+    status
+}
+
+/// [`invalid_argument`] for the hosts functions, with `NETDB_INTERNAL` in `herrnop` so that the
+/// caller looks at `errno`.
+pub unsafe fn invalid_host_argument(
+    errnop: *mut libc::c_int,
+    herrnop: *mut libc::c_int,
+) -> NssStatus {
+    if !herrnop.is_null() {
+        *herrnop = NETDB_INTERNAL;
+    }
+    invalid_argument(errnop)
+}
+
+/// Answers a `gethostbynameX_r` call for a family other than `AF_INET`, `AF_INET6` and
+/// `AF_UNSPEC`: `NotFound`, with `EAFNOSUPPORT` in `errnop` and `NETDB_INTERNAL` in `herrnop` so
+/// that the caller looks at it.
+pub unsafe fn unsupported_family(errnop: *mut libc::c_int, herrnop: *mut libc::c_int) -> NssStatus {
+    if !errnop.is_null() {
+        *errnop = libc::EAFNOSUPPORT;
+    }
+    if !herrnop.is_null() {
+        *herrnop = NETDB_INTERNAL;
+    }
+    NssStatus::NotFound
+}
 
-    fn get_host_by_name(name: &str, family: AddressFamily) -> Option<Host>;
+/// Answers a `gethostbynameX_r` call with `answer`: a host as [`write_hostent`] copies it, or
+/// `NotFound` with `NO_DATA` or `HOST_NOT_FOUND` in `herrnop`.
+pub unsafe fn write_host_answer(
+    answer: &HostAnswer,
+    result: *mut CHost,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+    herrnop: *mut libc::c_int,
+) -> NssStatus {
+    let herrno = match answer {
+        HostAnswer::Found(host) => {
+            return write_hostent(host, result, buf, buflen, errnop, herrnop)
+        }
+        HostAnswer::NoData => NO_DATA,
+        HostAnswer::NotFound => HOST_NOT_FOUND,
+    };
+    if !herrnop.is_null() {
+        *herrnop = herrno;
+    }
+    NssStatus::NotFound
+}
 
-    fn get_host_by_addr(addr: IpAddr) -> Option<Host>;
+/// `name` in the hooks' [`NameForm`], or `None` if it has no such form.
+///
+/// ```
+/// # #[cfg(feature = "idna")]
+/// # {
+/// # use std::net::IpAddr;
+/// use libnss::host::{normalize_name, AddressFamily, Host, HostHooks, NameForm};
+///
+/// struct ExampleHost;
+///
+/// impl HostHooks for ExampleHost {
+///     const NAME_FORM: NameForm = NameForm::Ascii;
+///     # fn get_all_entries() -> Vec<Host> { vec![] }
+///     # fn get_host_by_name(_: &str, _: AddressFamily) -> Option<Host> { None }
+///     # fn get_host_by_addr(_: IpAddr) -> Option<Host> { None }
+///     // ...
+/// }
+///
+/// let ascii = Some("xn--bcher-kva.example");
+/// assert_eq!(normalize_name::<ExampleHost>("Bücher.example").as_deref(), ascii);
+/// assert_eq!(normalize_name::<ExampleHost>("xn--bcher-kva.EXAMPLE").as_deref(), ascii);
+/// # }
+/// ```
+pub fn normalize_name<H: HostHooks>(name: &str) -> Option<Cow<'_, str>> {
+    if H::NAME_FORM == NameForm::AsGiven {
+        return Some(Cow::Borrowed(name));
+    }
+    let folded = fold_hostname(name);
+    if folded.is_empty() {
+        return None;
+    }
+    match H::NAME_FORM {
+        NameForm::AsGiven | NameForm::Folded => Some(folded),
+        #[cfg(feature = "idna")]
+        NameForm::Ascii => idna::domain_to_ascii(&folded).ok().map(Cow::Owned),
+        #[cfg(feature = "idna")]
+        NameForm::Unicode => match idna::domain_to_unicode(&folded) {
+            (unicode, Ok(())) => Some(Cow::Owned(unicode)),
+            (_, Err(_)) => None,
+        },
+    }
+}
+
+/// `name` as DNS compares names: without surrounding whitespace or a trailing dot, and with ASCII
+/// letters lowercased (RFC 4343). Borrowed if it is that already.
+///
+/// ```
+/// use libnss::host::fold_hostname;
+///
+/// assert_eq!(fold_hostname(" DB.Example.COM.\n"), "db.example.com");
+/// assert_eq!(fold_hostname("Bücher.example"), "bücher.example");
+/// ```
+pub fn fold_hostname(name: &str) -> Cow<'_, str> {
+    let name = name.trim();
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(name.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Looks `name` up for `AF_UNSPEC`: in one call if the hooks resolve unspecified lookups
+/// themselves, otherwise as IPv4 and then IPv6.
+pub fn get_host_by_name_unspecified<H: HostHooks>(name: &str) -> Option<Host> {
+    resolve_host_by_name_unspecified::<H>(name).into_host()
+}
+
+/// [`get_host_by_name_unspecified`], answering `NoData` if neither family found the host but
+/// either knew the name.
+///
+/// ```
+/// use std::net::{IpAddr, Ipv6Addr};
+/// use libnss::host::{resolve_host_by_name_unspecified, AddressFamily, Host, HostAnswer, HostHooks};
+///
+/// /// Knows `v6only.example`, which has an IPv6 address alone.
+/// struct ExampleHost;
+///
+/// impl HostHooks for ExampleHost {
+///     fn resolve_host_by_name(name: &str, family: AddressFamily) -> HostAnswer {
+///         match (name, family) {
+///             ("v6only.example", AddressFamily::IPv6) => HostAnswer::Found(
+///                 Host::builder().name(name).address(Ipv6Addr::LOCALHOST).build().unwrap(),
+///             ),
+///             ("v6only.example", _) => HostAnswer::NoData,
+///             _ => HostAnswer::NotFound,
+///         }
+///     }
+///
+///     fn get_host_by_name(name: &str, family: AddressFamily) -> Option<Host> {
+///         Self::resolve_host_by_name(name, family).into_host()
+///     }
+///     # fn get_all_entries() -> Vec<Host> { vec![] }
+///     # fn get_host_by_addr(_: IpAddr) -> Option<Host> { None }
+/// }
+///
+/// let host = ExampleHost::resolve_host_by_name("v6only.example", AddressFamily::IPv4);
+/// assert_eq!(host, HostAnswer::NoData);
+/// let host = resolve_host_by_name_unspecified::<ExampleHost>("v6only.example");
+/// assert!(matches!(host, HostAnswer::Found(_)));
+/// let host = resolve_host_by_name_unspecified::<ExampleHost>("unknown.example");
+/// assert_eq!(host, HostAnswer::NotFound);
+/// ```
+pub fn resolve_host_by_name_unspecified<H: HostHooks>(name: &str) -> HostAnswer {
+    if H::RESOLVES_UNSPECIFIED {
+        return H::resolve_host_by_name(name, AddressFamily::Unspecified);
+    }
+    match H::resolve_host_by_name(name, AddressFamily::IPv4) {
+        HostAnswer::Found(host) => HostAnswer::Found(host),
+        HostAnswer::NoData => match H::resolve_host_by_name(name, AddressFamily::IPv6) {
+            HostAnswer::NotFound => HostAnswer::NoData,
+            answer => answer,
+        },
+        HostAnswer::NotFound => H::resolve_host_by_name(name, AddressFamily::IPv6),
+    }
+}
+
+/// The answer `gethostbyname2_r` gives for `name`: normalized to the hooks' `NAME_FORM`, looked up
+/// as [`resolve_host_by_name_unspecified`] does for `AF_UNSPEC`, and checked as
+/// [`checked_answer`] does.
+pub fn resolve_name<H: HostHooks>(name: &str, family: AddressFamily) -> HostAnswer {
+    let name = match normalize_name::<H>(name) {
+        Some(name) => name,
+        None => return HostAnswer::NotFound,
+    };
+    let answer = match family {
+        // If unspecified, we are probably being called from gethostbyname_r
+        AddressFamily::Unspecified => resolve_host_by_name_unspecified::<H>(&name),
+        family => H::resolve_host_by_name(&name, family),
+    };
+    checked_answer(answer, family)
+}
+
+/// `answer` to a lookup for `family`: not found if the host isn't valid, and with only its
+/// addresses of `family` if they are mixed, or `NoData` if it has none.
+pub fn checked_answer(answer: HostAnswer, family: AddressFamily) -> HostAnswer {
+    match answer {
+        HostAnswer::Found(host) if !host.is_valid() => HostAnswer::NotFound,
+        HostAnswer::Found(host) => match host.of_family(family) {
+            Some(host) => HostAnswer::Found(host),
+            None => HostAnswer::NoData,
+        },
+        answer => answer,
+    }
+}
+
+/// Runs `lookup` for IPv4 and IPv6 at the same time, for backends whose answers for the two
+/// families come from separate queries, so an unspecified lookup takes as long as the slower
+/// query rather than both. Hooks doing this set `RESOLVES_UNSPECIFIED`.
+pub fn lookup_both_families<T, F>(lookup: F) -> (T, T)
+where
+    T: Send,
+    F: Fn(AddressFamily) -> T + Sync,
+{
+    thread::scope(|scope| {
+        let v6 = scope.spawn(|| lookup(AddressFamily::IPv6));
+        let v4 = lookup(AddressFamily::IPv4);
+        (v4, v6.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+    })
+}
+
+/// Combines the answers for each family into the one an unspecified lookup gives: the IPv4 host
+/// if there is one, plus any aliases (and the canonical name) only the IPv6 answer had.
+pub fn merge_families(v4: Option<Host>, v6: Option<Host>) -> Option<Host> {
+    match (v4, v6) {
+        (Some(mut v4), Some(v6)) => {
+            if v4.canonical_name.is_none() {
+                v4.canonical_name = v6.canonical_name;
+            }
+            for alias in v6.aliases {
+                if !v4.aliases.contains(&alias) {
+                    v4.aliases.push(alias);
+                }
+            }
+            Some(v4)
+        }
+        (v4, v6) => v4.or(v6),
+    }
 }
 
 /// NSS C Host object
@@ -104,127 +981,215 @@ pub struct CHost {
     pub h_addr_list: *mut *mut libc::c_char,
 }
 
+impl Rebase for CHost {
+    unsafe fn rebase(&mut self, from: usize, len: usize, to: usize) {
+        rebase_ptr(&mut self.name, from, len, to);
+        rebase_ptr(&mut self.h_aliases, from, len, to);
+        rebase_ptr(&mut self.h_addr_list, from, len, to);
+    }
+}
+
+/// Generates the `_nss_<module>_*` hosts functions NSS calls and the `<module>_hosts` module, as
+/// [`libnss_passwd_hooks!`] does for passwd.
 #[macro_export]
 macro_rules! libnss_host_hooks {
-($mod_ident:ident, $hooks_ident:ident) => (
-    paste::item! {
-        pub use self::[<libnss_host_ $mod_ident _hooks_impl>]::*;
-        mod [<libnss_host_ $mod_ident _hooks_impl>] {
+($mod_ident:ident, $hooks:ty) => (
+    $crate::paste::item! {
+        $crate::libnss_host_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, no_enumeration) => (
+    $crate::paste::item! {
+        $crate::libnss_host_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, no_enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, prefix = $prefix:ident) => (
+    $crate::libnss_host_hooks!(@module $mod_ident, $prefix, $hooks, enumeration);
+);
+($mod_ident:ident, $hooks:ty, no_enumeration, prefix = $prefix:ident) => (
+    $crate::libnss_host_hooks!(@module $mod_ident, $prefix, $hooks, no_enumeration);
+);
+(@module $mod_ident:ident, $prefix:ident, $hooks:ty, $enumeration:ident) => (
+    $crate::paste::item! {
+        // Named here so that the hooks type resolves where the macro was invoked
+        #[allow(non_camel_case_types)]
+        type [<libnss_host_ $mod_ident _hooks>] = $hooks;
+
+        pub mod [<$mod_ident _hosts>] {
             #![allow(non_upper_case_globals)]
 
-            use std::ffi::CStr;
-            use std::str;
-            use std::sync::{Mutex, MutexGuard};
-            use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-            use $crate::host::{CHost, HostHooks, Host, AddressFamily};
-            use $crate::interop::{CBuffer, NssStatus, Iterator};
+            use ::std::ffi::CStr;
+            use ::std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+            use $crate::host::{CHost, HostAnswer, HostHooks, Host, AddressFamily};
+            use super::[<libnss_host_ $mod_ident _hooks>] as Hooks;
+            use $crate::validate::Validate;
+            use $crate::interop::NssStatus;
+            use $crate::stats::{Call, Database};
 
-            lazy_static! {
-            static ref [<HOST_ $mod_ident _ITERATOR>]: Mutex<Iterator<Host>> = Mutex::new(Iterator::<Host>::new());
+            /// The entries `sethostent` enumerates, as `gethostent_r` would return them.
+            pub fn all_host_entries() -> Vec<Host> {
+                Hooks::get_all_entries()
+                    .into_iter()
+                    .filter(|host| host.is_valid() && host.validate_hostent().is_ok())
+                    .flat_map(|host| host.families())
+                    .collect()
             }
 
-            #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _sethostent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Host>> = [<HOST_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.open(super::$hooks_ident::get_all_entries());
-                NssStatus::Success.to_c()
+            /// The host `gethostbyaddr_r` returns, without going through its C interface.
+            pub fn lookup_host_by_addr(addr: IpAddr) -> Result<Host, NssStatus> {
+                let family = match addr {
+                    IpAddr::V4(_) => AddressFamily::IPv4,
+                    IpAddr::V6(_) => AddressFamily::IPv6,
+                };
+                Hooks::get_host_by_addr(addr)
+                    .filter(Validate::is_valid)
+                    .and_then(|host| host.of_family(family))
+                    .ok_or(NssStatus::NotFound)
             }
 
-            #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _endhostent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Host>> = [<HOST_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.close();
-                NssStatus::Success.to_c()
+            /// The host `gethostbyname2_r` returns, without going through its C interface.
+            pub fn lookup_host_by_name(name: &str, family: AddressFamily) -> Result<Host, NssStatus> {
+                resolve_host_by_name(name, family).into_host().ok_or(NssStatus::NotFound)
             }
 
-            #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _gethostent_r>](result: *mut CHost, buf: *mut libc::c_char, buflen: libc::size_t,
-                                                                  _errnop: *mut libc::c_int) -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Host>> = [<HOST_ $mod_ident _ITERATOR>].lock().unwrap();
-                match iter.next() {
-                    None => $crate::interop::NssStatus::NotFound.to_c(),
-                    Some(entry) => {
-                        let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                        buffer.clear();
-
-                        entry.to_c_hostent(result, &mut buffer);
-                        NssStatus::Success.to_c()
-                    }
-                }
+            /// [`lookup_host_by_name`], telling a name without addresses of `family` from an
+            /// unknown one as `gethostbyname2_r` does through `h_errno`.
+            pub fn resolve_host_by_name(name: &str, family: AddressFamily) -> HostAnswer {
+                $crate::host::resolve_name::<Hooks>(name, family)
+            }
+
+            /// [`resolve_host_by_name`] for the name as the application passed it, which only the
+            /// hooks' `resolve_host_by_name_bytes` can answer if it isn't UTF-8.
+            pub fn resolve_host_by_name_bytes(name: &CStr, family: AddressFamily) -> HostAnswer {
+                let answer = match name.to_str() {
+                    Ok(name) => return resolve_host_by_name(name, family),
+                    Err(_) => Hooks::resolve_host_by_name_bytes(name, family),
+                };
+                $crate::host::checked_answer(answer, family)
             }
 
+            $crate::libnss_host_hooks!(@$enumeration $mod_ident, $prefix);
+
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _gethostbyaddr_r>](addr: *const libc::c_char, len: libc::size_t, format: libc::c_int, result: *mut CHost, buf: *mut libc::c_char, buflen: libc::size_t, _errnop: *mut libc::c_int, _herrnop: *mut libc::c_int) -> libc::c_int {
+            unsafe extern "C" fn [<$prefix gethostbyaddr_r>](addr: *const $crate::libc::c_char, len: $crate::libc::size_t, format: $crate::libc::c_int, result: *mut CHost, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int, herrnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                let call = Call::start(Database::Hosts);
+                if addr.is_null() {
+                    return call.finish($crate::host::invalid_host_argument(errnop, herrnop), errnop);
+                }
                 // Convert address type
                 let a = match (len, format) {
-                    (4, libc::AF_INET) => {
+                    (4, $crate::libc::AF_INET) => {
                         let mut p = [0u8; 4];
-                        libc::memcpy(p.as_ptr() as *mut libc::c_void, addr as *mut libc::c_void, 4);
+                        $crate::libc::memcpy(p.as_ptr() as *mut $crate::libc::c_void, addr as *mut $crate::libc::c_void, 4);
                         IpAddr::V4(Ipv4Addr::from(p))
                     },
-                    (16, libc::AF_INET6) => {
+                    (16, $crate::libc::AF_INET6) => {
                         let mut p = [0u8; 16];
-                        libc::memcpy(p.as_ptr() as *mut libc::c_void, addr as *mut libc::c_void, 16);
+                        $crate::libc::memcpy(p.as_ptr() as *mut $crate::libc::c_void, addr as *mut $crate::libc::c_void, 16);
                         IpAddr::V6(Ipv6Addr::from(p))
                     },
                     _ => {
                         //error!("address length and format mismatch (length: {}, format: {})", len, format);
-                        return NssStatus::NotFound.to_c();
+                        return call.finish(NssStatus::NotFound, errnop);
                     }
                 };
 
-                match super::$hooks_ident::get_host_by_addr(a) {
-                    Some(val) => {
-                        let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                        buffer.clear();
+                let status = match lookup_host_by_addr(a) {
+                    Ok(val) => $crate::host::write_hostent(&val, result, buf, buflen, errnop, herrnop),
+                    Err(status) => status
+                };
+                call.finish(status, errnop)
+            }
 
-                        val.to_c_hostent(result, &mut buffer);
-                        NssStatus::Success.to_c()
-                    },
-                    None => NssStatus::NotFound.to_c()
-                }
+            #[no_mangle]
+            unsafe extern "C" fn [<$prefix gethostbyname_r>](name: *const $crate::libc::c_char, result: *mut CHost, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int, herrnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                [<$prefix gethostbyname2_r>](name, $crate::libc::AF_UNSPEC, result, buf, buflen, errnop, herrnop)
             }
 
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _gethostbyname_r>](name: *const libc::c_char, result: *mut CHost, buf: *mut libc::c_char, buflen: libc::size_t, errnop: *mut libc::c_int, herrnop: *mut libc::c_int) -> libc::c_int {
-                [<_nss_ $mod_ident _gethostbyname2_r>](name, libc::AF_UNSPEC, result, buf, buflen, errnop, herrnop)
+            unsafe extern "C" fn [<$prefix gethostbyname2_r>](name: *const $crate::libc::c_char, family: $crate::libc::c_int, result: *mut CHost, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int, herrnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                [<$prefix gethostbyname3_r>](name, family, result, buf, buflen, errnop, herrnop, ::std::ptr::null_mut(), ::std::ptr::null_mut())
             }
 
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _gethostbyname2_r>](name: *const libc::c_char, family: libc::c_int, result: *mut CHost, buf: *mut libc::c_char, buflen: libc::size_t, _errnop: *mut libc::c_int, _herrnop: *mut libc::c_int) -> libc::c_int {
-                let cstr = CStr::from_ptr(name);
-
-                match str::from_utf8(cstr.to_bytes()) {
-                    Ok(name) => {
-                        let host = match family {
-                            libc::AF_INET => super::$hooks_ident::get_host_by_name(&name.to_string(), AddressFamily::IPv4),
-                            libc::AF_INET6 => super::$hooks_ident::get_host_by_name(&name.to_string(), AddressFamily::IPv6),
-
-                            // If unspecified, we are probably being called from gethostbyname_r so
-                            // we will try IPv4 and if no results, then try IPv6
-                            libc::AF_UNSPEC => match super::$hooks_ident::get_host_by_name(&name.to_string(), AddressFamily::IPv4) {
-                                None => super::$hooks_ident::get_host_by_name(&name.to_string(), AddressFamily::IPv6),
-                                val => val,
-                            },
-                            _ => { return NssStatus::NotFound.to_c(); },
-                        };
-
-                        match host {
-                            Some(val) => {
-                                let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                                buffer.clear();
-
-                                val.to_c_hostent(result, &mut buffer);
-                                NssStatus::Success.to_c()
-                            },
-                            None => NssStatus::NotFound.to_c()
-                        }
-                    }
+            unsafe extern "C" fn [<$prefix gethostbyname3_r>](name: *const $crate::libc::c_char, family: $crate::libc::c_int, result: *mut CHost, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int, herrnop: *mut $crate::libc::c_int, _ttlp: *mut i32, canonp: *mut *mut $crate::libc::c_char) -> $crate::libc::c_int {
+                let call = Call::start(Database::Hosts);
+                if name.is_null() {
+                    return call.finish($crate::host::invalid_host_argument(errnop, herrnop), errnop);
+                }
+                let family = match AddressFamily::from(family) {
+                    AddressFamily::Other(_) => { return call.finish($crate::host::unsupported_family(errnop, herrnop), errnop); },
+                    family => family,
+                };
 
-                    Err(_) => NssStatus::NotFound.to_c()
+                let answer = resolve_host_by_name_bytes(CStr::from_ptr(name), family);
+                let status = $crate::host::write_host_answer(&answer, result, buf, buflen, errnop, herrnop);
+                // The hostent's name is the canonical one
+                if status == NssStatus::Success && !canonp.is_null() {
+                    *canonp = (*result).name;
                 }
+                call.finish(status, errnop)
+            }
+
+            #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+            #[no_mangle]
+            extern "C" fn [<$prefix hosts_constr>](_db_name: *const $crate::libc::c_char, _src_name: *const $crate::libc::c_char,
+                                                          _cfg_args: *const $crate::libc::c_char) -> *mut $crate::solaris::NssBackend {
+                $crate::solaris::hosts_backend::<Hooks>()
+            }
+
+            #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+            #[no_mangle]
+            extern "C" fn [<$prefix ipnodes_constr>](_db_name: *const $crate::libc::c_char, _src_name: *const $crate::libc::c_char,
+                                                          _cfg_args: *const $crate::libc::c_char) -> *mut $crate::solaris::NssBackend {
+                $crate::solaris::ipnodes_backend::<Hooks>()
             }
 
         }
     }
-)}
+);
+(@enumeration $mod_ident:ident, $prefix:ident) => (
+    $crate::paste::item! {
+        use ::std::sync::{Mutex, MutexGuard};
+        use $crate::interop::EntryCursor;
+
+        static [<HOST_ $mod_ident _ITERATOR>]: Mutex<EntryCursor<Host>> = Mutex::new(EntryCursor::new());
+
+        #[no_mangle]
+        extern "C" fn [<$prefix sethostent>](stayopen: $crate::libc::c_int) -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Host>> = [<HOST_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.open_cached(<Hooks as HostHooks>::ENUMERATION_CACHE_TTL, stayopen != 0, all_host_entries);
+            NssStatus::Success.to_c()
+        }
+
+        #[no_mangle]
+        extern "C" fn [<$prefix endhostent>]() -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Host>> = [<HOST_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.close();
+            NssStatus::Success.to_c()
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn [<$prefix gethostent_r>](result: *mut CHost, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t,
+                                                              errnop: *mut $crate::libc::c_int, herrnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+            let call = Call::start(Database::Hosts);
+            // Serializing the entry doesn't need the cursor, so release it first
+            let entry = [<HOST_ $mod_ident _ITERATOR>].lock().unwrap().next();
+            let status = match entry {
+                None => NssStatus::NotFound,
+                Some(entry) => {
+                    let status = $crate::host::write_hostent(&entry, result, buf, buflen, errnop, herrnop);
+                    // The caller will ask again with a bigger buffer
+                    if status == NssStatus::TryAgain {
+                        [<HOST_ $mod_ident _ITERATOR>].lock().unwrap().put_back(entry);
+                    }
+                    status
+                }
+            };
+            call.finish(status, errnop)
+        }
+    }
+);
+// The BSDs' method tables have no host enumeration, so nothing needs to stand in for it
+(@no_enumeration $mod_ident:ident, $prefix:ident) => ();
+}
@@ -0,0 +1,172 @@
+//! Matching hostnames against patterns, for host hooks answering whole domains at once.
+//!
+//! A [`HostPatterns`] maps patterns to values, such as the address of the proxy in front of a
+//! domain. Names are matched without regard to case or a trailing dot, and a pattern is one of:
+//!
+//! - `db.example.com`, that name alone;
+//! - `*.dev.example.com`, any name below `dev.example.com`, however many labels deeper, but not
+//!   `dev.example.com` itself;
+//! - `.dev.example.com`, `dev.example.com` and any name below it;
+//! - `~^web-\d+\.example\.com$`, with the `regex` feature, names the regular expression matches,
+//!   lowercased and without a trailing dot.
+//!
+//! When several match, an exact name wins, then the pattern with the longest domain (a `*.` one
+//! before a `.` one for the same domain), then the first regular expression added, as in nginx's
+//! `server_name`.
+//!
+//! ```
+//! use std::net::Ipv4Addr;
+//! use libnss::host_match::HostPatterns;
+//!
+//! let mut proxies = HostPatterns::new();
+//! proxies.insert("*.dev.example.com", Ipv4Addr::new(10, 0, 0, 1)).unwrap();
+//! proxies.insert("*.db.dev.example.com", Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+//! proxies.insert("legacy.dev.example.com", Ipv4Addr::new(10, 0, 0, 3)).unwrap();
+//!
+//! assert_eq!(proxies.get("app.dev.example.com"), Some(&Ipv4Addr::new(10, 0, 0, 1)));
+//! assert_eq!(proxies.get("a.b.dev.example.com"), Some(&Ipv4Addr::new(10, 0, 0, 1)));
+//! assert_eq!(proxies.get("pg.db.dev.example.com"), Some(&Ipv4Addr::new(10, 0, 0, 2)));
+//! assert_eq!(proxies.get("LEGACY.dev.example.com."), Some(&Ipv4Addr::new(10, 0, 0, 3)));
+//! assert_eq!(proxies.get("dev.example.com"), None);
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::host::normalize_hostname;
+
+pub struct HostPatterns<T> {
+    exact: HashMap<String, T>,
+    /// By the domain after `*.`
+    wildcards: HashMap<String, T>,
+    /// By the domain after `.`
+    suffixes: HashMap<String, T>,
+    #[cfg(feature = "regex")]
+    regexes: Vec<(regex::Regex, T)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PatternError {
+    /// The pattern, as given, isn't a hostname once its `*.` or `.` is taken off
+    InvalidName(String),
+    /// A `*` anywhere but as a whole first label
+    MisplacedWildcard(String),
+    /// A regular expression that doesn't compile, or a `~` pattern without the `regex` feature
+    InvalidRegex(String, String),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternError::InvalidName(pattern) => {
+                write!(f, "`{}` is not a valid hostname pattern", pattern)
+            }
+            PatternError::MisplacedWildcard(pattern) => write!(
+                f,
+                "`{}` has a `*` other than as its whole first label",
+                pattern
+            ),
+            PatternError::InvalidRegex(pattern, reason) => write!(f, "`{}`: {}", pattern, reason),
+        }
+    }
+}
+
+impl Error for PatternError {}
+
+impl<T> Default for HostPatterns<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> HostPatterns<T> {
+    pub fn new() -> Self {
+        HostPatterns {
+            exact: HashMap::new(),
+            wildcards: HashMap::new(),
+            suffixes: HashMap::new(),
+            #[cfg(feature = "regex")]
+            regexes: Vec::new(),
+        }
+    }
+
+    /// Adds `pattern`, replacing the value of an equal pattern added before.
+    pub fn insert(&mut self, pattern: &str, value: T) -> Result<(), PatternError> {
+        if let Some(regex) = pattern.strip_prefix('~') {
+            return self.insert_regex(pattern, regex, value);
+        }
+
+        let (table, name) = if let Some(domain) = pattern.strip_prefix("*.") {
+            (&mut self.wildcards, domain)
+        } else if let Some(domain) = pattern.strip_prefix('.') {
+            (&mut self.suffixes, domain)
+        } else {
+            (&mut self.exact, pattern)
+        };
+        if name.contains('*') {
+            return Err(PatternError::MisplacedWildcard(pattern.to_string()));
+        }
+        let name =
+            normalize_hostname(name).map_err(|_| PatternError::InvalidName(pattern.to_string()))?;
+        table.insert(name, value);
+        Ok(())
+    }
+
+    #[cfg(feature = "regex")]
+    fn insert_regex(&mut self, pattern: &str, regex: &str, value: T) -> Result<(), PatternError> {
+        let regex = regex::RegexBuilder::new(regex)
+            .case_insensitive(true)
+            .build()
+            .map_err(|err| PatternError::InvalidRegex(pattern.to_string(), err.to_string()))?;
+        self.regexes.push((regex, value));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn insert_regex(&mut self, pattern: &str, _: &str, _: T) -> Result<(), PatternError> {
+        Err(PatternError::InvalidRegex(
+            pattern.to_string(),
+            "regular expressions need the `regex` feature".to_string(),
+        ))
+    }
+
+    /// The value of the pattern `name` matches best, if any does.
+    pub fn get(&self, name: &str) -> Option<&T> {
+        let name = normalize_hostname(name).ok()?;
+        if let Some(value) = self.exact.get(&name) {
+            return Some(value);
+        }
+        if let Some(value) = self.suffixes.get(&name) {
+            return Some(value);
+        }
+
+        // Each domain `name` is below, longest first
+        let mut domain = name.as_str();
+        while let Some((_, parent)) = domain.split_once('.') {
+            domain = parent;
+            if let Some(value) = self
+                .wildcards
+                .get(domain)
+                .or_else(|| self.suffixes.get(domain))
+            {
+                return Some(value);
+            }
+        }
+
+        self.get_regex(&name)
+    }
+
+    #[cfg(feature = "regex")]
+    fn get_regex(&self, name: &str) -> Option<&T> {
+        self.regexes
+            .iter()
+            .find(|(regex, _)| regex.is_match(name))
+            .map(|(_, value)| value)
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn get_regex(&self, _: &str) -> Option<&T> {
+        None
+    }
+}
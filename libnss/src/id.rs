@@ -0,0 +1,86 @@
+//! Newtypes for user and group ids, so a uid can't be passed where a gid is expected.
+
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+/// A user id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Uid(libc::uid_t);
+
+/// A group id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Gid(libc::gid_t);
+
+macro_rules! id_impls {
+    ($id:ident, $raw:ty) => {
+        impl $id {
+            pub const fn from_raw(id: $raw) -> Self {
+                $id(id)
+            }
+
+            pub const fn as_raw(self) -> $raw {
+                self.0
+            }
+        }
+
+        impl From<$raw> for $id {
+            fn from(id: $raw) -> Self {
+                $id(id)
+            }
+        }
+
+        impl From<$id> for $raw {
+            fn from(id: $id) -> Self {
+                id.0
+            }
+        }
+
+        impl PartialEq<$raw> for $id {
+            fn eq(&self, other: &$raw) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl fmt::Display for $id {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $id {
+            type Err = ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, ParseIntError> {
+                s.parse().map($id)
+            }
+        }
+
+        #[cfg(feature = "nix")]
+        impl From<nix::unistd::$id> for $id {
+            fn from(id: nix::unistd::$id) -> Self {
+                $id(id.as_raw())
+            }
+        }
+
+        #[cfg(feature = "nix")]
+        impl From<$id> for nix::unistd::$id {
+            fn from(id: $id) -> Self {
+                nix::unistd::$id::from_raw(id.0)
+            }
+        }
+    };
+}
+
+id_impls!(Uid, libc::uid_t);
+id_impls!(Gid, libc::gid_t);
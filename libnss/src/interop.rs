@@ -1,8 +1,36 @@
 use libc::c_int;
+use std::cell::Cell;
 use std::collections::VecDeque;
-use std::ffi::CString;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::time::{Duration, Instant};
 
+/// `NSS_STATUS_TRYAGAIN` from glibc's `<nss.h>`: a temporary failure, or with `ERANGE` a buffer
+/// too small
+pub const NSS_STATUS_TRYAGAIN: c_int = -2;
+/// `NSS_STATUS_UNAVAIL`: the source can't answer, and won't if asked again
+pub const NSS_STATUS_UNAVAIL: c_int = -1;
+/// `NSS_STATUS_NOTFOUND`: the source answered, without an entry
+pub const NSS_STATUS_NOTFOUND: c_int = 0;
+/// `NSS_STATUS_SUCCESS`
+pub const NSS_STATUS_SUCCESS: c_int = 1;
+/// `NSS_STATUS_RETURN`: the end of an enumeration, on the BSDs and illumos
+pub const NSS_STATUS_RETURN: c_int = 2;
+
+/// `enum nss_status`, which every NSS function returns as an `int`.
+///
+/// ```
+/// use std::convert::TryFrom;
+/// use libnss::interop::{NssStatus, NSS_STATUS_TRYAGAIN};
+///
+/// assert_eq!(libc::c_int::from(NssStatus::TryAgain), NSS_STATUS_TRYAGAIN);
+/// assert_eq!(NssStatus::try_from(NSS_STATUS_TRYAGAIN), Ok(NssStatus::TryAgain));
+/// assert!(NssStatus::try_from(3).is_err());
+/// ```
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum NssStatus {
     TryAgain,
     Unavail,
@@ -14,36 +42,136 @@ pub enum NssStatus {
 impl NssStatus {
     pub fn to_c(&self) -> c_int {
         match *self {
-            NssStatus::TryAgain => -2,
-            NssStatus::Unavail => -1,
-            NssStatus::NotFound => 0,
-            NssStatus::Success => 1,
-            NssStatus::Return => 2,
+            NssStatus::TryAgain => NSS_STATUS_TRYAGAIN,
+            NssStatus::Unavail => NSS_STATUS_UNAVAIL,
+            NssStatus::NotFound => NSS_STATUS_NOTFOUND,
+            NssStatus::Success => NSS_STATUS_SUCCESS,
+            NssStatus::Return => NSS_STATUS_RETURN,
+        }
+    }
+}
+
+impl From<NssStatus> for c_int {
+    fn from(status: NssStatus) -> Self {
+        status.to_c()
+    }
+}
+
+/// Reads back what an NSS function returned.
+impl TryFrom<c_int> for NssStatus {
+    type Error = UnknownNssStatus;
+
+    fn try_from(status: c_int) -> Result<Self, UnknownNssStatus> {
+        match status {
+            NSS_STATUS_TRYAGAIN => Ok(NssStatus::TryAgain),
+            NSS_STATUS_UNAVAIL => Ok(NssStatus::Unavail),
+            NSS_STATUS_NOTFOUND => Ok(NssStatus::NotFound),
+            NSS_STATUS_SUCCESS => Ok(NssStatus::Success),
+            NSS_STATUS_RETURN => Ok(NssStatus::Return),
+            _ => Err(UnknownNssStatus(status)),
         }
     }
 }
 
-pub struct Iterator<T> {
+/// A value no `enum nss_status` has, as returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownNssStatus(pub c_int);
+
+impl fmt::Display for UnknownNssStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` is not an NSS status", self.0)
+    }
+}
+
+impl Error for UnknownNssStatus {}
+
+/// The entries of an enumeration (`setpwent`/`getpwent_r`/`endpwent` and friends), which must be
+/// opened before use and can be closed and reopened.
+pub struct EntryCursor<T> {
     items: Option<VecDeque<T>>,
+    /// The entries `open_cached` last fetched, and when
+    fetched: Option<(Instant, Vec<T>)>,
+    stayopen: bool,
 }
 
-impl<T> Iterator<T> {
-    pub fn new() -> Self {
-        Iterator { items: None }
+#[deprecated(note = "renamed to `EntryCursor`")]
+pub type Iterator<T> = EntryCursor<T>;
+
+impl<T> EntryCursor<T> {
+    pub const fn new() -> Self {
+        EntryCursor {
+            items: None,
+            fetched: None,
+            stayopen: false,
+        }
     }
+
     pub fn open(&mut self, items: Vec<T>) {
         self.items = Some(VecDeque::from(items));
     }
 
-    pub fn next(&mut self) -> Option<T> {
-        match self.items {
-            Some(ref mut val) => val.pop_front(),
-            None => panic!("Iterator not currently open"),
+    /// Opens with the entries an earlier call fetched if they are younger than `ttl`, or if that
+    /// call asked to stay open and the enumeration hasn't been ended since (`setXXent(1)` followed
+    /// by a rewinding `setXXent`). Otherwise fetches them. A zero `ttl` disables the reuse.
+    pub fn open_cached<F: FnOnce() -> Vec<T>>(&mut self, ttl: Duration, stayopen: bool, fetch: F)
+    where
+        T: Clone,
+    {
+        if ttl == Duration::from_secs(0) {
+            self.open(fetch());
+            return;
+        }
+
+        let reuse = match &self.fetched {
+            Some((at, _)) => self.stayopen || at.elapsed() < ttl,
+            None => false,
+        };
+        if !reuse {
+            self.fetched = Some((Instant::now(), fetch()));
+        }
+        if let Some((_, items)) = &self.fetched {
+            self.items = Some(items.iter().cloned().collect());
+        }
+        self.stayopen = stayopen;
+    }
+
+    /// Returns an entry taken with `next` to the front, for `getXXent_r` to return again once
+    /// its caller has a buffer big enough for it.
+    pub fn put_back(&mut self, item: T) {
+        if let Some(items) = &mut self.items {
+            items.push_front(item);
         }
     }
 
+    pub fn is_open(&self) -> bool {
+        self.items.is_some()
+    }
+
     pub fn close(&mut self) {
         self.items = None;
+        self.stayopen = false;
+    }
+}
+
+impl<T> std::iter::Iterator for EntryCursor<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.items {
+            Some(ref mut val) => val.pop_front(),
+            None => panic!("EntryCursor not currently open"),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.items.as_ref().map_or(0, VecDeque::len);
+        (len, Some(len))
+    }
+}
+
+impl<T> Default for EntryCursor<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -52,6 +180,12 @@ pub struct CBuffer {
     pos: *mut libc::c_void,
     free: libc::size_t,
     len: libc::size_t,
+    /// Offsets of the pointers stored inside the buffer, when building a `CBlob`
+    relocations: Option<Vec<usize>>,
+    /// Offsets of the strings written, when an `OwnedCBuffer` keeps track of them
+    strings: Option<Vec<usize>>,
+    /// Whether a pointer array has been written, which a copy must keep aligned
+    arrays: bool,
 }
 
 impl CBuffer {
@@ -61,65 +195,679 @@ impl CBuffer {
             pos: ptr,
             free: len,
             len,
+            relocations: None,
+            strings: None,
+            arrays: false,
+        }
+    }
+
+    /// How many bytes have been written or reserved.
+    pub fn used(&self) -> usize {
+        self.len - self.free
+    }
+
+    /// How many bytes are left.
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    fn record_string(&mut self, start: *mut libc::c_void) {
+        if let Some(strings) = &mut self.strings {
+            strings.push(start as usize - self.start as usize);
         }
     }
 
+    /// Zeroes the whole buffer, which nothing needs: every write terminates its own strings and
+    /// arrays, so only the bytes an entry takes are ever touched.
+    #[deprecated(note = "writes terminate themselves, so the buffer needn't be zeroed first")]
     pub unsafe fn clear(&mut self) {
         libc::memset(self.start, 0, self.len);
     }
 
+    /// Copies `string` and its NUL. Panics if it doesn't fit, which
+    /// [`copy_str`](Self::copy_str) returns as an error instead.
     pub unsafe fn write_str(&mut self, string: String) -> *mut libc::c_char {
-        // Capture start address
-        let str_start = self.pos;
-
-        // Convert string
-        let cstr = CString::new(string).expect("Failed to convert string");
-        let ptr = cstr.as_ptr();
-        let len = libc::strlen(ptr);
+        self.copy_str(&string)
+            .expect("Not enough free space in buffer")
+    }
 
-        // Ensure we have enough capacity
-        if self.free < len + 1 {
-            panic!("Not enough free space in buffer");
+    /// Copies `string` and its NUL without taking it, or returns `SizeOverflow` without writing
+    /// anything if it doesn't fit.
+    pub unsafe fn copy_str(&mut self, string: &str) -> Result<*mut libc::c_char, SizeOverflow> {
+        if string.as_bytes().contains(&0) {
+            panic!("Failed to convert string");
         }
+        let len = string.len();
+        let str_start = self.try_take(len.checked_add(1).ok_or(SizeOverflow)?)?;
+        libc::memcpy(str_start, string.as_ptr() as *const libc::c_void, len);
+        *(str_start as *mut u8).add(len) = 0;
+        self.record_string(str_start);
 
-        // Copy string
-        libc::memcpy(self.pos, ptr as *mut libc::c_void, len);
-        self.pos = self.pos.offset(len as isize + 1);
-        self.free -= len as usize + 1;
+        Ok(str_start as *mut libc::c_char)
+    }
+
+    /// Writes a field that shouldn't outlive the lookup in this process, such as a password hash.
+    /// With the `zeroize` feature the string is wiped once it has been copied into the buffer.
+    pub unsafe fn write_secret(&mut self, mut string: String) -> *mut libc::c_char {
+        let ptr = self.copy_str(&string);
+        wipe(&mut string);
+        ptr.expect("Not enough free space in buffer")
+    }
 
-        // Return start of string
-        str_start as *mut libc::c_char
+    /// Writes `strings` as a NULL terminated array of pointers to copies of them, returning where
+    /// the array starts, or `SizeOverflow` if they don't fit.
+    pub unsafe fn write_strs(
+        &mut self,
+        strings: &[String],
+    ) -> Result<*mut *mut libc::c_char, SizeOverflow> {
+        let array = self.reserve_ptr_array(strings.len())?;
+        for (index, string) in strings.iter().enumerate() {
+            let string = self.copy_str(string)?;
+            self.set_ptr(array.add(index), string);
+        }
+        Ok(array)
     }
 
-    pub unsafe fn write_strs(&mut self, strings: &[String]) -> *mut *mut libc::c_char {
-        let ptr_size = std::mem::size_of::<*mut libc::c_char>() as isize;
+    /// Copies a C string as it is, whatever its encoding, such as a name exactly as a caller
+    /// passed it, or returns `SizeOverflow` if it doesn't fit.
+    pub unsafe fn write_cstr(&mut self, string: &CStr) -> Result<*mut libc::c_char, SizeOverflow> {
+        let bytes = string.to_bytes_with_nul();
+        let start = self.try_take(bytes.len())?;
+        libc::memcpy(start, bytes.as_ptr() as *const libc::c_void, bytes.len());
+        self.record_string(start);
+        Ok(start as *mut libc::c_char)
+    }
 
-        let vec_start = self.reserve(ptr_size * (strings.len() as isize + 1)) as *mut *mut libc::c_char;
-        let mut pos = vec_start;
+    /// Copies `bytes` into the buffer as they are, such as an address's octets, returning where
+    /// they start, or `SizeOverflow` if they don't fit.
+    pub unsafe fn write_bytes(&mut self, bytes: &[u8]) -> Result<*mut libc::c_char, SizeOverflow> {
+        let start = self.try_take(bytes.len())?;
+        libc::memcpy(start, bytes.as_ptr() as *const libc::c_void, bytes.len());
+        Ok(start as *mut libc::c_char)
+    }
 
-        // Write strings
-        for s in strings {
-            *pos = self.write_str(s.to_string());
-            pos = pos.offset(1);
+    /// Writes `ptrs`, which point into this buffer, as a NULL terminated array, returning where it
+    /// starts, or `SizeOverflow` if it doesn't fit.
+    pub unsafe fn write_ptr_array(
+        &mut self,
+        ptrs: &[*mut libc::c_char],
+    ) -> Result<*mut *mut libc::c_char, SizeOverflow> {
+        let array = self.reserve_ptr_array(ptrs.len())?;
+        for (index, ptr) in ptrs.iter().enumerate() {
+            self.set_ptr(array.add(index), *ptr);
         }
+        Ok(array)
+    }
 
-        libc::memset(pos as *mut libc::c_void, 0, ptr_size as usize);
+    /// Claims a NULL terminated array of `len` pointers, for [`set_ptr`](Self::set_ptr) to fill
+    /// in. C reads the array as `char **`, so it starts at the next address aligned for a pointer,
+    /// as glibc's own modules lay theirs out; entries reserve theirs first, so that only the start
+    /// of a misaligned buffer is skipped.
+    pub unsafe fn reserve_ptr_array(
+        &mut self,
+        len: usize,
+    ) -> Result<*mut *mut libc::c_char, SizeOverflow> {
+        let size = ptr_array_size(len)?;
+        let padding = align_padding(self.pos as *const libc::c_char);
+        if self.free < checked_size(vec![padding, size])? {
+            return Err(SizeOverflow);
+        }
+        self.try_take(padding)?;
+        let array = self.try_take(size)? as *mut *mut libc::c_char;
+        array.add(len).write(std::ptr::null_mut());
+        self.arrays = true;
+        Ok(array)
+    }
 
-        vec_start
+    /// Stores `ptr`, which points into this buffer, at `slot`, which is also inside it.
+    pub unsafe fn set_ptr(&mut self, slot: *mut *mut libc::c_char, ptr: *mut libc::c_char) {
+        slot.write_unaligned(ptr);
+        if let Some(relocations) = &mut self.relocations {
+            relocations.push(slot as usize - self.start as usize);
+        }
     }
 
     pub unsafe fn reserve(&mut self, len: isize) -> *mut libc::c_char {
+        let len = usize::try_from(len).expect("Negative length reserved in buffer");
+        self.take(len) as *mut libc::c_char
+    }
+
+    /// Claims the next `len` bytes, returning where they start. Panics rather than overflowing the
+    /// buffer if they don't fit.
+    unsafe fn take(&mut self, len: usize) -> *mut libc::c_void {
+        self.try_take(len).expect("Not enough free space in buffer")
+    }
+
+    /// Claims the next `len` bytes, or returns `SizeOverflow` without claiming any if they don't
+    /// fit, which every write checks before touching the buffer.
+    unsafe fn try_take(&mut self, len: usize) -> Result<*mut libc::c_void, SizeOverflow> {
+        if self.free < len {
+            return Err(SizeOverflow);
+        }
         let start = self.pos;
+        self.pos = self.pos.add(len);
+        self.free -= len;
+        Ok(start)
+    }
+}
+
+/// An entry too big to lay out in any buffer, its size not fitting in an `isize`. No real entry
+/// is; a corrupt or hostile record from a backend could claim to be, and gets `Unavail` rather
+/// than a size that wraps around and lets writes run past the end of the buffer. [`CBuffer`]'s
+/// writers also return it for a part that doesn't fit in what is left of the buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeOverflow;
+
+impl SizeOverflow {
+    /// The status to return to NSS: `Unavail`, as no buffer will ever be big enough.
+    pub fn status(&self) -> NssStatus {
+        NssStatus::Unavail
+    }
+}
+
+impl fmt::Display for SizeOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "entry is too big to lay out in a buffer")
+    }
+}
+
+impl Error for SizeOverflow {}
+
+/// Empties a secret, such as a password hash, once it has been copied where it's needed. With
+/// the `zeroize` feature its bytes are overwritten first rather than left in freed memory.
+pub fn wipe(secret: &mut String) {
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(secret);
+    #[cfg(not(feature = "zeroize"))]
+    secret.clear();
+}
+
+/// Lays an entry out straight into the caller's buffer with `write`, as a `*_r` function returns
+/// it: `Success`, `TryAgain` with `ERANGE` in `errnop` if it needs more than `buflen` bytes, which
+/// [`last_shortfall`] then says, or `Unavail` if `size` overflowed. `size` includes any
+/// [`align_padding`] at `buf`; should `write` still run out of space, that too is `ERANGE`. A NULL
+/// `result` or `buf` is an [`invalid_argument`].
+pub unsafe fn write_entry<C, F>(
+    size: Result<usize, SizeOverflow>,
+    result: *mut C,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+    write: F,
+) -> NssStatus
+where
+    F: FnOnce(*mut C, &mut CBuffer) -> Result<(), SizeOverflow>,
+{
+    LAST_SHORTFALL.with(|last| last.set(None));
+    if result.is_null() || buf.is_null() {
+        return invalid_argument(errnop);
+    }
+    let size = match size {
+        Ok(size) => size,
+        Err(err) => return err.status(),
+    };
+    if buflen < size {
+        LAST_SHORTFALL.with(|last| {
+            last.set(Some(Shortfall {
+                needed: size,
+                available: buflen,
+            }))
+        });
+        if !errnop.is_null() {
+            *errnop = libc::ERANGE;
+        }
+        return NssStatus::TryAgain;
+    }
+
+    let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
+    match write(result, &mut buffer) {
+        Ok(()) => NssStatus::Success,
+        Err(SizeOverflow) => {
+            if !errnop.is_null() {
+                *errnop = libc::ERANGE;
+            }
+            NssStatus::TryAgain
+        }
+    }
+}
+
+/// The sum of `sizes`, the parts of an entry laid out in a buffer, unless it is too big for one.
+pub fn checked_size<I: IntoIterator<Item = usize>>(sizes: I) -> Result<usize, SizeOverflow> {
+    let size = sizes
+        .into_iter()
+        .try_fold(0usize, usize::checked_add)
+        .ok_or(SizeOverflow)?;
+    if isize::try_from(size).is_err() {
+        return Err(SizeOverflow);
+    }
+    Ok(size)
+}
+
+/// How many bytes past `ptr` the next address aligned for a pointer is.
+pub fn align_padding(ptr: *const libc::c_char) -> usize {
+    (ptr as usize).wrapping_neg() % std::mem::align_of::<*mut libc::c_char>()
+}
+
+/// The space a NULL terminated array of `len` pointers takes.
+pub fn ptr_array_size(len: usize) -> Result<usize, SizeOverflow> {
+    let ptr_size = std::mem::size_of::<*mut libc::c_char>();
+    len.checked_add(1)
+        .and_then(|len| len.checked_mul(ptr_size))
+        .ok_or(SizeOverflow)
+}
+
+/// A C entry struct whose pointers can be moved from one buffer to another.
+pub trait Rebase {
+    /// Moves every pointer into `[from, from + len)` to the same offset from `to`.
+    unsafe fn rebase(&mut self, from: usize, len: usize, to: usize);
+}
+
+/// Moves `ptr` from one buffer to another if it points into the first.
+pub unsafe fn rebase_ptr<T>(ptr: &mut *mut T, from: usize, len: usize, to: usize) {
+    let addr = *ptr as usize;
+    if addr >= from && addr < from + len {
+        *ptr = (addr - from + to) as *mut T;
+    }
+}
 
-        // Ensure we have enough capacity
-        if self.free < len as usize {
-            panic!("Not enough free space in buffer");
+/// An entry serialized once into its C layout, for entries hot enough that re-encoding them for
+/// every lookup shows up. Each copy is a `memcpy` of the strings and arrays plus fixing up the
+/// pointers between them, rather than a walk over the entry with a `CString` per field.
+///
+/// Nothing here keeps blobs between lookups: that is up to the hooks, which know which entries
+/// are hot and when they go stale, and can hold on to a blob per entry to copy with
+/// [`write_result`](Self::write_result).
+pub struct CBlob<C> {
+    entry: C,
+    /// Words rather than bytes, so that the layout starts aligned for its pointer arrays
+    data: Vec<usize>,
+    len: usize,
+    /// Offsets into `data` of the pointers it holds
+    relocations: Vec<usize>,
+    /// Whether it holds a pointer array, which copies must start aligned
+    arrays: bool,
+}
+
+// The pointers only ever point into `data`, which the blob owns
+unsafe impl<C> Send for CBlob<C> {}
+unsafe impl<C> Sync for CBlob<C> {}
+
+/// A blob of a passwd or shadow entry holds its password hash
+#[cfg(feature = "zeroize")]
+impl<C> Drop for CBlob<C> {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.data);
+    }
+}
+
+impl<C: Rebase> CBlob<C> {
+    /// Serializes an entry with `write`, as its `to_c_*` method would into a caller's buffer.
+    /// `capacity` must be at least the space the entry needs.
+    pub unsafe fn new<F>(capacity: usize, write: F) -> Self
+    where
+        F: FnOnce(*mut C, &mut CBuffer),
+    {
+        let mut data = vec![0usize; capacity.div_ceil(std::mem::size_of::<usize>())];
+        let mut buffer = CBuffer::new(data.as_mut_ptr() as *mut libc::c_void, capacity);
+        buffer.relocations = Some(Vec::new());
+
+        let mut entry = std::mem::MaybeUninit::<C>::zeroed();
+        write(entry.as_mut_ptr(), &mut buffer);
+        let entry = entry.assume_init();
+
+        CBlob {
+            entry,
+            data,
+            len: buffer.used(),
+            relocations: buffer.relocations.take().unwrap_or_default(),
+            arrays: buffer.arrays,
+        }
+    }
+
+    /// The space a copy needs in an aligned buffer, such as one from `malloc`.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The space a copy needs in a buffer starting at `buffer`: [`len`](Self::len), plus the
+    /// [`align_padding`] skipped there for an entry with pointer arrays.
+    pub fn len_at(&self, buffer: *const libc::c_char) -> usize {
+        let padding = if self.arrays {
+            align_padding(buffer)
+        } else {
+            0
+        };
+        self.len.saturating_add(padding)
+    }
+
+    /// Copies the entry into `result` and the caller's buffer, or returns false without writing
+    /// anything if the buffer is too small.
+    pub unsafe fn copy_to(&self, result: *mut C, buffer: *mut libc::c_char, buflen: usize) -> bool {
+        self.try_copy_to(result, buffer, buflen).is_ok()
+    }
+
+    /// As [`copy_to`](CBlob::copy_to), but saying how much space a buffer too small would have
+    /// needed.
+    pub unsafe fn try_copy_to(
+        &self,
+        result: *mut C,
+        buffer: *mut libc::c_char,
+        buflen: usize,
+    ) -> Result<(), Shortfall> {
+        let needed = self.len_at(buffer);
+        if buflen < needed {
+            return Err(Shortfall {
+                needed,
+                available: buflen,
+            });
+        }
+
+        let (len, buffer) = (self.len, buffer.add(needed - self.len));
+        std::ptr::copy_nonoverlapping(self.data.as_ptr() as *const u8, buffer as *mut u8, len);
+        let (from, to) = (self.data.as_ptr() as usize, buffer as usize);
+        for offset in &self.relocations {
+            let slot = buffer.add(*offset) as *mut *mut libc::c_char;
+            let mut ptr = slot.read_unaligned();
+            rebase_ptr(&mut ptr, from, len, to);
+            slot.write_unaligned(ptr);
+        }
+
+        std::ptr::copy_nonoverlapping(&self.entry, result, 1);
+        (*result).rebase(from, len, to);
+        Ok(())
+    }
+
+    /// Copies the entry as a `*_r` function returns it: `Success`, or `TryAgain` with `ERANGE` in
+    /// `errnop` if the buffer is too small, for the caller to retry with a bigger one. How much
+    /// bigger is left for [`last_shortfall`] to tell. A NULL `result` or `buffer` is an
+    /// [`invalid_argument`].
+    pub unsafe fn write_result(
+        &self,
+        result: *mut C,
+        buffer: *mut libc::c_char,
+        buflen: usize,
+        errnop: *mut c_int,
+    ) -> NssStatus {
+        if result.is_null() || buffer.is_null() {
+            LAST_SHORTFALL.with(|last| last.set(None));
+            return invalid_argument(errnop);
+        }
+        let copied = self.try_copy_to(result, buffer, buflen);
+        LAST_SHORTFALL.with(|last| last.set(copied.err()));
+        match copied {
+            Ok(()) => NssStatus::Success,
+            Err(_) => {
+                if !errnop.is_null() {
+                    *errnop = libc::ERANGE;
+                }
+                NssStatus::TryAgain
+            }
         }
+    }
+}
+
+/// Answers a call given a NULL pointer it needed, such as the name to look up or the buffer to
+/// write into: `Unavail`, with `EINVAL` in `errnop`, rather than crashing the caller's process.
+pub unsafe fn invalid_argument(errnop: *mut c_int) -> NssStatus {
+    if !errnop.is_null() {
+        *errnop = libc::EINVAL;
+    }
+    NssStatus::Unavail
+}
+
+/// A caller's buffer too small for an entry, and the size that would have held it exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shortfall {
+    pub needed: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for Shortfall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "entry needs a {} byte buffer but was given {}",
+            self.needed, self.available
+        )
+    }
+}
 
-        // Reserve space
-        self.pos = self.pos.offset(len as isize);
-        self.free -= len as usize;
+impl Error for Shortfall {}
+
+thread_local! {
+    static LAST_SHORTFALL: Cell<Option<Shortfall>> = const { Cell::new(None) };
+}
+
+/// Why this thread's last [`CBlob::write_result`] returned `ERANGE`, or `None` if it didn't.
+///
+/// glibc only learns that its buffer was too small, and doubles it until an entry fits. The
+/// size an entry needs is known before anything is copied, though, so a harness calling the
+/// `_nss_*_r` functions, or a hook logging why a lookup was slow, can ask for it here and retry
+/// once with exactly that much.
+pub fn last_shortfall() -> Option<Shortfall> {
+    LAST_SHORTFALL.with(Cell::get)
+}
+
+/// A [`CBuffer`] over memory of its own rather than a caller's, for exercising the conversions in
+/// tests, Miri's included, and looking at what they wrote. It dereferences to the `CBuffer`, so it
+/// can be handed to any `to_c_*` method.
+///
+/// ```
+/// use libnss::interop::OwnedCBuffer;
+/// use libnss::shadow::{CShadow, Shadow};
+///
+/// let shadow = Shadow {
+///     name: "test".to_string(),
+///     passwd: "!".to_string(),
+///     last_change: -1,
+///     change_min_days: -1,
+///     change_max_days: -1,
+///     change_warn_days: -1,
+///     change_inactive_days: -1,
+///     expire_date: -1,
+///     reserved: u64::MAX,
+/// };
+/// let mut buffer = OwnedCBuffer::new(16);
+/// let mut result = std::mem::MaybeUninit::<CShadow>::zeroed();
+/// unsafe { shadow.to_c_shadow(result.as_mut_ptr(), &mut buffer) };
+///
+/// assert_eq!(buffer.strings(), ["test", "!"]);
+/// assert_eq!(buffer.bytes(), b"test\0!\0");
+/// assert_eq!(buffer.free(), 9);
+/// ```
+#[cfg(feature = "testing")]
+pub struct OwnedCBuffer {
+    // Only ever accessed through the pointer the `CBuffer` holds, or after it has finished writing.
+    // Words rather than bytes, so that it starts aligned as `malloc`'s memory does.
+    data: Vec<usize>,
+    buffer: CBuffer,
+}
+
+#[cfg(feature = "testing")]
+impl OwnedCBuffer {
+    pub fn new(len: usize) -> Self {
+        let mut data = vec![0usize; len.div_ceil(std::mem::size_of::<usize>())];
+        let mut buffer = CBuffer::new(data.as_mut_ptr() as *mut libc::c_void, len);
+        buffer.strings = Some(Vec::new());
+        OwnedCBuffer { data, buffer }
+    }
+
+    /// The start of the memory, to copy a [`CBlob`] into.
+    pub fn as_mut_ptr(&mut self) -> *mut libc::c_char {
+        self.buffer.start as *mut libc::c_char
+    }
+
+    /// The bytes written or reserved so far.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr() as *const u8, self.buffer.used()) }
+    }
 
-        start as *mut libc::c_char
+    /// Every string written so far, in order.
+    pub fn strings(&self) -> Vec<&str> {
+        written_strings(self.bytes(), &self.buffer)
     }
-}
\ No newline at end of file
+
+    /// The string a written entry's field points to.
+    pub fn read_str(&self, ptr: *const libc::c_char) -> &str {
+        read_str(self.bytes(), ptr)
+    }
+
+    /// The strings of a NULL terminated array a written entry's field points to.
+    pub fn read_strs(&self, list: *const *mut libc::c_char) -> Vec<&str> {
+        read_strs(self.bytes(), list)
+    }
+}
+
+#[cfg(feature = "testing")]
+impl std::ops::Deref for OwnedCBuffer {
+    type Target = CBuffer;
+
+    fn deref(&self) -> &CBuffer {
+        &self.buffer
+    }
+}
+
+#[cfg(feature = "testing")]
+impl std::ops::DerefMut for OwnedCBuffer {
+    fn deref_mut(&mut self) -> &mut CBuffer {
+        &mut self.buffer
+    }
+}
+
+/// A [`CBuffer`] over a slice the test owns, such as one on its stack, otherwise like
+/// [`OwnedCBuffer`]. The slice stays borrowed for as long as the buffer lives.
+///
+/// ```
+/// use libnss::group::{CGroup, Group};
+/// use libnss::id::Gid;
+/// use libnss::interop::BorrowedCBuffer;
+///
+/// let group = Group {
+///     name: "wheel".to_string(),
+///     name_bytes: None,
+///     passwd: "x".to_string(),
+///     gid: Gid::from_raw(10),
+///     members: vec!["alice".to_string(), "bob".to_string()],
+/// };
+/// let mut memory = [0u8; 64];
+/// let mut buffer = BorrowedCBuffer::new(&mut memory);
+/// let mut result = std::mem::MaybeUninit::<CGroup>::zeroed();
+/// let result = unsafe {
+///     group.to_c_group(result.as_mut_ptr(), &mut buffer);
+///     result.assume_init()
+/// };
+///
+/// assert_eq!(buffer.read_str(result.name), "wheel");
+/// assert_eq!(buffer.read_strs(result.members), ["alice", "bob"]);
+/// assert_eq!(buffer.used() + buffer.free(), 64);
+/// ```
+#[cfg(feature = "testing")]
+pub struct BorrowedCBuffer<'a> {
+    buffer: CBuffer,
+    memory: std::marker::PhantomData<&'a mut [u8]>,
+}
+
+#[cfg(feature = "testing")]
+impl<'a> BorrowedCBuffer<'a> {
+    pub fn new(memory: &'a mut [u8]) -> Self {
+        let mut buffer = CBuffer::new(memory.as_mut_ptr() as *mut libc::c_void, memory.len());
+        buffer.strings = Some(Vec::new());
+        BorrowedCBuffer {
+            buffer,
+            memory: std::marker::PhantomData,
+        }
+    }
+
+    /// The bytes written or reserved so far.
+    pub fn bytes(&self) -> &[u8] {
+        // Only ever accessed through the pointer the `CBuffer` holds while the slice is borrowed
+        unsafe { std::slice::from_raw_parts(self.buffer.start as *const u8, self.buffer.used()) }
+    }
+
+    /// Every string written so far, in order.
+    pub fn strings(&self) -> Vec<&str> {
+        written_strings(self.bytes(), &self.buffer)
+    }
+
+    /// The string a written entry's field points to.
+    pub fn read_str(&self, ptr: *const libc::c_char) -> &str {
+        read_str(self.bytes(), ptr)
+    }
+
+    /// The strings of a NULL terminated array a written entry's field points to.
+    pub fn read_strs(&self, list: *const *mut libc::c_char) -> Vec<&str> {
+        read_strs(self.bytes(), list)
+    }
+}
+
+#[cfg(feature = "testing")]
+impl std::ops::Deref for BorrowedCBuffer<'_> {
+    type Target = CBuffer;
+
+    fn deref(&self) -> &CBuffer {
+        &self.buffer
+    }
+}
+
+#[cfg(feature = "testing")]
+impl std::ops::DerefMut for BorrowedCBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut CBuffer {
+        &mut self.buffer
+    }
+}
+
+#[cfg(feature = "testing")]
+fn written_strings<'d>(data: &'d [u8], buffer: &CBuffer) -> Vec<&'d str> {
+    let offsets = buffer.strings.as_deref().unwrap_or_default();
+    offsets.iter().map(|offset| str_at(data, *offset)).collect()
+}
+
+/// The NUL terminated string at `offset` into `data`.
+#[cfg(feature = "testing")]
+fn str_at(data: &[u8], offset: usize) -> &str {
+    let bytes = &data[offset..];
+    let len = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .expect("strings are NUL terminated");
+    std::str::from_utf8(&bytes[..len]).expect("strings are written from UTF-8")
+}
+
+/// The offset of `ptr` into `data`, which `len` bytes from it must also be in.
+#[cfg(feature = "testing")]
+fn offset_of<T>(data: &[u8], ptr: *const T, len: usize) -> usize {
+    let offset = (ptr as usize).wrapping_sub(data.as_ptr() as usize);
+    match offset.checked_add(len) {
+        Some(end) if end <= data.len() => offset,
+        _ => panic!("{:p} doesn't point into what the buffer has written", ptr),
+    }
+}
+
+#[cfg(feature = "testing")]
+fn read_str(data: &[u8], ptr: *const libc::c_char) -> &str {
+    str_at(data, offset_of(data, ptr, 1))
+}
+
+#[cfg(feature = "testing")]
+fn read_strs(data: &[u8], list: *const *mut libc::c_char) -> Vec<&str> {
+    use std::convert::TryInto;
+
+    let ptr_size = std::mem::size_of::<*mut libc::c_char>();
+    let mut slot = offset_of(data, list, ptr_size);
+    let mut strings = Vec::new();
+    loop {
+        let bytes = data
+            .get(slot..slot + ptr_size)
+            .expect("pointer arrays are NULL terminated");
+        let ptr = usize::from_ne_bytes(bytes.try_into().unwrap()) as *const libc::c_char;
+        if ptr.is_null() {
+            return strings;
+        }
+        strings.push(read_str(data, ptr));
+        slot += ptr_size;
+    }
+}
@@ -1,8 +1,55 @@
-extern crate libc;
-extern crate lazy_static;
+#![allow(clippy::missing_safety_doc)]
+
+// For the macros, so that modules needn't depend on these themselves
+#[doc(hidden)]
+pub use libc;
+#[doc(hidden)]
+pub use paste;
 
 pub mod interop;
+pub mod id;
+pub mod context;
 pub mod passwd;
 pub mod group;
 pub mod shadow;
 pub mod host;
+pub mod address_order;
+pub mod host_match;
+pub mod network;
+pub mod ether;
+pub mod service;
+pub mod backends;
+pub mod database;
+pub mod audit;
+pub mod build;
+pub mod files;
+pub mod validate;
+pub mod retry;
+pub mod stats;
+pub mod health;
+pub mod stale;
+pub mod shared;
+pub mod coalesce;
+pub mod ranges;
+pub mod combine;
+pub mod privileged;
+pub mod resolver;
+mod module;
+#[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+pub mod nsdispatch;
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+pub mod solaris;
+#[cfg(target_os = "linux")]
+pub mod preload;
+#[cfg(feature = "userdb")]
+pub mod userdb;
+#[cfg(feature = "userdb")]
+mod varlink;
+#[cfg(feature = "nscd")]
+pub mod nscd;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(feature = "proptest")]
+pub mod arbitrary;
+#[cfg(feature = "opentelemetry")]
+pub mod trace;
@@ -0,0 +1,288 @@
+/// Generates the per-module entry points that some platforms need on top of the per-database
+/// functions from the hooks macros. List the databases the module implements:
+///
+/// ```ignore
+/// libnss_passwd_hooks!(example, ExamplePasswd);
+/// libnss_group_hooks!(example, ExampleGroup);
+/// libnss_module!(example, passwd, group);
+/// ```
+///
+/// glibc needs nothing more, so this expands to nothing there. On FreeBSD and NetBSD it generates
+/// `nss_module_register`, which hands nsdispatch(3) a method table for each listed database.
+/// Neither has a shadow database, so `shadow` is accepted but skipped there.
+///
+/// Modules whose hooks macros were given a `prefix` name it here in place of the module:
+///
+/// ```ignore
+/// libnss_passwd_hooks!(example_test, ExamplePasswd, prefix = _nss_test_);
+/// libnss_module!(prefix = _nss_test_, passwd);
+/// ```
+#[macro_export]
+macro_rules! libnss_module {
+(prefix = $prefix:ident, $($db:ident),+ $(,)*) => (
+    #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+    mod libnss_nsdispatch_module {
+        $( $crate::libnss_module!(@nsdispatch $prefix, $db); )+
+
+        #[no_mangle]
+        unsafe extern "C" fn nss_module_register(_source: *const $crate::libc::c_char, len: *mut $crate::libc::c_uint,
+                                                 unregister: *mut $crate::nsdispatch::NsModuleUnregister) -> *mut $crate::nsdispatch::NsMtab {
+            if len.is_null() || unregister.is_null() {
+                return ::std::ptr::null_mut();
+            }
+            let mut methods = Vec::new();
+            $( methods.extend($db()); )+
+
+            *len = methods.len() as $crate::libc::c_uint;
+            *unregister = None;
+            Box::leak(methods.into_boxed_slice()).as_mut_ptr()
+        }
+    }
+);
+($mod_ident:ident, $($db:ident),+ $(,)*) => (
+    $crate::paste::item! {
+        $crate::libnss_module!(prefix = [<_nss_ $mod_ident _>], $($db),+);
+    }
+);
+(@nsdispatch $prefix:ident, passwd) => (
+    $crate::paste::item! {
+        extern "C" {
+            fn [<$prefix getpwnam_r>]();
+            fn [<$prefix getpwuid_r>]();
+            fn [<$prefix getpwent_r>]();
+            fn [<$prefix setpwent>]();
+            fn [<$prefix endpwent>]();
+        }
+
+        fn passwd() -> Vec<$crate::nsdispatch::NsMtab> {
+            $crate::nsdispatch::passwd_methods($crate::nsdispatch::PasswdFns {
+                getpwnam_r: [<$prefix getpwnam_r>],
+                getpwuid_r: [<$prefix getpwuid_r>],
+                getpwent_r: [<$prefix getpwent_r>],
+                setpwent: [<$prefix setpwent>],
+                endpwent: [<$prefix endpwent>],
+            })
+        }
+    }
+);
+(@nsdispatch $prefix:ident, group) => (
+    $crate::paste::item! {
+        extern "C" {
+            fn [<$prefix getgrnam_r>]();
+            fn [<$prefix getgrgid_r>]();
+            fn [<$prefix getgrent_r>]();
+            fn [<$prefix setgrent>]();
+            fn [<$prefix endgrent>]();
+        }
+
+        fn group() -> Vec<$crate::nsdispatch::NsMtab> {
+            $crate::nsdispatch::group_methods($crate::nsdispatch::GroupFns {
+                getgrnam_r: [<$prefix getgrnam_r>],
+                getgrgid_r: [<$prefix getgrgid_r>],
+                getgrent_r: [<$prefix getgrent_r>],
+                setgrent: [<$prefix setgrent>],
+                endgrent: [<$prefix endgrent>],
+            })
+        }
+    }
+);
+(@nsdispatch $prefix:ident, host) => (
+    $crate::paste::item! {
+        extern "C" {
+            fn [<$prefix gethostbyname2_r>]();
+            fn [<$prefix gethostbyaddr_r>]();
+        }
+
+        fn host() -> Vec<$crate::nsdispatch::NsMtab> {
+            $crate::nsdispatch::host_methods($crate::nsdispatch::HostFns {
+                gethostbyname2_r: [<$prefix gethostbyname2_r>],
+                gethostbyaddr_r: [<$prefix gethostbyaddr_r>],
+            })
+        }
+    }
+);
+(@nsdispatch $prefix:ident, shadow) => (
+    fn shadow() -> Vec<$crate::nsdispatch::NsMtab> {
+        Vec::new()
+    }
+);
+}
+
+/// Generates a library for musl, which has no NSS, that answers lookups from the module's
+/// functions: `getpwnam(3)` and friends, and `getaddrinfo(3)`, each asking libc first and the
+/// module if libc finds nothing. List the databases the module implements, as for
+/// [`libnss_module!`], and load the library ahead of libc with `LD_PRELOAD`:
+///
+/// ```no_run
+/// # use std::net::{IpAddr, Ipv4Addr};
+/// # use libnss::host::{AddressFamily, Host, HostHooks};
+/// # use libnss::id::{Gid, Uid};
+/// # use libnss::passwd::{Passwd, PasswdHooks};
+/// # struct ExamplePasswd;
+/// # impl PasswdHooks for ExamplePasswd {
+/// #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+/// #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+/// #     fn get_entry_by_name(name: String) -> Option<Passwd> {
+/// #         Some(Passwd {
+/// #             name,
+/// #             name_bytes: None,
+/// #             passwd: "x".to_string(),
+/// #             uid: Uid::from_raw(70000),
+/// #             gid: Gid::from_raw(70000),
+/// #             gecos: String::new(),
+/// #             dir: "/srv/app".to_string(),
+/// #             shell: "/bin/sh".to_string(),
+/// #         })
+/// #         .filter(|entry| entry.name == "preload-app")
+/// #     }
+/// # }
+/// # struct ExampleHost;
+/// # impl HostHooks for ExampleHost {
+/// #     fn get_all_entries() -> Vec<Host> { vec![] }
+/// #     fn get_host_by_name(name: &str, family: AddressFamily) -> Option<Host> {
+/// #         match (name, family) {
+/// #             ("app.preload.invalid", AddressFamily::IPv4) => {
+/// #                 Host::builder().name(name).address(Ipv4Addr::new(192, 0, 2, 7)).build().ok()
+/// #             }
+/// #             _ => None,
+/// #         }
+/// #     }
+/// #     fn get_host_by_addr(_: IpAddr) -> Option<Host> { None }
+/// # }
+/// use std::net::ToSocketAddrs;
+/// use libnss::{libnss_host_hooks, libnss_passwd_hooks, libnss_preload};
+///
+/// libnss_passwd_hooks!(example, ExamplePasswd);
+/// libnss_host_hooks!(example, ExampleHost);
+/// libnss_preload!(example, passwd, host);
+///
+/// fn main() {
+///     // Linked into a musl program, the library answers its lookups too
+///     let pwd = unsafe { libnss::libc::getpwnam(b"preload-app\0".as_ptr() as *const _) };
+///     assert_eq!(unsafe { pwd.as_ref() }.map(|pwd| pwd.pw_uid), Some(70000));
+///
+///     let mut addrs = ("app.preload.invalid", 443).to_socket_addrs().unwrap();
+///     assert_eq!(addrs.next().unwrap().to_string(), "192.0.2.7:443");
+/// }
+/// ```
+///
+/// It takes a `prefix` in place of the module as `libnss_module!` does. `shadow` is accepted but
+/// skipped, as musl reads `/etc/shadow` itself. Anywhere but musl this expands to nothing: glibc
+/// loads the module itself, and overriding its functions would answer ahead of `nsswitch.conf`.
+///
+/// A version script hides the overrides unless it lists them, and the library then overrides
+/// nothing. [`build::export_nss_symbols`](crate::build::export_nss_symbols) lists them when
+/// building for musl; a script of your own needs them too, as in
+/// [`build::preload_version_script`](crate::build::preload_version_script).
+#[macro_export]
+macro_rules! libnss_preload {
+(prefix = $prefix:ident, $($db:ident),+ $(,)*) => (
+    #[cfg(all(target_os = "linux", target_env = "musl"))]
+    mod libnss_preload {
+        $( $crate::libnss_preload!(@override $prefix, $db); )+
+    }
+);
+($mod_ident:ident, $($db:ident),+ $(,)*) => (
+    $crate::paste::item! {
+        $crate::libnss_preload!(prefix = [<_nss_ $mod_ident _>], $($db),+);
+    }
+);
+(@override $prefix:ident, passwd) => (
+    $crate::paste::item! {
+        use $crate::passwd::CPasswd;
+
+        extern "C" {
+            fn [<$prefix getpwnam_r>](name: *const $crate::libc::c_char, pwd: *mut CPasswd, buf: *mut $crate::libc::c_char,
+                                      buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int;
+            fn [<$prefix getpwuid_r>](uid: $crate::libc::uid_t, pwd: *mut CPasswd, buf: *mut $crate::libc::c_char,
+                                      buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int;
+        }
+
+        static NEXT_GETPWNAM_R: $crate::preload::Next = $crate::preload::Next::new(b"getpwnam_r\0");
+        static NEXT_GETPWUID_R: $crate::preload::Next = $crate::preload::Next::new(b"getpwuid_r\0");
+
+        #[no_mangle]
+        unsafe extern "C" fn getpwnam_r(name: *const $crate::libc::c_char, pwd: *mut CPasswd, buf: *mut $crate::libc::c_char,
+                                        buflen: $crate::libc::size_t, result: *mut *mut CPasswd) -> $crate::libc::c_int {
+            $crate::preload::getpwnam_r(&NEXT_GETPWNAM_R, [<$prefix getpwnam_r>], name, pwd, buf, buflen, result)
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn getpwuid_r(uid: $crate::libc::uid_t, pwd: *mut CPasswd, buf: *mut $crate::libc::c_char,
+                                        buflen: $crate::libc::size_t, result: *mut *mut CPasswd) -> $crate::libc::c_int {
+            $crate::preload::getpwuid_r(&NEXT_GETPWUID_R, [<$prefix getpwuid_r>], uid, pwd, buf, buflen, result)
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn getpwnam(name: *const $crate::libc::c_char) -> *mut CPasswd {
+            $crate::preload::getpwnam(&NEXT_GETPWNAM_R, [<$prefix getpwnam_r>], name)
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn getpwuid(uid: $crate::libc::uid_t) -> *mut CPasswd {
+            $crate::preload::getpwuid(&NEXT_GETPWUID_R, [<$prefix getpwuid_r>], uid)
+        }
+    }
+);
+(@override $prefix:ident, group) => (
+    $crate::paste::item! {
+        use $crate::group::CGroup;
+
+        extern "C" {
+            fn [<$prefix getgrnam_r>](name: *const $crate::libc::c_char, grp: *mut CGroup, buf: *mut $crate::libc::c_char,
+                                      buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int;
+            fn [<$prefix getgrgid_r>](gid: $crate::libc::gid_t, grp: *mut CGroup, buf: *mut $crate::libc::c_char,
+                                      buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int;
+        }
+
+        static NEXT_GETGRNAM_R: $crate::preload::Next = $crate::preload::Next::new(b"getgrnam_r\0");
+        static NEXT_GETGRGID_R: $crate::preload::Next = $crate::preload::Next::new(b"getgrgid_r\0");
+
+        #[no_mangle]
+        unsafe extern "C" fn getgrnam_r(name: *const $crate::libc::c_char, grp: *mut CGroup, buf: *mut $crate::libc::c_char,
+                                        buflen: $crate::libc::size_t, result: *mut *mut CGroup) -> $crate::libc::c_int {
+            $crate::preload::getgrnam_r(&NEXT_GETGRNAM_R, [<$prefix getgrnam_r>], name, grp, buf, buflen, result)
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn getgrgid_r(gid: $crate::libc::gid_t, grp: *mut CGroup, buf: *mut $crate::libc::c_char,
+                                        buflen: $crate::libc::size_t, result: *mut *mut CGroup) -> $crate::libc::c_int {
+            $crate::preload::getgrgid_r(&NEXT_GETGRGID_R, [<$prefix getgrgid_r>], gid, grp, buf, buflen, result)
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn getgrnam(name: *const $crate::libc::c_char) -> *mut CGroup {
+            $crate::preload::getgrnam(&NEXT_GETGRNAM_R, [<$prefix getgrnam_r>], name)
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn getgrgid(gid: $crate::libc::gid_t) -> *mut CGroup {
+            $crate::preload::getgrgid(&NEXT_GETGRGID_R, [<$prefix getgrgid_r>], gid)
+        }
+    }
+);
+(@override $prefix:ident, host) => (
+    $crate::paste::item! {
+        extern "C" {
+            fn [<$prefix gethostbyname2_r>](name: *const $crate::libc::c_char, family: $crate::libc::c_int, result: *mut $crate::host::CHost,
+                                            buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int,
+                                            herrnop: *mut $crate::libc::c_int) -> $crate::libc::c_int;
+        }
+
+        static NEXT_GETADDRINFO: $crate::preload::Next = $crate::preload::Next::new(b"getaddrinfo\0");
+        static NEXT_FREEADDRINFO: $crate::preload::Next = $crate::preload::Next::new(b"freeaddrinfo\0");
+
+        #[no_mangle]
+        unsafe extern "C" fn getaddrinfo(node: *const $crate::libc::c_char, service: *const $crate::libc::c_char,
+                                         hints: *const $crate::libc::addrinfo, res: *mut *mut $crate::libc::addrinfo) -> $crate::libc::c_int {
+            $crate::preload::getaddrinfo(&NEXT_GETADDRINFO, &NEXT_FREEADDRINFO, [<$prefix gethostbyname2_r>], node, service, hints, res)
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn freeaddrinfo(res: *mut $crate::libc::addrinfo) {
+            $crate::preload::freeaddrinfo(&NEXT_FREEADDRINFO, res)
+        }
+    }
+);
+(@override $prefix:ident, shadow) => ();
+}
@@ -0,0 +1,177 @@
+//! Entries of the networks database, which names IPv4 networks for tools such as `route` and
+//! `netstat`.
+//!
+//! A network is an [`Ipv4Net`], an address and prefix length, rather than the bare number
+//! `struct netent` carries. That number, `n_net`, is in the classful form of `/etc/networks`:
+//! the network's significant octets, right aligned in host byte order, so that `10.0.0.0/8` is
+//! `0x0a` and `192.168.1.0/24` is `0xc0a801`.
+//!
+//! ```
+//! use std::net::Ipv4Addr;
+//! use libnss::network::Ipv4Net;
+//!
+//! let net: Ipv4Net = "10.1.0.0/16".parse().unwrap();
+//! assert_eq!(net.addr(), Ipv4Addr::new(10, 1, 0, 0));
+//! assert_eq!(net.n_net(), 0x0a01);
+//! assert_eq!(Ipv4Net::from_n_net(0x0a01), net);
+//!
+//! // As `/etc/networks` writes them
+//! assert_eq!("192.168.1".parse::<Ipv4Net>().unwrap(), "192.168.1.0/24".parse().unwrap());
+//! assert_eq!("127.0.0.0".parse::<Ipv4Net>().unwrap().prefix_len(), 8);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub net: Ipv4Net,
+}
+
+/// An IPv4 network: an address whose bits past the prefix are all zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ipv4Net {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Net {
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Self, NetworkError> {
+        if prefix_len > 32 {
+            return Err(NetworkError::InvalidPrefix(prefix_len));
+        }
+        let net = Ipv4Net { addr, prefix_len };
+        if u32::from(addr) & !net.mask() != 0 {
+            return Err(NetworkError::HostBitsSet(net.to_string()));
+        }
+        Ok(net)
+    }
+
+    /// The network `addr` is in by its class, as before CIDR: its first octet for class A, first
+    /// two for class B and first three for class C, and for the rest the whole address. Octets
+    /// beyond those that aren't zero lengthen the network to include them.
+    pub fn classful(addr: Ipv4Addr) -> Self {
+        let bits = u32::from(addr);
+        let mut octets = 4 - host_octets(addr);
+        while octets < 4 && bits << (8 * octets) != 0 {
+            octets += 1;
+        }
+        Ipv4Net {
+            addr,
+            prefix_len: 8 * octets,
+        }
+    }
+
+    /// The network a `struct netent` numbers `n_net`, with the prefix its significant octets give.
+    pub fn from_n_net(n_net: u32) -> Self {
+        let shift = match n_net {
+            0..=0xff => 24,
+            0x100..=0xffff => 16,
+            0x1_0000..=0xff_ffff => 8,
+            _ => 0,
+        };
+        Ipv4Net::classful(Ipv4Addr::from(n_net << shift))
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0)
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.mask() == u32::from(self.addr)
+    }
+
+    /// The number `struct netent` gives the network as: its address without the trailing zero
+    /// octets its class leaves out. A network longer than its class keeps the octets it needs.
+    pub fn n_net(&self) -> u32 {
+        let bits = u32::from(self.addr);
+        let mut dropped = 0;
+        while dropped < host_octets(self.addr) && bits & (0xff << (8 * dropped)) == 0 {
+            dropped += 1;
+        }
+        bits >> (8 * dropped)
+    }
+}
+
+/// How many trailing octets the class of `addr` leaves to hosts.
+fn host_octets(addr: Ipv4Addr) -> u8 {
+    match addr.octets()[0] {
+        0..=127 => 3,
+        128..=191 => 2,
+        192..=223 => 1,
+        _ => 0,
+    }
+}
+
+/// `addr/prefix_len`, as `10.1.0.0/16`.
+impl fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Parses `addr/prefix_len`, or a network as `/etc/networks` writes it: one to four octets, the
+/// missing ones zero, taken as [classful](Ipv4Net::classful).
+impl FromStr for Ipv4Net {
+    type Err = NetworkError;
+
+    fn from_str(s: &str) -> Result<Self, NetworkError> {
+        let invalid = || NetworkError::InvalidNetwork(s.to_string());
+        if let Some((addr, prefix_len)) = s.split_once('/') {
+            let addr = addr.parse().map_err(|_| invalid())?;
+            let prefix_len = prefix_len.parse().map_err(|_| invalid())?;
+            return Ipv4Net::new(addr, prefix_len);
+        }
+
+        let mut octets = [0u8; 4];
+        for (index, octet) in s.split('.').enumerate() {
+            if index == 4 {
+                return Err(invalid());
+            }
+            octets[index] = octet.parse().map_err(|_| invalid())?;
+        }
+        Ok(Ipv4Net::classful(Ipv4Addr::from(octets)))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NetworkError {
+    /// Neither `addr/prefix_len` nor dotted octets, as given
+    InvalidNetwork(String),
+    /// A prefix longer than 32 bits
+    InvalidPrefix(u8),
+    /// An address with bits set past its prefix, as given
+    HostBitsSet(String),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::InvalidNetwork(network) => {
+                write!(f, "`{}` is not a valid network", network)
+            }
+            NetworkError::InvalidPrefix(prefix_len) => {
+                write!(f, "/{} is longer than an IPv4 address", prefix_len)
+            }
+            NetworkError::HostBitsSet(network) => {
+                write!(f, "`{}` has bits set past its prefix", network)
+            }
+        }
+    }
+}
+
+impl Error for NetworkError {}
@@ -0,0 +1,1035 @@
+//! The glibc nscd socket protocol.
+//!
+//! [`NscdClient`] asks a running cache daemon for entries, so a module can consult it before
+//! going to its own backend. [`NscdServer`] answers the same protocol from hooks implementations,
+//! so unmodified glibc programs reach a single long running daemon through `/var/run/nscd/socket`
+//! instead of each loading the module and opening their own backend connections.
+//!
+//! Only the plain request/response exchange is spoken; requests for the shared memory mappings
+//! are refused by closing the connection, which glibc handles by falling back to the socket.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::{fs, str};
+
+use crate::address_order;
+use crate::context::CallContext;
+use crate::group::{Group, GroupHooks};
+use crate::host::{self, AddressFamily, Addresses, Host, HostHooks};
+use crate::id::{Gid, Uid};
+use crate::passwd::{Passwd, PasswdHooks};
+use crate::validate::Validate;
+
+pub const DEFAULT_SOCKET: &str = "/var/run/nscd/socket";
+
+const NSCD_VERSION: i32 = 2;
+
+const GETPWBYNAME: i32 = 0;
+const GETPWBYUID: i32 = 1;
+const GETGRBYNAME: i32 = 2;
+const GETGRBYGID: i32 = 3;
+const GETHOSTBYNAME: i32 = 4;
+const GETHOSTBYNAMEV6: i32 = 5;
+const GETHOSTBYADDR: i32 = 6;
+const GETHOSTBYADDRV6: i32 = 7;
+const INVALIDATE: i32 = 10;
+const GETAI: i32 = 14;
+const INITGROUPS: i32 = 15;
+
+/// Longest key accepted from a client, matching nscd's own limit.
+const MAX_KEY_LEN: usize = 1024;
+
+const HOST_NOT_FOUND: i32 = 1;
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Accumulates a response in native byte order, as nscd sends it.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn i32(&mut self, value: i32) {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn len(&mut self, value: usize) {
+        self.i32(value as i32);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(protocol_error("truncated nscd response"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Ok(i32::from_ne_bytes(buf))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(self.i32()? as u32)
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Err(protocol_error("negative length in nscd response"));
+        }
+        Ok(len as usize)
+    }
+
+    /// Reads a NUL terminated string whose length (including the NUL) was sent earlier.
+    fn str(&mut self, len: usize) -> io::Result<String> {
+        let bytes = self.bytes(len)?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        str::from_utf8(bytes)
+            .map(str::to_string)
+            .map_err(|_| protocol_error("nscd response is not UTF-8"))
+    }
+}
+
+/// Talks to an nscd compatible daemon. Lookups return `Err` when the daemon can't be reached or
+/// is disabled for that database, and `Ok(None)` when it has positively no such entry.
+pub struct NscdClient {
+    path: PathBuf,
+    timeout: Duration,
+}
+
+impl Default for NscdClient {
+    fn default() -> Self {
+        NscdClient::new(DEFAULT_SOCKET)
+    }
+}
+
+impl NscdClient {
+    pub fn new<T: AsRef<Path>>(path: T) -> Self {
+        NscdClient {
+            path: path.as_ref().to_path_buf(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn get_passwd_by_name(&self, name: &str) -> io::Result<Option<Passwd>> {
+        self.get_passwd(GETPWBYNAME, name)
+    }
+
+    pub fn get_passwd_by_uid(&self, uid: Uid) -> io::Result<Option<Passwd>> {
+        self.get_passwd(GETPWBYUID, &uid.to_string())
+    }
+
+    pub fn get_group_by_name(&self, name: &str) -> io::Result<Option<Group>> {
+        self.get_group(GETGRBYNAME, name)
+    }
+
+    pub fn get_group_by_gid(&self, gid: Gid) -> io::Result<Option<Group>> {
+        self.get_group(GETGRBYGID, &gid.to_string())
+    }
+
+    pub fn get_host_by_name(&self, name: &str, family: AddressFamily) -> io::Result<Option<Host>> {
+        match family {
+            AddressFamily::IPv4 => self.get_host(GETHOSTBYNAME, &key(name)),
+            AddressFamily::IPv6 => self.get_host(GETHOSTBYNAMEV6, &key(name)),
+            AddressFamily::Unspecified => {
+                let (v4, v6) =
+                    host::lookup_both_families(|family| self.get_host_by_name(name, family));
+                match v4? {
+                    // The IPv6 answer only adds aliases here, so it failing doesn't matter
+                    Some(v4) => Ok(host::merge_families(Some(v4), v6.unwrap_or(None))),
+                    None => v6,
+                }
+            }
+            AddressFamily::Other(_) => Ok(None),
+        }
+    }
+
+    pub fn get_host_by_addr(&self, addr: IpAddr) -> io::Result<Option<Host>> {
+        match addr {
+            IpAddr::V4(addr) => self.get_host(GETHOSTBYADDR, &addr.octets()),
+            IpAddr::V6(addr) => self.get_host(GETHOSTBYADDRV6, &addr.octets()),
+        }
+    }
+
+    /// Asks the daemon to drop what it has cached for `database` (`"passwd"`, `"group"` or
+    /// `"hosts"`), as `nscd -i` does, for example after the backend's entries changed. nscd only
+    /// takes this from root.
+    pub fn invalidate(&self, database: &str) -> io::Result<()> {
+        let response = self.request(INVALIDATE, &key(database))?;
+        match Reader(&response).i32()? {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    fn request(&self, request_type: i32, key: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut request = Writer::default();
+        request.i32(NSCD_VERSION);
+        request.i32(request_type);
+        request.len(key.len());
+        request.0.extend_from_slice(key);
+        stream.write_all(&request.0)?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+
+    /// Checks the common `version` and `found` fields, returning whether an entry follows.
+    fn found(response: &mut Reader) -> io::Result<bool> {
+        if response.i32()? != NSCD_VERSION {
+            return Err(protocol_error("unsupported nscd protocol version"));
+        }
+        match response.i32()? {
+            -1 => Err(io::Error::other("nscd is disabled for this database")),
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    fn get_passwd(&self, request_type: i32, name: &str) -> io::Result<Option<Passwd>> {
+        let response = self.request(request_type, &key(name))?;
+        let mut r = Reader(&response);
+        if !Self::found(&mut r)? {
+            return Ok(None);
+        }
+
+        let name_len = r.len()?;
+        let passwd_len = r.len()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let gecos_len = r.len()?;
+        let dir_len = r.len()?;
+        let shell_len = r.len()?;
+
+        Ok(Some(Passwd {
+            name: r.str(name_len)?,
+            name_bytes: None,
+            passwd: r.str(passwd_len)?,
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(gid),
+            gecos: r.str(gecos_len)?,
+            dir: r.str(dir_len)?,
+            shell: r.str(shell_len)?,
+        }))
+    }
+
+    fn get_group(&self, request_type: i32, name: &str) -> io::Result<Option<Group>> {
+        let response = self.request(request_type, &key(name))?;
+        let mut r = Reader(&response);
+        if !Self::found(&mut r)? {
+            return Ok(None);
+        }
+
+        let name_len = r.len()?;
+        let passwd_len = r.len()?;
+        let gid = r.u32()?;
+        let member_count = r.len()?;
+        let member_lens = (0..member_count)
+            .map(|_| Ok(r.u32()? as usize))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Some(Group {
+            name: r.str(name_len)?,
+            name_bytes: None,
+            passwd: r.str(passwd_len)?,
+            gid: Gid::from_raw(gid),
+            members: member_lens
+                .into_iter()
+                .map(|len| r.str(len))
+                .collect::<io::Result<_>>()?,
+        }))
+    }
+
+    fn get_host(&self, request_type: i32, key: &[u8]) -> io::Result<Option<Host>> {
+        let response = self.request(request_type, key)?;
+        let mut r = Reader(&response);
+        if !Self::found(&mut r)? {
+            return Ok(None);
+        }
+
+        let name_len = r.len()?;
+        let alias_count = r.len()?;
+        let addr_type = r.i32()?;
+        let addr_len = r.len()?;
+        let addr_count = r.len()?;
+        let _error = r.i32()?;
+
+        let name = r.str(name_len)?;
+        let alias_lens = (0..alias_count)
+            .map(|_| Ok(r.u32()? as usize))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let addresses = match (addr_type, addr_len) {
+            (libc::AF_INET, 4) => Addresses::V4(
+                (0..addr_count)
+                    .map(|_| {
+                        let mut octets = [0u8; 4];
+                        octets.copy_from_slice(r.bytes(4)?);
+                        Ok(Ipv4Addr::from(octets))
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+            (libc::AF_INET6, 16) => Addresses::V6(
+                (0..addr_count)
+                    .map(|_| {
+                        let mut octets = [0u8; 16];
+                        octets.copy_from_slice(r.bytes(16)?);
+                        Ok(Ipv6Addr::from(octets))
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+            _ => return Err(protocol_error("unsupported address type in nscd response")),
+        };
+
+        Ok(Some(Host {
+            name,
+            aliases: alias_lens
+                .into_iter()
+                .map(|len| r.str(len))
+                .collect::<io::Result<_>>()?,
+            addresses,
+            canonical_name: None,
+        }))
+    }
+}
+
+fn key(name: &str) -> Vec<u8> {
+    let mut key = name.as_bytes().to_vec();
+    key.push(0);
+    key
+}
+
+struct PasswdLookups {
+    by_name: fn(String) -> Option<Passwd>,
+    by_uid: fn(Uid) -> Option<Passwd>,
+}
+
+struct GroupLookups {
+    all: fn() -> Vec<Group>,
+    by_name: fn(String) -> Option<Group>,
+    by_gid: fn(Gid) -> Option<Group>,
+}
+
+struct HostLookups {
+    by_name: fn(&str, AddressFamily) -> Option<Host>,
+    by_addr: fn(IpAddr) -> Option<Host>,
+}
+
+/// Answers nscd requests from hooks implementations. Databases that have no hooks registered
+/// reply with nscd's "disabled" status, which makes glibc fall back to its own NSS lookup.
+/// Nothing is cached, so invalidating (`nscd -i`) succeeds without anything to do.
+///
+/// ```no_run
+/// # use libnss::id::{Gid, Uid};
+/// # use libnss::passwd::{Passwd, PasswdHooks};
+/// # struct ExamplePasswd;
+/// # impl PasswdHooks for ExamplePasswd {
+/// #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+/// #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+/// #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+/// # }
+/// use libnss::nscd::{NscdServer, DEFAULT_SOCKET};
+///
+/// NscdServer::new()
+///     .with_passwd::<ExamplePasswd>()
+///     .listen(DEFAULT_SOCKET)
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct NscdServer {
+    passwd: Option<PasswdLookups>,
+    group: Option<GroupLookups>,
+    host: Option<HostLookups>,
+}
+
+impl NscdServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_passwd<P: PasswdHooks>(mut self) -> Self {
+        self.passwd = Some(PasswdLookups {
+            by_name: P::get_entry_by_name,
+            by_uid: P::get_entry_by_uid,
+        });
+        self
+    }
+
+    pub fn with_group<G: GroupHooks>(mut self) -> Self {
+        self.group = Some(GroupLookups {
+            all: G::get_all_entries,
+            by_name: G::get_entry_by_name,
+            by_gid: G::get_entry_by_gid,
+        });
+        self
+    }
+
+    pub fn with_host<H: HostHooks>(mut self) -> Self {
+        self.host = Some(HostLookups {
+            by_name: H::get_host_by_name,
+            by_addr: H::get_host_by_addr,
+        });
+        self
+    }
+
+    /// Binds a socket at `path`, replacing any stale one, and serves it forever.
+    pub fn listen<T: AsRef<Path>>(self, path: T) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        // Every process on the system must be able to connect, as with nscd itself
+        fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o666))?;
+        self.serve(listener)
+    }
+
+    /// Serves an already bound listener (for example one passed in by socket activation).
+    pub fn serve(self, listener: UnixListener) -> io::Result<()> {
+        let server = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = server.clone();
+            thread::spawn(move || {
+                let _ = server.handle_connection(stream);
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut header = [0u8; 12];
+        stream.read_exact(&mut header)?;
+        let mut r = Reader(&header);
+        let version = r.i32()?;
+        let request_type = r.i32()?;
+        let key_len = r.len()?;
+        if version != NSCD_VERSION || key_len > MAX_KEY_LEN {
+            return Ok(());
+        }
+
+        let mut key = vec![0u8; key_len];
+        stream.read_exact(&mut key)?;
+
+        // The hooks answer as if they were loaded into the peer
+        let response = match CallContext::of_peer(&stream) {
+            Some(peer) => peer.scope(|| self.respond(request_type, &key)),
+            None => self.respond(request_type, &key),
+        };
+        if let Some(response) = response {
+            stream.write_all(&response)?;
+        }
+        Ok(())
+    }
+
+    /// Builds the response to a request, or `None` to hang up without answering.
+    fn respond(&self, request_type: i32, key: &[u8]) -> Option<Vec<u8>> {
+        let text_key = || {
+            let key = key.strip_suffix(&[0]).unwrap_or(key);
+            str::from_utf8(key).ok().map(str::to_string)
+        };
+
+        match request_type {
+            GETPWBYNAME | GETPWBYUID => {
+                let lookups = match &self.passwd {
+                    Some(lookups) => lookups,
+                    None => return Some(disabled(7)),
+                };
+                let key = text_key()?;
+                let entry = if request_type == GETPWBYNAME {
+                    (lookups.by_name)(key)
+                } else {
+                    key.parse().ok().and_then(lookups.by_uid)
+                };
+                Some(passwd_response(entry.filter(Validate::is_valid).as_ref()))
+            }
+            GETGRBYNAME | GETGRBYGID => {
+                let lookups = match &self.group {
+                    Some(lookups) => lookups,
+                    None => return Some(disabled(4)),
+                };
+                let key = text_key()?;
+                let entry = if request_type == GETGRBYNAME {
+                    (lookups.by_name)(key)
+                } else {
+                    key.parse().ok().and_then(lookups.by_gid)
+                };
+                Some(group_response(entry.filter(Validate::is_valid).as_ref()))
+            }
+            INITGROUPS => {
+                let lookups = match &self.group {
+                    Some(lookups) => lookups,
+                    None => return Some(disabled(1)),
+                };
+                let user = text_key()?;
+                let gids: Vec<Gid> = (lookups.all)()
+                    .into_iter()
+                    .filter(|g| g.members.contains(&user))
+                    .map(|g| g.gid)
+                    .collect();
+
+                let mut w = Writer::default();
+                w.i32(NSCD_VERSION);
+                w.i32(if gids.is_empty() { 0 } else { 1 });
+                w.len(gids.len());
+                for gid in gids {
+                    w.u32(gid.as_raw());
+                }
+                Some(w.0)
+            }
+            GETHOSTBYNAME | GETHOSTBYNAMEV6 | GETHOSTBYADDR | GETHOSTBYADDRV6 => {
+                let lookups = match &self.host {
+                    Some(lookups) => lookups,
+                    None => return Some(disabled(6)),
+                };
+                let entry = match request_type {
+                    GETHOSTBYNAME => (lookups.by_name)(&text_key()?, AddressFamily::IPv4),
+                    GETHOSTBYNAMEV6 => (lookups.by_name)(&text_key()?, AddressFamily::IPv6),
+                    _ => (lookups.by_addr)(address_key(key)?),
+                };
+                Some(host_response(entry.filter(Validate::is_valid).as_ref()))
+            }
+            GETAI => {
+                let lookups = match &self.host {
+                    Some(lookups) => lookups,
+                    None => return Some(disabled(4)),
+                };
+                let name = text_key()?;
+                let v4 = (lookups.by_name)(&name, AddressFamily::IPv4).filter(Validate::is_valid);
+                let v6 = (lookups.by_name)(&name, AddressFamily::IPv6).filter(Validate::is_valid);
+                Some(ai_response(v4.as_ref(), v6.as_ref()))
+            }
+            INVALIDATE => {
+                // Answered as nscd does, though there is nothing to drop
+                let resp = if CallContext::current().euid() != 0 {
+                    libc::EACCES
+                } else {
+                    match text_key()?.as_str() {
+                        "passwd" | "group" | "hosts" => 0,
+                        _ => libc::EINVAL,
+                    }
+                };
+                let mut w = Writer::default();
+                w.i32(resp);
+                Some(w.0)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn address_key(key: &[u8]) -> Option<IpAddr> {
+    match key.len() {
+        4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(key);
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(key);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// A response telling the client that nscd doesn't serve this database. `fields` is the number
+/// of 32-bit fields in the database's response header after `version` and `found`.
+fn disabled(fields: usize) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.i32(NSCD_VERSION);
+    w.i32(-1);
+    for _ in 0..fields {
+        w.i32(0);
+    }
+    w.0
+}
+
+fn passwd_response(entry: Option<&Passwd>) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.i32(NSCD_VERSION);
+    match entry {
+        None => {
+            w.i32(0);
+            for _ in 0..7 {
+                w.i32(0);
+            }
+        }
+        Some(entry) => {
+            w.i32(1);
+            w.len(entry.name.len() + 1);
+            w.len(entry.passwd.len() + 1);
+            w.u32(entry.uid.as_raw());
+            w.u32(entry.gid.as_raw());
+            w.len(entry.gecos.len() + 1);
+            w.len(entry.dir.len() + 1);
+            w.len(entry.shell.len() + 1);
+            w.str(&entry.name);
+            w.str(&entry.passwd);
+            w.str(&entry.gecos);
+            w.str(&entry.dir);
+            w.str(&entry.shell);
+        }
+    }
+    w.0
+}
+
+fn group_response(entry: Option<&Group>) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.i32(NSCD_VERSION);
+    match entry {
+        None => {
+            w.i32(0);
+            for _ in 0..4 {
+                w.i32(0);
+            }
+        }
+        Some(entry) => {
+            w.i32(1);
+            w.len(entry.name.len() + 1);
+            w.len(entry.passwd.len() + 1);
+            w.u32(entry.gid.as_raw());
+            w.len(entry.members.len());
+            for member in &entry.members {
+                w.u32(member.len() as u32 + 1);
+            }
+            w.str(&entry.name);
+            w.str(&entry.passwd);
+            for member in &entry.members {
+                w.str(member);
+            }
+        }
+    }
+    w.0
+}
+
+fn host_response(entry: Option<&Host>) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.i32(NSCD_VERSION);
+    let entry = match entry {
+        None => {
+            w.i32(0);
+            for _ in 0..5 {
+                w.i32(0);
+            }
+            w.i32(HOST_NOT_FOUND);
+            return w.0;
+        }
+        Some(entry) => entry,
+    };
+
+    let (addr_type, addr_len, addresses): (i32, usize, Vec<Vec<u8>>) = match &entry.addresses {
+        Addresses::V4(addrs) => (
+            libc::AF_INET,
+            4,
+            addrs.iter().map(|a| a.octets().to_vec()).collect(),
+        ),
+        Addresses::V6(addrs) => (
+            libc::AF_INET6,
+            16,
+            addrs.iter().map(|a| a.octets().to_vec()).collect(),
+        ),
+        // As the hostent holds them, the IPv4 addresses mapped
+        Addresses::Mixed(addrs) => (
+            libc::AF_INET6,
+            16,
+            addrs
+                .iter()
+                .map(|a| address_order::to_ipv6(a).octets().to_vec())
+                .collect(),
+        ),
+    };
+
+    w.i32(1);
+    w.len(entry.name.len() + 1);
+    w.len(entry.aliases.len());
+    w.i32(addr_type);
+    w.len(addr_len);
+    w.len(addresses.len());
+    w.i32(0);
+    w.str(&entry.name);
+    for alias in &entry.aliases {
+        w.u32(alias.len() as u32 + 1);
+    }
+    for address in &addresses {
+        w.0.extend_from_slice(address);
+    }
+    for alias in &entry.aliases {
+        w.str(alias);
+    }
+    w.0
+}
+
+fn ai_response(v4: Option<&Host>, v6: Option<&Host>) -> Vec<u8> {
+    let mut addresses: Vec<(u8, Vec<u8>)> = Vec::new();
+    for host in v4.iter().chain(v6.iter()) {
+        match &host.addresses {
+            Addresses::V4(addrs) => addresses.extend(
+                addrs
+                    .iter()
+                    .map(|a| (libc::AF_INET as u8, a.octets().to_vec())),
+            ),
+            Addresses::V6(addrs) => addresses.extend(
+                addrs
+                    .iter()
+                    .map(|a| (libc::AF_INET6 as u8, a.octets().to_vec())),
+            ),
+            Addresses::Mixed(addrs) => addresses.extend(addrs.iter().map(|a| match a {
+                IpAddr::V4(a) => (libc::AF_INET as u8, a.octets().to_vec()),
+                IpAddr::V6(a) => (libc::AF_INET6 as u8, a.octets().to_vec()),
+            })),
+        }
+    }
+
+    let mut w = Writer::default();
+    w.i32(NSCD_VERSION);
+    let canon = match v4.or(v6) {
+        Some(host) if !addresses.is_empty() => &host.name,
+        _ => {
+            w.i32(0);
+            for _ in 0..3 {
+                w.i32(0);
+            }
+            w.i32(HOST_NOT_FOUND);
+            return w.0;
+        }
+    };
+
+    w.i32(1);
+    w.len(addresses.len());
+    w.len(addresses.iter().map(|(_, a)| a.len()).sum());
+    w.len(canon.len() + 1);
+    w.i32(0);
+    for (_, address) in &addresses {
+        w.0.extend_from_slice(address);
+    }
+    for (family, _) in &addresses {
+        w.0.push(*family);
+    }
+    w.str(canon);
+    w.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::JoinHandle;
+
+    fn socket(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("libnss-nscd-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Answers one request on `path` with `response`, returning the request it received.
+    fn fake_nscd(path: &Path, response: Vec<u8>) -> JoinHandle<Vec<u8>> {
+        let listener = UnixListener::bind(path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0u8; 12];
+            stream.read_exact(&mut request).unwrap();
+            let key_len = Reader(&request[8..]).len().unwrap();
+            let mut key = vec![0u8; key_len];
+            stream.read_exact(&mut key).unwrap();
+            request.extend(key);
+            stream.write_all(&response).unwrap();
+            request
+        })
+    }
+
+    fn request(request_type: i32, key: &[u8]) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.i32(NSCD_VERSION);
+        w.i32(request_type);
+        w.len(key.len());
+        w.0.extend_from_slice(key);
+        w.0
+    }
+
+    /// Asks a fake daemon, answering that it has no entry, returning the request it received.
+    fn sent<T>(name: &str, lookup: impl FnOnce(&NscdClient) -> io::Result<T>) -> Vec<u8> {
+        let path = socket(name);
+        let daemon = fake_nscd(&path, passwd_response(None));
+        let _ = lookup(&NscdClient::new(&path));
+        let request = daemon.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        request
+    }
+
+    #[test]
+    fn requests_are_encoded_as_glibc_sends_them() {
+        assert_eq!(
+            sent("pwnam", |c| c.get_passwd_by_name("alice")),
+            request(GETPWBYNAME, b"alice\0")
+        );
+        assert_eq!(
+            sent("pwuid", |c| c.get_passwd_by_uid(Uid::from_raw(1000))),
+            request(GETPWBYUID, b"1000\0")
+        );
+        assert_eq!(
+            sent("grnam", |c| c.get_group_by_name("staff")),
+            request(GETGRBYNAME, b"staff\0")
+        );
+        assert_eq!(
+            sent("grgid", |c| c.get_group_by_gid(Gid::from_raw(50))),
+            request(GETGRBYGID, b"50\0")
+        );
+        assert_eq!(
+            sent("hostv4", |c| c.get_host_by_name("db", AddressFamily::IPv4)),
+            request(GETHOSTBYNAME, b"db\0")
+        );
+        assert_eq!(
+            sent("hostv6", |c| c.get_host_by_name("db", AddressFamily::IPv6)),
+            request(GETHOSTBYNAMEV6, b"db\0")
+        );
+        assert_eq!(
+            sent("addrv4", |c| c.get_host_by_addr([10, 0, 0, 1].into())),
+            request(GETHOSTBYADDR, &[10, 0, 0, 1])
+        );
+        assert_eq!(
+            sent("addrv6", |c| c.get_host_by_addr(Ipv6Addr::LOCALHOST.into())),
+            request(GETHOSTBYADDRV6, &Ipv6Addr::LOCALHOST.octets())
+        );
+        assert_eq!(
+            sent("invalidate", |c| c.invalidate("passwd")),
+            request(INVALIDATE, b"passwd\0")
+        );
+    }
+
+    #[test]
+    fn missing_socket_is_an_error() {
+        let client = NscdClient::new(socket("missing"));
+        let err = client.get_passwd_by_name("alice").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(client.get_group_by_gid(Gid::from_raw(50)).is_err());
+        assert!(client
+            .get_host_by_name("db", AddressFamily::Unspecified)
+            .is_err());
+        assert!(client.invalidate("hosts").is_err());
+    }
+
+    #[test]
+    fn bad_responses_are_errors() {
+        let mut wrong_version = passwd_response(None);
+        wrong_version[..4].copy_from_slice(&1i32.to_ne_bytes());
+        let mut entry = passwd_response(Some(&passwd()));
+        entry.truncate(entry.len() - 1);
+
+        for (name, response) in [("version", wrong_version), ("truncated", entry)] {
+            let path = socket(name);
+            let daemon = fake_nscd(&path, response);
+            let err = NscdClient::new(&path)
+                .get_passwd_by_name("alice")
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", name);
+            daemon.join().unwrap();
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn invalidation_reports_the_daemons_error() {
+        let path = socket("refused");
+        let daemon = fake_nscd(&path, libc::EACCES.to_ne_bytes().to_vec());
+        let err = NscdClient::new(&path).invalidate("passwd").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        daemon.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalidation_is_for_root_and_known_databases() {
+        let server = NscdServer::new();
+        let invalidate = |euid: u32, database: &[u8]| {
+            CallContext::new(Uid::from_raw(euid), None)
+                .scope(|| server.respond(INVALIDATE, database))
+                .map(|response| Reader(&response).i32().unwrap())
+        };
+        assert_eq!(invalidate(0, b"passwd\0"), Some(0));
+        assert_eq!(invalidate(0, b"group\0"), Some(0));
+        assert_eq!(invalidate(0, b"hosts\0"), Some(0));
+        assert_eq!(invalidate(0, b"shadow\0"), Some(libc::EINVAL));
+        assert_eq!(invalidate(1000, b"passwd\0"), Some(libc::EACCES));
+    }
+
+    fn passwd() -> Passwd {
+        Passwd {
+            name: "alice".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(1000),
+            gid: Gid::from_raw(1000),
+            gecos: "Alice".to_string(),
+            dir: "/home/alice".to_string(),
+            shell: "/bin/sh".to_string(),
+        }
+    }
+
+    fn group() -> Group {
+        Group {
+            name: "staff".to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            gid: Gid::from_raw(50),
+            members: vec!["alice".to_string(), "bob".to_string()],
+        }
+    }
+
+    struct Hooks;
+
+    impl PasswdHooks for Hooks {
+        fn get_all_entries() -> Vec<Passwd> {
+            vec![passwd()]
+        }
+
+        fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+            Some(passwd()).filter(|p| p.uid == uid)
+        }
+
+        fn get_entry_by_name(name: String) -> Option<Passwd> {
+            Some(passwd()).filter(|p| p.name == name)
+        }
+    }
+
+    impl GroupHooks for Hooks {
+        fn get_all_entries() -> Vec<Group> {
+            vec![group()]
+        }
+
+        fn get_entry_by_gid(gid: Gid) -> Option<Group> {
+            Some(group()).filter(|g| g.gid == gid)
+        }
+
+        fn get_entry_by_name(name: String) -> Option<Group> {
+            Some(group()).filter(|g| g.name == name)
+        }
+    }
+
+    impl HostHooks for Hooks {
+        fn get_host_by_name(name: &str, family: AddressFamily) -> Option<Host> {
+            let addresses = match family {
+                AddressFamily::IPv4 => Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+                AddressFamily::IPv6 => Addresses::V6(vec![Ipv6Addr::LOCALHOST]),
+                _ => return None,
+            };
+            Some(Host {
+                name: "db.example".to_string(),
+                aliases: vec!["db".to_string()],
+                addresses,
+                canonical_name: None,
+            })
+            .filter(|_| name == "db.example")
+        }
+
+        fn get_host_by_addr(addr: IpAddr) -> Option<Host> {
+            match addr {
+                IpAddr::V4(_) => Self::get_host_by_name("db.example", AddressFamily::IPv4),
+                IpAddr::V6(_) => None,
+            }
+        }
+    }
+
+    fn serve(name: &str, server: NscdServer) -> NscdClient {
+        let path = socket(name);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || server.serve(listener));
+        NscdClient::new(path)
+    }
+
+    #[test]
+    fn entries_round_trip_through_the_server() {
+        let client = serve(
+            "server",
+            NscdServer::new()
+                .with_passwd::<Hooks>()
+                .with_group::<Hooks>()
+                .with_host::<Hooks>(),
+        );
+
+        assert_eq!(client.get_passwd_by_name("alice").unwrap(), Some(passwd()));
+        assert_eq!(
+            client.get_passwd_by_uid(Uid::from_raw(1000)).unwrap(),
+            Some(passwd())
+        );
+        assert_eq!(client.get_passwd_by_name("bob").unwrap(), None);
+        assert_eq!(client.get_group_by_name("staff").unwrap(), Some(group()));
+        assert_eq!(
+            client.get_group_by_gid(Gid::from_raw(50)).unwrap(),
+            Some(group())
+        );
+        assert_eq!(client.get_group_by_gid(Gid::from_raw(51)).unwrap(), None);
+
+        let v4 = client
+            .get_host_by_name("db.example", AddressFamily::IPv4)
+            .unwrap()
+            .unwrap();
+        assert_eq!(v4.aliases, ["db"]);
+        assert_eq!(
+            v4.addresses,
+            Addresses::V4(vec![Ipv4Addr::new(10, 0, 0, 1)])
+        );
+        assert_eq!(
+            client
+                .get_host_by_addr(Ipv4Addr::new(10, 0, 0, 1).into())
+                .unwrap(),
+            Some(v4)
+        );
+        assert_eq!(
+            client.get_host_by_addr(Ipv6Addr::LOCALHOST.into()).unwrap(),
+            None
+        );
+        assert_eq!(
+            client
+                .get_host_by_name("other.example", AddressFamily::IPv6)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn unserved_databases_are_disabled() {
+        let client = serve("disabled", NscdServer::new().with_passwd::<Hooks>());
+        assert!(client.get_passwd_by_name("alice").unwrap().is_some());
+        assert!(client.get_group_by_name("staff").is_err());
+        assert!(client
+            .get_host_by_name("db.example", AddressFamily::IPv4)
+            .is_err());
+    }
+}
@@ -0,0 +1,61 @@
+/*
+ * nsdispatch(3) methods that FreeBSD's libc has no glibc compatibility wrapper for. passwd and
+ * group lookups go through libc's own __nss_compat_* wrappers instead.
+ */
+
+#include "nsdispatch.h"
+
+int __libnss_rs_gethostbyname(void *retval, void *mdata, va_list ap)
+{
+    const struct libnss_rs_host_fns *fns = mdata;
+    const char *name = va_arg(ap, const char *);
+    int af = va_arg(ap, int);
+    struct hostent *hp = va_arg(ap, struct hostent *);
+    char *buf = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    int *errnop = va_arg(ap, int *);
+    int *h_errnop = va_arg(ap, int *);
+    int status;
+
+    status = fns->gethostbyname2_r(name, af, hp, buf, buflen, errnop, h_errnop);
+    *(struct hostent **)retval = status == NSS_STATUS_SUCCESS ? hp : NULL;
+    return host_status(status, h_errnop);
+}
+
+int __libnss_rs_gethostbyaddr(void *retval, void *mdata, va_list ap)
+{
+    const struct libnss_rs_host_fns *fns = mdata;
+    const void *addr = va_arg(ap, const void *);
+    socklen_t len = va_arg(ap, socklen_t);
+    int af = va_arg(ap, int);
+    struct hostent *hp = va_arg(ap, struct hostent *);
+    char *buf = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    int *errnop = va_arg(ap, int *);
+    int *h_errnop = va_arg(ap, int *);
+    int status;
+
+    status = fns->gethostbyaddr_r(addr, len, af, hp, buf, buflen, errnop, h_errnop);
+    *(struct hostent **)retval = status == NSS_STATUS_SUCCESS ? hp : NULL;
+    return host_status(status, h_errnop);
+}
+
+int __libnss_rs_getaddrinfo(void *retval, void *mdata, va_list ap)
+{
+    const char *name = va_arg(ap, const char *);
+    const struct addrinfo *pai = va_arg(ap, const struct addrinfo *);
+
+    return lookup_addrinfo(mdata, name, pai, retval);
+}
+
+int __libnss_rs_getgroupmembership(void *retval, void *mdata, va_list ap)
+{
+    const char *uname = va_arg(ap, const char *);
+    gid_t agroup = va_arg(ap, gid_t);
+    gid_t *groups = va_arg(ap, gid_t *);
+    int maxgrp = va_arg(ap, int);
+    int *grpcnt = va_arg(ap, int *);
+
+    (void)retval;
+    return collect_groups(mdata, uname, agroup, groups, maxgrp, grpcnt);
+}
@@ -0,0 +1,122 @@
+//! FreeBSD's method tables. The passwd and group entries point at libc's own `__nss_compat_*`
+//! wrappers, which take the matching glibc style function as their mdata; the rest are the shims
+//! in `freebsd.c`.
+
+use libc::c_void;
+
+use super::{leak, method, GroupFns, HostFns, NsMtab, PasswdFns};
+
+extern "C" {
+    fn __nss_compat_getpwnam_r();
+    fn __nss_compat_getpwuid_r();
+    fn __nss_compat_getpwent_r();
+    fn __nss_compat_setpwent();
+    fn __nss_compat_endpwent();
+
+    fn __nss_compat_getgrnam_r();
+    fn __nss_compat_getgrgid_r();
+    fn __nss_compat_getgrent_r();
+    fn __nss_compat_setgrent();
+    fn __nss_compat_endgrent();
+
+    fn __libnss_rs_getgroupmembership();
+    fn __libnss_rs_gethostbyname();
+    fn __libnss_rs_gethostbyaddr();
+    fn __libnss_rs_getaddrinfo();
+}
+
+pub fn passwd_methods(fns: PasswdFns) -> Vec<NsMtab> {
+    vec![
+        method(
+            b"passwd\0",
+            b"getpwnam_r\0",
+            __nss_compat_getpwnam_r,
+            fns.getpwnam_r as *mut c_void,
+        ),
+        method(
+            b"passwd\0",
+            b"getpwuid_r\0",
+            __nss_compat_getpwuid_r,
+            fns.getpwuid_r as *mut c_void,
+        ),
+        method(
+            b"passwd\0",
+            b"getpwent_r\0",
+            __nss_compat_getpwent_r,
+            fns.getpwent_r as *mut c_void,
+        ),
+        method(
+            b"passwd\0",
+            b"setpwent\0",
+            __nss_compat_setpwent,
+            fns.setpwent as *mut c_void,
+        ),
+        method(
+            b"passwd\0",
+            b"endpwent\0",
+            __nss_compat_endpwent,
+            fns.endpwent as *mut c_void,
+        ),
+    ]
+}
+
+pub fn group_methods(fns: GroupFns) -> Vec<NsMtab> {
+    let mut methods = vec![
+        method(
+            b"group\0",
+            b"getgrnam_r\0",
+            __nss_compat_getgrnam_r,
+            fns.getgrnam_r as *mut c_void,
+        ),
+        method(
+            b"group\0",
+            b"getgrgid_r\0",
+            __nss_compat_getgrgid_r,
+            fns.getgrgid_r as *mut c_void,
+        ),
+        method(
+            b"group\0",
+            b"getgrent_r\0",
+            __nss_compat_getgrent_r,
+            fns.getgrent_r as *mut c_void,
+        ),
+        method(
+            b"group\0",
+            b"setgrent\0",
+            __nss_compat_setgrent,
+            fns.setgrent as *mut c_void,
+        ),
+        method(
+            b"group\0",
+            b"endgrent\0",
+            __nss_compat_endgrent,
+            fns.endgrent as *mut c_void,
+        ),
+    ];
+    methods.push(method(
+        b"group\0",
+        b"getgroupmembership\0",
+        __libnss_rs_getgroupmembership,
+        leak(fns),
+    ));
+    methods
+}
+
+pub fn host_methods(fns: HostFns) -> Vec<NsMtab> {
+    let fns = leak(fns);
+    vec![
+        method(
+            b"hosts\0",
+            b"gethostbyname\0",
+            __libnss_rs_gethostbyname,
+            fns,
+        ),
+        method(
+            b"hosts\0",
+            b"gethostbyaddr\0",
+            __libnss_rs_gethostbyaddr,
+            fns,
+        ),
+        method(b"hosts\0", b"getaddrinfo\0", __libnss_rs_getaddrinfo, fns),
+    ]
+}
@@ -0,0 +1,81 @@
+//! FreeBSD and NetBSD nsdispatch(3) support, used by the `nss_module_register` function that
+//! [`libnss_module!`](../macro.libnss_module.html) generates.
+//!
+//! Both BSDs load `nss_<name>.so.<version>` and ask it for a table of methods per database. Each
+//! method is a small C shim (or on FreeBSD, for passwd and group, one of libc's own
+//! `__nss_compat_*` wrappers) that unpacks its arguments and calls the glibc style `_nss_<name>_*`
+//! functions the hooks macros already generate. The two differ only in which methods they look up
+//! and how those methods take their arguments.
+//!
+//! OpenBSD has no nsswitch at all: its libc only ever reads the `/etc` files and YP, so there is
+//! nothing for a module to hook into there.
+
+use libc::{c_char, c_uint, c_void};
+
+#[cfg(target_os = "freebsd")]
+mod freebsd;
+#[cfg(target_os = "freebsd")]
+pub use self::freebsd::{group_methods, host_methods, passwd_methods};
+
+#[cfg(target_os = "netbsd")]
+mod netbsd;
+#[cfg(target_os = "netbsd")]
+pub use self::netbsd::{group_methods, host_methods, passwd_methods};
+
+#[repr(C)]
+pub struct NsMtab {
+    pub database: *const c_char,
+    pub name: *const c_char,
+    pub method: *const c_void,
+    pub mdata: *mut c_void,
+}
+
+pub type NsModuleUnregister = Option<unsafe extern "C" fn(*mut NsMtab, c_uint)>;
+
+pub type NssFn = unsafe extern "C" fn();
+
+/// The module's passwd functions, mirrored by `struct libnss_rs_passwd_fns` in `nsdispatch.h`.
+#[repr(C)]
+pub struct PasswdFns {
+    pub getpwnam_r: NssFn,
+    pub getpwuid_r: NssFn,
+    pub getpwent_r: NssFn,
+    pub setpwent: NssFn,
+    pub endpwent: NssFn,
+}
+
+/// The module's group functions, mirrored by `struct libnss_rs_group_fns` in `nsdispatch.h`.
+#[repr(C)]
+pub struct GroupFns {
+    pub getgrnam_r: NssFn,
+    pub getgrgid_r: NssFn,
+    pub getgrent_r: NssFn,
+    pub setgrent: NssFn,
+    pub endgrent: NssFn,
+}
+
+/// The module's host functions, mirrored by `struct libnss_rs_host_fns` in `nsdispatch.h`.
+#[repr(C)]
+pub struct HostFns {
+    pub gethostbyname2_r: NssFn,
+    pub gethostbyaddr_r: NssFn,
+}
+
+fn method(
+    database: &'static [u8],
+    name: &'static [u8],
+    method: NssFn,
+    mdata: *mut c_void,
+) -> NsMtab {
+    NsMtab {
+        database: database.as_ptr() as *const c_char,
+        name: name.as_ptr() as *const c_char,
+        method: method as *const c_void,
+        mdata,
+    }
+}
+
+/// Lives as long as the module is loaded, which is the life of the process
+fn leak<T>(fns: T) -> *mut c_void {
+    Box::leak(Box::new(fns)) as *mut T as *mut c_void
+}
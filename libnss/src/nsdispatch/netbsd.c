@@ -0,0 +1,312 @@
+/*
+ * nsdispatch(3) methods for NetBSD. Unlike FreeBSD, NetBSD's libc has no glibc compatibility
+ * wrappers at all, so every method lives here. Reentrant methods receive their int *retval as the
+ * first variadic argument; the classic ones fill a static entry, as libc's own sources do.
+ */
+
+#include <errno.h>
+
+#include "nsdispatch.h"
+
+/* From NetBSD's private hostent.h: what gethostbyname(3) and friends pass as retval */
+struct getnamaddr {
+    struct hostent *hp;
+    char *buf;
+    size_t buflen;
+    int *he;
+};
+
+static struct passwd static_passwd;
+static struct group static_group;
+static char static_buffer[LOOKUP_BUFFER_LEN];
+
+/* Sets the reentrant out parameters the way libc's own sources do. */
+static int reentrant_status(int status, int errnop, int *retval, void *entry, void **result)
+{
+    *result = status == NSS_STATUS_SUCCESS ? entry : NULL;
+    *retval = status == NSS_STATUS_TRYAGAIN && errnop == ERANGE ? ERANGE : 0;
+    return ns_status(status);
+}
+
+int __libnss_rs_getpwnam(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+    struct passwd **retval = va_arg(ap, struct passwd **);
+    const char *name = va_arg(ap, const char *);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getpwnam_r(name, &static_passwd, static_buffer, sizeof(static_buffer), &errnop);
+    *retval = status == NSS_STATUS_SUCCESS ? &static_passwd : NULL;
+    return ns_status(status);
+}
+
+int __libnss_rs_getpwnam_r(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    const char *name = va_arg(ap, const char *);
+    struct passwd *pw = va_arg(ap, struct passwd *);
+    char *buffer = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    struct passwd **result = va_arg(ap, struct passwd **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getpwnam_r(name, pw, buffer, buflen, &errnop);
+    return reentrant_status(status, errnop, retval, pw, (void **)result);
+}
+
+int __libnss_rs_getpwuid(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+    struct passwd **retval = va_arg(ap, struct passwd **);
+    uid_t uid = va_arg(ap, uid_t);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getpwuid_r(uid, &static_passwd, static_buffer, sizeof(static_buffer), &errnop);
+    *retval = status == NSS_STATUS_SUCCESS ? &static_passwd : NULL;
+    return ns_status(status);
+}
+
+int __libnss_rs_getpwuid_r(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    uid_t uid = va_arg(ap, uid_t);
+    struct passwd *pw = va_arg(ap, struct passwd *);
+    char *buffer = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    struct passwd **result = va_arg(ap, struct passwd **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getpwuid_r(uid, pw, buffer, buflen, &errnop);
+    return reentrant_status(status, errnop, retval, pw, (void **)result);
+}
+
+int __libnss_rs_getpwent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+    struct passwd **retval = va_arg(ap, struct passwd **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getpwent_r(&static_passwd, static_buffer, sizeof(static_buffer), &errnop);
+    *retval = status == NSS_STATUS_SUCCESS ? &static_passwd : NULL;
+    return ns_status(status);
+}
+
+int __libnss_rs_getpwent_r(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    struct passwd *pw = va_arg(ap, struct passwd *);
+    char *buffer = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    struct passwd **result = va_arg(ap, struct passwd **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getpwent_r(pw, buffer, buflen, &errnop);
+    return reentrant_status(status, errnop, retval, pw, (void **)result);
+}
+
+int __libnss_rs_setpwent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+
+    (void)nsrv;
+    (void)ap;
+    return ns_status(fns->setpwent());
+}
+
+int __libnss_rs_setpassent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    int status;
+
+    (void)nsrv;
+    status = fns->setpwent();
+    *retval = status == NSS_STATUS_SUCCESS;
+    return ns_status(status);
+}
+
+int __libnss_rs_endpwent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_passwd_fns *fns = mdata;
+
+    (void)nsrv;
+    (void)ap;
+    return ns_status(fns->endpwent());
+}
+
+int __libnss_rs_getgrnam(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+    struct group **retval = va_arg(ap, struct group **);
+    const char *name = va_arg(ap, const char *);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getgrnam_r(name, &static_group, static_buffer, sizeof(static_buffer), &errnop);
+    *retval = status == NSS_STATUS_SUCCESS ? &static_group : NULL;
+    return ns_status(status);
+}
+
+int __libnss_rs_getgrnam_r(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    const char *name = va_arg(ap, const char *);
+    struct group *gr = va_arg(ap, struct group *);
+    char *buffer = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    struct group **result = va_arg(ap, struct group **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getgrnam_r(name, gr, buffer, buflen, &errnop);
+    return reentrant_status(status, errnop, retval, gr, (void **)result);
+}
+
+int __libnss_rs_getgrgid(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+    struct group **retval = va_arg(ap, struct group **);
+    gid_t gid = va_arg(ap, gid_t);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getgrgid_r(gid, &static_group, static_buffer, sizeof(static_buffer), &errnop);
+    *retval = status == NSS_STATUS_SUCCESS ? &static_group : NULL;
+    return ns_status(status);
+}
+
+int __libnss_rs_getgrgid_r(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    gid_t gid = va_arg(ap, gid_t);
+    struct group *gr = va_arg(ap, struct group *);
+    char *buffer = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    struct group **result = va_arg(ap, struct group **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getgrgid_r(gid, gr, buffer, buflen, &errnop);
+    return reentrant_status(status, errnop, retval, gr, (void **)result);
+}
+
+int __libnss_rs_getgrent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+    struct group **retval = va_arg(ap, struct group **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getgrent_r(&static_group, static_buffer, sizeof(static_buffer), &errnop);
+    *retval = status == NSS_STATUS_SUCCESS ? &static_group : NULL;
+    return ns_status(status);
+}
+
+int __libnss_rs_getgrent_r(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    struct group *gr = va_arg(ap, struct group *);
+    char *buffer = va_arg(ap, char *);
+    size_t buflen = va_arg(ap, size_t);
+    struct group **result = va_arg(ap, struct group **);
+    int errnop = 0, status;
+
+    (void)nsrv;
+    status = fns->getgrent_r(gr, buffer, buflen, &errnop);
+    return reentrant_status(status, errnop, retval, gr, (void **)result);
+}
+
+int __libnss_rs_setgrent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+
+    (void)nsrv;
+    (void)ap;
+    return ns_status(fns->setgrent());
+}
+
+int __libnss_rs_setgroupent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+    int *retval = va_arg(ap, int *);
+    int status;
+
+    (void)nsrv;
+    status = fns->setgrent();
+    *retval = status == NSS_STATUS_SUCCESS;
+    return ns_status(status);
+}
+
+int __libnss_rs_endgrent(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_group_fns *fns = mdata;
+
+    (void)nsrv;
+    (void)ap;
+    return ns_status(fns->endgrent());
+}
+
+int __libnss_rs_getgroupmembership(void *nsrv, void *mdata, va_list ap)
+{
+    int *retval = va_arg(ap, int *);
+    const char *uname = va_arg(ap, const char *);
+    gid_t agroup = va_arg(ap, gid_t);
+    gid_t *groups = va_arg(ap, gid_t *);
+    int maxgrp = va_arg(ap, int);
+    int *grpcnt = va_arg(ap, int *);
+    int status;
+
+    (void)nsrv;
+    status = collect_groups(mdata, uname, agroup, groups, maxgrp, grpcnt);
+    if (*grpcnt > maxgrp)
+        *retval = -1;
+    return status;
+}
+
+int __libnss_rs_gethostbyname(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_host_fns *fns = mdata;
+    struct getnamaddr *info = nsrv;
+    const char *name = va_arg(ap, const char *);
+    int errnop = 0, status, af;
+
+    (void)va_arg(ap, int); /* the length of name */
+    af = va_arg(ap, int);
+
+    status = fns->gethostbyname2_r(name, af, info->hp, info->buf, info->buflen, &errnop, info->he);
+    return host_status(status, info->he);
+}
+
+int __libnss_rs_gethostbyaddr(void *nsrv, void *mdata, va_list ap)
+{
+    const struct libnss_rs_host_fns *fns = mdata;
+    struct getnamaddr *info = nsrv;
+    const void *addr = va_arg(ap, const void *);
+    int len = va_arg(ap, int);
+    int af = va_arg(ap, int);
+    int errnop = 0, status;
+
+    status = fns->gethostbyaddr_r(addr, len, af, info->hp, info->buf, info->buflen, &errnop,
+                                  info->he);
+    return host_status(status, info->he);
+}
+
+int __libnss_rs_getaddrinfo(void *nsrv, void *mdata, va_list ap)
+{
+    const char *name = va_arg(ap, const char *);
+    const struct addrinfo *pai = va_arg(ap, const struct addrinfo *);
+
+    return lookup_addrinfo(mdata, name, pai, nsrv);
+}
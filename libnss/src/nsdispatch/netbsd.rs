@@ -0,0 +1,79 @@
+//! NetBSD's method tables. Every method is a shim in `netbsd.c` taking the whole set of the
+//! database's functions as its mdata.
+
+use super::{leak, method, GroupFns, HostFns, NsMtab, NssFn, PasswdFns};
+
+extern "C" {
+    fn __libnss_rs_getpwnam();
+    fn __libnss_rs_getpwnam_r();
+    fn __libnss_rs_getpwuid();
+    fn __libnss_rs_getpwuid_r();
+    fn __libnss_rs_getpwent();
+    fn __libnss_rs_getpwent_r();
+    fn __libnss_rs_setpwent();
+    fn __libnss_rs_setpassent();
+    fn __libnss_rs_endpwent();
+
+    fn __libnss_rs_getgrnam();
+    fn __libnss_rs_getgrnam_r();
+    fn __libnss_rs_getgrgid();
+    fn __libnss_rs_getgrgid_r();
+    fn __libnss_rs_getgrent();
+    fn __libnss_rs_getgrent_r();
+    fn __libnss_rs_setgrent();
+    fn __libnss_rs_setgroupent();
+    fn __libnss_rs_endgrent();
+    fn __libnss_rs_getgroupmembership();
+
+    fn __libnss_rs_gethostbyname();
+    fn __libnss_rs_gethostbyaddr();
+    fn __libnss_rs_getaddrinfo();
+}
+
+fn methods<T>(database: &'static [u8], shims: &[(&'static [u8], NssFn)], fns: T) -> Vec<NsMtab> {
+    let fns = leak(fns);
+    shims
+        .iter()
+        .map(|&(name, shim)| method(database, name, shim, fns))
+        .collect()
+}
+
+pub fn passwd_methods(fns: PasswdFns) -> Vec<NsMtab> {
+    let shims: [(&'static [u8], NssFn); 9] = [
+        (b"getpwnam\0", __libnss_rs_getpwnam),
+        (b"getpwnam_r\0", __libnss_rs_getpwnam_r),
+        (b"getpwuid\0", __libnss_rs_getpwuid),
+        (b"getpwuid_r\0", __libnss_rs_getpwuid_r),
+        (b"getpwent\0", __libnss_rs_getpwent),
+        (b"getpwent_r\0", __libnss_rs_getpwent_r),
+        (b"setpwent\0", __libnss_rs_setpwent),
+        (b"setpassent\0", __libnss_rs_setpassent),
+        (b"endpwent\0", __libnss_rs_endpwent),
+    ];
+    methods(b"passwd\0", &shims, fns)
+}
+
+pub fn group_methods(fns: GroupFns) -> Vec<NsMtab> {
+    let shims: [(&'static [u8], NssFn); 10] = [
+        (b"getgrnam\0", __libnss_rs_getgrnam),
+        (b"getgrnam_r\0", __libnss_rs_getgrnam_r),
+        (b"getgrgid\0", __libnss_rs_getgrgid),
+        (b"getgrgid_r\0", __libnss_rs_getgrgid_r),
+        (b"getgrent\0", __libnss_rs_getgrent),
+        (b"getgrent_r\0", __libnss_rs_getgrent_r),
+        (b"setgrent\0", __libnss_rs_setgrent),
+        (b"setgroupent\0", __libnss_rs_setgroupent),
+        (b"endgrent\0", __libnss_rs_endgrent),
+        (b"getgroupmembership\0", __libnss_rs_getgroupmembership),
+    ];
+    methods(b"group\0", &shims, fns)
+}
+
+pub fn host_methods(fns: HostFns) -> Vec<NsMtab> {
+    let shims: [(&'static [u8], NssFn); 3] = [
+        (b"gethostbyname\0", __libnss_rs_gethostbyname),
+        (b"gethostbyaddr\0", __libnss_rs_gethostbyaddr),
+        (b"getaddrinfo\0", __libnss_rs_getaddrinfo),
+    ];
+    methods(b"hosts\0", &shims, fns)
+}
@@ -0,0 +1,220 @@
+/*
+ * Helpers shared by the FreeBSD and NetBSD nsdispatch(3) methods. Every method unpacks its va_list
+ * and forwards to the module's glibc style _nss_<mod>_* functions, which the registration table
+ * passes in as mdata.
+ */
+
+#ifndef LIBNSS_RS_NSDISPATCH_H
+#define LIBNSS_RS_NSDISPATCH_H
+
+#include <sys/types.h>
+#include <sys/socket.h>
+#include <netinet/in.h>
+#include <grp.h>
+#include <netdb.h>
+#include <pwd.h>
+#include <stdarg.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* From <nsswitch.h>, which both BSDs agree on */
+#define NS_SUCCESS  (1 << 0)
+#define NS_UNAVAIL  (1 << 1)
+#define NS_NOTFOUND (1 << 2)
+#define NS_TRYAGAIN (1 << 3)
+#define NS_RETURN   (1 << 4)
+
+/* glibc's enum nss_status, as returned by the generated functions */
+#define NSS_STATUS_TRYAGAIN -2
+#define NSS_STATUS_UNAVAIL  -1
+#define NSS_STATUS_NOTFOUND 0
+#define NSS_STATUS_SUCCESS  1
+#define NSS_STATUS_RETURN   2
+
+#define LOOKUP_BUFFER_LEN 65536
+
+/* Mirrors of the structs in src/nsdispatch/mod.rs */
+struct libnss_rs_passwd_fns {
+    int (*getpwnam_r)(const char *, struct passwd *, char *, size_t, int *);
+    int (*getpwuid_r)(uid_t, struct passwd *, char *, size_t, int *);
+    int (*getpwent_r)(struct passwd *, char *, size_t, int *);
+    int (*setpwent)(void);
+    int (*endpwent)(void);
+};
+
+struct libnss_rs_group_fns {
+    int (*getgrnam_r)(const char *, struct group *, char *, size_t, int *);
+    int (*getgrgid_r)(gid_t, struct group *, char *, size_t, int *);
+    int (*getgrent_r)(struct group *, char *, size_t, int *);
+    int (*setgrent)(void);
+    int (*endgrent)(void);
+};
+
+struct libnss_rs_host_fns {
+    int (*gethostbyname2_r)(const char *, int, struct hostent *, char *, size_t, int *, int *);
+    int (*gethostbyaddr_r)(const void *, size_t, int, struct hostent *, char *, size_t, int *,
+                           int *);
+};
+
+static int ns_status(int status)
+{
+    switch (status) {
+    case NSS_STATUS_TRYAGAIN:
+        return NS_TRYAGAIN;
+    case NSS_STATUS_UNAVAIL:
+        return NS_UNAVAIL;
+    case NSS_STATUS_SUCCESS:
+        return NS_SUCCESS;
+    case NSS_STATUS_RETURN:
+        return NS_RETURN;
+    default:
+        return NS_NOTFOUND;
+    }
+}
+
+static int host_status(int status, int *h_errnop)
+{
+    /* Keep a module's NO_DATA, so callers go on to try the other family */
+    if (status == NSS_STATUS_NOTFOUND && *h_errnop != NO_DATA)
+        *h_errnop = HOST_NOT_FOUND;
+    else if (status == NSS_STATUS_TRYAGAIN)
+        *h_errnop = TRY_AGAIN;
+    else if (status == NSS_STATUS_UNAVAIL)
+        *h_errnop = NO_RECOVERY;
+    return ns_status(status);
+}
+
+/*
+ * Appends one addrinfo per address of hp to *tail. Each addrinfo is allocated together with its
+ * sockaddr, as libc's freeaddrinfo() expects.
+ */
+static int append_addrinfo(struct addrinfo ***tail, const struct hostent *hp,
+                           const struct addrinfo *pai, int canonname)
+{
+    char **addr;
+
+    for (addr = hp->h_addr_list; *addr != NULL; addr++) {
+        struct addrinfo *ai;
+        socklen_t socklen = hp->h_addrtype == AF_INET6 ? sizeof(struct sockaddr_in6)
+                                                       : sizeof(struct sockaddr_in);
+
+        ai = calloc(1, sizeof(*ai) + socklen);
+        if (ai == NULL)
+            return -1;
+        ai->ai_flags = pai->ai_flags;
+        ai->ai_family = hp->h_addrtype;
+        ai->ai_socktype = pai->ai_socktype;
+        ai->ai_protocol = pai->ai_protocol;
+        ai->ai_addrlen = socklen;
+        ai->ai_addr = (struct sockaddr *)(ai + 1);
+        ai->ai_addr->sa_family = hp->h_addrtype;
+#if defined(__FreeBSD__) || defined(__NetBSD__)
+        ai->ai_addr->sa_len = socklen;
+#endif
+        if (hp->h_addrtype == AF_INET6)
+            memcpy(&((struct sockaddr_in6 *)ai->ai_addr)->sin6_addr, *addr, hp->h_length);
+        else
+            memcpy(&((struct sockaddr_in *)ai->ai_addr)->sin_addr, *addr, hp->h_length);
+
+        if (canonname) {
+            ai->ai_canonname = strdup(hp->h_name);
+            canonname = 0;
+        }
+
+        **tail = ai;
+        *tail = &ai->ai_next;
+    }
+    return 0;
+}
+
+/* Resolves name in each family pai allows into a freshly allocated addrinfo chain. */
+static int lookup_addrinfo(const struct libnss_rs_host_fns *fns, const char *name,
+                           const struct addrinfo *pai, struct addrinfo **res)
+{
+    static const int families[] = { AF_INET, AF_INET6 };
+    struct addrinfo *result = NULL, **tail = &result;
+    int canonname = (pai->ai_flags & AI_CANONNAME) != 0;
+    int status = NSS_STATUS_NOTFOUND;
+    char *buf;
+    size_t i;
+
+    *res = NULL;
+    buf = malloc(LOOKUP_BUFFER_LEN);
+    if (buf == NULL)
+        return NS_UNAVAIL;
+
+    for (i = 0; i < sizeof(families) / sizeof(families[0]); i++) {
+        struct hostent he;
+        int errnop = 0, h_errnop = 0, found;
+
+        if (pai->ai_family != AF_UNSPEC && pai->ai_family != families[i])
+            continue;
+
+        found = fns->gethostbyname2_r(name, families[i], &he, buf, LOOKUP_BUFFER_LEN, &errnop,
+                                      &h_errnop);
+        if (found == NSS_STATUS_SUCCESS) {
+            if (append_addrinfo(&tail, &he, pai, canonname && result == NULL) != 0) {
+                status = NSS_STATUS_TRYAGAIN;
+                break;
+            }
+            status = NSS_STATUS_SUCCESS;
+        } else if (status != NSS_STATUS_SUCCESS && found != NSS_STATUS_NOTFOUND) {
+            status = found;
+        }
+    }
+    free(buf);
+
+    if (status != NSS_STATUS_SUCCESS) {
+        freeaddrinfo(result);
+        result = NULL;
+    }
+    *res = result;
+    return ns_status(status);
+}
+
+static void add_gid(gid_t gid, gid_t *groups, int maxgrp, int *grpcnt)
+{
+    int i;
+
+    for (i = 0; i < *grpcnt && i < maxgrp; i++) {
+        if (groups[i] == gid)
+            return;
+    }
+    if (*grpcnt < maxgrp)
+        groups[*grpcnt] = gid;
+    (*grpcnt)++;
+}
+
+/* Adds agroup and every group listing uname as a member to groups. */
+static int collect_groups(const struct libnss_rs_group_fns *fns, const char *uname, gid_t agroup,
+                          gid_t *groups, int maxgrp, int *grpcnt)
+{
+    struct group gr;
+    char *buf;
+    int errnop;
+
+    buf = malloc(LOOKUP_BUFFER_LEN);
+    if (buf == NULL)
+        return NS_UNAVAIL;
+
+    add_gid(agroup, groups, maxgrp, grpcnt);
+
+    fns->setgrent();
+    while (fns->getgrent_r(&gr, buf, LOOKUP_BUFFER_LEN, &errnop) == NSS_STATUS_SUCCESS) {
+        char **member;
+
+        for (member = gr.gr_mem; *member != NULL; member++) {
+            if (strcmp(*member, uname) == 0) {
+                add_gid(gr.gr_gid, groups, maxgrp, grpcnt);
+                break;
+            }
+        }
+    }
+    fns->endgrent();
+    free(buf);
+
+    /* Like the files source, let the remaining sources add their groups too */
+    return NS_NOTFOUND;
+}
+
+#endif
@@ -1,35 +1,192 @@
-use crate::interop::CBuffer;
+use crate::id::{Gid, Uid};
+use crate::interop::{
+    checked_size, rebase_ptr, wipe, write_entry, CBlob, CBuffer, NssStatus, Rebase, SizeOverflow,
+};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::time::Duration;
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Passwd {
     pub name: String,
+    /// The name's original bytes, for a name that isn't UTF-8, such as a latin-1 one from a legacy
+    /// directory. Every lookup copies these rather than `name`, the bytes decoded, so that the
+    /// name a `getpwuid` returns finds the entry again.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub name_bytes: Option<CString>,
     pub passwd: String,
-    pub uid: libc::uid_t,
-    pub gid: libc::gid_t,
+    pub uid: Uid,
+    pub gid: Gid,
     pub gecos: String,
     pub dir: String,
     pub shell: String,
 }
 
 impl Passwd {
-    pub unsafe fn to_c_passwd(self, pwbuf: *mut CPasswd, buffer: &mut CBuffer) {
-        (*pwbuf).name = buffer.write_str(self.name);
-        (*pwbuf).passwd = buffer.write_str(self.passwd);
-        (*pwbuf).uid = self.uid;
-        (*pwbuf).gid = self.gid;
-        (*pwbuf).gecos = buffer.write_str(self.gecos);
-        (*pwbuf).dir = buffer.write_str(self.dir);
-        (*pwbuf).shell = buffer.write_str(self.shell);
+    pub unsafe fn to_c_passwd(mut self, pwbuf: *mut CPasswd, buffer: &mut CBuffer) {
+        let written = self.write_c_passwd(None, pwbuf, buffer);
+        wipe(&mut self.passwd);
+        written.expect("Not enough free space in buffer")
+    }
+
+    /// Copies the fields, under `name` rather than the entry's own if given, without taking them,
+    /// or returns `SizeOverflow` once one doesn't fit.
+    unsafe fn write_c_passwd(
+        &self,
+        name: Option<&CStr>,
+        pwbuf: *mut CPasswd,
+        buffer: &mut CBuffer,
+    ) -> Result<(), SizeOverflow> {
+        (*pwbuf).name = match name.or(self.name_bytes.as_deref()) {
+            Some(name) => buffer.write_cstr(name)?,
+            None => buffer.copy_str(&self.name)?,
+        };
+        (*pwbuf).passwd = buffer.copy_str(&self.passwd)?;
+        (*pwbuf).uid = self.uid.as_raw();
+        (*pwbuf).gid = self.gid.as_raw();
+        (*pwbuf).gecos = buffer.copy_str(&self.gecos)?;
+        (*pwbuf).dir = buffer.copy_str(&self.dir)?;
+        (*pwbuf).shell = buffer.copy_str(&self.shell)?;
+
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        {
+            (*pwbuf).change = 0;
+            (*pwbuf).class = buffer.copy_str("")?;
+            (*pwbuf).expire = 0;
+        }
+        #[cfg(target_os = "freebsd")]
+        {
+            (*pwbuf).fields = 0;
+        }
+        Ok(())
+    }
+
+    /// The entry serialized once, to copy into each caller's buffer. Panics for an entry too big to
+    /// lay out, which [`try_to_c_blob`](Self::try_to_c_blob) returns as an error instead.
+    pub fn to_c_blob(&self) -> CBlob<CPasswd> {
+        self.try_to_c_blob().expect("entry too big to lay out")
+    }
+
+    pub fn try_to_c_blob(&self) -> Result<CBlob<CPasswd>, SizeOverflow> {
+        let capacity = self.checked_c_size(None)?;
+        unsafe {
+            Ok(CBlob::new(capacity, |pwbuf, buffer| {
+                self.write_c_passwd(None, pwbuf, buffer)
+                    .expect("entry sized to fit")
+            }))
+        }
+    }
+
+    /// The entry serialized as [`try_to_c_blob`](Self::try_to_c_blob) does, but under `name`, the
+    /// bytes it was looked up by, rather than its own name, which the hooks may have decoded from
+    /// them.
+    pub fn try_to_c_blob_as(&self, name: &CStr) -> Result<CBlob<CPasswd>, SizeOverflow> {
+        let capacity = self.checked_c_size(Some(name))?;
+        unsafe {
+            Ok(CBlob::new(capacity, |pwbuf, buffer| {
+                self.write_c_passwd(Some(name), pwbuf, buffer)
+                    .expect("entry sized to fit")
+            }))
+        }
+    }
+
+    fn checked_c_size(&self, name: Option<&CStr>) -> Result<usize, SizeOverflow> {
+        let name = name
+            .or(self.name_bytes.as_deref())
+            .map_or(self.name.len() + 1, |name| name.to_bytes_with_nul().len());
+        let strings = [&self.passwd, &self.gecos, &self.dir, &self.shell];
+        // Each string and its NUL, plus the empty login class on the BSDs
+        let class = cfg!(any(target_os = "freebsd", target_os = "netbsd")) as usize;
+        checked_size(
+            strings
+                .iter()
+                .map(|s| s.len() + 1)
+                .chain([name, class].iter().copied()),
+        )
+    }
+}
+
+/// Copies `passwd` as a `getpw*_r` function returns it, straight into the caller's buffer. A
+/// buffer too small for it gets `TryAgain` with `ERANGE` in `errnop`, and an entry too big for any
+/// buffer `Unavail`.
+pub unsafe fn write_passwd(
+    passwd: &Passwd,
+    result: *mut CPasswd,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+) -> NssStatus {
+    let size = passwd.checked_c_size(None);
+    write_entry(size, result, buf, buflen, errnop, |pwbuf, buffer| {
+        passwd.write_c_passwd(None, pwbuf, buffer)
+    })
+}
+
+/// [`write_passwd`] under `name`, the bytes the entry was looked up by, as
+/// [`Passwd::try_to_c_blob_as`] serializes it.
+pub unsafe fn write_passwd_as(
+    passwd: &Passwd,
+    name: &CStr,
+    result: *mut CPasswd,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+) -> NssStatus {
+    let size = passwd.checked_c_size(Some(name));
+    write_entry(size, result, buf, buflen, errnop, |pwbuf, buffer| {
+        passwd.write_c_passwd(Some(name), pwbuf, buffer)
+    })
+}
+
+/// The entry as `getent passwd` prints it.
+impl fmt::Display for Passwd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}:{}:{}:{}",
+            self.name, self.passwd, self.uid, self.gid, self.gecos, self.dir, self.shell
+        )
     }
 }
 
 pub trait PasswdHooks {
-    fn get_all_entries() -> Vec<Passwd>;
+    /// How long a `setpwent` may reuse the entries an earlier enumeration fetched, rather
+    /// than fetching them all again. Zero, the default, always fetches.
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(0);
+
+    /// The entries `getent passwd` lists. Every method has a default, so hooks only write the
+    /// lookups they support; this one lists nothing, which for a directory too big to enumerate
+    /// is better left out altogether with `no_enumeration`.
+    fn get_all_entries() -> Vec<Passwd> {
+        Vec::new()
+    }
 
-    fn get_entry_by_uid(uid: libc::uid_t) -> Option<Passwd>;
+    /// The default finds nothing, so `getpwuid` answers `NotFound`.
+    fn get_entry_by_uid(_uid: Uid) -> Option<Passwd> {
+        None
+    }
+
+    /// The default finds nothing, so `getpwnam` answers `NotFound`.
+    fn get_entry_by_name(_name: String) -> Option<Passwd> {
+        None
+    }
 
-    fn get_entry_by_name(name: String) -> Option<Passwd>;
+    /// `get_entry_by_name` for a name as the C string an application passed, which needn't be
+    /// UTF-8: a latin-1 name from a legacy directory, say. Lookups only call this for names that
+    /// aren't UTF-8, and return what it finds under the name exactly as given, so the entry's own
+    /// name may be the bytes decoded. Give the entry those bytes as its `name_bytes` wherever the
+    /// hooks return it, so that `getpwuid` and `getpwent` return them too. The default hands UTF-8
+    /// names to `get_entry_by_name` and finds nothing for the rest.
+    fn get_entry_by_name_bytes(name: &CStr) -> Option<Passwd> {
+        Self::get_entry_by_name(name.to_str().ok()?.to_string())
+    }
 }
 
+#[cfg(not(any(target_os = "freebsd", target_os = "netbsd")))]
 #[repr(C)]
 #[allow(missing_copy_implementations)]
 pub struct CPasswd {
@@ -42,90 +199,302 @@ pub struct CPasswd {
     pub shell: *mut libc::c_char,
 }
 
+/// The BSDs' `struct passwd` carries login class and expiry fields between the glibc ones
+#[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+#[repr(C)]
+#[allow(missing_copy_implementations)]
+pub struct CPasswd {
+    pub name: *mut libc::c_char,
+    pub passwd: *mut libc::c_char,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub change: libc::time_t,
+    pub class: *mut libc::c_char,
+    pub gecos: *mut libc::c_char,
+    pub dir: *mut libc::c_char,
+    pub shell: *mut libc::c_char,
+    pub expire: libc::time_t,
+    #[cfg(target_os = "freebsd")]
+    pub fields: libc::c_int,
+}
+
+impl Rebase for CPasswd {
+    unsafe fn rebase(&mut self, from: usize, len: usize, to: usize) {
+        rebase_ptr(&mut self.name, from, len, to);
+        rebase_ptr(&mut self.passwd, from, len, to);
+        rebase_ptr(&mut self.gecos, from, len, to);
+        rebase_ptr(&mut self.dir, from, len, to);
+        rebase_ptr(&mut self.shell, from, len, to);
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        rebase_ptr(&mut self.class, from, len, to);
+    }
+}
+
+/// Generates the `_nss_<module>_*` passwd functions NSS calls, answered by `$hooks`, any type
+/// implementing [`PasswdHooks`](passwd/trait.PasswdHooks.html). The same lookups are callable from
+/// Rust through the generated `<module>_passwd` module, e.g. `example_passwd::lookup_passwd_by_uid`.
+///
+/// Every generated item is named after the module, so one crate can implement several modules
+/// and databases side by side. Invoke it outside of any function, as it declares modules:
+///
+/// ```
+/// # use libnss::group::GroupHooks;
+/// # use libnss::host::HostHooks;
+/// # use libnss::id::Uid;
+/// # use libnss::passwd::PasswdHooks;
+/// # struct Users;
+/// # impl PasswdHooks for Users {}
+/// # struct Groups;
+/// # impl GroupHooks for Groups {}
+/// # struct Hosts;
+/// # impl HostHooks for Hosts {}
+/// use libnss::{libnss_group_hooks, libnss_host_hooks, libnss_passwd_hooks};
+///
+/// libnss_passwd_hooks!(first, Users);
+/// libnss_group_hooks!(first, Groups);
+/// libnss_host_hooks!(first, Hosts);
+///
+/// mod second {
+///     libnss::libnss_passwd_hooks!(second, super::Users);
+///     libnss::libnss_group_hooks!(second, super::Groups, no_enumeration);
+///     libnss::libnss_host_hooks!(second, super::Hosts);
+/// }
+///
+/// fn main() {
+///     assert!(first_passwd::lookup_passwd_by_uid(Uid::from_raw(0)).is_err());
+///     assert!(second::second_hosts::all_host_entries().is_empty());
+/// }
+/// ```
+///
+/// Given `prefix = <ident>` last, after `no_enumeration` if that's given too, every macro exports
+/// its functions as `<prefix><function>` instead, such as `_nss_test_getpwuid_r`, while the module
+/// name still names the Rust items. That exports the same hooks a second time, under a prefix a
+/// test harness calls in-process without clashing with the real module, or as a compatibility
+/// module of another name. Pass the prefix to [`libnss_module!`] as well on the BSDs:
+///
+/// ```
+/// # use libnss::id::{Gid, Uid};
+/// # use libnss::passwd::{Passwd, PasswdHooks};
+/// # struct Users;
+/// # impl PasswdHooks for Users {
+/// #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+/// #     fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+/// #         Some(Passwd {
+/// #             name: "root".to_string(),
+/// #             name_bytes: None,
+/// #             passwd: "x".to_string(),
+/// #             uid,
+/// #             gid: Gid::from_raw(0),
+/// #             gecos: String::new(),
+/// #             dir: "/root".to_string(),
+/// #             shell: "/bin/sh".to_string(),
+/// #         })
+/// #     }
+/// #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+/// # }
+/// use std::ffi::CStr;
+/// use std::mem::MaybeUninit;
+/// use libnss::libc::{c_char, c_int, size_t, uid_t};
+/// use libnss::passwd::CPasswd;
+///
+/// libnss::libnss_passwd_hooks!(example, Users);
+/// libnss::libnss_passwd_hooks!(example_test, Users, no_enumeration, prefix = _nss_test_);
+///
+/// extern "C" {
+///     fn _nss_test_getpwuid_r(uid: uid_t, result: *mut CPasswd, buf: *mut c_char, buflen: size_t,
+///                             errnop: *mut c_int) -> c_int;
+/// }
+///
+/// fn main() {
+///     let mut result = MaybeUninit::<CPasswd>::zeroed();
+///     let mut buf = [0 as c_char; 256];
+///     let mut errno = 0;
+///     unsafe {
+///         let status = _nss_test_getpwuid_r(0, result.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut errno);
+///         assert_eq!(status, libnss::interop::NSS_STATUS_SUCCESS);
+///         assert_eq!(CStr::from_ptr(result.assume_init().name).to_str(), Ok("root"));
+///     }
+/// }
+/// ```
 #[macro_export]
 macro_rules! libnss_passwd_hooks {
-($mod_ident:ident, $hooks_ident:ident) => (
-    paste::item! {
-        pub use self::[<libnss_passwd_ $mod_ident _hooks_impl>]::*;
-        mod [<libnss_passwd_ $mod_ident _hooks_impl>] {
+($mod_ident:ident, $hooks:ty) => (
+    $crate::paste::item! {
+        $crate::libnss_passwd_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, no_enumeration) => (
+    $crate::paste::item! {
+        $crate::libnss_passwd_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, no_enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, prefix = $prefix:ident) => (
+    $crate::libnss_passwd_hooks!(@module $mod_ident, $prefix, $hooks, enumeration);
+);
+($mod_ident:ident, $hooks:ty, no_enumeration, prefix = $prefix:ident) => (
+    $crate::libnss_passwd_hooks!(@module $mod_ident, $prefix, $hooks, no_enumeration);
+);
+(@module $mod_ident:ident, $prefix:ident, $hooks:ty, $enumeration:ident) => (
+    $crate::paste::item! {
+        // Named here so that the hooks type resolves where the macro was invoked
+        #[allow(non_camel_case_types)]
+        type [<libnss_passwd_ $mod_ident _hooks>] = $hooks;
+
+        pub mod [<$mod_ident _passwd>] {
             #![allow(non_upper_case_globals)]
 
-            use std::ffi::CStr;
-            use std::str;
-            use std::sync::{Mutex, MutexGuard};
-            use $crate::interop::{CBuffer, Iterator, NssStatus};
+            use ::std::ffi::CStr;
+            use ::std::str;
+            use $crate::validate::Validate;
+            use $crate::interop::NssStatus;
             use $crate::passwd::{CPasswd, Passwd, PasswdHooks};
+            use $crate::stats::{Call, Database};
+            use super::[<libnss_passwd_ $mod_ident _hooks>] as Hooks;
 
-            lazy_static! {
-            static ref [<PASSWD_ $mod_ident _ITERATOR>]: Mutex<Iterator<Passwd>> = Mutex::new(Iterator::<Passwd>::new());
+            /// The entries `setpwent` enumerates, as `getpwent_r` would return them.
+            pub fn all_passwd_entries() -> Vec<Passwd> {
+                Hooks::get_all_entries().into_iter().filter(Validate::is_valid).collect()
             }
 
-            #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _setpwent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Passwd>> = [<PASSWD_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.open(super::$hooks_ident::get_all_entries());
-                NssStatus::Success.to_c()
+            /// The entry `getpwuid_r` returns, without going through its C interface.
+            pub fn lookup_passwd_by_uid(uid: $crate::id::Uid) -> Result<Passwd, NssStatus> {
+                Hooks::get_entry_by_uid(uid).filter(Validate::is_valid).ok_or(NssStatus::NotFound)
             }
 
-            #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _endpwent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Passwd>> = [<PASSWD_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.close();
-
-                NssStatus::Success.to_c()
+            /// The entry `getpwnam_r` returns, without going through its C interface.
+            pub fn lookup_passwd_by_name(name: &str) -> Result<Passwd, NssStatus> {
+                Hooks::get_entry_by_name(name.to_string()).filter(Validate::is_valid).ok_or(NssStatus::NotFound)
             }
 
-            #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getpwent_r>](pwbuf: *mut CPasswd, buf: *mut libc::c_char, buflen: libc::size_t,
-                                                                  _errnop: *mut libc::c_int) -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Passwd>> = [<PASSWD_ $mod_ident _ITERATOR>].lock().unwrap();
-                match iter.next() {
-                    None => $crate::interop::NssStatus::NotFound.to_c(),
-                    Some(entry) => {
-                        let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                        buffer.clear();
-
-                        entry.to_c_passwd(pwbuf, &mut buffer);
-                        NssStatus::Success.to_c()
-                    }
-                }
+            /// The entry `getpwnam_r` returns for a name that isn't UTF-8, which it copies under
+            /// that name.
+            pub fn lookup_passwd_by_name_bytes(name: &CStr) -> Result<Passwd, NssStatus> {
+                $crate::validate::validate_name_bytes(name).map_err(|_| NssStatus::NotFound)?;
+                Hooks::get_entry_by_name_bytes(name).filter(Validate::is_valid).ok_or(NssStatus::NotFound)
             }
 
+            $crate::libnss_passwd_hooks!(@$enumeration $mod_ident, $prefix);
+
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getpwuid_r>](uid: libc::uid_t, pwbuf: *mut CPasswd, buf: *mut libc::c_char,
-                                                           buflen: libc::size_t, _errnop: *mut libc::c_int) -> libc::c_int {
-                match super::$hooks_ident::get_entry_by_uid(uid) {
-                    Some(val) => {
-                        let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                        buffer.clear();
-
-                        val.to_c_passwd(pwbuf, &mut buffer);
-                        NssStatus::Success.to_c()
+            unsafe extern "C" fn [<$prefix getpwuid_r>](uid: $crate::libc::uid_t, pwbuf: *mut CPasswd, buf: *mut $crate::libc::c_char,
+                                                           buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                let call = Call::start(Database::Passwd);
+                let status = match lookup_passwd_by_uid($crate::id::Uid::from_raw(uid)) {
+                    Ok(mut val) => {
+                        let status = $crate::passwd::write_passwd(&val, pwbuf, buf, buflen, errnop);
+                        $crate::interop::wipe(&mut val.passwd);
+                        status
                     },
-                    None => NssStatus::NotFound.to_c()
-                }
+                    Err(status) => status
+                };
+                call.finish(status, errnop)
             }
 
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getpwnam_r>](name_: *const libc::c_char, pwbuf: *mut CPasswd, buf: *mut libc::c_char,
-                                                           buflen: libc::size_t, _errnop: *mut libc::c_int) -> libc::c_int {
+            unsafe extern "C" fn [<$prefix getpwnam_r>](name_: *const $crate::libc::c_char, pwbuf: *mut CPasswd, buf: *mut $crate::libc::c_char,
+                                                           buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                let call = Call::start(Database::Passwd);
+                if name_.is_null() {
+                    return call.finish($crate::interop::invalid_argument(errnop), errnop);
+                }
                 let cstr = CStr::from_ptr(name_);
 
-                match str::from_utf8(cstr.to_bytes()) {
-                    Ok(name) => match super::$hooks_ident::get_entry_by_name(name.to_string()) {
-                        Some(val) => {
-                            let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                            buffer.clear();
-
-                            val.to_c_passwd(pwbuf, &mut buffer);
-                            NssStatus::Success.to_c()
+                let status = match str::from_utf8(cstr.to_bytes()) {
+                    Ok(name) => match lookup_passwd_by_name(name) {
+                        Ok(mut val) => {
+                            let status = $crate::passwd::write_passwd(&val, pwbuf, buf, buflen, errnop);
+                            $crate::interop::wipe(&mut val.passwd);
+                            status
                         },
-                        None => NssStatus::NotFound.to_c()
+                        Err(status) => status
                     },
-                    Err(_) => NssStatus::NotFound.to_c()
-                }
+                    Err(_) => match lookup_passwd_by_name_bytes(cstr) {
+                        Ok(mut val) => {
+                            let status = $crate::passwd::write_passwd_as(&val, cstr, pwbuf, buf, buflen, errnop);
+                            $crate::interop::wipe(&mut val.passwd);
+                            status
+                        },
+                        Err(status) => status
+                    }
+                };
+                call.finish(status, errnop)
             }
+
+            #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+            #[no_mangle]
+            extern "C" fn [<$prefix passwd_constr>](_db_name: *const $crate::libc::c_char, _src_name: *const $crate::libc::c_char,
+                                                          _cfg_args: *const $crate::libc::c_char) -> *mut $crate::solaris::NssBackend {
+                $crate::solaris::passwd_backend::<Hooks>()
+            }
+        }
+    }
+);
+(@enumeration $mod_ident:ident, $prefix:ident) => (
+    $crate::paste::item! {
+        use ::std::sync::{Mutex, MutexGuard};
+        use $crate::interop::EntryCursor;
+
+        static [<PASSWD_ $mod_ident _ITERATOR>]: Mutex<EntryCursor<Passwd>> = Mutex::new(EntryCursor::new());
+
+        #[no_mangle]
+        extern "C" fn [<$prefix setpwent>](stayopen: $crate::libc::c_int) -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Passwd>> = [<PASSWD_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.open_cached(<Hooks as PasswdHooks>::ENUMERATION_CACHE_TTL, stayopen != 0, all_passwd_entries);
+            NssStatus::Success.to_c()
+        }
+
+        #[no_mangle]
+        extern "C" fn [<$prefix endpwent>]() -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Passwd>> = [<PASSWD_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.close();
+
+            NssStatus::Success.to_c()
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn [<$prefix getpwent_r>](pwbuf: *mut CPasswd, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t,
+                                                              errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+            let call = Call::start(Database::Passwd);
+            // Serializing the entry doesn't need the cursor, so release it first
+            let entry = [<PASSWD_ $mod_ident _ITERATOR>].lock().unwrap().next();
+            let status = match entry {
+                None => NssStatus::NotFound,
+                Some(mut entry) => {
+                    let status = $crate::passwd::write_passwd(&entry, pwbuf, buf, buflen, errnop);
+                    // The caller will ask again with a bigger buffer
+                    if status == NssStatus::TryAgain {
+                        [<PASSWD_ $mod_ident _ITERATOR>].lock().unwrap().put_back(entry);
+                    } else {
+                        $crate::interop::wipe(&mut entry.passwd);
+                    }
+                    status
+                }
+            };
+            call.finish(status, errnop)
+        }
+    }
+);
+(@no_enumeration $mod_ident:ident, $prefix:ident) => (
+    // The BSDs' method tables need every function, so there an enumeration finds nothing
+    $crate::paste::item! {
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix setpwent>](_stayopen: $crate::libc::c_int) -> $crate::libc::c_int {
+            NssStatus::Success.to_c()
+        }
+
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix endpwent>]() -> $crate::libc::c_int {
+            NssStatus::Success.to_c()
+        }
+
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix getpwent_r>](_result: *mut CPasswd, _buf: *mut $crate::libc::c_char, _buflen: $crate::libc::size_t,
+                                                       _errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+            NssStatus::NotFound.to_c()
         }
     }
-)
+);
 }
\ No newline at end of file
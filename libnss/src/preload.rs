@@ -0,0 +1,903 @@
+//! musl support, used by the libc overrides that [`libnss_preload!`](../macro.libnss_preload.html)
+//! generates.
+//!
+//! musl has no NSS: its `getpwnam` and friends only ever read the `/etc` files (and nscd's
+//! socket), so on Alpine nothing loads a module. Instead, a library built with `libnss_preload!`
+//! defines those libc functions itself. Listed in `LD_PRELOAD`, or linked into a program, it comes
+//! before libc in the search order, so its functions answer every lookup: each asks libc first,
+//! as `files` comes first in a usual `nsswitch.conf`, and if libc finds nothing, it asks the
+//! module's `_nss_*` functions. The answers are the ones glibc would get from the module.
+//!
+//! The overrides cover looking entries up: `getpwnam`, `getpwuid`, `getgrnam` and `getgrgid`,
+//! their `_r` forms, and `getaddrinfo` with its `freeaddrinfo`. Enumerating (`getpwent`),
+//! supplementary groups (`getgrouplist`) and `gethostbyname` stay libc's alone.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::net::IpAddr;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::LocalKey;
+
+use libc::{addrinfo, c_char, c_int, gid_t, size_t, uid_t};
+
+use crate::group::CGroup;
+use crate::host::CHost;
+use crate::interop::NssStatus;
+use crate::passwd::CPasswd;
+
+pub type GetpwnamR = unsafe extern "C" fn(
+    *const c_char,
+    *mut CPasswd,
+    *mut c_char,
+    size_t,
+    *mut *mut CPasswd,
+) -> c_int;
+pub type GetpwuidR =
+    unsafe extern "C" fn(uid_t, *mut CPasswd, *mut c_char, size_t, *mut *mut CPasswd) -> c_int;
+pub type GetgrnamR = unsafe extern "C" fn(
+    *const c_char,
+    *mut CGroup,
+    *mut c_char,
+    size_t,
+    *mut *mut CGroup,
+) -> c_int;
+pub type GetgrgidR =
+    unsafe extern "C" fn(gid_t, *mut CGroup, *mut c_char, size_t, *mut *mut CGroup) -> c_int;
+pub type Getaddrinfo = unsafe extern "C" fn(
+    *const c_char,
+    *const c_char,
+    *const addrinfo,
+    *mut *mut addrinfo,
+) -> c_int;
+pub type Freeaddrinfo = unsafe extern "C" fn(*mut addrinfo);
+
+pub type NssGetpwnamR =
+    unsafe extern "C" fn(*const c_char, *mut CPasswd, *mut c_char, size_t, *mut c_int) -> c_int;
+pub type NssGetpwuidR =
+    unsafe extern "C" fn(uid_t, *mut CPasswd, *mut c_char, size_t, *mut c_int) -> c_int;
+pub type NssGetgrnamR =
+    unsafe extern "C" fn(*const c_char, *mut CGroup, *mut c_char, size_t, *mut c_int) -> c_int;
+pub type NssGetgrgidR =
+    unsafe extern "C" fn(gid_t, *mut CGroup, *mut c_char, size_t, *mut c_int) -> c_int;
+pub type NssGethostbyname2R = unsafe extern "C" fn(
+    *const c_char,
+    c_int,
+    *mut CHost,
+    *mut c_char,
+    size_t,
+    *mut c_int,
+    *mut c_int,
+) -> c_int;
+
+/// The largest buffer the functions answering from a buffer of their own grow it to
+const MAX_BUFFER: usize = 1 << 20;
+
+/// The libc function an override stands in front of, found with `dlsym(RTLD_NEXT)` on first use.
+pub struct Next {
+    /// NUL terminated
+    name: &'static [u8],
+    /// 0 before the first lookup, `usize::MAX` if there is no such function
+    address: AtomicUsize,
+}
+
+impl Next {
+    pub const fn new(name: &'static [u8]) -> Self {
+        Next {
+            name,
+            address: AtomicUsize::new(0),
+        }
+    }
+
+    /// The function, as `F`, which must be the type of a function pointer of its signature.
+    pub unsafe fn get<F: Copy>(&self) -> Option<F> {
+        assert_eq!(mem::size_of::<F>(), mem::size_of::<usize>());
+        let mut address = self.address.load(Ordering::Relaxed);
+        if address == 0 {
+            let found = libc::dlsym(libc::RTLD_NEXT, self.name.as_ptr() as *const c_char);
+            address = if found.is_null() {
+                usize::MAX
+            } else {
+                found as usize
+            };
+            self.address.store(address, Ordering::Relaxed);
+        }
+        match address {
+            usize::MAX => None,
+            address => Some(mem::transmute_copy(&address)),
+        }
+    }
+}
+
+pub unsafe fn getpwnam_r(
+    next: &Next,
+    module: NssGetpwnamR,
+    name: *const c_char,
+    pwd: *mut CPasswd,
+    buf: *mut c_char,
+    buflen: size_t,
+    result: *mut *mut CPasswd,
+) -> c_int {
+    let from_libc = next.get::<GetpwnamR>();
+    reentrant(
+        from_libc.map(|libc| move || libc(name, pwd, buf, buflen, result)),
+        |errnop| module(name, pwd, buf, buflen, errnop),
+        pwd,
+        result,
+    )
+}
+
+pub unsafe fn getpwuid_r(
+    next: &Next,
+    module: NssGetpwuidR,
+    uid: uid_t,
+    pwd: *mut CPasswd,
+    buf: *mut c_char,
+    buflen: size_t,
+    result: *mut *mut CPasswd,
+) -> c_int {
+    let from_libc = next.get::<GetpwuidR>();
+    reentrant(
+        from_libc.map(|libc| move || libc(uid, pwd, buf, buflen, result)),
+        |errnop| module(uid, pwd, buf, buflen, errnop),
+        pwd,
+        result,
+    )
+}
+
+pub unsafe fn getgrnam_r(
+    next: &Next,
+    module: NssGetgrnamR,
+    name: *const c_char,
+    grp: *mut CGroup,
+    buf: *mut c_char,
+    buflen: size_t,
+    result: *mut *mut CGroup,
+) -> c_int {
+    let from_libc = next.get::<GetgrnamR>();
+    reentrant(
+        from_libc.map(|libc| move || libc(name, grp, buf, buflen, result)),
+        |errnop| module(name, grp, buf, buflen, errnop),
+        grp,
+        result,
+    )
+}
+
+pub unsafe fn getgrgid_r(
+    next: &Next,
+    module: NssGetgrgidR,
+    gid: gid_t,
+    grp: *mut CGroup,
+    buf: *mut c_char,
+    buflen: size_t,
+    result: *mut *mut CGroup,
+) -> c_int {
+    let from_libc = next.get::<GetgrgidR>();
+    reentrant(
+        from_libc.map(|libc| move || libc(gid, grp, buf, buflen, result)),
+        |errnop| module(gid, grp, buf, buflen, errnop),
+        grp,
+        result,
+    )
+}
+
+/// Answers a `get*_r` call from libc if it has the entry, else from the module, as POSIX has them
+/// answer: 0 with `*result` pointing at `entry` or null if there is none, or an error number.
+unsafe fn reentrant<T, L, M>(
+    from_libc: Option<L>,
+    from_module: M,
+    entry: *mut T,
+    result: *mut *mut T,
+) -> c_int
+where
+    L: FnOnce() -> c_int,
+    M: FnOnce(*mut c_int) -> c_int,
+{
+    if result.is_null() {
+        return libc::EINVAL;
+    }
+    *result = ptr::null_mut();
+    if let Some(from_libc) = from_libc {
+        let err = from_libc();
+        // The caller will ask again with a bigger buffer, and libc may well find it then
+        if !(*result).is_null() || err == libc::ERANGE {
+            return err;
+        }
+        *result = ptr::null_mut();
+    }
+
+    let mut errno = 0;
+    match NssStatus::try_from(from_module(&mut errno)) {
+        Ok(NssStatus::Success) => {
+            *result = entry;
+            0
+        }
+        Ok(NssStatus::TryAgain) if errno != 0 => errno,
+        Ok(NssStatus::TryAgain) => libc::EAGAIN,
+        // Nothing found, or nothing the module could say
+        _ => 0,
+    }
+}
+
+/// An entry the non-reentrant functions return, with its strings, kept until the thread's next
+/// lookup of the same database.
+struct Slot<T> {
+    entry: T,
+    buf: Vec<c_char>,
+}
+
+thread_local! {
+    static PASSWD: RefCell<Slot<CPasswd>> = const {
+        RefCell::new(Slot {
+            // Only ever written by a lookup before it is read
+            entry: unsafe { mem::zeroed() },
+            buf: Vec::new(),
+        })
+    };
+    static GROUP: RefCell<Slot<CGroup>> = const {
+        RefCell::new(Slot {
+            entry: unsafe { mem::zeroed() },
+            buf: Vec::new(),
+        })
+    };
+}
+
+pub unsafe fn getpwnam(next: &Next, module: NssGetpwnamR, name: *const c_char) -> *mut CPasswd {
+    non_reentrant(&PASSWD, |pwd, buf, buflen, result| {
+        getpwnam_r(next, module, name, pwd, buf, buflen, result)
+    })
+}
+
+pub unsafe fn getpwuid(next: &Next, module: NssGetpwuidR, uid: uid_t) -> *mut CPasswd {
+    non_reentrant(&PASSWD, |pwd, buf, buflen, result| {
+        getpwuid_r(next, module, uid, pwd, buf, buflen, result)
+    })
+}
+
+pub unsafe fn getgrnam(next: &Next, module: NssGetgrnamR, name: *const c_char) -> *mut CGroup {
+    non_reentrant(&GROUP, |grp, buf, buflen, result| {
+        getgrnam_r(next, module, name, grp, buf, buflen, result)
+    })
+}
+
+pub unsafe fn getgrgid(next: &Next, module: NssGetgrgidR, gid: gid_t) -> *mut CGroup {
+    non_reentrant(&GROUP, |grp, buf, buflen, result| {
+        getgrgid_r(next, module, gid, grp, buf, buflen, result)
+    })
+}
+
+/// Answers a `getpwnam` style call from its `_r` form, into the thread's slot, growing the slot's
+/// buffer until the entry fits. Failures other than not finding the entry set `errno`.
+unsafe fn non_reentrant<T, F>(slot: &'static LocalKey<RefCell<Slot<T>>>, lookup: F) -> *mut T
+where
+    F: Fn(*mut T, *mut c_char, size_t, *mut *mut T) -> c_int,
+{
+    slot.with(|slot| {
+        let mut slot = slot.borrow_mut();
+        let slot = &mut *slot;
+        if slot.buf.is_empty() {
+            slot.buf.resize(1024, 0);
+        }
+        loop {
+            let mut result = ptr::null_mut();
+            match lookup(
+                &mut slot.entry,
+                slot.buf.as_mut_ptr(),
+                slot.buf.len(),
+                &mut result,
+            ) {
+                libc::ERANGE if slot.buf.len() < MAX_BUFFER => {
+                    let len = slot.buf.len() * 2;
+                    slot.buf.resize(len, 0);
+                }
+                0 => return result,
+                err => {
+                    *libc::__errno_location() = err;
+                    return ptr::null_mut();
+                }
+            }
+        }
+    })
+}
+
+/// A node of an `addrinfo` list answered from the module, with the address it points to.
+#[repr(C)]
+struct Node {
+    info: addrinfo,
+    addr: libc::sockaddr_storage,
+}
+
+/// The heads of the lists answered from the module, which `freeaddrinfo` frees itself rather than
+/// hand to libc.
+static LISTS: Mutex<Option<HashSet<usize>>> = Mutex::new(None);
+
+/// Answers `getaddrinfo` from libc, or if libc doesn't know `node`, with the addresses the
+/// module's `gethostbyname2_r` has for it. Ports, socket types and protocols are what libc gives
+/// for `service` and `hints` on each address.
+pub unsafe fn getaddrinfo(
+    next: &Next,
+    next_free: &Next,
+    module: NssGethostbyname2R,
+    node: *const c_char,
+    service: *const c_char,
+    hints: *const addrinfo,
+    res: *mut *mut addrinfo,
+) -> c_int {
+    match next.get::<Getaddrinfo>() {
+        Some(libc_getaddrinfo) => addrinfo_from(
+            libc_getaddrinfo,
+            next_free.get::<Freeaddrinfo>(),
+            module,
+            node,
+            service,
+            hints,
+            res,
+        ),
+        None => libc::EAI_SYSTEM,
+    }
+}
+
+/// [`getaddrinfo`] with libc's functions found.
+unsafe fn addrinfo_from(
+    libc_getaddrinfo: Getaddrinfo,
+    libc_freeaddrinfo: Option<Freeaddrinfo>,
+    module: NssGethostbyname2R,
+    node: *const c_char,
+    service: *const c_char,
+    hints: *const addrinfo,
+    res: *mut *mut addrinfo,
+) -> c_int {
+    let err = libc_getaddrinfo(node, service, hints, res);
+    // Without DNS, as in many containers, libc can't even say the name doesn't exist
+    if (err != libc::EAI_NONAME && err != libc::EAI_AGAIN) || node.is_null() || res.is_null() {
+        return err;
+    }
+    let mut hints = if hints.is_null() {
+        let mut hints: addrinfo = mem::zeroed();
+        hints.ai_family = libc::AF_UNSPEC;
+        hints
+    } else {
+        *hints
+    };
+    if hints.ai_flags & libc::AI_NUMERICHOST != 0 {
+        return err;
+    }
+    let families: &[c_int] = match hints.ai_family {
+        libc::AF_UNSPEC => &[libc::AF_INET, libc::AF_INET6],
+        libc::AF_INET => &[libc::AF_INET],
+        libc::AF_INET6 => &[libc::AF_INET6],
+        _ => return err,
+    };
+
+    let mut addresses = Vec::new();
+    let mut canonical = None;
+    for &family in families {
+        if let Some((name, found)) = host_by_name(module, node, family) {
+            canonical.get_or_insert(name);
+            addresses.extend(found);
+        }
+    }
+    if addresses.is_empty() {
+        return err;
+    }
+
+    let wants_canonical = hints.ai_flags & libc::AI_CANONNAME != 0;
+    // Each address as libc would answer it, for the port and socket types
+    hints.ai_flags =
+        (hints.ai_flags & (libc::AI_PASSIVE | libc::AI_NUMERICSERV)) | libc::AI_NUMERICHOST;
+    let mut nodes = Vec::new();
+    for address in addresses {
+        let numeric = CString::new(address.to_string()).unwrap();
+        hints.ai_family = match address {
+            IpAddr::V4(_) => libc::AF_INET,
+            IpAddr::V6(_) => libc::AF_INET6,
+        };
+        let mut list = ptr::null_mut();
+        let err = libc_getaddrinfo(numeric.as_ptr(), service, &hints, &mut list);
+        if err != 0 {
+            free_nodes(nodes);
+            return err;
+        }
+        let mut info = list;
+        while !info.is_null() {
+            let mut node = Box::new(Node {
+                info: *info,
+                addr: mem::zeroed(),
+            });
+            let len = ((*info).ai_addrlen as usize).min(mem::size_of::<libc::sockaddr_storage>());
+            ptr::copy_nonoverlapping(
+                (*info).ai_addr as *const u8,
+                &mut node.addr as *mut _ as *mut u8,
+                len,
+            );
+            node.info.ai_addr = &mut node.addr as *mut _ as *mut libc::sockaddr;
+            node.info.ai_canonname = ptr::null_mut();
+            node.info.ai_next = ptr::null_mut();
+            nodes.push(Box::into_raw(node));
+            info = (*info).ai_next;
+        }
+        if let Some(free) = libc_freeaddrinfo {
+            free(list);
+        }
+    }
+    if nodes.is_empty() {
+        return libc::EAI_NONAME;
+    }
+
+    for pair in nodes.windows(2) {
+        (*pair[0]).info.ai_next = pair[1] as *mut addrinfo;
+    }
+    if let (true, Some(canonical)) = (wants_canonical, canonical) {
+        (*nodes[0]).info.ai_canonname = canonical.into_raw();
+    }
+    let head = nodes[0] as *mut addrinfo;
+    LISTS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(head as usize);
+    *res = head;
+    0
+}
+
+/// Frees a list [`getaddrinfo`] answered from the module, or passes any other to libc.
+pub unsafe fn freeaddrinfo(next: &Next, list: *mut addrinfo) {
+    free_addrinfo(|| next.get::<Freeaddrinfo>(), list)
+}
+
+/// [`freeaddrinfo`], finding libc's only for a list that isn't ours.
+unsafe fn free_addrinfo<F>(libc_freeaddrinfo: F, list: *mut addrinfo)
+where
+    F: FnOnce() -> Option<Freeaddrinfo>,
+{
+    let ours = match LISTS.lock().unwrap().as_mut() {
+        Some(lists) => lists.remove(&(list as usize)),
+        None => false,
+    };
+    if !ours {
+        if let Some(free) = libc_freeaddrinfo() {
+            free(list);
+        }
+        return;
+    }
+    let mut nodes = Vec::new();
+    let mut node = list as *mut Node;
+    while !node.is_null() {
+        nodes.push(node);
+        node = (*node).info.ai_next as *mut Node;
+    }
+    free_nodes(nodes);
+}
+
+unsafe fn free_nodes(nodes: Vec<*mut Node>) {
+    for node in nodes {
+        let node = Box::from_raw(node);
+        if !node.info.ai_canonname.is_null() {
+            drop(CString::from_raw(node.info.ai_canonname));
+        }
+    }
+}
+
+/// The name and addresses the module's `gethostbyname2_r` answers for `name` in `family`.
+unsafe fn host_by_name(
+    module: NssGethostbyname2R,
+    name: *const c_char,
+    family: c_int,
+) -> Option<(CString, Vec<IpAddr>)> {
+    let mut buf = vec![0 as c_char; 1024];
+    let mut host: CHost = mem::zeroed();
+    loop {
+        let (mut errno, mut herrno) = (0, 0);
+        let status = module(
+            name,
+            family,
+            &mut host,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut errno,
+            &mut herrno,
+        );
+        match NssStatus::try_from(status) {
+            Ok(NssStatus::Success) => break,
+            Ok(NssStatus::TryAgain) if errno == libc::ERANGE && buf.len() < MAX_BUFFER => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            _ => return None,
+        }
+    }
+
+    let mut addresses = Vec::new();
+    let mut entry = host.h_addr_list;
+    while !entry.is_null() && !(*entry).is_null() {
+        let bytes = slice::from_raw_parts(*entry as *const u8, host.h_length as usize);
+        match (host.h_addrtype, bytes.len()) {
+            (libc::AF_INET, 4) => addresses.push(IpAddr::from(<[u8; 4]>::try_from(bytes).unwrap())),
+            (libc::AF_INET6, 16) => {
+                addresses.push(IpAddr::from(<[u8; 16]>::try_from(bytes).unwrap()))
+            }
+            _ => {}
+        }
+        entry = entry.add(1);
+    }
+    Some((CStr::from_ptr(host.name).to_owned(), addresses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{write_hostent, Host};
+    use crate::id::{Gid, Uid};
+    use crate::passwd::{write_passwd, Passwd};
+    use std::cell::Cell;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    /// The fake libc's `getpwnam_r`, already found as `dlsym` would find it.
+    fn libc() -> Next {
+        Next {
+            name: b"\0",
+            address: AtomicUsize::new(libc_getpwnam_r as *const () as usize),
+        }
+    }
+
+    /// No such libc function.
+    fn missing() -> Next {
+        Next {
+            name: b"\0",
+            address: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    /// Too big for the first buffer the non-reentrant functions try.
+    fn passwd(name: &str) -> Passwd {
+        Passwd {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(70000),
+            gid: Gid::from_raw(70000),
+            gecos: "g".repeat(1500),
+            dir: "/srv/app".to_string(),
+            shell: "/bin/sh".to_string(),
+        }
+    }
+
+    unsafe extern "C" fn libc_getpwnam_r(
+        name: *const c_char,
+        pwd: *mut CPasswd,
+        buf: *mut c_char,
+        buflen: size_t,
+        result: *mut *mut CPasswd,
+    ) -> c_int {
+        *result = ptr::null_mut();
+        if CStr::from_ptr(name).to_bytes() != b"libc-user" {
+            return 0;
+        }
+        let mut errno = 0;
+        match write_passwd(&passwd("libc-user"), pwd, buf, buflen, &mut errno) {
+            NssStatus::Success => {
+                *result = pwd;
+                0
+            }
+            _ => errno,
+        }
+    }
+
+    unsafe extern "C" fn module_getpwnam_r(
+        name: *const c_char,
+        pwd: *mut CPasswd,
+        buf: *mut c_char,
+        buflen: size_t,
+        errnop: *mut c_int,
+    ) -> c_int {
+        let status = match CStr::from_ptr(name).to_bytes() {
+            b"module-user" => write_passwd(&passwd("module-user"), pwd, buf, buflen, errnop),
+            b"libc-user" => panic!("asked the module for an entry libc has"),
+            b"unreachable" => NssStatus::TryAgain,
+            b"broken" => {
+                *errnop = libc::EIO;
+                NssStatus::TryAgain
+            }
+            _ => NssStatus::NotFound,
+        };
+        status.to_c()
+    }
+
+    unsafe fn getpwnam_r_of(next: &Next, name: &[u8], buflen: usize) -> (c_int, Option<String>) {
+        let mut entry: CPasswd = mem::zeroed();
+        let mut buf = vec![0 as c_char; buflen];
+        let mut result = ptr::null_mut();
+        let err = getpwnam_r(
+            next,
+            module_getpwnam_r,
+            name.as_ptr() as *const c_char,
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        let found = result.as_ref().map(|pwd| {
+            assert_eq!(pwd as *const CPasswd, &entry as *const CPasswd);
+            CStr::from_ptr(pwd.name).to_str().unwrap().to_string()
+        });
+        (err, found)
+    }
+
+    #[test]
+    fn reentrant_lookups_ask_libc_first() {
+        let libc = libc();
+        unsafe {
+            let user = |name: &str| (0, Some(name.to_string()));
+            assert_eq!(
+                getpwnam_r_of(&libc, b"libc-user\0", 4096),
+                user("libc-user")
+            );
+            assert_eq!(
+                getpwnam_r_of(&libc, b"module-user\0", 4096),
+                user("module-user")
+            );
+            assert_eq!(getpwnam_r_of(&libc, b"nobody-at-all\0", 4096), (0, None));
+            // Without a libc function, the module answers alone
+            let module = missing();
+            assert_eq!(
+                getpwnam_r_of(&module, b"module-user\0", 4096),
+                user("module-user")
+            );
+
+            // Too small a buffer is the caller's to grow, whichever of them has the entry
+            assert_eq!(
+                getpwnam_r_of(&libc, b"libc-user\0", 64),
+                (libc::ERANGE, None)
+            );
+            assert_eq!(
+                getpwnam_r_of(&libc, b"module-user\0", 64),
+                (libc::ERANGE, None)
+            );
+
+            let mut pwd: CPasswd = mem::zeroed();
+            let mut buf = [0 as c_char; 64];
+            let err = getpwnam_r(
+                &libc,
+                module_getpwnam_r,
+                b"module-user\0".as_ptr() as *const c_char,
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                ptr::null_mut(),
+            );
+            assert_eq!(err, libc::EINVAL);
+        }
+    }
+
+    #[test]
+    fn module_failures_are_error_numbers() {
+        let libc = libc();
+        unsafe {
+            assert_eq!(getpwnam_r_of(&libc, b"broken\0", 4096), (libc::EIO, None));
+            assert_eq!(
+                getpwnam_r_of(&libc, b"unreachable\0", 4096),
+                (libc::EAGAIN, None)
+            );
+        }
+    }
+
+    #[test]
+    fn non_reentrant_lookups_grow_the_buffer() {
+        let libc = libc();
+        let name = |pwd: *mut CPasswd| unsafe {
+            pwd.as_ref()
+                .map(|pwd| CStr::from_ptr(pwd.name).to_str().unwrap().to_string())
+        };
+        unsafe {
+            let lookup = |name: &[u8]| getpwnam(&libc, module_getpwnam_r, name.as_ptr() as _);
+            assert_eq!(name(lookup(b"libc-user\0")).as_deref(), Some("libc-user"));
+            assert_eq!(
+                name(lookup(b"module-user\0")).as_deref(),
+                Some("module-user")
+            );
+            let gecos = CStr::from_ptr((*lookup(b"module-user\0")).gecos);
+            assert_eq!(gecos.to_bytes().len(), 1500);
+            PASSWD.with(|slot| assert!(slot.borrow().buf.len() >= 2048));
+
+            // Not finding the entry leaves errno be
+            *libc::__errno_location() = 0;
+            assert!(lookup(b"nobody-at-all\0").is_null());
+            assert_eq!(*libc::__errno_location(), 0);
+            assert!(lookup(b"broken\0").is_null());
+            assert_eq!(*libc::__errno_location(), libc::EIO);
+        }
+    }
+
+    /// A list as the fake libc allocates it: one node, for the numeric address it was given.
+    #[repr(C)]
+    struct LibcNode {
+        info: addrinfo,
+        addr: libc::sockaddr_storage,
+    }
+
+    thread_local! {
+        /// The lists the fake libc allocated and hasn't freed
+        static LIBC_LISTS: Cell<usize> = const { Cell::new(0) };
+        /// The buffer sizes the fake module was asked to answer into
+        static MODULE_BUFLENS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    }
+
+    unsafe extern "C" fn libc_getaddrinfo(
+        node: *const c_char,
+        service: *const c_char,
+        hints: *const addrinfo,
+        res: *mut *mut addrinfo,
+    ) -> c_int {
+        let addr = CStr::from_ptr(node).to_str().ok();
+        let addr: IpAddr = match addr.and_then(|addr| addr.parse().ok()) {
+            Some(addr) => addr,
+            None if (*hints).ai_flags & libc::AI_NUMERICHOST != 0 => return libc::EAI_NONAME,
+            None => return libc::EAI_AGAIN,
+        };
+        let port: u16 = CStr::from_ptr(service).to_str().unwrap().parse().unwrap();
+        let mut node = Box::new(LibcNode {
+            info: mem::zeroed(),
+            addr: mem::zeroed(),
+        });
+        let (family, len) = match addr {
+            IpAddr::V4(addr) => {
+                let sin = &mut node.addr as *mut _ as *mut libc::sockaddr_in;
+                (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*sin).sin_port = port.to_be();
+                (*sin).sin_addr.s_addr = u32::from(addr).to_be();
+                (libc::AF_INET, mem::size_of::<libc::sockaddr_in>())
+            }
+            IpAddr::V6(addr) => {
+                let sin6 = &mut node.addr as *mut _ as *mut libc::sockaddr_in6;
+                (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*sin6).sin6_port = port.to_be();
+                (*sin6).sin6_addr.s6_addr = addr.octets();
+                (libc::AF_INET6, mem::size_of::<libc::sockaddr_in6>())
+            }
+        };
+        node.info.ai_family = family;
+        node.info.ai_socktype = libc::SOCK_STREAM;
+        node.info.ai_addrlen = len as libc::socklen_t;
+        node.info.ai_addr = &mut node.addr as *mut _ as *mut libc::sockaddr;
+        LIBC_LISTS.with(|lists| lists.set(lists.get() + 1));
+        *res = Box::into_raw(node) as *mut addrinfo;
+        0
+    }
+
+    unsafe extern "C" fn libc_freeaddrinfo(list: *mut addrinfo) {
+        LIBC_LISTS.with(|lists| lists.set(lists.get() - 1));
+        drop(Box::from_raw(list as *mut LibcNode));
+    }
+
+    /// Too big, with its aliases, for the first buffer the module is given.
+    fn host() -> Host {
+        let aliases = (0..40).map(|i| format!("alias-{:02}-{}.example", i, "a".repeat(20)));
+        Host::builder()
+            .name("app.example")
+            .aliases(aliases)
+            .address(Ipv4Addr::new(192, 0, 2, 7))
+            .address(Ipv4Addr::new(192, 0, 2, 8))
+            .build()
+            .unwrap()
+    }
+
+    unsafe extern "C" fn module_gethostbyname2_r(
+        name: *const c_char,
+        family: c_int,
+        result: *mut CHost,
+        buf: *mut c_char,
+        buflen: size_t,
+        errnop: *mut c_int,
+        herrnop: *mut c_int,
+    ) -> c_int {
+        MODULE_BUFLENS.with(|buflens| buflens.borrow_mut().push(buflen));
+        let status = match (CStr::from_ptr(name).to_bytes(), family) {
+            (b"app.example", libc::AF_INET) => {
+                write_hostent(&host(), result, buf, buflen, errnop, herrnop)
+            }
+            (b"busy.example", _) => {
+                *errnop = libc::EAGAIN;
+                NssStatus::TryAgain
+            }
+            _ => NssStatus::NotFound,
+        };
+        status.to_c()
+    }
+
+    unsafe fn addrinfo_of(name: &[u8], hints: &addrinfo) -> (c_int, *mut addrinfo) {
+        let mut res = ptr::null_mut();
+        let err = addrinfo_from(
+            libc_getaddrinfo,
+            Some(libc_freeaddrinfo),
+            module_gethostbyname2_r,
+            name.as_ptr() as *const c_char,
+            b"443\0".as_ptr() as *const c_char,
+            hints,
+            &mut res,
+        );
+        (err, res)
+    }
+
+    unsafe fn socket_addrs(mut list: *const addrinfo) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        while let Some(info) = list.as_ref() {
+            let addr = match info.ai_family {
+                libc::AF_INET => {
+                    let sin = &*(info.ai_addr as *const libc::sockaddr_in);
+                    let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                    SocketAddr::new(ip.into(), u16::from_be(sin.sin_port))
+                }
+                family => panic!("unexpected family {}", family),
+            };
+            addrs.push(addr);
+            list = info.ai_next;
+        }
+        addrs
+    }
+
+    fn hints(flags: c_int) -> addrinfo {
+        let mut hints: addrinfo = unsafe { mem::zeroed() };
+        hints.ai_family = libc::AF_UNSPEC;
+        hints.ai_flags = flags;
+        hints
+    }
+
+    #[test]
+    fn addrinfo_lists_are_answered_from_the_module() {
+        unsafe {
+            let (err, list) = addrinfo_of(b"app.example\0", &hints(libc::AI_CANONNAME));
+            assert_eq!(err, 0);
+            let addrs: Vec<String> = socket_addrs(list).iter().map(|a| a.to_string()).collect();
+            assert_eq!(addrs, ["192.0.2.7:443", "192.0.2.8:443"]);
+            assert_eq!(
+                CStr::from_ptr((*list).ai_canonname).to_bytes(),
+                b"app.example"
+            );
+            assert!((*(*list).ai_next).ai_canonname.is_null());
+
+            // libc's answer for each address was copied and handed back to it
+            assert_eq!(LIBC_LISTS.with(Cell::get), 0);
+            let buflens = MODULE_BUFLENS.with(|buflens| buflens.borrow().clone());
+            assert_eq!(buflens[..2], [1024, 2048]);
+
+            // The list is ours to free, not libc's
+            free_addrinfo(|| panic!("handed the module's list to libc"), list);
+            assert_eq!(LIBC_LISTS.with(Cell::get), 0);
+        }
+    }
+
+    #[test]
+    fn addrinfo_lists_from_libc_go_back_to_libc() {
+        unsafe {
+            let (err, list) = addrinfo_of(b"10.0.0.1\0", &hints(0));
+            assert_eq!(err, 0);
+            assert_eq!(socket_addrs(list)[0].to_string(), "10.0.0.1:443");
+            assert_eq!(LIBC_LISTS.with(Cell::get), 1);
+            assert!(MODULE_BUFLENS.with(|buflens| buflens.borrow().is_empty()));
+
+            free_addrinfo(|| Some(libc_freeaddrinfo as Freeaddrinfo), list);
+            assert_eq!(LIBC_LISTS.with(Cell::get), 0);
+        }
+    }
+
+    #[test]
+    fn unknown_names_keep_libcs_error() {
+        unsafe {
+            for name in [&b"unknown.example\0"[..], b"busy.example\0"] {
+                let (err, list) = addrinfo_of(name, &hints(0));
+                assert_eq!((err, list), (libc::EAI_AGAIN, ptr::null_mut()));
+            }
+            // Nor is the module asked for names that must be numeric
+            let (err, _) = addrinfo_of(b"app.example\0", &hints(libc::AI_NUMERICHOST));
+            assert_eq!(err, libc::EAI_NONAME);
+            let buflens = MODULE_BUFLENS.with(|buflens| buflens.borrow().clone());
+            // Both families of both names, each answered from the first buffer
+            assert_eq!(buflens, [1024; 4]);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Keeping password hashes from unprivileged callers.
+//!
+//! `/etc/shadow` is readable by root alone (and on some distributions the `shadow` group), so the
+//! `files` source finds nothing for anyone else, and sssd likewise only answers root. A module
+//! serving shadow entries from a backend has no file permissions to lean on, and every program on
+//! the machine can load it. [`PrivilegedOnly`] checks the [caller](CallContext) instead: lookups
+//! and enumerations by anyone its [`Privileged`] policy doesn't allow find nothing, without asking
+//! the backend. As with `files`, glibc then moves on to the next source.
+//!
+//! ```
+//! # use libnss::shadow::{Shadow, ShadowHooks};
+//! # struct ExampleShadow;
+//! # impl ShadowHooks for ExampleShadow {
+//! #     fn get_all_entries() -> Vec<Shadow> { vec![] }
+//! #     fn get_entry_by_name(_: String) -> Option<Shadow> { None }
+//! # }
+//! use libnss::database::ShadowDatabase;
+//! use libnss::privileged::{Privileged, PrivilegedOnly};
+//!
+//! /// Root, and members of gid 42, Debian's `shadow` group.
+//! struct ShadowGroup;
+//!
+//! impl Privileged for ShadowGroup {
+//!     const GIDS: &'static [u32] = &[42];
+//! }
+//!
+//! // Usable anywhere shadow hooks are, e.g. `libnss_shadow_hooks!(example, ExampleHooks)`
+//! type ExampleHooks = PrivilegedOnly<ShadowDatabase<ExampleShadow>, ShadowGroup>;
+//! ```
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::context::CallContext;
+use crate::database::{Answer, NssDatabase};
+use crate::host::NameForm;
+
+/// Who may see a database's entries.
+pub trait Privileged {
+    /// Groups whose members may see them as well as root. Gids rather than names, as looking a
+    /// name up would go back through NSS.
+    const GIDS: &'static [libc::gid_t] = &[];
+
+    fn allows(caller: &CallContext) -> bool {
+        caller.euid() == 0
+            || (!Self::GIDS.is_empty()
+                && caller
+                    .groups()
+                    .iter()
+                    .any(|gid| Self::GIDS.contains(&gid.as_raw())))
+    }
+}
+
+/// Callers running as root.
+pub struct RootOnly;
+
+impl Privileged for RootOnly {}
+
+/// A database only `P` may see the entries of.
+pub struct PrivilegedOnly<D, P = RootOnly>(PhantomData<(D, P)>);
+
+impl<D, P> NssDatabase for PrivilegedOnly<D, P>
+where
+    D: NssDatabase,
+    P: Privileged,
+{
+    type Entry = D::Entry;
+    type Key = D::Key;
+
+    const ENUMERATION_CACHE_TTL: Duration = D::ENUMERATION_CACHE_TTL;
+    const TRUNCATE_MEMBERS_AT: Option<usize> = D::TRUNCATE_MEMBERS_AT;
+    const RESOLVES_UNSPECIFIED: bool = D::RESOLVES_UNSPECIFIED;
+    const NAME_FORM: NameForm = D::NAME_FORM;
+
+    fn all_entries() -> Vec<D::Entry> {
+        if !P::allows(&CallContext::current()) {
+            return Vec::new();
+        }
+        D::all_entries()
+    }
+
+    fn lookup(key: D::Key) -> Answer<D::Entry> {
+        if !P::allows(&CallContext::current()) {
+            return Answer::NotFound;
+        }
+        D::lookup(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::{Gid, Uid};
+    use std::cell::Cell;
+
+    /// Root, and members of gid 42.
+    struct ShadowGroup;
+
+    impl Privileged for ShadowGroup {
+        const GIDS: &'static [libc::gid_t] = &[42];
+    }
+
+    thread_local! {
+        static BACKEND_CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Password hashes by name, counting how often it is asked for them.
+    struct Hashes;
+
+    impl NssDatabase for Hashes {
+        type Entry = String;
+        type Key = String;
+
+        fn all_entries() -> Vec<String> {
+            BACKEND_CALLS.with(|calls| calls.set(calls.get() + 1));
+            vec!["alice:$6$hash".to_string()]
+        }
+
+        fn lookup(name: String) -> Answer<String> {
+            BACKEND_CALLS.with(|calls| calls.set(calls.get() + 1));
+            match name.as_str() {
+                "alice" => Answer::Found("alice:$6$hash".to_string()),
+                _ => Answer::NotFound,
+            }
+        }
+    }
+
+    fn root() -> CallContext {
+        CallContext::new(Uid::from_raw(0), None).with_groups(vec![Gid::from_raw(0)])
+    }
+
+    /// In the shadow group through a supplementary group, not the effective gid.
+    fn member() -> CallContext {
+        let groups = vec![Gid::from_raw(1000), Gid::from_raw(100), Gid::from_raw(42)];
+        CallContext::new(Uid::from_raw(1000), None).with_groups(groups)
+    }
+
+    fn non_member() -> CallContext {
+        let groups = vec![Gid::from_raw(1001), Gid::from_raw(100)];
+        CallContext::new(Uid::from_raw(1001), None).with_groups(groups)
+    }
+
+    /// What `D` answers `caller`, and whether it asked the backend.
+    fn answers<D: NssDatabase<Key = String>>(
+        caller: CallContext,
+    ) -> (Answer<D::Entry>, Vec<D::Entry>, bool) {
+        BACKEND_CALLS.with(|calls| calls.set(0));
+        let (found, all) = caller.scope(|| (D::lookup("alice".to_string()), D::all_entries()));
+        (found, all, BACKEND_CALLS.with(Cell::get) > 0)
+    }
+
+    #[test]
+    fn root_only_hides_entries_from_everyone_else() {
+        type Hidden = PrivilegedOnly<Hashes>;
+        let hash = || "alice:$6$hash".to_string();
+
+        let answer = answers::<Hidden>(root());
+        assert_eq!(answer, (Answer::Found(hash()), vec![hash()], true));
+        // Membership of a group only counts for the groups a policy lists
+        for caller in [member(), non_member()] {
+            assert_eq!(answers::<Hidden>(caller), (Answer::NotFound, vec![], false));
+        }
+    }
+
+    #[test]
+    fn allowed_groups_admit_their_members() {
+        type Hidden = PrivilegedOnly<Hashes, ShadowGroup>;
+        let hash = || "alice:$6$hash".to_string();
+
+        for caller in [root(), member()] {
+            let (found, all, _) = answers::<Hidden>(caller);
+            assert_eq!((found, all), (Answer::Found(hash()), vec![hash()]));
+        }
+        assert_eq!(
+            answers::<Hidden>(non_member()),
+            (Answer::NotFound, vec![], false)
+        );
+
+        assert!(ShadowGroup::allows(&member()));
+        assert!(!ShadowGroup::allows(&non_member()));
+        assert!(!RootOnly::allows(&member()));
+        // Root needs no group
+        assert!(ShadowGroup::allows(
+            &CallContext::new(Uid::from_raw(0), None).with_groups(vec![])
+        ));
+    }
+}
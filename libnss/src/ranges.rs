@@ -0,0 +1,342 @@
+//! Keeping a module to the uids and gids it owns.
+//!
+//! A module serving a directory's users shouldn't answer for the ids the machine's own accounts
+//! use: a directory entry with uid 0, or one colliding with a package's system user, would give
+//! whoever controls the directory those accounts' files. A module declares the ranges it owns in
+//! an [`IdRanges`] impl and wraps its databases in [`InRange`]. Lookups of an id outside those
+//! ranges then find nothing without asking the backend, and entries the backend returns outside
+//! them are dropped and logged to syslog at `LOG_ERR`.
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # fn entry(name: &str, uid: u32) -> Passwd {
+//! #     Passwd {
+//! #         name: name.to_string(),
+//! #         name_bytes: None,
+//! #         passwd: "x".to_string(),
+//! #         uid: Uid::from_raw(uid),
+//! #         gid: Gid::from_raw(100),
+//! #         gecos: String::new(),
+//! #         dir: format!("/home/{}", name),
+//! #         shell: "/bin/sh".to_string(),
+//! #     }
+//! # }
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![entry("alice", 70001), entry("toor", 0)] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(name: String) -> Option<Passwd> {
+//! #         Self::get_all_entries().into_iter().find(|entry| entry.name == name)
+//! #     }
+//! # }
+//! use std::ops::RangeInclusive;
+//! use libnss::database::PasswdDatabase;
+//! use libnss::ranges::{IdRanges, InRange};
+//!
+//! struct Directory;
+//!
+//! impl IdRanges for Directory {
+//!     const UIDS: &'static [RangeInclusive<u32>] = &[70000..=79999];
+//!     const GIDS: &'static [RangeInclusive<u32>] = &[70000..=79999];
+//! }
+//!
+//! // Usable anywhere passwd hooks are, e.g. `libnss_passwd_hooks!(example, ExampleHooks)`
+//! type ExampleHooks = InRange<PasswdDatabase<ExamplePasswd>, Directory>;
+//!
+//! assert!(ExampleHooks::get_entry_by_name("alice".to_string()).is_some());
+//! assert!(ExampleHooks::get_entry_by_name("toor".to_string()).is_none());
+//! assert_eq!(ExampleHooks::get_all_entries().len(), 1);
+//! ```
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use crate::audit::{self, AuditedEntry};
+use crate::database::{Answer, GroupKey, NssDatabase, PasswdKey};
+use crate::group::Group;
+use crate::host::NameForm;
+use crate::id::{Gid, Uid};
+use crate::passwd::Passwd;
+
+/// The ids a module owns.
+pub trait IdRanges {
+    const UIDS: &'static [RangeInclusive<libc::uid_t>];
+    const GIDS: &'static [RangeInclusive<libc::gid_t>];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Id {
+    Uid(Uid),
+    Gid(Gid),
+}
+
+impl Id {
+    pub fn is_owned_by<R: IdRanges>(self) -> bool {
+        let (ranges, raw) = match self {
+            Id::Uid(uid) => (R::UIDS, uid.as_raw()),
+            Id::Gid(gid) => (R::GIDS, gid.as_raw()),
+        };
+        ranges.iter().any(|range| range.contains(&raw))
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Id::Uid(uid) => write!(f, "uid {}", uid),
+            Id::Gid(gid) => write!(f, "gid {}", gid),
+        }
+    }
+}
+
+/// An entry whose id must be in range.
+pub trait RangedEntry: AuditedEntry {
+    fn name(&self) -> &str;
+
+    /// The id identifying the entry; a passwd entry's primary gid isn't checked, as a directory's
+    /// users often belong to a local group such as `users`
+    fn id(&self) -> Id;
+}
+
+impl RangedEntry for Passwd {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Id {
+        Id::Uid(self.uid)
+    }
+}
+
+impl RangedEntry for Group {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Id {
+        Id::Gid(self.gid)
+    }
+}
+
+/// A key that may name an id.
+pub trait RangedKey {
+    fn id(&self) -> Option<Id>;
+}
+
+impl RangedKey for PasswdKey {
+    fn id(&self) -> Option<Id> {
+        match self {
+            PasswdKey::Uid(uid) => Some(Id::Uid(*uid)),
+            PasswdKey::Name(_) | PasswdKey::NameBytes(_) => None,
+        }
+    }
+}
+
+impl RangedKey for GroupKey {
+    fn id(&self) -> Option<Id> {
+        match self {
+            GroupKey::Gid(gid) => Some(Id::Gid(*gid)),
+            GroupKey::Name(_) | GroupKey::NameBytes(_) => None,
+        }
+    }
+}
+
+/// A database answering only for the ids `R` owns.
+pub struct InRange<D, R>(PhantomData<(D, R)>);
+
+impl<D, R> InRange<D, R>
+where
+    D: NssDatabase,
+    D::Entry: RangedEntry,
+    R: IdRanges,
+{
+    fn owned(entry: &D::Entry) -> bool {
+        let id = entry.id();
+        if id.is_owned_by::<R>() {
+            return true;
+        }
+        audit::syslog(
+            libc::LOG_ERR,
+            &format!(
+                "libnss: ignoring {} entry `{}` with {}, outside the ids this module owns",
+                D::Entry::DATABASE,
+                entry.name().escape_debug(),
+                id
+            ),
+        );
+        false
+    }
+}
+
+impl<D, R> NssDatabase for InRange<D, R>
+where
+    D: NssDatabase,
+    D::Entry: RangedEntry,
+    D::Key: RangedKey,
+    R: IdRanges,
+{
+    type Entry = D::Entry;
+    type Key = D::Key;
+
+    const ENUMERATION_CACHE_TTL: Duration = D::ENUMERATION_CACHE_TTL;
+    const TRUNCATE_MEMBERS_AT: Option<usize> = D::TRUNCATE_MEMBERS_AT;
+    const RESOLVES_UNSPECIFIED: bool = D::RESOLVES_UNSPECIFIED;
+    const NAME_FORM: NameForm = D::NAME_FORM;
+
+    fn all_entries() -> Vec<D::Entry> {
+        D::all_entries().into_iter().filter(Self::owned).collect()
+    }
+
+    fn lookup(key: D::Key) -> Answer<D::Entry> {
+        match key.id() {
+            Some(id) if !id.is_owned_by::<R>() => Answer::NotFound,
+            _ => D::lookup(key).filter(Self::owned),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Directory;
+
+    impl IdRanges for Directory {
+        const UIDS: &'static [RangeInclusive<libc::uid_t>] = &[70000..=79999, 90000..=90000];
+        const GIDS: &'static [RangeInclusive<libc::gid_t>] = &[70000..=79999];
+    }
+
+    fn user(name: &str, uid: u32) -> Passwd {
+        Passwd {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(100),
+            gecos: String::new(),
+            dir: format!("/home/{}", name),
+            shell: "/bin/sh".to_string(),
+        }
+    }
+
+    fn group(name: &str, gid: u32) -> Group {
+        Group {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            gid: Gid::from_raw(gid),
+            members: vec![],
+        }
+    }
+
+    /// A directory that also has entries it shouldn't, counting the lookups of an id made of it.
+    struct Users;
+
+    static USER_ID_LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
+    impl NssDatabase for Users {
+        type Entry = Passwd;
+        type Key = PasswdKey;
+
+        fn all_entries() -> Vec<Passwd> {
+            // A primary gid outside the ranges is fine
+            vec![user("alice", 70001), user("toor", 0), user("bob", 90000)]
+        }
+
+        fn lookup(key: PasswdKey) -> Answer<Passwd> {
+            if key.id().is_some() {
+                USER_ID_LOOKUPS.fetch_add(1, Ordering::SeqCst);
+            }
+            let found = Self::all_entries().into_iter().find(|entry| match &key {
+                PasswdKey::Uid(uid) => entry.uid == *uid,
+                PasswdKey::Name(name) => entry.name == *name,
+                PasswdKey::NameBytes(name) => entry.name.as_bytes() == name.to_bytes(),
+            });
+            found.into()
+        }
+    }
+
+    struct Groups;
+
+    static GROUP_ID_LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
+    impl NssDatabase for Groups {
+        type Entry = Group;
+        type Key = GroupKey;
+
+        fn all_entries() -> Vec<Group> {
+            vec![group("staff", 70000), group("wheel", 10)]
+        }
+
+        fn lookup(key: GroupKey) -> Answer<Group> {
+            if key.id().is_some() {
+                GROUP_ID_LOOKUPS.fetch_add(1, Ordering::SeqCst);
+            }
+            let found = Self::all_entries().into_iter().find(|entry| match &key {
+                GroupKey::Gid(gid) => entry.gid == *gid,
+                GroupKey::Name(name) => entry.name == *name,
+                GroupKey::NameBytes(name) => entry.name.as_bytes() == name.to_bytes(),
+            });
+            found.into()
+        }
+    }
+
+    #[test]
+    fn ids_outside_the_ranges_are_not_looked_up() {
+        type Ranged = InRange<Users, Directory>;
+        let uid = |uid| PasswdKey::Uid(Uid::from_raw(uid));
+
+        assert_eq!(Ranged::lookup(uid(0)), Answer::NotFound);
+        assert_eq!(Ranged::lookup(uid(69999)), Answer::NotFound);
+        assert_eq!(Ranged::lookup(uid(80000)), Answer::NotFound);
+        assert_eq!(USER_ID_LOOKUPS.load(Ordering::SeqCst), 0);
+
+        assert_eq!(
+            Ranged::lookup(uid(70001)),
+            Answer::Found(user("alice", 70001))
+        );
+        assert_eq!(
+            Ranged::lookup(uid(90000)),
+            Answer::Found(user("bob", 90000))
+        );
+        assert_eq!(Ranged::lookup(uid(79999)), Answer::NotFound);
+        assert_eq!(USER_ID_LOOKUPS.load(Ordering::SeqCst), 3);
+
+        type RangedGroups = InRange<Groups, Directory>;
+        let gid = |gid| GroupKey::Gid(Gid::from_raw(gid));
+        assert_eq!(RangedGroups::lookup(gid(10)), Answer::NotFound);
+        assert_eq!(GROUP_ID_LOOKUPS.load(Ordering::SeqCst), 0);
+        assert_eq!(
+            RangedGroups::lookup(gid(70000)),
+            Answer::Found(group("staff", 70000))
+        );
+        assert_eq!(GROUP_ID_LOOKUPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn entries_outside_the_ranges_are_dropped() {
+        type Ranged = InRange<Users, Directory>;
+        let name = |name: &str| PasswdKey::Name(name.to_string());
+
+        assert_eq!(Ranged::lookup(name("toor")), Answer::NotFound);
+        assert_eq!(
+            Ranged::lookup(name("alice")),
+            Answer::Found(user("alice", 70001))
+        );
+        assert_eq!(
+            Ranged::all_entries(),
+            vec![user("alice", 70001), user("bob", 90000)]
+        );
+
+        type RangedGroups = InRange<Groups, Directory>;
+        assert_eq!(
+            RangedGroups::lookup(GroupKey::Name("wheel".to_string())),
+            Answer::NotFound
+        );
+        assert_eq!(RangedGroups::all_entries(), vec![group("staff", 70000)]);
+    }
+}
@@ -0,0 +1,330 @@
+//! Lookups answered by hooks in-process, without going through glibc.
+//!
+//! A Rust program embedding a module's crate, such as a daemon serving the same backend, can look
+//! entries up with a [`Resolver`] rather than `getpwnam(3)` and friends, which would load the
+//! module a second time or ask another source entirely. It answers as the generated `_nss_*`
+//! functions do: entries that fail validation are not found, host names are normalized to the
+//! hooks' `NAME_FORM`, hosts that can't be a `hostent` are unavailable, and so is any database
+//! without hooks registered. [`DaemonServer`](crate::daemon::server::DaemonServer) answers the
+//! shim through one.
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+//! #         Some(Passwd {
+//! #             name: "app".to_string(),
+//! #             name_bytes: None,
+//! #             passwd: "x".to_string(),
+//! #             uid,
+//! #             gid: Gid::from_raw(1000),
+//! #             gecos: String::new(),
+//! #             dir: "/srv/app".to_string(),
+//! #             shell: "/bin/sh".to_string(),
+//! #         })
+//! #     }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! use std::time::Duration;
+//! use libnss::interop::NssStatus;
+//! use libnss::resolver::Resolver;
+//!
+//! let resolver = Resolver::new()
+//!     .with_passwd::<ExamplePasswd>()
+//!     .with_cache_ttl(Duration::from_secs(60));
+//!
+//! assert_eq!(resolver.lookup_passwd_by_uid(Uid::from_raw(1000)).unwrap().name, "app");
+//! assert_eq!(resolver.lookup_passwd_by_name("nobody"), Err(NssStatus::NotFound));
+//! assert_eq!(resolver.lookup_group_by_name("app"), Err(NssStatus::Unavail));
+//! ```
+
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::database::{GroupKey, HostKey, PasswdKey, ShadowKey};
+use crate::group::{Group, GroupHooks};
+use crate::host::{self, AddressFamily, Host, HostAnswer, HostHooks};
+use crate::id::{Gid, Uid};
+use crate::interop::NssStatus;
+use crate::passwd::{Passwd, PasswdHooks};
+use crate::shadow::{Shadow, ShadowHooks};
+use crate::stale::StaleCache;
+use crate::validate::Validate;
+
+struct PasswdLookups {
+    all: fn() -> Vec<Passwd>,
+    by_uid: fn(Uid) -> Option<Passwd>,
+    by_name: fn(String) -> Option<Passwd>,
+}
+
+struct GroupLookups {
+    all: fn() -> Vec<Group>,
+    by_gid: fn(Gid) -> Option<Group>,
+    by_name: fn(String) -> Option<Group>,
+}
+
+struct ShadowLookups {
+    all: fn() -> Vec<Shadow>,
+    by_name: fn(String) -> Option<Shadow>,
+}
+
+struct HostLookups {
+    all: fn() -> Vec<Host>,
+    by_name: fn(&str, AddressFamily) -> HostAnswer,
+    by_addr: fn(IpAddr) -> Option<Host>,
+}
+
+struct Caches {
+    passwd: StaleCache<PasswdKey, Passwd>,
+    group: StaleCache<GroupKey, Group>,
+    shadow: StaleCache<ShadowKey, Shadow>,
+    hosts: StaleCache<HostKey, HostAnswer>,
+}
+
+/// Answers lookups from the hooks registered for each database, with the `NssStatus` the
+/// matching `_nss_*` function would return: `NotFound` for no entry and `Unavail` for a database
+/// without hooks. Shadow entries are answered to any caller, as by the module itself.
+#[derive(Default)]
+pub struct Resolver {
+    passwd: Option<PasswdLookups>,
+    group: Option<GroupLookups>,
+    shadow: Option<ShadowLookups>,
+    host: Option<HostLookups>,
+    caches: Option<Caches>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_passwd<P: PasswdHooks>(mut self) -> Self {
+        self.passwd = Some(PasswdLookups {
+            all: P::get_all_entries,
+            by_uid: P::get_entry_by_uid,
+            by_name: P::get_entry_by_name,
+        });
+        self
+    }
+
+    pub fn with_group<G: GroupHooks>(mut self) -> Self {
+        self.group = Some(GroupLookups {
+            all: G::get_all_entries,
+            by_gid: G::get_entry_by_gid,
+            by_name: G::get_entry_by_name,
+        });
+        self
+    }
+
+    pub fn with_shadow<S: ShadowHooks>(mut self) -> Self {
+        self.shadow = Some(ShadowLookups {
+            all: S::get_all_entries,
+            by_name: S::get_entry_by_name,
+        });
+        self
+    }
+
+    pub fn with_host<H: HostHooks>(mut self) -> Self {
+        self.host = Some(HostLookups {
+            all: H::get_all_entries,
+            by_name: host::resolve_name::<H>,
+            by_addr: H::get_host_by_addr,
+        });
+        self
+    }
+
+    /// Answers lookups of an entry found within `ttl` without asking the hooks again. Entries not
+    /// found and enumerations always go to the hooks.
+    ///
+    /// ```
+    /// # use libnss::id::{Gid, Uid};
+    /// # use libnss::group::{Group, GroupHooks};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::time::Duration;
+    /// use libnss::resolver::Resolver;
+    ///
+    /// static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// struct CountedGroup;
+    ///
+    /// impl GroupHooks for CountedGroup {
+    ///     fn get_entry_by_gid(gid: Gid) -> Option<Group> {
+    ///         LOOKUPS.fetch_add(1, Ordering::SeqCst);
+    ///         Some(Group { name: "staff".to_string(), name_bytes: None, passwd: "x".to_string(), gid, members: vec![] })
+    ///     }
+    ///     # fn get_all_entries() -> Vec<Group> { vec![] }
+    ///     # fn get_entry_by_name(_: String) -> Option<Group> { None }
+    /// }
+    ///
+    /// let resolver = Resolver::new()
+    ///     .with_group::<CountedGroup>()
+    ///     .with_cache_ttl(Duration::from_secs(60));
+    /// for _ in 0..3 {
+    ///     assert!(resolver.lookup_group_by_gid(Gid::from_raw(50)).is_ok());
+    /// }
+    /// assert_eq!(LOOKUPS.load(Ordering::SeqCst), 1);
+    /// ```
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.caches = Some(Caches {
+            passwd: StaleCache::new(ttl).with_ttl(ttl),
+            group: StaleCache::new(ttl).with_ttl(ttl),
+            shadow: StaleCache::new(ttl).with_ttl(ttl),
+            hosts: StaleCache::new(ttl).with_ttl(ttl),
+        });
+        self
+    }
+
+    /// The entries `setpwent` enumerates, as `getpwent_r` would return them.
+    pub fn all_passwd_entries(&self) -> Result<Vec<Passwd>, NssStatus> {
+        let lookups = self.passwd.as_ref().ok_or(NssStatus::Unavail)?;
+        Ok((lookups.all)()
+            .into_iter()
+            .filter(Validate::is_valid)
+            .collect())
+    }
+
+    pub fn lookup_passwd_by_uid(&self, uid: Uid) -> Result<Passwd, NssStatus> {
+        let lookups = self.passwd.as_ref().ok_or(NssStatus::Unavail)?;
+        let cache = self.caches.as_ref().map(|caches| &caches.passwd);
+        cached(cache, PasswdKey::Uid(uid), || {
+            (lookups.by_uid)(uid).filter(Validate::is_valid)
+        })
+        .ok_or(NssStatus::NotFound)
+    }
+
+    pub fn lookup_passwd_by_name(&self, name: &str) -> Result<Passwd, NssStatus> {
+        let lookups = self.passwd.as_ref().ok_or(NssStatus::Unavail)?;
+        let cache = self.caches.as_ref().map(|caches| &caches.passwd);
+        cached(cache, PasswdKey::Name(name.to_string()), || {
+            (lookups.by_name)(name.to_string()).filter(Validate::is_valid)
+        })
+        .ok_or(NssStatus::NotFound)
+    }
+
+    /// The entries `setgrent` enumerates, as `getgrent_r` would return them.
+    pub fn all_group_entries(&self) -> Result<Vec<Group>, NssStatus> {
+        let lookups = self.group.as_ref().ok_or(NssStatus::Unavail)?;
+        Ok((lookups.all)()
+            .into_iter()
+            .filter(Validate::is_valid)
+            .collect())
+    }
+
+    pub fn lookup_group_by_gid(&self, gid: Gid) -> Result<Group, NssStatus> {
+        let lookups = self.group.as_ref().ok_or(NssStatus::Unavail)?;
+        let cache = self.caches.as_ref().map(|caches| &caches.group);
+        cached(cache, GroupKey::Gid(gid), || {
+            (lookups.by_gid)(gid).filter(Validate::is_valid)
+        })
+        .ok_or(NssStatus::NotFound)
+    }
+
+    pub fn lookup_group_by_name(&self, name: &str) -> Result<Group, NssStatus> {
+        let lookups = self.group.as_ref().ok_or(NssStatus::Unavail)?;
+        let cache = self.caches.as_ref().map(|caches| &caches.group);
+        cached(cache, GroupKey::Name(name.to_string()), || {
+            (lookups.by_name)(name.to_string()).filter(Validate::is_valid)
+        })
+        .ok_or(NssStatus::NotFound)
+    }
+
+    /// The entries `setspent` enumerates, as `getspent_r` would return them.
+    pub fn all_shadow_entries(&self) -> Result<Vec<Shadow>, NssStatus> {
+        let lookups = self.shadow.as_ref().ok_or(NssStatus::Unavail)?;
+        Ok((lookups.all)()
+            .into_iter()
+            .filter(Validate::is_valid)
+            .collect())
+    }
+
+    pub fn lookup_shadow_by_name(&self, name: &str) -> Result<Shadow, NssStatus> {
+        let lookups = self.shadow.as_ref().ok_or(NssStatus::Unavail)?;
+        let cache = self.caches.as_ref().map(|caches| &caches.shadow);
+        cached(cache, ShadowKey::Name(name.to_string()), || {
+            (lookups.by_name)(name.to_string()).filter(Validate::is_valid)
+        })
+        .ok_or(NssStatus::NotFound)
+    }
+
+    /// The hosts `sethostent` enumerates, as `gethostent_r` would return them.
+    pub fn all_host_entries(&self) -> Result<Vec<Host>, NssStatus> {
+        let lookups = self.host.as_ref().ok_or(NssStatus::Unavail)?;
+        Ok((lookups.all)()
+            .into_iter()
+            .filter(|host| host.is_valid() && host.validate_hostent().is_ok())
+            .collect())
+    }
+
+    pub fn lookup_host_by_addr(&self, addr: IpAddr) -> Result<Host, NssStatus> {
+        let lookups = self.host.as_ref().ok_or(NssStatus::Unavail)?;
+        let cache = self.caches.as_ref().map(|caches| &caches.hosts);
+        let answer = cached(cache, HostKey::Addr(addr), || {
+            (lookups.by_addr)(addr)
+                .filter(Validate::is_valid)
+                .map(HostAnswer::Found)
+        });
+        match answer {
+            Some(answer) => hostent(answer)?.into_host().ok_or(NssStatus::NotFound),
+            None => Err(NssStatus::NotFound),
+        }
+    }
+
+    pub fn lookup_host_by_name(
+        &self,
+        name: &str,
+        family: AddressFamily,
+    ) -> Result<Host, NssStatus> {
+        self.resolve_host_by_name(name, family)?
+            .into_host()
+            .ok_or(NssStatus::NotFound)
+    }
+
+    /// [`lookup_host_by_name`](Self::lookup_host_by_name), telling a name without addresses of
+    /// `family` from an unknown one as `gethostbyname2_r` does through `h_errno`.
+    pub fn resolve_host_by_name(
+        &self,
+        name: &str,
+        family: AddressFamily,
+    ) -> Result<HostAnswer, NssStatus> {
+        let lookups = self.host.as_ref().ok_or(NssStatus::Unavail)?;
+        let cache = self.caches.as_ref().map(|caches| &caches.hosts);
+        let answer = cached(
+            cache,
+            HostKey::Name(name.to_string(), family),
+            || match (lookups.by_name)(name, family) {
+                HostAnswer::NotFound => None,
+                answer => Some(answer),
+            },
+        );
+        hostent(answer.unwrap_or(HostAnswer::NotFound))
+    }
+}
+
+/// `answer`, unless it is a host that can't be laid out as a `hostent`, which `write_hostent`
+/// answers as unavailable.
+fn hostent(answer: HostAnswer) -> Result<HostAnswer, NssStatus> {
+    match answer {
+        HostAnswer::Found(host) if host.validate_hostent().is_err() => Err(NssStatus::Unavail),
+        answer => Ok(answer),
+    }
+}
+
+/// What `lookup` answers, or the entry `cache` holds for `key` if it was read recently enough.
+fn cached<K, V, F>(cache: Option<&StaleCache<K, V>>, key: K, lookup: F) -> Option<V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone + PartialEq,
+    F: FnOnce() -> Option<V>,
+{
+    match cache {
+        Some(cache) => cache
+            .lookup(key, || Ok::<_, Infallible>(lookup()))
+            .unwrap_or_else(|never| match never {}),
+        None => lookup(),
+    }
+}
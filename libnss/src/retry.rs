@@ -0,0 +1,272 @@
+//! Bounded retries for backends whose lookups cross the network.
+//!
+//! A lookup is on some program's critical path, so a transient failure (a reset connection, a
+//! timeout) is worth a couple of quick retries, but a program must never hang on a dead backend
+//! and there is no point retrying a request the backend rejected. [`RetryPolicy::run`] retries
+//! only failures the error's [`Retryable`] impl says are transient, sleeping a jittered,
+//! exponentially growing backoff between attempts, and reports what to tell NSS once it gives up:
+//!
+//! ```
+//! use std::io;
+//! use libnss::retry::RetryPolicy;
+//!
+//! let policy = RetryPolicy::default();
+//! let result = policy.run(|| -> io::Result<u32> {
+//!     Err(io::Error::new(io::ErrorKind::TimedOut, "backend timed out"))
+//! });
+//! let status = result.unwrap_err().status(); // NssStatus::TryAgain
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::interop::NssStatus;
+
+/// Whether a failure is transient, so that the same request may succeed if made again.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+/// Connection and timeout failures are retryable; anything else (a refused permission, a
+/// malformed reply) would fail the same way again.
+impl Retryable for io::Error {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+        )
+    }
+}
+
+pub struct RetryPolicy {
+    /// How many times to try in all, including the first
+    pub attempts: u32,
+    /// The backoff before the first retry, doubled for each one after
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RetryError<E> {
+    /// Every attempt failed with a retryable error; this is the last one
+    Exhausted(E),
+    /// An attempt failed with an error not worth retrying
+    Permanent(E),
+}
+
+impl<E> RetryError<E> {
+    /// The status to return to NSS: `TryAgain` once retries are exhausted, so that callers
+    /// (and nscd) know the answer may change, and `Unavail` for a permanent failure.
+    pub fn status(&self) -> NssStatus {
+        match self {
+            RetryError::Exhausted(_) => NssStatus::TryAgain,
+            RetryError::Permanent(_) => NssStatus::Unavail,
+        }
+    }
+
+    pub fn into_inner(self) -> E {
+        match self {
+            RetryError::Exhausted(err) | RetryError::Permanent(err) => err,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetryError::Exhausted(err) => write!(f, "gave up after retrying: {}", err),
+            RetryError::Permanent(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for RetryError<E> {}
+
+impl RetryPolicy {
+    /// Calls `attempt` until it succeeds, fails permanently or runs out of attempts.
+    pub fn run<T, E, F>(&self, attempt: F) -> Result<T, RetryError<E>>
+    where
+        E: Retryable,
+        F: FnMut() -> Result<T, E>,
+    {
+        self.run_with(attempt, E::is_retryable)
+    }
+
+    /// As [`run`](RetryPolicy::run), for errors classified by `is_retryable` rather than a
+    /// [`Retryable`] impl.
+    pub fn run_with<T, E, F, C>(&self, mut attempt: F, is_retryable: C) -> Result<T, RetryError<E>>
+    where
+        F: FnMut() -> Result<T, E>,
+        C: Fn(&E) -> bool,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut tries = 1;
+        loop {
+            let err = match attempt() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if !is_retryable(&err) {
+                return Err(RetryError::Permanent(err));
+            }
+            if tries >= self.attempts {
+                return Err(RetryError::Exhausted(err));
+            }
+
+            thread::sleep(jitter(backoff));
+            backoff = (backoff * 2).min(self.max_backoff);
+            tries += 1;
+        }
+    }
+}
+
+/// Somewhere between half of `backoff` and all of it, so that the threads of a busy program that
+/// all failed at once don't all retry at once too.
+fn jitter(backoff: Duration) -> Duration {
+    // A freshly keyed hasher is a random number without a dependency for one
+    let random = RandomState::new().build_hasher().finish();
+    let half = backoff / 2;
+    half + half.mul_f64((random >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        }
+    }
+
+    fn timed_out() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "timed out")
+    }
+
+    #[test]
+    fn attempts_are_counted() {
+        let mut tries = 0;
+        let result = policy(3).run(|| -> io::Result<()> {
+            tries += 1;
+            Err(timed_out())
+        });
+        assert_eq!(tries, 3);
+        assert!(matches!(result, Err(RetryError::Exhausted(_))));
+        assert_eq!(result.unwrap_err().status(), NssStatus::TryAgain);
+
+        let mut tries = 0;
+        let result = policy(3).run(|| {
+            tries += 1;
+            if tries < 2 {
+                Err(timed_out())
+            } else {
+                Ok(tries)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        // However few attempts are asked for, one is made
+        let mut tries = 0;
+        let result = policy(0).run(|| -> io::Result<()> {
+            tries += 1;
+            Err(timed_out())
+        });
+        assert_eq!(tries, 1);
+        assert!(matches!(result, Err(RetryError::Exhausted(_))));
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        let mut tries = 0;
+        let result = policy(5).run(|| -> io::Result<()> {
+            tries += 1;
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"))
+        });
+        assert_eq!(tries, 1);
+        let err = result.unwrap_err();
+        assert_eq!(err.status(), NssStatus::Unavail);
+        assert_eq!(err.to_string(), "denied");
+        assert_eq!(err.into_inner().kind(), io::ErrorKind::PermissionDenied);
+
+        let mut tries = 0;
+        let result = policy(5).run_with(
+            || -> Result<(), &str> {
+                tries += 1;
+                Err(if tries < 3 { "busy" } else { "no such table" })
+            },
+            |err| *err == "busy",
+        );
+        assert_eq!(tries, 3);
+        assert!(matches!(
+            result,
+            Err(RetryError::Permanent("no such table"))
+        ));
+    }
+
+    #[test]
+    fn backoff_is_jittered_within_bounds() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..1000 {
+            let slept = jitter(backoff);
+            assert!(slept >= backoff / 2 && slept <= backoff, "{:?}", slept);
+        }
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn backoff_is_slept_between_attempts() {
+        let policy = RetryPolicy {
+            attempts: 4,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(40),
+        };
+        let started = Instant::now();
+        let mut tries = 0;
+        let result = policy.run(|| -> io::Result<()> {
+            tries += 1;
+            Err(timed_out())
+        });
+        // At least half of 20ms, 40ms and 40ms again
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(tries, 4);
+        assert!(matches!(result, Err(RetryError::Exhausted(_))));
+    }
+
+    #[test]
+    fn backoff_stops_growing_at_the_maximum() {
+        let policy = RetryPolicy {
+            attempts: 12,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let started = Instant::now();
+        let _ = policy.run(|| -> io::Result<()> { Err(timed_out()) });
+        // Doubling each time, the last backoff alone would be at least a second
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,248 @@
+//! Entries of the services database, which names ports for `getservbyname` and `getservbyport`.
+//!
+//! A [`Service`]'s port is a [`NonZeroU16`] in host byte order. `struct servent` carries it in
+//! network byte order in an `int`, which is easy to get wrong from either side; the conversion
+//! happens here, in [`Service::to_c_servent`] and [`port_from_s_port`], and nowhere else.
+//!
+//! ```
+//! use std::num::NonZeroU16;
+//! use libnss::service::{port_from_s_port, Protocol, Service};
+//!
+//! let ssh = Service::builder()
+//!     .name("ssh")
+//!     .port(NonZeroU16::new(22).unwrap())
+//!     .protocol(Protocol::Tcp)
+//!     .alias("secure-shell")
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(ssh.to_string(), "ssh                   22/tcp secure-shell");
+//!
+//! // getservbyport(htons(22), "tcp")
+//! assert_eq!(port_from_s_port(ssh.s_port()), Some(ssh.port));
+//! assert_eq!(port_from_s_port(i32::from(22u16.to_be())), Some(ssh.port));
+//!
+//! assert!(Service::builder().name("ssh").alias("secure shell").build().is_err());
+//! ```
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::num::NonZeroU16;
+use std::str::FromStr;
+
+use crate::interop::{rebase_ptr, CBuffer, Rebase};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub port: NonZeroU16,
+    pub protocol: Protocol,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// Any other protocol `/etc/protocols` names, such as `sctp` or `ddp`
+    Other(String),
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Other(protocol) => protocol,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses a protocol name, without regard to case for `tcp` and `udp`.
+impl FromStr for Protocol {
+    type Err = ServiceError;
+
+    fn from_str(s: &str) -> Result<Self, ServiceError> {
+        if s.eq_ignore_ascii_case("tcp") {
+            Ok(Protocol::Tcp)
+        } else if s.eq_ignore_ascii_case("udp") {
+            Ok(Protocol::Udp)
+        } else if valid_name(s) {
+            Ok(Protocol::Other(s.to_string()))
+        } else {
+            Err(ServiceError::InvalidProtocol(s.to_string()))
+        }
+    }
+}
+
+impl Service {
+    pub fn builder() -> ServiceBuilder {
+        ServiceBuilder::default()
+    }
+
+    /// The port as `struct servent`'s `s_port` holds it, in network byte order.
+    pub fn s_port(&self) -> libc::c_int {
+        libc::c_int::from(self.port.get().to_be())
+    }
+
+    pub unsafe fn to_c_servent(self, servent: *mut CService, buffer: &mut CBuffer) {
+        (*servent).s_port = self.s_port();
+        // The aliases array first, so that only the start of a misaligned buffer is skipped
+        let aliases = buffer
+            .reserve_ptr_array(self.aliases.len())
+            .expect("Not enough free space in buffer");
+        (*servent).s_name = buffer.write_str(self.name);
+        for (index, alias) in self.aliases.into_iter().enumerate() {
+            let alias = buffer.write_str(alias);
+            buffer.set_ptr(aliases.add(index), alias);
+        }
+        (*servent).s_aliases = aliases;
+        (*servent).s_proto = buffer.write_str(self.protocol.as_str().to_string());
+    }
+}
+
+/// The port `getservbyport` was asked for, given as `s_port` is in network byte order, or `None`
+/// for port zero or a value no port converts to.
+pub fn port_from_s_port(s_port: libc::c_int) -> Option<NonZeroU16> {
+    let port = u16::try_from(s_port).ok()?;
+    NonZeroU16::new(u16::from_be(port))
+}
+
+/// The entry as `getent services` prints it.
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<21} {}/{}", self.name, self.port, self.protocol)?;
+        for alias in &self.aliases {
+            write!(f, " {}", alias)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ServiceBuilder {
+    name: Option<String>,
+    aliases: Vec<String>,
+    port: Option<NonZeroU16>,
+    protocol: Option<Protocol>,
+}
+
+impl ServiceBuilder {
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn alias(mut self, alias: impl Into<String>) -> Self {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    pub fn aliases<I: IntoIterator<Item = S>, S: Into<String>>(mut self, aliases: I) -> Self {
+        self.aliases.extend(aliases.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn port(mut self, port: NonZeroU16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    pub fn build(self) -> Result<Service, ServiceError> {
+        let name = self.name.ok_or(ServiceError::MissingName)?;
+        if !valid_name(&name) {
+            return Err(ServiceError::InvalidName(name));
+        }
+
+        let mut aliases: Vec<String> = Vec::new();
+        for alias in self.aliases {
+            if !valid_name(&alias) {
+                return Err(ServiceError::InvalidName(alias));
+            }
+            if alias != name && !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+
+        let protocol = match self.protocol.ok_or(ServiceError::MissingProtocol)? {
+            Protocol::Other(protocol) => protocol.parse()?,
+            protocol => protocol,
+        };
+
+        Ok(Service {
+            name,
+            aliases,
+            port: self.port.ok_or(ServiceError::MissingPort)?,
+            protocol,
+        })
+    }
+}
+
+/// A name `/etc/services` could hold: not empty, and without whitespace, a `#` starting a
+/// comment or a `/` splitting a port from its protocol.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '#' || c == '/')
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ServiceError {
+    MissingName,
+    MissingPort,
+    MissingProtocol,
+    /// The name or an alias, as given
+    InvalidName(String),
+    /// As given
+    InvalidProtocol(String),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::MissingName => write!(f, "service has no name"),
+            ServiceError::MissingPort => write!(f, "service has no port"),
+            ServiceError::MissingProtocol => write!(f, "service has no protocol"),
+            ServiceError::InvalidName(name) => {
+                write!(f, "`{}` is not a valid service name", name)
+            }
+            ServiceError::InvalidProtocol(protocol) => {
+                write!(f, "`{}` is not a valid protocol name", protocol)
+            }
+        }
+    }
+}
+
+impl Error for ServiceError {}
+
+/// NSS C `struct servent`
+#[repr(C)]
+#[allow(missing_copy_implementations)]
+#[derive(Debug)]
+pub struct CService {
+    pub s_name: *mut libc::c_char,
+    pub s_aliases: *mut *mut libc::c_char,
+    /// The port, in network byte order
+    pub s_port: libc::c_int,
+    pub s_proto: *mut libc::c_char,
+}
+
+impl Rebase for CService {
+    unsafe fn rebase(&mut self, from: usize, len: usize, to: usize) {
+        rebase_ptr(&mut self.s_name, from, len, to);
+        rebase_ptr(&mut self.s_aliases, from, len, to);
+        rebase_ptr(&mut self.s_proto, from, len, to);
+    }
+}
@@ -1,5 +1,11 @@
-use crate::interop::CBuffer;
+use crate::interop::{
+    checked_size, rebase_ptr, wipe, write_entry, CBlob, CBuffer, NssStatus, Rebase, SizeOverflow,
+};
+use std::fmt;
+use std::time::Duration;
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Shadow {
     pub name: String,
     pub passwd: String,
@@ -13,9 +19,20 @@ pub struct Shadow {
 }
 
 impl Shadow {
-    pub unsafe fn to_c_shadow(self, pwbuf: *mut CShadow, buffer: &mut CBuffer) {
-        (*pwbuf).name = buffer.write_str(self.name);
-        (*pwbuf).passwd = buffer.write_str(self.passwd);
+    pub unsafe fn to_c_shadow(mut self, pwbuf: *mut CShadow, buffer: &mut CBuffer) {
+        let written = self.write_c_shadow(pwbuf, buffer);
+        wipe(&mut self.passwd);
+        written.expect("Not enough free space in buffer")
+    }
+
+    /// Copies the fields without taking them, or returns `SizeOverflow` once one doesn't fit.
+    unsafe fn write_c_shadow(
+        &self,
+        pwbuf: *mut CShadow,
+        buffer: &mut CBuffer,
+    ) -> Result<(), SizeOverflow> {
+        (*pwbuf).name = buffer.copy_str(&self.name)?;
+        (*pwbuf).passwd = buffer.copy_str(&self.passwd)?;
         (*pwbuf).last_change = self.last_change;
         (*pwbuf).change_min_days = self.change_min_days;
         (*pwbuf).change_max_days = self.change_max_days;
@@ -23,13 +40,86 @@ impl Shadow {
         (*pwbuf).change_inactive_days = self.change_inactive_days;
         (*pwbuf).expire_date = self.expire_date;
         (*pwbuf).reserved = self.reserved;
+        Ok(())
+    }
+
+    /// The entry serialized once, to copy into each caller's buffer. Panics for an entry too big to
+    /// lay out, which [`try_to_c_blob`](Self::try_to_c_blob) returns as an error instead.
+    pub fn to_c_blob(&self) -> CBlob<CShadow> {
+        self.try_to_c_blob().expect("entry too big to lay out")
+    }
+
+    pub fn try_to_c_blob(&self) -> Result<CBlob<CShadow>, SizeOverflow> {
+        let capacity = self.checked_c_size()?;
+        unsafe {
+            Ok(CBlob::new(capacity, |pwbuf, buffer| {
+                self.write_c_shadow(pwbuf, buffer)
+                    .expect("entry sized to fit")
+            }))
+        }
+    }
+
+    fn checked_c_size(&self) -> Result<usize, SizeOverflow> {
+        checked_size(vec![self.name.len() + 1, self.passwd.len() + 1])
+    }
+}
+
+/// Copies `shadow` as a `getsp*_r` function returns it, straight into the caller's buffer. A
+/// buffer too small for it gets `TryAgain` with `ERANGE` in `errnop`, and an entry too big for any
+/// buffer `Unavail`.
+pub unsafe fn write_shadow(
+    shadow: &Shadow,
+    result: *mut CShadow,
+    buf: *mut libc::c_char,
+    buflen: usize,
+    errnop: *mut libc::c_int,
+) -> NssStatus {
+    let size = shadow.checked_c_size();
+    write_entry(size, result, buf, buflen, errnop, |pwbuf, buffer| {
+        shadow.write_c_shadow(pwbuf, buffer)
+    })
+}
+
+/// The entry as `getent shadow` prints it, leaving unset (-1) fields empty.
+impl fmt::Display for Shadow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:", self.name, self.passwd)?;
+        for field in &[
+            self.last_change,
+            self.change_min_days,
+            self.change_max_days,
+            self.change_warn_days,
+            self.change_inactive_days,
+            self.expire_date,
+        ] {
+            if *field == -1 {
+                write!(f, ":")?;
+            } else {
+                write!(f, "{}:", field)?;
+            }
+        }
+        if self.reserved != u64::MAX {
+            write!(f, "{}", self.reserved)?;
+        }
+        Ok(())
     }
 }
 
 pub trait ShadowHooks {
-    fn get_all_entries() -> Vec<Shadow>;
+    /// How long a `setspent` may reuse the entries an earlier enumeration fetched, rather
+    /// than fetching them all again. Zero, the default, always fetches.
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(0);
 
-    fn get_entry_by_name(name: String) -> Option<Shadow>;
+    /// The entries `getent shadow` lists. The default lists nothing, as
+    /// [`PasswdHooks::get_all_entries`](crate::passwd::PasswdHooks) does.
+    fn get_all_entries() -> Vec<Shadow> {
+        Vec::new()
+    }
+
+    /// The default finds nothing, so `getspnam` answers `NotFound`.
+    fn get_entry_by_name(_name: String) -> Option<Shadow> {
+        None
+    }
 }
 
 #[repr(C)]
@@ -46,75 +136,160 @@ pub struct CShadow {
     pub reserved: u64,
 }
 
+impl Rebase for CShadow {
+    unsafe fn rebase(&mut self, from: usize, len: usize, to: usize) {
+        rebase_ptr(&mut self.name, from, len, to);
+        rebase_ptr(&mut self.passwd, from, len, to);
+    }
+}
+
+/// Generates the `_nss_<module>_*` shadow functions NSS calls and the `<module>_shadow` module,
+/// as [`libnss_passwd_hooks!`] does for passwd.
 #[macro_export]
 macro_rules! libnss_shadow_hooks {
-($mod_ident:ident, $hooks_ident:ident) => (
-    paste::item! {
-        pub use self::[<libnss_shadow_ $mod_ident _hooks_impl>]::*;
-        mod [<libnss_shadow_ $mod_ident _hooks_impl>] {
+($mod_ident:ident, $hooks:ty) => (
+    $crate::paste::item! {
+        $crate::libnss_shadow_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, no_enumeration) => (
+    $crate::paste::item! {
+        $crate::libnss_shadow_hooks!(@module $mod_ident, [<_nss_ $mod_ident _>], $hooks, no_enumeration);
+    }
+);
+($mod_ident:ident, $hooks:ty, prefix = $prefix:ident) => (
+    $crate::libnss_shadow_hooks!(@module $mod_ident, $prefix, $hooks, enumeration);
+);
+($mod_ident:ident, $hooks:ty, no_enumeration, prefix = $prefix:ident) => (
+    $crate::libnss_shadow_hooks!(@module $mod_ident, $prefix, $hooks, no_enumeration);
+);
+(@module $mod_ident:ident, $prefix:ident, $hooks:ty, $enumeration:ident) => (
+    $crate::paste::item! {
+        // Named here so that the hooks type resolves where the macro was invoked
+        #[allow(non_camel_case_types)]
+        type [<libnss_shadow_ $mod_ident _hooks>] = $hooks;
+
+        pub mod [<$mod_ident _shadow>] {
             #![allow(non_upper_case_globals)]
 
-            use std::ffi::CStr;
-            use std::str;
-            use std::sync::{Mutex, MutexGuard};
-            use $crate::interop::{CBuffer, Iterator, NssStatus};
+            use ::std::ffi::CStr;
+            use ::std::str;
+            use $crate::validate::Validate;
+            use $crate::interop::NssStatus;
             use $crate::shadow::{CShadow, ShadowHooks, Shadow};
+            use $crate::stats::{Call, Database};
+            use super::[<libnss_shadow_ $mod_ident _hooks>] as Hooks;
 
-            lazy_static! {
-            static ref [<SHADOW_ $mod_ident _ITERATOR>]: Mutex<Iterator<Shadow>> = Mutex::new(Iterator::<Shadow>::new());
+            /// The entries `setspent` enumerates, as `getspent_r` would return them.
+            pub fn all_shadow_entries() -> Vec<Shadow> {
+                Hooks::get_all_entries().into_iter().filter(Validate::is_valid).collect()
             }
 
-            #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _setspent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Shadow>> = [<SHADOW_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.open(super::$hooks_ident::get_all_entries());
-                NssStatus::Success.to_c()
+            /// The entry `getspnam_r` returns, without going through its C interface.
+            pub fn lookup_shadow_by_name(name: &str) -> Result<Shadow, NssStatus> {
+                Hooks::get_entry_by_name(name.to_string()).filter(Validate::is_valid).ok_or(NssStatus::NotFound)
             }
 
+            $crate::libnss_shadow_hooks!(@$enumeration $mod_ident, $prefix);
+
             #[no_mangle]
-            extern "C" fn [<_nss_ $mod_ident _endspent>]() -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Shadow>> = [<SHADOW_ $mod_ident _ITERATOR>].lock().unwrap();
-                iter.close();
+            unsafe extern "C" fn [<$prefix getspnam_r>](name_: *const $crate::libc::c_char, pwbuf: *mut CShadow, buf: *mut $crate::libc::c_char,
+                                                                  buflen: $crate::libc::size_t, errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+                let call = Call::start(Database::Shadow);
+                if name_.is_null() {
+                    return call.finish($crate::interop::invalid_argument(errnop), errnop);
+                }
+                let cstr = CStr::from_ptr(name_);
 
-                NssStatus::Success.to_c()
+                let status = match str::from_utf8(cstr.to_bytes()) {
+                    Ok(name) => match lookup_shadow_by_name(name) {
+                        Ok(mut val) => {
+                            let status = $crate::shadow::write_shadow(&val, pwbuf, buf, buflen, errnop);
+                            $crate::interop::wipe(&mut val.passwd);
+                            status
+                        },
+                        Err(status) => status
+                    },
+                    Err(_) => NssStatus::NotFound
+                };
+                call.finish(status, errnop)
             }
 
+            #[cfg(any(target_os = "illumos", target_os = "solaris"))]
             #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getspent_r>](pwbuf: *mut CShadow, buf: *mut libc::c_char, buflen: libc::size_t,
-                                                                  _errnop: *mut libc::c_int) -> libc::c_int {
-                let mut iter: MutexGuard<Iterator<Shadow>> = [<SHADOW_ $mod_ident _ITERATOR>].lock().unwrap();
-                match iter.next() {
-                    None => $crate::interop::NssStatus::NotFound.to_c(),
-                    Some(entry) => {
-                        let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                        buffer.clear();
-
-                        entry.to_c_shadow(pwbuf, &mut buffer);
-                        NssStatus::Success.to_c()
-                    }
-                }
+            extern "C" fn [<$prefix shadow_constr>](_db_name: *const $crate::libc::c_char, _src_name: *const $crate::libc::c_char,
+                                                          _cfg_args: *const $crate::libc::c_char) -> *mut $crate::solaris::NssBackend {
+                $crate::solaris::shadow_backend::<Hooks>()
             }
+        }
+    }
+);
+(@enumeration $mod_ident:ident, $prefix:ident) => (
+    $crate::paste::item! {
+        use ::std::sync::{Mutex, MutexGuard};
+        use $crate::interop::EntryCursor;
 
-            #[no_mangle]
-            unsafe extern "C" fn [<_nss_ $mod_ident _getspnam_r>](name_: *const libc::c_char, pwbuf: *mut CShadow, buf: *mut libc::c_char,
-                                                                  buflen: libc::size_t, _errnop: *mut libc::c_int) -> libc::c_int {
-                let cstr = CStr::from_ptr(name_);
+        static [<SHADOW_ $mod_ident _ITERATOR>]: Mutex<EntryCursor<Shadow>> = Mutex::new(EntryCursor::new());
+
+        #[no_mangle]
+        extern "C" fn [<$prefix setspent>](stayopen: $crate::libc::c_int) -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Shadow>> = [<SHADOW_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.open_cached(<Hooks as ShadowHooks>::ENUMERATION_CACHE_TTL, stayopen != 0, all_shadow_entries);
+            NssStatus::Success.to_c()
+        }
 
-                match str::from_utf8(cstr.to_bytes()) {
-                    Ok(name) => match super::$hooks_ident::get_entry_by_name(name.to_string()) {
-                        Some(val) => {
-                            let mut buffer = CBuffer::new(buf as *mut libc::c_void, buflen);
-                            buffer.clear();
+        #[no_mangle]
+        extern "C" fn [<$prefix endspent>]() -> $crate::libc::c_int {
+            let mut iter: MutexGuard<EntryCursor<Shadow>> = [<SHADOW_ $mod_ident _ITERATOR>].lock().unwrap();
+            iter.close();
 
-                            val.to_c_shadow(pwbuf, &mut buffer);
-                            NssStatus::Success.to_c()
-                        },
-                        None => NssStatus::NotFound.to_c()
-                    },
-                    Err(_) => NssStatus::NotFound.to_c()
+            NssStatus::Success.to_c()
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn [<$prefix getspent_r>](pwbuf: *mut CShadow, buf: *mut $crate::libc::c_char, buflen: $crate::libc::size_t,
+                                                              errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+            let call = Call::start(Database::Shadow);
+            // Serializing the entry doesn't need the cursor, so release it first
+            let entry = [<SHADOW_ $mod_ident _ITERATOR>].lock().unwrap().next();
+            let status = match entry {
+                None => NssStatus::NotFound,
+                Some(mut entry) => {
+                    let status = $crate::shadow::write_shadow(&entry, pwbuf, buf, buflen, errnop);
+                    // The caller will ask again with a bigger buffer
+                    if status == NssStatus::TryAgain {
+                        [<SHADOW_ $mod_ident _ITERATOR>].lock().unwrap().put_back(entry);
+                    } else {
+                        $crate::interop::wipe(&mut entry.passwd);
+                    }
+                    status
                 }
-            }
+            };
+            call.finish(status, errnop)
+        }
+    }
+);
+(@no_enumeration $mod_ident:ident, $prefix:ident) => (
+    // The BSDs' method tables need every function, so there an enumeration finds nothing
+    $crate::paste::item! {
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix setspent>](_stayopen: $crate::libc::c_int) -> $crate::libc::c_int {
+            NssStatus::Success.to_c()
+        }
+
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix endspent>]() -> $crate::libc::c_int {
+            NssStatus::Success.to_c()
+        }
+
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+        #[no_mangle]
+        extern "C" fn [<$prefix getspent_r>](_result: *mut CShadow, _buf: *mut $crate::libc::c_char, _buflen: $crate::libc::size_t,
+                                                       _errnop: *mut $crate::libc::c_int) -> $crate::libc::c_int {
+            NssStatus::NotFound.to_c()
         }
     }
-)
+);
 }
\ No newline at end of file
@@ -0,0 +1,847 @@
+//! A cache segment shared by every process on a machine, so that sshd, cron and every shell
+//! resolve from one cached copy rather than each keeping their own.
+//!
+//! The segment is a file of a fixed size, usually on a tmpfs such as `/run`, that each process
+//! maps. Reads take no lock: the writer makes a sequence number odd while it changes anything and
+//! even again once it's done, and a reader retries (a few times, then gives up as if the entry
+//! weren't there) if the number was odd or changed while it read. Writers take turns with
+//! `flock`. Processes that may only read the file (e.g. for a segment created by a root daemon)
+//! read it all the same; they just can't add to it.
+//!
+//! An entry is a key and a value, both bytes, and when it was read. Once the segment is full the
+//! writer rebuilds it with only the most recently read entries. A writer that dies part way
+//! leaves the sequence number odd, and the next writer to come along starts the segment afresh,
+//! as does a writer that finds the segment corrupt.
+//!
+//! [`StaleCache::shared`](crate::stale::StaleCache::shared) keeps a cache in one.
+
+use std::cmp::Reverse;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"libnsshm");
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// Odd while the writer is changing the segment
+    seq: AtomicU64,
+    /// How many slots the index has, a power of two
+    slots: u64,
+    /// How many bytes of entries fit after the index
+    arena: u64,
+    /// How many bytes of the arena are taken
+    used: u64,
+    entries: u64,
+}
+
+/// An index slot, pointing at the entry's key and value, stored back to back in the arena.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Slot {
+    /// Zero for an empty slot
+    hash: u64,
+    offset: u64,
+    /// When the entry was read, in seconds since the epoch
+    read: u64,
+    key_len: u32,
+    value_len: u32,
+}
+
+/// The header's fields other than the sequence number, read and written volatile as other
+/// processes may be changing them
+#[derive(Clone, Copy)]
+enum Field {
+    Magic,
+    Slots,
+    Arena,
+    Used,
+    Entries,
+}
+
+const HEADER: usize = std::mem::size_of::<Header>();
+const SLOT: usize = std::mem::size_of::<Slot>();
+
+/// How many times a read is retried while the writer is busy
+const READ_ATTEMPTS: usize = 8;
+
+/// Refuses a segment, or the directory it is in, that someone other than root or this process's
+/// user owns, or that the group or others may write to.
+fn check_trusted(metadata: &fs::Metadata) -> io::Result<()> {
+    let euid = unsafe { libc::geteuid() };
+    if (metadata.uid() != 0 && metadata.uid() != euid) || metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "shared cache segment may be written by other users",
+        ));
+    }
+    Ok(())
+}
+
+pub struct SharedCache {
+    file: File,
+    map: *mut u8,
+    len: usize,
+    writable: bool,
+}
+
+// Every access to the mapping goes through the sequence number or the file lock
+unsafe impl Send for SharedCache {}
+unsafe impl Sync for SharedCache {}
+
+impl SharedCache {
+    /// Maps the segment at `path`, creating it `size` bytes long with `mode` if it doesn't exist
+    /// and this process may. A segment that exists keeps its size. The directory must exist, and
+    /// a segment or directory owned by anyone but root or this process's user, or that others may
+    /// write to, is refused: every process mapping the segment serves what it finds there.
+    pub fn open<P: AsRef<Path>>(path: P, mode: u32, size: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => check_trusted(&fs::metadata(dir)?)?,
+            _ => check_trusted(&fs::metadata(".")?)?,
+        }
+        let (file, writable) = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(mode)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+        {
+            Ok(file) => (file, true),
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => (
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .open(path)?,
+                false,
+            ),
+            Err(err) => return Err(err),
+        };
+        check_trusted(&file.metadata()?)?;
+
+        if writable {
+            let _lock = Lock::exclusive(&file)?;
+            if file.metadata()?.len() == 0 {
+                file.set_len(size as u64)?;
+            }
+        }
+        let len = file.metadata()?.len() as usize;
+        if len < HEADER + SLOT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared cache segment too small",
+            ));
+        }
+
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let cache = SharedCache {
+            file,
+            map: map as *mut u8,
+            len,
+            writable,
+        };
+        if writable && cache.geometry().is_none() {
+            cache.write(|_| Ok(()))?;
+        }
+        Ok(cache)
+    }
+
+    /// The value stored for `key` and when it was read.
+    pub fn get(&self, key: &[u8]) -> Option<(Vec<u8>, SystemTime)> {
+        let hash = hash(key);
+        self.read(|cache| {
+            let (_, slot) = cache.find(key, hash)?;
+            let value = cache.value(&slot)?;
+            Some((value, UNIX_EPOCH + Duration::from_secs(slot.read)))
+        })
+        .flatten()
+    }
+
+    /// Every key stored.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.read(|cache| {
+            let (slots, _) = cache.geometry()?;
+            (0..slots)
+                .map(|index| cache.slot(index))
+                .filter(|slot| slot.hash != 0)
+                .map(|slot| cache.bytes(slot.offset, slot.key_len as u64))
+                .collect::<Option<Vec<_>>>()
+        })
+        .flatten()
+        .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read(|cache| cache.field(Field::Entries) as usize)
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether this process may change the segment.
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Stores `value` for `key`, read at `read`, making room by dropping the entries read longest
+    /// ago if need be.
+    pub fn insert(&self, key: &[u8], value: &[u8], read: SystemTime) -> io::Result<()> {
+        let read = read
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let hash = hash(key);
+        self.write(|cache| {
+            let (slots, arena) = cache.geometry().ok_or_else(corrupt)?;
+            let entries = cache.field(Field::Entries);
+            let existing = cache.find(key, hash);
+
+            if let Some((index, mut slot)) = existing {
+                let stored = cache.value(&slot);
+                if stored.as_deref() == Some(value) {
+                    slot.read = read;
+                    cache.set_slot(index, slot);
+                    return Ok(());
+                }
+            }
+
+            let need = (key.len() + value.len()) as u64;
+            let used = cache.field(Field::Used);
+            let count = entries.saturating_add(u64::from(existing.is_none()));
+            // Half full at most, so that probing stays short
+            if count > slots / 2 || used.saturating_add(need) > arena {
+                if need > arena {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "entry too large for the shared cache",
+                    ));
+                }
+                let mut kept = cache.entries();
+                kept.retain(|(k, _, _)| k != key);
+                kept.sort_by_key(|(_, _, read)| Reverse(*read));
+                let mut total = need;
+                let mut fit = 0;
+                for (k, v, _) in &kept {
+                    let size = (k.len() + v.len()) as u64;
+                    if fit + 2 > slots / 2 || total + size > arena {
+                        break;
+                    }
+                    total += size;
+                    fit += 1;
+                }
+                kept.truncate(fit as usize);
+                cache.clear();
+                for (k, v, r) in &kept {
+                    cache.append(k, v, *r)?;
+                }
+                return cache.append(key, value, read);
+            }
+
+            match existing {
+                // The old value's bytes stay in the arena until the next rebuild
+                Some((index, mut slot)) => {
+                    slot.offset = cache.push_bytes(key, value);
+                    slot.read = read;
+                    slot.value_len = value.len() as u32;
+                    cache.set_slot(index, slot);
+                }
+                None => cache.append(key, value, read)?,
+            }
+            Ok(())
+        })
+    }
+
+    pub fn remove(&self, key: &[u8]) -> io::Result<()> {
+        let hash = hash(key);
+        self.write(|cache| {
+            if cache.find(key, hash).is_none() {
+                return Ok(());
+            }
+            let mut kept = cache.entries();
+            kept.retain(|(k, _, _)| k != key);
+            cache.clear();
+            for (k, v, r) in &kept {
+                cache.append(k, v, *r)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn header(&self) -> *mut Header {
+        self.map as *mut Header
+    }
+
+    fn seq(&self) -> &AtomicU64 {
+        unsafe { &*ptr::addr_of!((*self.header()).seq) }
+    }
+
+    fn field_ptr(&self, field: Field) -> *mut u64 {
+        let header = self.header();
+        unsafe {
+            match field {
+                Field::Magic => ptr::addr_of_mut!((*header).magic),
+                Field::Slots => ptr::addr_of_mut!((*header).slots),
+                Field::Arena => ptr::addr_of_mut!((*header).arena),
+                Field::Used => ptr::addr_of_mut!((*header).used),
+                Field::Entries => ptr::addr_of_mut!((*header).entries),
+            }
+        }
+    }
+
+    fn field(&self, field: Field) -> u64 {
+        unsafe { ptr::read_volatile(self.field_ptr(field)) }
+    }
+
+    fn set_field(&self, field: Field, value: u64) {
+        unsafe { ptr::write_volatile(self.field_ptr(field), value) }
+    }
+
+    /// The number of slots and the arena's size, if the header describes a segment that fits.
+    fn geometry(&self) -> Option<(u64, u64)> {
+        let slots = self.field(Field::Slots);
+        let arena = self.field(Field::Arena);
+        let fits = (HEADER as u64)
+            .checked_add(slots.checked_mul(SLOT as u64)?)?
+            .checked_add(arena)?
+            <= self.len as u64;
+        if self.field(Field::Magic) == MAGIC && slots.is_power_of_two() && fits {
+            Some((slots, arena))
+        } else {
+            None
+        }
+    }
+
+    /// Runs `read` until it ran without the writer changing anything.
+    fn read<T, F: Fn(&Self) -> T>(&self, read: F) -> Option<T> {
+        for _ in 0..READ_ATTEMPTS {
+            let before = self.seq().load(Ordering::Acquire);
+            if before % 2 == 1 {
+                thread::yield_now();
+                continue;
+            }
+            self.geometry()?;
+            let result = read(self);
+            fence(Ordering::Acquire);
+            if self.seq().load(Ordering::Relaxed) == before {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    /// Runs `write` holding the file lock, with the sequence number odd. A segment `write` finds
+    /// corrupt is laid out afresh.
+    fn write<F: FnOnce(&Self) -> io::Result<()>>(&self, write: F) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the shared cache is read only",
+            ));
+        }
+        let _lock = Lock::exclusive(&self.file)?;
+        let seq = self.seq().load(Ordering::Relaxed);
+        // Odd if the last writer died part way, leaving who knows what
+        let broken = seq % 2 == 1 || self.geometry().is_none();
+        let seq = seq | 1;
+        self.seq().store(seq, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        if broken {
+            self.initialize();
+        }
+        let result = write(self);
+        if matches!(&result, Err(err) if err.kind() == io::ErrorKind::InvalidData) {
+            self.initialize();
+        }
+
+        self.seq().store(seq + 1, Ordering::Release);
+        result
+    }
+
+    /// Lays out an empty segment, with a slot per 256 bytes or so.
+    fn initialize(&self) {
+        let room = (self.len - HEADER) as u64;
+        let mut slots = 1u64;
+        while slots * 2 * (SLOT as u64 + 224) <= room {
+            slots *= 2;
+        }
+        let arena = room - slots * SLOT as u64;
+        self.set_field(Field::Slots, slots);
+        self.set_field(Field::Arena, arena);
+        self.set_field(Field::Magic, MAGIC);
+        self.clear();
+    }
+
+    fn clear(&self) {
+        let (slots, _) = self.geometry().unwrap();
+        unsafe {
+            ptr::write_bytes(self.map.add(HEADER), 0, slots as usize * SLOT);
+        }
+        self.set_field(Field::Used, 0);
+        self.set_field(Field::Entries, 0);
+    }
+
+    fn slot(&self, index: u64) -> Slot {
+        unsafe { ptr::read_volatile(self.map.add(HEADER + index as usize * SLOT) as *const Slot) }
+    }
+
+    fn set_slot(&self, index: u64, slot: Slot) {
+        unsafe {
+            ptr::write_volatile(
+                self.map.add(HEADER + index as usize * SLOT) as *mut Slot,
+                slot,
+            )
+        }
+    }
+
+    /// Copies `len` bytes from `offset` in the arena, if they are inside it.
+    fn bytes(&self, offset: u64, len: u64) -> Option<Vec<u8>> {
+        let (slots, arena) = self.geometry()?;
+        if offset.checked_add(len)? > arena {
+            return None;
+        }
+        let start = unsafe {
+            self.map
+                .add(HEADER + slots as usize * SLOT + offset as usize)
+        };
+        Some(
+            (0..len as usize)
+                .map(|i| unsafe { ptr::read_volatile(start.add(i)) })
+                .collect(),
+        )
+    }
+
+    /// Copies the value `slot` points at, if it's inside the arena.
+    fn value(&self, slot: &Slot) -> Option<Vec<u8>> {
+        self.bytes(
+            slot.offset.checked_add(slot.key_len as u64)?,
+            slot.value_len as u64,
+        )
+    }
+
+    /// The slot holding `key`, and its index.
+    fn find(&self, key: &[u8], hash: u64) -> Option<(u64, Slot)> {
+        let (slots, _) = self.geometry()?;
+        let mut index = hash & (slots - 1);
+        for _ in 0..slots {
+            let slot = self.slot(index);
+            if slot.hash == 0 {
+                return None;
+            }
+            if slot.hash == hash
+                && slot.key_len as usize == key.len()
+                && self.bytes(slot.offset, slot.key_len as u64).as_deref() == Some(key)
+            {
+                return Some((index, slot));
+            }
+            index = (index + 1) & (slots - 1);
+        }
+        None
+    }
+
+    /// Every entry, as the writer reads them.
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>, u64)> {
+        let (slots, _) = self.geometry().unwrap();
+        (0..slots)
+            .map(|index| self.slot(index))
+            .filter(|slot| slot.hash != 0)
+            .filter_map(|slot| {
+                let key = self.bytes(slot.offset, slot.key_len as u64)?;
+                let value = self.value(&slot)?;
+                Some((key, value, slot.read))
+            })
+            .collect()
+    }
+
+    /// Copies a key and value to the end of the arena, which the caller has made room in.
+    fn push_bytes(&self, key: &[u8], value: &[u8]) -> u64 {
+        let (slots, _) = self.geometry().unwrap();
+        let offset = self.field(Field::Used);
+        unsafe {
+            let start = self
+                .map
+                .add(HEADER + slots as usize * SLOT + offset as usize);
+            ptr::copy_nonoverlapping(key.as_ptr(), start, key.len());
+            ptr::copy_nonoverlapping(value.as_ptr(), start.add(key.len()), value.len());
+        }
+        self.set_field(Field::Used, offset + (key.len() + value.len()) as u64);
+        offset
+    }
+
+    /// Adds a new entry, which the caller has made room for.
+    fn append(&self, key: &[u8], value: &[u8], read: u64) -> io::Result<()> {
+        let (slots, _) = self.geometry().ok_or_else(corrupt)?;
+        let hash = hash(key);
+        // Only a corrupt index has no empty slot, as it's never more than half full
+        let index = (0..slots)
+            .map(|probe| hash.wrapping_add(probe) & (slots - 1))
+            .find(|&index| self.slot(index).hash == 0)
+            .ok_or_else(corrupt)?;
+        let offset = self.push_bytes(key, value);
+        self.set_slot(
+            index,
+            Slot {
+                hash,
+                offset,
+                read,
+                key_len: key.len() as u32,
+                value_len: value.len() as u32,
+            },
+        );
+        self.set_field(Field::Entries, self.field(Field::Entries) + 1);
+        Ok(())
+    }
+}
+
+impl Drop for SharedCache {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "shared cache segment is corrupt",
+    )
+}
+
+/// FNV-1a, never zero, as that marks an empty slot.
+fn hash(key: &[u8]) -> u64 {
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash.max(1)
+}
+
+/// Holds `flock` on a file until dropped.
+struct Lock<'a>(&'a File);
+
+impl<'a> Lock<'a> {
+    fn exclusive(file: &'a File) -> io::Result<Self> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Lock(file))
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.0.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{FileExt, PermissionsExt};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    /// A segment in a directory of its own, as a world-writable one such as `/tmp` is refused.
+    fn segment(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libnss-shared-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        dir.join("segment")
+    }
+
+    fn refused(path: &Path) -> bool {
+        match SharedCache::open(path, 0o644, 4096) {
+            Err(err) => err.kind() == io::ErrorKind::PermissionDenied,
+            Ok(_) => false,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// Overwrites part of the segment behind the mappings' backs.
+    fn scribble(path: &Path, offset: usize, bytes: &[u8]) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all_at(bytes, offset as u64).unwrap();
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let path = segment("round-trip");
+        let cache = SharedCache::open(&path, 0o600, 4096).unwrap();
+        assert!(cache.is_writable());
+        assert!(cache.is_empty());
+
+        cache.insert(b"alice", b"1000", at(10)).unwrap();
+        cache.insert(b"bob", b"1001", at(20)).unwrap();
+        assert_eq!(cache.get(b"alice"), Some((b"1000".to_vec(), at(10))));
+        assert_eq!(cache.get(b"carol"), None);
+        assert_eq!(cache.len(), 2);
+
+        // Reading the same value again only moves when it was read
+        cache.insert(b"alice", b"1000", at(30)).unwrap();
+        assert_eq!(cache.get(b"alice"), Some((b"1000".to_vec(), at(30))));
+        cache.insert(b"alice", b"1002", at(40)).unwrap();
+        assert_eq!(cache.get(b"alice"), Some((b"1002".to_vec(), at(40))));
+        assert_eq!(cache.len(), 2);
+
+        // Another process sees the same entries
+        let other = SharedCache::open(&path, 0o600, 4096).unwrap();
+        let mut keys = other.keys();
+        keys.sort();
+        assert_eq!(keys, [b"alice".to_vec(), b"bob".to_vec()]);
+
+        other.remove(b"alice").unwrap();
+        assert_eq!(cache.get(b"alice"), None);
+        assert_eq!(cache.keys(), [b"bob".to_vec()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn full_segments_keep_the_most_recently_read() {
+        let path = segment("full");
+        let cache = SharedCache::open(&path, 0o600, 4096).unwrap();
+        for read in 0..16u64 {
+            let key = read.to_string();
+            cache.insert(key.as_bytes(), &[0; 64], at(read)).unwrap();
+            assert!(cache.get(key.as_bytes()).is_some());
+        }
+        assert!(cache.get(b"14").is_some());
+        assert!(cache.get(b"0").is_none());
+
+        let err = cache.insert(b"big", &[0; 4096], at(16)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(cache.get(b"15").is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn short_segments_are_refused() {
+        let path = segment("short");
+        fs::write(&path, [0; 8]).unwrap();
+        let err = SharedCache::open(&path, 0o600, 4096).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+
+        let err = SharedCache::open(&path, 0o600, HEADER).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+
+        // The smallest that holds anything
+        let cache = SharedCache::open(&path, 0o600, HEADER + SLOT + 2).unwrap();
+        cache.insert(b"a", b"1", at(0)).unwrap();
+        assert_eq!(cache.get(b"a"), Some((b"1".to_vec(), at(0))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_headers_are_started_afresh() {
+        let path = segment("header");
+        let cache = SharedCache::open(&path, 0o600, 4096).unwrap();
+        cache.insert(b"alice", b"1000", at(0)).unwrap();
+
+        // Slots that don't fit the segment
+        scribble(&path, 16, &u64::MAX.to_ne_bytes());
+        assert_eq!(cache.get(b"alice"), None);
+        assert!(cache.keys().is_empty());
+        assert_eq!(cache.len(), 0);
+
+        cache.insert(b"bob", b"1001", at(0)).unwrap();
+        assert_eq!(cache.get(b"bob"), Some((b"1001".to_vec(), at(0))));
+        assert_eq!(cache.get(b"alice"), None);
+
+        // Not a segment at all
+        scribble(&path, 0, &[0; HEADER]);
+        assert_eq!(cache.get(b"bob"), None);
+        cache.insert(b"alice", b"1000", at(0)).unwrap();
+        assert_eq!(cache.get(b"alice"), Some((b"1000".to_vec(), at(0))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dead_writers_are_cleaned_up_after() {
+        let path = segment("dead-writer");
+        let cache = SharedCache::open(&path, 0o600, 4096).unwrap();
+        cache.insert(b"alice", b"1000", at(0)).unwrap();
+
+        let seq = cache.seq().load(Ordering::Relaxed);
+        scribble(&path, 8, &(seq + 1).to_ne_bytes());
+        // Readers give up rather than wait
+        assert_eq!(cache.get(b"alice"), None);
+
+        cache.insert(b"bob", b"1001", at(0)).unwrap();
+        assert_eq!(cache.get(b"alice"), None);
+        assert_eq!(cache.get(b"bob"), Some((b"1001".to_vec(), at(0))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_slots_are_not_followed() {
+        let path = segment("slots");
+        let cache = SharedCache::open(&path, 0o600, 4096).unwrap();
+        cache.insert(b"alice", b"1000", at(0)).unwrap();
+        let (slots, _) = cache.geometry().unwrap();
+
+        // Entries outside the arena
+        for index in 0..slots as usize {
+            scribble(&path, HEADER + index * SLOT + 8, &u64::MAX.to_ne_bytes());
+        }
+        assert_eq!(cache.get(b"alice"), None);
+        assert!(cache.keys().is_empty());
+
+        // No empty slot left to probe to
+        scribble(&path, HEADER, &vec![0xff; slots as usize * SLOT]);
+        assert_eq!(cache.get(b"alice"), None);
+        let err = cache.insert(b"bob", b"1001", at(0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        cache.insert(b"bob", b"1001", at(0)).unwrap();
+        assert_eq!(cache.get(b"bob"), Some((b"1001".to_vec(), at(0))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// A value that says what it should be, so torn reads show.
+    fn value(n: u8) -> Vec<u8> {
+        vec![n; 1 + n as usize]
+    }
+
+    #[test]
+    fn readers_never_see_partial_writes() {
+        let path = segment("concurrent");
+        let size = 64 << 10;
+        SharedCache::open(&path, 0o600, size).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = SharedCache::open(&path, 0o600, size).unwrap();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut seen = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        if let Some((stored, _)) = cache.get(b"shared") {
+                            assert_eq!(stored, value(stored[0]));
+                            seen += 1;
+                        }
+                        for key in cache.keys() {
+                            assert!(key == b"shared" || key.starts_with(b"writer"));
+                        }
+                    }
+                    seen
+                })
+            })
+            .collect();
+
+        // Writers in separate mappings, as in separate processes, taking turns through the lock
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let cache = SharedCache::open(&path, 0o600, size).unwrap();
+                thread::spawn(move || {
+                    for i in 0..500u64 {
+                        let n = (i % 256) as u8;
+                        cache.insert(b"shared", &value(n), at(i)).unwrap();
+                        let key = format!("writer{}-{}", writer, i % 100);
+                        cache.insert(key.as_bytes(), &value(n), at(i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let cache = SharedCache::open(&path, 0o600, size).unwrap();
+        let (stored, _) = cache.get(b"shared").unwrap();
+        assert_eq!(stored, value(stored[0]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn segments_others_may_write_are_refused() {
+        let path = segment("loose");
+        let dir = path.parent().unwrap();
+        let cache = SharedCache::open(&path, 0o644, 4096).unwrap();
+        cache.insert(b"alice", b"1000", at(1)).unwrap();
+
+        for mode in [0o664, 0o646] {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            assert!(refused(&path), "mode {:o}", mode);
+        }
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        for mode in [0o775, 0o777, 0o1777] {
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode)).unwrap();
+            assert!(refused(&path), "directory mode {:o}", mode);
+        }
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let cache = SharedCache::open(&path, 0o644, 4096).unwrap();
+        assert_eq!(cache.get(b"alice").unwrap().0, b"1000");
+    }
+
+    #[test]
+    fn segments_other_users_own_are_refused() {
+        let path = segment("foreign");
+        SharedCache::open(&path, 0o644, 4096).unwrap();
+        // Only root may hand the segment to someone else; root's own segments are trusted
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        std::os::unix::fs::chown(&path, Some(65534), Some(65534)).unwrap();
+        assert!(refused(&path));
+        std::os::unix::fs::chown(&path, Some(0), Some(0)).unwrap();
+        assert!(SharedCache::open(&path, 0o644, 4096).is_ok());
+    }
+
+    #[test]
+    fn directories_are_not_created() {
+        let path = segment("missing").with_file_name("missing").join("segment");
+        let err = SharedCache::open(&path, 0o644, 4096).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!path.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn symlinked_segments_are_refused() {
+        let path = segment("symlink");
+        let target = path.with_file_name("target");
+        SharedCache::open(&target, 0o644, 4096).unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        assert!(SharedCache::open(&path, 0o644, 4096).is_err());
+    }
+}
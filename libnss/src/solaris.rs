@@ -0,0 +1,389 @@
+//! illumos and Solaris nsswitch support, used by the `_nss_<mod>_<db>_constr` functions that the
+//! hooks macros generate on those platforms.
+//!
+//! The Solaris frontend asks each constructor for a backend: a table of operations indexed by the
+//! `NSS_DBOP_*` numbers from `<nss_dbdefs.h>`. Rather than mirror every platform's entry structs,
+//! lookups answer in the `/etc` files format and let the frontend's own `str2ent` parse it. That is
+//! also the form nscd asks for, by passing no result struct at all.
+
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
+use std::slice;
+
+use libc::{c_char, c_int, c_void, gid_t, uid_t};
+
+use crate::files::FilesEntry;
+use crate::group::GroupHooks;
+use crate::host::{self, AddressFamily, HostAnswer, HostHooks};
+use crate::id::{Gid, Uid};
+use crate::passwd::PasswdHooks;
+use crate::shadow::ShadowHooks;
+
+// nss_status_t
+const NSS_SUCCESS: c_int = 0;
+const NSS_NOTFOUND: c_int = 1;
+const NSS_UNAVAIL: c_int = 2;
+
+// str2ent results
+const NSS_STR_PARSE_SUCCESS: c_int = 0;
+const NSS_STR_PARSE_ERANGE: c_int = 2;
+
+const HOST_NOT_FOUND: c_int = 1;
+const NO_DATA: c_int = 4;
+
+pub type NssBackendOp = unsafe extern "C" fn(*mut NssBackend, *mut c_void) -> c_int;
+
+type Str2Ent = unsafe extern "C" fn(*const c_char, c_int, *mut c_void, *mut c_char, c_int) -> c_int;
+
+/// `nss_backend_t`
+#[repr(C)]
+pub struct NssBackend {
+    ops: *const NssBackendOp,
+    n_ops: c_int,
+}
+
+#[repr(C)]
+struct XbyYBuf {
+    result: *mut c_void,
+    buffer: *mut c_char,
+    buflen: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct HostAddrKey {
+    addr: *const c_char,
+    len: c_int,
+    addr_type: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IpNodeKey {
+    name: *const c_char,
+    af_family: c_int,
+    flags: c_int,
+}
+
+/// `union nss_XbyY_key`, trimmed to the members we read. `pair` stands in for the two pointer
+/// members so the union keeps its full size.
+#[repr(C)]
+#[allow(dead_code)]
+union XbyYKey {
+    uid: uid_t,
+    gid: gid_t,
+    name: *const c_char,
+    hostaddr: HostAddrKey,
+    ipnode: IpNodeKey,
+    pair: [*const c_char; 2],
+}
+
+/// `nss_XbyY_args_t`
+#[repr(C)]
+#[allow(dead_code)]
+struct XbyYArgs {
+    buf: XbyYBuf,
+    stayopen: c_int,
+    str2ent: Option<Str2Ent>,
+    key: XbyYKey,
+    returnval: *mut c_void,
+    erange: c_int,
+    h_errno: c_int,
+    status: c_int,
+    key2str: Option<unsafe extern "C" fn()>,
+    returnlen: libc::size_t,
+}
+
+/// `struct nss_groupsbymem`, the argument to initgroups style lookups.
+#[repr(C)]
+#[allow(dead_code)]
+struct GroupsByMember {
+    username: *const c_char,
+    gid_array: *mut gid_t,
+    maxgids: c_int,
+    force_slow_way: c_int,
+    str2ent: Option<Str2Ent>,
+    process_cstr: Option<unsafe extern "C" fn()>,
+    numgids: c_int,
+}
+
+/// One backend instance. The frontend keeps a few of these per database and only ever hands the
+/// `base` pointer back, so it has to come first.
+#[repr(C)]
+struct Backend<T> {
+    base: NssBackend,
+    ops: Vec<NssBackendOp>,
+    all: fn() -> Vec<T>,
+    entries: Option<std::vec::IntoIter<T>>,
+}
+
+fn backend<T: FilesEntry>(all: fn() -> Vec<T>, lookups: &[NssBackendOp]) -> *mut NssBackend {
+    // The first four operations are the same for every database
+    let mut ops: Vec<NssBackendOp> = vec![destructor::<T>, endent::<T>, setent::<T>, getent::<T>];
+    ops.extend_from_slice(lookups);
+
+    let mut backend = Box::new(Backend {
+        base: NssBackend {
+            ops: ptr::null(),
+            n_ops: ops.len() as c_int,
+        },
+        ops,
+        all,
+        entries: None,
+    });
+    backend.base.ops = backend.ops.as_ptr();
+    Box::into_raw(backend) as *mut NssBackend
+}
+
+pub fn passwd_backend<P: PasswdHooks>() -> *mut NssBackend {
+    backend(
+        P::get_all_entries,
+        &[passwd_by_name::<P>, passwd_by_uid::<P>],
+    )
+}
+
+pub fn group_backend<G: GroupHooks>() -> *mut NssBackend {
+    backend(
+        G::get_all_entries,
+        &[group_by_name::<G>, group_by_gid::<G>, group_by_member::<G>],
+    )
+}
+
+pub fn shadow_backend<S: ShadowHooks>() -> *mut NssBackend {
+    backend(S::get_all_entries, &[shadow_by_name::<S>])
+}
+
+/// The `hosts` database, which only ever deals in IPv4.
+pub fn hosts_backend<H: HostHooks>() -> *mut NssBackend {
+    backend(H::get_all_entries, &[hosts_by_name::<H>, host_by_addr::<H>])
+}
+
+/// The `ipnodes` database, behind `getipnodebyname(3)` and the IPv6 half of `getaddrinfo(3)`.
+pub fn ipnodes_backend<H: HostHooks>() -> *mut NssBackend {
+    backend(
+        H::get_all_entries,
+        &[ipnodes_by_name::<H>, host_by_addr::<H>],
+    )
+}
+
+unsafe extern "C" fn destructor<T>(be: *mut NssBackend, _args: *mut c_void) -> c_int {
+    drop(Box::from_raw(be as *mut Backend<T>));
+    NSS_SUCCESS
+}
+
+unsafe extern "C" fn endent<T>(be: *mut NssBackend, _args: *mut c_void) -> c_int {
+    (*(be as *mut Backend<T>)).entries = None;
+    NSS_SUCCESS
+}
+
+unsafe extern "C" fn setent<T>(be: *mut NssBackend, _args: *mut c_void) -> c_int {
+    let backend = &mut *(be as *mut Backend<T>);
+    backend.entries = Some((backend.all)().into_iter());
+    NSS_SUCCESS
+}
+
+unsafe extern "C" fn getent<T: FilesEntry>(be: *mut NssBackend, args: *mut c_void) -> c_int {
+    let backend = &mut *(be as *mut Backend<T>);
+    // Callers may skip setent, so start the enumeration on demand
+    let all = backend.all;
+    let entry = backend
+        .entries
+        .get_or_insert_with(|| all().into_iter())
+        .next();
+    answer(args as *mut XbyYArgs, entry)
+}
+
+unsafe extern "C" fn passwd_by_name<P: PasswdHooks>(
+    _be: *mut NssBackend,
+    args: *mut c_void,
+) -> c_int {
+    let args = args as *mut XbyYArgs;
+    let entry = key_name((*args).key.name).and_then(P::get_entry_by_name);
+    answer(args, entry)
+}
+
+unsafe extern "C" fn passwd_by_uid<P: PasswdHooks>(
+    _be: *mut NssBackend,
+    args: *mut c_void,
+) -> c_int {
+    let args = args as *mut XbyYArgs;
+    answer(args, P::get_entry_by_uid(Uid::from_raw((*args).key.uid)))
+}
+
+unsafe extern "C" fn group_by_name<G: GroupHooks>(
+    _be: *mut NssBackend,
+    args: *mut c_void,
+) -> c_int {
+    let args = args as *mut XbyYArgs;
+    let entry = key_name((*args).key.name).and_then(G::get_entry_by_name);
+    answer(args, entry)
+}
+
+unsafe extern "C" fn group_by_gid<G: GroupHooks>(_be: *mut NssBackend, args: *mut c_void) -> c_int {
+    let args = args as *mut XbyYArgs;
+    answer(args, G::get_entry_by_gid(Gid::from_raw((*args).key.gid)))
+}
+
+unsafe extern "C" fn group_by_member<G: GroupHooks>(
+    _be: *mut NssBackend,
+    args: *mut c_void,
+) -> c_int {
+    let args = &mut *(args as *mut GroupsByMember);
+    let user = match key_name(args.username) {
+        Some(user) => user,
+        None => return NSS_NOTFOUND,
+    };
+    let gids = slice::from_raw_parts_mut(args.gid_array, args.maxgids as usize);
+
+    for group in G::get_all_entries() {
+        if !group.members.contains(&user)
+            || gids[..args.numgids as usize].contains(&group.gid.as_raw())
+        {
+            continue;
+        }
+        if args.numgids == args.maxgids {
+            // The list is full, so there is no point asking any later sources either
+            return NSS_SUCCESS;
+        }
+        gids[args.numgids as usize] = group.gid.as_raw();
+        args.numgids += 1;
+    }
+
+    // Not found lets the sources after this one add their groups too
+    NSS_NOTFOUND
+}
+
+unsafe extern "C" fn shadow_by_name<S: ShadowHooks>(
+    _be: *mut NssBackend,
+    args: *mut c_void,
+) -> c_int {
+    let args = args as *mut XbyYArgs;
+    let entry = key_name((*args).key.name).and_then(S::get_entry_by_name);
+    answer(args, entry)
+}
+
+unsafe extern "C" fn hosts_by_name<H: HostHooks>(_be: *mut NssBackend, args: *mut c_void) -> c_int {
+    let args = args as *mut XbyYArgs;
+    let answer = key_name((*args).key.name)
+        .and_then(|name| {
+            let name = host::normalize_name::<H>(&name)?;
+            Some(H::resolve_host_by_name(&name, AddressFamily::IPv4))
+        })
+        .unwrap_or(HostAnswer::NotFound);
+    answer_host(args, answer)
+}
+
+unsafe extern "C" fn ipnodes_by_name<H: HostHooks>(
+    _be: *mut NssBackend,
+    args: *mut c_void,
+) -> c_int {
+    let args = args as *mut XbyYArgs;
+    let key = (*args).key.ipnode;
+    let answer = key_name(key.name)
+        .and_then(|name| {
+            let name = host::normalize_name::<H>(&name)?;
+            if key.af_family == libc::AF_INET {
+                return Some(H::resolve_host_by_name(&name, AddressFamily::IPv4));
+            }
+            // The frontend maps IPv4 answers itself when the caller asked for that
+            Some(match H::resolve_host_by_name(&name, AddressFamily::IPv6) {
+                HostAnswer::Found(host) => HostAnswer::Found(host),
+                HostAnswer::NoData => match H::resolve_host_by_name(&name, AddressFamily::IPv4) {
+                    HostAnswer::NotFound => HostAnswer::NoData,
+                    answer => answer,
+                },
+                HostAnswer::NotFound => H::resolve_host_by_name(&name, AddressFamily::IPv4),
+            })
+        })
+        .unwrap_or(HostAnswer::NotFound);
+    answer_host(args, answer)
+}
+
+unsafe extern "C" fn host_by_addr<H: HostHooks>(_be: *mut NssBackend, args: *mut c_void) -> c_int {
+    let args = args as *mut XbyYArgs;
+    let key = (*args).key.hostaddr;
+    let addr = match (key.len, key.addr_type) {
+        (4, libc::AF_INET) => {
+            let mut octets = [0u8; 4];
+            ptr::copy_nonoverlapping(key.addr as *const u8, octets.as_mut_ptr(), 4);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (16, libc::AF_INET6) => {
+            let mut octets = [0u8; 16];
+            ptr::copy_nonoverlapping(key.addr as *const u8, octets.as_mut_ptr(), 16);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return answer_host(args, HostAnswer::NotFound),
+    };
+    answer_host(args, H::get_host_by_addr(addr).into())
+}
+
+unsafe fn key_name(name: *const c_char) -> Option<String> {
+    if name.is_null() {
+        return None;
+    }
+    CStr::from_ptr(name).to_str().ok().map(str::to_string)
+}
+
+unsafe fn answer_host(args: *mut XbyYArgs, host: HostAnswer) -> c_int {
+    let h_errno = match host {
+        HostAnswer::NoData => NO_DATA,
+        _ => HOST_NOT_FOUND,
+    };
+    let status = answer(args, host.into_host());
+    if status != NSS_SUCCESS {
+        (*args).h_errno = h_errno;
+    }
+    status
+}
+
+/// Hands an entry back in files format, parsed into the caller's struct if it supplied one.
+unsafe fn answer<T: FilesEntry>(args: *mut XbyYArgs, entry: Option<T>) -> c_int {
+    let args = &mut *args;
+    let line = match entry.and_then(|entry| CString::new(entry.to_line().ok()?).ok()) {
+        Some(line) => line,
+        None => return NSS_NOTFOUND,
+    };
+    let len = line.as_bytes().len();
+    let (c_len, buflen) = match (c_int::try_from(len), usize::try_from(args.buf.buflen)) {
+        (Ok(c_len), Ok(buflen)) => (c_len, buflen),
+        _ => return NSS_UNAVAIL,
+    };
+
+    if args.buf.result.is_null() {
+        // nscd wants the line itself
+        if len >= buflen {
+            args.erange = 1;
+            return NSS_NOTFOUND;
+        }
+        ptr::copy_nonoverlapping(line.as_ptr(), args.buf.buffer, len + 1);
+        args.returnval = args.buf.buffer as *mut c_void;
+        args.returnlen = len;
+        return NSS_SUCCESS;
+    }
+
+    let str2ent = match args.str2ent {
+        Some(str2ent) => str2ent,
+        None => return NSS_UNAVAIL,
+    };
+    match str2ent(
+        line.as_ptr(),
+        c_len,
+        args.buf.result,
+        args.buf.buffer,
+        args.buf.buflen,
+    ) {
+        NSS_STR_PARSE_SUCCESS => {
+            args.returnval = args.buf.result;
+            args.returnlen = len;
+            NSS_SUCCESS
+        }
+        NSS_STR_PARSE_ERANGE => {
+            args.erange = 1;
+            NSS_NOTFOUND
+        }
+        _ => NSS_NOTFOUND,
+    }
+}
@@ -0,0 +1,1002 @@
+//! Serving the last known good entry while a backend is down, so that an outage of the identity
+//! service doesn't fail every login on every machine depending on it.
+//!
+//! A [`StaleCache`] sits behind a backend's lookups. Whatever the backend answers passes through
+//! and is remembered, a definite "no such entry" included. When the backend fails instead, the
+//! entry last read for the same key is answered in its place, as long as it was read within
+//! `max_staleness`; after that the failure gets through, as an entry that stale may well be wrong:
+//!
+//! ```
+//! use std::io;
+//! use std::time::Duration;
+//! use libnss::stale::StaleCache;
+//!
+//! let cache = StaleCache::new(Duration::from_secs(3600));
+//! let read: io::Result<Option<u32>> = Ok(Some(1000));
+//! assert_eq!(cache.resolve("app".to_string(), read).unwrap(), Some(1000));
+//!
+//! // The backend is down: the entry read above is served instead of the failure
+//! let failed = Err(io::Error::new(io::ErrorKind::TimedOut, "backend timed out"));
+//! assert_eq!(cache.resolve("app".to_string(), failed).unwrap(), Some(1000));
+//! ```
+//!
+//! How many entries a cache keeps is up to its [`Eviction`] policy, and [`CacheBudgets`] picks
+//! one per database, as a host's working set of host names is rarely the size of its users'.
+//! The `redis` and `grpc` backends keep a cache per database, bounded by their configs'
+//! `max_staleness` and `eviction`.
+//!
+//! A cache given a [`ttl`](StaleCache::with_ttl) also answers [`lookup`](StaleCache::lookup)s
+//! from entries younger than it without asking the backend, which spares the backend a lookup from
+//! every process on every machine when a fleet restarts at once.
+//!
+//! A cache made with [`StaleCache::persistent`] is also kept in a file, so that a machine fresh
+//! from a reboot, or a process that has never asked, can be answered before the backend is
+//! reachable. Changes are written at most every few seconds, and whatever is left unwritten when
+//! the cache is dropped or the process exits, to a new file that is then renamed over the old, so
+//! readers only ever see a whole cache. Writers hold a lock on `<path>.lock` while they merge their
+//! entries with those other processes wrote, so that none are lost to the last writer. A file
+//! that fails its checksum is set aside with a `.corrupt` suffix and the cache starts empty.
+//! Processes that can't write the file (those not running as root, usually) still read it.
+//!
+//! A cache made with [`StaleCache::shared`] keeps its entries in a [`SharedCache`] segment
+//! rather than in the process, so that every process on the machine shares one copy. The segment
+//! evicts the entries read longest ago once full, whatever the [`Eviction`] policy.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::{TryFrom, TryInto};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::shared::SharedCache;
+use crate::stats::{Database, DATABASES};
+
+/// Which entries a cache drops to make room, once it holds its maximum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eviction {
+    /// No maximum: entries are only dropped once too stale to serve, oldest first
+    Ttl,
+    /// At most this many entries, dropping the one looked up least recently
+    Lru(usize),
+    /// At most this many entries, dropping the one looked up least often (the least recently of
+    /// those, on a tie)
+    Lfu(usize),
+}
+
+/// The eviction policy for each database's cache.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheBudgets {
+    pub passwd: Eviction,
+    pub group: Eviction,
+    pub shadow: Eviction,
+    pub hosts: Eviction,
+}
+
+impl CacheBudgets {
+    /// The same policy for every database.
+    pub fn all(eviction: Eviction) -> Self {
+        CacheBudgets {
+            passwd: eviction,
+            group: eviction,
+            shadow: eviction,
+            hosts: eviction,
+        }
+    }
+
+    pub fn get(&self, database: Database) -> Eviction {
+        match database {
+            Database::Passwd => self.passwd,
+            Database::Group => self.group,
+            Database::Shadow => self.shadow,
+            Database::Hosts => self.hosts,
+        }
+    }
+
+    /// A cache for each database, indexed by `Database as usize`: in the segment
+    /// `<shared>/<database>.shm` if there is a `shared` directory and the segment can be mapped
+    /// (see [`SharedCache::open`] for the segments it refuses), else kept in
+    /// `<dir>/<database>.cache` if there is a `dir`, else only in the process.
+    pub fn caches<K, V>(
+        &self,
+        max_staleness: Duration,
+        dir: Option<&Path>,
+        shared: Option<&Path>,
+    ) -> Vec<StaleCache<K, V>>
+    where
+        K: Clone + Eq + Hash + Persist + Send + 'static,
+        V: Clone + PartialEq + Persist + Send + 'static,
+    {
+        DATABASES
+            .iter()
+            .map(|&database| {
+                // Password hashes are for root's eyes only, as in /etc/shadow
+                let mode = match database {
+                    Database::Shadow => 0o600,
+                    _ => 0o644,
+                };
+                let segment = shared.and_then(|shared| {
+                    let path = shared.join(format!("{}.shm", database.name()));
+                    SharedCache::open(path, mode, SHARED_SIZE).ok()
+                });
+                let eviction = self.get(database);
+                match (segment, dir) {
+                    (Some(segment), _) => StaleCache::shared(segment, max_staleness),
+                    (None, Some(dir)) => {
+                        let path = dir.join(format!("{}.cache", database.name()));
+                        StaleCache::persistent(path, mode, max_staleness, eviction)
+                    }
+                    (None, None) => StaleCache::with_eviction(max_staleness, eviction),
+                }
+            })
+            .collect()
+    }
+}
+
+/// `Ttl` for every database, as a backend's entries are usually few enough to keep them all.
+impl Default for CacheBudgets {
+    fn default() -> Self {
+        CacheBudgets::all(Eviction::Ttl)
+    }
+}
+
+/// A key or value a persistent cache can write to its file.
+pub trait Persist: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Persist for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl Persist for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    /// When the backend last answered with `value`. Wall clock time, as it outlives the process
+    read: SystemTime,
+    /// The entry's key in `State::order`
+    rank: (u64, u64),
+    uses: u64,
+}
+
+type Entries<K, V> = HashMap<K, Entry<V>>;
+
+struct State<K, V> {
+    entries: Entries<K, V>,
+    /// Every key, first to be evicted first
+    order: BTreeMap<(u64, u64), K>,
+    /// Counts lookups, to order them
+    clock: u64,
+    /// When this process last wrote the file
+    saved: Option<Instant>,
+    /// Whether entries changed since, and have yet to be written
+    dirty: bool,
+    /// Whether entries were read again since, without changing
+    refreshed: bool,
+    /// The keys the backend said have no entry since, and when, to take out of the file's
+    removed: HashMap<K, SystemTime>,
+    /// When the entries were last loaded from or written to the file. Those read before then that
+    /// the file no longer has were taken out by another process.
+    synced: Option<SystemTime>,
+}
+
+/// Where a persistent cache keeps its entries.
+struct Disk<K, V> {
+    path: PathBuf,
+    mode: u32,
+    save: fn(&Path, u32, &State<K, V>) -> io::Result<()>,
+    /// Cleared once writing the file is refused
+    writable: Mutex<bool>,
+}
+
+impl<K, V> Disk<K, V> {
+    /// Writes the entries if they changed since last written. Failing to is only worth giving up
+    /// on for the rest of the process.
+    fn write(&self, state: &mut State<K, V>) {
+        let mut writable = self.writable.lock().unwrap();
+        if !*writable {
+            return;
+        }
+        let started = SystemTime::now();
+        match (self.save)(&self.path, self.mode, state) {
+            Ok(()) => {
+                state.saved = Some(Instant::now());
+                state.synced = Some(started);
+                state.dirty = false;
+                state.refreshed = false;
+                state.removed.clear();
+            }
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => *writable = false,
+            Err(_) => {}
+        }
+    }
+}
+
+/// A shared segment holding the entries in place of `State`, and how to encode them for it.
+struct Shared<K, V> {
+    segment: SharedCache,
+    encode_key: fn(&K) -> Vec<u8>,
+    decode_key: fn(&[u8]) -> Option<K>,
+    encode_value: fn(&V) -> Vec<u8>,
+    decode_value: fn(&[u8]) -> Option<V>,
+}
+
+impl<K, V> Shared<K, V> {
+    /// The value stored for `key`, if read within `max_age`.
+    fn get(&self, key: &[u8], max_age: Duration) -> Option<V> {
+        let (value, read) = self.segment.get(key)?;
+        if age(read) > max_age {
+            return None;
+        }
+        (self.decode_value)(&value)
+    }
+}
+
+/// Each database's segment is this big, as a sparse file, so only the pages used take memory.
+pub const SHARED_SIZE: usize = 8 << 20;
+
+pub struct StaleCache<K, V> {
+    /// How long after it was read an entry may still be served; zero disables serving stale
+    max_staleness: Duration,
+    /// How long after it was read an entry is served without asking the backend
+    ttl: Duration,
+    eviction: Eviction,
+    /// Shared with the exit hook, for persistent caches
+    state: Arc<Mutex<State<K, V>>>,
+    disk: Option<Arc<Disk<K, V>>>,
+    shared: Option<Shared<K, V>>,
+}
+
+/// How often entries that were only read again, not changed, are written, to keep their age in the
+/// file current without writing on every lookup.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often changed entries are written, so that a burst of lookups, such as an enumeration
+/// filling the cache, costs one write rather than one per entry.
+const WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+type ExitFlush = Box<dyn Fn() + Send>;
+
+/// The persistent caches to write at exit, which the module's statics never are dropped for.
+static AT_EXIT: Mutex<Vec<ExitFlush>> = Mutex::new(Vec::new());
+
+extern "C" fn flush_at_exit() {
+    // Another thread may be exiting with the list held; its caches are its own to write then
+    if let Ok(flushes) = AT_EXIT.try_lock() {
+        for flush in flushes.iter() {
+            flush();
+        }
+    }
+}
+
+/// Writes `state` at exit if it still has changes then, unless the cache is gone by then.
+fn flush_on_exit<K: Send + 'static, V: Send + 'static>(
+    state: Weak<Mutex<State<K, V>>>,
+    disk: Weak<Disk<K, V>>,
+) {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| unsafe {
+        libc::atexit(flush_at_exit);
+    });
+    let mut flushes = AT_EXIT.lock().unwrap_or_else(|e| e.into_inner());
+    flushes.push(Box::new(move || {
+        if let (Some(state), Some(disk)) = (state.upgrade(), disk.upgrade()) {
+            if let Ok(mut state) = state.try_lock() {
+                if state.dirty {
+                    disk.write(&mut state);
+                }
+            }
+        }
+    }));
+}
+
+impl<K: Clone + Eq + Hash, V: Clone + PartialEq> StaleCache<K, V> {
+    /// A cache evicting only by staleness.
+    pub fn new(max_staleness: Duration) -> Self {
+        StaleCache::with_eviction(max_staleness, Eviction::Ttl)
+    }
+
+    pub fn with_eviction(max_staleness: Duration, eviction: Eviction) -> Self {
+        StaleCache {
+            max_staleness,
+            ttl: Duration::from_secs(0),
+            eviction,
+            state: Arc::new(Mutex::new(State {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                clock: 0,
+                saved: None,
+                dirty: false,
+                refreshed: false,
+                removed: HashMap::new(),
+                synced: None,
+            })),
+            disk: None,
+            shared: None,
+        }
+    }
+
+    /// A cache keeping its entries in `segment`, shared with every other process mapping it.
+    pub fn shared(segment: SharedCache, max_staleness: Duration) -> Self
+    where
+        K: Persist,
+        V: Persist,
+    {
+        let mut cache = StaleCache::new(max_staleness);
+        cache.shared = Some(Shared {
+            segment,
+            encode_key: encode::<K>,
+            decode_key: K::decode,
+            encode_value: encode::<V>,
+            decode_value: V::decode,
+        });
+        cache
+    }
+
+    /// A cache kept in the file at `path`, created with `mode` if need be, starting with the
+    /// entries already there.
+    pub fn persistent(path: PathBuf, mode: u32, max_staleness: Duration, eviction: Eviction) -> Self
+    where
+        K: Persist + Send + 'static,
+        V: Persist + Send + 'static,
+    {
+        let mut cache = StaleCache::with_eviction(max_staleness, eviction);
+        let loading = SystemTime::now();
+        let mut loaded = match load::<K, V>(&path) {
+            Ok(loaded) => loaded,
+            Err(_) => {
+                let mut corrupt = OsString::from(&path);
+                corrupt.push(".corrupt");
+                let _ = fs::rename(&path, corrupt);
+                vec![]
+            }
+        };
+        // Oldest first, so that each entry's place in the eviction order is as if read in turn
+        loaded.sort_by_key(|(_, read, _)| *read);
+        {
+            let mut state = cache.state.lock().unwrap();
+            for (key, read, value) in loaded {
+                if age(read) > max_staleness {
+                    continue;
+                }
+                state.clock += 1;
+                cache.insert(&mut state, key, value, read);
+            }
+            state.synced = Some(loading);
+        }
+        let disk = Arc::new(Disk {
+            path,
+            mode,
+            save: save::<K, V>,
+            writable: Mutex::new(true),
+        });
+        flush_on_exit(Arc::downgrade(&cache.state), Arc::downgrade(&disk));
+        cache.disk = Some(disk);
+        cache
+    }
+
+    /// Answers `lookup`s of entries read within `ttl` from the cache. Only entries kept for serving
+    /// stale are, so `ttl` is bounded by `max_staleness`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Answers from the cache if the entry for `key` was read within `ttl`, or else resolves
+    /// what `fetch` answers from the backend.
+    pub fn lookup<E, F>(&self, key: K, fetch: F) -> Result<Option<V>, E>
+    where
+        F: FnOnce() -> Result<Option<V>, E>,
+    {
+        if let (Some(shared), true) = (&self.shared, self.ttl > Duration::from_secs(0)) {
+            let max_age = self.ttl.min(self.max_staleness);
+            if let Some(value) = shared.get(&(shared.encode_key)(&key), max_age) {
+                return Ok(Some(value));
+            }
+        } else if self.ttl > Duration::from_secs(0) {
+            let mut state = self.state.lock().unwrap();
+            let fresh = match state.entries.get(&key) {
+                Some(entry) => age(entry.read) <= self.ttl.min(self.max_staleness),
+                None => false,
+            };
+            if fresh {
+                state.clock += 1;
+                return Ok(Some(self.touch(&mut state, &key).value.clone()));
+            }
+        }
+        self.resolve(key, fetch())
+    }
+
+    /// Passes on what the backend answered for `key`, remembering it, or answers a failure with
+    /// the entry last read for `key` if that is no older than `max_staleness`.
+    pub fn resolve<E>(&self, key: K, result: Result<Option<V>, E>) -> Result<Option<V>, E> {
+        if self.max_staleness == Duration::from_secs(0) {
+            return result;
+        }
+        // Writing is refused to processes that may only read the segment, which is fine
+        if let Some(shared) = &self.shared {
+            let key = (shared.encode_key)(&key);
+            return match result {
+                Ok(Some(value)) => {
+                    let encoded = (shared.encode_value)(&value);
+                    let _ = shared.segment.insert(&key, &encoded, SystemTime::now());
+                    Ok(Some(value))
+                }
+                Ok(None) => {
+                    let _ = shared.segment.remove(&key);
+                    Ok(None)
+                }
+                Err(err) => match shared.get(&key, self.max_staleness) {
+                    Some(value) => Ok(Some(value)),
+                    None => Err(err),
+                },
+            };
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        match result {
+            Ok(Some(value)) => {
+                let changed = self.insert(&mut state, key, value.clone(), SystemTime::now());
+                self.save(&mut state, changed);
+                Ok(Some(value))
+            }
+            Ok(None) => {
+                let changed = remove(&mut state, &key);
+                // Taken out of the file too, as far as this process knew it was there
+                if changed && self.disk.is_some() {
+                    state.removed.insert(key, SystemTime::now());
+                }
+                self.save(&mut state, changed);
+                Ok(None)
+            }
+            Err(err) => {
+                let fresh = match state.entries.get(&key) {
+                    Some(entry) => age(entry.read) <= self.max_staleness,
+                    None => return Err(err),
+                };
+                if !fresh {
+                    remove(&mut state, &key);
+                    return Err(err);
+                }
+                let value = self.touch(&mut state, &key).value.clone();
+                Ok(Some(value))
+            }
+        }
+    }
+
+    /// The keys that would still be answered from the cache, e.g. to list entries while the
+    /// backend can't be asked for them. Forgets the rest.
+    pub fn keys(&self) -> Vec<K> {
+        if let Some(shared) = &self.shared {
+            return shared
+                .segment
+                .keys()
+                .into_iter()
+                .filter(|key| shared.get(key, self.max_staleness).is_some())
+                .filter_map(|key| (shared.decode_key)(&key))
+                .collect();
+        }
+        let mut state = self.state.lock().unwrap();
+        let stale: Vec<K> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| age(entry.read) > self.max_staleness)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &stale {
+            remove(&mut state, key);
+        }
+        state.entries.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        if let Some(shared) = &self.shared {
+            return shared.segment.len();
+        }
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the entry is new or changed, rather than only read again.
+    fn insert(&self, state: &mut State<K, V>, key: K, value: V, read: SystemTime) -> bool {
+        let (uses, changed) = match state.entries.get(&key) {
+            Some(entry) => (entry.uses + 1, entry.value != value),
+            None => (1, true),
+        };
+        remove(state, &key);
+
+        match self.eviction {
+            // Entries are ordered by when they were read, so the stale ones are at the front
+            Eviction::Ttl => {
+                while let Some((_, oldest)) = state.order.iter().next() {
+                    let oldest = oldest.clone();
+                    if age(state.entries[&oldest].read) <= self.max_staleness {
+                        break;
+                    }
+                    remove(state, &oldest);
+                }
+            }
+            Eviction::Lru(max) | Eviction::Lfu(max) => {
+                if max == 0 {
+                    return false;
+                }
+                while state.entries.len() >= max {
+                    let first = match state.order.keys().next() {
+                        Some(first) => *first,
+                        None => break,
+                    };
+                    let evicted = state.order.remove(&first).unwrap();
+                    state.entries.remove(&evicted);
+                }
+            }
+        }
+
+        let rank = self.rank(state.clock, uses);
+        state.order.insert(rank, key.clone());
+        state.entries.insert(
+            key,
+            Entry {
+                value,
+                read,
+                rank,
+                uses,
+            },
+        );
+        changed
+    }
+
+    /// Writes the file if the entries changed and haven't been written for [`WRITE_INTERVAL`],
+    /// or were read again and haven't been for [`REFRESH_INTERVAL`]. Changes left unwritten are
+    /// written by a later call, [`flush`](Self::flush), or when the cache is dropped or the process
+    /// exits.
+    fn save(&self, state: &mut State<K, V>, changed: bool) {
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return,
+        };
+        state.dirty |= changed;
+        state.refreshed |= !changed;
+        let due = match state.saved {
+            Some(saved) => {
+                let elapsed = saved.elapsed();
+                (state.dirty && elapsed >= WRITE_INTERVAL)
+                    || (state.refreshed && elapsed >= REFRESH_INTERVAL)
+            }
+            None => true,
+        };
+        if due {
+            disk.write(state);
+        }
+    }
+
+    /// Writes the changes not yet written to the file, for a persistent cache.
+    pub fn flush(&self) {
+        if let Some(disk) = &self.disk {
+            let mut state = self.state.lock().unwrap();
+            if state.dirty {
+                disk.write(&mut state);
+            }
+        }
+    }
+
+    /// Counts a lookup of `key`, which must be cached, moving it back in the eviction order.
+    fn touch<'a>(&self, state: &'a mut State<K, V>, key: &K) -> &'a mut Entry<V> {
+        let clock = state.clock;
+        let entry = state.entries.get_mut(key).unwrap();
+        entry.uses += 1;
+        // Ordered by staleness, an entry keeps its place until read again
+        let rank = match self.eviction {
+            Eviction::Ttl => entry.rank,
+            _ => self.rank(clock, entry.uses),
+        };
+        if rank != entry.rank {
+            state.order.remove(&entry.rank);
+            state.order.insert(rank, key.clone());
+            entry.rank = rank;
+        }
+        entry
+    }
+
+    fn rank(&self, clock: u64, uses: u64) -> (u64, u64) {
+        match self.eviction {
+            Eviction::Lfu(_) => (uses, clock),
+            Eviction::Ttl | Eviction::Lru(_) => (0, clock),
+        }
+    }
+}
+
+impl<K, V> Drop for StaleCache<K, V> {
+    fn drop(&mut self) {
+        if let Some(disk) = &self.disk {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.dirty {
+                disk.write(&mut state);
+            }
+        }
+    }
+}
+
+fn encode<T: Persist>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    value.encode(&mut bytes);
+    bytes
+}
+
+/// Returns whether there was an entry to remove.
+fn remove<K: Eq + Hash, V>(state: &mut State<K, V>, key: &K) -> bool {
+    match state.entries.remove(key) {
+        Some(entry) => {
+            state.order.remove(&entry.rank);
+            true
+        }
+        None => false,
+    }
+}
+
+/// How long ago `read` was, or no time at all if the clock has since been set back past it.
+fn age(read: SystemTime) -> Duration {
+    read.elapsed().unwrap_or_default()
+}
+
+/// The file starts with this, then a checksum of the rest, then each entry: when it was read, in
+/// seconds since the epoch, then its key and its value, each prefixed with its length. Integers
+/// are little endian; lengths are 32 bits, and the rest 64.
+const MAGIC: &[u8; 8] = b"libnssc\x01";
+
+/// Writes the entries to the file at `path`, merged under its lock with those already there: the
+/// entry read last wins, keys removed since are dropped unless read again, and entries this
+/// process read before it last synced that the file no longer has stay out of it.
+fn save<K, V>(path: &Path, mode: u32, state: &State<K, V>) -> io::Result<()>
+where
+    K: Persist + Eq + Hash,
+    V: Persist,
+{
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut lock_path = OsString::from(path);
+    lock_path.push(".lock");
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .mode(mode)
+        .open(lock_path)?;
+    lock_exclusive(&lock)?;
+
+    // A corrupt file is as good as none, as it is replaced whole
+    let others: HashMap<K, (SystemTime, V)> = load::<K, V>(path)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, read, value)| (key, (read, value)))
+        .collect();
+    let ours = state
+        .entries
+        .iter()
+        .filter(|(key, entry)| match others.get(key) {
+            Some((theirs, _)) => entry.read >= *theirs,
+            None => state.synced.is_none_or(|synced| entry.read > synced),
+        });
+    let theirs = others.iter().filter(|(key, (read, _))| {
+        let newer = state.entries.get(key).is_none_or(|ours| ours.read < *read);
+        let removed = state.removed.get(key).is_some_and(|at| at >= read);
+        newer && !removed
+    });
+
+    let mut body = Vec::new();
+    let mut field = Vec::new();
+    let ours = ours.map(|(key, entry)| (key, entry.read, &entry.value));
+    let theirs = theirs.map(|(key, (read, value))| (key, *read, value));
+    for (key, read, value) in ours.chain(theirs) {
+        let read = read.duration_since(UNIX_EPOCH).unwrap_or_default();
+        body.extend_from_slice(&read.as_secs().to_le_bytes());
+        key.encode(&mut field);
+        put_field(&mut body, &mut field)?;
+        value.encode(&mut field);
+        put_field(&mut body, &mut field)?;
+    }
+
+    // Private to this process, so that concurrent writers don't write into each other's
+    let mut temporary = OsString::from(path);
+    temporary.push(format!(".{}.tmp", process::id()));
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&temporary)
+        .and_then(|mut file| {
+            file.write_all(MAGIC)?;
+            file.write_all(&checksum(&body).to_le_bytes())?;
+            file.write_all(&body)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temporary, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written
+}
+
+/// Takes `flock` on `file`, which closing it gives up.
+fn lock_exclusive(file: &File) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn put_field(body: &mut Vec<u8>, field: &mut Vec<u8>) -> io::Result<()> {
+    let len = u32::try_from(field.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry too large to cache"))?;
+    body.extend_from_slice(&len.to_le_bytes());
+    body.append(field);
+    Ok(())
+}
+
+/// The entries in the file at `path`, none if there is no file, or an error if it can't be read
+/// or is corrupt.
+fn load<K: Persist, V: Persist>(path: &Path) -> io::Result<Vec<(K, SystemTime, V)>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt cache file");
+
+    let header = MAGIC.len() + 8;
+    if bytes.len() < header || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(corrupt());
+    }
+    let mut body = &bytes[header..];
+    if bytes[MAGIC.len()..header] != checksum(body).to_le_bytes() {
+        return Err(corrupt());
+    }
+
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        let secs = take(&mut body, 8)
+            .and_then(|secs| secs.try_into().ok())
+            .map(u64::from_le_bytes)
+            .ok_or_else(corrupt)?;
+        let key = take_field(&mut body).and_then(K::decode);
+        let value = take_field(&mut body).and_then(V::decode);
+        match (key, value) {
+            (Some(key), Some(value)) => {
+                entries.push((key, UNIX_EPOCH + Duration::from_secs(secs), value))
+            }
+            _ => return Err(corrupt()),
+        }
+    }
+    Ok(entries)
+}
+
+fn take<'a>(body: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if body.len() < len {
+        return None;
+    }
+    let (taken, rest) = body.split_at(len);
+    *body = rest;
+    Some(taken)
+}
+
+fn take_field<'a>(body: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_le_bytes(take(body, 4)?.try_into().ok()?);
+    take(body, len as usize)
+}
+
+/// FNV-1a, which is enough to tell a torn or scribbled over file from a whole one.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("libnss-stale-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("passwd.cache")
+    }
+
+    fn open(path: &Path) -> StaleCache<String, String> {
+        StaleCache::persistent(path.to_path_buf(), 0o600, HOUR, Eviction::Ttl)
+    }
+
+    fn found(cache: &StaleCache<String, String>, key: &str, value: &str) {
+        let read: io::Result<_> = Ok(Some(value.to_string()));
+        cache.resolve(key.to_string(), read).unwrap();
+    }
+
+    fn failed(cache: &StaleCache<String, String>, key: &str) -> io::Result<Option<String>> {
+        let err = io::Error::new(io::ErrorKind::TimedOut, "backend timed out");
+        cache.resolve(key.to_string(), Err(err))
+    }
+
+    /// The keys in the file, sorted.
+    fn stored(path: &Path) -> Vec<String> {
+        let mut keys: Vec<String> = load::<String, String>(path)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn entries_survive_a_new_process() {
+        let path = path("round-trip");
+        let cache = open(&path);
+        found(&cache, "alice", "1000");
+        found(&cache, "bob", "1001");
+        drop(cache);
+
+        let cache = open(&path);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(failed(&cache, "alice").unwrap(), Some("1000".to_string()));
+        assert_eq!(failed(&cache, "bob").unwrap(), Some("1001".to_string()));
+        assert!(failed(&cache, "carol").is_err());
+    }
+
+    #[test]
+    fn changes_are_written_together() {
+        let path = path("batched");
+        let cache = open(&path);
+        found(&cache, "alice", "1000");
+        assert_eq!(stored(&path), ["alice"]);
+
+        // Within the write interval of the first write
+        found(&cache, "bob", "1001");
+        found(&cache, "carol", "1002");
+        assert_eq!(stored(&path), ["alice"]);
+
+        cache.flush();
+        assert_eq!(stored(&path), ["alice", "bob", "carol"]);
+    }
+
+    #[test]
+    fn processes_merge_their_entries() {
+        let path = path("merged");
+        let first = open(&path);
+        let second = open(&path);
+        found(&first, "alice", "1000");
+        found(&second, "bob", "1001");
+        assert_eq!(stored(&path), ["alice", "bob"]);
+
+        found(&first, "carol", "1002");
+        first.flush();
+        assert_eq!(stored(&path), ["alice", "bob", "carol"]);
+
+        // A definite answer that there is no such entry takes it out of the file
+        let fourth = open(&path);
+        let third = open(&path);
+        third
+            .resolve::<io::Error>("alice".to_string(), Ok(None))
+            .unwrap();
+        third.flush();
+        assert_eq!(stored(&path), ["bob", "carol"]);
+
+        // And a process that read it before then doesn't put it back
+        found(&fourth, "dave", "1003");
+        fourth.flush();
+        assert_eq!(stored(&path), ["bob", "carol", "dave"]);
+    }
+
+    #[test]
+    fn corrupt_files_are_set_aside() {
+        let path = path("corrupt");
+        let cache = open(&path);
+        found(&cache, "alice", "1000");
+        drop(cache);
+
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(
+            load::<String, String>(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let cache = open(&path);
+        assert!(cache.is_empty());
+        let mut corrupt = OsString::from(&path);
+        corrupt.push(".corrupt");
+        assert!(Path::new(&corrupt).exists());
+    }
+
+    #[test]
+    fn files_without_the_header_are_corrupt() {
+        let path = path("header");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"libnssc").unwrap();
+        assert!(load::<String, String>(&path).is_err());
+        fs::write(&path, b"not a cache file at all").unwrap();
+        assert!(load::<String, String>(&path).is_err());
+    }
+
+    #[test]
+    fn entries_older_than_max_staleness_are_not_served() {
+        let cache = StaleCache::new(Duration::from_millis(20));
+        found(&cache, "alice", "1000");
+        assert_eq!(failed(&cache, "alice").unwrap(), Some("1000".to_string()));
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(failed(&cache, "alice").is_err());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn entries_older_than_max_staleness_are_not_loaded() {
+        let path = path("stale-file");
+        let read = SystemTime::now() - 2 * HOUR;
+        let cache = StaleCache::<String, String>::new(3 * HOUR);
+        let mut state = cache.state.lock().unwrap();
+        for key in ["alice", "bob"].iter() {
+            state.clock += 1;
+            cache.insert(&mut state, key.to_string(), "1000".to_string(), read);
+        }
+        save(&path, 0o600, &state).unwrap();
+        assert_eq!(stored(&path), ["alice", "bob"]);
+
+        assert!(open(&path).is_empty());
+        let lenient =
+            StaleCache::<String, String>::persistent(path, 0o600, 3 * HOUR, Eviction::Ttl);
+        assert_eq!(lenient.len(), 2);
+    }
+
+    #[test]
+    fn zero_max_staleness_passes_failures_through() {
+        let cache = StaleCache::new(Duration::from_secs(0));
+        found(&cache, "alice", "1000");
+        assert!(failed(&cache, "alice").is_err());
+    }
+
+    #[test]
+    fn untrusted_shared_directories_fall_back_to_the_process() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let shared = path("shared").with_file_name("shm");
+        fs::create_dir_all(&shared).unwrap();
+        let budgets = CacheBudgets::all(Eviction::Ttl);
+        for (mode, mapped) in [(0o777, false), (0o755, true)] {
+            fs::set_permissions(&shared, fs::Permissions::from_mode(mode)).unwrap();
+            let caches = budgets.caches::<String, String>(HOUR, None, Some(&shared));
+            assert!(caches.iter().all(|cache| cache.shared.is_some() == mapped));
+            // Nor are segments created where they would be refused
+            assert_eq!(fs::read_dir(&shared).unwrap().next().is_some(), mapped);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Counters kept in memory for every call NSS makes into the module, to answer "is this module
+//! even being called?" without a metrics daemon or a debugger.
+//!
+//! The functions the `libnss_*_hooks!` macros generate record each call into [`Stats::global`]:
+//! whether it found an entry, found none, failed, or found one too big for the caller's buffer (an
+//! `ERANGE`, which glibc retries with a bigger one), and how long it took. Hooks can read them, or
+//! print them from a debug command built into the same program:
+//!
+//! ```
+//! use libnss::stats::{Database, Stats};
+//!
+//! let passwd = Stats::global().get(Database::Passwd);
+//! assert_eq!(passwd.calls, passwd.hits + passwd.misses + passwd.errors + passwd.erange_retries);
+//! eprint!("{}", Stats::global());
+//! ```
+//!
+//! The counters are per process, and shared by every module built into one library. Lookups made
+//! from Rust, through the generated `lookup_*` functions, aren't counted.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::interop::{self, NssStatus, Shortfall};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Database {
+    Passwd,
+    Group,
+    Shadow,
+    Hosts,
+}
+
+pub(crate) const DATABASES: [Database; 4] = [
+    Database::Passwd,
+    Database::Group,
+    Database::Shadow,
+    Database::Hosts,
+];
+
+impl Database {
+    /// The database as named in `nsswitch.conf`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Database::Passwd => "passwd",
+            Database::Group => "group",
+            Database::Shadow => "shadow",
+            Database::Hosts => "hosts",
+        }
+    }
+}
+
+/// The counters for one database, as read at one moment.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DatabaseStats {
+    pub calls: u64,
+    /// Calls that returned an entry
+    pub hits: u64,
+    /// Calls that found nothing, including enumerations reaching their end
+    pub misses: u64,
+    /// Calls that returned `TRYAGAIN` or `UNAVAIL` other than for a small buffer
+    pub errors: u64,
+    /// Calls that found an entry too big for the caller's buffer
+    pub erange_retries: u64,
+    /// The biggest buffer an entry too big for its caller's needed, in bytes: a size to start
+    /// callers with if retries are frequent
+    pub erange_max_needed: u64,
+    /// The slowest call
+    pub max_latency: Duration,
+}
+
+struct Counters {
+    calls: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+    erange_retries: AtomicU64,
+    erange_max_needed: AtomicU64,
+    /// In nanoseconds
+    max_latency: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            calls: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            erange_retries: AtomicU64::new(0),
+            erange_max_needed: AtomicU64::new(0),
+            max_latency: AtomicU64::new(0),
+        }
+    }
+}
+
+pub struct Stats {
+    databases: [Counters; DATABASES.len()],
+}
+
+static GLOBAL: Stats = Stats::new();
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            databases: [
+                Counters::new(),
+                Counters::new(),
+                Counters::new(),
+                Counters::new(),
+            ],
+        }
+    }
+
+    /// The instance the generated functions record into.
+    pub fn global() -> &'static Stats {
+        &GLOBAL
+    }
+
+    /// Counts a call that returned `status`, which is an `ERANGE` retry if it is `TryAgain` with
+    /// `errno` set to `ERANGE`.
+    pub fn record(&self, database: Database, status: NssStatus, errno: i32, elapsed: Duration) {
+        let counters = &self.databases[database as usize];
+        counters.calls.fetch_add(1, Ordering::Relaxed);
+        let counter = match status {
+            NssStatus::Success => &counters.hits,
+            NssStatus::NotFound | NssStatus::Return => &counters.misses,
+            NssStatus::TryAgain if errno == libc::ERANGE => &counters.erange_retries,
+            NssStatus::TryAgain | NssStatus::Unavail => &counters.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        counters.max_latency.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Notes the size an entry too big for its caller's buffer needed, after [`record`] counted
+    /// the call.
+    ///
+    /// [`record`]: Stats::record
+    pub fn record_shortfall(&self, database: Database, shortfall: Shortfall) {
+        let needed = u64::try_from(shortfall.needed).unwrap_or(u64::MAX);
+        self.databases[database as usize]
+            .erange_max_needed
+            .fetch_max(needed, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, database: Database) -> DatabaseStats {
+        let counters = &self.databases[database as usize];
+        DatabaseStats {
+            calls: counters.calls.load(Ordering::Relaxed),
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            erange_retries: counters.erange_retries.load(Ordering::Relaxed),
+            erange_max_needed: counters.erange_max_needed.load(Ordering::Relaxed),
+            max_latency: Duration::from_nanos(counters.max_latency.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Zeroes every counter, e.g. between two runs of a debug command.
+    pub fn reset(&self) {
+        for counters in &self.databases {
+            counters.calls.store(0, Ordering::Relaxed);
+            counters.hits.store(0, Ordering::Relaxed);
+            counters.misses.store(0, Ordering::Relaxed);
+            counters.errors.store(0, Ordering::Relaxed);
+            counters.erange_retries.store(0, Ordering::Relaxed);
+            counters.erange_max_needed.store(0, Ordering::Relaxed);
+            counters.max_latency.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+/// A table with a line per database, e.g.
+/// `passwd  calls=12 hits=10 misses=2 errors=0 erange=1 erange_max_needed=2048 max_latency=1.2ms`.
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for database in DATABASES {
+            let stats = self.get(database);
+            writeln!(
+                f,
+                "{:<7} calls={} hits={} misses={} errors={} erange={} erange_max_needed={} \
+                 max_latency={:?}",
+                database.name(),
+                stats.calls,
+                stats.hits,
+                stats.misses,
+                stats.errors,
+                stats.erange_retries,
+                stats.erange_max_needed,
+                stats.max_latency
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// One call from NSS into a generated function, timed from its start.
+pub struct Call {
+    database: Database,
+    started: Instant,
+}
+
+impl Call {
+    pub fn start(database: Database) -> Self {
+        Call {
+            database,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records the call into [`Stats::global`] and returns `status` for NSS.
+    pub unsafe fn finish(self, status: NssStatus, errnop: *mut libc::c_int) -> libc::c_int {
+        let errno = if errnop.is_null() { 0 } else { *errnop };
+        let stats = Stats::global();
+        stats.record(self.database, status, errno, self.started.elapsed());
+        if status == NssStatus::TryAgain && errno == libc::ERANGE {
+            if let Some(shortfall) = interop::last_shortfall() {
+                stats.record_shortfall(self.database, shortfall);
+            }
+        }
+        status.to_c()
+    }
+}
@@ -0,0 +1,399 @@
+//! OpenTelemetry spans for lookups, so that a slow login can be traced down to the backend call
+//! that made it slow.
+//!
+//! [`Traced`] wraps any [`NssDatabase`] and hands a [`SpanRecord`] for each hook call to a
+//! [`SpanExporter`]: the database, the operation (named after the libc call, e.g. `getpwnam`), a
+//! hash of the key, what was found and how long it took. Keys are hashed so that user and host
+//! names stay out of the tracing backend while repeated lookups of the same key still line up;
+//! entries are never recorded.
+//!
+//! Spans are encoded as OTLP/JSON, and exporters are configured with the standard `OTEL_*`
+//! environment variables:
+//!
+//! - [`TraceFile`] appends each span to the file named by `LIBNSS_TRACE_FILE`, one export request
+//!   per line, which the OpenTelemetry Collector's `otlpjsonfile` receiver reads. It writes from
+//!   the calling thread and starts none, so it's the one to use in-process.
+//! - [`Otlp`] batches spans on a background thread and POSTs them to an OTLP/HTTP collector at
+//!   `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` (or `OTEL_EXPORTER_OTLP_ENDPOINT` + `/v1/traces`,
+//!   `http://localhost:4318/v1/traces` by default). Use it from
+//!   [`DaemonServer`](crate::daemon::server::DaemonServer), not in-process: the host program
+//!   would gain a thread, and a collector named by host name would be resolved through NSS.
+//!
+//! ```
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! use libnss::database::PasswdDatabase;
+//! use libnss::trace::{Otlp, Traced};
+//!
+//! // Usable anywhere passwd hooks are, e.g. `libnss_passwd_hooks!(example, ExampleHooks)`
+//! type ExampleHooks = Traced<PasswdDatabase<ExamplePasswd>>;
+//! // Or in a daemon, `DaemonServer::new().with_passwd::<DaemonHooks>()`
+//! type DaemonHooks = Traced<PasswdDatabase<ExamplePasswd>, Otlp>;
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::{Condvar, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use ureq::Agent;
+
+use crate::audit::{AuditedEntry, Outcome};
+use crate::database::{Answer, GroupKey, HostKey, NssDatabase, PasswdKey, ShadowKey};
+use crate::group::Group;
+use crate::host::{AddressFamily, Host, NameForm};
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+
+/// An entry type that can be traced, with the libc call that enumerates it.
+pub trait TracedEntry: AuditedEntry {
+    const ENUMERATION: &'static str;
+}
+
+impl TracedEntry for Passwd {
+    const ENUMERATION: &'static str = "getpwent";
+}
+
+impl TracedEntry for Group {
+    const ENUMERATION: &'static str = "getgrent";
+}
+
+impl TracedEntry for Shadow {
+    const ENUMERATION: &'static str = "getspent";
+}
+
+impl TracedEntry for Host {
+    const ENUMERATION: &'static str = "gethostent";
+}
+
+/// A key that can be traced, with the libc call that looks it up.
+pub trait TracedKey: fmt::Display {
+    fn operation(&self) -> &'static str;
+}
+
+impl TracedKey for PasswdKey {
+    fn operation(&self) -> &'static str {
+        match self {
+            PasswdKey::Uid(_) => "getpwuid",
+            PasswdKey::Name(_) | PasswdKey::NameBytes(_) => "getpwnam",
+        }
+    }
+}
+
+impl TracedKey for GroupKey {
+    fn operation(&self) -> &'static str {
+        match self {
+            GroupKey::Gid(_) => "getgrgid",
+            GroupKey::Name(_) | GroupKey::NameBytes(_) => "getgrnam",
+        }
+    }
+}
+
+impl TracedKey for ShadowKey {
+    fn operation(&self) -> &'static str {
+        "getspnam"
+    }
+}
+
+impl TracedKey for HostKey {
+    fn operation(&self) -> &'static str {
+        match self {
+            HostKey::Name(_, AddressFamily::Unspecified)
+            | HostKey::NameBytes(_, AddressFamily::Unspecified) => "gethostbyname",
+            HostKey::Name(..) | HostKey::NameBytes(..) => "gethostbyname2",
+            HostKey::Addr(_) => "gethostbyaddr",
+        }
+    }
+}
+
+/// The first 16 bytes of the SHA-256 of the key as displayed (e.g. `name=alice`), in hex.
+pub fn key_hash(key: &dyn fmt::Display) -> String {
+    hex(&Sha256::digest(key.to_string().as_bytes())[..16])
+}
+
+/// One hook call.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpanRecord {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    /// The database as named in `nsswitch.conf`
+    pub database: &'static str,
+    /// The libc call answered, e.g. `getpwnam`
+    pub operation: &'static str,
+    /// See [`key_hash`]; `None` for an enumeration
+    pub key_hash: Option<String>,
+    pub outcome: Outcome,
+    pub start: SystemTime,
+    pub duration: Duration,
+}
+
+impl SpanRecord {
+    /// The span as an OTLP/JSON `Span`.
+    pub fn to_otlp(&self) -> Value {
+        let start = self
+            .start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let end = start + self.duration.as_nanos();
+
+        let mut attributes = vec![
+            string_attribute("nss.database", self.database),
+            string_attribute("nss.operation", self.operation),
+        ];
+        if let Some(hash) = &self.key_hash {
+            attributes.push(string_attribute("nss.key_hash", hash));
+        }
+        match self.outcome {
+            Outcome::Found => attributes.push(string_attribute("nss.status", "found")),
+            Outcome::NoData => attributes.push(string_attribute("nss.status", "nodata")),
+            Outcome::NotFound => attributes.push(string_attribute("nss.status", "notfound")),
+            Outcome::Entries(count) => {
+                attributes.push(string_attribute("nss.status", "found"));
+                attributes.push(json!({
+                    "key": "nss.entries",
+                    "value": { "intValue": count.to_string() },
+                }));
+            }
+        }
+
+        json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.operation,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            // 64-bit integers are strings in OTLP/JSON
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": attributes,
+            "status": {},
+        })
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `spans` as an OTLP/JSON `ExportTraceServiceRequest`, under the service named by
+/// `OTEL_SERVICE_NAME` (the program's own name by default).
+pub fn export_request(spans: &[SpanRecord]) -> Value {
+    let service = env::var("OTEL_SERVICE_NAME")
+        .ok()
+        .or_else(|| {
+            let exe = env::current_exe().ok()?;
+            Some(exe.file_name()?.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "libnss".to_string());
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [string_attribute("service.name", &service)],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "libnss", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(SpanRecord::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+/// A random id, from freshly keyed hashers as in `retry`.
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    for chunk in id.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_be_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    id
+}
+
+pub trait SpanExporter {
+    fn export(span: SpanRecord);
+}
+
+/// Appends each span to `LIBNSS_TRACE_FILE` as a line of OTLP/JSON, or drops it if that isn't
+/// set or the file can't be opened.
+pub struct TraceFile;
+
+impl SpanExporter for TraceFile {
+    fn export(span: SpanRecord) {
+        let path = match env::var_os("LIBNSS_TRACE_FILE") {
+            Some(path) => path,
+            None => return,
+        };
+        let mut line = export_request(&[span]).to_string();
+        line.push('\n');
+        // One write on an `O_APPEND` file, so that lines from concurrent processes don't mix
+        if let Ok(mut file) = OpenOptions::new().append(true).create(true).open(path) {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Spans waiting to be sent by [`Otlp`], beyond which new ones are dropped.
+const QUEUE_LIMIT: usize = 2048;
+/// The most spans sent in one request.
+const BATCH_SIZE: usize = 512;
+/// How long a span may wait for others to be sent along with it.
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// Queues each span for a background thread that POSTs batches of them to an OTLP/HTTP
+/// collector. A collector that is down or slow costs lookups nothing: spans that don't fit in the
+/// queue, or whose request fails, are dropped.
+pub struct Otlp;
+
+struct Queue {
+    spans: Mutex<Vec<SpanRecord>>,
+    ready: Condvar,
+}
+
+impl Otlp {
+    /// Where spans are sent, from the `OTEL_EXPORTER_OTLP_*` variables.
+    pub fn endpoint() -> String {
+        if let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+            return endpoint;
+        }
+        let base = env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:4318".to_string());
+        format!("{}/v1/traces", base.trim_end_matches('/'))
+    }
+
+    fn queue() -> &'static Queue {
+        static QUEUE: Queue = Queue {
+            spans: Mutex::new(Vec::new()),
+            ready: Condvar::new(),
+        };
+        static SENDER: Once = Once::new();
+        SENDER.call_once(|| {
+            thread::spawn(|| Otlp::send_batches(&QUEUE));
+        });
+        &QUEUE
+    }
+
+    fn send_batches(queue: &Queue) {
+        let endpoint = Otlp::endpoint();
+        let agent: Agent = Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(10)))
+            .build()
+            .new_agent();
+        loop {
+            let batch = {
+                let mut spans = queue.spans.lock().unwrap_or_else(|e| e.into_inner());
+                while spans.is_empty() {
+                    spans = queue.ready.wait(spans).unwrap_or_else(|e| e.into_inner());
+                }
+                if spans.len() < BATCH_SIZE {
+                    spans = queue
+                        .ready
+                        .wait_timeout(spans, BATCH_DELAY)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                }
+                let count = spans.len().min(BATCH_SIZE);
+                spans.drain(..count).collect::<Vec<_>>()
+            };
+            let _ = agent
+                .post(&endpoint)
+                .content_type("application/json")
+                .send(export_request(&batch).to_string());
+        }
+    }
+}
+
+impl SpanExporter for Otlp {
+    fn export(span: SpanRecord) {
+        let queue = Otlp::queue();
+        let mut spans = queue.spans.lock().unwrap_or_else(|e| e.into_inner());
+        if spans.len() < QUEUE_LIMIT {
+            spans.push(span);
+            if spans.len() == 1 || spans.len() == BATCH_SIZE {
+                queue.ready.notify_one();
+            }
+        }
+    }
+}
+
+/// A database that reports a span for every hook call to `E`.
+pub struct Traced<D, E = TraceFile>(PhantomData<(D, E)>);
+
+impl<D, E> Traced<D, E>
+where
+    D: NssDatabase,
+    D::Entry: TracedEntry,
+    E: SpanExporter,
+{
+    fn traced<T>(
+        operation: &'static str,
+        key_hash: Option<String>,
+        call: impl FnOnce() -> T,
+        outcome: impl FnOnce(&T) -> Outcome,
+    ) -> T {
+        let start = SystemTime::now();
+        let started = Instant::now();
+        let result = call();
+        E::export(SpanRecord {
+            trace_id: random_id(),
+            span_id: random_id(),
+            database: D::Entry::DATABASE,
+            operation,
+            key_hash,
+            outcome: outcome(&result),
+            start,
+            duration: started.elapsed(),
+        });
+        result
+    }
+}
+
+impl<D, E> NssDatabase for Traced<D, E>
+where
+    D: NssDatabase,
+    D::Entry: TracedEntry,
+    D::Key: TracedKey,
+    E: SpanExporter,
+{
+    type Entry = D::Entry;
+    type Key = D::Key;
+
+    const ENUMERATION_CACHE_TTL: Duration = D::ENUMERATION_CACHE_TTL;
+    const TRUNCATE_MEMBERS_AT: Option<usize> = D::TRUNCATE_MEMBERS_AT;
+    const RESOLVES_UNSPECIFIED: bool = D::RESOLVES_UNSPECIFIED;
+    const NAME_FORM: NameForm = D::NAME_FORM;
+
+    fn all_entries() -> Vec<D::Entry> {
+        Self::traced(D::Entry::ENUMERATION, None, D::all_entries, |entries| {
+            Outcome::Entries(entries.len())
+        })
+    }
+
+    fn lookup(key: D::Key) -> Answer<D::Entry> {
+        let operation = key.operation();
+        let hash = key_hash(&key);
+        Self::traced(
+            operation,
+            Some(hash),
+            || D::lookup(key),
+            |answer| Outcome::from(answer),
+        )
+    }
+}
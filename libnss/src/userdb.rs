@@ -0,0 +1,552 @@
+//! Serves `PasswdHooks`/`GroupHooks` implementations as a systemd `io.systemd.UserDatabase`
+//! varlink service, so `systemd-userdbd`, `logind` and `nss-systemd` see the same entries as the
+//! NSS module.
+//!
+//! systemd discovers services by their socket in `/run/systemd/userdb`, and the socket file name
+//! must match the service name passed to [`UserDbServer::new`]:
+//!
+//! ```no_run
+//! # use libnss::group::{Group, GroupHooks};
+//! # use libnss::id::{Gid, Uid};
+//! # use libnss::passwd::{Passwd, PasswdHooks};
+//! # struct ExamplePasswd;
+//! # impl PasswdHooks for ExamplePasswd {
+//! #     fn get_all_entries() -> Vec<Passwd> { vec![] }
+//! #     fn get_entry_by_uid(_: Uid) -> Option<Passwd> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Passwd> { None }
+//! # }
+//! # struct ExampleGroup;
+//! # impl GroupHooks for ExampleGroup {
+//! #     fn get_all_entries() -> Vec<Group> { vec![] }
+//! #     fn get_entry_by_gid(_: Gid) -> Option<Group> { None }
+//! #     fn get_entry_by_name(_: String) -> Option<Group> { None }
+//! # }
+//! use libnss::userdb::UserDbServer;
+//!
+//! UserDbServer::<ExamplePasswd, ExampleGroup>::new("io.example.Users")
+//!     .listen("/run/systemd/userdb/io.example.Users")
+//!     .unwrap();
+//! ```
+//!
+//! Lookups are answered on a thread per connection, so the hooks must be thread safe, as they
+//! already are for NSS.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufReader};
+use std::marker::PhantomData;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use serde_json::{json, Map, Value};
+
+use crate::group::{Group, GroupHooks};
+use crate::id::{Gid, Uid};
+use crate::passwd::{Passwd, PasswdHooks};
+use crate::varlink;
+
+const NO_RECORD_FOUND: &str = "io.systemd.UserDatabase.NoRecordFound";
+const CONFLICTING_RECORD_FOUND: &str = "io.systemd.UserDatabase.ConflictingRecordFound";
+const BAD_SERVICE: &str = "io.systemd.UserDatabase.BadService";
+const METHOD_NOT_FOUND: &str = "org.varlink.service.MethodNotFound";
+const INVALID_PARAMETER: &str = "org.varlink.service.InvalidParameter";
+const EXPECTED_MORE: &str = "org.varlink.service.ExpectedMore";
+
+pub struct UserDbServer<P, G> {
+    service: Arc<String>,
+    hooks: PhantomData<fn() -> (P, G)>,
+}
+
+impl<P: PasswdHooks + 'static, G: GroupHooks + 'static> UserDbServer<P, G> {
+    pub fn new(service: &str) -> Self {
+        UserDbServer {
+            service: Arc::new(service.to_string()),
+            hooks: PhantomData,
+        }
+    }
+
+    /// Binds a socket at `path`, replacing any stale one, and serves it forever.
+    pub fn listen<T: AsRef<Path>>(&self, path: T) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        self.serve(UnixListener::bind(path)?)
+    }
+
+    /// Serves an already bound listener (for example one passed in by socket activation).
+    pub fn serve(&self, listener: UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let service = self.service.clone();
+            thread::spawn(move || {
+                let _ = handle_connection::<P, G>(&service, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection<P: PasswdHooks, G: GroupHooks>(
+    service: &str,
+    stream: UnixStream,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    while let Some(request) = varlink::read_message(&mut reader)? {
+        let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+        let parameters = request
+            .get("parameters")
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        let more = request
+            .get("more")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let oneway = request
+            .get("oneway")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+
+        let replies = dispatch::<P, G>(service, method, &parameters, more);
+        if oneway {
+            continue;
+        }
+
+        let mut writer = &stream;
+        match replies {
+            Ok(replies) => {
+                let count = replies.len();
+                for (i, reply) in replies.into_iter().enumerate() {
+                    let mut message = json!({ "parameters": reply });
+                    if i + 1 < count {
+                        message["continues"] = json!(true);
+                    }
+                    varlink::write_message(&mut writer, &message)?;
+                }
+            }
+            Err(error) => {
+                varlink::write_message(&mut writer, &json!({ "error": error, "parameters": {} }))?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Answers one call, returning the parameters of each reply or the name of a varlink error.
+fn dispatch<P: PasswdHooks, G: GroupHooks>(
+    service: &str,
+    method: &str,
+    parameters: &Map<String, Value>,
+    more: bool,
+) -> Result<Vec<Value>, &'static str> {
+    if method == "org.varlink.service.GetInfo" {
+        return Ok(vec![json!({
+            "vendor": "libnss-rs",
+            "product": service,
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": env!("CARGO_PKG_REPOSITORY"),
+            "interfaces": ["io.systemd.UserDatabase", "org.varlink.service"],
+        })]);
+    }
+
+    if parameters.get("service").and_then(Value::as_str) != Some(service) {
+        return Err(BAD_SERVICE);
+    }
+
+    let replies = match method {
+        "io.systemd.UserDatabase.GetUserRecord" => get_user_record::<P>(parameters)?,
+        "io.systemd.UserDatabase.GetGroupRecord" => get_group_record::<G>(parameters)?,
+        "io.systemd.UserDatabase.GetMemberships" => get_memberships::<G>(parameters)?,
+        _ => return Err(METHOD_NOT_FOUND),
+    };
+
+    match replies.len() {
+        0 => Err(NO_RECORD_FOUND),
+        1 => Ok(replies),
+        _ if more => Ok(replies),
+        _ => Err(EXPECTED_MORE),
+    }
+}
+
+fn optional_str<'a>(
+    parameters: &'a Map<String, Value>,
+    field: &str,
+) -> Result<Option<&'a str>, &'static str> {
+    match parameters.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_str().map(Some).ok_or(INVALID_PARAMETER),
+    }
+}
+
+fn optional_id(parameters: &Map<String, Value>, field: &str) -> Result<Option<u32>, &'static str> {
+    match parameters.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .and_then(|id| u32::try_from(id).ok())
+            .map(Some)
+            .ok_or(INVALID_PARAMETER),
+    }
+}
+
+fn get_user_record<P: PasswdHooks>(
+    parameters: &Map<String, Value>,
+) -> Result<Vec<Value>, &'static str> {
+    let name = optional_str(parameters, "userName")?;
+    let uid = optional_id(parameters, "uid")?;
+
+    let entries = match (name, uid) {
+        (None, None) => P::get_all_entries(),
+        (Some(name), _) => P::get_entry_by_name(name.to_string()).into_iter().collect(),
+        (None, Some(uid)) => P::get_entry_by_uid(Uid::from_raw(uid)).into_iter().collect(),
+    };
+
+    if let (Some(uid), Some(entry)) = (uid, entries.first()) {
+        if name.is_some() && entry.uid != uid {
+            return Err(CONFLICTING_RECORD_FOUND);
+        }
+    }
+
+    Ok(entries.iter().map(user_record).collect())
+}
+
+fn get_group_record<G: GroupHooks>(
+    parameters: &Map<String, Value>,
+) -> Result<Vec<Value>, &'static str> {
+    let name = optional_str(parameters, "groupName")?;
+    let gid = optional_id(parameters, "gid")?;
+
+    let entries = match (name, gid) {
+        (None, None) => G::get_all_entries(),
+        (Some(name), _) => G::get_entry_by_name(name.to_string()).into_iter().collect(),
+        (None, Some(gid)) => G::get_entry_by_gid(Gid::from_raw(gid)).into_iter().collect(),
+    };
+
+    if let (Some(gid), Some(entry)) = (gid, entries.first()) {
+        if name.is_some() && entry.gid != gid {
+            return Err(CONFLICTING_RECORD_FOUND);
+        }
+    }
+
+    Ok(entries.iter().map(group_record).collect())
+}
+
+fn get_memberships<G: GroupHooks>(
+    parameters: &Map<String, Value>,
+) -> Result<Vec<Value>, &'static str> {
+    let user = optional_str(parameters, "userName")?;
+    let group = optional_str(parameters, "groupName")?;
+
+    let groups = match group {
+        Some(group) => G::get_entry_by_name(group.to_string())
+            .into_iter()
+            .collect(),
+        None => G::get_all_entries(),
+    };
+
+    Ok(groups
+        .iter()
+        .flat_map(|g| {
+            g.members
+                .iter()
+                .filter(|m| user.is_none_or(|user| user == m.as_str()))
+                .map(move |m| json!({ "userName": m, "groupName": g.name }))
+        })
+        .collect())
+}
+
+fn user_record(entry: &Passwd) -> Value {
+    let mut record = json!({
+        "userName": entry.name,
+        "uid": entry.uid.as_raw(),
+        "gid": entry.gid.as_raw(),
+        "homeDirectory": entry.dir,
+        "shell": entry.shell,
+    });
+    if !entry.gecos.is_empty() {
+        record["realName"] = json!(entry.gecos);
+    }
+
+    json!({ "record": record, "incomplete": false })
+}
+
+fn group_record(entry: &Group) -> Value {
+    json!({
+        "record": {
+            "groupName": entry.name,
+            "gid": entry.gid.as_raw(),
+            "members": entry.members,
+        },
+        "incomplete": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::userdb::{UserDbBackend, UserDbConfig};
+
+    struct Users;
+
+    impl PasswdHooks for Users {
+        fn get_all_entries() -> Vec<Passwd> {
+            vec![user("alice", 1000, "Alice"), user("bob", 1001, "")]
+        }
+
+        fn get_entry_by_uid(uid: Uid) -> Option<Passwd> {
+            Self::get_all_entries().into_iter().find(|u| u.uid == uid)
+        }
+
+        fn get_entry_by_name(name: String) -> Option<Passwd> {
+            Self::get_all_entries().into_iter().find(|u| u.name == name)
+        }
+    }
+
+    struct Groups;
+
+    impl GroupHooks for Groups {
+        fn get_all_entries() -> Vec<Group> {
+            vec![
+                group("wheel", 10, &["alice"]),
+                group("staff", 50, &["alice", "bob"]),
+            ]
+        }
+
+        fn get_entry_by_gid(gid: Gid) -> Option<Group> {
+            Self::get_all_entries().into_iter().find(|g| g.gid == gid)
+        }
+
+        fn get_entry_by_name(name: String) -> Option<Group> {
+            Self::get_all_entries().into_iter().find(|g| g.name == name)
+        }
+    }
+
+    fn user(name: &str, uid: u32, gecos: &str) -> Passwd {
+        Passwd {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(100),
+            gecos: gecos.to_string(),
+            dir: format!("/home/{}", name),
+            shell: "/bin/sh".to_string(),
+        }
+    }
+
+    fn group(name: &str, gid: u32, members: &[&str]) -> Group {
+        Group {
+            name: name.to_string(),
+            name_bytes: None,
+            passwd: "x".to_string(),
+            gid: Gid::from_raw(gid),
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    const SERVICE: &str = "io.example.Users";
+
+    fn call(method: &str, parameters: Value, more: bool) -> Result<Vec<Value>, &'static str> {
+        let mut parameters = parameters.as_object().unwrap().clone();
+        parameters.insert("service".to_string(), json!(SERVICE));
+        dispatch::<Users, Groups>(
+            SERVICE,
+            &format!("io.systemd.UserDatabase.{}", method),
+            &parameters,
+            more,
+        )
+    }
+
+    #[test]
+    fn user_records() {
+        assert_eq!(
+            call("GetUserRecord", json!({ "userName": "alice" }), false),
+            Ok(vec![json!({
+                "record": {
+                    "userName": "alice",
+                    "uid": 1000,
+                    "gid": 100,
+                    "realName": "Alice",
+                    "homeDirectory": "/home/alice",
+                    "shell": "/bin/sh",
+                },
+                "incomplete": false,
+            })])
+        );
+        // No `realName` for an empty gecos
+        let bob = call(
+            "GetUserRecord",
+            json!({ "uid": 1001, "userName": null }),
+            false,
+        )
+        .unwrap();
+        assert_eq!(bob[0]["record"].get("realName"), None);
+        assert_eq!(bob[0]["record"]["userName"], "bob");
+
+        assert_eq!(call("GetUserRecord", json!({}), true).unwrap().len(), 2);
+        assert_eq!(call("GetUserRecord", json!({}), false), Err(EXPECTED_MORE));
+        assert_eq!(
+            call("GetUserRecord", json!({ "userName": "carol" }), false),
+            Err(NO_RECORD_FOUND)
+        );
+        assert_eq!(
+            call(
+                "GetUserRecord",
+                json!({ "userName": "alice", "uid": 1001 }),
+                false
+            ),
+            Err(CONFLICTING_RECORD_FOUND)
+        );
+    }
+
+    #[test]
+    fn group_records_and_memberships() {
+        let wheel = call("GetGroupRecord", json!({ "gid": 10 }), false).unwrap();
+        assert_eq!(
+            wheel,
+            vec![json!({
+                "record": { "groupName": "wheel", "gid": 10, "members": ["alice"] },
+                "incomplete": false,
+            })]
+        );
+        assert_eq!(
+            call(
+                "GetGroupRecord",
+                json!({ "groupName": "wheel", "gid": 50 }),
+                false
+            ),
+            Err(CONFLICTING_RECORD_FOUND)
+        );
+
+        assert_eq!(
+            call("GetMemberships", json!({ "userName": "alice" }), true),
+            Ok(vec![
+                json!({ "userName": "alice", "groupName": "wheel" }),
+                json!({ "userName": "alice", "groupName": "staff" }),
+            ])
+        );
+        assert_eq!(
+            call(
+                "GetMemberships",
+                json!({ "groupName": "staff", "userName": "bob" }),
+                false
+            ),
+            Ok(vec![json!({ "userName": "bob", "groupName": "staff" })])
+        );
+        assert_eq!(
+            call("GetMemberships", json!({ "userName": "carol" }), true),
+            Err(NO_RECORD_FOUND)
+        );
+    }
+
+    #[test]
+    fn invalid_requests() {
+        for parameters in [
+            json!({ "userName": 1000 }),
+            json!({ "uid": "1000" }),
+            json!({ "uid": -1 }),
+            json!({ "uid": 4_294_967_296u64 }),
+        ] {
+            assert_eq!(
+                call("GetUserRecord", parameters.clone(), false),
+                Err(INVALID_PARAMETER),
+                "{}",
+                parameters
+            );
+        }
+        assert_eq!(
+            call("GetGroupRecord", json!({ "gid": 1.5 }), false),
+            Err(INVALID_PARAMETER)
+        );
+        assert_eq!(call("GetPassword", json!({}), false), Err(METHOD_NOT_FOUND));
+
+        // Calls for another service, or none
+        let parameters = json!({ "service": "io.example.Other" });
+        assert_eq!(
+            dispatch::<Users, Groups>(
+                SERVICE,
+                "io.systemd.UserDatabase.GetUserRecord",
+                parameters.as_object().unwrap(),
+                true
+            ),
+            Err(BAD_SERVICE)
+        );
+        assert_eq!(
+            dispatch::<Users, Groups>(
+                SERVICE,
+                "io.systemd.UserDatabase.GetUserRecord",
+                &Map::new(),
+                true
+            ),
+            Err(BAD_SERVICE)
+        );
+        // Which GetInfo doesn't need
+        let info =
+            dispatch::<Users, Groups>(SERVICE, "org.varlink.service.GetInfo", &Map::new(), false)
+                .unwrap();
+        assert_eq!(info[0]["product"], SERVICE);
+    }
+
+    #[test]
+    fn connections() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handler = thread::spawn(move || handle_connection::<Users, Groups>(SERVICE, server));
+        let mut reader = BufReader::new(&client);
+        let mut read = || varlink::read_message(&mut reader).unwrap().unwrap();
+
+        let request = |method: &str, more: bool, oneway: bool| {
+            json!({
+                "method": format!("io.systemd.UserDatabase.{}", method),
+                "parameters": { "service": SERVICE },
+                "more": more,
+                "oneway": oneway,
+            })
+        };
+        // A oneway call is answered with nothing, so the next reply is the next call's
+        varlink::write_message(&mut &client, &request("GetUserRecord", true, true)).unwrap();
+        varlink::write_message(&mut &client, &request("GetUserRecord", true, false)).unwrap();
+        let first = read();
+        assert_eq!(first["continues"], true);
+        assert_eq!(first["parameters"]["record"]["userName"], "alice");
+        let last = read();
+        assert_eq!(last.get("continues"), None);
+        assert_eq!(last["parameters"]["record"]["userName"], "bob");
+
+        varlink::write_message(&mut &client, &request("GetUserRecord", false, false)).unwrap();
+        assert_eq!(read(), json!({ "error": EXPECTED_MORE, "parameters": {} }));
+
+        // Hanging up ends the connection
+        drop(reader);
+        drop(client);
+        handler.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn served_to_the_userdb_backend() {
+        let dir = std::env::temp_dir().join(format!("libnss-userdb-server-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let listener = UnixListener::bind(dir.join(SERVICE)).unwrap();
+        thread::spawn(move || UserDbServer::<Users, Groups>::new(SERVICE).serve(listener));
+
+        let backend = UserDbBackend::new(UserDbConfig {
+            socket_dir: dir.clone(),
+            ..UserDbConfig::default()
+        });
+        let alice = backend.get_passwd_by_name("alice").unwrap();
+        assert_eq!(alice, user("alice", 1000, "Alice"));
+        assert_eq!(
+            backend.get_passwd_by_uid(Uid::from_raw(1001)).unwrap().name,
+            "bob"
+        );
+        assert_eq!(backend.get_all_passwd().len(), 2);
+        assert_eq!(
+            backend.get_group_by_gid(Gid::from_raw(50)),
+            Some(group("staff", 50, &["alice", "bob"]))
+        );
+        assert_eq!(backend.get_passwd_by_name("carol"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
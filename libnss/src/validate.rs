@@ -0,0 +1,122 @@
+//! Checks that an entry's fields survive serialization.
+//!
+//! A NUL byte can't be written into the C structs at all, and a `:` in a passwd field or a `,` in
+//! a group member silently becomes a different entry for anything that re-serializes it (`getent`,
+//! the files format, nscd's cache). The generated hooks and the nscd server skip entries that fail
+//! validation rather than handing them on, and [`crate::files`] refuses to write them.
+
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+
+use crate::group::Group;
+use crate::host::Host;
+use crate::passwd::Passwd;
+use crate::shadow::Shadow;
+
+/// Characters that end a field in every format.
+const ALWAYS: [char; 2] = ['\0', '\n'];
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidField {
+    pub field: &'static str,
+    pub value: String,
+    pub character: char,
+}
+
+impl fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} `{}` contains {:?}, which the entry formats cannot represent",
+            self.field,
+            self.value.escape_debug(),
+            self.character
+        )
+    }
+}
+
+impl Error for InvalidField {}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), InvalidField>;
+
+    fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+}
+
+fn check(field: &'static str, value: &str, separators: &[char]) -> Result<(), InvalidField> {
+    match value
+        .chars()
+        .find(|c| ALWAYS.contains(c) || separators.contains(c))
+    {
+        Some(character) => Err(InvalidField {
+            field,
+            value: value.to_string(),
+            character,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks a name as a caller passed it, which needn't be UTF-8, for the entry returned under it:
+/// the bytes a passwd or group name can't hold are the ASCII ones [`Validate`] rejects.
+pub fn validate_name_bytes(name: &CStr) -> Result<(), InvalidField> {
+    match name.to_bytes().iter().find(|&&b| b == b'\n' || b == b':') {
+        Some(&b) => Err(InvalidField {
+            field: "name",
+            value: name.to_string_lossy().into_owned(),
+            character: char::from(b),
+        }),
+        None => Ok(()),
+    }
+}
+
+impl Validate for Passwd {
+    fn validate(&self) -> Result<(), InvalidField> {
+        check("name", &self.name, &[':'])?;
+        if let Some(name) = &self.name_bytes {
+            validate_name_bytes(name)?;
+        }
+        check("passwd", &self.passwd, &[':'])?;
+        check("gecos", &self.gecos, &[':'])?;
+        check("dir", &self.dir, &[':'])?;
+        check("shell", &self.shell, &[':'])
+    }
+}
+
+impl Validate for Group {
+    fn validate(&self) -> Result<(), InvalidField> {
+        check("name", &self.name, &[':'])?;
+        if let Some(name) = &self.name_bytes {
+            validate_name_bytes(name)?;
+        }
+        check("passwd", &self.passwd, &[':'])?;
+        for member in &self.members {
+            check("member", member, &[':', ','])?;
+        }
+        Ok(())
+    }
+}
+
+impl Validate for Shadow {
+    fn validate(&self) -> Result<(), InvalidField> {
+        check("name", &self.name, &[':'])?;
+        check("passwd", &self.passwd, &[':'])
+    }
+}
+
+impl Validate for Host {
+    fn validate(&self) -> Result<(), InvalidField> {
+        let separators = [' ', '\t', '#'];
+        check("name", &self.name, &separators)?;
+        if let Some(canonical) = &self.canonical_name {
+            check("canonical name", canonical, &separators)?;
+        }
+        for alias in &self.aliases {
+            check("alias", alias, &separators)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! Minimal varlink framing: JSON objects, each terminated by a NUL byte.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+/// Reads the next message, or `None` if the peer closed the connection between messages.
+pub(crate) fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut buf = Vec::new();
+    reader.read_until(0, &mut buf)?;
+    match buf.pop() {
+        None => Ok(None),
+        Some(0) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "varlink connection closed mid-message",
+        )),
+    }
+}
+
+pub(crate) fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let mut buf = serde_json::to_vec(message)?;
+    buf.push(0);
+    writer.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn messages_are_nul_terminated() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &json!({ "method": "a.B" })).unwrap();
+        write_message(&mut buf, &json!({ "parameters": { "s": "x\u{0}y" } })).unwrap();
+        assert_eq!(buf.iter().filter(|&&b| b == 0).count(), 2);
+        assert!(buf.ends_with(b"}\0"));
+
+        let mut reader = &buf[..];
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(json!({ "method": "a.B" }))
+        );
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(json!({ "parameters": { "s": "x\u{0}y" } }))
+        );
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_and_malformed_messages() {
+        let err = read_message(&mut &b"{\"method\": \"a.B\"}"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = read_message(&mut &b"{\"method\": }\0"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_message(&mut &b"not json\0"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
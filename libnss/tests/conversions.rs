@@ -0,0 +1,292 @@
+//! The conversions into C entries, run against buffers laid out for AddressSanitizer to catch any
+//! write past them: heap allocations of exactly the size an entry needs, starting at every offset
+//! from an aligned address, with the padding that aligns its pointer arrays there, and ones a byte
+//! short, which must be refused without being written past. They pass without a sanitizer too, but only catch overruns under one:
+//!
+//! ```bash
+//! RUSTFLAGS=-Zsanitizer=address cargo +nightly test -p libnss --features proptest \
+//!     --test conversions --target x86_64-unknown-linux-gnu
+//! ```
+
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::mem::{self, MaybeUninit};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use libnss::arbitrary;
+use libnss::group::{CGroup, Group};
+use libnss::host::{Addresses, CHost, Host};
+use libnss::id::{Gid, Uid};
+use libnss::interop::{CBlob, CBuffer, Rebase};
+use libnss::passwd::{CPasswd, Passwd};
+use libnss::shadow::{CShadow, Shadow};
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+/// Converts `entry` into exactly sized buffers at every alignment, both in place as the generated
+/// functions do and by copying its blob, and returns the entries read back from each. The pointer
+/// arrays must come out aligned wherever the buffer starts.
+fn convert<T: Clone, C: Rebase>(
+    entry: &T,
+    blob: CBlob<C>,
+    to_c: unsafe fn(T, *mut C, &mut CBuffer),
+    from_c: unsafe fn(&C) -> T,
+) -> Result<Vec<T>, TestCaseError> {
+    let align = mem::align_of::<*mut libc::c_char>();
+    let mut read = Vec::new();
+
+    for offset in 0..align {
+        // Not zeroed, as callers' buffers aren't. Words, so that `offset` is from an aligned address.
+        let mut data = vec![usize::MAX; (offset + blob.len() + align).div_ceil(align)];
+        let buf = unsafe { (data.as_mut_ptr() as *mut libc::c_char).add(offset) };
+        let len = blob.len_at(buf);
+        prop_assert!(len - blob.len() < align);
+        let mut result = MaybeUninit::<C>::zeroed();
+
+        unsafe {
+            let mut buffer = CBuffer::new(buf as *mut libc::c_void, len);
+            to_c(entry.clone(), result.as_mut_ptr(), &mut buffer);
+            prop_assert_eq!(buffer.used(), len);
+            read.push(from_c(&*result.as_ptr()));
+
+            prop_assert!(blob.copy_to(result.as_mut_ptr(), buf, len));
+            read.push(from_c(&*result.as_ptr()));
+        }
+    }
+
+    let len = blob.len();
+    if len > 0 {
+        let mut data = vec![0u8; len - 1].into_boxed_slice();
+        let buf = data.as_mut_ptr() as *mut libc::c_char;
+        let mut result = MaybeUninit::<C>::zeroed();
+
+        let copied = unsafe { blob.copy_to(result.as_mut_ptr(), buf, len - 1) };
+        prop_assert!(!copied, "copied into a buffer a byte short");
+        let panicked = panics(|| unsafe {
+            let mut buffer = CBuffer::new(buf as *mut libc::c_void, len - 1);
+            to_c(entry.clone(), result.as_mut_ptr(), &mut buffer);
+        });
+        prop_assert!(panicked, "converted into a buffer a byte short");
+    }
+    Ok(read)
+}
+
+thread_local! {
+    static EXPECTING_PANIC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether `f` panics, without printing the panic as a failure would.
+fn panics<F: FnOnce()>(f: F) -> bool {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !EXPECTING_PANIC.with(Cell::get) {
+                default(info)
+            }
+        }));
+    });
+
+    EXPECTING_PANIC.with(|expecting| expecting.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    EXPECTING_PANIC.with(|expecting| expecting.set(false));
+    result.is_err()
+}
+
+/// A NUL can't be converted at all, which the generated functions rule out by validating first.
+fn has_nul<'a>(mut fields: impl Iterator<Item = &'a String>) -> bool {
+    fields.any(|field| field.contains('\0'))
+}
+
+unsafe fn string(ptr: *const libc::c_char) -> String {
+    CStr::from_ptr(ptr).to_str().unwrap().to_string()
+}
+
+/// A NULL terminated array, which must be aligned as C reads it.
+unsafe fn strings(mut list: *const *mut libc::c_char) -> Vec<String> {
+    assert!(list.is_aligned(), "misaligned array at {:p}", list);
+    let mut strings = Vec::new();
+    loop {
+        let ptr = list.read();
+        if ptr.is_null() {
+            return strings;
+        }
+        strings.push(string(ptr));
+        list = list.add(1);
+    }
+}
+
+unsafe fn passwd_from_c(c: &CPasswd) -> Passwd {
+    Passwd {
+        name: string(c.name),
+        name_bytes: None,
+        passwd: string(c.passwd),
+        uid: Uid::from_raw(c.uid),
+        gid: Gid::from_raw(c.gid),
+        gecos: string(c.gecos),
+        dir: string(c.dir),
+        shell: string(c.shell),
+    }
+}
+
+unsafe fn group_from_c(c: &CGroup) -> Group {
+    Group {
+        name: string(c.name),
+        name_bytes: None,
+        passwd: string(c.passwd),
+        gid: Gid::from_raw(c.gid),
+        members: strings(c.members),
+    }
+}
+
+unsafe fn shadow_from_c(c: &CShadow) -> Shadow {
+    Shadow {
+        name: string(c.name),
+        passwd: string(c.passwd),
+        last_change: c.last_change,
+        change_min_days: c.change_min_days,
+        change_max_days: c.change_max_days,
+        change_warn_days: c.change_warn_days,
+        change_inactive_days: c.change_inactive_days,
+        expire_date: c.expire_date,
+        reserved: c.reserved,
+    }
+}
+
+/// The hostent as it reads: the canonical name, then the aliases including the original name.
+unsafe fn host_from_c(c: &CHost) -> Host {
+    let mut addresses = Vec::new();
+    let mut list = c.h_addr_list as *const *mut libc::c_char;
+    assert!(list.is_aligned(), "misaligned array at {:p}", list);
+    loop {
+        let ptr = list.read() as *const u8;
+        if ptr.is_null() {
+            break;
+        }
+        addresses.push(std::slice::from_raw_parts(ptr, c.h_length as usize).to_vec());
+        list = list.add(1);
+    }
+
+    let addresses = match c.h_addrtype {
+        libc::AF_INET => Addresses::V4(
+            addresses
+                .iter()
+                .map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+                .collect(),
+        ),
+        _ => Addresses::V6(
+            addresses
+                .iter()
+                .map(|octets| {
+                    let mut bytes = [0u8; 16];
+                    bytes.copy_from_slice(octets);
+                    Ipv6Addr::from(bytes)
+                })
+                .collect(),
+        ),
+    };
+    Host {
+        name: string(c.name),
+        aliases: strings(c.h_aliases),
+        addresses,
+        canonical_name: None,
+    }
+}
+
+fn check_passwd(entry: Passwd) -> Result<(), TestCaseError> {
+    for read in convert(
+        &entry,
+        entry.to_c_blob(),
+        Passwd::to_c_passwd,
+        passwd_from_c,
+    )? {
+        prop_assert_eq!(&read, &entry);
+    }
+    Ok(())
+}
+
+fn check_group(entry: Group) -> Result<(), TestCaseError> {
+    for read in convert(&entry, entry.to_c_blob(), Group::to_c_group, group_from_c)? {
+        prop_assert_eq!(&read, &entry);
+    }
+    Ok(())
+}
+
+fn check_shadow(entry: Shadow) -> Result<(), TestCaseError> {
+    for read in convert(
+        &entry,
+        entry.to_c_blob(),
+        Shadow::to_c_shadow,
+        shadow_from_c,
+    )? {
+        prop_assert_eq!(&read, &entry);
+    }
+    Ok(())
+}
+
+fn check_host(entry: Host) -> Result<(), TestCaseError> {
+    let canonical = entry.canonical_name.as_ref().unwrap_or(&entry.name);
+    for read in convert(&entry, entry.to_c_blob(), Host::to_c_hostent, host_from_c)? {
+        prop_assert_eq!(&read.name, canonical);
+        prop_assert_eq!(&read.addresses, &entry.addresses);
+        for alias in &read.aliases {
+            prop_assert!(alias == &entry.name || entry.aliases.contains(alias));
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn passwd(entry in any::<Passwd>()) {
+        check_passwd(entry)?;
+    }
+
+    #[test]
+    fn adversarial_passwd(entry in arbitrary::adversarial_passwd()) {
+        let fields = [&entry.name, &entry.passwd, &entry.gecos, &entry.dir, &entry.shell];
+        if !has_nul(fields.iter().copied()) {
+            check_passwd(entry)?;
+        }
+    }
+
+    #[test]
+    fn group(entry in any::<Group>()) {
+        check_group(entry)?;
+    }
+
+    #[test]
+    fn adversarial_group(entry in arbitrary::adversarial_group()) {
+        let fields = [&entry.name, &entry.passwd];
+        if !has_nul(fields.iter().copied().chain(&entry.members)) {
+            check_group(entry)?;
+        }
+    }
+
+    #[test]
+    fn shadow(entry in any::<Shadow>()) {
+        check_shadow(entry)?;
+    }
+
+    #[test]
+    fn adversarial_shadow(entry in arbitrary::adversarial_shadow()) {
+        if !has_nul([&entry.name, &entry.passwd].iter().copied()) {
+            check_shadow(entry)?;
+        }
+    }
+
+    #[test]
+    fn host(entry in any::<Host>()) {
+        check_host(entry)?;
+    }
+
+    #[test]
+    fn adversarial_host(entry in arbitrary::adversarial_host()) {
+        let names = [Some(&entry.name), entry.canonical_name.as_ref()];
+        if !has_nul(names.iter().flatten().copied().chain(&entry.aliases)) {
+            check_host(entry)?;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Which pattern a name matches, at the edges: bare domains, several labels deep, and patterns
+//! overlapping one another.
+
+use libnss::host_match::{HostPatterns, PatternError};
+
+fn patterns(patterns: &[&'static str]) -> HostPatterns<&'static str> {
+    let mut table = HostPatterns::new();
+    for pattern in patterns {
+        table.insert(pattern, *pattern).unwrap();
+    }
+    table
+}
+
+#[test]
+fn wildcard_excludes_the_bare_domain() {
+    let table = patterns(&["*.dev.example.com"]);
+    assert_eq!(table.get("dev.example.com"), None);
+    assert_eq!(table.get("app.dev.example.com"), Some(&"*.dev.example.com"));
+}
+
+#[test]
+fn suffix_includes_the_bare_domain() {
+    let table = patterns(&[".dev.example.com"]);
+    assert_eq!(table.get("dev.example.com"), Some(&".dev.example.com"));
+    assert_eq!(table.get("app.dev.example.com"), Some(&".dev.example.com"));
+}
+
+#[test]
+fn wildcard_matches_several_labels_deep() {
+    let table = patterns(&["*.dev.example.com"]);
+    assert_eq!(
+        table.get("a.b.c.dev.example.com"),
+        Some(&"*.dev.example.com")
+    );
+}
+
+#[test]
+fn domains_match_whole_labels_only() {
+    let table = patterns(&["*.example.com", ".example.org"]);
+    assert_eq!(table.get("badexample.com"), None);
+    assert_eq!(table.get("example.com.evil.net"), None);
+    assert_eq!(table.get("notexample.org"), None);
+}
+
+#[test]
+fn case_and_trailing_dot_are_ignored() {
+    let table = patterns(&["*.Dev.Example.com.", "DB.example.com"]);
+    assert_eq!(
+        table.get("APP.dev.example.COM."),
+        Some(&"*.Dev.Example.com.")
+    );
+    assert_eq!(table.get("db.EXAMPLE.com."), Some(&"DB.example.com"));
+}
+
+#[test]
+fn exact_names_win() {
+    let table = patterns(&["*.example.com", ".example.com", "www.example.com"]);
+    assert_eq!(table.get("www.example.com"), Some(&"www.example.com"));
+}
+
+#[test]
+fn longest_domain_wins() {
+    let table = patterns(&[".example.com", "*.dev.example.com", "*.db.dev.example.com"]);
+    assert_eq!(
+        table.get("pg.db.dev.example.com"),
+        Some(&"*.db.dev.example.com")
+    );
+    assert_eq!(table.get("db.dev.example.com"), Some(&"*.dev.example.com"));
+    assert_eq!(table.get("dev.example.com"), Some(&".example.com"));
+}
+
+#[test]
+fn bare_suffix_beats_a_shorter_wildcard() {
+    let table = patterns(&["*.example.com", ".dev.example.com"]);
+    assert_eq!(table.get("dev.example.com"), Some(&".dev.example.com"));
+}
+
+#[test]
+fn wildcard_beats_suffix_for_the_same_domain() {
+    let table = patterns(&[".dev.example.com", "*.dev.example.com"]);
+    assert_eq!(table.get("app.dev.example.com"), Some(&"*.dev.example.com"));
+    assert_eq!(table.get("dev.example.com"), Some(&".dev.example.com"));
+}
+
+#[test]
+fn later_insert_replaces_an_equal_pattern() {
+    let mut table = HostPatterns::new();
+    table.insert("*.example.com", 1).unwrap();
+    table.insert("*.EXAMPLE.com.", 2).unwrap();
+    assert_eq!(table.get("www.example.com"), Some(&2));
+}
+
+#[test]
+fn invalid_names_match_nothing() {
+    let table = patterns(&["*.example.com"]);
+    assert_eq!(table.get(""), None);
+    assert_eq!(table.get(".example.com"), None);
+    assert_eq!(table.get("a..example.com"), None);
+}
+
+#[test]
+fn malformed_patterns_are_refused() {
+    let mut table = HostPatterns::new();
+    for pattern in &[
+        "a.*.example.com",
+        "*example.com",
+        "**.example.com",
+        "*.*.example.com",
+    ] {
+        assert_eq!(
+            table.insert(pattern, ()),
+            Err(PatternError::MisplacedWildcard(pattern.to_string()))
+        );
+    }
+    for pattern in &["", "*.", ".", "-bad.example.com", "a..example.com"] {
+        assert_eq!(
+            table.insert(pattern, ()),
+            Err(PatternError::InvalidName(pattern.to_string()))
+        );
+    }
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn regexes_come_last_in_the_order_added() {
+    let table = patterns(&[
+        r"~^web-\d+\.example\.com$",
+        r"~^web-",
+        "*.internal.example.com",
+    ]);
+    assert_eq!(
+        table.get("WEB-12.example.com."),
+        Some(&r"~^web-\d+\.example\.com$")
+    );
+    assert_eq!(table.get("web-x.example.com"), Some(&"~^web-"));
+    assert_eq!(
+        table.get("web-1.internal.example.com"),
+        Some(&"*.internal.example.com")
+    );
+    assert_eq!(table.get("api.example.com"), None);
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn invalid_regex_is_refused() {
+    let mut table = HostPatterns::new();
+    assert!(matches!(
+        table.insert("~(unclosed", ()),
+        Err(PatternError::InvalidRegex(..))
+    ));
+}
+
+#[cfg(not(feature = "regex"))]
+#[test]
+fn regexes_need_the_feature() {
+    let mut table = HostPatterns::new();
+    assert!(matches!(
+        table.insert("~^web-", ()),
+        Err(PatternError::InvalidRegex(..))
+    ));
+}
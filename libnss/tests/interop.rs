@@ -0,0 +1,804 @@
+//! The conversions into C entries over an `OwnedCBuffer`, small enough to run under Miri, which
+//! checks every pointer they write and follow:
+//!
+//! ```bash
+//! cargo +nightly miri test -p libnss --features testing --test interop
+//! ```
+//!
+//! Rebasing a blob's pointers casts integers back to pointers, which Miri warns about;
+//! `MIRIFLAGS=-Zmiri-permissive-provenance` accepts them quietly.
+
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::mem::{self, MaybeUninit};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroU16;
+use std::ptr;
+
+use libnss::group::{write_group, write_group_as, CGroup, Group};
+use libnss::host::{
+    checked_answer, unsupported_family, write_host_answer, write_hostent, AddressFamily, Addresses,
+    CHost, Host, HostAnswer, HostError,
+};
+use libnss::id::{Gid, Uid};
+use libnss::interop::{
+    checked_size, last_shortfall, ptr_array_size, BorrowedCBuffer, NssStatus, OwnedCBuffer,
+    SizeOverflow,
+};
+use libnss::passwd::{write_passwd, write_passwd_as, CPasswd, Passwd};
+use libnss::service::{CService, Protocol, Service};
+use libnss::shadow::{write_shadow, CShadow, Shadow};
+
+unsafe fn string(ptr: *const libc::c_char) -> String {
+    CStr::from_ptr(ptr).to_str().unwrap().to_string()
+}
+
+/// A NULL terminated array, which must be aligned as C reads it.
+unsafe fn strings(mut list: *const *mut libc::c_char) -> Vec<String> {
+    assert!(list.is_aligned(), "misaligned array at {:p}", list);
+    let mut strings = Vec::new();
+    loop {
+        let ptr = list.read();
+        if ptr.is_null() {
+            return strings;
+        }
+        strings.push(string(ptr));
+        list = list.add(1);
+    }
+}
+
+fn passwd() -> Passwd {
+    Passwd {
+        name: "test".to_string(),
+        name_bytes: None,
+        passwd: "x".to_string(),
+        uid: Uid::from_raw(1005),
+        gid: Gid::from_raw(1005),
+        gecos: "Test Account".to_string(),
+        dir: "/home/test".to_string(),
+        shell: "/bin/bash".to_string(),
+    }
+}
+
+fn group() -> Group {
+    Group {
+        name: "test".to_string(),
+        name_bytes: None,
+        passwd: "x".to_string(),
+        gid: Gid::from_raw(1005),
+        members: vec!["someone".to_string(), "test".to_string()],
+    }
+}
+
+fn host() -> Host {
+    Host {
+        name: "test.example".to_string(),
+        aliases: vec!["test".to_string()],
+        addresses: Addresses::V6(vec![Ipv6Addr::LOCALHOST, Ipv6Addr::UNSPECIFIED]),
+        canonical_name: Some("canonical.example".to_string()),
+    }
+}
+
+#[test]
+fn passwd_fields_point_into_the_buffer() {
+    let mut buffer = OwnedCBuffer::new(128);
+    let mut result = MaybeUninit::<CPasswd>::zeroed();
+    unsafe {
+        passwd().to_c_passwd(result.as_mut_ptr(), &mut buffer);
+        let result = result.assume_init();
+        assert_eq!(string(result.name), "test");
+        assert_eq!(string(result.passwd), "x");
+        assert_eq!(string(result.gecos), "Test Account");
+        assert_eq!(string(result.dir), "/home/test");
+        assert_eq!(string(result.shell), "/bin/bash");
+        assert_eq!((result.uid, result.gid), (1005, 1005));
+    }
+    assert_eq!(buffer.used() + buffer.free(), 128);
+    assert_eq!(
+        &buffer.strings()[..5],
+        ["test", "x", "Test Account", "/home/test", "/bin/bash"]
+    );
+}
+
+#[test]
+fn group_members_are_null_terminated() {
+    let mut buffer = OwnedCBuffer::new(128);
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    unsafe {
+        group().to_c_group(result.as_mut_ptr(), &mut buffer);
+        let result = result.assume_init();
+        assert_eq!(string(result.name), "test");
+        assert_eq!(strings(result.members), ["someone", "test"]);
+    }
+    assert_eq!(buffer.strings(), ["test", "x", "someone", "test"]);
+}
+
+#[test]
+fn group_without_members() {
+    let group = Group {
+        members: Vec::new(),
+        ..group()
+    };
+    let mut buffer = OwnedCBuffer::new(group.to_c_blob().len());
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    unsafe {
+        group.to_c_group(result.as_mut_ptr(), &mut buffer);
+        assert!(strings(result.assume_init().members).is_empty());
+    }
+    assert_eq!(buffer.free(), 0);
+}
+
+#[test]
+fn group_size_is_known_up_front() {
+    let group = group();
+    assert_eq!(group.c_size(), group.to_c_blob().len());
+
+    let mut buffer = OwnedCBuffer::new(group.c_size());
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    unsafe { group.to_c_group(result.as_mut_ptr(), &mut buffer) };
+    assert_eq!(buffer.free(), 0);
+}
+
+#[test]
+fn large_group_is_truncated_only_when_asked() {
+    let group = Group {
+        members: (0..1000).map(|n| format!("member{}", n)).collect(),
+        ..group()
+    };
+    let buflen = 1024;
+    let mut buffer = OwnedCBuffer::new(buflen);
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    let mut errno = 0;
+
+    let status = unsafe {
+        write_group(
+            &group,
+            None,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buflen,
+            &mut errno,
+        )
+    };
+    assert_eq!((status, errno), (NssStatus::TryAgain, libc::ERANGE));
+
+    let status = unsafe {
+        write_group(
+            &group,
+            Some(buflen),
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buflen,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    let members = unsafe { strings(result.assume_init().members) };
+    let truncated = group.truncated_to(buflen).unwrap();
+    assert!(!members.is_empty() && members.len() < 1000);
+    assert_eq!(members, truncated.members);
+    assert!(truncated.c_size() <= buflen);
+    assert!(group.truncated_to(4).is_none());
+}
+
+#[test]
+fn lookups_write_straight_into_an_exactly_sized_buffer() {
+    let passwd = passwd();
+    let len = passwd.to_c_blob().len();
+    let mut buffer = OwnedCBuffer::new(len);
+    let range = buffer.as_mut_ptr() as usize..buffer.as_mut_ptr() as usize + len;
+    let mut result = MaybeUninit::<CPasswd>::zeroed();
+    let mut errno = 0;
+
+    let status = unsafe {
+        write_passwd(
+            &passwd,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            len,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    unsafe {
+        let result = result.assume_init();
+        for field in &[
+            result.name,
+            result.passwd,
+            result.gecos,
+            result.dir,
+            result.shell,
+        ] {
+            assert!(range.contains(&(*field as usize)));
+        }
+        assert_eq!(string(result.passwd), "x");
+        assert_eq!(string(result.shell), "/bin/bash");
+    }
+
+    let shadow = Shadow {
+        name: "test".to_string(),
+        passwd: "$6$salt$hash".to_string(),
+        last_change: -1,
+        change_min_days: -1,
+        change_max_days: -1,
+        change_warn_days: -1,
+        change_inactive_days: -1,
+        expire_date: -1,
+        reserved: u64::MAX,
+    };
+    let len = shadow.to_c_blob().len();
+    let mut result = MaybeUninit::<CShadow>::zeroed();
+    let short = unsafe {
+        write_shadow(
+            &shadow,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            len - 1,
+            &mut errno,
+        )
+    };
+    assert_eq!((short, errno), (NssStatus::TryAgain, libc::ERANGE));
+    let status = unsafe {
+        write_shadow(
+            &shadow,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            len,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    assert_eq!(
+        unsafe { string(result.assume_init().passwd) },
+        "$6$salt$hash"
+    );
+}
+
+#[test]
+fn wiped_secrets_are_empty() {
+    let mut secret = "$6$salt$hash".to_string();
+    libnss::interop::wipe(&mut secret);
+    assert!(secret.is_empty());
+}
+
+#[test]
+fn entries_looked_up_by_non_utf8_names_keep_those_bytes() {
+    let name = CStr::from_bytes_with_nul(b"j\xf6rg\0").unwrap();
+    let mut buffer = OwnedCBuffer::new(128);
+    let mut errno = 0;
+
+    let mut result = MaybeUninit::<CPasswd>::zeroed();
+    let status = unsafe {
+        write_passwd_as(
+            &passwd(),
+            name,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            128,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    unsafe {
+        let result = result.assume_init();
+        assert_eq!(CStr::from_ptr(result.name), name);
+        assert_eq!(string(result.dir), "/home/test");
+        assert_eq!((result.uid, result.gid), (1005, 1005));
+    }
+
+    let group = Group {
+        members: (0..100).map(|n| format!("member{}", n)).collect(),
+        ..group()
+    };
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    let status = unsafe {
+        write_group_as(
+            &group,
+            name,
+            Some(128),
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            128,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    unsafe {
+        let result = result.assume_init();
+        assert_eq!(CStr::from_ptr(result.name), name);
+        assert!(!strings(result.members).is_empty());
+        assert_eq!(result.gid, 1005);
+    }
+}
+
+#[test]
+fn entries_with_non_utf8_names_keep_those_bytes_wherever_found() {
+    let name = CStr::from_bytes_with_nul(b"j\xf6rg\0").unwrap();
+    let mut buffer = OwnedCBuffer::new(128);
+    let mut errno = 0;
+
+    // As `getpwuid_r` writes it
+    let passwd = Passwd {
+        name: "jörg".to_string(),
+        name_bytes: Some(name.to_owned()),
+        ..passwd()
+    };
+    let mut result = MaybeUninit::<CPasswd>::zeroed();
+    let status = unsafe {
+        write_passwd(
+            &passwd,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            128,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    unsafe {
+        let result = result.assume_init();
+        assert_eq!(CStr::from_ptr(result.name), name);
+        assert_eq!(string(result.shell), "/bin/bash");
+    }
+
+    let group = Group {
+        name: "jörg".to_string(),
+        name_bytes: Some(name.to_owned()),
+        ..group()
+    };
+    // Sized for the four latin-1 bytes rather than the five of the UTF-8 name
+    let decoded = Group {
+        name_bytes: None,
+        ..group.clone()
+    };
+    assert_eq!(group.c_size() + 1, decoded.c_size());
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    let status = unsafe {
+        write_group(
+            &group,
+            None,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            128,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    unsafe {
+        let result = result.assume_init();
+        assert_eq!(CStr::from_ptr(result.name), name);
+        assert_eq!(strings(result.members), ["someone", "test"]);
+    }
+}
+
+#[test]
+fn misaligned_buffers_pad_the_arrays_or_get_erange() {
+    let group = group();
+    let mut buffer = OwnedCBuffer::new(group.c_size() + 1);
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    let mut errno = 0;
+    let buf = unsafe { buffer.as_mut_ptr().add(1) };
+
+    // Exactly what an aligned buffer needs is a pointer's padding short
+    let status = unsafe {
+        write_group(
+            &group,
+            None,
+            result.as_mut_ptr(),
+            buf,
+            group.c_size(),
+            &mut errno,
+        )
+    };
+    assert_eq!((status, errno), (NssStatus::TryAgain, libc::ERANGE));
+    let shortfall = last_shortfall().unwrap();
+    assert_eq!(
+        shortfall.needed,
+        group.c_size() + mem::align_of::<*mut libc::c_char>() - 1
+    );
+
+    let mut buffer = OwnedCBuffer::new(shortfall.needed + 1);
+    let buf = unsafe { buffer.as_mut_ptr().add(1) };
+    let status = unsafe {
+        write_group(
+            &group,
+            None,
+            result.as_mut_ptr(),
+            buf,
+            shortfall.needed,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    unsafe {
+        let result = result.assume_init();
+        assert_eq!(strings(result.members), ["someone", "test"]);
+    }
+}
+
+#[test]
+fn small_buffer_reports_the_size_needed() {
+    let group = Group {
+        members: (0..1000).map(|n| format!("member{}", n)).collect(),
+        ..group()
+    };
+    let mut buffer = OwnedCBuffer::new(1024);
+    let mut result = MaybeUninit::<CGroup>::zeroed();
+    let mut errno = 0;
+
+    let status = unsafe {
+        write_group(
+            &group,
+            None,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            1024,
+            &mut errno,
+        )
+    };
+    assert_eq!((status, errno), (NssStatus::TryAgain, libc::ERANGE));
+    let shortfall = last_shortfall().unwrap();
+    assert_eq!(
+        (shortfall.needed, shortfall.available),
+        (group.c_size(), 1024)
+    );
+
+    // One retry with exactly that much is enough
+    let mut buffer = OwnedCBuffer::new(shortfall.needed);
+    let status = unsafe {
+        write_group(
+            &group,
+            None,
+            result.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            shortfall.needed,
+            &mut errno,
+        )
+    };
+    assert_eq!(status, NssStatus::Success);
+    assert_eq!(last_shortfall(), None);
+    assert_eq!(unsafe { strings(result.assume_init().members) }.len(), 1000);
+}
+
+#[test]
+fn entries_need_no_zeroed_buffer() {
+    // Whatever a previous, bigger entry left behind
+    let mut memory = [0xffu8; 256];
+    let mut buffer = BorrowedCBuffer::new(&mut memory);
+    let mut group_result = MaybeUninit::<CGroup>::zeroed();
+    let mut host_result = MaybeUninit::<CHost>::zeroed();
+    unsafe {
+        group().to_c_group(group_result.as_mut_ptr(), &mut buffer);
+        host().to_c_hostent(host_result.as_mut_ptr(), &mut buffer);
+        let (group_result, host_result) = (group_result.assume_init(), host_result.assume_init());
+        assert_eq!(buffer.read_str(group_result.name), group().name);
+        assert_eq!(buffer.read_strs(group_result.members), group().members);
+        assert_eq!(buffer.read_str(host_result.name), "canonical.example");
+        assert_eq!(
+            buffer.read_strs(host_result.h_aliases),
+            ["test.example", "test"]
+        );
+        assert!(host_result.h_addr_list.add(2).read_unaligned().is_null());
+    }
+}
+
+#[test]
+fn shadow_fields_point_into_the_buffer() {
+    let shadow = Shadow {
+        name: "test".to_string(),
+        passwd: "!".to_string(),
+        last_change: 19000,
+        change_min_days: 0,
+        change_max_days: 99999,
+        change_warn_days: 7,
+        change_inactive_days: -1,
+        expire_date: -1,
+        reserved: u64::MAX,
+    };
+    let mut buffer = OwnedCBuffer::new(7);
+    let mut result = MaybeUninit::<CShadow>::zeroed();
+    unsafe {
+        shadow.to_c_shadow(result.as_mut_ptr(), &mut buffer);
+        let result = result.assume_init();
+        assert_eq!(string(result.name), "test");
+        assert_eq!(string(result.passwd), "!");
+        assert_eq!(result.change_max_days, 99999);
+    }
+    assert_eq!(buffer.bytes(), b"test\0!\0");
+}
+
+#[test]
+fn hostent_lists_aliases_and_addresses() {
+    let mut buffer = OwnedCBuffer::new(256);
+    let mut result = MaybeUninit::<CHost>::zeroed();
+    unsafe {
+        host().to_c_hostent(result.as_mut_ptr(), &mut buffer);
+        let result = result.assume_init();
+        assert_eq!(string(result.name), "canonical.example");
+        assert_eq!(strings(result.h_aliases), ["test.example", "test"]);
+        assert_eq!(result.h_addrtype, libc::AF_INET6);
+
+        let addresses = result.h_addr_list;
+        assert!(addresses.is_aligned());
+        let first = addresses.read() as *const [u8; 16];
+        let second = addresses.add(1).read() as *const [u8; 16];
+        assert_eq!(first.read_unaligned(), Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(second.read_unaligned(), Ipv6Addr::UNSPECIFIED.octets());
+        assert!(addresses.add(2).read().is_null());
+    }
+}
+
+#[test]
+fn addresses_are_collected_by_family() {
+    let v4 = Ipv4Addr::new(10, 0, 0, 1);
+    let v6 = Ipv6Addr::LOCALHOST;
+
+    let addresses: Addresses = vec![IpAddr::V4(v4), IpAddr::V4(v4)].into_iter().collect();
+    assert_eq!(addresses, Addresses::V4(vec![v4, v4]));
+    let addresses: Addresses = Some(IpAddr::V6(v6)).into_iter().collect();
+    assert_eq!(addresses, Addresses::V6(vec![v6]));
+
+    // Both families are kept, in order
+    let mixed = vec![IpAddr::V6(v6), IpAddr::V4(v4), IpAddr::V6(v6)];
+    let addresses: Addresses = mixed.iter().copied().collect();
+    assert_eq!(addresses, Addresses::Mixed(mixed.clone()));
+    assert_eq!(Addresses::from(mixed.clone()), addresses);
+    assert_eq!(addresses.len(), 3);
+    assert_eq!(addresses.to_vec(), mixed);
+
+    assert_eq!(
+        Addresses::try_from("::1, 10.0.0.1"),
+        Ok(Addresses::Mixed(vec![IpAddr::V6(v6), IpAddr::V4(v4)]))
+    );
+    assert_eq!(Addresses::try_from(" "), Err(HostError::NoAddresses));
+}
+
+#[test]
+fn mixed_hosts_answer_the_family_asked_for() {
+    let v4 = Ipv4Addr::new(10, 0, 0, 1);
+    let mixed = Host {
+        addresses: vec![IpAddr::V6(Ipv6Addr::LOCALHOST), IpAddr::V4(v4)]
+            .into_iter()
+            .collect(),
+        ..host()
+    };
+    let answer = |family| match checked_answer(HostAnswer::Found(mixed.clone()), family) {
+        HostAnswer::Found(host) => Some(host.addresses),
+        _ => None,
+    };
+    assert_eq!(answer(AddressFamily::IPv4), Some(v4.into()));
+    assert_eq!(
+        answer(AddressFamily::IPv6),
+        Some(Ipv6Addr::LOCALHOST.into())
+    );
+    assert_eq!(answer(AddressFamily::Unspecified), Some(v4.into()));
+    assert_eq!(
+        checked_answer(HostAnswer::Found(host()), AddressFamily::IPv4),
+        HostAnswer::NoData
+    );
+
+    let families: Vec<Addresses> = mixed.families().into_iter().map(|h| h.addresses).collect();
+    assert_eq!(families, [v4.into(), Ipv6Addr::LOCALHOST.into()]);
+}
+
+#[test]
+fn mixed_hostents_map_ipv4_into_ipv6() {
+    let mut buffer = OwnedCBuffer::new(256);
+    let mut result = MaybeUninit::<CHost>::zeroed();
+    let v4 = Ipv4Addr::new(10, 0, 0, 1);
+    let host = Host {
+        addresses: Addresses::Mixed(vec![IpAddr::V4(v4), IpAddr::V6(Ipv6Addr::LOCALHOST)]),
+        ..host()
+    };
+    unsafe {
+        host.to_c_hostent(result.as_mut_ptr(), &mut buffer);
+        let result = result.assume_init();
+        assert_eq!((result.h_addrtype, result.h_length), (libc::AF_INET6, 16));
+
+        let addresses = result.h_addr_list;
+        let first = addresses.read() as *const [u8; 16];
+        let second = addresses.add(1).read() as *const [u8; 16];
+        assert_eq!(first.read_unaligned(), v4.to_ipv6_mapped().octets());
+        assert_eq!(second.read_unaligned(), Ipv6Addr::LOCALHOST.octets());
+        assert!(addresses.add(2).read().is_null());
+    }
+}
+
+#[test]
+fn missing_hosts_say_why_in_h_errno() {
+    let mut buf = [0 as libc::c_char; 256];
+    let mut result = MaybeUninit::<CHost>::zeroed();
+    let (mut errno, mut herrno) = (0, 0);
+    let mut answer = |answer| unsafe {
+        let status = write_host_answer(
+            &answer,
+            result.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut errno,
+            &mut herrno,
+        );
+        (status, herrno)
+    };
+    // <netdb.h>'s NO_DATA and HOST_NOT_FOUND
+    assert_eq!(answer(HostAnswer::NoData), (NssStatus::NotFound, 4));
+    assert_eq!(answer(HostAnswer::NotFound), (NssStatus::NotFound, 1));
+    assert_eq!(answer(HostAnswer::Found(host())).0, NssStatus::Success);
+}
+
+#[test]
+fn unsupported_families_say_so_in_errno() {
+    let (mut errno, mut herrno) = (0, 0);
+    let status = unsafe { unsupported_family(&mut errno, &mut herrno) };
+    // <netdb.h>'s NETDB_INTERNAL: see errno
+    assert_eq!(
+        (status, errno, herrno),
+        (NssStatus::NotFound, libc::EAFNOSUPPORT, -1)
+    );
+    let status = unsafe { unsupported_family(ptr::null_mut(), ptr::null_mut()) };
+    assert_eq!(status, NssStatus::NotFound);
+}
+
+#[test]
+fn hosts_that_cannot_be_hostents_are_unavailable() {
+    let mut buf = [0 as libc::c_char; 1024];
+    let mut result = MaybeUninit::<CHost>::zeroed();
+    let (mut errno, mut herrno) = (0, 0);
+    let mut write = |host: Host| unsafe {
+        let status = write_hostent(
+            &host,
+            result.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut errno,
+            &mut herrno,
+        );
+        (status, herrno)
+    };
+    // <netdb.h>'s NO_RECOVERY
+    let unavailable = (NssStatus::Unavail, 3);
+    assert_eq!(
+        write(Host {
+            addresses: Addresses::V4(vec![]),
+            ..host()
+        }),
+        unavailable
+    );
+    assert_eq!(
+        write(Host {
+            name: format!("{}.example", "a".repeat(250)),
+            ..host()
+        }),
+        unavailable
+    );
+    assert_eq!(
+        write(Host {
+            aliases: vec!["test".to_string(), "test".to_string()],
+            ..host()
+        }),
+        unavailable
+    );
+    assert_eq!(write(host()).0, NssStatus::Success);
+
+    assert_eq!(
+        Host {
+            aliases: vec!["test".to_string(), "test".to_string()],
+            ..host()
+        }
+        .validate_hostent(),
+        Err(HostError::DuplicateAlias("test".to_string()))
+    );
+}
+
+#[test]
+fn null_pointers_are_invalid_arguments() {
+    let mut buf = [0 as libc::c_char; 256];
+    let mut result = MaybeUninit::<CPasswd>::zeroed();
+    let mut errno = 0;
+    let blob = passwd().to_c_blob();
+    unsafe {
+        let status = blob.write_result(ptr::null_mut(), buf.as_mut_ptr(), buf.len(), &mut errno);
+        assert_eq!((status, errno), (NssStatus::Unavail, libc::EINVAL));
+
+        errno = 0;
+        let status = blob.write_result(result.as_mut_ptr(), ptr::null_mut(), 0, &mut errno);
+        assert_eq!((status, errno), (NssStatus::Unavail, libc::EINVAL));
+        assert_eq!(last_shortfall(), None);
+    }
+
+    let (mut errno, mut herrno) = (0, 0);
+    let status = unsafe {
+        write_hostent(
+            &host(),
+            ptr::null_mut(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut errno,
+            &mut herrno,
+        )
+    };
+    // <netdb.h>'s NETDB_INTERNAL: see errno
+    assert_eq!(
+        (status, errno, herrno),
+        (NssStatus::Unavail, libc::EINVAL, -1)
+    );
+}
+
+#[test]
+fn servent_port_is_in_network_byte_order() {
+    let service = Service::builder()
+        .name("https")
+        .port(NonZeroU16::new(443).unwrap())
+        .protocol(Protocol::Tcp)
+        .alias("ssl")
+        .build()
+        .unwrap();
+    let mut buffer = OwnedCBuffer::new(64);
+    let mut result = MaybeUninit::<CService>::zeroed();
+    unsafe {
+        service.to_c_servent(result.as_mut_ptr(), &mut buffer);
+        let result = result.assume_init();
+        assert_eq!(string(result.s_name), "https");
+        assert_eq!(strings(result.s_aliases), ["ssl"]);
+        assert_eq!(string(result.s_proto), "tcp");
+        // What ntohs gives back, whatever this host's byte order
+        assert_eq!(u16::from_be(result.s_port as u16), 443);
+        assert_eq!((result.s_port as u16).to_ne_bytes(), [0x01, 0xbb]);
+    }
+}
+
+#[test]
+fn blob_copies_point_into_the_new_buffer() {
+    let blob = host().to_c_blob();
+    let mut buffer = OwnedCBuffer::new(blob.len());
+    let mut result = MaybeUninit::<CHost>::zeroed();
+    unsafe {
+        assert!(blob.copy_to(result.as_mut_ptr(), buffer.as_mut_ptr(), blob.len()));
+        let result = result.assume_init();
+
+        let range = buffer.as_mut_ptr() as usize..buffer.as_mut_ptr() as usize + blob.len();
+        assert!(range.contains(&(result.name as usize)));
+        assert!(range.contains(&(result.h_aliases as usize)));
+        assert_eq!(string(result.name), "canonical.example");
+        assert_eq!(strings(result.h_aliases), ["test.example", "test"]);
+    }
+    drop(blob);
+
+    let blob = passwd().to_c_blob();
+    let mut result = MaybeUninit::<CPasswd>::zeroed();
+    unsafe {
+        assert!(!blob.copy_to(result.as_mut_ptr(), buffer.as_mut_ptr(), blob.len() - 1));
+    }
+}
+
+#[test]
+fn sizes_too_big_for_any_buffer_are_errors() {
+    let ptr_size = mem::size_of::<*mut libc::c_char>();
+    assert_eq!(checked_size(vec![3, 4]), Ok(7));
+    assert_eq!(checked_size(vec![usize::MAX, 1]), Err(SizeOverflow));
+    assert_eq!(
+        checked_size(vec![isize::MAX as usize, 1]),
+        Err(SizeOverflow)
+    );
+    assert_eq!(ptr_array_size(2), Ok(3 * ptr_size));
+    assert_eq!(ptr_array_size(usize::MAX / ptr_size), Err(SizeOverflow));
+    assert_eq!(ptr_array_size(usize::MAX), Err(SizeOverflow));
+    assert_eq!(SizeOverflow.status(), NssStatus::Unavail);
+}
+
+#[test]
+#[should_panic(expected = "Negative length reserved in buffer")]
+fn negative_reservation_panics_rather_than_wrapping() {
+    let mut buffer = OwnedCBuffer::new(16);
+    unsafe {
+        buffer.reserve(-8);
+    }
+}
+
+#[test]
+#[should_panic(expected = "Not enough free space in buffer")]
+fn full_buffer_panics_rather_than_overflowing() {
+    let mut buffer = OwnedCBuffer::new(4);
+    unsafe {
+        buffer.write_str("test".to_string());
+    }
+}
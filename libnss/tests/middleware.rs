@@ -0,0 +1,280 @@
+//! Hooks seen through middleware answer as they do on their own, whichever of their methods they
+//! implement, and keep the settings their associated consts make.
+
+use std::ffi::{CStr, CString};
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use libnss::audit::{AuditRecord, AuditSink, Audited};
+use libnss::coalesce::Coalesced;
+use libnss::combine::Combined;
+use libnss::context::CallContext;
+use libnss::database::{GroupDatabase, HostDatabase, PasswdDatabase};
+use libnss::group::{Group, GroupHooks};
+use libnss::host::{AddressFamily, Host, HostAnswer, HostHooks, NameForm};
+use libnss::id::{Gid, Uid};
+use libnss::passwd::{Passwd, PasswdHooks};
+use libnss::privileged::{Privileged, PrivilegedOnly};
+use libnss::ranges::{IdRanges, InRange};
+
+/// Implements only the resolving methods, knowing `known.example` with only an IPv4 address, and
+/// `café.example` only by its latin-1 bytes.
+struct ResolveOnly;
+
+impl HostHooks for ResolveOnly {
+    fn resolve_host_by_name(name: &str, family: AddressFamily) -> HostAnswer {
+        match (name, family) {
+            ("known.example", AddressFamily::IPv4) => HostAnswer::Found(host(name)),
+            ("known.example", _) => HostAnswer::NoData,
+            _ => HostAnswer::NotFound,
+        }
+    }
+
+    fn resolve_host_by_name_bytes(name: &CStr, _family: AddressFamily) -> HostAnswer {
+        match name.to_bytes() {
+            b"caf\xe9.example" => HostAnswer::Found(host("xn--caf-dma.example")),
+            _ => HostAnswer::NotFound,
+        }
+    }
+}
+
+/// Knows nothing.
+struct Empty;
+
+impl HostHooks for Empty {}
+
+fn host(name: &str) -> Host {
+    Host::builder()
+        .name(name)
+        .address(Ipv4Addr::LOCALHOST)
+        .build()
+        .unwrap()
+}
+
+fn answers_as_resolve_only<H: HostHooks>() {
+    assert_eq!(
+        H::resolve_host_by_name("known.example", AddressFamily::IPv4),
+        HostAnswer::Found(host("known.example"))
+    );
+    assert_eq!(
+        H::resolve_host_by_name("known.example", AddressFamily::IPv6),
+        HostAnswer::NoData
+    );
+    assert_eq!(
+        H::resolve_host_by_name("unknown.example", AddressFamily::IPv4),
+        HostAnswer::NotFound
+    );
+
+    let latin1 = CStr::from_bytes_with_nul(b"caf\xe9.example\0").unwrap();
+    assert_eq!(
+        H::resolve_host_by_name_bytes(latin1, AddressFamily::IPv4),
+        HostAnswer::Found(host("xn--caf-dma.example"))
+    );
+}
+
+#[test]
+fn coalesced_resolve_only_hooks_keep_their_answers() {
+    answers_as_resolve_only::<ResolveOnly>();
+    answers_as_resolve_only::<Coalesced<HostDatabase<ResolveOnly>>>();
+}
+
+#[test]
+fn combined_resolve_only_hooks_keep_their_answers() {
+    answers_as_resolve_only::<Combined<HostDatabase<ResolveOnly>, HostDatabase<Empty>>>();
+    answers_as_resolve_only::<Combined<HostDatabase<Empty>, HostDatabase<ResolveOnly>>>();
+}
+
+/// Knows the user and group `jörg` only by their latin-1 bytes.
+struct Latin1;
+
+impl PasswdHooks for Latin1 {
+    fn get_entry_by_name_bytes(name: &CStr) -> Option<Passwd> {
+        match name.to_bytes() {
+            b"j\xf6rg" => Some(user()),
+            _ => None,
+        }
+    }
+}
+
+impl GroupHooks for Latin1 {
+    fn get_entry_by_name_bytes(name: &CStr) -> Option<Group> {
+        match name.to_bytes() {
+            b"j\xf6rg" => Some(group()),
+            _ => None,
+        }
+    }
+}
+
+impl PasswdHooks for Empty {}
+
+fn user() -> Passwd {
+    Passwd {
+        name: "jörg".to_string(),
+        name_bytes: Some(CString::new(*b"j\xf6rg").unwrap()),
+        passwd: "x".to_string(),
+        uid: Uid::from_raw(70001),
+        gid: Gid::from_raw(70001),
+        gecos: String::new(),
+        dir: "/home/joerg".to_string(),
+        shell: "/bin/sh".to_string(),
+    }
+}
+
+fn group() -> Group {
+    Group {
+        name: "jörg".to_string(),
+        name_bytes: Some(CString::new(*b"j\xf6rg").unwrap()),
+        passwd: "x".to_string(),
+        gid: Gid::from_raw(70001),
+        members: vec!["jörg".to_string()],
+    }
+}
+
+/// Owns the ids `Latin1`'s entries have.
+struct Directory;
+
+impl IdRanges for Directory {
+    const UIDS: &'static [RangeInclusive<u32>] = &[70000..=79999];
+    const GIDS: &'static [RangeInclusive<u32>] = &[70000..=79999];
+}
+
+/// Lets every caller through, so that the tests needn't run as root.
+struct Anyone;
+
+impl Privileged for Anyone {
+    fn allows(_: &CallContext) -> bool {
+        true
+    }
+}
+
+fn answers_as_latin1<P: PasswdHooks, G: GroupHooks>() {
+    let latin1 = CStr::from_bytes_with_nul(b"j\xf6rg\0").unwrap();
+    assert_eq!(P::get_entry_by_name_bytes(latin1), Some(user()));
+    assert_eq!(G::get_entry_by_name_bytes(latin1), Some(group()));
+
+    let unknown = CStr::from_bytes_with_nul(b"\xe9mile\0").unwrap();
+    assert_eq!(P::get_entry_by_name_bytes(unknown), None);
+    assert_eq!(G::get_entry_by_name_bytes(unknown), None);
+}
+
+#[test]
+fn middleware_keeps_latin1_user_and_group_names() {
+    answers_as_latin1::<Latin1, Latin1>();
+    answers_as_latin1::<Coalesced<PasswdDatabase<Latin1>>, Coalesced<GroupDatabase<Latin1>>>();
+    answers_as_latin1::<
+        InRange<PasswdDatabase<Latin1>, Directory>,
+        InRange<GroupDatabase<Latin1>, Directory>,
+    >();
+    answers_as_latin1::<
+        Combined<PasswdDatabase<Empty>, PasswdDatabase<Latin1>>,
+        Combined<GroupDatabase<Empty>, GroupDatabase<Latin1>>,
+    >();
+    answers_as_latin1::<
+        PrivilegedOnly<PasswdDatabase<Latin1>, Anyone>,
+        PrivilegedOnly<GroupDatabase<Latin1>, Anyone>,
+    >();
+    answers_as_latin1::<
+        Audited<PasswdDatabase<Latin1>, Discard>,
+        Audited<GroupDatabase<Latin1>, Discard>,
+    >();
+}
+
+#[cfg(feature = "opentelemetry")]
+#[test]
+fn traced_hooks_keep_latin1_user_and_group_names() {
+    use libnss::trace::{SpanExporter, SpanRecord, Traced};
+
+    struct Dropped;
+
+    impl SpanExporter for Dropped {
+        fn export(_: SpanRecord) {}
+    }
+
+    answers_as_latin1::<
+        Traced<PasswdDatabase<Latin1>, Dropped>,
+        Traced<GroupDatabase<Latin1>, Dropped>,
+    >();
+}
+
+/// Sets every setting its hooks traits have away from the defaults.
+struct Configured;
+
+impl HostHooks for Configured {
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(30);
+    const RESOLVES_UNSPECIFIED: bool = true;
+    const NAME_FORM: NameForm = NameForm::Folded;
+}
+
+impl GroupHooks for Configured {
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(30);
+    const TRUNCATE_MEMBERS_AT: Option<usize> = Some(4096);
+}
+
+impl PasswdHooks for Configured {
+    const ENUMERATION_CACHE_TTL: Duration = Duration::from_secs(30);
+}
+
+impl GroupHooks for Empty {}
+
+struct Discard;
+
+impl AuditSink for Discard {
+    fn record(_: &AuditRecord) {}
+}
+
+fn host_settings<H: HostHooks>() -> (Duration, bool, NameForm) {
+    (
+        H::ENUMERATION_CACHE_TTL,
+        H::RESOLVES_UNSPECIFIED,
+        H::NAME_FORM,
+    )
+}
+
+fn group_settings<G: GroupHooks>() -> (Duration, Option<usize>) {
+    (G::ENUMERATION_CACHE_TTL, G::TRUNCATE_MEMBERS_AT)
+}
+
+#[test]
+fn middleware_forwards_the_settings() {
+    let host = (Duration::from_secs(30), true, NameForm::Folded);
+    assert_eq!(host_settings::<Configured>(), host);
+    assert_eq!(host_settings::<Coalesced<HostDatabase<Configured>>>(), host);
+    assert_eq!(
+        host_settings::<Audited<HostDatabase<Configured>, Discard>>(),
+        host
+    );
+    assert_eq!(
+        host_settings::<PrivilegedOnly<Coalesced<HostDatabase<Configured>>>>(),
+        host
+    );
+
+    let group = (Duration::from_secs(30), Some(4096));
+    assert_eq!(group_settings::<Configured>(), group);
+    assert_eq!(
+        group_settings::<Coalesced<GroupDatabase<Configured>>>(),
+        group
+    );
+
+    type Passwd = Coalesced<PasswdDatabase<Configured>>;
+    assert_eq!(
+        <Passwd as PasswdHooks>::ENUMERATION_CACHE_TTL,
+        Duration::from_secs(30)
+    );
+}
+
+#[test]
+fn combined_settings_take_the_stricter() {
+    type Hosts = Combined<HostDatabase<Configured>, HostDatabase<Empty>>;
+    // No reuse, as `Empty` allows none, and both families asked for, as `Empty` needs
+    assert_eq!(
+        host_settings::<Hosts>(),
+        (Duration::from_secs(0), false, NameForm::Folded)
+    );
+
+    type Groups = Combined<GroupDatabase<Empty>, GroupDatabase<Configured>>;
+    assert_eq!(
+        group_settings::<Groups>(),
+        (Duration::from_secs(0), Some(4096))
+    );
+}